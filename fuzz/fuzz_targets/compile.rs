@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Run with `cargo fuzz run compile`; seed the corpus with lib/test/*.c
+fuzz_target!(|data: &[u8]| {
+    let _ = tci::fuzz_compile(data);
+});
@@ -0,0 +1,20 @@
+#ifndef __TCI_POLL_H
+#define __TCI_POLL_H
+
+#define POLLIN 0x1
+#define POLLOUT 0x4
+#define POLLHUP 0x10
+#define POLLNVAL 0x20
+
+typedef unsigned long nfds_t;
+
+struct pollfd {
+  int fd;
+  short events;
+  short revents;
+};
+
+// TCI's poll never waits; `timeout` is ignored and it always returns immediately
+int poll(struct pollfd *fds, nfds_t nfds, int timeout);
+
+#endif
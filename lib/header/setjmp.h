@@ -0,0 +1,21 @@
+#ifndef __TCI_SETJMP_H
+#define __TCI_SETJMP_H
+
+#include <stdint.h>
+#include <tci.h>
+
+typedef uint64_t jmp_buf[8];
+
+// setjmp saves the state of the function it's called from, so it has to be a
+// macro instead of a function with its own stack frame
+#define setjmp(env)                                                            \
+  (__tci_builtin_push((uint64_t *)(env)),                                      \
+   __tci_builtin_push(TCI_ECALL_SETJMP),                                       \
+   (int)__tci_builtin_op("Ecall", sizeof(uint64_t)))
+
+// Jumping to a `jmp_buf` whose setjmp call's function has returned is an error.
+// `env` is declared as the pointer a `jmp_buf` decays to, because parameters with
+// array typedef types aren't adjusted to pointers yet.
+void longjmp(uint64_t *env, int val);
+
+#endif
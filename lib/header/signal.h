@@ -0,0 +1,27 @@
+#ifndef __TCI_SIGNAL_H
+#define __TCI_SIGNAL_H
+
+#define SIGINT 2
+#define SIGABRT 6
+#define SIGFPE 8
+#define SIGSEGV 11
+#define SIGTERM 15
+
+#define NSIG 32
+
+typedef int sig_atomic_t;
+typedef void (*sighandler_t)(int);
+
+#define SIG_DFL ((sighandler_t)0)
+#define SIG_IGN ((sighandler_t)1)
+#define SIG_ERR ((sighandler_t)-1)
+
+// TCI raises SIGFPE on integer division by zero and SIGSEGV on invalid pointer
+// accesses. A handler for those runs before the program is terminated; since
+// the faulting instruction can't be resumed, the program still terminates with
+// the original error once the handler returns.
+sighandler_t signal(int sig, sighandler_t handler);
+
+int raise(int sig);
+
+#endif
@@ -3,6 +3,8 @@
 
 #define NULL ((void *)0)
 
+#define RAND_MAX 32767
+
 typedef unsigned long size_t;
 
 void *malloc(size_t size);
@@ -11,6 +13,13 @@ void free(void *value);
 
 void exit(int status);
 
+// Raises SIGABRT, then terminates the program even if a handler returns
+void abort(void);
+
+// Always the same sequence for the same seed; the seed starts at 1
+int rand(void);
+void srand(unsigned int seed);
+
 double atof(const char *str);
 
 int atoi(const char *str);
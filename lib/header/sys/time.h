@@ -0,0 +1,16 @@
+#ifndef __TCI_SYS_TIME_H
+#define __TCI_SYS_TIME_H
+
+#include <time.h>
+
+typedef long suseconds_t;
+
+struct timeval {
+  time_t tv_sec;
+  suseconds_t tv_usec;
+};
+
+// `tz` is ignored
+int gettimeofday(struct timeval *tv, void *tz);
+
+#endif
@@ -2,5 +2,6 @@
 #define __TCI_SYS_TYPES_H
 
 typedef long ssize_t;
+typedef int pid_t;
 
 #endif
@@ -12,6 +12,23 @@
 #define TCI_ECALL_APPEND_FD 6U
 #define TCI_ECALL_FD_LEN 7U
 
+#define TCI_ECALL_FORK 8U
+#define TCI_ECALL_EXECVE 9U
+#define TCI_ECALL_WAITPID 10U
+#define TCI_ECALL_PIPE 11U
+#define TCI_ECALL_CLOSE_FD 12U
+#define TCI_ECALL_DUP2 13U
+#define TCI_ECALL_POLL_FD 14U
+#define TCI_ECALL_TIME 15U
+#define TCI_ECALL_CPU_TIME 16U
+#define TCI_ECALL_SIGNAL 17U
+#define TCI_ECALL_SIGNAL_RETURN 18U
+#define TCI_ECALL_SETJMP 19U
+#define TCI_ECALL_LONGJMP 20U
+
+// functions the embedder provides, declared in <tci_host.h>
+#define TCI_ECALL_HOST 1024U
+
 #define TCI_FILE_ERR_DOESNT_EXIST 1U
 #define TCI_FILE_ERR_NAME_NOT_UTF8 2U
 #define TCI_FILE_ERR_TOO_MANY_FILES 3U
@@ -23,6 +40,8 @@
 #define TCI_STDLOG_READ 8U
 #define TCI_STDIN_WRITE 9U
 
+#define TCI_BROKEN_PIPE 12U
+
 #define TCI_ERRNO_DOESNT_EXIST 4U
 #define TCI_ERRNO_TOO_MANY_FILES 5U
 #define TCI_ERRNO_FILES_TOO_LARGE 6U
@@ -0,0 +1,19 @@
+#ifndef __TCI_TIME_H
+#define __TCI_TIME_H
+
+#include <stddef.h>
+
+#define CLOCKS_PER_SEC 1000000L
+
+typedef long time_t;
+typedef long clock_t;
+
+// Unless the host switches TCI to its own clock, time is virtual: it starts at
+// 2021-01-01 00:00:00 UTC and advances a microsecond per instruction, so runs are
+// reproducible
+time_t time(time_t *t);
+
+// Processor time used by the program, also a microsecond per instruction
+clock_t clock(void);
+
+#endif
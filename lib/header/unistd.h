@@ -0,0 +1,21 @@
+#ifndef __TCI_UNISTD_H
+#define __TCI_UNISTD_H
+#include <stddef.h>
+#include <sys/types.h>
+
+pid_t fork(void);
+
+int execve(const char *path, char *const argv[], char *const envp[]);
+int execv(const char *path, char *const argv[]);
+
+#define STDIN_FILENO 0
+#define STDOUT_FILENO 1
+#define STDERR_FILENO 2
+
+int pipe(int fds[2]);
+ssize_t read(int fd, void *buf, size_t count);
+ssize_t write(int fd, const void *buf, size_t count);
+int close(int fd);
+int dup2(int oldfd, int newfd);
+
+#endif
@@ -86,7 +86,7 @@ FILE *fopen(const char *name, const char *mode) {
   flags |= FLAG_FULL_BUF;
   uint32_t open_mode = should_create + should_clear;
 
-  uint64_t fd = tci_ecall(TCI_ECALL_OPEN_FD, name, open_mode);
+  uint64_t fd = (uint64_t)tci_ecall(TCI_ECALL_OPEN_FD, name, open_mode);
   switch (fd >> 32) {
   case 0:
     break;
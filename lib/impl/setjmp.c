@@ -0,0 +1,10 @@
+#include <setjmp.h>
+#include <stdint.h>
+#include <tci.h>
+
+void longjmp(uint64_t *env, int val) {
+  __tci_builtin_push(env);
+  __tci_builtin_push(val);
+  __tci_builtin_push(TCI_ECALL_LONGJMP);
+  __tci_builtin_op("Ecall", sizeof(uint64_t));
+}
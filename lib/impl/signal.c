@@ -0,0 +1,55 @@
+#include <signal.h>
+#include <stdint.h>
+#include <tci.h>
+
+static sighandler_t __tci_signal_handlers[NSIG];
+
+// The kernel calls this when a process faults with a handler installed. Faults
+// can't be resumed, so after the handler returns the kernel terminates the
+// process with the error that caused the fault.
+void __tci_signal_trampoline(int sig, sighandler_t handler) {
+  handler(sig);
+
+  __tci_builtin_push(TCI_ECALL_SIGNAL_RETURN);
+  __tci_builtin_op("Ecall", sizeof(uint64_t));
+}
+
+sighandler_t signal(int sig, sighandler_t handler) {
+  if (sig <= 0 || sig >= NSIG)
+    return SIG_ERR;
+
+  sighandler_t prev = __tci_signal_handlers[sig];
+  __tci_signal_handlers[sig] = handler;
+
+  __tci_builtin_push(sig);
+  __tci_builtin_push(handler);
+  __tci_builtin_push(__tci_signal_trampoline);
+  __tci_builtin_push(TCI_ECALL_SIGNAL);
+  __tci_builtin_op("Ecall", sizeof(uint64_t));
+  return prev;
+}
+
+int raise(int sig) {
+  if (sig <= 0 || sig >= NSIG)
+    return -1;
+
+  sighandler_t handler = __tci_signal_handlers[sig];
+  if (handler == SIG_IGN)
+    return 0;
+
+  if (handler == SIG_DFL) {
+    switch (sig) {
+    case SIGABRT:
+      tci_throw_error("Aborted", "the program was aborted", 1);
+    case SIGFPE:
+      tci_throw_error("DivideByZero", "the program raised SIGFPE", 1);
+    case SIGSEGV:
+      tci_throw_error("InvalidPointer", "the program raised SIGSEGV", 1);
+    default:
+      tci_throw_error("Signal", "the program was terminated by a signal", 1);
+    }
+  }
+
+  handler(sig);
+  return 0;
+}
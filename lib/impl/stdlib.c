@@ -1,3 +1,4 @@
+#include <signal.h>
 #include <stdlib.h>
 #include <tci.h>
 
@@ -25,15 +26,34 @@ void *realloc(void *_buffer, size_t new_size) {
   for (size_t i = 0; i < copy_bound; i++)
     new_buffer[i] = buffer[i];
 
-  free(buffer);
+  // Like `free`, but later uses of `buffer` point at this call
+  __tci_builtin_push(buffer);
+  __tci_builtin_push(1);
+  __tci_builtin_op("HeapMove", sizeof(void *));
 
   return new_buffer;
 }
 
 void free(void *buffer) {
   __tci_builtin_push(buffer);
-  __tci_builtin_push(2);
+  __tci_builtin_push(1);
   return __tci_builtin_op("HeapDealloc", sizeof(void *));
 }
 
 void exit(int status) { tci_ecall(TCI_ECALL_EXIT, status); }
+
+void abort(void) {
+  raise(SIGABRT);
+
+  // The SIGABRT handler returned instead of leaving the program
+  tci_throw_error("Aborted", "the program was aborted", 1);
+}
+
+static unsigned int __tci_rand_state = 1;
+
+int rand(void) {
+  __tci_rand_state = __tci_rand_state * 1103515245U + 12345U;
+  return (__tci_rand_state / 65536U) % 32768U;
+}
+
+void srand(unsigned int seed) { __tci_rand_state = seed; }
@@ -19,7 +19,8 @@ void *memset(void *s, int _c, size_t n) {
 }
 
 size_t strnlen(const char *str, size_t max_len) {
-  for (char *begin = str, *len = max_len; len--; str++)
+  const char *begin = str;
+  for (size_t len = max_len; len--; str++)
     if (!*str)
       return str - begin;
 
@@ -40,8 +40,7 @@ void *tci_ecall(int ecall_num, ...) {
   va_list list;
   va_start(list, ecall_num);
 
-  const unsigned long diff = ((unsigned long)(unsigned)-1) + 1;
-  void *next = ((char *)list.current) - diff;
+  void *next = __tci_va_next(list.current);
   size_t size = tci_var_size(next);
 
   while (size != -1) {
@@ -50,7 +49,7 @@ void *tci_ecall(int ecall_num, ...) {
     __tci_builtin_op("PushDyn", sizeof(void));
 
     list.current = next;
-    next = ((char *)list.current) - diff;
+    next = __tci_va_next(list.current);
     size = tci_var_size(next);
   }
 
@@ -75,3 +74,42 @@ char *tci_strerror(int error) {
 
   return NULL;
 }
+
+// Prints what the function given with `--entry` returned; `kind` is from
+// `print_kind` in src/assembler.rs
+void __tci_print_entry(const void *value, int kind) {
+  switch (kind) {
+  case 0:
+    printf("%d\n", *(const signed char *)value);
+    break;
+  case 1:
+    printf("%u\n", *(const unsigned char *)value);
+    break;
+  case 2:
+    printf("%d\n", *(const short *)value);
+    break;
+  case 3:
+    printf("%u\n", *(const unsigned short *)value);
+    break;
+  case 4:
+    printf("%d\n", *(const int *)value);
+    break;
+  case 5:
+    printf("%u\n", *(const unsigned int *)value);
+    break;
+  case 6:
+    printf("%ld\n", *(const long *)value);
+    break;
+  case 7:
+    printf("%lu\n", *(const unsigned long *)value);
+    break;
+  case 8:
+    printf("%f\n", (double)*(const float *)value);
+    break;
+  case 9:
+    printf("%f\n", *(const double *)value);
+    break;
+  default:
+    printf("%p\n", *(void *const *)value);
+  }
+}
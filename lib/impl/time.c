@@ -0,0 +1,30 @@
+#include <stdint.h>
+#include <sys/time.h>
+#include <tci.h>
+#include <time.h>
+
+time_t time(time_t *t) {
+  __tci_builtin_push(TCI_ECALL_TIME);
+  uint64_t micros = __tci_builtin_op("Ecall", sizeof(uint64_t));
+
+  time_t now = micros / 1000000;
+  if (t != NULL)
+    *t = now;
+
+  return now;
+}
+
+clock_t clock(void) {
+  __tci_builtin_push(TCI_ECALL_CPU_TIME);
+  uint64_t micros = __tci_builtin_op("Ecall", sizeof(uint64_t));
+  return micros;
+}
+
+int gettimeofday(struct timeval *tv, void *tz) {
+  __tci_builtin_push(TCI_ECALL_TIME);
+  uint64_t micros = __tci_builtin_op("Ecall", sizeof(uint64_t));
+
+  tv->tv_sec = micros / 1000000;
+  tv->tv_usec = micros % 1000000;
+  return 0;
+}
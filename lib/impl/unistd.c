@@ -0,0 +1,93 @@
+#include <stddef.h>
+#include <poll.h>
+#include <stdint.h>
+#include <sys/types.h>
+#include <sys/wait.h>
+#include <tci.h>
+#include <unistd.h>
+
+pid_t fork(void) {
+  __tci_builtin_push(TCI_ECALL_FORK);
+  return (pid_t)__tci_builtin_op("Ecall", sizeof(uint64_t));
+}
+
+// TCI doesn't pass arguments or an environment to programs yet, so `argv` and
+// `envp` are ignored
+int execve(const char *path, char *const argv[], char *const envp[]) {
+  __tci_builtin_push(path);
+  __tci_builtin_push(TCI_ECALL_EXECVE);
+  return (int)__tci_builtin_op("Ecall", sizeof(uint64_t));
+}
+
+int execv(const char *path, char *const argv[]) {
+  return execve(path, argv, NULL);
+}
+
+pid_t waitpid(pid_t pid, int *status, int options) {
+  __tci_builtin_push(pid);
+  __tci_builtin_push(status);
+  __tci_builtin_push(options);
+  __tci_builtin_push(TCI_ECALL_WAITPID);
+  return (pid_t)__tci_builtin_op("Ecall", sizeof(uint64_t));
+}
+
+pid_t wait(int *status) { return waitpid(-1, status, 0); }
+
+int pipe(int fds[2]) {
+  __tci_builtin_push(fds);
+  __tci_builtin_push(TCI_ECALL_PIPE);
+  uint64_t ret = __tci_builtin_op("Ecall", sizeof(uint64_t));
+  return ret == 0 ? 0 : -1;
+}
+
+// Files don't keep a position per descriptor, so reads start at the beginning of
+// the file and writes append to it. Use <stdio.h> for anything fancier.
+ssize_t read(int fd, void *buf, size_t count) {
+  __tci_builtin_push((unsigned int)fd);
+  __tci_builtin_push(0U);
+  __tci_builtin_push(buf);
+  __tci_builtin_push((unsigned int)count);
+  __tci_builtin_push(TCI_ECALL_READ_FD);
+  uint64_t ret = __tci_builtin_op("Ecall", sizeof(uint64_t));
+  return ret >> 32 ? -1 : (ssize_t)ret;
+}
+
+ssize_t write(int fd, const void *buf, size_t count) {
+  __tci_builtin_push((unsigned int)fd);
+  __tci_builtin_push(buf);
+  __tci_builtin_push((unsigned int)count);
+  __tci_builtin_push(TCI_ECALL_APPEND_FD);
+  uint64_t ret = __tci_builtin_op("Ecall", sizeof(uint64_t));
+  return ret >> 32 ? -1 : (ssize_t)count;
+}
+
+int close(int fd) {
+  __tci_builtin_push((unsigned int)fd);
+  __tci_builtin_push(TCI_ECALL_CLOSE_FD);
+  uint64_t ret = __tci_builtin_op("Ecall", sizeof(uint64_t));
+  return ret == 0 ? 0 : -1;
+}
+
+int dup2(int oldfd, int newfd) {
+  __tci_builtin_push((unsigned int)oldfd);
+  __tci_builtin_push((unsigned int)newfd);
+  __tci_builtin_push(TCI_ECALL_DUP2);
+  uint64_t ret = __tci_builtin_op("Ecall", sizeof(uint64_t));
+  return ret >> 32 ? -1 : newfd;
+}
+
+int poll(struct pollfd *fds, nfds_t nfds, int timeout) {
+  int ready = 0;
+  for (nfds_t i = 0; i < nfds; i++) {
+    __tci_builtin_push((unsigned int)fds[i].fd);
+    __tci_builtin_push(TCI_ECALL_POLL_FD);
+    uint64_t events = __tci_builtin_op("Ecall", sizeof(uint64_t));
+
+    short mask = fds[i].events | POLLHUP | POLLNVAL;
+    fds[i].revents = (short)events & mask;
+    if (fds[i].revents != 0)
+      ready++;
+  }
+
+  return ready;
+}
@@ -0,0 +1,49 @@
+#include <stdio.h>
+
+struct Shape {
+  char kind;
+  struct {
+    int x;
+    long y;
+  };
+  union {
+    int radius;
+    float side;
+  };
+  struct {
+    struct {
+      char deep;
+    };
+    long after;
+  };
+};
+
+union Word {
+  struct {
+    short lo;
+    short hi;
+  };
+  int whole;
+};
+
+int main() {
+  struct Shape shape;
+  struct Shape *ptr = &shape;
+  shape.kind = 1;
+  shape.x = 2;
+  ptr->y = 3;
+  shape.radius = 4;
+  ptr->deep = 5;
+  shape.after = 6;
+
+  printf("%d %d %ld %d %d %ld\n", shape.kind, ptr->x, shape.y, ptr->radius,
+         shape.deep, ptr->after);
+  printf("sizeof Shape is %ld\n", sizeof(struct Shape));
+  printf("after is at %ld\n", (char *)&shape.after - (char *)&shape);
+
+  union Word word;
+  word.whole = 0;
+  word.hi = 1;
+  printf("sizeof Word is %ld, whole is %d\n", sizeof(union Word), word.whole);
+  return 0;
+}
@@ -0,0 +1,18 @@
+#include <stdio.h>
+struct point { int x, y; };
+struct poly { int n; struct point pts[3]; };
+int grid[][3] = {{1, 2, 3}, {4, 5, 6}};
+int main() {
+  int a[] = {1, 2, 3};
+  printf("%ld\n", sizeof(a));
+  int b[][2] = {{1, 2}, {3, 4}, {5, 6}};
+  printf("%ld %d %d\n", sizeof(b), b[2][1], b[1][0]);
+  int c[3][2] = {{7, 8}};
+  printf("%ld %d %d\n", sizeof(c) / sizeof(c[0]), c[0][1], c[0][0]);
+  struct point ps[] = {{1, 2}, {3, 4}};
+  printf("%ld %d\n", sizeof(ps) / sizeof(ps[0]), ps[1].y);
+  struct poly tri = {3, {{0, 0}, {4, 0}, {0, 3}}};
+  printf("%d %d %d\n", tri.n, tri.pts[1].x, tri.pts[2].y);
+  printf("%ld %d\n", sizeof(grid), grid[1][2]);
+  return 0;
+}
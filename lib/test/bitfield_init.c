@@ -0,0 +1,52 @@
+#include <stdio.h>
+#include <string.h>
+
+struct Flags {
+  unsigned a : 3, b : 5;
+  unsigned char c : 4;
+  int d;
+  int e : 7;
+  unsigned long f : 40, g : 20;
+  unsigned h : 12;
+};
+
+// `q` and `r` share three bytes that start at offset 1
+struct Odd {
+  unsigned char p;
+  unsigned q : 12, r : 12;
+  short s;
+};
+
+struct Flags global = {5, 17, 9, 3, 20, 0x12345678FFul, 0xABCDE};
+struct Flags named = {.b = 31, .e = 63, .h = 4095};
+struct Odd odd = {200, 4000, 1234, 77};
+
+void print(struct Flags *s) {
+  printf("%u %u %u %d %d %lx %lx %u\n", s->a, s->b, s->c, s->d, s->e, s->f,
+         s->g, s->h);
+}
+
+int main() {
+  int x = 6, y = -1;
+  struct Flags local = {x, x * 3, x + 10, y, y * 5, 0x1234567890ul, x, 77};
+  print(&global);
+  print(&named);
+  print(&local);
+
+  // Only the bits that belong to a field are printed, not the padding
+  struct Flags mask;
+  memset(&mask, 0, sizeof(mask));
+  mask.a = mask.b = mask.c = mask.e = mask.f = mask.g = mask.h = ~0;
+  mask.d = ~0;
+  unsigned char *m = (unsigned char *)&mask;
+  unsigned char *l = (unsigned char *)&local;
+  for (int i = 0; i < (int)sizeof(local); i++)
+    printf("%02x", l[i] & m[i]);
+  printf("\n");
+
+  int z = 3000;
+  struct Odd odd_local = {1, z, z + 1000, -2};
+  printf("%u %u %u %d\n", odd.p, odd.q, odd.r, odd.s);
+  printf("%u %u %u %d\n", odd_local.p, odd_local.q, odd_local.r, odd_local.s);
+  return 0;
+}
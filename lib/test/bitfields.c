@@ -0,0 +1,47 @@
+#include <stdio.h>
+
+struct Flags {
+  unsigned ready : 1;
+  unsigned mode : 3;
+  int delta : 4;
+  unsigned : 0;
+  unsigned char tag;
+  unsigned long big : 40;
+};
+
+struct Packed {
+  char c;
+  int x : 4;
+};
+
+int main() {
+  struct Flags flags;
+  struct Flags *ptr = &flags;
+  flags.ready = 1;
+  flags.mode = 5;
+  ptr->delta = -3;
+  flags.tag = 200;
+  flags.big = 1;
+  flags.big <<= 39;
+
+  printf("%d %d %d %d %ld\n", flags.ready, ptr->mode, flags.delta, ptr->tag,
+         flags.big);
+
+  flags.mode = 9; // truncated to 3 bits
+  ptr->delta += 10;
+  printf("%d %d\n", flags.mode, ptr->delta);
+
+  int old = flags.mode++;
+  ptr->mode--;
+  ptr->mode--;
+  printf("%d %d %d\n", old, flags.mode, flags.ready);
+
+  struct Packed packed;
+  packed.c = 'a';
+  packed.x = 7;
+  printf("%c %d\n", packed.c, packed.x);
+
+  printf("sizeof Flags is %ld, sizeof Packed is %ld\n", sizeof(struct Flags),
+         sizeof(struct Packed));
+  return 0;
+}
@@ -0,0 +1,27 @@
+#include <stdio.h>
+
+#define ENABLED
+
+int main() {
+#ifdef ENABLED
+  printf("enabled\n");
+#else
+  printf("not enabled\n");
+#endif
+
+#ifndef ENABLED
+#if defined(ENABLED)
+  printf("nested, never printed\n");
+#else
+  printf("nested else, never printed\n");
+#endif
+#else
+  printf("else after nested\n");
+#endif
+
+#if defined(DISABLED)
+#error DISABLED shouldn't be defined
+#endif
+
+  return 0;
+}
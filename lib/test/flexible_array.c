@@ -0,0 +1,31 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+struct buf {
+  int len;
+  char data[];
+};
+
+struct buf *buf_new(char *text) {
+  int len = strlen(text);
+  struct buf *buf = malloc(sizeof(struct buf) + len + 1);
+  buf->len = len;
+  for (int i = 0; i <= len; i++)
+    buf->data[i] = text[i];
+  return buf;
+}
+
+int main() {
+  struct buf *buf = buf_new("flexible");
+  printf("%d %s %c\n", buf->len, buf->data, buf->data[buf->len - 1]);
+
+  struct buf local;
+  local.len = 0;
+  char *data = local.data;
+  printf("data is at %ld, sizeof buf is %ld\n", data - (char *)&local,
+         sizeof(struct buf));
+
+  free(buf);
+  return 0;
+}
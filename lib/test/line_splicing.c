@@ -0,0 +1,25 @@
+#include <stdio.h>
+
+// Backslash-newline joins lines anywhere, even in the middle of a token
+%:define SQUARE(x) \
+  ((x) *           \
+   (x))
+
+in\
+t main() <%
+  int arr<:3:> = {1, 2, 3};
+  // this comment continues onto the next line \
+  arr[0] = 100;
+
+  char *s = "hello, \
+world";
+  int to\
+tal = SQUARE(arr<:2:>) + ar\
+r[0];
+  total +\
+= 1;
+
+  printf("%s %d\n", s, total);
+  printf("trigraphs stay as they are??!\n");
+  return 0;
+%>
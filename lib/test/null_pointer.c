@@ -0,0 +1,34 @@
+#include <stddef.h>
+#include <stdio.h>
+
+struct node {
+  int value;
+  struct node *next;
+};
+
+int length(struct node *list) {
+  int len = 0;
+  for (; list != NULL; list = list->next)
+    len++;
+  return len;
+}
+
+int main() {
+  int *p = 0;
+  char *q = NULL;
+  long *l = 0L;
+  struct node *n = (void *)0;
+  printf("%d %d %d %d\n", p == 0, q == NULL, l == NULL, n == 0);
+
+  int x = 3;
+  int *r = &x;
+  printf("%d %d %d\n", r != NULL, 0 == p, NULL != r);
+
+  struct node b = {2, NULL};
+  struct node a = {1, &b};
+  printf("%d %d\n", length(&a), length(NULL));
+
+  r = 0;
+  printf("%d %d\n", r == p, !r);
+  return 0;
+}
@@ -0,0 +1,19 @@
+#include <stdint.h>
+#include <stdio.h>
+int main() {
+  int x = 5;
+  int *p = &x;
+  intptr_t i = (intptr_t)p;
+  int *q = (int *)i;
+  void *v = (void *)i;
+  uintptr_t u = (uintptr_t)v;
+  long l = (long)p;
+  int small = (int)p;
+  char c = 3;
+  char *cp = (char *)(intptr_t)c;
+  printf("%d %d %d %d\n", *q, q == p, u == (uintptr_t)p, l == i);
+  p += 1;
+  p -= 1;
+  printf("%d %d\n", *p, cp == (char *)3);
+  return 0;
+}
@@ -0,0 +1,36 @@
+#include <stddef.h>
+#include <stdio.h>
+
+int square(int x) { return x * x; }
+int twice(int x) { return x + x; }
+
+int *find(int *begin, int *end, int value) {
+  for (int *p = begin; p < end; p++)
+    if (*p == value)
+      return p;
+  return NULL;
+}
+
+int main() {
+  int arr[5] = {4, 8, 15, 16, 23};
+  int *a = &arr[1], *b = &arr[3];
+  printf("%d %d %d %d %d %d\n", a == b, a != b, a < b, a <= b, a > b, a >= b);
+  printf("%d %d %d\n", a <= a, a >= a, arr + 1 == a);
+
+  int *found = find(arr, arr + 5, 16);
+  printf("%d %d\n", found != NULL, (int)(found - arr));
+  printf("%d\n", find(arr, arr + 5, 42) == NULL);
+
+  void *v = a;
+  printf("%d %d\n", v == a, b != v);
+
+  int (*f)(int) = square;
+  printf("%d %d %d\n", f == square, f != twice, f(3));
+
+  char text[] = "hello";
+  char *end = text;
+  while (*end != 0)
+    end++;
+  printf("%d %d\n", (int)(end - text), text < end);
+  return 0;
+}
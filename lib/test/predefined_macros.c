@@ -0,0 +1,13 @@
+#include <stdio.h>
+
+#define LOG(msg) printf("%s:%d: %s\n", __FILE__, __LINE__, msg)
+
+void report() { printf("in %s, also %s\n", __func__, __FUNCTION__); }
+
+int main() {
+  printf("line %d\n", __LINE__);
+  LOG("from a macro");
+  report();
+  printf("%s %s\n", __DATE__, __TIME__);
+  return 0;
+}
@@ -0,0 +1,40 @@
+#include <stdio.h>
+
+volatile int counter;
+static volatile unsigned long ticks = 3;
+
+struct device {
+  volatile int status;
+  int *volatile next;
+};
+
+void copy(char *restrict dst, const char *restrict src, int n) {
+  for (int i = 0; i < n; i++)
+    dst[i] = src[i];
+}
+
+int sum(int n, int arr[restrict 3]) {
+  int total = 0;
+  for (int i = 0; i < n; i++)
+    total += arr[i];
+  return total;
+}
+
+int main() {
+  volatile int x = 4;
+  int *volatile p = &x;
+  const volatile int *restrict q = &x;
+  counter += x;
+  printf("%d %d %d %lu\n", counter, *p, *q, ticks);
+
+  struct device dev;
+  dev.status = 2;
+  dev.next = p;
+  printf("%d %d\n", dev.status, *dev.next);
+
+  char buf[6];
+  copy(buf, "hello", 6);
+  int arr[3] = {1, 2, 3};
+  printf("%s %d %d\n", buf, sum(3, arr), (int)sizeof(volatile int));
+  return 0;
+}
@@ -0,0 +1,16 @@
+#include <stdio.h>
+char global[] = "global";
+struct person { char name[8]; int age; };
+int main() {
+  char s[] = "hello";
+  char t[10] = "hi";
+  char exact[3] = "abc";
+  char names[][6] = {"ann", "bob"};
+  struct person p = {"carl", 40};
+  s[0] = 'j';
+  printf("%s %ld %s %ld %d %d\n", s, sizeof(s), t, sizeof(t), t[2], t[9]);
+  printf("%c%c%c %ld\n", exact[0], exact[1], exact[2], sizeof(exact));
+  printf("%s %s %ld\n", names[0], names[1], sizeof(names));
+  printf("%s %d %s %ld\n", p.name, p.age, global, sizeof(global));
+  return 0;
+}
@@ -1,22 +1,27 @@
 use crate::buckets::*;
 use crate::filedb::*;
+use crate::interner::*;
 use crate::runtime::*;
 use crate::tc_ast::*;
 use crate::util::*;
 use core::mem;
+use core::ops::Range;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ASMFunc {
     pub func_type: TCFuncType,
     pub decl_loc: CodeLoc,
+    pub decl_file: u32, // translation unit of `decl_loc`
     pub func_header: Option<(VarPointer, CodeLoc)>,
+    pub defn_file: u32, // translation unit that defined the function, once `func_header` is set
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ASMVar {
     pub ty: TCType,
     pub decl_loc: CodeLoc,
     pub header: Option<(VarPointer, CodeLoc)>,
+    pub defn_file: u32, // translation unit that defined the variable, once `header` is set
 }
 
 pub struct FuncEnv {
@@ -26,6 +31,11 @@ pub struct FuncEnv {
     pub var_offsets: Vec<i16>,
     pub var_temps: Vec<u32>,
     pub func_temps: Vec<u32>,
+    pub shared_slots: HashMap<u32, u32>, // locals that use another local's stack slot
+
+    pub ops: &'static [TCOpcode], // the function's ops, for statement expressions
+    pub next_offset: i16,         // where should the next variable be allocated (relative to fp)?
+    pub error: Option<Error>,     // the first expression that couldn't be translated
 }
 
 impl FuncEnv {
@@ -37,6 +47,10 @@ impl FuncEnv {
             var_offsets: Vec::new(),
             var_temps: Vec::new(),
             func_temps: Vec::new(),
+            shared_slots: HashMap::new(),
+            ops: &[],
+            next_offset: 0,
+            error: None,
         }
     }
 
@@ -47,6 +61,10 @@ impl FuncEnv {
         self.var_offsets.clear();
         self.var_temps.clear();
         self.func_temps.clear();
+        self.shared_slots.clear();
+        self.ops = &[];
+        self.next_offset = 0;
+        self.error = None;
     }
 }
 
@@ -69,6 +87,168 @@ impl FileEnv {
     }
 }
 
+/// Where the assembler put things, recorded while assembling so that a debugger can
+/// compile more code against the finished program.
+#[derive(Clone)]
+pub struct DebugLayout {
+    pub symbols: Symbols,
+    pub files: HashMap<u32, Vec<u32>>, // binary offsets of each translation unit's globals
+    pub funcs: HashMap<LinkName, DebugFunc>,
+    pub layouts: Vec<PointerLayout>,
+    pub struct_layouts: HashMap<CodeLoc, u32>, // index into `layouts` of each struct definition
+}
+
+/// Where the pointers are in a value of some type, and the layout of what each one
+/// points to, so that the pointers between objects can be found from their types
+#[derive(Debug, Clone)]
+pub struct PointerLayout {
+    pub size: u32,
+    pub pointers: Vec<(u32, n32)>, // offset of each pointer, and the layout it points to
+    pub unknown: Vec<(u32, u32)>,  // offset and size of each union, whose pointers aren't known
+}
+
+#[derive(Debug, Clone)]
+pub struct DebugLocal {
+    pub name: String,
+    pub ty: String,
+    pub label: u32,
+    pub size: u32,
+    pub decl_loc: CodeLoc,
+    pub scope: CodeLoc, // the block the variable is declared in
+    pub layout: n32,    // index into `DebugInfo::layouts`; NULL if its size isn't known
+}
+
+#[derive(Debug, Clone)]
+pub struct DebugFunc {
+    pub file: n32, // translation unit the function is defined in
+    pub ptr: VarPointer,
+    pub ret_size: u32,
+    pub var_offsets: Vec<i16>, // offset from fp of each local, indexed by label
+    pub locals: Vec<DebugLocal>,
+    pub stmts: Vec<CodeLoc>, // the function's entry and statements, for coverage
+}
+
+#[derive(Clone)]
+pub struct DebugInfo {
+    pub symbols: Symbols, // the compiler's symbols, so that identifiers map to the same ids
+    pub files: HashMap<u32, Vec<u32>>,
+    pub vars: Vec<VarPointer>, // header of each global, indexed by id; NULL if undefined
+    pub func_linkage: HashMap<LinkName, u32>,
+    pub funcs: Vec<DebugFunc>,
+    pub layouts: Vec<PointerLayout>,
+}
+
+impl DebugInfo {
+    /// Locations of every function entry and statement in the program
+    pub fn stmts(&self) -> Vec<CodeLoc> {
+        let stmts = self.funcs.iter().map(|f| f.stmts.iter().map(|l| *l));
+        return stmts.flatten().collect();
+    }
+
+    /// Frame layout of every defined function, sorted by name
+    pub fn frame_layouts(&self) -> Vec<FrameLayout> {
+        let mut layouts = Vec::new();
+        for (link_name, &id) in &self.func_linkage {
+            let func = &self.funcs[id as usize];
+            if func.file == n32::NULL {
+                continue;
+            }
+
+            let (mut params, mut locals) = (Vec::new(), Vec::new());
+            for local in &func.locals {
+                let offset = func.var_offsets[local.label as usize];
+                let slot = FrameSlot {
+                    name: local.name.clone(),
+                    ty: local.ty.clone(),
+                    offset,
+                    size: local.size,
+                };
+
+                match offset < 0 {
+                    true => params.push((local.label, slot)),
+                    false => locals.push((local.label, slot)),
+                }
+            }
+
+            params.sort_by_key(|(label, _)| *label);
+            locals.sort_by_key(|(label, _)| *label);
+            layouts.push(FrameLayout {
+                name: self.symbols.to_str(link_name.name).unwrap().to_string(),
+                file: func.file.into(),
+                ret_size: func.ret_size,
+                params: params.into_iter().map(|(_, slot)| slot).collect(),
+                locals: locals.into_iter().map(|(_, slot)| slot).collect(),
+            });
+        }
+
+        layouts.sort_by(|a, b| (&a.name, a.file).cmp(&(&b.name, b.file)));
+        return layouts;
+    }
+}
+
+/// Where a function keeps its return value, parameters and locals, as offsets in
+/// stack slots from its frame pointer. This is the calling convention that the
+/// debugger, host functions and the entry point all rely on:
+///
+/// - The caller allocates an empty slot so that varargs don't run off the stack,
+///   then each argument from last to first, then the return value. So the return
+///   value is at `fp - 1`, and parameter `idx` is at `fp - 2 - idx`.
+/// - Locals get `fp + 0`, `fp + 1`, ... in the order their scopes begin, and in
+///   the order they're declared within a scope. A scope's slots are freed when it
+///   ends, so locals in sibling scopes get the same offsets. With `-O1`, locals
+///   that are never live at the same time can share a slot.
+/// - Statics live in the binary, and temporaries on the expression stack, so
+///   neither gets a slot.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct FrameLayout {
+    pub name: String,
+    pub file: u32, // translation unit the function is defined in
+    pub ret_size: u32,
+    pub params: Vec<FrameSlot>, // in the order they're declared
+    pub locals: Vec<FrameSlot>, // in the order they're declared
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct FrameSlot {
+    pub name: String,
+    pub ty: String,
+    pub offset: i16, // from fp
+    pub size: u32,
+}
+
+/// How many functions the missing `main` error lists
+const MAX_LISTED_FUNCS: usize = 8;
+
+/// What debug info calls the temporary behind a compound literal
+const COMPOUND_LIT_NAME: &str = "(compound literal)";
+
+/// Runtime checks the assembler can add to a program, to catch undefined behavior
+/// that TCI would otherwise let slide
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sanitizers {
+    pub alignment: bool, // loads and stores through pointers must be aligned for their type
+    pub signed_overflow: bool, // signed `int` and `long` arithmetic can't overflow
+    pub pointer_provenance: bool, // pointers stay in their object, and only meet pointers into it
+}
+
+impl Sanitizers {
+    /// Parses the value of a `--sanitize=` flag, a comma-separated list of checks
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut sanitize = Self::default();
+        for check in value.split(',').map(|c| c.trim()) {
+            match check {
+                "alignment" => sanitize.alignment = true,
+                "signed-overflow" => sanitize.signed_overflow = true,
+                "pointer-provenance" => sanitize.pointer_provenance = true,
+                "" => {}
+                _ => return Err(format!("unknown sanitizer `{}`", check)),
+            }
+        }
+
+        return Ok(sanitize);
+    }
+}
+
 pub struct BinaryInit {
     pub init: BinaryData,
     pub main_call: VarPointer,
@@ -101,7 +281,7 @@ lazy_static! {
         data.push(Opcode::StackDealloc);
 
         data.push(Opcode::Make32);
-        data.push(Ecall::Exit);
+        data.push(Ecall::Exit as u32);
         data.push(Opcode::Ecall);
 
         let mut init = BinaryData::new();
@@ -111,6 +291,16 @@ lazy_static! {
     };
 }
 
+/// A function for the program to start at instead of `main`, for `--entry`.
+/// Identifiers are `None` if the name never appears in the program.
+#[derive(Debug, Clone)]
+pub struct EntryPoint {
+    pub name: String,
+    pub ident: Option<u32>,
+    pub printer: Option<u32>, // `__tci_print_entry` from the bundled libc
+    pub args: Vec<String>,    // as written on the command line
+}
+
 pub struct Assembler {
     pub buckets: BucketListFactory,
 
@@ -125,6 +315,23 @@ pub struct Assembler {
     pub func: FuncEnv,
     pub file: FileEnv,
     pub data: BinaryData,
+
+    pub debug: Option<DebugLayout>,
+    pub sanitize: Sanitizers,
+    pub peephole: Vec<PeepholeRule>, // run over every function once it's translated
+    pub share_slots: bool,           // let locals with disjoint lifetimes share stack slots
+    pub entry: Option<EntryPoint>,   // called instead of `main`, with its return value printed
+}
+
+/// What adding files and linking change in an `Assembler`, so that a REPL input
+/// that doesn't compile can be taken back out with `Assembler::rollback`
+pub struct AsmCheckpoint {
+    func_linkage: HashMap<LinkName, u32>,
+    functions: Vec<ASMFunc>,
+    var_linkage: HashMap<LinkName, u32>,
+    vars: Vec<ASMVar>,
+    data: BinaryData,
+    debug: Option<DebugLayout>,
 }
 
 impl Drop for Assembler {
@@ -164,15 +371,74 @@ impl Assembler {
 
             func: FuncEnv::new(),
             file: FileEnv::new(),
+
+            debug: None,
+            sanitize: Sanitizers::default(),
+            peephole: Vec::new(),
+            share_slots: false,
+            entry: None,
         }
     }
 
-    pub fn add_file(&mut self, mut tu: TranslationUnit) -> Result<(), Error> {
+    pub fn with_debug_info(symbols: Symbols) -> Self {
+        let mut asm = Self::new();
+        asm.debug = Some(DebugLayout {
+            symbols,
+            files: HashMap::new(),
+            funcs: HashMap::new(),
+            layouts: Vec::new(),
+            struct_layouts: HashMap::new(),
+        });
+        return asm;
+    }
+
+    pub fn checkpoint(&self) -> AsmCheckpoint {
+        return AsmCheckpoint {
+            func_linkage: self.func_linkage.clone(),
+            functions: self.functions.clone(),
+            var_linkage: self.var_linkage.clone(),
+            vars: self.vars.clone(),
+            data: self.data.clone(),
+            debug: self.debug.clone(),
+        };
+    }
+
+    /// Goes back to `checkpoint`, forgetting everything added since
+    pub fn rollback(&mut self, checkpoint: AsmCheckpoint) {
+        self.func_linkage = checkpoint.func_linkage;
+        self.functions = checkpoint.functions;
+        self.var_linkage = checkpoint.var_linkage;
+        self.vars = checkpoint.vars;
+        self.data = checkpoint.data;
+        self.debug = checkpoint.debug;
+
+        self.function_temps.clear();
+        self.var_temps.clear();
+        self.func.clear();
+        self.file.clear();
+    }
+
+    /// Adds the globals and functions of `tu`. Errors about symbols that another
+    /// translation unit also declares name both files, since the locations alone
+    /// can point into a header that both of them include.
+    ///
+    /// A file can be added again after code is appended to it, like the REPL does
+    /// with each input; what an earlier version of the file already defined at the
+    /// same location is skipped.
+    pub fn add_file(&mut self, files: &FileDb, tu: &TranslationUnit) -> Result<(), Error> {
+        let name = |file: u32| files.name(file).unwrap_or("<unknown>");
+
         self.file.binary_offsets.resize(tu.var_count as usize, !0);
 
         let mut to_init = Vec::new();
 
         for (loc, static_internal) in &tu.static_internal_vars {
+            let added = |v: &ASMVar| v.defn_file == tu.file && v.header.map(|h| h.1) == Some(*loc);
+            if let Some(prev) = self.vars.iter().position(added) {
+                self.file.binary_offsets[static_internal.var_idx as usize] = prev as u32;
+                continue;
+            }
+
             self.file.binary_offsets[static_internal.var_idx as usize] = self.vars.len() as u32;
             let vptr = self.data.reserve(static_internal.ty.size().into());
             let (kind, ty, loc) = (static_internal.init, static_internal.ty, *loc);
@@ -183,6 +449,7 @@ impl Assembler {
                 ty: static_internal.ty,
                 decl_loc: loc,
                 header: Some((vptr, loc)),
+                defn_file: tu.file,
             };
 
             self.vars.push(var);
@@ -211,6 +478,7 @@ impl Assembler {
                         ty: global.ty,
                         decl_loc: global.loc,
                         header: None,
+                        defn_file: tu.file,
                     });
 
                     id
@@ -225,10 +493,23 @@ impl Assembler {
                 TCDeclInit::Default(i) | TCDeclInit::Static(i) | TCDeclInit::ExternInit(i) => i,
             };
 
-            if let Some((_, prev_loc)) = self.vars[prev as usize].header {
+            let prev_var = &self.vars[prev as usize];
+            if let Some((_, prev_loc)) = prev_var.header {
+                if prev_loc == global.loc && prev_var.defn_file == tu.file {
+                    continue;
+                }
+
+                let (first, second) = (name(prev_var.defn_file), name(tu.file));
+                let message = format!(
+                    "defined global variable twice; both {} and {} define it",
+                    first, second
+                );
                 return Err(error!(
-                    "defined global variable twice",
-                    prev_loc, "first definition here", global.loc, "second definition here"
+                    message,
+                    prev_loc,
+                    format!("first definition here, in {}", first),
+                    global.loc,
+                    format!("second definition here, in {}", second)
                 ));
             }
 
@@ -238,15 +519,12 @@ impl Assembler {
             to_init.push((vptr, expr));
 
             self.vars[prev as usize].header = Some((vptr, global.loc));
-        }
-
-        for (vptr, expr) in to_init {
-            self.make_var(vptr, expr)?;
+            self.vars[prev as usize].defn_file = tu.file;
         }
 
         let mut defns = Vec::new();
 
-        for (ident, tc_func) in core::mem::replace(&mut tu.functions, HashMap::new()) {
+        for (&ident, &tc_func) in &tu.functions {
             let link_name = if tc_func.is_static {
                 LinkName::new_static(ident, tu.file)
             } else {
@@ -262,7 +540,9 @@ impl Assembler {
                     self.functions.push(ASMFunc {
                         func_type: tc_func.func_type.clone_into_alloc(&*self.buckets),
                         decl_loc: tc_func.decl_loc,
+                        decl_file: tu.file,
                         func_header: None,
+                        defn_file: tu.file,
                     });
 
                     if let Some(defn) = tc_func.defn {
@@ -274,7 +554,8 @@ impl Assembler {
             };
 
             if prev.func_type != tc_func.func_type {
-                let error = func_decl_mismatch(prev.decl_loc, tc_func.decl_loc);
+                let files = (name(prev.decl_file), name(tu.file));
+                let error = func_decl_mismatch(prev.decl_loc, tc_func.decl_loc, files);
                 return Err(error);
             }
 
@@ -283,10 +564,31 @@ impl Assembler {
             }
         }
 
+        // Initializers can take the address of a function, so they go after
+        // every function has a link name
+        for (vptr, expr) in to_init {
+            self.make_var(vptr, expr)?;
+        }
+
         for (link_name, defn) in defns {
-            let header = self.functions[self.func_linkage[&link_name] as usize].func_header;
-            if let Some((_, defn_loc)) = header.as_ref() {
-                return Err(func_redef(*defn_loc, defn.loc));
+            let prev = &self.functions[self.func_linkage[&link_name] as usize];
+            if let Some((_, defn_loc)) = prev.func_header.as_ref() {
+                // An inline function in a header gets defined again by every
+                // file that includes it; those copies are all the same code.
+                if defn.is_inline && *defn_loc == defn.loc {
+                    continue;
+                }
+
+                // A file that's added again defines everything again. Code
+                // appended to a function that's already there gets assembled
+                // with `add_snippet` instead.
+                let same_start = (defn_loc.file, defn_loc.start) == (defn.loc.file, defn.loc.start);
+                if prev.defn_file == tu.file && same_start {
+                    continue;
+                }
+
+                let files = (name(prev.defn_file), name(tu.file));
+                return Err(func_redef(*defn_loc, defn.loc, files));
             }
 
             let label_count = defn.label_count as usize;
@@ -300,6 +602,14 @@ impl Assembler {
             self.func.opcodes.push(defn.loc);
 
             self.add_function(&defn);
+            if let Some(error) = self.func.error.take() {
+                self.func.clear();
+                return Err(error);
+            }
+
+            if self.peephole.len() != 0 {
+                self.run_peephole(func_temps_begin, var_temps_begin);
+            }
 
             let fptr = self.data.add_data(&mut self.func.opcodes.data);
 
@@ -319,16 +629,46 @@ impl Assembler {
                 self.data.write(ptr, fptr.with_offset(label_offset));
             }
 
-            let header = &mut self.functions[self.func_linkage[&link_name] as usize].func_header;
-            *header = Some((fptr, defn.loc));
+            let func = &mut self.functions[self.func_linkage[&link_name] as usize];
+            func.func_header = Some((fptr, defn.loc));
+            func.defn_file = tu.file;
+
+            if let Some(debug) = &mut self.debug {
+                let func_type = &self.functions[self.func_linkage[&link_name] as usize].func_type;
+                let func = DebugFunc {
+                    file: tu.file.into(),
+                    ptr: fptr,
+                    ret_size: func_type.return_type.repr_size(),
+                    var_offsets: mem::replace(&mut self.func.var_offsets, Vec::new()),
+                    locals: debug_locals(debug, tu, &defn),
+                    stmts: debug_stmts(&defn),
+                };
+                debug.funcs.insert(link_name, func);
+            }
+
             self.func.clear();
         }
 
+        if let Some(debug) = &mut self.debug {
+            let offsets = mem::replace(&mut self.file.binary_offsets, Vec::new());
+            debug.files.insert(tu.file, offsets);
+        }
+
         self.file.clear();
         return Ok(());
     }
 
     pub fn make_var(&mut self, mut ptr: VarPointer, expr: TCExpr) -> Result<VarPointer, Error> {
+        // A function's type has no size, so its address is written as-is; it's
+        // patched into a real pointer once every function has been assembled
+        if let TCExprKind::FunctionIdent { ident } = expr.kind {
+            let link_name = self.file.link_names[&ident];
+            let id = self.func_linkage[&link_name];
+            self.function_temps.push((ptr, expr.loc));
+            self.data.write(ptr, VarPointer::new_binary(0, id));
+            return Ok(ptr.add(8));
+        }
+
         ptr = ptr.align(expr.ty.align().unwrap() as u64);
 
         match expr.kind {
@@ -345,8 +685,7 @@ impl Assembler {
             TCExprKind::F32Lit(i) => self.data.write(ptr, i),
             TCExprKind::F64Lit(i) => self.data.write(ptr, i),
             TCExprKind::StringLit(s) => {
-                let string = self.data.add_slice(s.as_bytes());
-                self.data.data.push(0u8);
+                let string = self.data.add_string(s);
                 self.data.write(ptr, string);
             }
 
@@ -374,6 +713,7 @@ impl Assembler {
                 defn_loc,
                 offset,
                 loc,
+                ..
             }) => {
                 let id = self.file.binary_offsets[binary_offset as usize];
                 self.var_temps.push((ptr, expr.loc));
@@ -389,12 +729,28 @@ impl Assembler {
 
                 return Ok(ptr);
             }
-            TCExprKind::StructLit { fields, size } => {
-                for &field in fields {
-                    ptr = self.make_var(ptr, field)?;
+            TCExprKind::StructLit {
+                fields,
+                offsets,
+                size,
+            } => {
+                for (&field, &(offset, bytes)) in fields.iter().zip(offsets) {
+                    let at = ptr.add(offset as u64);
+
+                    // Packed bitfields can be unaligned, and narrower than their type
+                    match crate::optimizer::int_value(&field.kind) {
+                        Some(value) => {
+                            for idx in 0..(bytes as u64) {
+                                self.data.write(at.add(idx), (value >> (idx * 8)) as u8);
+                            }
+                        }
+                        None => {
+                            self.make_var(at, field)?;
+                        }
+                    }
                 }
 
-                return Ok(ptr);
+                return Ok(ptr.add(size as u64));
             }
 
             x => {
@@ -409,6 +765,78 @@ impl Assembler {
         return Ok(ptr.add(expr.ty.size().unwrap() as u64));
     }
 
+    /// Decodes the function in `self.func`, runs the peephole rules over it, and
+    /// encodes it again, moving labels, jumps, and temps to their new offsets
+    pub fn run_peephole(&mut self, func_temps_begin: usize, var_temps_begin: usize) {
+        let mut labels: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (idx, label) in self.func.labels.iter().enumerate() {
+            let at = labels.entry(label.offset).or_insert_with(Vec::new);
+            at.push(idx as u32);
+        }
+
+        let mut temps = HashMap::new();
+        for (idx, (ptr, _)) in self.function_temps.iter().enumerate() {
+            if idx >= func_temps_begin {
+                temps.insert(ptr.offset(), Temp::Func(idx));
+            }
+        }
+        for (idx, (ptr, _)) in self.var_temps.iter().enumerate() {
+            if idx >= var_temps_begin {
+                temps.insert(ptr.offset(), Temp::Var(idx));
+            }
+        }
+
+        let data = &self.func.opcodes.data;
+        let mut code = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let op = Opcode::from_bytes(&data[pos..(pos + 1)]);
+            let begin = pos + 1;
+            let end = begin + op.operand_size();
+
+            code.push(Instr {
+                op,
+                operand: data[begin..end].to_vec(),
+                labels: labels.remove(&(pos as u32)).unwrap_or_else(Vec::new),
+                temp: temps.get(&(begin as u32)).map(|&t| t),
+            });
+
+            pos = end;
+        }
+
+        let end_labels = labels.remove(&(pos as u32)).unwrap_or_else(Vec::new);
+        let code = peephole(code, &self.peephole);
+
+        let mut data = VecU8::new();
+        self.func.gotos.clear();
+        for instr in &code {
+            let offset = data.data.len() as u32;
+            for &label in &instr.labels {
+                self.func.labels[label as usize].offset = offset;
+            }
+
+            let operand = VarPointer::new_binary(0, offset + 1);
+            match instr.temp {
+                Some(Temp::Func(idx)) => self.function_temps[idx].0 = operand,
+                Some(Temp::Var(idx)) => self.var_temps[idx].0 = operand,
+                None => {}
+            }
+
+            if instr.jump_target().is_some() {
+                self.func.gotos.push(offset + 1);
+            }
+
+            data.push(instr.op);
+            data.data.extend_from_slice(&instr.operand);
+        }
+
+        for label in end_labels {
+            self.func.labels[label as usize].offset = data.data.len() as u32;
+        }
+
+        self.func.opcodes = data;
+    }
+
     pub fn add_function(&mut self, defn: &TCFuncDefn) {
         if defn.ops.len() < 2 {
             unreachable!()
@@ -419,7 +847,8 @@ impl Assembler {
         self.func
             .var_offsets
             .resize(defn.sym_count as usize, i16::MAX);
-        let mut next_offset = 0; // where should the next variable be allocated (relative to fp)?
+        self.func.ops = defn.ops;
+        self.func.next_offset = 0;
 
         for idx in 0..defn.param_count {
             self.func.var_offsets[idx as usize] = -(idx as i16) - 2;
@@ -434,193 +863,194 @@ impl Assembler {
             }
         }
 
+        if self.share_slots {
+            self.func.shared_slots = crate::optimizer::shared_slots(defn);
+        }
+
         if let TCOpcodeKind::ScopeBegin(vars, _) = defn.ops[0].kind {
             self.func.opcodes.push(Opcode::Loc);
             self.func.opcodes.push(defn.ops[0].loc);
+            self.alloc_scope(vars);
+        } else {
+            panic!("idk what happened man");
+        }
 
-            for (&var, &ty) in vars {
-                if self.func.var_offsets[var as usize] < 0 {
-                    continue;
-                }
+        let mut idx = 1;
+        while idx < defn.ops.len() - 1 {
+            idx = self.translate_op(idx);
+        }
 
-                self.func.opcodes.push(Opcode::StackAlloc);
-                self.func.opcodes.push(ty.size());
-                self.func.var_offsets[var as usize] = next_offset;
-                next_offset += 1;
+        let last = defn.ops[defn.ops.len() - 1];
+        if let TCOpcodeKind::ScopeEnd { count, begin } = last.kind {
+            self.func.opcodes.push(Opcode::Loc);
+            self.func.opcodes.push(last.loc);
+
+            let count = self.slot_count(defn.ops, begin);
+            for _ in 0..(count - defn.param_count) {
+                self.func.opcodes.push(Opcode::StackDealloc);
             }
         } else {
-            panic!("idk what happened man");
+            panic!("invariant broken");
         }
 
-        for t_op in &defn.ops[1..(defn.ops.len() - 1)] {
-            match t_op.kind {
-                TCOpcodeKind::ScopeBegin(vars, _) => {
-                    for (&var, &ty) in vars {
-                        self.func.opcodes.push(Opcode::StackAlloc);
-                        self.func.opcodes.push(ty.size());
-
-                        self.func.var_offsets[var as usize] = next_offset;
-                        next_offset += 1;
-                    }
-                }
-                TCOpcodeKind::ScopeEnd { count, begin } => {
-                    for _ in 0..count {
-                        self.func.opcodes.push(Opcode::StackDealloc);
-                    }
+        self.func.opcodes.push(Opcode::Ret);
+    }
 
-                    next_offset -= count as i16;
+    /// Translates `self.func.ops[idx]`, and returns the index of the next op to
+    /// translate. Statement expressions are skipped over; they're translated
+    /// where they're used.
+    fn translate_op(&mut self, idx: usize) -> usize {
+        let ops = self.func.ops;
+        let t_op = &ops[idx];
+        match t_op.kind {
+            TCOpcodeKind::ScopeBegin(vars, _) => self.alloc_scope(vars),
+            TCOpcodeKind::ScopeEnd { count, begin } => {
+                let count = self.slot_count(ops, begin);
+                for _ in 0..count {
+                    self.func.opcodes.push(Opcode::StackDealloc);
                 }
-                TCOpcodeKind::Ret => {
-                    self.func.opcodes.push(Opcode::Loc);
-                    self.func.opcodes.push(t_op.loc);
 
-                    for _ in 0..next_offset {
-                        self.func.opcodes.push(Opcode::StackDealloc);
-                    }
+                self.func.next_offset -= count as i16;
+            }
+            TCOpcodeKind::Ret => {
+                self.func.opcodes.push(Opcode::Loc);
+                self.func.opcodes.push(t_op.loc);
 
-                    self.func.opcodes.push(Opcode::Ret);
+                for _ in 0..self.func.next_offset {
+                    self.func.opcodes.push(Opcode::StackDealloc);
                 }
-                TCOpcodeKind::RetVal(val) => {
-                    self.translate_expr(&val);
 
-                    self.func.opcodes.push(Opcode::Loc);
-                    self.func.opcodes.push(t_op.loc);
+                self.func.opcodes.push(Opcode::Ret);
+            }
+            TCOpcodeKind::RetVal(val) => {
+                self.translate_expr(&val);
 
-                    self.func.opcodes.push(Opcode::MakeFp);
-                    self.func.opcodes.push(-1i16);
-                    self.func.opcodes.push(Opcode::Set);
-                    self.func.opcodes.push(val.ty.repr_size());
+                self.func.opcodes.push(Opcode::Loc);
+                self.func.opcodes.push(t_op.loc);
 
-                    for _ in 0..next_offset {
-                        self.func.opcodes.push(Opcode::StackDealloc);
-                    }
+                self.func.opcodes.push(Opcode::MakeFp);
+                self.func.opcodes.push(-1i16);
+                self.func.opcodes.push(Opcode::Set);
+                self.func.opcodes.push(val.ty.repr_size());
 
-                    self.func.opcodes.push(Opcode::Ret);
+                for _ in 0..self.func.next_offset {
+                    self.func.opcodes.push(Opcode::StackDealloc);
                 }
-                TCOpcodeKind::Expr(expr) => {
-                    self.translate_expr(&expr);
 
-                    let bytes = expr.ty.repr_size();
-                    self.func.opcodes.push(Opcode::Pop);
-                    self.func.opcodes.push(bytes);
-                }
+                self.func.opcodes.push(Opcode::Ret);
+            }
+            TCOpcodeKind::Expr(expr) => {
+                self.translate_expr(&expr);
 
-                TCOpcodeKind::Label { label, .. } => {
-                    self.func.labels[label as usize].offset = self.func.opcodes.data.len() as u32;
-                }
-                TCOpcodeKind::Goto { goto, scope_idx } => {
-                    let label = self.func.labels[goto as usize];
-                    self.solve_scope_difference(defn.ops, scope_idx, label.scope_idx, t_op.loc);
-                    self.func.opcodes.push(Opcode::Jump);
-                    self.func.gotos.push(self.func.opcodes.data.len() as u32);
-                    self.func.opcodes.push(VarPointer::new_binary(0, goto));
-                }
-                TCOpcodeKind::GotoIfZero {
-                    cond,
-                    cond_ty,
-                    goto,
-                    scope_idx,
-                } => {
-                    self.translate_expr(&cond);
-
-                    let op = match cond_ty.size() {
-                        1 => Opcode::JumpIfZero8,
-                        2 => Opcode::JumpIfZero16,
-                        4 => Opcode::JumpIfZero32,
-                        8 => Opcode::JumpIfZero64,
-                        _ => unreachable!(),
-                    };
+                let bytes = expr.ty.repr_size();
+                self.func.opcodes.push(Opcode::Pop);
+                self.func.opcodes.push(bytes);
+            }
 
-                    self.func.opcodes.push(op);
-                    self.func.gotos.push(self.func.opcodes.data.len() as u32);
-                    self.func.opcodes.push(VarPointer::new_binary(0, goto));
-                }
-                TCOpcodeKind::GotoIfNotZero {
-                    cond,
-                    cond_ty,
-                    goto,
-                    scope_idx,
-                } => {
-                    self.translate_expr(&cond);
-
-                    let op = match cond_ty.size() {
-                        1 => Opcode::JumpIfNotZero8,
-                        2 => Opcode::JumpIfNotZero16,
-                        4 => Opcode::JumpIfNotZero32,
-                        8 => Opcode::JumpIfNotZero64,
-                        _ => unreachable!(),
-                    };
+            TCOpcodeKind::Label { label, .. } => {
+                self.func.labels[label as usize].offset = self.func.opcodes.data.len() as u32;
+            }
+            TCOpcodeKind::Goto { goto, scope_idx } => {
+                let label = self.func.labels[goto as usize];
+                self.solve_scope_difference(ops, scope_idx, label.scope_idx, t_op.loc);
+                self.func.opcodes.push(Opcode::Jump);
+                self.func.gotos.push(self.func.opcodes.data.len() as u32);
+                self.func.opcodes.push(VarPointer::new_binary(0, goto));
+            }
+            TCOpcodeKind::GotoIfZero {
+                cond,
+                cond_ty,
+                goto,
+                scope_idx,
+            } => {
+                self.translate_expr(&cond);
 
-                    self.func.opcodes.push(op);
-                    self.func.gotos.push(self.func.opcodes.data.len() as u32);
-                    self.func.opcodes.push(VarPointer::new_binary(0, goto));
-                }
+                let op = match cond_ty.size() {
+                    1 => Opcode::JumpIfZero8,
+                    2 => Opcode::JumpIfZero16,
+                    4 => Opcode::JumpIfZero32,
+                    8 => Opcode::JumpIfZero64,
+                    _ => unreachable!(),
+                };
 
-                TCOpcodeKind::Switch {
-                    expr,
-                    cases,
-                    default,
-                } => {
-                    let bytes = expr.ty.repr_size();
-                    self.translate_expr(&expr);
+                self.func.opcodes.push(op);
+                self.func.gotos.push(self.func.opcodes.data.len() as u32);
+                self.func.opcodes.push(VarPointer::new_binary(0, goto));
+            }
+            TCOpcodeKind::GotoIfNotZero {
+                cond,
+                cond_ty,
+                goto,
+                scope_idx,
+            } => {
+                self.translate_expr(&cond);
 
-                    for (case, take_case) in cases {
-                        let skip_case = self.func.labels.len() as u32;
-                        self.func.labels.push(LabelData::uninit());
+                let op = match cond_ty.size() {
+                    1 => Opcode::JumpIfNotZero8,
+                    2 => Opcode::JumpIfNotZero16,
+                    4 => Opcode::JumpIfNotZero32,
+                    8 => Opcode::JumpIfNotZero64,
+                    _ => unreachable!(),
+                };
 
-                        self.func.opcodes.push(Opcode::Dup);
-                        self.func.opcodes.push(bytes);
-                        self.translate_expr(case);
-
-                        let op = match bytes {
-                            1 => Opcode::CompEq8,
-                            2 => Opcode::CompEq16,
-                            4 => Opcode::CompEq32,
-                            8 => Opcode::CompEq64,
-                            _ => unreachable!(),
-                        };
-                        self.func.opcodes.push(op);
-                        self.func.opcodes.push(Opcode::JumpIfZero8);
-                        self.func.gotos.push(self.func.opcodes.data.len() as u32);
-                        let ptr = VarPointer::new_binary(0, skip_case);
-                        self.func.opcodes.push(ptr);
+                self.func.opcodes.push(op);
+                self.func.gotos.push(self.func.opcodes.data.len() as u32);
+                self.func.opcodes.push(VarPointer::new_binary(0, goto));
+            }
 
-                        self.func.opcodes.push(Opcode::Pop);
-                        self.func.opcodes.push(bytes);
+            TCOpcodeKind::Switch {
+                expr,
+                cases,
+                default,
+            } => {
+                let bytes = expr.ty.repr_size();
+                self.translate_expr(&expr);
 
-                        self.func.opcodes.push(Opcode::Jump);
-                        self.func.gotos.push(self.func.opcodes.data.len() as u32);
-                        let ptr = VarPointer::new_binary(0, *take_case);
-                        self.func.opcodes.push(ptr);
+                for (case, take_case) in cases {
+                    let skip_case = self.func.labels.len() as u32;
+                    self.func.labels.push(LabelData::uninit());
 
-                        self.func.labels[skip_case as usize].offset =
-                            self.func.opcodes.data.len() as u32;
-                    }
+                    self.func.opcodes.push(Opcode::Dup);
+                    self.func.opcodes.push(bytes);
+                    self.translate_expr(case);
+
+                    let op = match bytes {
+                        1 => Opcode::CompEq8,
+                        2 => Opcode::CompEq16,
+                        4 => Opcode::CompEq32,
+                        8 => Opcode::CompEq64,
+                        _ => unreachable!(),
+                    };
+                    self.func.opcodes.push(op);
+                    self.func.opcodes.push(Opcode::JumpIfZero8);
+                    self.func.gotos.push(self.func.opcodes.data.len() as u32);
+                    let ptr = VarPointer::new_binary(0, skip_case);
+                    self.func.opcodes.push(ptr);
 
                     self.func.opcodes.push(Opcode::Pop);
                     self.func.opcodes.push(bytes);
 
                     self.func.opcodes.push(Opcode::Jump);
                     self.func.gotos.push(self.func.opcodes.data.len() as u32);
-                    let ptr = VarPointer::new_binary(0, default);
+                    let ptr = VarPointer::new_binary(0, *take_case);
                     self.func.opcodes.push(ptr);
+
+                    self.func.labels[skip_case as usize].offset =
+                        self.func.opcodes.data.len() as u32;
                 }
-            }
-        }
 
-        let last = defn.ops[defn.ops.len() - 1];
-        if let TCOpcodeKind::ScopeEnd { count, begin } = last.kind {
-            self.func.opcodes.push(Opcode::Loc);
-            self.func.opcodes.push(last.loc);
+                self.func.opcodes.push(Opcode::Pop);
+                self.func.opcodes.push(bytes);
 
-            for _ in 0..(count - defn.param_count) {
-                self.func.opcodes.push(Opcode::StackDealloc);
+                self.func.opcodes.push(Opcode::Jump);
+                self.func.gotos.push(self.func.opcodes.data.len() as u32);
+                let ptr = VarPointer::new_binary(0, default);
+                self.func.opcodes.push(ptr);
             }
-        } else {
-            panic!("invariant broken");
+            TCOpcodeKind::StmtExpr { end } => return end as usize,
         }
 
-        self.func.opcodes.push(Opcode::Ret);
+        return idx + 1;
     }
 
     /// inserts stack deallocs and stack allocs to solve scope problems
@@ -663,20 +1093,63 @@ impl Assembler {
             label_scopes.pop();
         }
 
-        for (_, scope) in goto_scopes {
-            for _ in 0..scope.len() {
+        for (begin, _) in goto_scopes {
+            for _ in 0..self.slot_count(ops, begin) {
                 self.func.opcodes.push(Opcode::StackDealloc);
             }
         }
 
         for (_, scope) in label_scopes.into_iter().rev() {
             for (&var, &ty) in scope {
+                if self.func.shared_slots.contains_key(&var) {
+                    continue;
+                }
+
                 self.func.opcodes.push(Opcode::StackAlloc);
                 self.func.opcodes.push(ty.size());
             }
         }
     }
 
+    /// Allocates stack slots for the locals in a scope, skipping parameters, which
+    /// the caller allocates, and locals that use another local's slot
+    fn alloc_scope(&mut self, vars: HashRef<'static, u32, TCType>) {
+        // Labels are handed out in declaration order, so sorting by them keeps
+        // the layout the same no matter how the scope's variables are hashed
+        let mut sorted: Vec<_> = vars.into_iter().map(|(&var, &ty)| (var, ty)).collect();
+        sorted.sort_by_key(|&(var, _)| var);
+
+        for (var, ty) in sorted {
+            let is_param = self.func.var_offsets[var as usize] < 0;
+            if is_param || self.func.shared_slots.contains_key(&var) {
+                continue;
+            }
+
+            self.func.opcodes.push(Opcode::StackAlloc);
+            self.func.opcodes.push(ty.size());
+            self.func.var_offsets[var as usize] = self.func.next_offset;
+            self.func.next_offset += 1;
+        }
+
+        for (&var, _) in vars {
+            if let Some(&owner) = self.func.shared_slots.get(&var) {
+                self.func.var_offsets[var as usize] = self.func.var_offsets[owner as usize];
+            }
+        }
+    }
+
+    /// How many stack slots the scope that begins at `ops[begin]` takes up
+    fn slot_count(&self, ops: &[TCOpcode], begin: u32) -> u32 {
+        let vars = match ops[begin as usize].kind {
+            TCOpcodeKind::ScopeBegin(vars, _) => vars,
+            _ => panic!("scope_idx pointed to wrong opcode"),
+        };
+
+        let shared = vars.into_iter();
+        let shared = shared.filter(|(var, _)| self.func.shared_slots.contains_key(var));
+        return vars.len() as u32 - shared.count() as u32;
+    }
+
     pub fn translate_expr(&mut self, expr: &TCExpr) {
         match &expr.kind {
             TCExprKind::I8Lit(val) => {
@@ -748,8 +1221,7 @@ impl Assembler {
                 self.func.opcodes.push(Opcode::Loc);
                 self.func.opcodes.push(expr.loc);
 
-                let ptr = self.data.add_slice(val.as_bytes());
-                self.data.data.push(0u8);
+                let ptr = self.data.add_string(val);
                 self.func.opcodes.push(Opcode::Make64);
                 self.func.opcodes.push(ptr);
             }
@@ -796,7 +1268,7 @@ impl Assembler {
 
             TCExprKind::Uninit => {
                 self.func.opcodes.push(Opcode::PushUndef);
-                self.func.opcodes.push(expr.ty.repr_size());
+                self.func.opcodes.push(value_size(&expr.ty));
             }
             TCExprKind::TypePun(expr) => self.translate_expr(expr),
             &TCExprKind::ArrayInit { elems, elem_ty: ty } => {
@@ -805,17 +1277,26 @@ impl Assembler {
                     self.translate_expr(&elem);
                 }
             }
-            TCExprKind::StructLit { fields, size } => {
+            TCExprKind::StructLit {
+                fields,
+                offsets,
+                size,
+            } => {
                 let mut offset = 0;
-                for field in *fields {
-                    let aligned_offset = align_u32(offset, field.ty.align().into());
-                    if aligned_offset > offset {
+                for (field, &(at, bytes)) in fields.iter().zip(*offsets) {
+                    if at > offset {
                         self.func.opcodes.push(Opcode::PushUndef);
-                        self.func.opcodes.push(aligned_offset - offset);
+                        self.func.opcodes.push(at - offset);
                     }
 
+                    // The stack is little endian, so this drops the high bytes
                     self.translate_expr(field);
-                    offset = aligned_offset + field.ty.repr_size();
+                    if bytes < value_size(&field.ty) {
+                        self.func.opcodes.push(Opcode::Pop);
+                        self.func.opcodes.push(value_size(&field.ty) - bytes);
+                    }
+
+                    offset = at + bytes;
                 }
 
                 if offset < *size {
@@ -835,11 +1316,18 @@ impl Assembler {
                 }
             }
 
+            TCExprKind::PostIncr { incr_ty, value } if value.bitfield.is_some() => {
+                self.translate_bitfield_post_op(BinOp::Add, *incr_ty, value, expr.loc);
+            }
+            TCExprKind::PostDecr { decr_ty, value } if value.bitfield.is_some() => {
+                self.translate_bitfield_post_op(BinOp::Sub, *decr_ty, value, expr.loc);
+            }
             TCExprKind::PostIncr { incr_ty, value } => {
                 use TCPrimType::*;
                 self.translate_assign(value);
                 self.func.opcodes.push(Opcode::Loc);
                 self.func.opcodes.push(expr.loc);
+                self.check_target_align(value);
                 self.func.opcodes.push(Opcode::Dup);
                 self.func.opcodes.push(8u32);
 
@@ -864,20 +1352,32 @@ impl Assembler {
                     I32 | U32 => {
                         self.func.opcodes.push(Opcode::Make32);
                         self.func.opcodes.push(1u32);
+                        self.check_overflow(CheckedOp::Add, *incr_ty);
                         self.func.opcodes.push(Opcode::Add32);
                     }
                     I64 | U64 => {
                         self.func.opcodes.push(Opcode::Make64);
                         self.func.opcodes.push(1u64);
+                        self.check_overflow(CheckedOp::Add, *incr_ty);
                         self.func.opcodes.push(Opcode::Add64);
                     }
                     Pointer { stride } => {
                         let stride: u32 = stride.into();
                         self.func.opcodes.push(Opcode::Make64);
                         self.func.opcodes.push(stride as u64);
+                        self.check_in_object(CheckedOp::Add);
                         self.func.opcodes.push(Opcode::Add64);
                     }
-                    x => unimplemented!("post incr for {:?}", x),
+                    F32 => {
+                        self.func.opcodes.push(Opcode::Make32);
+                        self.func.opcodes.push(1f32);
+                        self.func.opcodes.push(Opcode::AddF32);
+                    }
+                    F64 => {
+                        self.func.opcodes.push(Opcode::Make64);
+                        self.func.opcodes.push(1f64);
+                        self.func.opcodes.push(Opcode::AddF64);
+                    }
                 }
 
                 self.func.opcodes.push(Opcode::Swap);
@@ -891,6 +1391,7 @@ impl Assembler {
                 self.translate_assign(value);
                 self.func.opcodes.push(Opcode::Loc);
                 self.func.opcodes.push(expr.loc);
+                self.check_target_align(value);
                 self.func.opcodes.push(Opcode::Dup);
                 self.func.opcodes.push(8u32);
 
@@ -902,9 +1403,26 @@ impl Assembler {
                 self.func.opcodes.push(bytes);
 
                 match decr_ty {
+                    I8 | U8 => {
+                        self.func.opcodes.push(Opcode::Make8);
+                        self.func.opcodes.push(1u8);
+                        match decr_ty {
+                            I8 => self.func.opcodes.push(Opcode::SubI8),
+                            _ => self.func.opcodes.push(Opcode::SubU8),
+                        }
+                    }
+                    I16 | U16 => {
+                        self.func.opcodes.push(Opcode::Make16);
+                        self.func.opcodes.push(1u16);
+                        match decr_ty {
+                            I16 => self.func.opcodes.push(Opcode::SubI16),
+                            _ => self.func.opcodes.push(Opcode::SubU16),
+                        }
+                    }
                     I32 => {
                         self.func.opcodes.push(Opcode::Make32);
                         self.func.opcodes.push(1u32);
+                        self.check_overflow(CheckedOp::Sub, I32);
                         self.func.opcodes.push(Opcode::SubI32);
                     }
                     U32 => {
@@ -915,6 +1433,7 @@ impl Assembler {
                     I64 => {
                         self.func.opcodes.push(Opcode::Make64);
                         self.func.opcodes.push(1u64);
+                        self.check_overflow(CheckedOp::Sub, I64);
                         self.func.opcodes.push(Opcode::SubI64);
                     }
                     U64 => {
@@ -926,9 +1445,19 @@ impl Assembler {
                         let stride: u32 = stride.into();
                         self.func.opcodes.push(Opcode::Make64);
                         self.func.opcodes.push(stride as u64);
+                        self.check_in_object(CheckedOp::Sub);
                         self.func.opcodes.push(Opcode::SubU64);
                     }
-                    x => unimplemented!("post incr for {:?}", x),
+                    F32 => {
+                        self.func.opcodes.push(Opcode::Make32);
+                        self.func.opcodes.push(1f32);
+                        self.func.opcodes.push(Opcode::SubF32);
+                    }
+                    F64 => {
+                        self.func.opcodes.push(Opcode::Make64);
+                        self.func.opcodes.push(1f64);
+                        self.func.opcodes.push(Opcode::SubF64);
+                    }
                 }
 
                 self.func.opcodes.push(Opcode::Swap);
@@ -946,6 +1475,7 @@ impl Assembler {
             } => {
                 self.translate_expr(left);
                 self.translate_expr(right);
+                self.check_provenance(*op, &left.ty, &right.ty, expr.loc);
                 self.translate_bin_op(*op, *op_type, expr.loc);
             }
 
@@ -1079,12 +1609,19 @@ impl Assembler {
                         self.func.opcodes.push(4u32)
                     }
                     (TCPrimType::Pointer { .. }, 8, false) => {}
-                    x => unimplemented!("conversion {:?}", x),
+                    _ => {
+                        let what = format!("conversion from {:?} to {:?}", from, to);
+                        self.unsupported(expr.loc, what);
+                    }
                 }
             }
 
             // TODO fix this with array members
-            &TCExprKind::Member { base, offset } => {
+            &TCExprKind::Member {
+                base,
+                offset,
+                bitfield,
+            } => {
                 self.translate_expr(base);
 
                 self.func.opcodes.push(Opcode::Loc);
@@ -1102,8 +1639,17 @@ impl Assembler {
                     self.func.opcodes.push(Opcode::Pop);
                     self.func.opcodes.push(offset);
                 }
+
+                if let Some(bitfield) = bitfield {
+                    let unit = expr.ty.to_prim_type().unwrap();
+                    self.translate_bitfield_get(bitfield, unit);
+                }
             }
-            &TCExprKind::PtrMember { base, offset } => {
+            &TCExprKind::PtrMember {
+                base,
+                offset,
+                bitfield,
+            } => {
                 self.translate_expr(base);
 
                 self.func.opcodes.push(Opcode::Loc);
@@ -1114,25 +1660,77 @@ impl Assembler {
                 self.func.opcodes.push(offset as u64);
                 self.func.opcodes.push(Opcode::Add64);
                 if !expr.ty.is_array() {
+                    self.check_align(&expr.ty);
                     self.func.opcodes.push(Opcode::Get);
                     self.func.opcodes.push(bytes);
                 }
+
+                if let Some(bitfield) = bitfield {
+                    let unit = expr.ty.to_prim_type().unwrap();
+                    self.translate_bitfield_get(bitfield, unit);
+                }
             }
 
-            TCExprKind::Assign { target, value } => {
+            TCExprKind::Assign { target, value } if target.bitfield.is_some() => {
                 self.translate_expr(value);
 
                 let bytes: u32 = target.ty.size().into();
-                self.func.opcodes.push(Opcode::Dup);
+                self.translate_assign(target);
+
+                self.func.opcodes.push(Opcode::Loc);
+                self.func.opcodes.push(expr.loc);
+                self.check_target_align(target);
+                self.func.opcodes.push(Opcode::Swap);
+                self.func.opcodes.push(8u32);
                 self.func.opcodes.push(bytes);
+
+                let unit = target.ty.to_prim_type().unwrap();
+                self.translate_bitfield_set(target.bitfield.unwrap(), unit);
+            }
+            TCExprKind::Assign { target, value } => {
+                self.translate_expr(value);
+
+                let bytes: u32 = target.ty.size().into();
+                if !target.ty.is_array() {
+                    self.func.opcodes.push(Opcode::Dup);
+                    self.func.opcodes.push(bytes);
+                }
                 self.translate_assign(target);
 
                 self.func.opcodes.push(Opcode::Loc);
                 self.func.opcodes.push(expr.loc);
+                self.check_target_align(target);
                 self.func.opcodes.push(Opcode::Set);
                 self.func.opcodes.push(bytes);
+
+                // arrays are represented by their address, not their elements
+                if target.ty.is_array() {
+                    self.translate_assign(target);
+                }
             }
 
+            TCExprKind::MutAssign {
+                target,
+                value,
+                op,
+                op_type,
+            } if target.bitfield.is_some() => {
+                let (bitfield, unit) =
+                    (target.bitfield.unwrap(), target.ty.to_prim_type().unwrap());
+                self.translate_assign(target);
+                self.check_target_align(target);
+
+                self.func.opcodes.push(Opcode::Dup);
+                self.func.opcodes.push(8u32);
+                self.func.opcodes.push(Opcode::Get);
+                self.func.opcodes.push(target.ty.repr_size());
+                self.translate_bitfield_get(bitfield, unit);
+
+                self.translate_expr(value);
+                self.translate_bin_op(*op, *op_type, expr.loc);
+
+                self.translate_bitfield_set(bitfield, unit);
+            }
             TCExprKind::MutAssign {
                 target,
                 value,
@@ -1140,6 +1738,7 @@ impl Assembler {
                 op_type,
             } => {
                 self.translate_assign(target);
+                self.check_target_align(target);
 
                 self.func.opcodes.push(Opcode::Dup);
                 self.func.opcodes.push(8u32);
@@ -1150,6 +1749,10 @@ impl Assembler {
 
                 self.translate_expr(value);
 
+                // `ptr += n` converts `n` to a pointer, but it's still an offset
+                let offset_ty = TCType::new(TCTypeBase::U64);
+                self.check_provenance(*op, &target.ty, &offset_ty, expr.loc);
+
                 self.translate_bin_op(*op, *op_type, expr.loc);
 
                 self.func.opcodes.push(Opcode::Dup);
@@ -1165,11 +1768,16 @@ impl Assembler {
 
             TCExprKind::Deref(ptr) => {
                 self.translate_expr(ptr);
-                self.func.opcodes.push(Opcode::Loc);
-                self.func.opcodes.push(expr.loc);
 
-                self.func.opcodes.push(Opcode::Get);
-                self.func.opcodes.push(expr.ty.size());
+                // arrays are represented by their address, which is `ptr`
+                if !expr.ty.is_array() {
+                    self.func.opcodes.push(Opcode::Loc);
+                    self.func.opcodes.push(expr.loc);
+                    self.check_align(&expr.ty);
+
+                    self.func.opcodes.push(Opcode::Get);
+                    self.func.opcodes.push(expr.ty.size());
+                }
             }
             TCExprKind::Ref(lvalue) => self.translate_assign(lvalue),
 
@@ -1180,6 +1788,11 @@ impl Assembler {
                 self.func.opcodes.push(Opcode::StackAlloc);
                 self.func.opcodes.push(0u32);
 
+                // Locals of a statement expression in a later argument go
+                // after the arguments that are already on the stack
+                let pending = params.len() as i16 + 1;
+                self.func.next_offset += 1;
+
                 for param in params.iter().rev() {
                     let bytes = param.ty.repr_size();
 
@@ -1190,8 +1803,11 @@ impl Assembler {
                     self.func.opcodes.push(0i16);
                     self.func.opcodes.push(Opcode::Set);
                     self.func.opcodes.push(bytes);
+                    self.func.next_offset += 1;
                 }
 
+                self.func.next_offset -= pending;
+
                 self.func.opcodes.push(Opcode::Loc);
                 self.func.opcodes.push(expr.loc);
 
@@ -1214,6 +1830,25 @@ impl Assembler {
                 self.func.opcodes.push(Opcode::StackDealloc); // for the safety allocation
             }
 
+            TCExprKind::StmtExpr { begin, value } => {
+                let ops = self.func.ops;
+                let end = match ops[*begin as usize].kind {
+                    TCOpcodeKind::StmtExpr { end } => end as usize,
+                    _ => panic!("statement expression pointed to wrong opcode"),
+                };
+
+                let mut idx = *begin as usize + 1;
+                while idx < end {
+                    match ops[idx].kind {
+                        TCOpcodeKind::Expr(expr) if value.opt() == Some(idx as u32) => {
+                            self.translate_expr(&expr); // left on the stack as the result
+                            idx += 1;
+                        }
+                        _ => idx = self.translate_op(idx),
+                    }
+                }
+            }
+
             TCExprKind::Ternary {
                 condition,
                 cond_ty,
@@ -1260,6 +1895,242 @@ impl Assembler {
         }
     }
 
+    /// With the alignment sanitizer on, checks that the pointer on top of the stack is
+    /// aligned for loading or storing a `ty`
+    pub fn check_align(&mut self, ty: &TCType) {
+        if !self.sanitize.alignment || ty.to_prim_type().is_none() {
+            return;
+        }
+
+        let align: u32 = ty.align().into();
+        if align > 1 {
+            self.func.opcodes.push(Opcode::AssertAlign);
+            self.func.opcodes.push(align);
+        }
+    }
+
+    /// With the signed overflow sanitizer on, checks that `op` on the operands on top
+    /// of the stack won't overflow. Narrower types are promoted to `int` before
+    /// arithmetic, so only `int` and `long` can overflow.
+    pub fn check_overflow(&mut self, op: CheckedOp, op_type: TCPrimType) {
+        let bytes: u32 = match op_type {
+            TCPrimType::I32 => 4,
+            TCPrimType::I64 => 8,
+            _ => return,
+        };
+
+        if self.sanitize.signed_overflow {
+            self.func.opcodes.push(Opcode::AssertNoOverflow);
+            self.func.opcodes.push(op);
+            self.func.opcodes.push(bytes);
+        }
+    }
+
+    /// With the pointer provenance sanitizer on, checks that `op` on the operands on
+    /// top of the stack doesn't move a pointer into a different object, and that
+    /// pointers that are subtracted or ordered point into the same object
+    pub fn check_provenance(&mut self, op: BinOp, left: &TCType, right: &TCType, loc: CodeLoc) {
+        if !self.sanitize.pointer_provenance {
+            return;
+        }
+
+        let is_ptr = |ty: &TCType| ty.is_pointer() || ty.is_array();
+        let in_object = match (op, is_ptr(left), is_ptr(right)) {
+            (BinOp::Add, true, false) => Some(CheckedOp::Add),
+            (BinOp::Sub, true, false) => Some(CheckedOp::Sub),
+            (BinOp::Sub, true, true) | (BinOp::Lt, true, true) | (BinOp::Gt, true, true) => None,
+            (BinOp::Leq, true, true) | (BinOp::Geq, true, true) => None,
+            _ => return,
+        };
+
+        self.func.opcodes.push(Opcode::Loc);
+        self.func.opcodes.push(loc);
+        match in_object {
+            Some(op) => self.check_in_object(op),
+            None => self.func.opcodes.push(Opcode::AssertSameObject),
+        }
+    }
+
+    /// With the pointer provenance sanitizer on, checks that moving the pointer on
+    /// top of the stack by the byte offset above it keeps it in the same object
+    pub fn check_in_object(&mut self, op: CheckedOp) {
+        if self.sanitize.pointer_provenance {
+            self.func.opcodes.push(Opcode::AssertInObject);
+            self.func.opcodes.push(op);
+        }
+    }
+
+    /// Locals and globals are always aligned, so only targets that go through a
+    /// pointer need checking
+    pub fn check_target_align(&mut self, target: &TCAssignTarget) {
+        if let TCAssignTargetKind::Ptr(_) = target.kind {
+            self.check_align(&target.ty);
+        }
+    }
+
+    /// Pushes a constant the size of a bitfield's storage unit
+    fn push_unit_const(&mut self, unit: TCPrimType, value: u64) {
+        match unit.size() {
+            1 => {
+                self.func.opcodes.push(Opcode::Make8);
+                self.func.opcodes.push(value as u8);
+            }
+            2 => {
+                self.func.opcodes.push(Opcode::Make16);
+                self.func.opcodes.push(value as u16);
+            }
+            4 => {
+                self.func.opcodes.push(Opcode::Make32);
+                self.func.opcodes.push(value as u32);
+            }
+            _ => {
+                self.func.opcodes.push(Opcode::Make64);
+                self.func.opcodes.push(value);
+            }
+        }
+    }
+
+    /// Shifts the bitfield in the storage unit on top of the stack to the
+    /// bottom of the unit, clearing the bits above it (or copying the sign bit
+    /// into them if the field is signed)
+    fn shift_bitfield_down(&mut self, bitfield: TCBitfield, unit: TCPrimType) {
+        use TCPrimType::*;
+        let unit_bits = unit.size() as u32 * 8;
+        let (left, right) = (
+            unit_bits - bitfield.offset - bitfield.width,
+            unit_bits - bitfield.width,
+        );
+
+        let (lshift, rshift) = match unit {
+            I8 => (Opcode::LShiftU8, Opcode::RShiftI8),
+            U8 => (Opcode::LShiftU8, Opcode::RShiftU8),
+            I16 => (Opcode::LShiftU16, Opcode::RShiftI16),
+            U16 => (Opcode::LShiftU16, Opcode::RShiftU16),
+            I32 => (Opcode::LShiftU32, Opcode::RShiftI32),
+            U32 => (Opcode::LShiftU32, Opcode::RShiftU32),
+            I64 => (Opcode::LShiftU64, Opcode::RShiftI64),
+            U64 => (Opcode::LShiftU64, Opcode::RShiftU64),
+            x => unreachable!("bitfield of type {:?}", x),
+        };
+
+        if left != 0 {
+            self.func.opcodes.push(Opcode::Make8);
+            self.func.opcodes.push(left as u8);
+            self.func.opcodes.push(lshift);
+        }
+
+        if right != 0 {
+            self.func.opcodes.push(Opcode::Make8);
+            self.func.opcodes.push(right as u8);
+            self.func.opcodes.push(rshift);
+        }
+    }
+
+    /// Turns the storage unit on top of the stack into the value of the
+    /// bitfield inside it
+    pub fn translate_bitfield_get(&mut self, bitfield: TCBitfield, unit: TCPrimType) {
+        self.shift_bitfield_down(bitfield, unit);
+    }
+
+    /// Stores the value on top of the stack into the bitfield that the pointer
+    /// below it points to, without touching the other bits of the storage
+    /// unit. Leaves the value the bitfield ends up with, i.e. the value
+    /// truncated to the bitfield's width.
+    pub fn translate_bitfield_set(&mut self, bitfield: TCBitfield, unit: TCPrimType) {
+        let bytes = unit.size() as u32;
+        let (and, or, shift) = match bytes {
+            1 => (Opcode::BitAnd8, Opcode::BitOr8, Opcode::LShiftU8),
+            2 => (Opcode::BitAnd16, Opcode::BitOr16, Opcode::LShiftU16),
+            4 => (Opcode::BitAnd32, Opcode::BitOr32, Opcode::LShiftU32),
+            _ => (Opcode::BitAnd64, Opcode::BitOr64, Opcode::LShiftU64),
+        };
+
+        let mask = match bitfield.width {
+            64 => !0u64,
+            width => (1u64 << width) - 1,
+        };
+
+        // ptr, value -> ptr, truncated
+        let truncate = TCBitfield {
+            offset: 0,
+            width: bitfield.width,
+        };
+        self.shift_bitfield_down(truncate, unit);
+
+        // -> ptr, truncated, bits in place
+        self.func.opcodes.push(Opcode::Dup);
+        self.func.opcodes.push(bytes);
+        self.push_unit_const(unit, mask);
+        self.func.opcodes.push(and);
+        if bitfield.offset != 0 {
+            self.func.opcodes.push(Opcode::Make8);
+            self.func.opcodes.push(bitfield.offset as u8);
+            self.func.opcodes.push(shift);
+        }
+
+        // -> truncated, bits in place, ptr, old unit with the field cleared
+        self.func.opcodes.push(Opcode::Swap);
+        self.func.opcodes.push(bytes * 2);
+        self.func.opcodes.push(8u32);
+        self.func.opcodes.push(Opcode::Dup);
+        self.func.opcodes.push(8u32);
+        self.func.opcodes.push(Opcode::Get);
+        self.func.opcodes.push(bytes);
+        self.push_unit_const(unit, !(mask << bitfield.offset));
+        self.func.opcodes.push(and);
+
+        // -> truncated, ptr, new unit
+        self.func.opcodes.push(Opcode::Swap);
+        self.func.opcodes.push(bytes + 8);
+        self.func.opcodes.push(bytes);
+        self.func.opcodes.push(or);
+
+        self.func.opcodes.push(Opcode::Swap);
+        self.func.opcodes.push(bytes);
+        self.func.opcodes.push(8u32);
+        self.func.opcodes.push(Opcode::Set);
+        self.func.opcodes.push(bytes);
+    }
+
+    /// `x++` and `x--` on a bitfield
+    fn translate_bitfield_post_op(
+        &mut self,
+        op: BinOp,
+        op_type: TCPrimType,
+        target: &TCAssignTarget,
+        loc: CodeLoc,
+    ) {
+        let bitfield = target.bitfield.unwrap();
+        let bytes = op_type.size() as u32;
+
+        self.translate_assign(target);
+        self.func.opcodes.push(Opcode::Loc);
+        self.func.opcodes.push(loc);
+        self.check_target_align(target);
+
+        // ptr -> old, ptr, old
+        self.func.opcodes.push(Opcode::Dup);
+        self.func.opcodes.push(8u32);
+        self.func.opcodes.push(Opcode::Get);
+        self.func.opcodes.push(bytes);
+        self.translate_bitfield_get(bitfield, op_type);
+        self.func.opcodes.push(Opcode::Dup);
+        self.func.opcodes.push(bytes);
+        self.func.opcodes.push(Opcode::Swap);
+        self.func.opcodes.push(bytes * 2);
+        self.func.opcodes.push(8u32);
+        self.func.opcodes.push(Opcode::Swap);
+        self.func.opcodes.push(8u32);
+        self.func.opcodes.push(bytes);
+
+        self.push_unit_const(op_type, 1);
+        self.translate_bin_op(op, op_type, loc);
+        self.translate_bitfield_set(bitfield, op_type);
+
+        self.func.opcodes.push(Opcode::Pop);
+        self.func.opcodes.push(bytes);
+    }
+
     pub fn translate_assign(&mut self, assign: &TCAssignTarget) {
         match assign.kind {
             TCAssignTargetKind::Ptr(expr) => {
@@ -1304,6 +2175,15 @@ impl Assembler {
         }
     }
 
+    /// Records that the expression at `loc` can't be translated; the type checker
+    /// should've rejected it. Only the first one is reported.
+    fn unsupported(&mut self, loc: CodeLoc, what: String) {
+        if self.func.error.is_none() {
+            let label = format!("{} isn't supported", what);
+            self.func.error = Some(error!("couldn't compile expression", loc, label));
+        }
+    }
+
     pub fn translate_un_op(&mut self, op: TCUnaryOp, op_type: TCPrimType, loc: CodeLoc) {
         use TCPrimType::*;
         use TCUnaryOp::*;
@@ -1345,6 +2225,7 @@ impl Assembler {
                 self.func.opcodes.push(Opcode::Add16);
             }
             (Neg, I32) => {
+                self.check_overflow(CheckedOp::Neg, I32);
                 self.func.opcodes.push(Opcode::Make32);
                 self.func.opcodes.push(-1i32);
                 self.func.opcodes.push(Opcode::MulI32);
@@ -1361,6 +2242,7 @@ impl Assembler {
                 self.func.opcodes.push(Opcode::Add32);
             }
             (Neg, I64) => {
+                self.check_overflow(CheckedOp::Neg, I64);
                 self.func.opcodes.push(Opcode::Make64);
                 self.func.opcodes.push(-1i64);
                 self.func.opcodes.push(Opcode::MulI64);
@@ -1396,6 +2278,16 @@ impl Assembler {
             (BoolNorm, I64) => self.func.opcodes.push(Opcode::BoolNorm64),
             (BoolNorm, U64) => self.func.opcodes.push(Opcode::BoolNorm64),
             (BoolNorm, Pointer { .. }) => self.func.opcodes.push(Opcode::BoolNorm64),
+            (BoolNorm, F32) => {
+                self.func.opcodes.push(Opcode::Make32);
+                self.func.opcodes.push(0f32);
+                self.func.opcodes.push(Opcode::CompNeqF32);
+            }
+            (BoolNorm, F64) => {
+                self.func.opcodes.push(Opcode::Make64);
+                self.func.opcodes.push(0f64);
+                self.func.opcodes.push(Opcode::CompNeqF64);
+            }
 
             (BoolNot, I8) => self.func.opcodes.push(Opcode::BoolNot8),
             (BoolNot, U8) => self.func.opcodes.push(Opcode::BoolNot8),
@@ -1406,6 +2298,16 @@ impl Assembler {
             (BoolNot, I64) => self.func.opcodes.push(Opcode::BoolNot64),
             (BoolNot, U64) => self.func.opcodes.push(Opcode::BoolNot64),
             (BoolNot, Pointer { .. }) => self.func.opcodes.push(Opcode::BoolNot64),
+            (BoolNot, F32) => {
+                self.func.opcodes.push(Opcode::Make32);
+                self.func.opcodes.push(0f32);
+                self.func.opcodes.push(Opcode::CompEqF32);
+            }
+            (BoolNot, F64) => {
+                self.func.opcodes.push(Opcode::Make64);
+                self.func.opcodes.push(0f64);
+                self.func.opcodes.push(Opcode::CompEqF64);
+            }
 
             (BitNot, I8) => self.func.opcodes.push(Opcode::BitNot8),
             (BitNot, U8) => self.func.opcodes.push(Opcode::BitNot8),
@@ -1416,7 +2318,7 @@ impl Assembler {
             (BitNot, I64) => self.func.opcodes.push(Opcode::BitNot64),
             (BitNot, U64) => self.func.opcodes.push(Opcode::BitNot64),
 
-            x => unimplemented!("unary op: {:?}", x),
+            (op, op_type) => self.unsupported(loc, format!("{:?} on {:?}", op, op_type)),
         }
     }
 
@@ -1424,6 +2326,15 @@ impl Assembler {
         self.func.opcodes.push(Opcode::Loc);
         self.func.opcodes.push(loc);
 
+        match op {
+            BinOp::Add => self.check_overflow(CheckedOp::Add, op_type),
+            BinOp::Sub => self.check_overflow(CheckedOp::Sub, op_type),
+            BinOp::Mul => self.check_overflow(CheckedOp::Mul, op_type),
+            BinOp::Div => self.check_overflow(CheckedOp::Div, op_type),
+            BinOp::Mod => self.check_overflow(CheckedOp::Mod, op_type),
+            _ => {}
+        }
+
         let op = match (op, op_type) {
             (BinOp::Add, TCPrimType::I8) => Opcode::Add8,
             (BinOp::Add, TCPrimType::U8) => Opcode::Add8,
@@ -1709,69 +2620,1041 @@ impl Assembler {
             (BinOp::BitXor, TCPrimType::I64) => Opcode::BitXor64,
             (BinOp::BitXor, TCPrimType::U64) => Opcode::BitXor64,
 
-            (op, ptype) => unimplemented!("op={:?} type={:?}", op, ptype),
+            (op, op_type) => {
+                self.unsupported(loc, format!("{:?} on {:?}", op, op_type));
+                return;
+            }
         };
 
         self.func.opcodes.push(op);
     }
 
-    pub fn assemble(mut self, env: &FileDb) -> Result<BinaryData, Error> {
-        let no_main = || error!("missing main function definition");
+    /// Only meaningful after every file has been added; `None` unless the assembler
+    /// was created with `with_debug_info`
+    pub fn debug_info(&mut self) -> Option<DebugInfo> {
+        let DebugLayout {
+            symbols,
+            files,
+            mut funcs,
+            layouts,
+            ..
+        } = self.debug.take()?;
+
+        let header = |h: Option<(VarPointer, CodeLoc)>| {
+            h.map(|h| h.0).unwrap_or(VarPointer::new_binary(0, 0))
+        };
+        let vars = self.vars.iter().map(|v| header(v.header)).collect();
 
-        let main_link_name = LinkName {
-            name: BuiltinSymbol::Main as u32,
+        let undefined = DebugFunc {
             file: n32::NULL,
+            ptr: VarPointer::new_binary(0, 0),
+            ret_size: 0,
+            var_offsets: Vec::new(),
+            locals: Vec::new(),
+            stmts: Vec::new(),
         };
+        let mut by_id = Vec::new();
+        by_id.resize(self.functions.len(), undefined);
 
-        let main_func_idx = self.func_linkage.get(&main_link_name).ok_or_else(no_main)?;
-
-        let main_func = &self.functions[*main_func_idx as usize];
-        let (main_ptr, main_loc) = main_func.func_header.ok_or_else(no_main)?;
+        for (link_name, &id) in &self.func_linkage {
+            if let Some(func) = funcs.remove(link_name) {
+                by_id[id as usize] = func;
+            }
+        }
 
-        self.data.write(BINARY_INIT.main_call, main_ptr);
+        return Some(DebugInfo {
+            symbols,
+            files,
+            vars,
+            func_linkage: self.func_linkage.clone(),
+            funcs: by_id,
+            layouts,
+        });
+    }
 
-        for (temp, loc) in &self.var_temps {
-            let ptr: VarPointer = self.data.read(*temp).unwrap();
-            let offset = ptr.offset();
-            let var = &self.vars[ptr.var_idx()];
-            if let Some((vptr, _loc)) = var.header {
-                self.data.write(*temp, vptr.add(offset as u64));
+    /// Assembles `expr`, which was checked as though it were inside `func`, into a
+    /// standalone piece of code appended to `data`, the binary of a running program.
+    /// Returns the new binary along with a pointer to the code and its length.
+    pub fn assemble_expr(
+        debug: &DebugInfo,
+        tu: &TranslationUnit,
+        func: LinkName,
+        data: BinaryData,
+        expr: &TCExpr,
+    ) -> Result<(BinaryData, VarPointer, u32), Error> {
+        let mut asm = Self::new();
+        asm.data = data;
+        asm.func_linkage = debug.func_linkage.clone();
+
+        let no_file = || error!("no debug info for the current file");
+        asm.file.binary_offsets = debug.files.get(&tu.file).ok_or_else(no_file)?.clone();
+
+        for (&ident, tc_func) in &tu.functions {
+            let link_name = if tc_func.is_static {
+                LinkName::new_static(ident, tu.file)
             } else {
+                LinkName::new(ident)
+            };
+
+            if !asm.func_linkage.contains_key(&link_name) {
+                return Err(error!(
+                    "function doesn't exist in the program",
+                    tc_func.decl_loc, "declared here"
+                ));
+            }
+
+            asm.file.link_names.insert(ident, link_name);
+        }
+
+        let no_func = || error!("no debug info for the current function");
+        let id = *debug.func_linkage.get(&func).ok_or_else(no_func)?;
+        asm.func.var_offsets = debug.funcs[id as usize].var_offsets.clone();
+
+        asm.translate_expr(expr);
+        if let Some(error) = asm.func.error.take() {
+            return Err(error);
+        }
+
+        let len = asm.func.opcodes.data.len() as u32;
+        let fptr = asm.data.add_data(&mut asm.func.opcodes.data);
+
+        for &goto in asm.func.gotos.iter() {
+            let ptr = fptr.with_offset(goto);
+            let label_ptr: VarPointer = asm.data.read(ptr).unwrap();
+            let label_offset = asm.func.labels[label_ptr.offset() as usize].offset;
+            asm.data.write(ptr, fptr.with_offset(label_offset));
+        }
+
+        for &(temp, loc) in &asm.var_temps {
+            let temp = fptr.with_offset(temp.offset());
+            let ptr: VarPointer = asm.data.read(temp).unwrap();
+            let vptr = debug.vars[ptr.var_idx()];
+            if vptr.var_idx() == 0 {
                 return Err(error!(
                     "couldn't find definition for variable",
-                    *loc, "used here", var.decl_loc, "declared here"
+                    loc, "used here"
                 ));
             }
+
+            asm.data.write(temp, vptr.add(ptr.offset() as u64));
         }
 
-        for (temp, loc) in &self.function_temps {
-            let ptr: VarPointer = self.data.read(*temp).unwrap();
-            let function = &self.functions[ptr.offset() as usize];
-            if let Some((fptr, _loc)) = function.func_header {
-                self.data.write(*temp, fptr);
-            } else {
-                let func_loc = function.decl_loc;
+        for &(temp, loc) in &asm.function_temps {
+            let temp = fptr.with_offset(temp.offset());
+            let ptr: VarPointer = asm.data.read(temp).unwrap();
+            let fptr = debug.funcs[ptr.offset() as usize].ptr;
+            if fptr.var_idx() == 0 {
                 return Err(error!(
                     "couldn't find definition for function",
-                    *loc, "called here", func_loc, "declared here"
+                    loc, "called here"
                 ));
             }
+
+            asm.data.write(temp, fptr);
+        }
+
+        let data = mem::replace(&mut asm.data, BinaryData::new());
+        return Ok((data, fptr, len));
+    }
+
+    /// Assembles `ops` of the function `ident` in `tu`, statements at the top of a
+    /// function whose file has been added already, into a standalone piece of code that runs in the function's
+    /// frame while it's paused in between statements; this is how the REPL runs
+    /// each input. Locals the statements declare at the top of the function get
+    /// stack slots after the ones the function already has. With `keep_value`, the
+    /// last statement is an expression, and its value is left on the stack.
+    ///
+    /// Returns a pointer to the code and its length. Uses of globals and functions
+    /// in it still have to be linked.
+    pub fn add_snippet(
+        &mut self,
+        tu: &TranslationUnit,
+        ident: u32,
+        ops: Range<usize>,
+        keep_value: bool,
+    ) -> (VarPointer, u32) {
+        let debug = self.debug.as_ref().expect("snippets need debug info");
+        self.file.binary_offsets = debug.files[&tu.file].clone();
+        for (&ident, tc_func) in &tu.functions {
+            let link_name = if tc_func.is_static {
+                LinkName::new_static(ident, tu.file)
+            } else {
+                LinkName::new(ident)
+            };
+
+            self.file.link_names.insert(ident, link_name);
+        }
+
+        let link_name = self.file.link_names[&ident];
+        let defn = tu.functions[&ident]
+            .defn
+            .expect("snippets go in a defined function");
+        let func = &debug.funcs[&link_name];
+        self.func.var_offsets = func.var_offsets.clone();
+        self.func
+            .var_offsets
+            .resize(defn.sym_count as usize, i16::MAX);
+        self.func.ops = defn.ops;
+
+        self.func
+            .labels
+            .resize(defn.label_count as usize, LabelData::uninit());
+        for t_op in defn.ops {
+            if let TCOpcodeKind::Label { label, scope_idx } = t_op.kind {
+                self.func.labels[label as usize].scope_idx = scope_idx;
+            }
+        }
+
+        let func_temps_begin = self.function_temps.len();
+        let var_temps_begin = self.var_temps.len();
+
+        let vars = match defn.ops[0].kind {
+            TCOpcodeKind::ScopeBegin(vars, _) => vars,
+            _ => panic!("idk what happened man"),
+        };
+        let mut sorted: Vec<_> = vars.into_iter().map(|(&var, &ty)| (var, ty)).collect();
+        sorted.sort_by_key(|&(var, _)| var);
+
+        // The function's scope was allocated when it was called, so only the
+        // locals that are new to it need slots
+        let offsets = sorted
+            .iter()
+            .map(|&(var, _)| self.func.var_offsets[var as usize]);
+        self.func.next_offset = offsets.filter(|&o| o >= 0 && o != i16::MAX).count() as i16;
+        for (var, ty) in sorted {
+            if self.func.var_offsets[var as usize] != i16::MAX {
+                continue;
+            }
+
+            self.func.opcodes.push(Opcode::StackAlloc);
+            self.func.opcodes.push(ty.size());
+            self.func.var_offsets[var as usize] = self.func.next_offset;
+            self.func.next_offset += 1;
+        }
+
+        let mut idx = ops.start;
+        while idx < ops.end {
+            match defn.ops[idx].kind {
+                TCOpcodeKind::Expr(expr) if keep_value && idx + 1 == ops.end => {
+                    self.translate_expr(&expr); // left on the stack as the result
+                    idx += 1;
+                }
+                _ => idx = self.translate_op(idx),
+            }
+        }
+
+        let len = self.func.opcodes.data.len() as u32;
+        let fptr = self.data.add_data(&mut self.func.opcodes.data);
+
+        for (ptr, _) in &mut self.function_temps[func_temps_begin..] {
+            *ptr = fptr.with_offset(ptr.offset());
+        }
+        for (ptr, _) in &mut self.var_temps[var_temps_begin..] {
+            *ptr = fptr.with_offset(ptr.offset());
+        }
+
+        for &goto in self.func.gotos.iter() {
+            let ptr = fptr.with_offset(goto);
+            let label_ptr: VarPointer = self.data.read(ptr).unwrap();
+            let label_offset = self.func.labels[label_ptr.offset() as usize].offset;
+            self.data.write(ptr, fptr.with_offset(label_offset));
+        }
+
+        let debug = self.debug.as_mut().unwrap();
+        let locals = debug_locals(debug, tu, &defn);
+        let func = debug.funcs.get_mut(&link_name).unwrap();
+        func.var_offsets = mem::replace(&mut self.func.var_offsets, Vec::new());
+        func.locals = locals;
+        func.stmts = debug_stmts(&defn);
+
+        self.func.clear();
+        self.file.clear();
+        return (fptr, len);
+    }
+
+    /// Points every use of a global or function at its definition, which might be
+    /// in another file. Anything that's declared (e.g. with `extern`) but never
+    /// defined gets one error that lists every place it's used. Uses are forgotten
+    /// once they're linked, so linking again only links code added since.
+    pub fn link(&mut self) -> Result<(), Vec<Error>> {
+        let mut missing_vars: Vec<(usize, Vec<CodeLoc>)> = Vec::new();
+        for &(temp, loc) in &self.var_temps {
+            let ptr: VarPointer = self.data.read(temp).unwrap();
+            let var = &self.vars[ptr.var_idx()];
+            if let Some((vptr, _loc)) = var.header {
+                self.data.write(temp, vptr.add(ptr.offset() as u64));
+                continue;
+            }
+
+            match missing_vars
+                .iter_mut()
+                .find(|(idx, _)| *idx == ptr.var_idx())
+            {
+                Some((_, uses)) => uses.push(loc),
+                None => missing_vars.push((ptr.var_idx(), vec![loc])),
+            }
         }
 
+        let mut missing_funcs: Vec<(usize, Vec<CodeLoc>)> = Vec::new();
+        for &(temp, loc) in &self.function_temps {
+            let ptr: VarPointer = self.data.read(temp).unwrap();
+            let function = &self.functions[ptr.offset() as usize];
+            if let Some((fptr, _loc)) = function.func_header {
+                self.data.write(temp, fptr);
+                continue;
+            }
+
+            let idx = ptr.offset() as usize;
+            match missing_funcs.iter_mut().find(|(i, _)| *i == idx) {
+                Some((_, uses)) => uses.push(loc),
+                None => missing_funcs.push((idx, vec![loc])),
+            }
+        }
+
+        let mut errors = Vec::new();
+        for (idx, uses) in missing_vars {
+            let message = "couldn't find definition for variable";
+            let decl_loc = self.vars[idx].decl_loc;
+            errors.push(missing_definition(message, decl_loc, "used here", &uses));
+        }
+
+        for (idx, uses) in missing_funcs {
+            let message = "couldn't find definition for function";
+            let decl_loc = self.functions[idx].decl_loc;
+            errors.push(missing_definition(message, decl_loc, "called here", &uses));
+        }
+
+        if errors.len() != 0 {
+            return Err(errors);
+        }
+
+        self.var_temps.clear();
+        self.function_temps.clear();
+        return Ok(());
+    }
+
+    /// Links the program, starting it at `main`, or at `self.entry` if it's set.
+    /// `symbols` are only used to name functions in errors.
+    pub fn assemble(mut self, env: &FileDb, symbols: &Symbols) -> Result<BinaryData, Vec<Error>> {
+        self.link_program(env, symbols)?;
         return Ok(mem::replace(&mut self.data, BinaryData::new()));
     }
+
+    /// Like `assemble`, but leaves the program in `self.data`, so that more code can
+    /// be added to it afterwards
+    pub fn link_program(&mut self, env: &FileDb, symbols: &Symbols) -> Result<(), Vec<Error>> {
+        if let Some(entry) = self.entry.take() {
+            let entry_ptr = self.entry_func(&entry).map_err(|e| vec![e])?;
+            self.data.write(BINARY_INIT.main_call, entry_ptr);
+            return self.link();
+        }
+
+        let main_link_name = LinkName {
+            name: BuiltinSymbol::Main as u32,
+            file: n32::NULL,
+        };
+
+        let main_func = self.func_linkage.get(&main_link_name);
+        let main_func = main_func.map(|&idx| &self.functions[idx as usize]);
+        let (main_ptr, main_loc) = match main_func.and_then(|f| f.func_header) {
+            Some(header) => header,
+            None => return Err(vec![self.missing_main(env, symbols)]),
+        };
+
+        self.data.write(BINARY_INIT.main_call, main_ptr);
+
+        return self.link();
+    }
+
+    /// Error for a program without a `main` to start at. Lists the functions the
+    /// program does define, and points out a `main` that's static, declared but
+    /// never defined, or misspelled.
+    fn missing_main(&self, files: &FileDb, symbols: &Symbols) -> Error {
+        let main = BuiltinSymbol::Main as u32;
+        let mut defined = Vec::new();
+        let mut sections = Vec::new();
+        for (link_name, &idx) in &self.func_linkage {
+            let func = &self.functions[idx as usize];
+            let loc = match func.func_header {
+                Some((_, loc)) if !files.is_system(func.defn_file) => loc,
+                None if link_name.name == main => {
+                    let message = "`main` is declared here, but never defined".to_string();
+                    sections.push((
+                        0,
+                        ErrorSection {
+                            location: func.decl_loc,
+                            message,
+                        },
+                    ));
+                    continue;
+                }
+                _ => continue,
+            };
+
+            let name = symbols.to_str(link_name.name).unwrap();
+            defined.push((name, loc));
+
+            if link_name.name == main {
+                let message = "`main` is static here, so the program can't start at it";
+                let message = message.to_string();
+                sections.push((
+                    0,
+                    ErrorSection {
+                        location: loc,
+                        message,
+                    },
+                ));
+                continue;
+            }
+
+            // Close enough to be a typo, but not so short that `max` counts
+            let distance = edit_distance(&name.to_lowercase(), "main");
+            if name.len() >= 4 && distance <= 2 {
+                let message = format!("`{}` is defined here; did you mean `main`?", name);
+                sections.push((
+                    distance,
+                    ErrorSection {
+                        location: loc,
+                        message,
+                    },
+                ));
+            }
+        }
+
+        defined.sort_by(|a, b| (a.0, a.1.file, a.1.start).cmp(&(b.0, b.1.file, b.1.start)));
+        let names = defined.iter().take(MAX_LISTED_FUNCS);
+        let mut names: Vec<String> = names.map(|(name, _)| format!("`{}`", name)).collect();
+        if defined.len() > MAX_LISTED_FUNCS {
+            names.push(format!("and {} more", defined.len() - MAX_LISTED_FUNCS));
+        }
+
+        let mut message = "missing main function definition".to_string();
+        message += &match names.len() {
+            0 => "; the program doesn't define any functions".to_string(),
+            _ => format!("; the program defines {}", names.join(", ")),
+        };
+
+        let mut err = error!(message);
+        sections.sort_by_key(|(distance, section)| (*distance, section.location.start));
+        err.sections
+            .extend(sections.into_iter().map(|(_, section)| section));
+        return err;
+    }
+
+    /// Adds a function that takes the place of `main`: it calls `entry.name` with
+    /// `entry.args`, passes what it returns to `__tci_print_entry`, and returns 0
+    fn entry_func(&mut self, entry: &EntryPoint) -> Result<VarPointer, Error> {
+        let no_func = || error!(format!("no function named `{}`", entry.name));
+        let ident = entry.ident.ok_or_else(no_func)?;
+
+        // Static functions are fine too, as long as there's only one of them
+        let global = LinkName {
+            name: ident,
+            file: n32::NULL,
+        };
+        let link_name = match self.func_linkage.contains_key(&global) {
+            true => global,
+            false => {
+                let statics = self.func_linkage.keys().filter(|l| l.name == ident);
+                let statics: Vec<LinkName> = statics.map(|l| *l).collect();
+                if statics.len() > 1 {
+                    let message =
+                        format!("more than one static function is named `{}`", entry.name);
+                    return Err(error!(message));
+                }
+
+                *statics.first().ok_or_else(no_func)?
+            }
+        };
+
+        let idx = self.func_linkage[&link_name];
+        let func = &self.functions[idx as usize];
+        let (func_ptr, func_loc) = func.func_header.ok_or_else(|| {
+            let message = format!("`{}` is declared but never defined", entry.name);
+            error!(message, func.decl_loc, "declared here")
+        })?;
+
+        let params = func.func_type.params.map(|p| p.types).unwrap_or(&[]);
+        let varargs = func.func_type.params.map(|p| p.varargs).unwrap_or(false);
+        if varargs {
+            let message = format!("`{}` takes a variable number of arguments", entry.name);
+            return Err(error!(message, func_loc, "defined here"));
+        }
+
+        if params.len() != entry.args.len() {
+            let message = format!(
+                "`{}` takes {} arguments, but was given {}",
+                entry.name,
+                params.len(),
+                entry.args.len()
+            );
+            return Err(error!(message, func_loc, "defined here"));
+        }
+
+        let mut code = VecU8::new();
+        code.push(Opcode::Func);
+        code.push(link_name);
+        code.push(func_loc);
+
+        code.push(Opcode::StackAlloc); // so that varargs don't run off the stack
+        code.push(0u32);
+        for (ty, arg) in params.iter().zip(&entry.args).rev() {
+            let bytes = entry_arg(ty, arg).map_err(|label| {
+                let message = format!("can't pass `{}` to `{}`", arg, entry.name);
+                error!(message, func_loc, label)
+            })?;
+
+            match bytes.len() {
+                1 => code.push(Opcode::Make8),
+                2 => code.push(Opcode::Make16),
+                4 => code.push(Opcode::Make32),
+                _ => code.push(Opcode::Make64),
+            }
+            code.data.extend_from_slice(&bytes);
+
+            code.push(Opcode::StackAlloc);
+            code.push(bytes.len() as u32);
+            code.push(Opcode::MakeSp);
+            code.push(0i16);
+            code.push(Opcode::Set);
+            code.push(bytes.len() as u32);
+        }
+
+        let rtype = func.func_type.return_type;
+        code.push(Opcode::StackAlloc);
+        code.push(rtype.repr_size());
+        code.push(Opcode::Make64);
+        code.push(func_ptr);
+        code.push(Opcode::Call);
+        let mut allocs = params.len() + 2;
+
+        if let Some(prim) = rtype.to_prim_type() {
+            let no_printer = || error!("the bundled libc is missing `__tci_print_entry`");
+            let printer = LinkName {
+                name: entry.printer.ok_or_else(no_printer)?,
+                file: n32::NULL,
+            };
+            let printer = self.func_linkage.get(&printer).ok_or_else(no_printer)?;
+            let printer = self.functions[*printer as usize].func_header;
+            let (printer_ptr, _) = printer.ok_or_else(no_printer)?;
+
+            // __tci_print_entry(&value, kind)
+            code.push(Opcode::StackAlloc);
+            code.push(0u32);
+            code.push(Opcode::Make32);
+            code.push(print_kind(prim));
+            code.push(Opcode::StackAlloc);
+            code.push(4u32);
+            code.push(Opcode::MakeSp);
+            code.push(0i16);
+            code.push(Opcode::Set);
+            code.push(4u32);
+            code.push(Opcode::MakeSp);
+            code.push(-2i16); // the return value, before the kind and the safety allocation
+            code.push(Opcode::StackAlloc);
+            code.push(8u32);
+            code.push(Opcode::MakeSp);
+            code.push(0i16);
+            code.push(Opcode::Set);
+            code.push(8u32);
+            code.push(Opcode::StackAlloc);
+            code.push(0u32);
+            code.push(Opcode::Make64);
+            code.push(printer_ptr);
+            code.push(Opcode::Call);
+            allocs += 4;
+        } else if rtype.repr_size() != 0 {
+            let message = format!("can't print what `{}` returns", entry.name);
+            return Err(error!(message, func_loc, "returns a struct or union"));
+        }
+
+        code.push(Opcode::Make32);
+        code.push(0u32);
+        code.push(Opcode::MakeFp);
+        code.push(-1i16);
+        code.push(Opcode::Set);
+        code.push(4u32);
+        for _ in 0..allocs {
+            code.push(Opcode::StackDealloc);
+        }
+        code.push(Opcode::Ret);
+
+        return Ok(self.data.add_data(&mut code.data));
+    }
+}
+
+/// `arg` as the bytes of a value of type `ty`, or why it can't be one
+fn entry_arg(ty: &TCType, arg: &str) -> Result<Vec<u8>, &'static str> {
+    let int = || arg.parse::<i64>().map_err(|_| "expected an integer");
+    let float = || arg.parse::<f64>().map_err(|_| "expected a number");
+    return match ty.to_prim_type() {
+        Some(TCPrimType::I8) | Some(TCPrimType::U8) => Ok(to_mem_bytes(int()? as u8)),
+        Some(TCPrimType::I16) | Some(TCPrimType::U16) => Ok(to_mem_bytes(int()? as u16)),
+        Some(TCPrimType::I32) | Some(TCPrimType::U32) => Ok(to_mem_bytes(int()? as u32)),
+        Some(TCPrimType::I64) => Ok(to_mem_bytes(int()?)),
+        Some(TCPrimType::U64) => match arg.parse::<u64>() {
+            Ok(value) => Ok(to_mem_bytes(value)),
+            Err(_) => Ok(to_mem_bytes(int()?)),
+        },
+        Some(TCPrimType::F32) => Ok(to_mem_bytes(float()? as f32)),
+        Some(TCPrimType::F64) => Ok(to_mem_bytes(float()?)),
+        Some(TCPrimType::Pointer { .. }) => Err("pointers can't be passed on the command line"),
+        None => Err("only numbers can be passed on the command line"),
+    };
 }
 
-pub fn func_decl_mismatch(original: CodeLoc, new: CodeLoc) -> Error {
+/// How `__tci_print_entry` in lib/impl/tci.c reads and prints a value of type `prim`
+fn print_kind(prim: TCPrimType) -> u32 {
+    return match prim {
+        TCPrimType::I8 => 0,
+        TCPrimType::U8 => 1,
+        TCPrimType::I16 => 2,
+        TCPrimType::U16 => 3,
+        TCPrimType::I32 => 4,
+        TCPrimType::U32 => 5,
+        TCPrimType::I64 => 6,
+        TCPrimType::U64 => 7,
+        TCPrimType::F32 => 8,
+        TCPrimType::F64 => 9,
+        TCPrimType::Pointer { .. } => 10,
+    };
+}
+
+/// How many bytes an initializer of type `ty` pushes. Arrays are usually
+/// represented by a pointer, but their initializers push every element.
+fn value_size(ty: &TCType) -> u32 {
+    if ty.is_array() {
+        return ty.size().into();
+    }
+
+    return ty.repr_size();
+}
+
+fn debug_locals(
+    debug: &mut DebugLayout,
+    tu: &TranslationUnit,
+    defn: &TCFuncDefn,
+) -> Vec<DebugLocal> {
+    let mut scopes = HashMap::new();
+    for op in defn.ops {
+        if let TCOpcodeKind::ScopeBegin(vars, _) = op.kind {
+            for (&label, _) in vars {
+                scopes.insert(label, op.loc);
+            }
+        }
+    }
+
+    let mut locals = Vec::new();
+    for (ident, var) in defn.locals {
+        let label = match var.symbol_label {
+            LabelOrLoc::Ident(label) => label,
+            LabelOrLoc::Loc(_) => continue, // static, so it lives in the binary
+        };
+
+        let layout = pointer_layout(debug, tu, &var.ty);
+        let symbols = &debug.symbols;
+        locals.push(DebugLocal {
+            name: symbols
+                .to_str(*ident)
+                .unwrap_or(COMPOUND_LIT_NAME)
+                .to_string(),
+            ty: var.ty.display(symbols),
+            label,
+            size: var.ty.size().unwrap_or(0),
+            decl_loc: var.loc,
+            scope: scopes.get(&label).map(|l| *l).unwrap_or(defn.loc),
+            layout,
+        });
+    }
+
+    return locals;
+}
+
+fn debug_stmts(defn: &TCFuncDefn) -> Vec<CodeLoc> {
+    let mut stmts = vec![defn.ops[0].loc];
+    for op in defn.ops {
+        match op.kind {
+            TCOpcodeKind::Expr(_) | TCOpcodeKind::Ret | TCOpcodeKind::RetVal(_) => {}
+            TCOpcodeKind::GotoIfZero { .. } | TCOpcodeKind::GotoIfNotZero { .. } => {}
+            TCOpcodeKind::Switch { .. } => {}
+            _ => continue,
+        }
+
+        stmts.push(op.loc);
+    }
+
+    return stmts;
+}
+
+/// Index into `debug.layouts` of the layout of a value of type `ty`, or NULL if its
+/// size isn't known, like for `void` or a struct that's only declared
+fn pointer_layout(debug: &mut DebugLayout, tu: &TranslationUnit, ty: &impl TCTy) -> n32 {
+    let size = match ty.size().opt() {
+        Some(size) => size,
+        None => return n32::NULL,
+    };
+
+    let (mut pointers, mut unknown) = (Vec::new(), Vec::new());
+    if ty.is_array() {
+        let elem = ty.deref().unwrap();
+        let layout = match pointer_layout(debug, tu, &elem).opt() {
+            Some(layout) => layout,
+            None => return n32::NULL,
+        };
+
+        let elem_size = debug.layouts[layout as usize].size;
+        let count = size / core::cmp::max(elem_size, 1);
+        for i in 0..count {
+            let elem = &debug.layouts[layout as usize];
+            let offset = i * elem_size;
+            pointers.extend(elem.pointers.iter().map(|&(o, to)| (offset + o, to)));
+            unknown.extend(elem.unknown.iter().map(|&(o, len)| (offset + o, len)));
+        }
+    } else if ty.is_callable() {
+        pointers.push((0, n32::NULL));
+    } else if ty.is_pointer() {
+        let pointee = ty.deref().unwrap();
+        pointers.push((0, pointer_layout(debug, tu, &pointee)));
+    } else if let Some((true, id)) = ty.get_id_strict() {
+        return struct_layout(debug, tu, id, size);
+    } else if !ty.is_integer() && !ty.is_floating_pt() {
+        unknown.push((0, size));
+    }
+
+    let layout = PointerLayout {
+        size,
+        pointers,
+        unknown,
+    };
+    debug.layouts.push(layout);
+    return (debug.layouts.len() as u32 - 1).into();
+}
+
+/// Structs get one layout per definition, so that one that points to itself,
+/// like a linked list node, can refer to its own layout
+fn struct_layout(debug: &mut DebugLayout, tu: &TranslationUnit, id: LabelOrLoc, size: u32) -> n32 {
+    let aggregate = tu.aggregates.iter().find(|a| {
+        !a.is_union
+            && match id {
+                LabelOrLoc::Ident(ident) => a.ident == ident.into(),
+                LabelOrLoc::Loc(loc) => a.defn.loc == loc,
+            }
+    });
+    let defn = match aggregate {
+        Some(aggregate) => aggregate.defn,
+        None => return n32::NULL,
+    };
+
+    if let Some(&idx) = debug.struct_layouts.get(&defn.loc) {
+        return idx.into();
+    }
+
+    let idx = debug.layouts.len() as u32;
+    debug.struct_layouts.insert(defn.loc, idx);
+    debug.layouts.push(PointerLayout {
+        size,
+        pointers: Vec::new(),
+        unknown: Vec::new(),
+    });
+
+    let (mut pointers, mut unknown) = (Vec::new(), Vec::new());
+    for field in defn.fields.iter().filter(|f| f.bitfield.is_none()) {
+        let layout = match pointer_layout(debug, tu, &field.ty).opt() {
+            Some(layout) => &debug.layouts[layout as usize],
+            None => continue,
+        };
+
+        let offset = field.offset;
+        pointers.extend(layout.pointers.iter().map(|&(o, to)| (offset + o, to)));
+        unknown.extend(layout.unknown.iter().map(|&(o, len)| (offset + o, len)));
+    }
+
+    debug.layouts[idx as usize].pointers = pointers;
+    debug.layouts[idx as usize].unknown = unknown;
+    return idx.into();
+}
+
+/// An instruction of a function the assembler has translated, for the peephole pass
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instr {
+    pub op: Opcode,
+    pub operand: Vec<u8>,
+    pub labels: Vec<u32>,   // labels that point at this instruction
+    pub temp: Option<Temp>, // the operand gets patched once the binary is laid out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Temp {
+    Func(usize), // index into `Assembler::function_temps`
+    Var(usize),  // index into `Assembler::var_temps`
+}
+
+impl Instr {
+    pub fn new(op: Opcode) -> Self {
+        return Self::with(op, ());
+    }
+
+    pub fn with<T: MemValue>(op: Opcode, operand: T) -> Self {
+        return Self {
+            op,
+            operand: to_mem_bytes(operand),
+            labels: Vec::new(),
+            temp: None,
+        };
+    }
+
+    pub fn operand<T: MemValue>(&self) -> T {
+        return T::from_bytes(&self.operand[..T::SIZE]);
+    }
+
+    /// The label this instruction jumps to, if it's a jump
+    pub fn jump_target(&self) -> Option<u32> {
+        return match self.op {
+            Opcode::Jump => Some(self.operand::<VarPointer>().offset()),
+            Opcode::JumpIfZero8 | Opcode::JumpIfZero16 => {
+                Some(self.operand::<VarPointer>().offset())
+            }
+            Opcode::JumpIfZero32 | Opcode::JumpIfZero64 => {
+                Some(self.operand::<VarPointer>().offset())
+            }
+            Opcode::JumpIfNotZero8 | Opcode::JumpIfNotZero16 => {
+                Some(self.operand::<VarPointer>().offset())
+            }
+            Opcode::JumpIfNotZero32 | Opcode::JumpIfNotZero64 => {
+                Some(self.operand::<VarPointer>().offset())
+            }
+            _ => None,
+        };
+    }
+}
+
+/// A rewrite for the peephole pass to try. It's given the code from some instruction
+/// on, and returns how many instructions to replace and what to replace them with.
+/// Rewrites that would replace an instruction a label points to, other than the
+/// first one, are skipped.
+#[derive(Clone, Copy)]
+pub struct PeepholeRule {
+    pub name: &'static str,
+    pub rewrite: fn(&[Instr]) -> Option<(usize, Vec<Instr>)>,
+}
+
+pub const PEEPHOLE_RULES: &[PeepholeRule] = &[
+    PeepholeRule {
+        name: "push-pop",
+        rewrite: push_pop,
+    },
+    PeepholeRule {
+        name: "merge-constants",
+        rewrite: merge_constants,
+    },
+    PeepholeRule {
+        name: "jump-to-next",
+        rewrite: jump_to_next,
+    },
+    PeepholeRule {
+        name: "dead-loc",
+        rewrite: dead_loc,
+    },
+    PeepholeRule {
+        name: "store-without-dup",
+        rewrite: store_without_dup,
+    },
+];
+
+/// Applies `rules` to `code` until none of them match anywhere
+pub fn peephole(mut code: Vec<Instr>, rules: &[PeepholeRule]) -> Vec<Instr> {
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        let mut idx = 0;
+        while idx < code.len() {
+            let rewrite = rules.iter().find_map(|rule| {
+                let (len, with) = (rule.rewrite)(&code[idx..])?;
+                let mut inner = code[(idx + 1)..(idx + len)].iter();
+                if inner.any(|instr| instr.labels.len() != 0) {
+                    return None;
+                }
+
+                // Labels on the first instruction need somewhere to go
+                if with.len() == 0 && idx + len == code.len() {
+                    return None;
+                }
+
+                return Some((len, with));
+            });
+
+            let (len, mut with) = match rewrite {
+                Some(rewrite) => rewrite,
+                None => {
+                    idx += 1;
+                    continue;
+                }
+            };
+
+            let labels = mem::replace(&mut code[idx].labels, Vec::new());
+            for instr in &mut with {
+                instr.labels.clear();
+            }
+
+            code.splice(idx..(idx + len), with);
+            code[idx].labels.extend(labels);
+            changed = true;
+        }
+    }
+
+    return code;
+}
+
+/// A value that's pushed and then immediately popped
+fn push_pop(code: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    let (push, pop) = (code.get(0)?, code.get(1)?);
+    if pop.op != Opcode::Pop || push.temp.is_some() {
+        return None;
+    }
+
+    let pushed = match push.op {
+        Opcode::Make8 | Opcode::Make16 | Opcode::Make32 | Opcode::Make64 => push.operand.len(),
+        Opcode::MakeFp | Opcode::MakeSp => mem::size_of::<VarPointer>(),
+        Opcode::Dup => push.operand::<u32>() as usize,
+        _ => return None,
+    };
+
+    if pop.operand::<u32>() as usize != pushed {
+        return None;
+    }
+
+    return Some((2, Vec::new()));
+}
+
+/// Two constants pushed one after the other, which can be pushed as one
+fn merge_constants(code: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    let (first, second) = (code.get(0)?, code.get(1)?);
+    if first.temp.is_some() || second.temp.is_some() {
+        return None;
+    }
+
+    let op = match (first.op, second.op) {
+        (Opcode::Make8, Opcode::Make8) => Opcode::Make16,
+        (Opcode::Make16, Opcode::Make16) => Opcode::Make32,
+        (Opcode::Make32, Opcode::Make32) => Opcode::Make64,
+        _ => return None,
+    };
+
+    let mut merged = Instr::new(op);
+    merged.operand.extend_from_slice(&first.operand);
+    merged.operand.extend_from_slice(&second.operand);
+    return Some((2, vec![merged]));
+}
+
+/// A jump to the instruction right after it; a conditional one still pops its condition
+fn jump_to_next(code: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    let (jump, next) = (code.get(0)?, code.get(1)?);
+    if !next.labels.contains(&jump.jump_target()?) {
+        return None;
+    }
+
+    let bytes: u32 = match jump.op {
+        Opcode::Jump => return Some((1, Vec::new())),
+        Opcode::JumpIfZero8 | Opcode::JumpIfNotZero8 => 1,
+        Opcode::JumpIfZero16 | Opcode::JumpIfNotZero16 => 2,
+        Opcode::JumpIfZero32 | Opcode::JumpIfNotZero32 => 4,
+        _ => 8,
+    };
+
+    return Some((1, vec![Instr::with(Opcode::Pop, bytes)]));
+}
+
+/// A location that nothing runs at, because another one replaces it right away
+fn dead_loc(code: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    let (first, second) = (code.get(0)?, code.get(1)?);
+    if first.op != Opcode::Loc || second.op != Opcode::Loc {
+        return None;
+    }
+
+    return Some((1, Vec::new()));
+}
+
+/// An assignment whose value isn't used: the copy of the value made for the result
+/// of the assignment is popped right after the store
+fn store_without_dup(code: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    let dup = code.get(0)?;
+    if dup.op != Opcode::Dup {
+        return None;
+    }
+
+    // The store's pointer is computed between the `Dup` and the `Set`, and it can't
+    // touch anything below it on the stack
+    let (bytes, ptr_size) = (dup.operand::<u32>(), mem::size_of::<VarPointer>());
+    let mut depth = 0;
+    for (idx, instr) in code.iter().enumerate().skip(1) {
+        match instr.op {
+            Opcode::Loc => {}
+            Opcode::MakeFp | Opcode::MakeSp | Opcode::Make64 => depth += ptr_size,
+            Opcode::Add64 if depth >= 2 * ptr_size => depth -= ptr_size,
+            Opcode::AssertAlign if depth >= ptr_size => {}
+            Opcode::Set if depth == ptr_size && instr.operand::<u32>() == bytes => {
+                let pop = code.get(idx + 1)?;
+                if pop.op != Opcode::Pop || pop.operand::<u32>() != bytes {
+                    return None;
+                }
+
+                return Some((idx + 2, code[1..=idx].to_vec()));
+            }
+            _ => return None,
+        }
+    }
+
+    return None;
+}
+
+/// `files` are the translation units that the declarations are in
+pub fn func_decl_mismatch(original: CodeLoc, new: CodeLoc, files: (&str, &str)) -> Error {
+    let message = format!(
+        "function declaration type doesn't match previous declaration; {} and {} disagree",
+        files.0, files.1
+    );
     return error!(
-        "function declaration type doesn't match previous declaration",
-        original, "original declaration here", new, "second declaration here"
+        message,
+        original,
+        format!("original declaration here, in {}", files.0),
+        new,
+        format!("second declaration here, in {}", files.1)
     );
 }
 
-pub fn func_redef(original: CodeLoc, redef: CodeLoc) -> Error {
+/// Link error for a symbol that's declared but never defined; lists every use
+pub fn missing_definition(
+    message: &str,
+    decl_loc: CodeLoc,
+    use_label: &str,
+    uses: &[CodeLoc],
+) -> Error {
+    let mut err = error!(message);
+    for &location in uses {
+        let message = use_label.to_string();
+        err.sections.push(ErrorSection { location, message });
+    }
+
+    let message = "declared here".to_string();
+    err.sections.push(ErrorSection {
+        location: decl_loc,
+        message,
+    });
+    return err;
+}
+
+/// `files` are the translation units that the definitions are in
+pub fn func_redef(original: CodeLoc, redef: CodeLoc, files: (&str, &str)) -> Error {
+    let message = format!(
+        "redefinition of function; both {} and {} define it",
+        files.0, files.1
+    );
     return error!(
-        "redefinition of function",
-        original, "original definition here", redef, "second definition here"
+        message,
+        original,
+        format!("original definition here, in {}", files.0),
+        redef,
+        format!("second definition here, in {}", files.1)
     );
 }
@@ -84,6 +84,11 @@ pub enum ExprKind {
         if_true: &'static Expr,
         if_false: &'static Expr,
     },
+    StmtExpr(Block), // GNU `({ ... })`; its value is the last statement's
+    CompoundLit {
+        ty: TypeName,
+        items: &'static [Initializer],
+    },
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -138,6 +143,8 @@ pub enum TypeSpecifier {
     Struct(StructType),
     Union(StructType),
     Ident(u32),
+    TypeofExpr(&'static Expr), // GNU `typeof`
+    TypeofTy(&'static TypeName),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -201,6 +208,7 @@ pub struct TypeName {
 #[derive(Debug, Clone, Copy)]
 pub struct StructDeclarator {
     pub declarator: Declarator,
+    pub bit_width: Option<&'static Expr>,
     pub loc: CodeLoc,
 }
 
@@ -276,12 +284,13 @@ pub struct ParameterDeclaration {
 #[derive(Debug, Clone, Copy)]
 pub enum InitializerKind {
     Expr(&'static Expr),
-    List(&'static [Expr]), // TODO support initializer list syntax
+    List(&'static [Initializer]),
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct Initializer {
     pub kind: InitializerKind,
+    pub field: Option<(u32, CodeLoc)>, // the `x` in `.x = 1`
     pub loc: CodeLoc,
 }
 
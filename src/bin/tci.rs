@@ -0,0 +1,486 @@
+//! The `tci` command line. Without a subcommand, it compiles the files it's given
+//! and runs the program on the terminal.
+//!
+//! - `tci [flags] file.c...` takes the flags `CompileOptions::parse_flag` does,
+//!   and also searches `TCI_INCLUDE_PATH` for headers. `--timings` prints how long
+//!   each phase took to stderr; see `timings`. `--profile` prints where the program
+//!   spent its instructions when it exits; see `runtime::profile`. `--clock=host`
+//!   gives the program the host's time instead of the reproducible virtual clock
+//! - `tci fmt [flags] file.c` prints the file formatted; see `formatter`
+//! - `tci outline [--json] file.c` lists what the file declares; see `outline`
+//! - `tci test [--junit] [flags] dir/` runs the programs in `dir`; see `test_runner`
+//! - `tci lsp` is a language server on stdin and stdout; see `lsp`
+//! - `tci repl` reads C a line at a time and runs it; see `repl`
+
+use std::fmt::Write as _;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::process::exit;
+use tci::filedb::FileDb;
+use tci::native::Backend;
+use tci::runtime::*;
+use tci::timings::Timings;
+use tci::util::Error;
+use tci::{CompileOptions, EmitConfig, Program};
+
+const USAGE: &str = "\
+usage: tci [--timings] [--profile] [flags] file.c...
+       tci fmt [flags] file.c
+       tci outline [--json] file.c
+       tci test [--junit] [flags] dir/
+       tci lsp
+       tci repl
+";
+
+// Programs run by `tci test` stop after this many instructions unless `--max-ops=`
+// says otherwise, so that one that never finishes can't hang the whole run
+const TEST_MAX_OPS: u64 = 100_000_000;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let code = match args.split_first() {
+        Some((&"fmt", rest)) => fmt(rest),
+        Some((&"outline", rest)) => outline(rest),
+        Some((&"test", rest)) => test(rest),
+        Some((&"lsp", [])) => lsp(),
+        Some((&"repl", [])) => repl(),
+        Some((&"-h", _)) | Some((&"--help", _)) => {
+            print!("{}", USAGE);
+            0
+        }
+        Some(_) => run(&args),
+        None => usage(),
+    };
+
+    exit(code);
+}
+
+fn usage() -> i32 {
+    eprint!("{}", USAGE);
+    return 2;
+}
+
+/// Reads `paths` into a `FileDb`, returning the id of each. Headers they include
+/// are read too, from `options.include_paths` or the directories in
+/// `TCI_INCLUDE_PATH`, which is also added to the `FileDb`'s include paths
+fn load(paths: &[&str], options: &CompileOptions) -> Result<(FileDb, Vec<u32>), i32> {
+    let mut files = FileDb::new();
+    set_date(&mut files);
+    if let Ok(paths) = std::env::var("TCI_INCLUDE_PATH") {
+        files.add_include_paths(&paths);
+    }
+
+    // Only files from disk are scanned for includes, not the bundled libc
+    let (mut ids, index) = (Vec::new(), files.files.len());
+    for path in paths {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                eprintln!("tci: couldn't read {}: {}", path, err);
+                return Err(1);
+            }
+        };
+
+        match files.add_bytes(path, &bytes) {
+            Ok(id) => ids.push(id),
+            Err(err) => return Err(report(&[err], &files, options)),
+        }
+    }
+
+    let mut dirs: Vec<String> = options.include_paths.clone();
+    dirs.extend(files.include_paths.iter().cloned());
+    if let Err(err) = files.add_includes(index, &dirs, |path| std::fs::read(path).ok()) {
+        return Err(report(&[err], &files, options));
+    }
+
+    return Ok((files, ids));
+}
+
+/// Sets `__DATE__` and `__TIME__` from the host clock, in UTC. Like GCC,
+/// `SOURCE_DATE_EPOCH` overrides the clock, for reproducible builds.
+fn set_date(files: &mut FileDb) {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let epoch = std::env::var("SOURCE_DATE_EPOCH").ok();
+    let secs = match epoch.and_then(|secs| secs.parse::<u64>().ok()) {
+        Some(secs) => secs,
+        None => match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(now) => now.as_secs(),
+            Err(_) => return,
+        },
+    };
+
+    // Howard Hinnant's `civil_from_days`
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    files.date = format!("{} {:>2} {}", MONTHS[month as usize - 1], day, year);
+    files.time = format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+}
+
+/// Prints compile errors to stderr, and returns the exit code for them
+fn report(errs: &[Error], files: &FileDb, options: &CompileOptions) -> i32 {
+    let config = EmitConfig::new(options, std::io::stderr().is_terminal());
+    let mut out = String::new();
+    tci::emit_err_with(errs, files, &config, &mut out);
+    eprint!("{}", out);
+    return 1;
+}
+
+/// Prints the warnings about the program in `files` to stderr
+fn warn(files: &FileDb, options: &CompileOptions) {
+    let config = EmitConfig::new(options, std::io::stderr().is_terminal());
+    let mut out = String::new();
+    tci::emit_warnings(
+        tci::program_warnings(files, options),
+        files,
+        &config,
+        &mut out,
+    );
+    eprint!("{}", out);
+}
+
+fn fmt(args: &[&str]) -> i32 {
+    let (mut options, mut path) = (CompileOptions::default(), None);
+    for arg in args {
+        if arg.starts_with('-') {
+            if let Err(message) = options.parse_flag(arg) {
+                eprintln!("tci: {}", message);
+                return 2;
+            }
+        } else if path.replace(*arg).is_some() {
+            return usage();
+        }
+    }
+
+    let path = match path {
+        Some(path) => path,
+        None => return usage(),
+    };
+
+    for message in &options.ignored_flags {
+        eprintln!("tci: warning: {}", message);
+    }
+
+    let (files, ids) = match load(&[path], &options) {
+        Ok(loaded) => loaded,
+        Err(code) => return code,
+    };
+
+    return match tci::formatter::format_with(&files, ids[0], &options) {
+        Ok(text) => {
+            print!("{}", text);
+            0
+        }
+        Err(errs) => report(&errs, &files, &options),
+    };
+}
+
+fn outline(args: &[&str]) -> i32 {
+    let (json, path) = match args {
+        ["--json", path] => (true, path),
+        [path] => (false, path),
+        _ => return usage(),
+    };
+
+    let (files, ids) = match load(&[path], &CompileOptions::default()) {
+        Ok(loaded) => loaded,
+        Err(code) => return code,
+    };
+
+    return match tci::outline::outline(&files, ids[0]) {
+        Ok(outline) if json => {
+            println!("{}", outline.to_json());
+            0
+        }
+        Ok(outline) => {
+            print!("{}", outline.to_text());
+            0
+        }
+        Err(errs) => report(&errs, &files, &CompileOptions::default()),
+    };
+}
+
+fn test(args: &[&str]) -> i32 {
+    let (mut junit, mut dir, mut options) = (false, None, CompileOptions::default());
+    for arg in args {
+        if *arg == "--junit" {
+            junit = true;
+        } else if arg.starts_with('-') {
+            if let Err(message) = options.parse_flag(arg) {
+                eprintln!("tci: {}", message);
+                return 2;
+            }
+        } else if dir.replace(*arg).is_some() {
+            return usage();
+        }
+    }
+
+    for message in &options.ignored_flags {
+        eprintln!("tci: warning: {}", message);
+    }
+
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return usage(),
+    };
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("tci: couldn't read {}: {}", dir, err);
+            return 1;
+        }
+    };
+
+    // Sources that aren't UTF-8 are decoded, or fail, when the test is compiled
+    let mut contents = Vec::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Ok(bytes) = std::fs::read(entry.path()) {
+            contents.push((name, bytes));
+        }
+    }
+
+    let entries: Vec<(&str, &[u8])> = contents
+        .iter()
+        .map(|(name, bytes)| (name.as_str(), bytes.as_slice()))
+        .collect();
+    let cases = tci::test_runner::cases_from_dir(dir, &entries);
+    let mut limits = options.limits;
+    if limits.max_ops == Limits::DEFAULT.max_ops {
+        limits.max_ops = TEST_MAX_OPS;
+    }
+
+    let include_path = std::env::var("TCI_INCLUDE_PATH").unwrap_or_default();
+    let headers = tci::test_runner::Headers {
+        include_path: &include_path,
+        read: &|path| std::fs::read(path).ok(),
+    };
+    let summary = tci::test_runner::run_tests(&cases, &options, limits, &headers);
+    for result in &summary.results {
+        eprint!("{}", result.warnings);
+    }
+
+    match junit {
+        true => print!("{}", summary.to_junit()),
+        false => print!("{}", summary.to_tap()),
+    }
+
+    return if summary.failed() == 0 { 0 } else { 1 };
+}
+
+/// Exits with 0 if the client asked the server to shut down before it exited,
+/// like the protocol says
+fn lsp() -> i32 {
+    let mut server = tci::lsp::LspServer::new();
+    server.read_file = Some(|path| std::fs::read(path).ok());
+    let (mut stdin, mut stdout) = (std::io::stdin(), std::io::stdout());
+    let mut buffer = vec![0; 1 << 16];
+    while !server.exited {
+        let len = match stdin.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+
+        server.recv(&buffer[..len]);
+        let output = server.take_output();
+        if stdout
+            .write_all(&output)
+            .and_then(|_| stdout.flush())
+            .is_err()
+        {
+            break;
+        }
+    }
+
+    return if server.shutdown { 0 } else { 1 };
+}
+
+fn repl() -> i32 {
+    let mut repl = tci::repl::Repl::new();
+    let stdin = std::io::stdin();
+    loop {
+        print!("> ");
+        std::io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => return 0,
+            Ok(_) => {}
+        }
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match repl.eval(&line) {
+            Ok(out) => {
+                print!("{}", out.output);
+                if let Some(value) = out.value {
+                    println!("({}) {}", value.ty, value.value);
+                }
+            }
+            Err(message) => eprint!("{}", message),
+        }
+    }
+}
+
+fn run(args: &[&str]) -> i32 {
+    let (mut options, mut paths) = (CompileOptions::default(), Vec::new());
+    let (mut timings, mut profile) = (Timings::disabled(), false);
+    for arg in args {
+        if *arg == "--timings" {
+            timings = Timings::new(clock);
+        } else if *arg == "--profile" {
+            profile = true;
+        } else if arg.starts_with('-') {
+            if let Err(message) = options.parse_flag(arg) {
+                eprintln!("tci: {}", message);
+                return 2;
+            }
+        } else {
+            paths.push(*arg);
+        }
+    }
+
+    if paths.is_empty() {
+        return usage();
+    }
+
+    for message in &options.ignored_flags {
+        eprintln!("tci: warning: {}", message);
+    }
+
+    if profile && options.backend != Backend::Interpreter {
+        eprintln!("tci: warning: only the interpreter can profile; ignoring `--profile`");
+    }
+
+    let (files, _) = match load(&paths, &options) {
+        Ok(loaded) => loaded,
+        Err(code) => return code,
+    };
+
+    let program = match tci::compile_program_timed(&files, &options, &mut timings) {
+        Ok(program) => program,
+        Err(errs) => return report(&errs, &files, &options),
+    };
+
+    warn(&files, &options);
+
+    let mut kernel = Kernel::new(Vec::new());
+    kernel.limits = options.limits;
+    if options.host_clock {
+        kernel.use_host_clock(clock);
+    }
+
+    let code = timings.time("run", || match program {
+        Program::Bytecode(binary) => {
+            if profile {
+                kernel.enable_profiling(clock);
+            }
+
+            let code = run_on_terminal(&mut kernel, &binary, &files);
+            if let Some(profile) = &kernel.profile {
+                eprint!("{}", profile.report(&files));
+            }
+
+            code
+        }
+        Program::Native(_) => {
+            let result = program.run_captured_in(&mut kernel);
+            print!("{}", result.stdout);
+            eprint!("{}", result.stderr);
+            result.exit_code.unwrap_or(1)
+        }
+        Program::Wasm(module) => match std::io::stdout().write_all(&module) {
+            Ok(()) => 0,
+            Err(_) => 1,
+        },
+        Program::CallGraph(text) | Program::Layout(text) | Program::Frames(text) => {
+            print!("{}", text);
+            0
+        }
+    });
+
+    if !timings.phases.is_empty() {
+        eprint!("{}", timings.table());
+    }
+
+    return code;
+}
+
+/// Microseconds since the epoch, for `Timings`, `Profile` and `--clock=host`
+fn clock() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    return now.map(|d| d.as_micros() as u64).unwrap_or(0);
+}
+
+/// Runs the program on `kernel` with the terminal as its stdin, stdout and stderr.
+/// Input is read a line at a time, whenever every process is waiting for it.
+fn run_on_terminal(kernel: &mut Kernel, binary: &BinaryData, files: &FileDb) -> i32 {
+    kernel.stdin_open = true;
+    let proc_id = kernel.load_term_program(binary);
+    let stdin = std::io::stdin();
+
+    loop {
+        if let Some(code) = kernel.exit_status(proc_id) {
+            write_output(kernel);
+            return code;
+        }
+
+        if kernel.active_count == 0 {
+            if !kernel.stdin_open {
+                eprintln!("Deadlock: every process is blocked, so the program can't make progress");
+                return 1;
+            }
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(len) if len > 0 => kernel.write_str(&line).unwrap(),
+                _ => kernel.close_stdin(),
+            }
+        }
+
+        let result = kernel.run_op_count(100_000);
+        write_output(kernel);
+
+        // Errors in forked children only end the child
+        if let Err(err) = result {
+            let proc = kernel.current_proc;
+            match kernel.memory(proc) {
+                Ok(memory) => eprint!("{}", print_error(&err, memory, files)),
+                Err(_) => eprintln!("{}: {}", err.short_name, err.message),
+            }
+
+            if proc == proc_id {
+                return 1;
+            }
+        }
+    }
+}
+
+fn write_output(kernel: &mut Kernel) {
+    let (mut stdout, mut stderr) = (std::io::stdout(), std::io::stderr());
+    for tci::util::TE(tag, bytes) in &kernel.events() {
+        let _ = match tag {
+            WriteEvt::StdoutWrite => stdout.write_all(bytes),
+            WriteEvt::StderrWrite | WriteEvt::StdlogWrite => stderr.write_all(bytes),
+            _ => Ok(()),
+        };
+    }
+
+    let _ = stdout.flush();
+}
@@ -215,11 +215,41 @@ pub fn grow_array(len: usize) -> usize {
     return len / 2 + len;
 }
 
+/// How big each new bucket is, relative to the one before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BucketGrowth {
+    /// Every bucket has the same capacity as the first
+    Fixed,
+    /// Each bucket's capacity is this percentage of the previous one's
+    Percent(usize),
+}
+
+impl BucketGrowth {
+    pub const DEFAULT: Self = Self::Percent(150);
+
+    pub fn next_len(self, len: usize) -> usize {
+        return match self {
+            Self::Fixed => len,
+            Self::Percent(percent) => len.saturating_mul(percent) / 100,
+        };
+    }
+}
+
+/// Memory use of a bucket list, from the bucket it was queried on to the end of the chain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BucketStats {
+    pub buckets: usize,
+    pub allocated: usize, // bytes reserved for bucket data
+    pub used: usize,      // bytes handed out to callers, including alignment padding
+    pub wasted: usize,    // unused space at the end of buckets that have been moved past
+}
+
 #[repr(C)]
 pub struct BucketListInner {
     pub next: AtomicPtr<BucketListInner>,
     pub bump: AtomicPtr<u8>,
     pub len: usize,
+    pub growth: BucketGrowth,
     pub array_begin: (),
 }
 
@@ -269,7 +299,7 @@ impl BucketListInner {
     pub unsafe fn make_next(&self, min_layout: Layout) -> (*mut BucketListInner, Layout) {
         let bucket_align = cmp::max(min_layout.align(), mem::align_of::<BucketListInner>());
         let inner_size = cmp::max(bucket_align, mem::size_of::<BucketListInner>());
-        let new_len = cmp::max(grow_array(self.len), min_layout.size());
+        let new_len = cmp::max(self.growth.next_len(self.len), min_layout.size());
         let bucket_size = inner_size + new_len;
 
         let next_layout = match Layout::from_size_align(bucket_size, bucket_align) {
@@ -282,10 +312,44 @@ impl BucketListInner {
         new_buffer.next = AtomicPtr::new(ptr::null_mut());
         new_buffer.bump = AtomicPtr::new(next_array_begin);
         new_buffer.len = new_len;
+        new_buffer.growth = self.growth;
         return (new_buffer, next_layout);
     }
 
     pub unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        return self.alloc_in_chain(layout).0;
+    }
+
+    /// Allocates in this bucket or the first one after it that has room, and
+    /// returns the bucket that was used so callers can start there next time.
+    pub unsafe fn alloc_in_chain(&self, layout: Layout) -> (*mut u8, &BucketListInner) {
+        let mut bucket = self;
+        loop {
+            if let Some(ptr) = bucket.alloc_here(layout) {
+                return (ptr, bucket);
+            }
+
+            let mut next = bucket.next.load(Ordering::SeqCst);
+            if next.is_null() {
+                let (new_buffer, new_layout) = bucket.make_next(layout);
+                if let Err(ptr) = bucket.next.compare_exchange(
+                    ptr::null_mut(),
+                    new_buffer,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    dealloc(new_buffer as *mut u8, new_layout);
+                    next = ptr;
+                } else {
+                    next = new_buffer;
+                }
+            }
+
+            bucket = &*next;
+        }
+    }
+
+    unsafe fn alloc_here(&self, layout: Layout) -> Option<*mut u8> {
         let array_begin = &self.array_begin as *const () as *const u8;
         let bucket_end = array_begin.add(self.len);
         let mut bump = self.bump.load(Ordering::SeqCst);
@@ -299,27 +363,11 @@ impl BucketListInner {
             ) {
                 bump = ptr;
             } else {
-                return ptr.as_ptr();
-            }
-        }
-
-        let mut next = self.next.load(Ordering::SeqCst);
-        if next.is_null() {
-            let (new_buffer, new_layout) = self.make_next(layout);
-            if let Err(ptr) = self.next.compare_exchange(
-                ptr::null_mut(),
-                new_buffer,
-                Ordering::SeqCst,
-                Ordering::SeqCst,
-            ) {
-                dealloc(new_buffer as *mut u8, new_layout);
-                next = ptr;
-            } else {
-                next = new_buffer;
+                return Some(ptr.as_ptr());
             }
         }
 
-        return (&*next).alloc(layout);
+        return None;
     }
 }
 
@@ -336,6 +384,10 @@ impl<'a> BucketList<'a> {
     }
 
     pub fn with_capacity(capacity: usize) -> BucketListRef<'a> {
+        return Self::with_growth(capacity, BucketGrowth::DEFAULT);
+    }
+
+    pub fn with_growth(capacity: usize, growth: BucketGrowth) -> BucketListRef<'a> {
         let bucket_align = mem::align_of::<BucketListInner>();
         let bucket_size = mem::size_of::<BucketListInner>() + capacity;
         unsafe {
@@ -344,6 +396,7 @@ impl<'a> BucketList<'a> {
             new.data.next = AtomicPtr::new(ptr::null_mut());
             new.data.bump = AtomicPtr::new(&mut new.data.array_begin as *mut () as *mut u8);
             new.data.len = capacity;
+            new.data.growth = growth;
             return BucketListRef {
                 buckets: NonNull::new_unchecked(new as *mut Self),
             };
@@ -364,6 +417,26 @@ impl<'a> BucketList<'a> {
         });
     }
 
+    pub fn stats(&self) -> BucketStats {
+        let mut stats = BucketStats::default();
+        let mut bucket = self;
+        loop {
+            let begin = &bucket.data.array_begin as *const () as usize;
+            let used = bucket.data.bump.load(Ordering::SeqCst) as usize - begin;
+            stats.buckets += 1;
+            stats.allocated += bucket.data.len;
+            stats.used += used;
+
+            match bucket.next() {
+                Some(next) => {
+                    stats.wasted += bucket.data.len - used;
+                    bucket = unsafe { &*next.buckets.as_ptr() };
+                }
+                None => return stats,
+            }
+        }
+    }
+
     pub fn next(&self) -> Option<BucketListRef<'a>> {
         let next = NonNull::new(self.data.next.load(Ordering::SeqCst));
         if let Some(next) = next {
@@ -433,6 +506,24 @@ impl BucketListFactory {
         }
     }
 
+    pub fn with_growth(capa: usize, growth: BucketGrowth) -> Self {
+        let begin = BucketList::with_growth(capa, growth).buckets.as_ptr();
+
+        Self {
+            begin: AtomicPtr::new(begin),
+            current: AtomicPtr::new(begin),
+        }
+    }
+
+    pub fn stats(&self) -> BucketStats {
+        return unsafe { &*self.begin.load(Ordering::SeqCst) }.stats();
+    }
+
+    /// Bytes handed out by this arena so far, across every bucket
+    pub fn used_bytes(&self) -> usize {
+        return self.stats().used;
+    }
+
     pub unsafe fn dealloc(&mut self) {
         while let Some(new) = (&mut *self.begin.load(Ordering::SeqCst)).dealloc() {
             self.begin.store(new.buckets.as_ptr(), Ordering::SeqCst);
@@ -440,28 +531,93 @@ impl BucketListFactory {
     }
 }
 
+// `current` only ever moves forward, so keeping it at the tail is amortized O(1)
+// no matter how many buckets the chain has.
 impl Deref for BucketListFactory {
     type Target = BucketList<'static>;
     fn deref(&self) -> &Self::Target {
-        let current = unsafe { &mut *self.current.load(Ordering::SeqCst) };
-
-        if let Some(n) = current.next() {
-            let result = self.current.compare_exchange(
-                current,
-                n.buckets.as_ptr(),
-                Ordering::SeqCst,
-                Ordering::SeqCst,
-            );
+        let mut current = self.current.load(Ordering::SeqCst);
+        let mut tail = current;
+        while let Some(n) = unsafe { &*tail }.next() {
+            tail = n.buckets.as_ptr();
+        }
 
-            if result.is_ok() {
-                return unsafe { &*n.buckets.as_ptr() };
+        while tail != current {
+            match self
+                .current
+                .compare_exchange(current, tail, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(ptr) => current = ptr,
             }
         }
 
-        return current;
+        return unsafe { &*tail };
+    }
+}
+
+impl Allocator<'static> for BucketListFactory {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = self.current.load(Ordering::SeqCst);
+        let (ptr, bucket) = (&*current).data.alloc_in_chain(layout);
+
+        // BucketList is repr(C) with a zero-sized first field, so a bucket's
+        // BucketListInner is at the same address as the BucketList itself
+        let bucket = bucket as *const BucketListInner as *mut BucketList<'static>;
+        if bucket != current {
+            let (success, failure) = (Ordering::SeqCst, Ordering::SeqCst);
+            let _ = self
+                .current
+                .compare_exchange(current, bucket, success, failure);
+        }
+
+        return ptr;
     }
 }
 
+#[test]
+fn test_bucket_stats() {
+    let buckets = BucketListFactory::with_growth(64, BucketGrowth::Fixed);
+    assert_eq!(buckets.stats().buckets, 1);
+    assert_eq!(buckets.stats().used, 0);
+
+    buckets.add([0u8; 40]);
+    buckets.add([0u8; 40]);
+    let stats = buckets.stats();
+    assert_eq!(stats.buckets, 2);
+    assert_eq!(stats.allocated, 128);
+    assert_eq!(stats.used, 80);
+    assert_eq!(stats.wasted, 24);
+
+    let buckets = BucketListFactory::with_growth(64, BucketGrowth::Percent(200));
+    buckets.add([0u8; 40]);
+    buckets.add([0u8; 40]);
+    assert_eq!(buckets.stats().allocated, 64 + 128);
+}
+
+#[test]
+fn test_bucket_tail() {
+    let buckets = BucketListFactory::with_growth(16, BucketGrowth::Fixed);
+    for i in 0..1000u64 {
+        buckets.add(i);
+        buckets.add(i);
+    }
+
+    let current = unsafe { &*buckets.current.load(Ordering::SeqCst) };
+    assert!(current.next().is_none());
+    assert_eq!(buckets.stats().buckets, 1000);
+
+    let other = BucketListFactory::with_growth(16, BucketGrowth::Fixed);
+    let num = (&*other).add(1u64);
+    (&*other).add([0u8; 16]);
+    let tail = &*other as *const BucketList;
+    assert_eq!(
+        tail,
+        other.current.load(Ordering::SeqCst) as *const BucketList
+    );
+    assert_eq!(*num, 1);
+}
+
 #[test]
 fn test_bucket_list() {
     use alloc::vec;
@@ -0,0 +1,486 @@
+//! Call graph of a program, for `--emit=callgraph`. Calls through function
+//! pointers are followed when the pointer can only hold functions we saw being
+//! stored in it; the rest point at an unknown callee.
+
+use crate::filedb::*;
+use crate::lexer::*;
+use crate::optimizer::visit;
+use crate::parser::*;
+use crate::tc_ast::*;
+use crate::type_checker::*;
+use crate::util::*;
+
+pub const UNKNOWN: &str = "?"; // the callee of a call we couldn't resolve
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallEdge {
+    pub caller: String,
+    pub callee: String,
+    pub indirect: bool, // through a function pointer
+    pub loc: CodeLoc,   // the first call like this
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallGraph {
+    pub defined: Vec<String>,  // functions defined in the program, sorted
+    pub external: Vec<String>, // functions called but defined elsewhere, e.g. libc
+    pub edges: Vec<CallEdge>,
+    pub cycles: Vec<Vec<String>>, // groups of mutually recursive functions
+    pub address_taken: Vec<String>, // functions used other than by calling them, sorted
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Var {
+    Local { func: u32, label: u32 },
+    Global { tu: usize, binary_offset: u32 },
+}
+
+/// What each variable could hold, as far as function pointers go. A variable
+/// missing from `values` has never had a function stored in it.
+struct Values {
+    values: HashMap<Var, Vec<u32>>,
+    unknown: HashMap<Var, ()>, // something we couldn't follow got stored in these
+    changed: bool,
+}
+
+impl Values {
+    /// The functions `expr` could point to, or `None` if we can't tell
+    fn of(&self, tu: usize, func: u32, expr: &TCExpr) -> Option<Vec<u32>> {
+        let var = match expr.kind {
+            TCExprKind::FunctionIdent { ident } => return Some(vec![ident]),
+            TCExprKind::TypePun(e) | TCExprKind::Deref(e) => return self.of(tu, func, e),
+            TCExprKind::Conv { expr, .. } => return self.of(tu, func, expr),
+            TCExprKind::Assign { value, .. } => return self.of(tu, func, value),
+            TCExprKind::ParenList(exprs) => return self.of(tu, func, exprs.last()?),
+            TCExprKind::Ternary {
+                if_true, if_false, ..
+            } => {
+                let mut values = self.of(tu, func, if_true)?;
+                values.extend(self.of(tu, func, if_false)?);
+                return Some(values);
+            }
+            TCExprKind::LocalIdent { label } => Var::Local { func, label },
+            TCExprKind::GlobalIdent { binary_offset } => Var::Global { tu, binary_offset },
+            _ => return None,
+        };
+
+        if self.unknown.contains_key(&var) {
+            return None;
+        }
+
+        return Some(self.values.get(&var).cloned().unwrap_or(Vec::new()));
+    }
+
+    fn store(&mut self, var: Var, values: Option<Vec<u32>>) {
+        let values = match values {
+            Some(values) => values,
+            None => {
+                self.changed |= self.unknown.insert(var, ()).is_none();
+                return;
+            }
+        };
+
+        let known = self.values.entry(var).or_insert(Vec::new());
+        for value in values {
+            if !known.contains(&value) {
+                known.push(value);
+                self.changed = true;
+            }
+        }
+    }
+}
+
+fn target_var(tu: usize, func: u32, target: &TCAssignTarget) -> Option<Var> {
+    return match target.kind {
+        TCAssignTargetKind::LocalIdent { label } => Some(Var::Local { func, label }),
+        TCAssignTargetKind::GlobalIdent { binary_offset } => {
+            Some(Var::Global { tu, binary_offset })
+        }
+        TCAssignTargetKind::Ptr(_) => None,
+    };
+}
+
+/// The expressions `op` evaluates
+pub fn op_exprs(op: &TCOpcode) -> Vec<&TCExpr> {
+    return match &op.kind {
+        TCOpcodeKind::GotoIfZero { cond, .. } | TCOpcodeKind::GotoIfNotZero { cond, .. } => {
+            vec![cond]
+        }
+        TCOpcodeKind::Switch { expr, cases, .. } => {
+            let mut exprs = vec![expr];
+            exprs.extend(cases.iter().map(|(case, _)| case));
+            exprs
+        }
+        TCOpcodeKind::Expr(expr) | TCOpcodeKind::RetVal(expr) => vec![expr],
+        _ => Vec::new(),
+    };
+}
+
+/// Builds the call graph of every file in `files` other than the bundled libc
+pub fn call_graph(files: &FileDb) -> Result<CallGraph, Vec<Error>> {
+    let mut lexer = Lexer::new(files);
+    let mut tus = Vec::new();
+    for file in files.impls() {
+        if files.is_system(file) {
+            continue;
+        }
+
+        let (id, tokens) = lexer.lex(file).map_err(|e| vec![e])?;
+        let env = parse(id, tokens)?;
+        tus.push(check_tree(files, env.file, &lexer.symbols, &env.tree)?);
+    }
+
+    let mut defns: Vec<(usize, u32, &TCFuncDefn)> = Vec::new();
+    for (idx, tu) in tus.iter().enumerate() {
+        for (ident, func) in &tu.functions {
+            if let Some(defn) = &func.defn {
+                defns.push((idx, *ident, defn));
+            }
+        }
+    }
+
+    // Find what every variable could hold; a variable can get its value from
+    // another, so keep going until nothing changes
+    let mut values = Values {
+        values: HashMap::new(),
+        unknown: HashMap::new(),
+        changed: true,
+    };
+
+    for (idx, tu) in tus.iter().enumerate() {
+        for var in tu.vars.values() {
+            let kind = match var.init {
+                TCDeclInit::Default(kind) | TCDeclInit::Static(kind) => kind,
+                TCDeclInit::ExternInit(kind) => kind,
+                _ => continue,
+            };
+
+            let (ty, loc) = (var.ty, var.loc);
+            let init = values.of(idx, 0, &TCExpr { kind, ty, loc });
+            let var = Var::Global {
+                tu: idx,
+                binary_offset: var.var_idx,
+            };
+
+            values.store(var, init);
+        }
+    }
+
+    while values.changed {
+        values.changed = false;
+        for &(tu, func, defn) in &defns {
+            for op in defn.ops {
+                for expr in op_exprs(op) {
+                    visit(expr, &mut |e| match &e.kind {
+                        TCExprKind::Assign { target, value } => {
+                            if let Some(var) = target_var(tu, func, target) {
+                                let stored = values.of(tu, func, value);
+                                values.store(var, stored);
+                            }
+                        }
+                        TCExprKind::MutAssign { target, .. } => {
+                            if let Some(var) = target_var(tu, func, target) {
+                                values.store(var, None);
+                            }
+                        }
+                        TCExprKind::Call {
+                            func: callee,
+                            params,
+                        } => {
+                            let callee = match callee.kind {
+                                TCExprKind::FunctionIdent { ident } => ident,
+                                _ => return,
+                            };
+
+                            for (label, param) in params.iter().enumerate() {
+                                let stored = values.of(tu, func, param);
+                                let label = label as u32;
+                                values.store(
+                                    Var::Local {
+                                        func: callee,
+                                        label,
+                                    },
+                                    stored,
+                                );
+                            }
+                        }
+                        _ => {}
+                    });
+                }
+            }
+        }
+    }
+
+    let name = |ident: u32| lexer.symbols.to_str(ident).unwrap().to_string();
+    let mut edges: Vec<CallEdge> = Vec::new();
+    let mut add_edge = |caller: u32, callee: Option<u32>, indirect, loc| {
+        let caller = name(caller);
+        let callee = callee.map(name).unwrap_or(UNKNOWN.to_string());
+        let edge = CallEdge {
+            caller,
+            callee,
+            indirect,
+            loc,
+        };
+
+        let same = |e: &CallEdge| {
+            (&e.caller, &e.callee, e.indirect) == (&edge.caller, &edge.callee, edge.indirect)
+        };
+        if !edges.iter().any(same) {
+            edges.push(edge);
+        }
+    };
+
+    for &(tu, func, defn) in &defns {
+        for op in defn.ops {
+            for expr in op_exprs(op) {
+                visit(expr, &mut |e| {
+                    let callee = match &e.kind {
+                        TCExprKind::Call { func: callee, .. } => callee,
+                        _ => return,
+                    };
+
+                    if let TCExprKind::FunctionIdent { ident } = callee.kind {
+                        return add_edge(func, Some(ident), false, e.loc);
+                    }
+
+                    match values.of(tu, func, callee) {
+                        Some(targets) if targets.len() != 0 => {
+                            for target in targets {
+                                add_edge(func, Some(target), true, e.loc);
+                            }
+                        }
+                        _ => add_edge(func, None, true, e.loc),
+                    }
+                });
+            }
+        }
+    }
+
+    // A function named anywhere other than as the callee of a call might be
+    // called from somewhere we can't see, e.g. through a pointer passed to `qsort`
+    let mut mentions: HashMap<u32, i32> = HashMap::new();
+    let mut count = |e: &TCExpr| match &e.kind {
+        TCExprKind::FunctionIdent { ident } => *mentions.entry(*ident).or_insert(0) += 1,
+        TCExprKind::Call { func: callee, .. } => {
+            if let TCExprKind::FunctionIdent { ident } = callee.kind {
+                *mentions.entry(ident).or_insert(0) -= 1;
+            }
+        }
+        _ => {}
+    };
+
+    for &(_, _, defn) in &defns {
+        for op in defn.ops {
+            for expr in op_exprs(op) {
+                visit(expr, &mut count);
+            }
+        }
+    }
+
+    for tu in &tus {
+        for (ident, var) in &tu.vars {
+            let kind = match var.init {
+                TCDeclInit::Default(kind) | TCDeclInit::Static(kind) => kind,
+                TCDeclInit::ExternInit(kind) => kind,
+                _ => continue,
+            };
+
+            // Functions are globals too, but they don't take their own address
+            if !tu.functions.contains_key(ident) {
+                visit(
+                    &TCExpr {
+                        kind,
+                        ty: var.ty,
+                        loc: var.loc,
+                    },
+                    &mut count,
+                );
+            }
+        }
+
+        for (&loc, var) in &tu.static_internal_vars {
+            visit(
+                &TCExpr {
+                    kind: var.init,
+                    ty: var.ty,
+                    loc,
+                },
+                &mut count,
+            );
+        }
+    }
+
+    let mut address_taken: Vec<String> = mentions
+        .into_iter()
+        .filter(|&(_, count)| count > 0)
+        .map(|(ident, _)| name(ident))
+        .collect();
+    address_taken.sort();
+
+    let mut defined: Vec<String> = defns.iter().map(|&(_, ident, _)| name(ident)).collect();
+    defined.sort();
+    defined.dedup();
+
+    let mut external: Vec<String> = Vec::new();
+    for edge in &edges {
+        if !defined.contains(&edge.callee) && !external.contains(&edge.callee) {
+            external.push(edge.callee.clone());
+        }
+    }
+
+    external.sort();
+    edges.sort_by(|a, b| (&a.caller, &a.callee).cmp(&(&b.caller, &b.callee)));
+    let cycles = cycles(&defined, &edges);
+
+    return Ok(CallGraph {
+        defined,
+        external,
+        edges,
+        cycles,
+        address_taken,
+    });
+}
+
+/// Finds the strongly connected components of the graph with Tarjan's algorithm,
+/// keeping the ones where a function can end up calling itself
+fn cycles(functions: &[String], edges: &[CallEdge]) -> Vec<Vec<String>> {
+    struct Tarjan<'a> {
+        functions: &'a [String],
+        succs: Vec<Vec<usize>>,
+        index: Vec<Option<usize>>,
+        low: Vec<usize>,
+        stack: Vec<usize>,
+        on_stack: Vec<bool>,
+        next: usize,
+        out: Vec<Vec<String>>,
+    }
+
+    impl<'a> Tarjan<'a> {
+        fn visit(&mut self, node: usize) {
+            self.index[node] = Some(self.next);
+            self.low[node] = self.next;
+            self.next += 1;
+            self.stack.push(node);
+            self.on_stack[node] = true;
+
+            for idx in 0..self.succs[node].len() {
+                let succ = self.succs[node][idx];
+                match self.index[succ] {
+                    None => {
+                        self.visit(succ);
+                        self.low[node] = core::cmp::min(self.low[node], self.low[succ]);
+                    }
+                    Some(index) if self.on_stack[succ] => {
+                        self.low[node] = core::cmp::min(self.low[node], index);
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            if Some(self.low[node]) != self.index[node] {
+                return;
+            }
+
+            let mut component = Vec::new();
+            loop {
+                let member = self.stack.pop().unwrap();
+                self.on_stack[member] = false;
+                component.push(member);
+                if member == node {
+                    break;
+                }
+            }
+
+            let recursive = component.len() > 1 || self.succs[node].contains(&node);
+            if recursive {
+                let mut names: Vec<_> = component
+                    .iter()
+                    .map(|&i| self.functions[i].clone())
+                    .collect();
+                names.sort();
+                self.out.push(names);
+            }
+        }
+    }
+
+    let position = |name: &String| functions.iter().position(|f| f == name);
+    let mut succs = vec![Vec::new(); functions.len()];
+    for edge in edges {
+        if let (Some(caller), Some(callee)) = (position(&edge.caller), position(&edge.callee)) {
+            succs[caller].push(callee);
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        functions,
+        succs,
+        index: vec![None; functions.len()],
+        low: vec![0; functions.len()],
+        stack: Vec::new(),
+        on_stack: vec![false; functions.len()],
+        next: 0,
+        out: Vec::new(),
+    };
+
+    for node in 0..functions.len() {
+        if tarjan.index[node].is_none() {
+            tarjan.visit(node);
+        }
+    }
+
+    tarjan.out.sort();
+    return tarjan.out;
+}
+
+impl CallGraph {
+    pub fn is_recursive(&self, func: &str) -> bool {
+        return self
+            .cycles
+            .iter()
+            .any(|cycle| cycle.iter().any(|f| f == func));
+    }
+
+    /// Graphviz source for the graph. Recursive functions and the calls between
+    /// them are red, calls through function pointers are dashed, and functions
+    /// defined outside the program are boxes.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph callgraph {\n");
+        for func in &self.defined {
+            match self.is_recursive(func) {
+                true => out += &format!("    \"{}\" [color=red];\n", func),
+                false => out += &format!("    \"{}\";\n", func),
+            }
+        }
+
+        for func in &self.external {
+            out += &format!("    \"{}\" [shape=box];\n", func);
+        }
+
+        for edge in &self.edges {
+            let same_cycle = self
+                .cycles
+                .iter()
+                .any(|cycle| cycle.contains(&edge.caller) && cycle.contains(&edge.callee));
+
+            let mut attrs = Vec::new();
+            if edge.indirect {
+                attrs.push("style=dashed");
+            }
+
+            if same_cycle {
+                attrs.push("color=red");
+            }
+
+            out += &format!("    \"{}\" -> \"{}\"", edge.caller, edge.callee);
+            if attrs.len() != 0 {
+                out += &format!(" [{}]", attrs.join(", "));
+            }
+
+            out += ";\n";
+        }
+
+        out += "}\n";
+        return out;
+    }
+}
@@ -0,0 +1,531 @@
+//! Evaluation of C expressions against a paused program. Expressions go through the
+//! same lexer, parser, and type checker as the program did, in the scope of the
+//! function the program is paused in, and then run on a copy of its memory.
+
+use crate::assembler::*;
+use crate::buckets::BucketListFactory;
+use crate::filedb::FileDb;
+use crate::interner::Symbols;
+use crate::lexer::{Lexer, Macro};
+use crate::parser;
+use crate::runtime::*;
+use crate::tc_ast::*;
+use crate::tc_structs::GlobalScope;
+use crate::type_checker::{check_expr_at, check_scope};
+use crate::util::*;
+
+const SCRATCH_FILE: &str = "<debugger>";
+const MAX_OPS: u32 = 1_000_000;
+
+#[derive(Debug)]
+pub enum EvalError {
+    Compile(Vec<Error>),
+    Runtime(IError),
+}
+
+impl From<Error> for EvalError {
+    fn from(err: Error) -> Self {
+        return Self::Compile(vec![err]);
+    }
+}
+
+impl From<Vec<Error>> for EvalError {
+    fn from(errs: Vec<Error>) -> Self {
+        return Self::Compile(errs);
+    }
+}
+
+impl From<IError> for EvalError {
+    fn from(err: IError) -> Self {
+        return Self::Runtime(err);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvalResult {
+    pub ty: String,
+    pub value: String,
+}
+
+/// A translation unit the program was paused in, lexed, parsed, and checked once
+/// so that each expression only has to go through those steps itself
+struct EvalUnit {
+    buckets: BucketListFactory, // the lexer's, which the macros and the tree point into
+    symbols: Symbols,
+    macros: HashMap<u32, (Macro, CodeLoc)>,
+    typenames: HashMap<u32, bool>,
+    scope: GlobalScope,
+    _parsed: parser::ParseEnv,
+}
+
+impl Drop for EvalUnit {
+    fn drop(&mut self) {
+        unsafe { self.buckets.dealloc() };
+    }
+}
+
+/// Evaluates expressions against one compiled program, keeping each translation
+/// unit it's been paused in
+pub struct Evaluator {
+    units: HashMap<u32, EvalUnit>,
+}
+
+impl Evaluator {
+    pub fn new() -> Self {
+        return Self {
+            units: HashMap::new(),
+        };
+    }
+
+    /// Evaluates `source` as though it were written at `memory.loc`. Nothing the
+    /// expression does, including assignments, affects `memory`.
+    pub fn eval(
+        &mut self,
+        files: &mut FileDb,
+        debug: &DebugInfo,
+        memory: &Memory,
+        source: &str,
+    ) -> Result<EvalResult, EvalError> {
+        let func = memory.current_func;
+        let no_func = || error!("no debug info for the current function");
+        let id = *debug.func_linkage.get(&func).ok_or_else(no_func)?;
+        let file = debug.funcs[id as usize].file;
+        let file = file.ok_or_else(no_func)?;
+
+        if !self.units.contains_key(&file) {
+            let unit = EvalUnit::new(files, debug, file)?;
+            self.units.insert(file, unit);
+        }
+
+        let unit = self.units.get_mut(&file).unwrap();
+        let scratch = files.set_scratch(SCRATCH_FILE, source);
+
+        let mut lexer = Lexer::with_symbols(files, unit.symbols.clone());
+        lexer.macros = unit.macros.clone();
+        let (_, expr_tokens) = lexer.lex_with_macros(scratch)?;
+
+        // String literals in the tokens point into the lexer's buckets, so it has to
+        // outlive them
+        let symbols = &lexer.symbols;
+
+        let typenames = unit.typenames.clone();
+        let (_expr_env, expr) = parser::parse_expr(scratch, expr_tokens, typenames)?;
+        let expr = check_expr_at(&mut unit.scope, symbols, func.name, memory.loc, &expr)?;
+        return run_expr(debug, &unit.scope.tu, symbols, memory, &expr);
+    }
+}
+
+impl EvalUnit {
+    fn new(files: &FileDb, debug: &DebugInfo, file: u32) -> Result<Self, EvalError> {
+        let mut lexer = Lexer::with_symbols(files, debug.symbols.clone());
+        let (_, tokens) = lexer.lex(file)?;
+        let parsed = parser::parse(file, tokens)?;
+        let typenames = parsed.symbol_is_type.borrow()[0].clone();
+        let scope = check_scope(file, &lexer.symbols, &parsed.tree)?;
+
+        let macros = core::mem::replace(&mut lexer.macros, HashMap::new());
+        let buckets = core::mem::replace(&mut lexer.buckets, BucketListFactory::new());
+        return Ok(Self {
+            buckets,
+            symbols: lexer.symbols(),
+            macros,
+            typenames,
+            scope,
+            _parsed: parsed,
+        });
+    }
+}
+
+/// Runs `expr`, which was checked against `tu`, on a copy of `memory`
+fn run_expr(
+    debug: &DebugInfo,
+    tu: &TranslationUnit,
+    symbols: &Symbols,
+    memory: &Memory,
+    expr: &TCExpr,
+) -> Result<EvalResult, EvalError> {
+    let func = memory.current_func;
+    let heap_begin = memory.heap.get(0).map(|v| v.idx);
+    let heap_begin = heap_begin.unwrap_or(memory.shared_data.len());
+    let data = BinaryData {
+        data: memory.shared_data[..heap_begin].to_vec(),
+        vars: memory.binary.clone(),
+        strings: HashMap::new(),
+    };
+
+    let (data, fptr, len) = Assembler::assemble_expr(debug, tu, func, data, expr)?;
+
+    let mut memory = memory.clone();
+    memory.change_log = None;
+    memory.watchpoints.clear();
+    memory.expr_stack.clear();
+    memory.extend_binary(&data);
+
+    memory.jump(fptr);
+    let end = fptr.with_offset(len);
+    for _ in 0..MAX_OPS {
+        if memory.pc.var_idx() == end.var_idx() && memory.pc.offset() == end.offset() {
+            let value = format_value(&expr.ty, &memory.expr_stack);
+            let ty = expr.ty.display(symbols);
+            return Ok(EvalResult { ty, value });
+        }
+
+        if let Some(_) = run_op(&mut memory)? {
+            let message = "expression made a system call, which the debugger doesn't allow";
+            return Err(ierror!("EvalEcall", "{}", message).into());
+        }
+    }
+
+    return Err(ierror!(
+        "EvalTooLong",
+        "expression didn't finish after {} instructions",
+        MAX_OPS
+    )
+    .into());
+}
+
+/// Shows `bytes`, a value of type `ty`, as C would write it; values that aren't
+/// numbers or pointers are shown as bytes
+pub fn format_value(ty: &TCType, bytes: &[u8]) -> String {
+    let prim = match ty.to_prim_type() {
+        Some(prim) if ty.repr_size() as usize == bytes.len() => prim,
+        _ => {
+            let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            return format!("{{ {} }}", bytes.join(" "));
+        }
+    };
+
+    return match prim {
+        TCPrimType::I8 => format!("{}", i8::from_bytes(bytes)),
+        TCPrimType::U8 => format!("{}", u8::from_bytes(bytes)),
+        TCPrimType::I16 => format!("{}", i16::from_bytes(bytes)),
+        TCPrimType::U16 => format!("{}", u16::from_bytes(bytes)),
+        TCPrimType::I32 => format!("{}", i32::from_bytes(bytes)),
+        TCPrimType::U32 => format!("{}", u32::from_bytes(bytes)),
+        TCPrimType::I64 => format!("{}", i64::from_bytes(bytes)),
+        TCPrimType::U64 => format!("{}", u64::from_bytes(bytes)),
+        TCPrimType::F32 => format!("{}", f32::from_bytes(bytes)),
+        TCPrimType::F64 => format!("{}", f64::from_bytes(bytes)),
+        TCPrimType::Pointer { .. } => format!("{}", VarPointer::from_bytes(bytes)),
+    };
+}
+
+/// A value read out of a program's memory
+#[derive(Debug, Clone, PartialEq)]
+pub enum CValue {
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Pointer(VarPointer),
+    Array(Vec<CValue>),
+    Struct(Vec<(String, CValue)>), // unions too, with every member read from the same bytes
+}
+
+/// Reads the value of type `ty` at `ptr`. Types come from `tu`, the checked file
+/// that `ty` was found in, which has the definitions of the structs it names.
+pub fn peek(
+    tu: &TranslationUnit,
+    symbols: &Symbols,
+    memory: &Memory,
+    ptr: VarPointer,
+    ty: TCType,
+) -> Result<CValue, IError> {
+    let invalid = |what: &str| ierror!("InvalidPeek", "can't read {} from memory", what);
+    match ty.mods.first() {
+        Some(TCTypeModifier::Array(len)) => {
+            let elem = ty.deref().unwrap();
+            let stride = elem.repr_size();
+            let mut out = Vec::new();
+            for idx in 0..*len {
+                let elem_ptr = ptr.with_offset(ptr.offset() + idx * stride);
+                out.push(peek(tu, symbols, memory, elem_ptr, elem)?);
+            }
+
+            return Ok(CValue::Array(out));
+        }
+        Some(TCTypeModifier::Pointer(_)) => return Ok(CValue::Pointer(memory.read(ptr)?)),
+        Some(TCTypeModifier::VariableArray) => return Err(invalid("a variable length array")),
+        Some(_) => return Err(invalid("a function")),
+        None => {}
+    }
+
+    let (is_union, id) = match ty.base {
+        TCTypeBase::Typedef { refers_to, .. } | TCTypeBase::InternalTypedef(refers_to) => {
+            return peek(tu, symbols, memory, ptr, *refers_to);
+        }
+        TCTypeBase::NamedStruct { ident, .. } => (false, LabelOrLoc::Ident(ident)),
+        TCTypeBase::UnnamedStruct { loc, .. } => (false, LabelOrLoc::Loc(loc)),
+        TCTypeBase::NamedUnion { ident, .. } => (true, LabelOrLoc::Ident(ident)),
+        TCTypeBase::UnnamedUnion { loc, .. } => (true, LabelOrLoc::Loc(loc)),
+        TCTypeBase::Void => return Err(invalid("`void`")),
+        _ => return Ok(peek_prim(memory, ptr, ty.to_prim_type().unwrap())?),
+    };
+
+    let aggregate = tu.aggregates.iter().find(|a| {
+        a.is_union == is_union
+            && match id {
+                LabelOrLoc::Ident(ident) => a.ident == ident.into(),
+                LabelOrLoc::Loc(loc) => a.defn.loc == loc,
+            }
+    });
+    let aggregate = aggregate.ok_or_else(|| invalid("an incomplete type"))?;
+
+    let mut out = Vec::new();
+    for field in aggregate.defn.fields {
+        let name = symbols.to_str(field.name).unwrap().to_string();
+        let field_ptr = ptr.with_offset(ptr.offset() + field.offset);
+        let value = match field.bitfield {
+            None => peek(tu, symbols, memory, field_ptr, field.ty)?,
+            Some(bits) => {
+                let size = field.ty.repr_size();
+                let mut unit = [0u8; 8];
+                unit[..size as usize].copy_from_slice(memory.read_bytes(field_ptr, size)?);
+                let unit = u64::from_le_bytes(unit) >> bits.offset;
+                let shift = 64 - bits.width;
+                match ty_is_signed(field.ty) {
+                    true => CValue::Int(((unit << shift) as i64) >> shift),
+                    false => CValue::UInt((unit << shift) >> shift),
+                }
+            }
+        };
+
+        out.push((name, value));
+    }
+
+    return Ok(CValue::Struct(out));
+}
+
+fn peek_prim(memory: &Memory, ptr: VarPointer, prim: TCPrimType) -> Result<CValue, IError> {
+    return Ok(match prim {
+        TCPrimType::I8 => CValue::Int(memory.read::<i8>(ptr)? as i64),
+        TCPrimType::U8 => CValue::UInt(memory.read::<u8>(ptr)? as u64),
+        TCPrimType::I16 => CValue::Int(memory.read::<i16>(ptr)? as i64),
+        TCPrimType::U16 => CValue::UInt(memory.read::<u16>(ptr)? as u64),
+        TCPrimType::I32 => CValue::Int(memory.read::<i32>(ptr)? as i64),
+        TCPrimType::U32 => CValue::UInt(memory.read::<u32>(ptr)? as u64),
+        TCPrimType::I64 => CValue::Int(memory.read::<i64>(ptr)?),
+        TCPrimType::U64 => CValue::UInt(memory.read::<u64>(ptr)?),
+        TCPrimType::F32 => CValue::Float(memory.read::<f32>(ptr)? as f64),
+        TCPrimType::F64 => CValue::Float(memory.read::<f64>(ptr)?),
+        TCPrimType::Pointer { .. } => CValue::Pointer(memory.read(ptr)?),
+    });
+}
+
+fn ty_is_signed(ty: TCType) -> bool {
+    return match ty.to_prim_type() {
+        Some(TCPrimType::I8) | Some(TCPrimType::I16) => true,
+        Some(TCPrimType::I32) | Some(TCPrimType::I64) => true,
+        _ => false,
+    };
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MemoryNode {
+    pub id: String,
+    pub kind: &'static str, // "stack" or "heap"
+    pub name: String,       // variable name; empty for heap allocations
+    pub ty: String,         // variable type; empty for heap allocations
+    pub func: String,       // function the variable belongs to; empty for heap allocations
+    pub size: u32,
+    pub loc: CodeLoc, // declaration or allocation site
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MemoryEdge {
+    pub from: String,
+    pub from_offset: u32,
+    pub to: String,
+    pub to_offset: u32,
+    pub heuristic: bool, // found in memory no type describes, so it might be an integer
+}
+
+/// The stack and heap of a paused or exited program, as a graph of allocations and
+/// the pointers between them, for drawing memory diagrams.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MemoryGraph {
+    pub nodes: Vec<MemoryNode>,
+    pub edges: Vec<MemoryEdge>,
+}
+
+impl MemoryGraph {
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string(self).unwrap();
+    }
+}
+
+/// Pointers are found using the types of the locals, and of the pointers that lead
+/// to each heap allocation. Memory that no type describes, like a union or an
+/// allocation only reached through a `void *`, is scanned for anything that looks
+/// like a pointer to another node, and the edges found that way are heuristic.
+pub fn memory_graph(debug: &DebugInfo, memory: &Memory) -> MemoryGraph {
+    let mut frames = vec![(memory.current_func, memory.loc, memory.fp)];
+    let callers = memory.callstack.iter().rev();
+    frames.extend(callers.map(|f| (f.name, f.loc, f.fp)));
+
+    let mut nodes = Vec::new();
+    let mut typed = Vec::new();
+    let mut stack_nodes = HashMap::new();
+
+    for (link_name, loc, fp) in frames {
+        let id = match debug.func_linkage.get(&link_name) {
+            Some(&id) => id,
+            None => continue,
+        };
+
+        let func = &debug.funcs[id as usize];
+        let func_name = debug.symbols.to_str(link_name.name).unwrap();
+        for local in &func.locals {
+            let (scope, decl) = (local.scope, local.decl_loc);
+            let in_scope = scope.file == loc.file && scope.start <= loc.start;
+            let in_scope = in_scope && loc.start < scope.end;
+            if !in_scope || (decl.file == loc.file && decl.start > loc.start) {
+                continue;
+            }
+
+            let var = fp as i16 + func.var_offsets[local.label as usize];
+            let ptr = VarPointer::new_stack(var as u16, 0);
+            if var <= 0 || memory.read_bytes(ptr, local.size).is_err() {
+                continue;
+            }
+
+            let id = format!("stack:{}", var);
+            stack_nodes.insert(var as u32, id.clone());
+            typed.push((id.clone(), ptr, local.layout));
+            nodes.push(MemoryNode {
+                id,
+                kind: "stack",
+                name: local.name.clone(),
+                ty: local.ty.clone(),
+                func: func_name.to_string(),
+                size: local.size,
+                loc: local.decl_loc,
+            });
+        }
+    }
+
+    let mut heap = Vec::new();
+    for (idx, var) in memory.heap.iter().enumerate() {
+        if var.meta.len != n32::NULL {
+            continue;
+        }
+
+        let ptr = VarPointer::new_heap(idx as u32 + 1, 0);
+        let size = memory.upper_bound(ptr).map(|p| p.offset()).unwrap_or(0);
+        let id = format!("heap:{}", idx + 1);
+        heap.push((id.clone(), ptr, size));
+        nodes.push(MemoryNode {
+            id,
+            kind: "heap",
+            name: String::new(),
+            ty: String::new(),
+            func: String::new(),
+            size,
+            loc: var.meta.alloc_loc,
+        });
+    }
+
+    let target = |ptr: VarPointer| -> Option<String> {
+        if ptr.var_idx() == 0 || memory.upper_bound(ptr).is_none() {
+            return None;
+        }
+
+        if ptr.is_stack() {
+            return stack_nodes.get(&(ptr.var_idx() as u32)).cloned();
+        } else if ptr.is_heap() {
+            return Some(format!("heap:{}", ptr.var_idx()));
+        }
+
+        return None;
+    };
+
+    // A pointer to a heap allocation could point anywhere in an array of its
+    // pointee type, so the whole allocation gets the pointee's layout
+    let mut edges = Vec::new();
+    let mut untyped = Vec::new();
+    let mut seen = HashMap::new();
+    let mut typed_heap = vec![false; memory.heap.len() + 1];
+    while let Some((from, ptr, layout)) = typed.pop() {
+        let layout = match layout.opt() {
+            Some(layout) => layout,
+            None => continue,
+        };
+
+        let key = (from.clone(), ptr.offset(), layout);
+        if seen.insert(key, ()).is_some() {
+            continue;
+        }
+
+        let layout = &debug.layouts[layout as usize];
+        for &(offset, pointee) in &layout.pointers {
+            let from_offset = ptr.offset() + offset;
+            let value: VarPointer = match memory.read(ptr.with_offset(from_offset)) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+
+            let to = match target(value) {
+                Some(to) => to,
+                None => continue,
+            };
+
+            let pointee_size = pointee.opt().map(|l| debug.layouts[l as usize].size);
+            if let (true, Some(size)) = (value.is_heap(), pointee_size.filter(|&s| s != 0)) {
+                typed_heap[value.var_idx()] = true;
+                let end = memory.upper_bound(value).map(|p| p.offset()).unwrap_or(0);
+                let mut elem = value.offset() % size;
+                while elem + size <= end {
+                    typed.push((to.clone(), value.with_offset(elem), pointee));
+                    elem += size;
+                }
+            }
+
+            let (from, to_offset) = (from.clone(), value.offset());
+            let heuristic = false;
+            let edge = MemoryEdge {
+                from,
+                from_offset,
+                to,
+                to_offset,
+                heuristic,
+            };
+            edges.push(edge);
+        }
+
+        for &(offset, size) in &layout.unknown {
+            let begin = ptr.offset() + offset;
+            untyped.push((from.clone(), ptr, begin, begin + size));
+        }
+    }
+
+    for (from, ptr, size) in heap {
+        if !typed_heap[ptr.var_idx()] {
+            untyped.push((from, ptr, 0, size));
+        }
+    }
+
+    let ptr_size = core::mem::size_of::<VarPointer>() as u32;
+    for (from, ptr, begin, end) in untyped {
+        let offsets = (begin..end).filter(|o| o % ptr_size == 0 && o + ptr_size <= end);
+        for from_offset in offsets {
+            let value: VarPointer = match memory.read(ptr.with_offset(from_offset)) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+
+            if let Some(to) = target(value) {
+                let (from, to_offset) = (from.clone(), value.offset());
+                let heuristic = true;
+                let edge = MemoryEdge {
+                    from,
+                    from_offset,
+                    to,
+                    to_offset,
+                    heuristic,
+                };
+                edges.push(edge);
+            }
+        }
+    }
+
+    return MemoryGraph { nodes, edges };
+}
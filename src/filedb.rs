@@ -1,20 +1,76 @@
 use crate::buckets::*;
 use crate::util::*;
+use core::cell::RefCell;
 use core::include_bytes;
-use core::{fmt, str};
+use core::{fmt, mem, str};
+
+/// The on-screen column, counting from 0, at the given byte index in a line:
+/// tabs advance to the next multiple of `tab_width`, and characters take up as
+/// many columns as a terminal gives them, so `é` is one column and `漢` is two.
+pub fn display_column(line: &str, byte_index: usize, tab_width: usize) -> usize {
+    use unicode_width::UnicodeWidthChar;
+
+    let mut column = 0;
+    for (idx, c) in line.char_indices() {
+        if idx >= byte_index {
+            break;
+        }
 
-/// The column index at the given byte index in the source file.
-/// This is the number of characters to the given byte index.
-///
-/// If the byte index is smaller than the start of the line, then `0` is returned.
-/// If the byte index is past the end of the line, the column index of the last
-/// character `+ 1` is returned.
-pub fn column_index(source: &str, line_range: core::ops::Range<usize>, byte_index: usize) -> usize {
-    let end_index = core::cmp::min(byte_index, core::cmp::min(line_range.end, source.len()));
-
-    (line_range.start..end_index)
-        .filter(|byte_index| source.is_char_boundary(byte_index + 1))
-        .count()
+        column += match c {
+            '\t' if tab_width == 0 => 0,
+            '\t' => tab_width - column % tab_width,
+            c => c.width().unwrap_or(0),
+        };
+    }
+
+    return column;
+}
+
+/// The biggest file TCI can compile. Locations store byte offsets as `u32`s, so
+/// anything past this would wrap around and point somewhere else in the file.
+pub const MAX_FILE_SIZE: usize = u32::MAX as usize;
+
+/// Errors if a file of `len` bytes is too big for locations in it to work
+pub fn check_file_size(len: usize) -> Result<(), &'static str> {
+    if len > MAX_FILE_SIZE {
+        return Err("too large; files can be at most 4 GiB");
+    }
+
+    return Ok(());
+}
+
+/// Decodes the raw bytes of a source file. A UTF-8 byte order mark is dropped;
+/// anything else that isn't UTF-8 is an error naming the file and the offset of
+/// the first bad byte.
+pub fn decode_source<'b>(file_name: &str, bytes: &'b [u8]) -> Result<&'b str, Error> {
+    if bytes.starts_with(&[0xFF, 0xFE]) || bytes.starts_with(&[0xFE, 0xFF]) {
+        let message = format!("`{}` is UTF-16; save it as UTF-8", file_name);
+        return Err(error!(message));
+    }
+
+    let bom = if bytes.starts_with(UTF8_BOM) {
+        UTF8_BOM.len()
+    } else {
+        0
+    };
+    let err = match str::from_utf8(&bytes[bom..]) {
+        Ok(source) => return Ok(source),
+        Err(err) => err,
+    };
+
+    let offset = bom + err.valid_up_to();
+    let line = bytes[..offset].iter().filter(|&&b| b == b'\n').count() + 1;
+    let message = format!(
+        "`{}` isn't valid UTF-8: bad byte 0x{:02X} at offset {} (line {})",
+        file_name, bytes[offset], offset, line
+    );
+    return Err(error!(message));
+}
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+
+fn strip_bom(source: &str) -> &str {
+    return source.strip_prefix('\u{FEFF}').unwrap_or(source);
 }
 
 pub fn line_starts<'source>(source: &'source str) -> impl 'source + Iterator<Item = usize> {
@@ -59,6 +115,7 @@ impl<'a> File<'a> {
             FileType::Impl
         };
 
+        let source = strip_bom(source);
         let line_starts: Vec<usize> = line_starts(source).collect();
         File {
             ty,
@@ -158,6 +215,13 @@ lazy_static! {
         new_file!(@HEADER, "inttypes.h");
 
         new_file!(@HEADER, "sys/types.h");
+        new_file!(@HEADER, "sys/wait.h");
+        new_file!(@HEADER, "unistd.h");
+        new_file!(@HEADER, "poll.h");
+        new_file!(@HEADER, "time.h");
+        new_file!(@HEADER, "sys/time.h");
+        new_file!(@HEADER, "signal.h");
+        new_file!(@HEADER, "setjmp.h");
 
         new_file!(@IMPL, "tci.c");
         new_file!(@IMPL, "printf.c");
@@ -170,21 +234,252 @@ lazy_static! {
         new_file!(@IMPL, "ctype.c");
         new_file!(@IMPL, "files.c");
         new_file!(@IMPL, "errors.c");
+        new_file!(@IMPL, "unistd.c");
+        new_file!(@IMPL, "time.c");
+        new_file!(@IMPL, "signal.c");
+        new_file!(@IMPL, "setjmp.c");
 
         m
     };
 }
 
 pub const NO_SYMBOL: u32 = !0;
+pub const NO_EXPANSION: u32 = !0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct IncludeEdge {
+    pub from: u32,
+    pub to: u32,
+    pub loc: CodeLoc, // location of the include directive in `from`
+}
+
+/// Which files include which, filled in as files are lexed.
+#[derive(Debug, Clone)]
+pub struct IncludeGraph {
+    pub edges: Vec<IncludeEdge>,
+}
+
+impl IncludeGraph {
+    pub fn new() -> Self {
+        return Self { edges: Vec::new() };
+    }
+
+    pub fn add(&mut self, edge: IncludeEdge) {
+        let exists = |e: &IncludeEdge| e.from == edge.from && e.to == edge.to;
+        if !self.edges.iter().any(exists) {
+            self.edges.push(edge);
+        }
+    }
+
+    /// Forget what `file` includes, e.g. because its source changed
+    pub fn clear_file(&mut self, file: u32) {
+        self.edges.retain(|e| e.from != file);
+    }
+
+    pub fn includes(&self, file: u32) -> Vec<IncludeEdge> {
+        let edges = self.edges.iter().filter(|e| e.from == file);
+        return edges.map(|e| *e).collect();
+    }
+
+    pub fn included_by(&self, file: u32) -> Vec<IncludeEdge> {
+        let edges = self.edges.iter().filter(|e| e.to == file);
+        return edges.map(|e| *e).collect();
+    }
+
+    /// Every file that includes `file`, directly or indirectly
+    pub fn dependents(&self, file: u32) -> Vec<u32> {
+        let mut out = Vec::new();
+        let mut stack = vec![file];
+        while let Some(current) = stack.pop() {
+            for edge in self.edges.iter().filter(|e| e.to == current) {
+                if edge.from != file && !out.contains(&edge.from) {
+                    out.push(edge.from);
+                    stack.push(edge.from);
+                }
+            }
+        }
+
+        return out;
+    }
+}
+
+/// One macro expansion: the tokens at `site` came from the definition of macro
+/// `name` at `def`. A macro used inside another macro's definition is expanded
+/// at the same site, with `parent` pointing at the outer expansion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    pub name: String,
+    pub def: CodeLoc,
+    pub site: CodeLoc,
+    pub parent: Option<u32>,
+}
+
+/// Every macro expansion seen while lexing, so that a location in expanded code
+/// can be traced back through the macros that produced it.
+#[derive(Debug, Clone)]
+pub struct ExpansionTable {
+    pub expansions: Vec<Expansion>,
+    ids: HashMap<(CodeLoc, CodeLoc, Option<u32>), u32>, // (def, site, parent) -> id
+}
+
+impl ExpansionTable {
+    pub fn new() -> Self {
+        return Self {
+            expansions: Vec::new(),
+            ids: HashMap::new(),
+        };
+    }
+
+    /// Returns the id of `expansion`, adding it if it's new. Lexing the same file
+    /// twice gives the same ids.
+    pub fn add(&mut self, expansion: Expansion) -> u32 {
+        let key = (expansion.def, expansion.site, expansion.parent);
+        if let Some(&id) = self.ids.get(&key) {
+            return id;
+        }
+
+        let id = self.expansions.len() as u32;
+        self.expansions.push(expansion);
+        self.ids.insert(key, id);
+        return id;
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Expansion> {
+        return self.expansions.get(id as usize);
+    }
+
+    /// Forget the expansions in `file`, e.g. because its source changed
+    pub fn clear_file(&mut self, file: u32) {
+        let mut ids = Vec::new();
+        let mut kept = Vec::new();
+        for expansion in self.expansions.drain(..) {
+            if expansion.site.file == file {
+                ids.push(None);
+                continue;
+            }
+
+            ids.push(Some(kept.len() as u32));
+            kept.push(expansion);
+        }
+
+        // A nested expansion has the same site as its parent, so parents of the
+        // ones that are left are never removed
+        self.ids.clear();
+        for (id, expansion) in kept.iter_mut().enumerate() {
+            expansion.parent = expansion.parent.and_then(|p| ids[p as usize]);
+            let key = (expansion.def, expansion.site, expansion.parent);
+            self.ids.insert(key, id as u32);
+        }
+
+        self.expansions = kept;
+    }
+
+    /// The outermost expansion whose site contains `loc`, if `loc` came from a
+    /// macro
+    pub fn containing(&self, loc: CodeLoc) -> Option<u32> {
+        let mut found: Option<(u32, u32)> = None;
+        for (id, expansion) in self.expansions.iter().enumerate() {
+            let site = expansion.site;
+            let contains = site.file == loc.file && site.start <= loc.start && loc.end <= site.end;
+            if !contains || expansion.parent.is_some() {
+                continue;
+            }
+
+            let len = site.end - site.start;
+            if found.map(|(_, found_len)| len < found_len).unwrap_or(true) {
+                found = Some((id as u32, len));
+            }
+        }
+
+        return found.map(|(id, _)| id);
+    }
+
+    /// The most nested expansion that the code at `loc` can be pinned to. Every
+    /// token from one use of a macro has the same location, so this only goes
+    /// into a nested expansion if it's the only one at that level.
+    pub fn innermost(&self, loc: CodeLoc) -> Option<u32> {
+        let mut id = self.containing(loc)?;
+        loop {
+            let children = self.expansions.iter().enumerate();
+            let mut children = children.filter(|(_, e)| e.parent == Some(id));
+            match (children.next(), children.next()) {
+                (Some((child, _)), None) => id = child as u32,
+                _ => return Some(id),
+            }
+        }
+    }
+
+    /// `id` and the expansions it's nested in, innermost first
+    pub fn backtrace(&self, id: u32) -> Vec<&Expansion> {
+        let mut out = Vec::new();
+        let mut current = Some(id);
+        while let Some(expansion) = current.and_then(|id| self.get(id)) {
+            out.push(expansion);
+            current = expansion.parent;
+        }
+
+        return out;
+    }
+
+    /// Labels for the backtrace of `id`, to add to an error's sections
+    pub fn sections(&self, id: u32) -> Vec<ErrorSection> {
+        let backtrace = self.backtrace(id);
+        let mut out = Vec::new();
+        for expansion in &backtrace {
+            // Macros from the command line don't have anywhere to point at
+            if expansion.def == NO_FILE {
+                continue;
+            }
+
+            out.push(ErrorSection {
+                location: expansion.def,
+                message: format!("in expansion of macro `{}` defined here", expansion.name),
+            });
+        }
+
+        if let Some(outermost) = backtrace.last() {
+            out.push(ErrorSection {
+                location: outermost.site,
+                message: format!("macro `{}` used here", outermost.name),
+            });
+        }
+
+        return out;
+    }
+}
+
+/// Whether headers on the include paths can replace the bundled system headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemHeaders {
+    PreferUser,
+    PreferBundled,
+}
 
 pub struct FileDb {
     pub buckets: BucketListFactory,
+    replaced: HashMap<u32, BucketListFactory>, // sources from `replace`, one arena per file
     pub names: HashMap<(bool, &'static str), u32>,
     pub files: Vec<File<'static>>,
+    pub include_graph: RefCell<IncludeGraph>,
+    pub expansions: RefCell<ExpansionTable>,
+    pub include_paths: Vec<String>,
+    pub system_headers: SystemHeaders,
+    pub tab_width: usize, // for column numbers and for drawing source in diagnostics
+
+    /// Values of `__DATE__` and `__TIME__`. There's no clock in here, so these
+    /// start out as the fixed `Jan  1 1970` and `00:00:00`, which keeps the
+    /// library's output and its tests reproducible. The `tci` binary sets them
+    /// from the host clock; other embedders that want the real date should too.
+    pub date: String,
+    pub time: String,
 }
 
 impl Drop for FileDb {
     fn drop(&mut self) {
+        for (_, buckets) in &mut self.replaced {
+            unsafe { buckets.dealloc() };
+        }
+
         unsafe { self.buckets.dealloc() };
     }
 }
@@ -194,8 +489,17 @@ impl FileDb {
     pub fn new() -> Self {
         let mut new_self = Self {
             buckets: BucketListFactory::new(),
+            replaced: HashMap::new(),
             files: Vec::new(),
             names: HashMap::new(),
+            include_graph: RefCell::new(IncludeGraph::new()),
+            expansions: RefCell::new(ExpansionTable::new()),
+            include_paths: Vec::new(),
+            system_headers: SystemHeaders::PreferUser,
+            tab_width: 8,
+
+            date: "Jan  1 1970".to_string(),
+            time: "00:00:00".to_string(),
         };
 
         for (idx, file) in SYS_LIBS.iter().enumerate() {
@@ -206,6 +510,11 @@ impl FileDb {
         new_self
     }
 
+    /// Whether `file_id` is one of the bundled libc files
+    pub fn is_system(&self, file_id: u32) -> bool {
+        return (file_id as usize) < SYS_LIBS.len();
+    }
+
     pub fn impls(&self) -> Vec<u32> {
         let mut out = Vec::with_capacity(self.files.len());
         for (idx, file) in self.files.iter().enumerate() {
@@ -226,6 +535,8 @@ impl FileDb {
             return Err("already exists");
         }
 
+        check_file_size(source.len())?;
+
         let file_id = self.files.len() as u32;
         let file = File::new(&*self.buckets, file_name, &source);
         self.files.push(file);
@@ -234,6 +545,107 @@ impl FileDb {
         Ok(file_id)
     }
 
+    /// Like `add`, but for a file's raw bytes, e.g. straight from disk. See
+    /// `decode_source` for what's accepted.
+    pub fn add_bytes(&mut self, file_name: &str, bytes: &[u8]) -> Result<u32, Error> {
+        let source = decode_source(file_name, bytes)?;
+        let message = |e| format!("couldn't add `{}`: {}", file_name, e);
+        return self.add(file_name, source).map_err(|e| error!(message(e)));
+    }
+
+    /// Adds the headers that the files from index `first` on include, and the ones
+    /// those include, reading each one with `read`. Quoted includes are looked for
+    /// next to the including file first, then in `dirs`. Headers that `read` can't
+    /// find are left for the bundled ones.
+    pub fn add_includes(
+        &mut self,
+        first: usize,
+        dirs: &[String],
+        mut read: impl FnMut(&str) -> Option<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let mut index = first;
+        while index < self.files.len() {
+            let (name, source) = (self.files[index].name, self.files[index].source);
+            index += 1;
+            for (include, quoted) in includes(source) {
+                let parent = match name.rfind('/') {
+                    Some(0) => "/",
+                    Some(idx) => &name[..idx],
+                    None => "",
+                };
+
+                let local = Some(parent).filter(|_| quoted);
+                for dir in local.into_iter().chain(dirs.iter().map(|d| &**d)) {
+                    let path = match dir {
+                        "" => include.to_string(),
+                        dir => format!("{}/{}", dir.trim_end_matches('/'), include),
+                    };
+
+                    if self.names.contains_key(&(false, &*path)) {
+                        break;
+                    }
+
+                    if let Some(bytes) = read(&path) {
+                        self.add_bytes(&path, &bytes)?;
+                        break;
+                    }
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Adds a file that's never compiled on its own, like a header, or replaces its
+    /// source if it already exists. The debugger keeps the expressions it's asked to
+    /// evaluate in one of these.
+    pub fn set_scratch(&mut self, file_name: &str, source: &str) -> u32 {
+        if let Some(&id) = self.names.get(&(false, file_name)) {
+            self.replace(id, source).unwrap();
+            return id;
+        }
+
+        let file_id = self.files.len() as u32;
+        let mut file = File::new(&*self.buckets, file_name, &source);
+        file.ty = FileType::Header;
+        self.files.push(file);
+        self.names.insert((false, file.name), file_id);
+
+        return file_id;
+    }
+
+    /// Replace the source of a file that was previously added, keeping its handle.
+    /// The old source is freed, so nothing borrowed from it can be used after this.
+    pub fn replace(&mut self, file_id: u32, source: &str) -> Result<(), &'static str> {
+        if (file_id as usize) < SYS_LIBS.len() {
+            return Err("can't replace system file");
+        }
+
+        let file = self.files.get(file_id as usize).ok_or("doesn't exist")?;
+        check_file_size(source.len())?;
+        let source = strip_bom(source);
+        let line_starts: Vec<usize> = line_starts(source).collect();
+
+        // Each replaced source gets its own arena, so that the next one can free it
+        let capa = source.len() + line_starts.len() * mem::size_of::<usize>() + 16;
+        let buckets = BucketListFactory::with_capacity(capa);
+        let file = File {
+            ty: file.ty,
+            name: file.name,
+            source: buckets.add_str(source),
+            line_starts: buckets.add_array(line_starts),
+        };
+
+        if let Some(mut old) = self.replaced.insert(file_id, buckets) {
+            unsafe { old.dealloc() };
+        }
+
+        self.files[file_id as usize] = file;
+        self.include_graph.borrow_mut().clear_file(file_id);
+        self.expansions.borrow_mut().clear_file(file_id);
+        return Ok(());
+    }
+
     pub fn display_loc(&self, out: &mut impl fmt::Write, loc: CodeLoc) -> fmt::Result {
         let file = self.files[loc.file as usize];
         let start_line = file.line_index(loc.start as usize).unwrap();
@@ -258,35 +670,131 @@ impl FileDb {
         return out.into_string();
     }
 
-    pub fn resolve_include(&self, include: &str, file: u32) -> Result<u32, &'static str> {
-        if !include.starts_with("/") {
-            let or_else = || -> &'static str { "not found" };
-            let mut path =
-                parent_if_file(self.files.get(file as usize).ok_or_else(or_else)?.name).to_string();
-            if !path.ends_with("/") && path != "" {
+    pub fn include_graph(&self) -> IncludeGraph {
+        return self.include_graph.borrow().clone();
+    }
+
+    pub fn add_include(&self, edge: IncludeEdge) {
+        self.include_graph.borrow_mut().add(edge);
+    }
+
+    pub fn add_expansion(&self, expansion: Expansion) -> u32 {
+        return self.expansions.borrow_mut().add(expansion);
+    }
+
+    /// Labels saying which macros produced the code at `loc`, innermost first;
+    /// empty if `loc` isn't in expanded code
+    pub fn expansion_sections(&self, loc: CodeLoc) -> Vec<ErrorSection> {
+        let table = self.expansions.borrow();
+        return match table.innermost(loc) {
+            Some(id) => table.sections(id),
+            None => Vec::new(),
+        };
+    }
+
+    /// Add a directory to search for includes, like `-I` on the command line.
+    /// Directories are searched in the order they're added.
+    pub fn add_include_path(&mut self, path: &str) {
+        let path = path.trim_end_matches('/');
+        let path = if path == "" { "/" } else { path };
+        self.include_paths.push(path.to_string());
+    }
+
+    /// Add every directory in a `:`-separated list, e.g. the value of the
+    /// `TCI_INCLUDE_PATH` environment variable. Empty entries are skipped.
+    pub fn add_include_paths(&mut self, paths: &str) {
+        for path in paths.split(':').filter(|p| *p != "") {
+            self.add_include_path(path);
+        }
+    }
+
+    fn search_include_paths(&self, include: &str, extra: &[String]) -> Option<u32> {
+        for dir in extra.iter().chain(&self.include_paths) {
+            let mut path = dir.clone();
+            if !path.ends_with("/") {
                 path.push_str("/");
             }
             path.push_str(include);
 
-            if let Some(id) = self.names.get(&(false, &path)) {
+            if let Some(id) = self.names.get(&(false, &*path)) {
+                return Some(*id);
+            }
+        }
+
+        return None;
+    }
+
+    /// Resolves `#include "include"`: relative to the including file first, then
+    /// the same way as `#include <include>`.
+    pub fn resolve_include(&self, include: &str, file: u32) -> Result<u32, &'static str> {
+        return self.resolve_include_in(include, file, &[]);
+    }
+
+    /// Like `resolve_include`, but searches `extra` (e.g. `-I` flags) before
+    /// this `FileDb`'s own include paths
+    pub fn resolve_include_in(
+        &self,
+        include: &str,
+        file: u32,
+        extra: &[String],
+    ) -> Result<u32, &'static str> {
+        if include.starts_with("/") {
+            if let Some(id) = self.names.get(&(false, include)) {
                 return Ok(*id);
             }
 
             return Err("not found");
         }
 
-        if let Some(id) = self.names.get(&(false, include)) {
+        let or_else = || -> &'static str { "not found" };
+        let mut path =
+            parent_if_file(self.files.get(file as usize).ok_or_else(or_else)?.name).to_string();
+        if !path.ends_with("/") && path != "" {
+            path.push_str("/");
+        }
+        path.push_str(include);
+
+        if let Some(id) = self.names.get(&(false, &path)) {
             return Ok(*id);
         }
 
-        return Err("not found");
+        return self.resolve_system_include_in(include, file, extra);
+    }
+
+    /// Resolves `#include <include>`. By default the include paths are searched
+    /// before the bundled system headers, so a project can provide its own
+    /// `stdio.h`; the bundled libc itself always uses the bundled headers.
+    pub fn resolve_system_include(&self, include: &str, file: u32) -> Result<u32, &'static str> {
+        return self.resolve_system_include_in(include, file, &[]);
     }
 
-    pub fn resolve_system_include(&self, include: &str) -> Result<u32, &'static str> {
+    /// Like `resolve_system_include`, but searches `extra` first; see
+    /// `resolve_include_in`
+    pub fn resolve_system_include_in(
+        &self,
+        include: &str,
+        file: u32,
+        extra: &[String],
+    ) -> Result<u32, &'static str> {
+        let from_system = (file as usize) < SYS_LIBS.len();
+        let prefer_user = self.system_headers == SystemHeaders::PreferUser && !from_system;
+
+        if prefer_user {
+            if let Some(id) = self.search_include_paths(include, extra) {
+                return Ok(id);
+            }
+        }
+
         if let Some(id) = self.names.get(&(true, include)) {
             return Ok(*id);
         }
 
+        if !from_system {
+            if let Some(id) = self.search_include_paths(include, extra) {
+                return Ok(id);
+            }
+        }
+
         return Err("not found");
     }
 
@@ -337,7 +845,9 @@ impl FileDb {
     pub fn column_number(&self, id: u32, line_index: usize, byte_index: usize) -> Option<usize> {
         let source = self.source(id)?;
         let line_range = self.line_range(id, line_index)?;
-        let column_index = column_index(source.as_ref(), line_range, byte_index);
+        let line = source.get(line_range.start..line_range.end)?;
+        let byte_index = byte_index.saturating_sub(line_range.start);
+        let column_index = display_column(line, byte_index, self.tab_width);
 
         Some(column_index + 1)
     }
@@ -354,71 +864,6 @@ impl FileDb {
     }
 }
 
-pub struct Symbols {
-    pub buckets: BucketListFactory,
-    pub to_symbol: HashMap<&'static str, u32>,
-    pub to_name: Vec<&'static str>,
-}
-
-impl Drop for Symbols {
-    fn drop(&mut self) {
-        unsafe { self.buckets.dealloc() };
-    }
-}
-
-#[repr(u32)]
-pub enum BuiltinSymbol {
-    Main = 0,
-
-    MacroDefined,
-
-    BuiltinPush,
-    BuiltinOp,
-}
-
-impl Symbols {
-    pub fn new() -> Self {
-        let mut new_self = Self {
-            buckets: BucketListFactory::new(),
-            to_symbol: HashMap::new(),
-            to_name: Vec::new(),
-        };
-
-        new_self.add_str("main");
-
-        new_self.add_str("defined");
-
-        new_self.add_str("__tci_builtin_push");
-        new_self.add_str("__tci_builtin_op");
-
-        new_self
-    }
-
-    pub fn add_str(&mut self, s: &str) -> u32 {
-        if let Some(id) = self.to_symbol.get(s) {
-            return *id;
-        }
-
-        let s = self.buckets.add_str(s);
-        let id = self.to_name.len() as u32;
-        self.to_symbol.insert(s, id);
-        self.to_name.push(s);
-        return id;
-    }
-
-    pub fn from_str(&self, s: &str) -> n32 {
-        if let Some(id) = self.to_symbol.get(s) {
-            return (*id).into();
-        }
-
-        return n32::NULL;
-    }
-
-    pub fn to_str(&self, id: u32) -> Option<&str> {
-        return self.to_name.get(id as usize).map(|a| *a);
-    }
-}
-
 #[cfg(not(target_os = "windows"))]
 const PATH_SEP: u8 = b'/';
 #[cfg(target_os = "windows")]
@@ -514,3 +959,32 @@ fn clean_internal(path: &[u8]) -> Vec<u8> {
     }
     out
 }
+
+/// The names in the `#include` lines of `source`, and whether each was in quotes
+fn includes(source: &str) -> Vec<(&str, bool)> {
+    let mut names = Vec::new();
+    for line in source.lines() {
+        let line = line.trim_start();
+        let rest = match line.strip_prefix('#') {
+            Some(rest) => rest.trim_start(),
+            None => continue,
+        };
+
+        let rest = match rest.strip_prefix("include") {
+            Some(rest) => rest.trim_start(),
+            None => continue,
+        };
+
+        let (close, quoted) = match rest.chars().next() {
+            Some('"') => ('"', true),
+            Some('<') => ('>', false),
+            _ => continue,
+        };
+
+        if let Some(end) = rest[1..].find(close) {
+            names.push((&rest[1..(end + 1)], quoted));
+        }
+    }
+
+    return names;
+}
@@ -0,0 +1,1193 @@
+//! Source formatter behind `tci fmt`. It prints the parse tree back out with
+//! consistent indentation and spacing, and puts the comments back where they
+//! were. Anything the tree can't reproduce (a statement that uses a macro, a
+//! comment in the middle of an expression, code in a skipped `#if` block) is
+//! copied from the source as-is.
+
+use crate::ast::*;
+use crate::filedb::*;
+use crate::interner::*;
+use crate::lexer::*;
+use crate::parser::*;
+use crate::util::*;
+use crate::CompileOptions;
+
+const INDENT: &str = "    ";
+
+/// Formats `file`, returning its new source text. Fails if it doesn't lex or
+/// parse.
+pub fn format(files: &FileDb, file: u32) -> Result<String, Vec<Error>> {
+    return format_with(files, file, &CompileOptions::default());
+}
+
+/// Like `format`, but lexes with the `-D`, `-I`, `--std` and other flags in
+/// `options`, for files that only lex with them
+pub fn format_with(
+    files: &FileDb,
+    file: u32,
+    options: &CompileOptions,
+) -> Result<String, Vec<Error>> {
+    let mut lexer = Lexer::new(files);
+    crate::configure_lexer(&mut lexer, options)?;
+    lexer.keep_trivia = true;
+    let (id, tokens) = lexer.lex(file).map_err(|e| vec![e])?;
+    let env = parse(id, tokens)?;
+
+    let comments = lexer.comments.iter().map(|loc| (loc, true));
+    let expansions = lexer.expansions.iter().map(|loc| (loc, false));
+    let mut trivia: Vec<Trivia> = comments
+        .chain(expansions)
+        .filter(|(loc, _)| loc.file == file)
+        .map(|(loc, is_comment)| Trivia {
+            loc: *loc,
+            is_comment,
+            printed: false,
+        })
+        .collect();
+    trivia.sort_by_key(|t| (t.loc.start, t.loc.end));
+    trivia.dedup_by_key(|t| (t.loc.start, t.loc.end));
+
+    let mut fmt = Formatter::new(files.source(file).unwrap(), file, &lexer.symbols);
+    fmt.trivia = trivia;
+    fmt.translation_unit(&env.tree);
+    return Ok(fmt.out);
+}
+
+/// Prints parts of declarations on one line, struct bodies and all, e.g. for
+/// `outline` to show signatures
+pub struct Signatures<'a> {
+    fmt: Formatter<'a>,
+}
+
+impl<'a> Signatures<'a> {
+    pub fn new(source: &'a str, file: u32, symbols: &'a Symbols) -> Self {
+        let mut fmt = Formatter::new(source, file, symbols);
+        fmt.one_line = true;
+        return Self { fmt };
+    }
+
+    fn take(&mut self) -> String {
+        self.fmt.line_start = true;
+        return core::mem::replace(&mut self.fmt.out, String::new());
+    }
+
+    /// e.g. `int main(int argc, char **argv)`
+    pub fn function(&mut self, func: &FunctionDefinition) -> String {
+        self.fmt.function_signature(func);
+        return self.take();
+    }
+
+    /// e.g. `static char *names[3]`
+    pub fn declarator(&mut self, specs: &[DeclarationSpecifier], decl: &Declarator) -> String {
+        self.fmt.decl_specifiers(specs);
+        self.fmt.write(" ");
+        self.fmt.declarator(decl);
+        return self.take();
+    }
+
+    /// e.g. `struct pair { int a; int *b; }`
+    pub fn type_specifier(&mut self, ty: &TypeSpecifier, loc: CodeLoc) -> String {
+        self.fmt.type_specifier(ty, loc);
+        return self.take();
+    }
+}
+
+/// A comment or macro use, which has to end up in the output somehow
+#[derive(Debug, Clone, Copy)]
+struct Trivia {
+    loc: CodeLoc,
+    is_comment: bool,
+    printed: bool,
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    file: u32,
+    symbols: &'a Symbols,
+    trivia: Vec<Trivia>,
+
+    out: String,
+    indent: usize,
+    line_start: bool, // nothing's been written to the current line yet
+    blank_ok: bool,   // a blank line from the source can be kept here
+    one_line: bool,   // struct bodies go on one line, for `Signatures`
+}
+
+impl<'a> Formatter<'a> {
+    fn new(source: &'a str, file: u32, symbols: &'a Symbols) -> Self {
+        return Self {
+            source,
+            file,
+            symbols,
+            trivia: Vec::new(),
+            out: String::new(),
+            indent: 0,
+            line_start: true,
+            blank_ok: false,
+            one_line: false,
+        };
+    }
+
+    fn write(&mut self, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+
+        if self.line_start {
+            for _ in 0..self.indent {
+                self.out.push_str(INDENT);
+            }
+
+            self.line_start = false;
+        }
+
+        self.out.push_str(s);
+    }
+
+    fn newline(&mut self) {
+        while self.out.ends_with(' ') {
+            self.out.pop();
+        }
+
+        self.out.push('\n');
+        self.line_start = true;
+    }
+
+    /// Starts a new line, plus a blank one if there were blank lines in the source
+    fn line_break(&mut self, newlines: u32) {
+        if !self.line_start {
+            self.newline();
+        }
+
+        if newlines > 1 && self.blank_ok && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn text(&self, start: u32, end: u32) -> &'a str {
+        return &self.source[start as usize..end as usize];
+    }
+
+    fn name(&self, id: u32) -> &'a str {
+        return self.symbols.to_str(id).unwrap();
+    }
+
+    /// The bracket or brace `plain` at `pos`, spelled the way the source spells
+    /// it, so that digraphs like `<%` stay digraphs
+    fn punct(&self, pos: u32, plain: &'static str) -> &'a str {
+        let rest = self.source.get(pos as usize..).unwrap_or("");
+        return match digraph(plain) {
+            Some(digraph) if rest.starts_with(digraph) => &rest[..digraph.len()],
+            _ => plain,
+        };
+    }
+
+    /// The bracket or brace `plain` that ends at `end`, like `punct`
+    fn punct_before(&self, end: u32, plain: &'static str) -> &'a str {
+        let before = self.source.get(..end as usize).unwrap_or("");
+        return match digraph(plain) {
+            Some(digraph) if before.ends_with(digraph) => &before[(before.len() - 2)..],
+            _ => plain,
+        };
+    }
+
+    /// Where the next bracket or brace is, starting at `pos`, which can be right
+    /// after an expression that has parentheses around it
+    fn punct_after(&self, pos: u32) -> u32 {
+        let mut pos = self.skip_trivia(pos);
+        while self.source.as_bytes().get(pos as usize) == Some(&b')') {
+            pos = self.skip_trivia(pos + 1);
+        }
+
+        return pos;
+    }
+
+    /// The identifier at `loc` the way the source spells it, which can have
+    /// backslash-newlines in the middle of it, or just `name` if it came from
+    /// somewhere else
+    fn spelled(&self, loc: CodeLoc, name: &'a str) -> &'a str {
+        if loc.file != self.file || loc.end as usize > self.source.len() {
+            return name;
+        }
+
+        let text = self.text(loc.start, loc.end);
+        let mut pieces = text.split("\\\n").flat_map(|p| p.split("\\\r\n"));
+        let mut rest = name;
+        let same = pieces.all(|piece| match rest.strip_prefix(piece) {
+            Some(after) => {
+                rest = after;
+                true
+            }
+            None => false,
+        });
+
+        return if same && rest.is_empty() { text } else { name };
+    }
+
+    fn trivia_in(&self, start: u32, end: u32) -> core::ops::Range<usize> {
+        let begin = self.trivia.partition_point(|t| t.loc.start < start);
+        let end = self.trivia.partition_point(|t| t.loc.start < end);
+        return begin..end;
+    }
+
+    fn comment_at(&self, pos: u32) -> Option<CodeLoc> {
+        let idx = self.trivia.partition_point(|t| t.loc.start < pos);
+        return match self.trivia.get(idx) {
+            Some(t) if t.is_comment && t.loc.start == pos => Some(t.loc),
+            _ => None,
+        };
+    }
+
+    /// The first position at or after `pos` that isn't whitespace or a comment
+    fn skip_trivia(&self, mut pos: u32) -> u32 {
+        loop {
+            match self.source.as_bytes().get(pos as usize) {
+                Some(c) if c.is_ascii_whitespace() => pos += 1,
+                Some(_) => match self.comment_at(pos) {
+                    Some(comment) => pos = comment.end,
+                    None => return pos,
+                },
+                None => return pos,
+            }
+        }
+    }
+
+    /// Declarations and expression statements don't include their `;`
+    fn semicolon_end(&self, end: u32) -> u32 {
+        let pos = self.skip_trivia(end);
+        return match self.source.as_bytes().get(pos as usize) {
+            Some(b';') => pos + 1,
+            _ => end,
+        };
+    }
+
+    fn stmt_end(&self, stmt: &Statement) -> u32 {
+        return match stmt.kind {
+            StatementKind::Expr(_) => self.semicolon_end(stmt.loc.end),
+            StatementKind::Labeled { labeled, .. } => self.stmt_end(labeled),
+            StatementKind::CaseLabeled { labeled, .. } => self.stmt_end(labeled),
+            StatementKind::DefaultCaseLabeled(labeled) => self.stmt_end(labeled),
+            StatementKind::Branch {
+                if_body, else_body, ..
+            } => self.stmt_end(else_body.unwrap_or(if_body)),
+            StatementKind::For { body, .. } => self.stmt_end(body),
+            StatementKind::ForDecl { body, .. } => self.stmt_end(body),
+            StatementKind::While { body, .. } => self.stmt_end(body),
+            StatementKind::Switch { body, .. } => self.stmt_end(body),
+            _ => stmt.loc.end,
+        };
+    }
+
+    /// Copies `start..end` from the source, shifting its lines over to the
+    /// current indentation
+    fn verbatim(&mut self, start: u32, end: u32) {
+        let line_begin = self.text(0, start).rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let column = start as usize - line_begin;
+
+        let mut spliced = false;
+        for (idx, line) in self.text(start, end).split('\n').enumerate() {
+            let mut line = line.trim_end_matches('\r');
+            if idx != 0 && spliced {
+                // The line is part of the one before it, maybe in the middle of
+                // a token, so its whitespace has to stay the way it is
+                self.out.push('\n');
+                self.line_start = false;
+            } else if idx != 0 {
+                self.newline();
+                let ws = line.len() - line.trim_start().len();
+                line = &line[core::cmp::min(ws, column)..];
+            }
+
+            spliced = line.ends_with('\\');
+            self.write(line);
+        }
+
+        for idx in self.trivia_in(start, end) {
+            self.trivia[idx].printed = true;
+        }
+    }
+
+    /// Prints the item at `start..end` with `f`, or copies it from the source if
+    /// `f` didn't account for every comment and macro use in it
+    fn item(&mut self, start: u32, end: u32, f: impl FnOnce(&mut Self)) {
+        let (len, indent, line_start) = (self.out.len(), self.indent, self.line_start);
+        f(self);
+
+        if self
+            .trivia_in(start, end)
+            .any(|idx| !self.trivia[idx].printed)
+        {
+            self.out.truncate(len);
+            self.indent = indent;
+            self.line_start = line_start;
+            self.verbatim(start, end);
+        }
+
+        self.blank_ok = true;
+    }
+
+    /// Prints what's between two items: comments, which stay on the line they
+    /// were on, and preprocessor lines or skipped code, which are kept as-is.
+    /// Returns how many newlines there were after the last of them.
+    fn gap(&mut self, start: u32, end: u32) -> u32 {
+        let bytes = self.source.as_bytes();
+        let mut pos = start;
+        let mut newlines = 0;
+
+        while pos < end {
+            let c = bytes[pos as usize];
+            if c == b'\n' {
+                newlines += 1;
+                pos += 1;
+                continue;
+            }
+
+            if c.is_ascii_whitespace() {
+                pos += 1;
+                continue;
+            }
+
+            if let Some(comment) = self.comment_at(pos) {
+                if newlines == 0 && !self.line_start {
+                    self.write(" ");
+                } else {
+                    self.line_break(newlines);
+                }
+
+                self.verbatim(comment.start, comment.end);
+                self.blank_ok = true;
+                pos = comment.end;
+                newlines = 0;
+                continue;
+            }
+
+            let line_begin = self.text(0, pos).rfind('\n').map(|i| i + 1).unwrap_or(0);
+            let begin = core::cmp::max(line_begin as u32, start);
+            let line_end = self.line_end(pos, end);
+            self.line_break(newlines);
+
+            let indent = core::mem::replace(&mut self.indent, 0);
+            self.verbatim(begin, line_end);
+            self.indent = indent;
+            self.blank_ok = true;
+            pos = line_end;
+            newlines = 0;
+        }
+
+        return newlines;
+    }
+
+    /// The end of the line at `pos`, counting backslash-newlines and comments
+    /// that run past the end of the line
+    fn line_end(&self, mut pos: u32, end: u32) -> u32 {
+        let bytes = self.source.as_bytes();
+        while pos < end {
+            if let Some(comment) = self.comment_at(pos) {
+                pos = comment.end;
+                continue;
+            }
+
+            let rest = &bytes[pos as usize..];
+            if rest.starts_with(b"\n") {
+                return pos;
+            } else if rest.starts_with(b"\\\n") {
+                pos += 2;
+            } else if rest.starts_with(b"\\\r\n") {
+                pos += 3;
+            } else {
+                pos += 1;
+            }
+        }
+
+        return core::cmp::min(pos, end);
+    }
+
+    fn translation_unit(&mut self, tree: &[GlobalStatement]) {
+        let mut prev = 0;
+        for stmt in tree {
+            if stmt.loc.file != self.file {
+                continue;
+            }
+
+            let end = match stmt.kind {
+                GlobalStatementKind::Declaration(decl) => self.semicolon_end(decl.loc.end),
+                GlobalStatementKind::FunctionDefinition(func) => func.loc.end,
+                GlobalStatementKind::Pragma(_) => continue, // `gap` copies the line
+            };
+
+            let start = self.skip_trivia(stmt.loc.start);
+            if start < prev {
+                continue; // more than one statement from the same macro
+            }
+
+            let newlines = self.gap(prev, start);
+            self.line_break(newlines);
+            self.item(start, end, |f| match &stmt.kind {
+                GlobalStatementKind::Declaration(decl) => f.declaration(decl),
+                GlobalStatementKind::FunctionDefinition(func) => f.function(func),
+                GlobalStatementKind::Pragma(_) => unreachable!(),
+            });
+
+            prev = end;
+        }
+
+        self.gap(prev, self.source.len() as u32);
+        if !self.line_start {
+            self.newline();
+        }
+    }
+
+    fn function(&mut self, func: &FunctionDefinition) {
+        self.function_signature(func);
+        self.write(" ");
+        self.block(&func.statements);
+    }
+
+    fn function_signature(&mut self, func: &FunctionDefinition) {
+        self.decl_specifiers(func.specifiers);
+        self.write(" ");
+        for pointer in func.pointer {
+            self.pointer(pointer.quals, true);
+        }
+
+        self.write(self.name(func.ident));
+        match &func.params {
+            Some(params) => self.params(params),
+            None => self.write("()"),
+        }
+    }
+
+    fn declaration(&mut self, decl: &Declaration) {
+        self.decl_specifiers(decl.specifiers);
+        for (idx, init) in decl.declarators.iter().enumerate() {
+            self.write(if idx == 0 { " " } else { ", " });
+            self.declarator(&init.declarator);
+
+            if let Some(init) = &init.initializer {
+                self.write(" = ");
+                self.initializer(init);
+            }
+        }
+
+        self.write(";");
+    }
+
+    fn initializer(&mut self, init: &Initializer) {
+        if let Some((field, _)) = init.field {
+            self.write(".");
+            self.write(self.name(field));
+            self.write(" = ");
+        }
+
+        match init.kind {
+            InitializerKind::Expr(expr) => self.expr(expr, 2),
+            InitializerKind::List(items) => {
+                let close = init.loc.end - self.punct_before(init.loc.end, "}").len() as u32;
+                self.initializer_list(items, init.loc.start, close);
+            }
+        }
+    }
+
+    /// `open` and `close` are where the list's `{` and `}` are
+    fn initializer_list(&mut self, items: &[Initializer], open: u32, close: u32) {
+        self.write(self.punct(open, "{"));
+        for (idx, item) in items.iter().enumerate() {
+            if idx != 0 {
+                self.write(", ");
+            }
+
+            self.initializer(item);
+        }
+        self.write(self.punct(close, "}"));
+    }
+
+    fn decl_specifiers(&mut self, specs: &[DeclarationSpecifier]) {
+        for (idx, spec) in specs.iter().enumerate() {
+            if idx != 0 {
+                self.write(" ");
+            }
+
+            match spec.kind {
+                DeclarationSpecifierKind::TypeSpecifier(ty) => self.type_specifier(&ty, spec.loc),
+                _ => self.write(self.text(spec.loc.start, spec.loc.end)),
+            }
+        }
+    }
+
+    fn specifier_qualifiers(&mut self, specs: &[SpecifierQualifier]) {
+        for (idx, spec) in specs.iter().enumerate() {
+            if idx != 0 {
+                self.write(" ");
+            }
+
+            match spec.kind {
+                SpecifierQualifierKind::TypeSpecifier(ty) => self.type_specifier(&ty, spec.loc),
+                _ => self.write(self.text(spec.loc.start, spec.loc.end)),
+            }
+        }
+    }
+
+    fn type_specifier(&mut self, ty: &TypeSpecifier, loc: CodeLoc) {
+        let (keyword, st) = match ty {
+            TypeSpecifier::Struct(st) => ("struct", st),
+            TypeSpecifier::Union(st) => ("union", st),
+            _ => return self.write(self.text(loc.start, loc.end)),
+        };
+
+        self.write(keyword);
+        let fields = match st.kind {
+            StructTypeKind::Named(ident) => {
+                self.write(" ");
+                return self.write(self.name(ident));
+            }
+            StructTypeKind::NamedDecl {
+                ident,
+                declarations,
+            } => {
+                self.write(" ");
+                self.write(self.name(ident));
+                declarations
+            }
+            StructTypeKind::UnnamedDecl { declarations } => declarations,
+        };
+
+        self.write(" ");
+        let text = self.text(st.loc.start, st.loc.end).as_bytes();
+        let open = match text.windows(2).position(|w| w[0] == b'{' || w == b"<%") {
+            Some(offset) => st.loc.start + offset as u32,
+            None => st.loc.start,
+        };
+
+        if self.one_line {
+            self.write(self.punct(open, "{"));
+            for field in fields {
+                self.write(" ");
+                self.struct_field(field);
+            }
+
+            self.write(" ");
+            return self.write(self.punct_before(st.loc.end, "}"));
+        }
+
+        let opened = self.open(open);
+        let mut prev = open + self.punct(open, "{").len() as u32;
+        for field in fields {
+            let start = self.skip_trivia(field.loc.start);
+            let newlines = self.gap(prev, start);
+            self.line_break(newlines);
+            self.item(start, field.loc.end, |f| f.struct_field(field));
+            prev = field.loc.end;
+        }
+
+        self.close(opened, prev, st.loc.end);
+    }
+
+    fn struct_field(&mut self, field: &StructField) {
+        self.specifier_qualifiers(field.specifiers);
+        for (idx, decl) in field.declarators.iter().enumerate() {
+            self.write(if idx == 0 { " " } else { ", " });
+            self.declarator(&decl.declarator);
+            if let Some(width) = decl.bit_width {
+                match decl.declarator.kind {
+                    DeclaratorKind::Abstract => self.write(": "),
+                    _ => self.write(" : "),
+                }
+
+                self.expr(width, 2);
+            }
+        }
+
+        self.write(";");
+    }
+
+    fn type_name(&mut self, ty: &TypeName) {
+        self.specifier_qualifiers(ty.specifiers);
+        if let Some(decl) = &ty.declarator {
+            self.spaced_declarator(decl);
+        }
+    }
+
+    /// A declarator that follows its specifiers, e.g. the `*` in `(int *)`
+    fn spaced_declarator(&mut self, decl: &Declarator) {
+        let is_array = |d: &DerivedDeclarator| match d.kind {
+            DerivedDeclaratorKind::Array(_) => true,
+            _ => false,
+        };
+
+        let bare = match decl.kind {
+            DeclaratorKind::Abstract => decl.derived.first().map(is_array).unwrap_or(true),
+            _ => false,
+        };
+
+        if !bare {
+            self.write(" ");
+        }
+
+        self.declarator(decl);
+    }
+
+    fn pointer(&mut self, quals: &[TypeQualifier], more: bool) {
+        self.write("*");
+        for (idx, qual) in quals.iter().enumerate() {
+            if idx != 0 {
+                self.write(" ");
+            }
+
+            self.write(self.text(qual.loc.start, qual.loc.end));
+        }
+
+        if more && quals.len() != 0 {
+            self.write(" ");
+        }
+    }
+
+    fn declarator(&mut self, decl: &Declarator) {
+        let is_pointer = |d: &&DerivedDeclarator| match d.kind {
+            DerivedDeclaratorKind::Pointer(_) => true,
+            _ => false,
+        };
+
+        let pointers: Vec<_> = decl.derived.iter().filter(is_pointer).collect();
+        let suffixes = decl.derived.len() != pointers.len();
+        let named = match decl.kind {
+            DeclaratorKind::Abstract => false,
+            _ => true,
+        };
+
+        for (idx, pointer) in pointers.iter().enumerate() {
+            if let DerivedDeclaratorKind::Pointer(quals) = pointer.kind {
+                let more = idx + 1 < pointers.len() || named || suffixes;
+                self.pointer(quals, more);
+            }
+        }
+
+        match decl.kind {
+            DeclaratorKind::Abstract => {}
+            DeclaratorKind::Identifier(id) => self.write(self.name(id)),
+            DeclaratorKind::Declarator(inner) => {
+                self.write("(");
+                self.declarator(inner);
+                self.write(")");
+            }
+        }
+
+        for derived in decl.derived {
+            match derived.kind {
+                DerivedDeclaratorKind::Pointer(_) => {}
+                DerivedDeclaratorKind::Array(array) => {
+                    self.write(self.punct(derived.loc.start, "["));
+                    for qual in array.qualifiers {
+                        self.write(self.text(qual.loc.start, qual.loc.end));
+                        self.write(" ");
+                    }
+
+                    if let ArraySizeKind::VariableExpression(size) = array.size.kind {
+                        self.expr(size, 2);
+                    }
+
+                    self.write(self.punct_before(derived.loc.end, "]"));
+                }
+                DerivedDeclaratorKind::Function(func) => self.params(&func),
+                DerivedDeclaratorKind::EmptyFunction => self.write("()"),
+            }
+        }
+    }
+
+    fn params(&mut self, func: &FunctionDeclarator) {
+        self.write("(");
+        for (idx, param) in func.parameters.iter().enumerate() {
+            if idx != 0 {
+                self.write(", ");
+            }
+
+            self.decl_specifiers(param.specifiers);
+            if let Some(decl) = &param.declarator {
+                self.spaced_declarator(decl);
+            }
+        }
+
+        if func.varargs {
+            self.write(", ...");
+        }
+
+        self.write(")");
+    }
+
+    /// Writes the `{` at `pos`, returning where its contents start in the output
+    fn open(&mut self, pos: u32) -> usize {
+        self.write(self.punct(pos, "{"));
+        self.indent += 1;
+        self.blank_ok = false;
+        return self.out.len();
+    }
+
+    /// Writes the `}` that ends at `end`, after what's between it and `prev`
+    fn close(&mut self, opened: usize, prev: u32, end: u32) {
+        let close = self.punct_before(end, "}");
+        self.gap(prev, end - close.len() as u32);
+        self.indent -= 1;
+        if self.out.len() != opened && !self.line_start {
+            self.newline();
+        }
+
+        self.write(close);
+    }
+
+    fn block(&mut self, block: &Block) {
+        let opened = self.open(block.loc.start);
+        let mut prev = block.loc.start + self.punct(block.loc.start, "{").len() as u32;
+        for item in block.stmts {
+            let start = self.skip_trivia(item.loc.start);
+            let end = match &item.kind {
+                BlockItemKind::Declaration(decl) => self.semicolon_end(decl.loc.end),
+                BlockItemKind::Statement(stmt) => self.stmt_end(stmt),
+            };
+
+            let newlines = self.gap(prev, start);
+            self.line_break(newlines);
+            self.item(start, end, |f| match &item.kind {
+                BlockItemKind::Declaration(decl) => f.declaration(decl),
+                BlockItemKind::Statement(stmt) => f.statement(stmt),
+            });
+
+            prev = end;
+        }
+
+        self.close(opened, prev, block.loc.end);
+    }
+
+    /// Whether `stmt` is just a `;`
+    fn is_empty(&self, stmt: &Statement) -> bool {
+        return match stmt.kind {
+            StatementKind::Block(block) => self.source.as_bytes()[block.loc.start as usize] == b';',
+            _ => false,
+        };
+    }
+
+    /// The body of an `if`, loop, or `switch`; returns whether it was a block
+    fn body(&mut self, body: &Statement) -> bool {
+        if self.is_empty(body) {
+            self.write(";");
+            return false;
+        }
+
+        if let StatementKind::Block(block) = &body.kind {
+            self.write(" ");
+            self.block(block);
+            return true;
+        }
+
+        self.indent += 1;
+        self.newline();
+        self.statement(body);
+        self.indent -= 1;
+        return false;
+    }
+
+    /// Labels go one level to the left of the statements around them
+    fn labeled(&mut self, label: impl FnOnce(&mut Self), labeled: &Statement) {
+        let indent = self.indent;
+        self.indent = indent.saturating_sub(1);
+        label(self);
+        self.write(":");
+
+        match labeled.kind {
+            StatementKind::Block(block) if !self.is_empty(labeled) => {
+                self.write(" ");
+                self.block(&block);
+                self.indent = indent;
+            }
+            _ => {
+                self.indent = indent;
+                self.newline();
+                self.statement(labeled);
+            }
+        }
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        match stmt.kind {
+            StatementKind::Labeled { label, labeled, .. } => {
+                self.labeled(|f| f.write(f.name(label)), labeled)
+            }
+            StatementKind::CaseLabeled {
+                case_value,
+                labeled,
+            } => self.labeled(
+                |f| {
+                    f.write("case ");
+                    f.expr(&case_value, 3);
+                },
+                labeled,
+            ),
+            StatementKind::DefaultCaseLabeled(labeled) => {
+                self.labeled(|f| f.write("default"), labeled)
+            }
+            StatementKind::Goto { label, .. } => {
+                self.write("goto ");
+                self.write(self.name(label));
+                self.write(";");
+            }
+            StatementKind::Expr(expr) => {
+                self.expr(&expr, 0);
+                self.write(";");
+            }
+            StatementKind::Ret => self.write("return;"),
+            StatementKind::RetVal(expr) => {
+                self.write("return ");
+                self.expr(&expr, 0);
+                self.write(";");
+            }
+            StatementKind::Branch {
+                if_cond,
+                if_body,
+                else_body,
+            } => {
+                self.write("if (");
+                self.expr(&if_cond, 0);
+                self.write(")");
+                let braced = self.body(if_body);
+
+                if let Some(else_body) = else_body {
+                    if braced {
+                        self.write(" else");
+                    } else {
+                        self.newline();
+                        self.write("else");
+                    }
+
+                    if let StatementKind::Branch { .. } = else_body.kind {
+                        self.write(" ");
+                        self.statement(else_body);
+                    } else {
+                        self.body(else_body);
+                    }
+                }
+            }
+            StatementKind::Block(block) => match self.is_empty(stmt) {
+                true => self.write(";"),
+                false => self.block(&block),
+            },
+            StatementKind::For {
+                at_start,
+                condition,
+                post_expr,
+                body,
+            } => {
+                self.write("for (");
+                if let Some(at_start) = &at_start {
+                    self.expr(at_start, 0);
+                }
+
+                self.write(";");
+                self.for_tail(condition, post_expr, body);
+            }
+            StatementKind::ForDecl {
+                decl,
+                condition,
+                post_expr,
+                body,
+            } => {
+                self.write("for (");
+                self.declaration(&decl);
+                self.for_tail(condition, post_expr, body);
+            }
+            StatementKind::While { condition, body } => {
+                self.write("while (");
+                self.expr(&condition, 0);
+                self.write(")");
+                self.body(body);
+            }
+            StatementKind::DoWhile { condition, body } => {
+                self.write("do");
+                match self.body(body) {
+                    true => self.write(" "),
+                    false => self.newline(),
+                }
+
+                self.write("while (");
+                self.expr(&condition, 0);
+                self.write(");");
+            }
+            StatementKind::Switch { expr, body } => {
+                self.write("switch (");
+                self.expr(&expr, 0);
+                self.write(")");
+                self.body(body);
+            }
+            StatementKind::Break => self.write("break;"),
+            StatementKind::Continue => self.write("continue;"),
+        }
+    }
+
+    fn for_tail(&mut self, condition: Option<Expr>, post_expr: Option<Expr>, body: &Statement) {
+        if let Some(condition) = &condition {
+            self.write(" ");
+            self.expr(condition, 0);
+        }
+
+        self.write(";");
+        if let Some(post_expr) = &post_expr {
+            self.write(" ");
+            self.expr(post_expr, 0);
+        }
+
+        self.write(")");
+        self.body(body);
+    }
+
+    fn expr_list(&mut self, exprs: &[Expr]) {
+        for (idx, expr) in exprs.iter().enumerate() {
+            if idx != 0 {
+                self.write(", ");
+            }
+
+            self.expr(expr, 2);
+        }
+    }
+
+    /// Writes `expr`, in parentheses if it binds less tightly than `min`
+    fn expr(&mut self, expr: &Expr, min: u8) {
+        let prec = precedence(expr);
+        if prec < min {
+            self.write("(");
+            self.expr(expr, 0);
+            self.write(")");
+            return;
+        }
+
+        match expr.kind {
+            ExprKind::StringLit(_) => self.string(expr.loc),
+            ExprKind::IntLit(_)
+            | ExprKind::LongLit(_)
+            | ExprKind::ULit(_)
+            | ExprKind::ULongLit(_)
+            | ExprKind::FloatLit(_)
+            | ExprKind::DoubleLit(_)
+            | ExprKind::CharLit(_) => self.write(self.text(expr.loc.start, expr.loc.end)),
+            ExprKind::ParenList(exprs) => self.expr_list(exprs),
+            ExprKind::Ident(id) => self.write(self.spelled(expr.loc, self.name(id))),
+            ExprKind::BinOp(BinOp::Index, base, index) => {
+                self.expr(base, 15);
+                self.write(self.punct(self.punct_after(base.loc.end), "["));
+                self.expr(index, 0);
+                self.write(self.punct(self.punct_after(index.loc.end), "]"));
+            }
+            ExprKind::BinOp(op, l, r) => {
+                self.expr(l, prec);
+                self.write(" ");
+                self.write(bin_op_str(op));
+                self.write(" ");
+                self.expr(r, prec + 1);
+            }
+            ExprKind::Assign { op, to, val } => {
+                self.expr(to, 14);
+                match op {
+                    AssignOp::Assign => self.write(" = "),
+                    AssignOp::MutAssign(op) => {
+                        self.write(" ");
+                        self.write(bin_op_str(op));
+                        self.write("= ");
+                    }
+                }
+
+                self.expr(val, 2);
+            }
+            ExprKind::SizeofExpr(expr) => {
+                self.write("sizeof(");
+                self.expr(expr, 0);
+                self.write(")");
+            }
+            ExprKind::SizeofTy(ty) => {
+                self.write("sizeof(");
+                self.type_name(&ty);
+                self.write(")");
+            }
+            ExprKind::Cast { to, from } => {
+                self.write("(");
+                self.type_name(&to);
+                self.write(")");
+                self.expr(from, 14);
+            }
+            ExprKind::CompoundLit { ty, items } => {
+                self.write("(");
+                self.type_name(&ty);
+                self.write(")");
+
+                // The list's `{` is after the `)`, and its `}` after the last item
+                let open = self.punct_after(ty.loc.end);
+                let last = items.last().map(|item| item.loc.end).unwrap_or(open + 1);
+                let mut close = self.punct_after(last);
+                if self.source.as_bytes().get(close as usize) == Some(&b',') {
+                    close = self.skip_trivia(close + 1);
+                }
+
+                self.initializer_list(items, open, close);
+            }
+            ExprKind::Member { member, base } => {
+                self.expr(base, 15);
+                self.write(".");
+                self.write(self.name(member));
+            }
+            ExprKind::PtrMember { member, base } => {
+                self.expr(base, 15);
+                self.write("->");
+                self.write(self.name(member));
+            }
+            ExprKind::UnaryOp(UnaryOp::PostIncr, operand) => {
+                self.expr(operand, 15);
+                self.write("++");
+            }
+            ExprKind::UnaryOp(UnaryOp::PostDecr, operand) => {
+                self.expr(operand, 15);
+                self.write("--");
+            }
+            ExprKind::UnaryOp(op, operand) => {
+                let (s, clashes): (_, &[UnaryOp]) = match op {
+                    UnaryOp::Neg => ("-", &[UnaryOp::Neg, UnaryOp::PreDecr]),
+                    UnaryOp::PreDecr => ("--", &[UnaryOp::Neg, UnaryOp::PreDecr]),
+                    UnaryOp::PreIncr => ("++", &[UnaryOp::PreIncr]),
+                    UnaryOp::BoolNot => ("!", &[]),
+                    UnaryOp::BitNot => ("~", &[]),
+                    UnaryOp::Deref => ("*", &[]),
+                    UnaryOp::Ref => ("&", &[]),
+                    UnaryOp::PostIncr | UnaryOp::PostDecr => unreachable!(),
+                };
+
+                // `- -x` shouldn't turn into `--x`
+                let clash = match operand.kind {
+                    ExprKind::UnaryOp(inner, _) => clashes.contains(&inner),
+                    _ => false,
+                };
+
+                self.write(s);
+                self.expr(operand, if clash { 15 } else { 14 });
+            }
+            ExprKind::Call { function, params } => {
+                self.expr(function, 15);
+                self.write("(");
+                self.expr_list(params);
+                self.write(")");
+            }
+            ExprKind::Ternary {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                self.expr(condition, 4);
+                self.write(" ? ");
+                self.expr(if_true, 0);
+                self.write(" : ");
+                self.expr(if_false, 3);
+            }
+            ExprKind::StmtExpr(block) => {
+                self.write("(");
+                self.block(&block);
+                self.write(")");
+            }
+        }
+    }
+
+    /// Adjacent string literals stay on separate lines if they started out that way
+    fn string(&mut self, loc: CodeLoc) {
+        let text = self.text(loc.start, loc.end);
+        let bytes = text.as_bytes();
+        let (mut pieces, mut multiline, mut idx) = (Vec::new(), false, 0);
+        while idx < bytes.len() {
+            if bytes[idx] != b'"' {
+                multiline |= bytes[idx] == b'\n';
+                idx += 1;
+                continue;
+            }
+
+            let begin = idx;
+            idx += 1;
+            while idx < bytes.len() && bytes[idx] != b'"' {
+                idx += if bytes[idx] == b'\\' { 2 } else { 1 };
+            }
+
+            idx = core::cmp::min(idx + 1, bytes.len());
+            pieces.push(&text[begin..idx]);
+        }
+
+        for (idx, piece) in pieces.into_iter().enumerate() {
+            if idx == 1 {
+                self.indent += 1;
+            }
+
+            if idx != 0 && multiline {
+                self.newline();
+            } else if idx != 0 {
+                self.write(" ");
+            }
+
+            self.write(piece);
+        }
+
+        if multiline {
+            self.indent -= 1;
+        }
+    }
+}
+
+/// How tightly an expression binds, from 1 for comma lists to 16 for literals
+fn precedence(expr: &Expr) -> u8 {
+    return match expr.kind {
+        ExprKind::ParenList(_) => 1,
+        ExprKind::Assign { .. } => 2,
+        ExprKind::Ternary { .. } => 3,
+        ExprKind::BinOp(op, _, _) => match op {
+            BinOp::BoolOr => 4,
+            BinOp::BoolAnd => 5,
+            BinOp::BitOr => 6,
+            BinOp::BitXor => 7,
+            BinOp::BitAnd => 8,
+            BinOp::Eq | BinOp::Neq => 9,
+            BinOp::Lt | BinOp::Gt | BinOp::Leq | BinOp::Geq => 10,
+            BinOp::LShift | BinOp::RShift => 11,
+            BinOp::Add | BinOp::Sub => 12,
+            BinOp::Mul | BinOp::Div | BinOp::Mod => 13,
+            BinOp::Index => 15,
+        },
+        ExprKind::UnaryOp(UnaryOp::PostIncr, _) | ExprKind::UnaryOp(UnaryOp::PostDecr, _) => 15,
+        ExprKind::Call { .. } | ExprKind::Member { .. } | ExprKind::PtrMember { .. } => 15,
+        ExprKind::UnaryOp(_, _) | ExprKind::Cast { .. } => 14,
+        ExprKind::SizeofExpr(_) | ExprKind::SizeofTy(_) => 14,
+        _ => 16,
+    };
+}
+
+pub fn bin_op_str(op: BinOp) -> &'static str {
+    return match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::Mod => "%",
+        BinOp::Lt => "<",
+        BinOp::Gt => ">",
+        BinOp::Leq => "<=",
+        BinOp::Geq => ">=",
+        BinOp::Eq => "==",
+        BinOp::Neq => "!=",
+        BinOp::LShift => "<<",
+        BinOp::RShift => ">>",
+        BinOp::BitAnd => "&",
+        BinOp::BitXor => "^",
+        BinOp::BitOr => "|",
+        BinOp::BoolAnd => "&&",
+        BinOp::BoolOr => "||",
+        BinOp::Index => unreachable!(),
+    };
+}
+
+/// The digraph that can be written instead of the bracket or brace `plain`
+fn digraph(plain: &str) -> Option<&'static str> {
+    return match plain {
+        "{" => Some("<%"),
+        "}" => Some("%>"),
+        "[" => Some("<:"),
+        "]" => Some(":>"),
+        _ => None,
+    };
+}
@@ -0,0 +1,267 @@
+//! Stack frame sizes and how deep calls from `main` can go, for `--emit=frames`.
+//! Helps explain a stack overflow: each call costs its frame, so deep or
+//! unbounded recursion runs into the interpreter's limits.
+//!
+//! Sizes match how the assembler lays out frames without `-O1`, where locals
+//! can share slots. Calls into the bundled libc aren't counted.
+
+use crate::callgraph::*;
+use crate::filedb::*;
+use crate::lexer::*;
+use crate::parser::*;
+use crate::runtime::memory::Limits;
+use crate::tc_ast::*;
+use crate::type_checker::*;
+use crate::util::*;
+
+/// Variables the interpreter's stack can hold; see `Memory::add_stack_var`
+pub const MAX_STACK_VARS: u32 = 4000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub name: String,
+    pub bytes: u32, // return value, parameters, and the most locals live at once
+    pub slots: u32, // stack variables; the interpreter allocates one per local
+    pub loc: CodeLoc,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Depth {
+    /// The call chain from `main` that uses the most stack
+    Bounded {
+        chain: Vec<String>,
+        bytes: u32,
+        slots: u32,
+    },
+    /// `main` reaches a group of functions that call each other
+    Unbounded {
+        chain: Vec<String>,
+        cycle: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameReport {
+    pub frames: Vec<Frame>,   // sorted by name
+    pub depth: Option<Depth>, // `None` if there's no `main`
+}
+
+/// Frame sizes of every function defined outside the bundled libc
+pub fn frames(files: &FileDb) -> Result<FrameReport, Vec<Error>> {
+    let graph = call_graph(files)?;
+
+    let mut lexer = Lexer::new(files);
+    let mut frames: Vec<Frame> = Vec::new();
+    for file in files.impls() {
+        if files.is_system(file) {
+            continue;
+        }
+
+        let (id, tokens) = lexer.lex(file).map_err(|e| vec![e])?;
+        let env = parse(id, tokens)?;
+        let tu = check_tree(files, env.file, &lexer.symbols, &env.tree)?;
+
+        for (&ident, func) in &tu.functions {
+            if let Some(defn) = &func.defn {
+                let name = lexer.symbols.to_str(ident).unwrap();
+                frames.push(frame(name, func, defn));
+            }
+        }
+    }
+
+    frames.sort_by(|a, b| a.name.cmp(&b.name));
+    let depth = match frames.iter().any(|f| f.name == "main") {
+        true => Some(deepest(&graph, &frames)),
+        false => None,
+    };
+
+    return Ok(FrameReport { frames, depth });
+}
+
+fn frame(name: &str, func: &TCFunction, defn: &TCFuncDefn) -> Frame {
+    // The caller allocates the return value and each parameter, plus an empty
+    // variable so that varargs don't run off the stack
+    let params = defn.locals[..defn.param_count as usize].iter();
+    let mut bytes = func.func_type.return_type.repr_size();
+    bytes += params.map(|(_, var)| var.ty.repr_size()).sum::<u32>();
+    let mut slots = defn.param_count + 2;
+
+    // Locals are allocated when their scope begins and freed when it ends
+    let (mut live, mut peak) = ((0, 0), (0, 0));
+    let mut scopes = Vec::new();
+    for op in defn.ops {
+        match op.kind {
+            TCOpcodeKind::ScopeBegin(vars, _) => {
+                let vars = vars.into_iter().filter(|(&var, _)| var >= defn.param_count);
+                let (count, size) = vars.fold((0, 0), |(count, size), (_, ty)| {
+                    (count + 1, size + ty.size().opt().unwrap_or(0))
+                });
+
+                scopes.push((count, size));
+                live = (live.0 + count, live.1 + size);
+                peak = (peak.0.max(live.0), peak.1.max(live.1));
+            }
+            TCOpcodeKind::ScopeEnd { .. } => {
+                let (count, size) = scopes.pop().unwrap_or((0, 0));
+                live = (live.0 - count, live.1 - size);
+            }
+            _ => {}
+        }
+    }
+
+    slots += peak.0;
+    bytes += peak.1;
+
+    return Frame {
+        name: name.to_string(),
+        bytes,
+        slots,
+        loc: defn.loc,
+    };
+}
+
+fn deepest(graph: &CallGraph, frames: &[Frame]) -> Depth {
+    struct Search<'a> {
+        graph: &'a CallGraph,
+        frames: &'a [Frame],
+        deepest: HashMap<&'a str, (Vec<String>, u32, u32)>, // (chain, bytes, slots)
+    }
+
+    impl<'a> Search<'a> {
+        /// The most stack a call to `func` can use, or the chain to a cycle
+        fn visit(&mut self, func: &'a str) -> Result<(Vec<String>, u32, u32), Depth> {
+            if let Some(found) = self.deepest.get(func) {
+                return Ok(found.clone());
+            }
+
+            let graph = self.graph;
+            if let Some(cycle) = graph.cycles.iter().find(|c| c.contains(&func.to_string())) {
+                let (chain, cycle) = (vec![func.to_string()], cycle.clone());
+                return Err(Depth::Unbounded { chain, cycle });
+            }
+
+            let frame = self.frames.iter().find(|f| f.name == func);
+            let (bytes, slots) = frame.map(|f| (f.bytes, f.slots)).unwrap_or((0, 0));
+            let mut best = (Vec::new(), 0, 0);
+            let callees = graph.edges.iter().filter(|e| e.caller == func);
+            for edge in callees.filter(|e| graph.defined.contains(&e.callee)) {
+                match self.visit(&edge.callee) {
+                    Ok(found) if found.1 > best.1 => best = found,
+                    Ok(_) => {}
+                    Err(Depth::Unbounded { mut chain, cycle }) => {
+                        chain.insert(0, func.to_string());
+                        return Err(Depth::Unbounded { chain, cycle });
+                    }
+                    Err(depth) => return Err(depth),
+                }
+            }
+
+            best.0.insert(0, func.to_string());
+            let found = (best.0, best.1 + bytes, best.2 + slots);
+            self.deepest.insert(func, found.clone());
+            return Ok(found);
+        }
+    }
+
+    let mut search = Search {
+        graph,
+        frames,
+        deepest: HashMap::new(),
+    };
+
+    return match search.visit("main") {
+        Ok((chain, bytes, slots)) => Depth::Bounded {
+            chain,
+            bytes,
+            slots,
+        },
+        Err(depth) => depth,
+    };
+}
+
+impl FrameReport {
+    /// A table of frames, then the deepest call chain, like:
+    ///
+    /// ```text
+    /// function  bytes  slots
+    /// fact          8      3
+    /// main          4      2
+    ///
+    /// unbounded recursion: main -> fact -> fact -> ...
+    /// each time around uses 8 bytes and 3 stack variables; about 999 times fit before running out of nested calls
+    /// ```
+    pub fn to_text(&self) -> String {
+        let width = self.frames.iter().map(|f| f.name.len()).max().unwrap_or(0);
+        let width = width.max("function".len());
+
+        let mut out = format!("{:<width$}  bytes  slots\n", "function", width = width);
+        for f in &self.frames {
+            out += &format!(
+                "{:<width$}  {:>5}  {:>5}\n",
+                f.name,
+                f.bytes,
+                f.slots,
+                width = width
+            );
+        }
+
+        let limits = Limits::DEFAULT;
+        let frame = |name: &str| self.frames.iter().find(|f| f.name == name);
+        match &self.depth {
+            None => {}
+            Some(Depth::Bounded {
+                chain,
+                bytes,
+                slots,
+            }) => {
+                out += &format!("\ndeepest call chain: {}\n", chain.join(" -> "));
+                out += &format!(
+                    "uses {} of {} bytes of stack, and {} of {} stack variables\n",
+                    bytes, limits.max_stack_bytes, slots, MAX_STACK_VARS
+                );
+            }
+            Some(Depth::Unbounded { chain, cycle }) => {
+                let entry = chain.last().unwrap();
+                let mut path = chain.clone();
+                path.extend(cycle.iter().filter(|f| *f != entry).cloned());
+                path.push(entry.clone());
+                out += &format!("\nunbounded recursion: {} -> ...\n", path.join(" -> "));
+
+                // Frames on the stack before the recursion starts, and per time around
+                let sum = |names: &[String]| {
+                    let frames = names.iter().filter_map(|name| frame(name));
+                    frames.fold((0, 0), |(bytes, slots), f| {
+                        (bytes + f.bytes, slots + f.slots)
+                    })
+                };
+                let (before, before_slots) = sum(&chain[..chain.len() - 1]);
+                let (each, each_slots) = sum(cycle);
+
+                let max_bytes = limits.max_stack_bytes as u32;
+                let max_depth = limits.max_call_depth as u32;
+                let fits = [
+                    (
+                        max_bytes.saturating_sub(before) / each.max(1),
+                        "bytes of stack",
+                    ),
+                    (
+                        MAX_STACK_VARS.saturating_sub(before_slots) / each_slots.max(1),
+                        "stack variables",
+                    ),
+                    (
+                        max_depth.saturating_sub(chain.len() as u32 - 1) / cycle.len() as u32,
+                        "nested calls",
+                    ),
+                ];
+                let (fit, limit) = fits.iter().min_by_key(|(fit, _)| *fit).unwrap();
+
+                out += &format!(
+                    "each time around uses {} bytes and {} stack variables; about {} times fit before running out of {}\n",
+                    each, each_slots, fit, limit
+                );
+            }
+        }
+
+        return out;
+    }
+}
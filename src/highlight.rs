@@ -0,0 +1,233 @@
+//! Semantic highlighting: what each token in a file is, for editors that color
+//! code by meaning rather than by syntax alone. Type names from typedefs, and
+//! whether a name is a function, parameter, or variable, can't be told from the
+//! text, so this uses the parser's and type checker's results.
+
+use crate::filedb::*;
+use crate::lexer::*;
+use crate::parser::*;
+use crate::tc_ast::*;
+use crate::type_checker::*;
+use crate::util::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HighlightKind {
+    Keyword,
+    Type, // typedef names, and struct and union tags
+    Function,
+    Parameter,
+    Variable,
+    Member,
+    Macro,
+    String,
+    Number,
+    Comment,
+}
+
+impl HighlightKind {
+    /// Every kind, in the order of the LSP legend
+    pub const ALL: [HighlightKind; 10] = [
+        HighlightKind::Keyword,
+        HighlightKind::Type,
+        HighlightKind::Function,
+        HighlightKind::Parameter,
+        HighlightKind::Variable,
+        HighlightKind::Member,
+        HighlightKind::Macro,
+        HighlightKind::String,
+        HighlightKind::Number,
+        HighlightKind::Comment,
+    ];
+
+    /// The name of the matching LSP semantic token type
+    pub fn lsp_name(self) -> &'static str {
+        return match self {
+            HighlightKind::Keyword => "keyword",
+            HighlightKind::Type => "type",
+            HighlightKind::Function => "function",
+            HighlightKind::Parameter => "parameter",
+            HighlightKind::Variable => "variable",
+            HighlightKind::Member => "property",
+            HighlightKind::Macro => "macro",
+            HighlightKind::String => "string",
+            HighlightKind::Number => "number",
+            HighlightKind::Comment => "comment",
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct Highlight {
+    pub kind: HighlightKind,
+    pub loc: CodeLoc,
+}
+
+/// Classifies the tokens in `file`, in source order. Punctuation, and names that
+/// are none of the above, like labels, are left out. If the file has type errors,
+/// names are still classified as well as the parser can, without the type checker.
+/// Macro bodies aren't tokens until they're expanded, so only macro names are.
+pub fn highlight(files: &FileDb, file: u32) -> Result<Vec<Highlight>, Vec<Error>> {
+    let mut lexer = Lexer::new(files);
+    lexer.keep_trivia = true;
+    let (id, tokens) = lexer.lex(file).map_err(|e| vec![e])?;
+    let env = parse(id, tokens)?;
+    let tu = check_tree(files, env.file, &lexer.symbols, &env.tree).ok();
+
+    let source = files.source(file).unwrap();
+    let mut out = Vec::new();
+    let mut add = |kind, loc: CodeLoc| out.push(Highlight { kind, loc });
+
+    for &loc in &lexer.comments {
+        add(HighlightKind::Comment, loc);
+    }
+
+    // Uses of macros; the tokens they expand to don't appear in the source
+    for &loc in lexer.expansions.iter().filter(|loc| loc.file == file) {
+        let name = source[loc.start as usize..]
+            .bytes()
+            .take_while(|&c| is_ident_char(c));
+        let end = loc.start + name.count() as u32;
+        add(HighlightKind::Macro, l(loc.start, end, file));
+    }
+
+    for (&id, &(_, def)) in &lexer.macros {
+        let name = lexer.symbols.to_str(id).unwrap();
+        if def.file != file {
+            continue;
+        }
+
+        let text = &source[def.start as usize..def.end as usize];
+        let after = text.find("define").map(|idx| idx + "define".len());
+        if let Some(start) = after.and_then(|a| text[a..].find(name).map(|idx| a + idx)) {
+            let start = def.start + start as u32;
+            add(
+                HighlightKind::Macro,
+                l(start, start + name.len() as u32, file),
+            );
+        }
+    }
+
+    let mut refs = HashMap::new();
+    let mut params = Vec::new();
+    let mut typedefs = Vec::new();
+    if let Some(tu) = &tu {
+        for r in tu.refs.iter().filter(|r| r.loc.file == file) {
+            refs.entry(r.ident).or_insert_with(Vec::new).push(r);
+        }
+
+        for defn in tu.functions.values().filter_map(|f| f.defn) {
+            let count = defn.param_count as usize;
+            params.extend(defn.locals[..count].iter().map(|(_, var)| var.loc));
+        }
+
+        typedefs.extend(tu.typedefs.keys().map(|&(id, _)| id));
+    }
+
+    let global_types = &env.symbol_is_type.borrow()[0];
+    let tokens = &env.tokens;
+    let mut prev = TokenKind::Whitespace; // the last token that isn't whitespace
+    let mut prev_is_tag = false; // whether `prev` names a struct or union
+    let mut in_struct = Vec::new(); // for each open brace, whether it's a struct body
+    let mut number: Option<CodeLoc> = None;
+    for idx in 0..tokens.len() {
+        let (kind, loc) = (tokens.kind(idx), tokens.loc(idx));
+        if kind == TokenKind::Whitespace {
+            continue;
+        }
+
+        let before = core::mem::replace(&mut prev, kind);
+        let struct_kw = matches!(before, TokenKind::Struct | TokenKind::Union);
+        let after_tag = core::mem::replace(&mut prev_is_tag, false);
+        match kind {
+            TokenKind::LBrace => in_struct.push(struct_kw || after_tag),
+            TokenKind::RBrace => drop(in_struct.pop()),
+            TokenKind::Ident(_) => prev_is_tag = struct_kw,
+            _ => {}
+        }
+
+        if loc.file != file || tokens.expansion(idx).is_some() {
+            continue;
+        }
+
+        // Numbers are lexed a character at a time
+        let is_number = match kind {
+            TokenKind::IntChar(_) => true,
+            TokenKind::Dot => number.map(|n| n.end == loc.start).unwrap_or(false),
+            _ => false,
+        };
+        match number {
+            Some(n) if is_number && n.end == loc.start => {
+                number = Some(l(n.start, loc.end, file));
+                continue;
+            }
+            Some(n) => add(HighlightKind::Number, n),
+            None => {}
+        }
+
+        number = None;
+        if is_number {
+            number = Some(loc);
+            continue;
+        }
+
+        let id = match kind {
+            TokenKind::Ident(id) => id,
+            TokenKind::StringLit(_) | TokenKind::CharLit(_) | TokenKind::MultiCharLit(_) => {
+                add(HighlightKind::String, loc);
+                continue;
+            }
+            kind if kind != TokenKind::Unimplemented && KEYWORD_KINDS.contains(&kind) => {
+                add(HighlightKind::Keyword, loc);
+                continue;
+            }
+            _ => continue,
+        };
+
+        if let TokenKind::Dot | TokenKind::Arrow = before {
+            add(HighlightKind::Member, loc);
+            continue;
+        }
+
+        if let TokenKind::Struct | TokenKind::Union | TokenKind::Enum = before {
+            add(HighlightKind::Type, loc);
+            continue;
+        }
+
+        // The narrowest reference covering this name: the name itself when it's
+        // used, or the whole declarator when it's declared
+        let found = refs.get(&id).and_then(|refs| {
+            let covers = |r: &&&TCSymbolRef| r.loc.start <= loc.start && loc.end <= r.loc.end;
+            return refs
+                .iter()
+                .filter(covers)
+                .min_by_key(|r| r.loc.end - r.loc.start);
+        });
+
+        let kind = match found.map(|r| (r.scope, r.ty)) {
+            Some((TCSymbolScope::Local(decl), _)) if params.contains(&decl) => {
+                HighlightKind::Parameter
+            }
+            Some((_, ty)) if ty.is_function() => HighlightKind::Function,
+            Some(_) => HighlightKind::Variable,
+            None if typedefs.contains(&id) || global_types.get(&id) == Some(&true) => {
+                HighlightKind::Type
+            }
+            None if in_struct.last() == Some(&true) => HighlightKind::Member,
+            None => match &tu {
+                Some(tu) if tu.functions.contains_key(&id) => HighlightKind::Function,
+                Some(tu) if tu.vars.contains_key(&id) => HighlightKind::Variable,
+                _ => continue,
+            },
+        };
+
+        add(kind, loc);
+    }
+
+    if let Some(n) = number {
+        add(HighlightKind::Number, n);
+    }
+
+    out.sort_by_key(|h| (h.loc.start, h.loc.end));
+    return Ok(out);
+}
@@ -0,0 +1,126 @@
+use crate::filedb::*;
+use crate::interner::*;
+use crate::lexer::*;
+use crate::parser::*;
+use crate::runtime::*;
+use crate::tc_ast::*;
+use crate::util::*;
+use crate::CompileOptions;
+
+pub struct CachedUnit {
+    pub tu: TranslationUnit,
+    pub deps: Vec<u32>, // every file that was lexed to produce `tu`, including the impl itself
+}
+
+/// Compiler state that survives between compiles, so that after a file changes
+/// only the translation units that include it are lexed, parsed, and checked again.
+pub struct Incremental {
+    pub symbols: Symbols,
+    pub units: HashMap<u32, CachedUnit>,
+    pub options: CompileOptions, // what `units` were compiled with
+}
+
+impl Incremental {
+    pub fn new() -> Self {
+        return Self {
+            symbols: Symbols::new(),
+            units: HashMap::new(),
+            options: CompileOptions::default(),
+        };
+    }
+
+    /// Drops every cached translation unit that depends on `file`. Call this after
+    /// `FileDb::replace`, before the next `compile`.
+    pub fn invalidate(&mut self, file: u32) {
+        self.units.retain(|_, unit| !unit.deps.contains(&file));
+    }
+
+    /// Returns the impl files that would be rechecked by the next `compile`
+    pub fn stale(&self, files: &FileDb) -> Vec<u32> {
+        let impls = files.impls().into_iter();
+        return impls.filter(|f| !self.units.contains_key(f)).collect();
+    }
+
+    pub fn compile(&mut self, files: &FileDb) -> Result<BinaryData, Vec<Error>> {
+        return self.compile_with_options(files, &CompileOptions::default());
+    }
+
+    /// Like `compile`, but with the flags in `options`. Every unit is checked
+    /// again if they change how files are lexed or optimized.
+    pub fn compile_with_options(
+        &mut self,
+        files: &FileDb,
+        options: &CompileOptions,
+    ) -> Result<BinaryData, Vec<Error>> {
+        if !same_units(options, &self.options) {
+            self.units.clear();
+        }
+        self.options = options.clone();
+
+        let mut errors: Vec<Error> = Vec::new();
+        let stale = self.stale(files);
+
+        let symbols = core::mem::replace(&mut self.symbols, Symbols::new());
+        let mut lexer = Lexer::with_symbols(files, symbols);
+        if let Err(errs) = crate::configure_lexer(&mut lexer, options) {
+            self.symbols = lexer.symbols();
+            return Err(errs);
+        }
+
+        let mut checked = Vec::new();
+        for file in stale {
+            let lexed = match lexer.lex(file) {
+                Ok(lexed) => lexed,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+
+            let mut deps = core::mem::replace(&mut lexer.deps, Vec::new());
+            deps.push(file);
+
+            let (id, tokens) = lexed;
+            match parse(id, tokens) {
+                Ok(env) => checked.push((env, deps)),
+                Err(e) => errors.extend(e),
+            }
+        }
+
+        self.symbols = lexer.symbols();
+
+        for (env, deps) in checked {
+            match crate::check_parsed(files, &self.symbols, env) {
+                Ok(mut tu) => {
+                    crate::optimize_unit(&mut tu, options);
+                    self.units.insert(tu.file, CachedUnit { tu, deps });
+                }
+                Err(e) => errors.extend(e),
+            }
+        }
+
+        if errors.len() != 0 {
+            return Err(errors);
+        }
+
+        let mut assembler = crate::new_assembler(&self.symbols, options)?;
+        for file in files.impls() {
+            if let Err(err) = assembler.add_file(files, &self.units[&file].tu) {
+                return Err(vec![err]);
+            }
+        }
+
+        return assembler.assemble(files, &self.symbols);
+    }
+}
+
+/// Whether units compiled with `a` are the same as with `b`. Everything else in
+/// the options is only used by the assembler, which runs on every compile anyway.
+fn same_units(a: &CompileOptions, b: &CompileOptions) -> bool {
+    return a.gnu_extensions == b.gnu_extensions
+        && a.lex_limits == b.lex_limits
+        && a.defines == b.defines
+        && a.include_paths == b.include_paths
+        && a.opt_level == b.opt_level
+        && a.no_inline == b.no_inline;
+}
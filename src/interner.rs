@@ -0,0 +1,163 @@
+//! String interning for identifiers. Every name the compiler sees gets a stable
+//! `u32` symbol, shared by the lexer, type checker, and assembler.
+
+use crate::util::*;
+use alloc::rc::Rc;
+
+#[repr(u32)]
+pub enum BuiltinSymbol {
+    Main = 0,
+
+    MacroDefined,
+
+    BuiltinPush,
+    BuiltinOp,
+
+    Line,
+    File,
+    Date,
+    Time,
+    Func,
+    Function,
+
+    Typeof, // only a keyword with GNU extensions on
+    GnuTypeof,
+}
+
+const BUILTINS: [&str; 12] = [
+    "main",
+    "defined",
+    "__tci_builtin_push",
+    "__tci_builtin_op",
+    "__LINE__",
+    "__FILE__",
+    "__DATE__",
+    "__TIME__",
+    "__func__",
+    "__FUNCTION__",
+    "typeof",
+    "__typeof__",
+];
+
+/// Interned right after the builtins, so `keyword` is a range check
+pub const KEYWORDS: [&str; 58] = [
+    "auto",
+    "break",
+    "case",
+    "char",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "long",
+    "register",
+    "restrict",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "typedef",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+    "_Alignas",
+    "_Alignof",
+    "_Atomic",
+    "_Bool",
+    "_Complex",
+    "_Generic",
+    "_Imaginary",
+    "_Noreturn",
+    "_Static_assert",
+    "_Thread_local",
+    "_Float16",
+    "_Float16x",
+    "_Float32",
+    "_Float32x",
+    "_Float64",
+    "_Float64x",
+    "_Float128",
+    "_Float128x",
+    "_Decimal32",
+    "_Decimal32x",
+    "_Decimal64",
+    "_Decimal64x",
+    "_Decimal128",
+    "_Decimal128x",
+];
+
+const FIRST_KEYWORD: u32 = BUILTINS.len() as u32;
+
+#[derive(Clone)]
+pub struct Symbols {
+    pub to_symbol: HashMap<Rc<str>, u32>,
+    to_name: Vec<Rc<str>>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        let mut new_self = Self {
+            to_symbol: HashMap::new(),
+            to_name: Vec::new(),
+        };
+
+        for name in BUILTINS.iter().chain(KEYWORDS.iter()) {
+            new_self.add_str(name);
+        }
+
+        new_self
+    }
+
+    pub fn add_str(&mut self, s: &str) -> u32 {
+        if let Some(id) = self.to_symbol.get(s) {
+            return *id;
+        }
+
+        let s: Rc<str> = Rc::from(s);
+        let id = self.to_name.len() as u32;
+        self.to_symbol.insert(s.clone(), id);
+        self.to_name.push(s);
+        return id;
+    }
+
+    pub fn from_str(&self, s: &str) -> n32 {
+        if let Some(id) = self.to_symbol.get(s) {
+            return (*id).into();
+        }
+
+        return n32::NULL;
+    }
+
+    pub fn to_str(&self, id: u32) -> Option<&str> {
+        return self.to_name.get(id as usize).map(|a| &**a);
+    }
+
+    /// Index into `KEYWORDS` if the symbol is a reserved word
+    pub fn keyword(&self, id: u32) -> Option<usize> {
+        let idx = id.checked_sub(FIRST_KEYWORD)? as usize;
+        if idx < KEYWORDS.len() {
+            return Some(idx);
+        }
+
+        return None;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.to_name.len();
+    }
+}
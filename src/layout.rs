@@ -0,0 +1,225 @@
+//! Memory layout of every struct and union in a program, for `--emit=layout`.
+//! Shows where each member goes and the padding the compiler puts between them,
+//! which helps when teaching struct packing or when `sizeof` is bigger than
+//! expected.
+
+use crate::filedb::*;
+use crate::interner::*;
+use crate::lexer::*;
+use crate::parser::*;
+use crate::tc_ast::*;
+use crate::type_checker::*;
+use crate::util::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemberLayout {
+    pub name: String,
+    pub ty: String,
+    pub offset: u32,
+    pub size: u32, // 0 for a flexible array member
+    pub align: u32,
+    pub bits: Option<(u32, u32)>, // first bit in the byte at `offset`, and width
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructLayout {
+    pub name: String, // e.g. `struct pair`, or the typedef of an anonymous struct
+    pub is_union: bool,
+    pub size: u32,
+    pub align: u32,
+    pub members: Vec<MemberLayout>,
+    pub loc: CodeLoc,
+}
+
+/// A row of the layout table: a member, or padding the compiler added
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutRow<'a> {
+    Member(&'a MemberLayout),
+    Padding { offset: u32, size: u32 },
+}
+
+impl StructLayout {
+    /// Members and padding in the order they're laid out. Padding at the end
+    /// rounds the size up to the alignment, so that arrays stay aligned.
+    pub fn rows(&self) -> Vec<LayoutRow<'_>> {
+        let mut rows = Vec::new();
+        let mut end = 0;
+        for member in &self.members {
+            if member.offset > end {
+                let size = member.offset - end;
+                rows.push(LayoutRow::Padding { offset: end, size });
+            }
+
+            rows.push(LayoutRow::Member(member));
+            end = core::cmp::max(end, member.offset + member.size);
+        }
+
+        if self.size > end {
+            let size = self.size - end;
+            rows.push(LayoutRow::Padding { offset: end, size });
+        }
+
+        return rows;
+    }
+
+    pub fn padding(&self) -> u32 {
+        let rows = self.rows().into_iter();
+        let padding = rows.map(|row| match row {
+            LayoutRow::Padding { size, .. } => size,
+            LayoutRow::Member(_) => 0,
+        });
+
+        return padding.sum();
+    }
+}
+
+/// Lays out the structs and unions defined in the program's own files, in the
+/// order they're defined. Ones from the bundled headers are left out.
+pub fn layouts(files: &FileDb) -> Result<Vec<StructLayout>, Vec<Error>> {
+    let mut lexer = Lexer::new(files);
+    let mut out: Vec<StructLayout> = Vec::new();
+    for file in files.impls() {
+        if files.is_system(file) {
+            continue;
+        }
+
+        let (id, tokens) = lexer.lex(file).map_err(|e| vec![e])?;
+        let env = parse(id, tokens)?;
+        let tu = check_tree(files, env.file, &lexer.symbols, &env.tree)?;
+
+        for aggregate in &tu.aggregates {
+            let loc = aggregate.defn.loc;
+            if files.is_system(loc.file) || out.iter().any(|l| l.loc == loc) {
+                continue; // a header included by more than one file
+            }
+
+            out.push(layout(&tu, &lexer.symbols, aggregate));
+        }
+    }
+
+    return Ok(out);
+}
+
+fn layout(tu: &TranslationUnit, symbols: &Symbols, aggregate: &TCAggregate) -> StructLayout {
+    let keyword = if aggregate.is_union {
+        "union"
+    } else {
+        "struct"
+    };
+    let loc = aggregate.defn.loc;
+    let name = match aggregate.ident.opt() {
+        Some(ident) => format!("{} {}", keyword, symbols.to_str(ident).unwrap()),
+        None => anonymous_name(tu, symbols, aggregate.is_union, loc)
+            .unwrap_or_else(|| format!("anonymous {}", keyword)),
+    };
+
+    let mut members = Vec::new();
+    for field in aggregate.defn.fields {
+        let mut size = field.ty.size().opt().unwrap_or(0);
+        let mut offset = field.offset;
+        let bits = field.bitfield.map(|b| {
+            // just the bytes the bitfield touches, not its whole storage unit
+            offset += b.offset / 8;
+            size = (b.offset % 8 + b.width + 7) / 8;
+            (b.offset % 8, b.width)
+        });
+
+        members.push(MemberLayout {
+            name: symbols.to_str(field.name).unwrap().to_string(),
+            ty: field.ty.display(symbols),
+            offset,
+            size,
+            align: field.ty.align().into(),
+            bits,
+        });
+    }
+
+    return StructLayout {
+        name,
+        is_union: aggregate.is_union,
+        size: aggregate.sa.size.into(),
+        align: aggregate.sa.align.into(),
+        members,
+        loc,
+    };
+}
+
+/// The name an anonymous struct gets through a typedef, like `Point` in
+/// `typedef struct { int x, y; } Point;`
+fn anonymous_name(
+    tu: &TranslationUnit,
+    syms: &Symbols,
+    is_union: bool,
+    loc: CodeLoc,
+) -> Option<String> {
+    let mut typedefs: Vec<_> = tu.typedefs.iter().collect();
+    typedefs.sort_by_key(|((_, td_loc), _)| (td_loc.file, td_loc.start));
+    for ((ident, _), ty) in typedefs {
+        if ty.mods.len() != 0 {
+            continue;
+        }
+
+        let found = match ty.base {
+            TCTypeBase::UnnamedStruct { loc: l, .. } => !is_union && l == loc,
+            TCTypeBase::UnnamedUnion { loc: l, .. } => is_union && l == loc,
+            _ => false,
+        };
+
+        if found {
+            return Some(syms.to_str(*ident).unwrap().to_string());
+        }
+    }
+
+    return None;
+}
+
+/// One table per struct, like:
+///
+/// ```text
+/// struct pair (size 16, align 8, 4 bytes of padding)
+///   offset  size  align  member
+///        0     4      4  int a
+///        4     4         (padding)
+///        8     8      8  long b
+/// ```
+///
+/// Bitfields show the bytes they use, like `1  1  4  unsigned int mode : 3
+/// (from bit 2)`.
+pub fn to_text(layouts: &[StructLayout]) -> String {
+    let mut out = String::new();
+    for (idx, layout) in layouts.iter().enumerate() {
+        if idx != 0 {
+            out += "\n";
+        }
+
+        out += &format!(
+            "{} (size {}, align {}, {} bytes of padding)\n",
+            layout.name,
+            layout.size,
+            layout.align,
+            layout.padding()
+        );
+        out += "  offset  size  align  member\n";
+
+        for row in layout.rows() {
+            out += &match row {
+                LayoutRow::Member(m) => {
+                    let bits = match m.bits {
+                        Some((first, width)) => format!(" : {} (from bit {})", width, first),
+                        None => String::new(),
+                    };
+
+                    format!(
+                        "  {:>6}  {:>4}  {:>5}  {} {}{}\n",
+                        m.offset, m.size, m.align, m.ty, m.name, bits
+                    )
+                }
+                LayoutRow::Padding { offset, size } => {
+                    format!("  {:>6}  {:>4}         (padding)\n", offset, size)
+                }
+            };
+        }
+    }
+
+    return out;
+}
@@ -1,10 +1,9 @@
 use crate::buckets::*;
 use crate::filedb::*;
+use crate::interner::*;
 use crate::util::*;
 use core::{mem, str};
 
-pub const CLOSING_CHAR: u8 = !0;
-
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum NumChar {
@@ -33,12 +32,14 @@ pub enum NumChar {
     _INVALID,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, strum::EnumDiscriminants)]
+#[strum_discriminants(name(TokenTag))]
 pub enum TokenKind {
     Ident(u32),
     IntChar(NumChar),
     StringLit(&'static IStr),
     CharLit(i8),
+    MultiCharLit(i32),
 
     Whitespace,
 
@@ -134,6 +135,197 @@ pub enum TokenKind {
     Switch,
 }
 
+/// Every `NumChar`, indexed by its value
+const NUM_CHARS: [NumChar; 20] = [
+    NumChar::_0,
+    NumChar::_1,
+    NumChar::_2,
+    NumChar::_3,
+    NumChar::_4,
+    NumChar::_5,
+    NumChar::_6,
+    NumChar::_7,
+    NumChar::_8,
+    NumChar::_9,
+    NumChar::_A,
+    NumChar::_B,
+    NumChar::_C,
+    NumChar::_D,
+    NumChar::_E,
+    NumChar::_F,
+    NumChar::_L,
+    NumChar::_X,
+    NumChar::_U,
+    NumChar::_INVALID,
+];
+
+/// Lexer output, stored column-wise. Each token is a one-byte tag, a 4-byte
+/// payload, and a start offset and length; the file is stored once per run of
+/// tokens from the same file, and string literal and pragma text goes in a side
+/// table that the payload indexes.
+pub struct TokenBuf {
+    /// Position the parser starts at; parsing resumes from here after an error
+    pub start: usize,
+
+    /// Whether the parser should accept GNU extensions; see `Lexer::gnu_extensions`
+    pub gnu_extensions: bool,
+
+    tags: Vec<TokenTag>,
+    payloads: Vec<u32>,
+    starts: Vec<u32>,
+    lens: Vec<u32>,
+    files: Vec<(u32, u32)>,      // (index of first token, file)
+    expansions: Vec<(u32, u32)>, // (index of first token, macro expansion or NO_EXPANSION)
+    literals: Vec<&'static IStr>,
+
+    fieldless: Vec<TokenKind>, // indexed by tag, for tokens without a payload
+}
+
+impl TokenBuf {
+    pub fn new() -> Self {
+        return Self {
+            start: 0,
+            gnu_extensions: false,
+            tags: Vec::new(),
+            payloads: Vec::new(),
+            starts: Vec::new(),
+            lens: Vec::new(),
+            files: Vec::new(),
+            expansions: Vec::new(),
+            literals: Vec::new(),
+            fieldless: Vec::new(),
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.tags.len();
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn push(&mut self, kind: TokenKind, loc: CodeLoc) {
+        self.push_expanded(kind, loc, NO_EXPANSION);
+    }
+
+    /// Pushes a token that came from `expansion` in `FileDb::expansions`
+    pub fn push_expanded(&mut self, kind: TokenKind, loc: CodeLoc, expansion: u32) {
+        let tag = TokenTag::from(kind);
+        let payload = match kind {
+            TokenKind::Ident(id) => id,
+            TokenKind::IntChar(c) => c as u32,
+            TokenKind::CharLit(c) => c as u8 as u32,
+            TokenKind::MultiCharLit(c) => c as u32,
+            TokenKind::StringLit(s) | TokenKind::Pragma(s) => {
+                self.literals.push(s);
+                self.literals.len() as u32 - 1
+            }
+            _ => {
+                let idx = tag as usize;
+                if self.fieldless.len() <= idx {
+                    self.fieldless.resize(idx + 1, TokenKind::Unimplemented);
+                }
+
+                self.fieldless[idx] = kind;
+                0
+            }
+        };
+
+        if self.files.last().map(|(_, file)| *file) != Some(loc.file) {
+            self.files.push((self.tags.len() as u32, loc.file));
+        }
+
+        if self.expansions.last().map(|(_, e)| *e) != Some(expansion) {
+            self.expansions.push((self.tags.len() as u32, expansion));
+        }
+
+        self.tags.push(tag);
+        self.payloads.push(payload);
+        self.starts.push(loc.start);
+        self.lens.push(loc.end - loc.start);
+    }
+
+    /// Inserts a token before `idx`, in the same file and macro expansion as the
+    /// token before it. Used by the parser to recover from a missing `;` or `}`.
+    pub fn insert(&mut self, idx: usize, kind: TokenKind, loc: CodeLoc) {
+        let len = self.len();
+        self.push(kind, loc);
+        self.files.retain(|&(first, _)| first as usize != len);
+        self.expansions.retain(|&(first, _)| first as usize != len);
+
+        for (first, _) in self.files.iter_mut().chain(self.expansions.iter_mut()) {
+            if *first as usize >= idx && *first != 0 {
+                *first += 1;
+            }
+        }
+
+        self.tags[idx..].rotate_right(1);
+        self.payloads[idx..].rotate_right(1);
+        self.starts[idx..].rotate_right(1);
+        self.lens[idx..].rotate_right(1);
+    }
+
+    /// Turns the tokens in `range` into whitespace. Used by the parser to skip a
+    /// statement it can't parse, and still check the rest of the function.
+    pub fn blank(&mut self, range: core::ops::Range<usize>) {
+        let tag = TokenTag::from(TokenKind::Whitespace);
+        if self.fieldless.len() <= tag as usize {
+            self.fieldless
+                .resize(tag as usize + 1, TokenKind::Unimplemented);
+        }
+
+        self.fieldless[tag as usize] = TokenKind::Whitespace;
+        for idx in range {
+            self.tags[idx] = tag;
+            self.payloads[idx] = 0;
+        }
+    }
+
+    pub fn kind(&self, idx: usize) -> TokenKind {
+        let payload = self.payloads[idx];
+        return match self.tags[idx] {
+            TokenTag::Ident => TokenKind::Ident(payload),
+            TokenTag::IntChar => TokenKind::IntChar(NUM_CHARS[payload as usize]),
+            TokenTag::CharLit => TokenKind::CharLit(payload as u8 as i8),
+            TokenTag::MultiCharLit => TokenKind::MultiCharLit(payload as i32),
+            TokenTag::StringLit => TokenKind::StringLit(self.literals[payload as usize]),
+            TokenTag::Pragma => TokenKind::Pragma(self.literals[payload as usize]),
+            tag => self.fieldless[tag as usize],
+        };
+    }
+
+    pub fn loc(&self, idx: usize) -> CodeLoc {
+        let run = match self
+            .files
+            .binary_search_by_key(&(idx as u32), |(first, _)| *first)
+        {
+            Ok(run) => run,
+            Err(run) => run - 1,
+        };
+
+        let start = self.starts[idx];
+        return l(start, start + self.lens[idx], self.files[run].1);
+    }
+
+    /// The macro expansion the token came from, if any
+    pub fn expansion(&self, idx: usize) -> Option<u32> {
+        let run = match self
+            .expansions
+            .binary_search_by_key(&(idx as u32), |(first, _)| *first)
+        {
+            Ok(run) => run,
+            Err(run) => run - 1,
+        };
+
+        return Some(self.expansions[run].1).filter(|&e| e != NO_EXPANSION);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = TokenKind> + '_ {
+        return (0..self.len()).map(move |idx| self.kind(idx));
+    }
+}
+
 pub enum MacroTok {
     Tok(TokenKind),
 }
@@ -152,6 +344,9 @@ pub enum RawTok {
     Define(u32),
     FuncDefine(u32),
     EndPPLine,
+
+    Error(&'static str),
+    Warning(&'static str),
 }
 
 #[derive(Debug, Clone)]
@@ -164,81 +359,117 @@ pub enum Macro {
     Marker,
 }
 
-lazy_static! {
-    pub static ref RESERVED_KEYWORDS: HashMap<&'static str, TokenKind> = {
-        let mut set = HashMap::new();
-        set.insert("auto", TokenKind::Unimplemented);
-        set.insert("break", TokenKind::Break);
-        set.insert("case", TokenKind::Case);
-        set.insert("char", TokenKind::Char);
-        set.insert("const", TokenKind::Const);
-        set.insert("continue", TokenKind::Continue);
-        set.insert("default", TokenKind::Default);
-        set.insert("do", TokenKind::Do);
-        set.insert("double", TokenKind::Double);
-        set.insert("else", TokenKind::Else);
-        set.insert("enum", TokenKind::Enum);
-        set.insert("extern", TokenKind::Extern);
-        set.insert("float", TokenKind::Float);
-        set.insert("for", TokenKind::For);
-        set.insert("goto", TokenKind::Goto);
-        set.insert("if", TokenKind::If);
-        set.insert("inline", TokenKind::Inline);
-        set.insert("int", TokenKind::Int);
-        set.insert("long", TokenKind::Long);
-        set.insert("register", TokenKind::Register);
-        set.insert("restrict", TokenKind::Restrict);
-        set.insert("return", TokenKind::Return);
-        set.insert("short", TokenKind::Short);
-        set.insert("signed", TokenKind::Signed);
-        set.insert("sizeof", TokenKind::Sizeof);
-        set.insert("static", TokenKind::Static);
-        set.insert("struct", TokenKind::Struct);
-        set.insert("switch", TokenKind::Switch);
-        set.insert("typedef", TokenKind::Typedef);
-        set.insert("union", TokenKind::Union);
-        set.insert("unsigned", TokenKind::Unsigned);
-        set.insert("void", TokenKind::Void);
-        set.insert("volatile", TokenKind::Unimplemented);
-        set.insert("while", TokenKind::While);
-        set.insert("_Alignas", TokenKind::Unimplemented);
-        set.insert("_Alignof", TokenKind::Unimplemented);
-        set.insert("_Atomic", TokenKind::Unimplemented);
-        set.insert("_Bool", TokenKind::Unimplemented);
-        set.insert("_Complex", TokenKind::Unimplemented);
-        set.insert("_Generic", TokenKind::Unimplemented);
-        set.insert("_Imaginary", TokenKind::Unimplemented);
-        set.insert("_Noreturn", TokenKind::Unimplemented);
-        set.insert("_Static_assert", TokenKind::Unimplemented);
-        set.insert("_Thread_local", TokenKind::Unimplemented);
-        set.insert("_Float16", TokenKind::Unimplemented);
-        set.insert("_Float16x", TokenKind::Unimplemented);
-        set.insert("_Float32", TokenKind::Unimplemented);
-        set.insert("_Float32x", TokenKind::Unimplemented);
-        set.insert("_Float64", TokenKind::Unimplemented);
-        set.insert("_Float64x", TokenKind::Unimplemented);
-        set.insert("_Float128", TokenKind::Unimplemented);
-        set.insert("_Float128x", TokenKind::Unimplemented);
-        set.insert("_Decimal32", TokenKind::Unimplemented);
-        set.insert("_Decimal32x", TokenKind::Unimplemented);
-        set.insert("_Decimal64", TokenKind::Unimplemented);
-        set.insert("_Decimal64x", TokenKind::Unimplemented);
-        set.insert("_Decimal128", TokenKind::Unimplemented);
-        set.insert("_Decimal128x", TokenKind::Unimplemented);
-
-        set
-    };
+/// Token kinds of `interner::KEYWORDS`, in the same order
+pub const KEYWORD_KINDS: [TokenKind; KEYWORDS.len()] = [
+    TokenKind::Unimplemented, // auto
+    TokenKind::Break,
+    TokenKind::Case,
+    TokenKind::Char,
+    TokenKind::Const,
+    TokenKind::Continue,
+    TokenKind::Default,
+    TokenKind::Do,
+    TokenKind::Double,
+    TokenKind::Else,
+    TokenKind::Enum,
+    TokenKind::Extern,
+    TokenKind::Float,
+    TokenKind::For,
+    TokenKind::Goto,
+    TokenKind::If,
+    TokenKind::Inline,
+    TokenKind::Int,
+    TokenKind::Long,
+    TokenKind::Register,
+    TokenKind::Restrict,
+    TokenKind::Return,
+    TokenKind::Short,
+    TokenKind::Signed,
+    TokenKind::Sizeof,
+    TokenKind::Static,
+    TokenKind::Struct,
+    TokenKind::Switch,
+    TokenKind::Typedef,
+    TokenKind::Union,
+    TokenKind::Unsigned,
+    TokenKind::Void,
+    TokenKind::Volatile,
+    TokenKind::While,
+    TokenKind::Unimplemented, // _Alignas
+    TokenKind::Unimplemented, // _Alignof
+    TokenKind::Unimplemented, // _Atomic
+    TokenKind::Unimplemented, // _Bool
+    TokenKind::Unimplemented, // _Complex
+    TokenKind::Unimplemented, // _Generic
+    TokenKind::Unimplemented, // _Imaginary
+    TokenKind::Unimplemented, // _Noreturn
+    TokenKind::Unimplemented, // _Static_assert
+    TokenKind::Unimplemented, // _Thread_local
+    TokenKind::Unimplemented, // _Float16
+    TokenKind::Unimplemented, // _Float16x
+    TokenKind::Unimplemented, // _Float32
+    TokenKind::Unimplemented, // _Float32x
+    TokenKind::Unimplemented, // _Float64
+    TokenKind::Unimplemented, // _Float64x
+    TokenKind::Unimplemented, // _Float128
+    TokenKind::Unimplemented, // _Float128x
+    TokenKind::Unimplemented, // _Decimal32
+    TokenKind::Unimplemented, // _Decimal32x
+    TokenKind::Unimplemented, // _Decimal64
+    TokenKind::Unimplemented, // _Decimal64x
+    TokenKind::Unimplemented, // _Decimal128
+    TokenKind::Unimplemented, // _Decimal128x
+];
+
+pub fn num_char(digit: u8) -> TokenKind {
+    return TokenKind::IntChar(NUM_CHARS[(digit - b'0') as usize]);
 }
 
 #[inline]
-pub fn invalid_token(begin: usize, end: usize, file: u32) -> Error {
-    return error!(
-        "invalid token",
-        l(begin as u32, end as u32, file),
-        "token found here"
-    );
+pub fn invalid_token(loc: CodeLoc) -> Error {
+    return error!("invalid token", loc, "token found here");
 }
 
+/// Limits on how much a single translation unit can expand to, so that a
+/// pathological chain of headers stops with an error instead of using up memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LexLimits {
+    pub max_include_depth: u32, // files open at once, counting the one being lexed
+    pub max_tokens: u32,        // after includes and macros are expanded
+}
+
+impl LexLimits {
+    pub const DEFAULT: Self = Self {
+        max_include_depth: 200,
+        max_tokens: 1 << 22,
+    };
+}
+
+impl Default for LexLimits {
+    fn default() -> Self {
+        return Self::DEFAULT;
+    }
+}
+
+/// Macros every file starts with, describing the environment TCI compiles for
+pub const PREDEFINED_MACROS: [(&str, &str); 15] = [
+    ("__TCI__", "1"),
+    ("__STDC__", "1"),
+    ("__STDC_HOSTED__", "1"),
+    ("__CHAR_BIT__", "8"),
+    ("__SIZEOF_SHORT__", "2"),
+    ("__SIZEOF_INT__", "4"),
+    ("__SIZEOF_LONG__", "8"),
+    ("__SIZEOF_LONG_LONG__", "8"),
+    ("__SIZEOF_FLOAT__", "4"),
+    ("__SIZEOF_DOUBLE__", "8"),
+    ("__SIZEOF_POINTER__", "8"),
+    ("__LP64__", "1"),
+    ("__ORDER_LITTLE_ENDIAN__", "1234"),
+    ("__ORDER_BIG_ENDIAN__", "4321"),
+    ("__BYTE_ORDER__", "__ORDER_LITTLE_ENDIAN__"),
+];
+
 const WHITESPACE: [u8; 2] = [b' ', b'\t'];
 const CRLF: [u8; 2] = [b'\r', b'\n'];
 
@@ -248,8 +479,34 @@ pub struct Lexer<'a> {
     pub files: &'a FileDb,
 
     pub macros: HashMap<u32, (Macro, CodeLoc)>,
-    pub toks: Vec<TokenKind>,
-    pub locs: Vec<CodeLoc>,
+    pub tokens: TokenBuf,
+
+    /// What `macros` starts as for each file; see `define` and `undefine`
+    pub predefined: HashMap<u32, (Macro, CodeLoc)>,
+
+    /// Files included by the most recent call to `lex`
+    pub deps: Vec<u32>,
+
+    pub pragma_once: Vec<u32>,
+    pub include_guards: HashMap<u32, Option<u32>>, // file -> guard macro, if the file has one
+
+    /// `#warning` directives seen so far, which `warnings::warnings_with` reports
+    pub warnings: Vec<Error>,
+
+    /// When set, the most recent call to `lex` also records where the comments
+    /// and macro uses were, for tools like `formatter` that work on the source
+    pub keep_trivia: bool,
+    pub comments: Vec<CodeLoc>,
+    pub expansions: Vec<CodeLoc>,
+
+    /// `--std=gnu11`: lets the parser accept statement expressions and `typeof`,
+    /// and the type checker treat `void` as 1 byte for `sizeof` and `void*` arithmetic
+    pub gnu_extensions: bool,
+
+    pub limits: LexLimits,
+
+    /// `-I` directories, searched before `files.include_paths`
+    pub include_paths: Vec<String>,
 }
 
 impl<'a> Drop for Lexer<'a> {
@@ -260,29 +517,104 @@ impl<'a> Drop for Lexer<'a> {
 
 impl<'a> Lexer<'a> {
     pub fn new(files: &'a FileDb) -> Self {
-        Self {
+        return Self::with_symbols(files, Symbols::new());
+    }
+
+    pub fn with_symbols(files: &'a FileDb, symbols: Symbols) -> Self {
+        let mut lexer = Self {
             buckets: BucketListFactory::new(),
-            symbols: Symbols::new(),
+            symbols,
             files,
 
             macros: HashMap::new(),
-            toks: Vec::new(),
-            locs: Vec::new(),
+            tokens: TokenBuf::new(),
+
+            predefined: HashMap::new(),
+
+            deps: Vec::new(),
+
+            pragma_once: Vec::new(),
+            include_guards: HashMap::new(),
+
+            warnings: Vec::new(),
+
+            keep_trivia: false,
+            comments: Vec::new(),
+            expansions: Vec::new(),
+
+            gnu_extensions: false,
+
+            limits: LexLimits::DEFAULT,
+            include_paths: Vec::new(),
+        };
+
+        for (name, value) in &PREDEFINED_MACROS {
+            lexer.define(name, value).unwrap();
+        }
+
+        return lexer;
+    }
+
+    /// Defines `name` as `value` before each file, like `-DNAME=value` on the
+    /// command line. The definition has no location, so errors about it can't
+    /// point at it.
+    pub fn define(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        let cant_define = |reason: &str| {
+            let message = format!("can't define `{}` from the command line; {}", name, reason);
+            error!(message)
+        };
+
+        let is_ident = name.bytes().all(is_ident_char);
+        if name.is_empty() || !is_ident || name.as_bytes()[0].is_ascii_digit() {
+            return Err(cant_define("it isn't an identifier"));
+        }
+
+        let data = value.as_bytes();
+        let mut lexer = SimpleLexer::new(NO_FILE.file);
+        lexer.at_line_begin = false;
+        let mut toks = Vec::new();
+        loop {
+            let tok = lexer.lex(&*self.buckets, &mut self.symbols, self.files, data);
+            match tok.map_err(|e| cant_define(&e.message))? {
+                None => break,
+                Some(RawTok::Tok(tok)) => toks.push(tok),
+                Some(_) => return Err(cant_define("the value has a preprocessor directive")),
+            }
         }
+
+        let id = self.symbols.add_str(name);
+        self.predefined.insert(id, (Macro::Value(toks), NO_FILE));
+        return Ok(());
+    }
+
+    /// Removes a definition from `define`, or one of `PREDEFINED_MACROS`, like
+    /// `-UNAME` on the command line
+    pub fn undefine(&mut self, name: &str) {
+        let id = self.symbols.add_str(name);
+        self.predefined.remove(&id);
     }
 
     pub fn symbols(mut self) -> Symbols {
         return mem::replace(&mut self.symbols, Symbols::new());
     }
 
-    pub fn lex(&mut self, file: u32) -> Result<(u32, Vec<TokenKind>, Vec<CodeLoc>), Error> {
-        self.macros.clear();
-        self.toks.clear();
-        self.locs.clear();
+    pub fn lex(&mut self, file: u32) -> Result<(u32, TokenBuf), Error> {
+        self.macros = self.predefined.clone();
+        self.pragma_once.clear();
+        return self.lex_with_macros(file);
+    }
+
+    /// Lexes `file` with the macros left over from the last call to `lex`, e.g. to
+    /// lex an expression in the context of a translation unit
+    pub fn lex_with_macros(&mut self, file: u32) -> Result<(u32, TokenBuf), Error> {
+        self.tokens.clear();
+        self.deps.clear();
+        self.comments.clear();
+        self.expansions.clear();
 
-        let data = self.files.source(file).unwrap().as_bytes();
+        let (data, splices) = splice_lines(self.files.source(file).unwrap().as_bytes());
         let mut lexers = TaggedMultiArray::new();
-        lexers.push_from(SimpleLexer::new(file), data);
+        lexers.push_from(self.simple_lexer(file, splices), &data);
 
         loop {
             let TE(lexer, data) = match lexers.last_mut() {
@@ -290,16 +622,61 @@ impl<'a> Lexer<'a> {
                 None => break,
             };
 
-            match self.lex_file_until_include(lexer, data)? {
+            let include = self.lex_file_until_include(lexer, data);
+            if let Some(comments) = &mut lexer.comments {
+                self.comments.extend(comments.drain(..));
+            }
+
+            let include = match include {
+                Ok(include) => include,
+                Err(_) if self.tokens.len() > self.limits.max_tokens as usize => {
+                    let chain = (&lexers)
+                        .into_iter()
+                        .map(|TE(lex, _)| (lex.file, lex.loc()));
+                    let chain: Vec<_> = chain.collect();
+                    return Err(too_many_tokens(self.files, &chain, self.limits.max_tokens));
+                }
+                Err(err) => return Err(err),
+            };
+
+            match include {
                 Some(include) => {
                     let loc = lexer.loc();
+                    let from = lexer.file;
+                    self.files.add_include(IncludeEdge {
+                        from,
+                        to: include,
+                        loc,
+                    });
+
+                    if !self.deps.contains(&include) {
+                        self.deps.push(include);
+                    }
+
+                    if self.already_included(include) {
+                        continue;
+                    }
+
                     let mut iter = (&lexers).into_iter();
-                    if iter.find(|TE(lex, _)| lex.file == include).is_some() {
-                        return Err(error!("include cycle detected", loc, "cycle found here"));
+                    if let Some(begin) = iter.position(|TE(lex, _)| lex.file == include) {
+                        let iter = (&lexers).into_iter().skip(begin);
+                        let chain: Vec<_> = iter.map(|TE(lex, _)| (lex.file, lex.loc())).collect();
+                        return Err(include_cycle(self.files, &chain));
                     }
 
-                    let data = self.files.source(include).unwrap().as_bytes();
-                    lexers.push_from(SimpleLexer::new(include), data);
+                    if lexers.len() as u32 >= self.limits.max_include_depth {
+                        let chain = (&lexers)
+                            .into_iter()
+                            .map(|TE(lex, _)| (lex.file, lex.loc()));
+                        let mut chain: Vec<_> = chain.collect();
+                        chain.push((include, loc));
+                        let depth = self.limits.max_include_depth;
+                        return Err(include_too_deep(self.files, &chain, depth));
+                    }
+
+                    let source = self.files.source(include).unwrap().as_bytes();
+                    let (data, splices) = splice_lines(source);
+                    lexers.push_from(self.simple_lexer(include, splices), &data);
                 }
                 None => {
                     lexers.pop();
@@ -307,9 +684,43 @@ impl<'a> Lexer<'a> {
             }
         }
 
-        let toks = mem::replace(&mut self.toks, Vec::new());
-        let locs = mem::replace(&mut self.locs, Vec::new());
-        return Ok((file, toks, locs));
+        let mut tokens = mem::replace(&mut self.tokens, TokenBuf::new());
+        tokens.gnu_extensions = self.gnu_extensions;
+        return Ok((file, tokens));
+    }
+
+    fn simple_lexer(&self, file: u32, splices: Vec<(u32, u32)>) -> SimpleLexer {
+        let mut lexer = SimpleLexer::new(file);
+        lexer.splices = splices;
+        lexer.include_paths = self.include_paths.clone();
+        if self.keep_trivia {
+            lexer.comments = Some(Vec::new());
+        }
+
+        return lexer;
+    }
+
+    /// Whether including `file` again would produce no tokens, because it used
+    /// `#pragma once` or its include guard is already defined
+    pub fn already_included(&mut self, file: u32) -> bool {
+        if self.pragma_once.contains(&file) {
+            return true;
+        }
+
+        let guard = match self.include_guards.get(&file) {
+            Some(guard) => *guard,
+            None => {
+                let source = self.files.source(file).unwrap();
+                let guard = include_guard(source).map(|name| self.symbols.add_str(name));
+                self.include_guards.insert(file, guard);
+                guard
+            }
+        };
+
+        return match guard {
+            Some(guard) => self.macros.contains_key(&guard),
+            None => false,
+        };
     }
 
     pub fn lex_file_until_include(
@@ -318,6 +729,10 @@ impl<'a> Lexer<'a> {
         data: &[u8],
     ) -> Result<Option<u32>, Error> {
         loop {
+            if self.tokens.len() > self.limits.max_tokens as usize {
+                return Err(error!("too many tokens"));
+            }
+
             let tok = match lexer.lex(&*self.buckets, &mut self.symbols, self.files, data)? {
                 Some(tok) => tok,
                 None => return Ok(None),
@@ -329,24 +744,40 @@ impl<'a> Lexer<'a> {
                 RawTok::Tok(TokenKind::Ident(id)) => {
                     let (mac, loc) = if let Some((mac, loc)) = self.macros.get(&id) {
                         ((*mac).clone(), *loc)
+                    } else if let Some(toks) = self.builtin_macro(id, lexer.loc()) {
+                        if self.keep_trivia {
+                            self.expansions.push(lexer.loc());
+                        }
+
+                        for tok in toks {
+                            self.tokens.push(tok, lexer.loc());
+                        }
+                        continue;
                     } else {
-                        self.toks.push(TokenKind::Ident(id));
-                        self.locs.push(lexer.loc());
+                        self.tokens.push(TokenKind::Ident(id), lexer.loc());
                         continue;
                     };
 
                     self.expand_macro(lexer, data, id, &mac, loc)?;
                 }
-                RawTok::Tok(tok) => {
-                    self.toks.push(tok);
-                    self.locs.push(lexer.loc());
+                RawTok::Tok(TokenKind::Pragma(pragma)) if pragma.as_str().trim() == "once" => {
+                    if !self.pragma_once.contains(&lexer.file) {
+                        self.pragma_once.push(lexer.file);
+                    }
                 }
+                RawTok::Tok(tok) => self.tokens.push(tok, lexer.loc()),
 
                 RawTok::If => {
-                    let should_write = self.eval_macro_if(lexer, data)?;
                     let prev_should_write = lexer.should_write.last().map(|a| *a).unwrap_or(true);
+                    if !prev_should_write {
+                        // the condition might not even be valid, so don't evaluate it
+                        lexer.skip_line(data);
+                        lexer.should_write.push(false);
+                        continue;
+                    }
 
-                    lexer.should_write.push(should_write && prev_should_write);
+                    let should_write = self.eval_macro_if(lexer, data)?;
+                    lexer.should_write.push(should_write);
                 }
                 RawTok::Ifdef(def) => {
                     let should_write = self.macros.contains_key(&def);
@@ -368,8 +799,22 @@ impl<'a> Lexer<'a> {
                 RawTok::Else => {
                     let loc = lexer.loc();
                     let or_else = move || error!("#else without matching #if", loc, "found here");
+                    let len = lexer.should_write.len();
+                    let prev_should_write = match len {
+                        0 | 1 => true,
+                        len => lexer.should_write[len - 2],
+                    };
+
                     let last = lexer.should_write.last_mut().ok_or_else(or_else)?;
-                    *last = !*last;
+                    *last = prev_should_write && !*last;
+                }
+
+                RawTok::Error(message) => {
+                    return Err(error!(message, lexer.loc(), "#error directive here"));
+                }
+                RawTok::Warning(message) => {
+                    let warning = error!(message, lexer.loc(), "#warning directive here");
+                    self.warnings.push(warning);
                 }
 
                 RawTok::Define(id) => {
@@ -544,15 +989,42 @@ impl<'a> Lexer<'a> {
             }
         };
 
+        let def = loc;
         let loc = l_from(begin, lexer.loc());
-        let output = self.expand_macro_rec(&mut expanded, &expansion, loc)?;
+        if self.keep_trivia {
+            self.expansions.push(loc);
+        }
 
-        self.toks.extend_from_slice(&output);
-        self.locs.resize(self.toks.len(), loc);
+        let parent = self.add_expansion(id, def, loc, None);
+        let output = self.expand_macro_rec(&mut expanded, &expansion, loc, parent)?;
+
+        for (tok, expansion) in output {
+            self.tokens.push_expanded(tok, loc, expansion);
+        }
 
         return Ok(());
     }
 
+    /// Expansion of the predefined macros that depend on where they're used
+    pub fn builtin_macro(&self, id: u32, loc: CodeLoc) -> Option<Vec<TokenKind>> {
+        let string = |s: &str| vec![TokenKind::StringLit(self.buckets.add_i_str(s))];
+
+        if id == BuiltinSymbol::Line as u32 {
+            let line = self.files.line_index(loc.file, loc.start as usize)? + 1;
+            let mut toks: Vec<_> = format!("{}", line).bytes().map(num_char).collect();
+            toks.push(TokenKind::Whitespace);
+            return Some(toks);
+        } else if id == BuiltinSymbol::File as u32 {
+            return Some(string(self.files.name(loc.file)?));
+        } else if id == BuiltinSymbol::Date as u32 {
+            return Some(string(&self.files.date));
+        } else if id == BuiltinSymbol::Time as u32 {
+            return Some(string(&self.files.time));
+        }
+
+        return None;
+    }
+
     pub fn expand_macro_simple(
         &self,
         params: HashMap<u32, Vec<TokenKind>>,
@@ -580,12 +1052,26 @@ impl<'a> Lexer<'a> {
         return output;
     }
 
+    /// Records that the tokens at `site` come from expanding `id`
+    fn add_expansion(&self, id: u32, def: CodeLoc, site: CodeLoc, parent: Option<u32>) -> u32 {
+        let name = self.symbols.to_str(id).unwrap().to_string();
+        return self.files.add_expansion(Expansion {
+            name,
+            def,
+            site,
+            parent,
+        });
+    }
+
+    /// Expands the macros in `tokens`, which came from the expansion `parent`,
+    /// pairing each output token with the expansion it came from
     pub fn expand_macro_rec(
         &self,
         expanded: &mut Vec<u32>,
         tokens: &[TokenKind],
         loc: CodeLoc,
-    ) -> Result<Vec<TokenKind>, Error> {
+        parent: u32,
+    ) -> Result<Vec<(TokenKind, u32)>, Error> {
         let mut toks = tokens.iter();
         let mut output = Vec::new();
 
@@ -595,7 +1081,7 @@ impl<'a> Lexer<'a> {
             let id = match tok {
                 TokenKind::Ident(id) => *id,
                 _ => {
-                    output.push(*tok);
+                    output.push((*tok, parent));
                     continue;
                 }
             };
@@ -603,14 +1089,17 @@ impl<'a> Lexer<'a> {
             let (macro_def, def_loc) = match self.macros.get(&id) {
                 Some(def) => {
                     if expanded.contains(&id) {
-                        output.push(*tok); // TODO output warning here
+                        output.push((*tok, parent)); // TODO output warning here
                         continue;
                     }
 
                     def
                 }
                 None => {
-                    output.push(*tok);
+                    match self.builtin_macro(id, loc) {
+                        Some(toks) => output.extend(toks.into_iter().map(|t| (t, parent))),
+                        None => output.push((*tok, parent)),
+                    }
                     continue;
                 }
             };
@@ -623,8 +1112,9 @@ impl<'a> Lexer<'a> {
                     ))
                 }
                 Macro::Value(toks) => {
+                    let nested = self.add_expansion(id, *def_loc, loc, Some(parent));
                     expanded.push(id);
-                    let mut expanded_toks = self.expand_macro_rec(expanded, toks, loc)?;
+                    let mut expanded_toks = self.expand_macro_rec(expanded, toks, loc, nested)?;
                     expanded.pop();
                     output.append(&mut expanded_toks);
                     continue;
@@ -693,9 +1183,10 @@ impl<'a> Lexer<'a> {
                 params_hash.insert(macro_params[idx], param);
             }
 
+            let nested = self.add_expansion(id, *def_loc, loc, Some(parent));
             expanded.push(id);
             let expanded_toks = self.expand_macro_simple(params_hash, macro_toks);
-            let mut expanded_toks = self.expand_macro_rec(expanded, &expanded_toks, loc)?;
+            let mut expanded_toks = self.expand_macro_rec(expanded, &expanded_toks, loc, nested)?;
             expanded.pop();
             output.append(&mut expanded_toks);
         }
@@ -857,7 +1348,10 @@ pub struct SimpleLexer {
     pub begin: usize,
     pub current: usize,
     pub file: u32,
-    pub should_write: Vec<bool>, // yeah yeah yeah whatever
+    pub should_write: Vec<bool>,        // yeah yeah yeah whatever
+    pub comments: Option<Vec<CodeLoc>>, // only kept when asked for
+    pub splices: Vec<(u32, u32)>,       // see `splice_lines`
+    pub include_paths: Vec<String>,     // see `Lexer::include_paths`
 }
 
 impl SimpleLexer {
@@ -870,6 +1364,9 @@ impl SimpleLexer {
             current: 0,
             file,
             should_write: Vec::new(),
+            comments: None,
+            splices: Vec::new(),
+            include_paths: Vec::new(),
         }
     }
 
@@ -884,6 +1381,7 @@ impl SimpleLexer {
         if let Some(tok) = tok {
             match tok {
                 RawTok::Ifdef(_) | RawTok::Ifndef(_) | RawTok::Endif => return Ok(Some(tok)),
+                RawTok::If | RawTok::Else => return Ok(Some(tok)),
                 _ => {
                     if self.should_write.last().map(|a| *a).unwrap_or(true) {
                         return Ok(Some(tok));
@@ -986,12 +1484,18 @@ impl SimpleLexer {
                     self.current += 1;
                 }
 
-                let word = unsafe { str::from_utf8_unchecked(&data[self.begin..self.current]) };
-                if let Some(kind) = RESERVED_KEYWORDS.get(word) {
-                    ret!(*kind);
+                let word = self.text(data, self.begin, self.current)?;
+                if let "L" | "u" | "U" = word {
+                    if self.peek_eq(data, b'\'') || self.peek_eq(data, b'"') {
+                        return Err(self.wide_literal(word, data));
+                    }
                 }
 
                 let id = symbols.add_str(word);
+                if let Some(keyword) = symbols.keyword(id) {
+                    ret!(KEYWORD_KINDS[keyword]);
+                }
+
                 ret!(TokenKind::Ident(id));
             }
 
@@ -1007,34 +1511,43 @@ impl SimpleLexer {
             b'9' => num_ret!(TokenKind::IntChar(NumChar::_9)),
 
             b'\"' => {
-                let mut cur = self.lex_character(b'\"', data)?;
                 let mut chars = Vec::new();
-                while cur != CLOSING_CHAR {
+                while let Some(cur) = self.lex_character(b'\"', data)? {
                     chars.push(cur);
-                    cur = self.lex_character(b'\"', data)?;
                 }
 
-                let string = unsafe { str::from_utf8_unchecked(&chars) };
+                let string = str::from_utf8(&chars).map_err(|_| {
+                    let loc = self.l(self.begin, self.current);
+                    error!(
+                        "string literal isn't valid UTF-8",
+                        loc, "escape sequences here make bytes that aren't valid UTF-8"
+                    )
+                })?;
                 let string = buckets.add_i_str(string);
                 ret!(TokenKind::StringLit(string));
             }
 
             b'\'' => {
-                let byte = self.lex_character(b'\'', data)?;
-                if byte == CLOSING_CHAR {
-                    return Err(error!("empty character literal", self.loc(), "found here"));
-                }
-
-                let closing = self.expect(data)?;
-                if closing != b'\'' {
-                    return Err(error!(
-                        "expected closing single quote",
-                        self.loc(),
-                        "this should be a closing single quote"
-                    ));
+                // Multi-character constants like 'ab' are ints, with the first
+                // character in the most significant byte, like in GCC and Clang
+                let (mut value, mut count) = (0u32, 0);
+                while let Some(byte) = self.lex_character(b'\'', data)? {
+                    value = value << 8 | byte as u32;
+                    count += 1;
+                }
+
+                match count {
+                    0 => return Err(error!("empty character literal", self.loc(), "found here")),
+                    1 => ret!(TokenKind::CharLit(value as u8 as i8)),
+                    2..=4 => ret!(TokenKind::MultiCharLit(value as i32)),
+                    _ => {
+                        return Err(error!(
+                            "character literal is too long",
+                            self.loc(),
+                            "multi-character constants can have at most 4 characters"
+                        ))
+                    }
                 }
-
-                ret!(TokenKind::CharLit(byte as i8));
             }
 
             b'{' => ret!(TokenKind::LBrace),
@@ -1045,9 +1558,20 @@ impl SimpleLexer {
             b']' => ret!(TokenKind::RBracket),
             b'~' => ret!(TokenKind::Tilde),
             b';' => ret!(TokenKind::Semicolon),
-            b':' => ret!(TokenKind::Colon),
+            b':' => {
+                if self.peek_eq(data, b'>') {
+                    incr_ret!(TokenKind::RBracket); // digraph for ]
+                } else {
+                    ret!(TokenKind::Colon);
+                }
+            }
             b',' => ret!(TokenKind::Comma),
             b'?' => ret!(TokenKind::Question),
+            b'%' if self.peek_eq(data, b':') && self.at_line_begin => {
+                self.current += 1; // digraph for #
+                self.at_line_begin = false;
+                return Ok(Some(self.lex_directive(buckets, symbols, files, data)?));
+            }
             b'#' => {
                 if self.at_line_begin {
                     self.at_line_begin = false;
@@ -1064,7 +1588,7 @@ impl SimpleLexer {
                         incr_ret!(TokenKind::DotDotDot);
                     }
 
-                    return Err(invalid_token(self.begin, self.current, self.file));
+                    return Err(invalid_token(self.loc()));
                 }
 
                 ret!(TokenKind::Dot);
@@ -1106,6 +1630,8 @@ impl SimpleLexer {
             b'%' => {
                 if self.peek_eq(data, b'=') {
                     incr_ret!(TokenKind::PercentEq);
+                } else if self.peek_eq(data, b'>') {
+                    incr_ret!(TokenKind::RBrace); // digraph for }
                 } else {
                     ret!(TokenKind::Percent);
                 }
@@ -1126,6 +1652,10 @@ impl SimpleLexer {
             b'<' => {
                 if self.peek_eq(data, b'=') {
                     incr_ret!(TokenKind::Leq);
+                } else if self.peek_eq(data, b':') {
+                    incr_ret!(TokenKind::LBracket); // digraph for [
+                } else if self.peek_eq(data, b'%') {
+                    incr_ret!(TokenKind::LBrace); // digraph for {
                 } else if self.peek_eq(data, b'<') {
                     self.current += 1;
                     if self.peek_eq(data, b'=') {
@@ -1177,7 +1707,7 @@ impl SimpleLexer {
             }
 
             x => {
-                return Err(invalid_token(self.begin, self.current, self.file));
+                return Err(invalid_token(self.loc()));
             }
         }
     }
@@ -1195,7 +1725,7 @@ impl SimpleLexer {
                 self.current += 1;
             }
 
-            unsafe { str::from_utf8_unchecked(&data[begin..self.current]) }
+            self.text(data, begin, self.current)?
         };
 
         match directive {
@@ -1228,13 +1758,13 @@ impl SimpleLexer {
                     self.current += 1;
                 }
 
-                let ident = unsafe { str::from_utf8_unchecked(&data[ident_begin..self.current]) };
+                let ident = self.text(data, ident_begin, self.current)?;
 
                 // Don't add the empty string
                 if ident == "" {
                     return Err(error!(
                         "expected an identifer for ifndef",
-                        l(ident_begin as u32, ident_begin as u32 + 1, self.file),
+                        self.l(ident_begin, ident_begin + 1),
                         "This should be an identifier"
                     ));
                 }
@@ -1253,13 +1783,13 @@ impl SimpleLexer {
                     self.current += 1;
                 }
 
-                let ident = unsafe { str::from_utf8_unchecked(&data[ident_begin..self.current]) };
+                let ident = self.text(data, ident_begin, self.current)?;
 
                 // Don't add the empty string
                 if ident == "" {
                     return Err(error!(
                         "expected an identifer for ifdef",
-                        l(ident_begin as u32, ident_begin as u32 + 1, self.file),
+                        self.l(ident_begin, ident_begin + 1),
                         "This should be an identifier"
                     ));
                 }
@@ -1284,6 +1814,25 @@ impl SimpleLexer {
                 return Ok(RawTok::Endif);
             }
 
+            "error" | "warning" => {
+                while self.peek_neq(data, b'\n') && self.peek_neq_series(data, &CRLF) {
+                    self.current += 1;
+                }
+
+                let line_begin = self.begin + 1 + directive.len();
+                let message = self.text(data, line_begin, self.current)?;
+                let message = match message.trim() {
+                    "" => buckets.add_str(&format!("#{} directive", directive)),
+                    message => buckets.add_str(message),
+                };
+
+                if directive == "error" {
+                    return Ok(RawTok::Error(message));
+                } else {
+                    return Ok(RawTok::Warning(message));
+                }
+            }
+
             "pragma" => {
                 self.current += 1;
                 let begin = self.current;
@@ -1291,7 +1840,7 @@ impl SimpleLexer {
                     self.current += 1;
                 }
 
-                let pragma = unsafe { str::from_utf8_unchecked(&data[begin..self.current]) };
+                let pragma = self.text(data, begin, self.current)?;
                 let pragma = buckets.add_i_str(pragma);
 
                 return Ok(RawTok::Tok(TokenKind::Pragma(pragma)));
@@ -1306,13 +1855,13 @@ impl SimpleLexer {
                     self.current += 1;
                 }
 
-                let ident = unsafe { str::from_utf8_unchecked(&data[ident_begin..self.current]) };
+                let ident = self.text(data, ident_begin, self.current)?;
 
                 // Don't add the empty string
                 if ident == "" {
                     return Err(error!(
                         "expected an identifer for macro definition",
-                        l(ident_begin as u32, ident_begin as u32 + 1, self.file),
+                        self.l(ident_begin, ident_begin + 1),
                         "This should be an identifier"
                     ));
                 }
@@ -1342,12 +1891,7 @@ impl SimpleLexer {
                     let name_end = self.current;
                     self.current += 1;
                     if self.peek_neq(data, b'\n') && self.peek_neq_series(data, &CRLF) {
-                        return Err(expected_newline(
-                            "include",
-                            self.begin,
-                            self.current,
-                            self.file,
-                        ));
+                        return Err(expected_newline("include", self.loc()));
                     }
 
                     if !self.should_write.last().map(|a| *a).unwrap_or(true) {
@@ -1362,10 +1906,9 @@ impl SimpleLexer {
                         )
                     };
 
-                    let include_name =
-                        unsafe { str::from_utf8_unchecked(&data[name_begin..name_end]) };
+                    let include_name = self.text(data, name_begin, name_end)?;
                     let include_id = files
-                        .resolve_include(include_name, self.file)
+                        .resolve_include_in(include_name, self.file, &self.include_paths)
                         .map_err(map_err)?;
 
                     return Ok(RawTok::Include(include_id));
@@ -1383,21 +1926,16 @@ impl SimpleLexer {
                     if b'>' != self.expect(data)? {
                         return Err(error!(
                             "expected a '>'",
-                            l((self.current - 1) as u32, self.current as u32, self.file),
+                            self.l(self.current - 1, self.current),
                             "this should be a '>'"
                         ));
                     }
 
                     if self.peek_neq(data, b'\n') && self.peek_neq_series(data, &CRLF) {
-                        return Err(expected_newline(
-                            "include",
-                            self.begin,
-                            self.current,
-                            self.file,
-                        ));
+                        return Err(expected_newline("include", self.loc()));
                     }
 
-                    let sys_file = unsafe { str::from_utf8_unchecked(&data[name_begin..name_end]) };
+                    let sys_file = self.text(data, name_begin, name_end)?;
                     if !self.should_write.last().map(|a| *a).unwrap_or(true) {
                         return Ok(RawTok::Noop);
                     }
@@ -1410,7 +1948,9 @@ impl SimpleLexer {
                         )
                     };
 
-                    let id = files.resolve_system_include(sys_file).map_err(map_err)?;
+                    let id = files
+                        .resolve_system_include_in(sys_file, self.file, &self.include_paths)
+                        .map_err(map_err)?;
                     return Ok(RawTok::Include(id));
                 } else {
                     return Err(error!(
@@ -1430,6 +1970,21 @@ impl SimpleLexer {
         }
     }
 
+    /// The source text from `begin` to `end`
+    fn text<'b>(&self, data: &'b [u8], begin: usize, end: usize) -> Result<&'b str, Error> {
+        return str::from_utf8(&data[begin..end]).map_err(|_| {
+            let loc = self.l(begin, end);
+            error!("text isn't valid UTF-8", loc, "found here")
+        });
+    }
+
+    fn record_comment(&mut self, start: usize) {
+        let loc = self.l(start, self.current);
+        if let Some(comments) = &mut self.comments {
+            comments.push(loc);
+        }
+    }
+
     pub fn kill_whitespace(&mut self, data: &[u8], avoid_newlines: bool) -> Result<bool, Error> {
         self.begin = self.current;
 
@@ -1439,10 +1994,12 @@ impl SimpleLexer {
                 self.at_line_begin = false;
             }
 
+            let comment_start = self.current;
             if self.peek_eq_series(data, &[b'/', b'/']) {
                 self.current += 2;
                 loop {
                     if self.current == data.len() {
+                        self.record_comment(comment_start);
                         return Ok(true);
                     }
 
@@ -1450,16 +2007,12 @@ impl SimpleLexer {
                         break;
                     } else if self.peek_eq_series(data, &CRLF) {
                         break;
-                    } else if self.peek_eq_series(data, &[b'\\', b'\n']) {
-                        self.current += 2;
-                        continue;
-                    } else if self.peek_eq_series(data, &[b'\\', b'\r', b'\n']) {
-                        self.current += 3;
-                        continue;
                     } else {
                         self.current += 1;
                     }
                 }
+
+                self.record_comment(comment_start);
             } else if self.peek_eq_series(data, &[b'/', b'*']) {
                 self.current += 2;
                 loop {
@@ -1480,6 +2033,7 @@ impl SimpleLexer {
 
                 self.current += 2;
                 self.at_line_begin = false;
+                self.record_comment(comment_start);
                 continue;
             }
 
@@ -1497,10 +2051,6 @@ impl SimpleLexer {
 
                 self.current += 2;
                 self.at_line_begin = true;
-            } else if self.peek_eq_series(data, &[b'\\', b'\n']) {
-                self.current += 2;
-            } else if self.peek_eq_series(data, &[b'\\', b'\r', b'\n']) {
-                self.current += 3;
             } else {
                 break;
             }
@@ -1511,7 +2061,37 @@ impl SimpleLexer {
 
     #[inline]
     pub fn loc(&self) -> CodeLoc {
-        return l(self.begin as u32, self.current as u32, self.file);
+        return self.l(self.begin, self.current);
+    }
+
+    /// The location of the bytes from `begin` to `end` in the original file,
+    /// before lines were spliced
+    pub fn l(&self, begin: usize, end: usize) -> CodeLoc {
+        let removed_before = |offset: usize, inclusive: bool| {
+            let idx = self.splices.partition_point(|&(at, _)| {
+                (at as usize) < offset || (inclusive && at as usize == offset)
+            });
+
+            return idx
+                .checked_sub(1)
+                .map(|idx| self.splices[idx].1)
+                .unwrap_or(0);
+        };
+
+        let start = begin + removed_before(begin, true) as usize;
+        let end = end + removed_before(end, false) as usize;
+        return l(start as u32, core::cmp::max(start, end) as u32, self.file);
+    }
+
+    /// Skips to the end of the current line, e.g. the rest of a preprocessor
+    /// directive that doesn't need to be lexed
+    pub fn skip_line(&mut self, data: &[u8]) {
+        while self.peek_neq(data, b'\n') {
+            self.current += 1;
+        }
+
+        self.in_macro = false;
+        self.in_number = false;
     }
 
     #[inline]
@@ -1611,114 +2191,302 @@ impl SimpleLexer {
         return false;
     }
 
-    pub fn lex_character(&mut self, surround: u8, data: &[u8]) -> Result<u8, Error> {
-        loop {
-            let cur_b = self.expect(data)?;
-            let cur: char = cur_b.into();
+    /// Error for a literal with an `L`, `u`, or `U` prefix; the prefix was just lexed
+    pub fn wide_literal(&mut self, prefix: &str, data: &[u8]) -> Error {
+        let quote = data[self.current];
+        let kind = if quote == b'"' { "string" } else { "character" };
+        self.current += 1;
+        while let Ok(Some(_)) = self.lex_character(quote, data) {}
+
+        return error!(
+            &format!("wide {} literals aren't supported", kind),
+            self.loc(),
+            format!(
+                "remove the `{}` prefix to make this a plain {} literal",
+                prefix, kind
+            )
+        );
+    }
 
-            if !cur.is_ascii() {
+    /// Lexes one character of a string or character literal, or returns `None`
+    /// for the closing `surround`
+    pub fn lex_character(&mut self, surround: u8, data: &[u8]) -> Result<Option<u8>, Error> {
+        let cur_b = self.expect(data)?;
+        let cur: char = cur_b.into();
+
+        if !cur.is_ascii() {
+            return Err(error!(
+                "character is not valid ascii",
+                self.l(self.current - 1, self.current),
+                "invalid character literal here"
+            ));
+        }
+
+        if cur_b == surround {
+            return Ok(None);
+        }
+
+        if cur_b == b'\n' || cur_b == b'\r' {
+            if surround == b'\"' {
+                return Err(error!(
+                    "invalid character found when parsing string literal",
+                    self.l(self.current - 1, self.current),
+                    "invalid character here"
+                ));
+            } else {
                 return Err(error!(
-                    "character is not valid ascii",
-                    l(self.current as u32 - 1, self.current as u32, self.file),
-                    "invalid character literal here"
+                    "invalid character found when parsing character literal",
+                    self.l(self.current - 1, self.current),
+                    "invalid character here"
                 ));
             }
+        }
 
-            if cur_b == surround {
-                return Ok(CLOSING_CHAR);
-            }
+        if cur_b != b'\\' {
+            return Ok(Some(cur_b));
+        }
 
-            if cur_b == b'\n' || cur_b == b'\r' {
-                if surround == b'\"' {
-                    return Err(error!(
-                        "invalid character found when parsing string literal",
-                        l(self.current as u32 - 1, self.current as u32, self.file),
-                        "invalid character here"
-                    ));
-                } else {
+        match self.expect(data)? {
+            b'n' => return Ok(Some(b'\n')),
+            b't' => return Ok(Some(b'\t')),
+            b'\'' => return Ok(Some(b'\'')),
+            b'"' => return Ok(Some(b'"')),
+
+            // \nnn where each 'n' is an octal digit
+            x @ b'0'..=b'7' => {
+                let begin = self.current - 2;
+                let mut c = (x - b'0') as u32;
+                for _ in 0..2 {
+                    if !self.peek_check(data, |c| c >= b'0' && c <= b'7') {
+                        break;
+                    }
+
+                    c = c * 8 + (data[self.current] - b'0') as u32;
+                    self.current += 1;
+                }
+
+                if c > u8::MAX as u32 {
                     return Err(error!(
-                        "invalid character found when parsing character literal",
-                        l(self.current as u32 - 1, self.current as u32, self.file),
-                        "invalid character here"
+                        "octal escape sequence is out of range",
+                        self.l(begin, self.current),
+                        "escape sequence here"
                     ));
                 }
+
+                return Ok(Some(c as u8));
             }
 
-            if cur_b != b'\\' {
-                return Ok(cur_b);
+            _ => {
+                return Err(error!(
+                    "invalid escape sequence",
+                    self.l(self.current - 2, self.current),
+                    "invalid escape sequence here"
+                ))
             }
+        }
+    }
+}
 
-            match self.expect(data)? {
-                b'n' => return Ok(b'\n'),
-                b't' => return Ok(b'\t'),
-                b'\'' => return Ok(b'\''),
-                b'"' => return Ok(b'"'),
+/// Removes every backslash-newline from `data`, so that lines are joined
+/// everywhere, including in the middle of a token, string, or comment, before
+/// the file is lexed. Returns the joined bytes, along with a sorted list of
+/// `(offset, removed)` pairs: `removed` bytes were taken out of the file before
+/// `offset` in the joined bytes. Locations are mapped back with these, so that
+/// errors point at the original text.
+///
+/// Trigraphs like `??=` aren't replaced, the same as GCC without `-trigraphs`;
+/// they were removed from the language in C23.
+pub fn splice_lines(data: &[u8]) -> (Vec<u8>, Vec<(u32, u32)>) {
+    let mut out = Vec::with_capacity(data.len());
+    let mut splices: Vec<(u32, u32)> = Vec::new();
+    let (mut idx, mut removed) = (0, 0);
+    while idx < data.len() {
+        let len = match &data[idx..] {
+            [b'\\', b'\n', ..] => 2,
+            [b'\\', b'\r', b'\n', ..] => 3,
+            _ => {
+                out.push(data[idx]);
+                idx += 1;
+                continue;
+            }
+        };
 
-                // \nnn where each 'n' is an octal digit
-                x @ b'0'..=b'7' => {
-                    let mut c = x - b'0';
-                    if !self.peek_check(data, |c| c >= b'0' && c <= b'7') {
-                        return Ok(c);
-                    }
+        idx += len;
+        removed += len as u32;
+        match splices.last_mut() {
+            Some(last) if last.0 == out.len() as u32 => last.1 = removed,
+            _ => splices.push((out.len() as u32, removed)),
+        }
+    }
 
-                    c *= 8;
-                    c += data[self.current] - b'0';
-                    self.current += 1;
+    return (out, splices);
+}
 
-                    if !self.peek_check(data, |c| c >= b'0' && c <= b'7') {
-                        return Ok(c);
+pub fn is_ident_char(cur: u8) -> bool {
+    (cur >= b'a' && cur <= b'z')
+        || (cur >= b'A' && cur <= b'Z')
+        || cur == b'_'
+        || (cur >= b'0' && cur <= b'9')
+}
+
+/// Returns the guard macro if the whole file is wrapped in
+/// `#ifndef GUARD`/`#define GUARD`/.../`#endif`, with no `#else` or `#elif` for
+/// the `#ifndef`, ignoring comments and blank lines.
+pub fn include_guard(source: &str) -> Option<&str> {
+    let mut lines = Vec::new();
+    let mut in_comment = false;
+    for line in source.lines() {
+        let mut line = line.trim();
+        let mut code = false;
+        while line != "" {
+            if in_comment {
+                match line.find("*/") {
+                    Some(end) => {
+                        line = line[(end + 2)..].trim();
+                        in_comment = false;
                     }
+                    None => line = "",
+                }
+            } else if line.starts_with("/*") {
+                line = &line[2..];
+                in_comment = true;
+            } else if line.starts_with("//") {
+                line = "";
+            } else {
+                code = true;
+                break;
+            }
+        }
 
-                    c *= 8;
-                    c += data[self.current] - b'0';
-                    self.current += 1;
+        if code {
+            lines.push(line);
+        }
+    }
 
-                    return Ok(c);
-                }
+    fn directive(line: &str) -> Option<(&str, &str)> {
+        let line = line.strip_prefix('#')?.trim_start();
+        let name_end = line
+            .find(|c: char| !is_ident_char(c as u8))
+            .unwrap_or(line.len());
+        let (name, rest) = line.split_at(name_end);
+        let rest = rest.split("//").next().unwrap().split("/*").next().unwrap();
+        return Some((name, rest.trim()));
+    }
 
-                b'\n' => continue,
-                b'\r' => {
-                    if self.peek_eq(data, b'\n') {
-                        self.current += 1;
-                        continue;
-                    } else {
-                        return Err(error!(
-                            "encoding of the file is probably messed up",
-                            l(self.current as u32 - 1, self.current as u32, self.file),
-                            "invalid character here"
-                        ));
-                    }
-                }
+    let (first, second, last) = (lines.first()?, lines.get(1)?, lines.last()?);
+    let guard = match directive(first)? {
+        ("ifndef", guard) if guard != "" => guard,
+        _ => return None,
+    };
 
-                _ => {
-                    return Err(error!(
-                        "invalid escape sequence",
-                        l(self.current as u32 - 2, self.current as u32, self.file),
-                        "invalid escape sequence here"
-                    ))
+    let (define, defined) = directive(second)?;
+    if define != "define" || defined.split_whitespace().next() != Some(guard) {
+        return None;
+    }
+
+    if directive(last)?.0 != "endif" {
+        return None;
+    }
+
+    let mut depth = 0;
+    for (idx, line) in lines.iter().enumerate() {
+        match directive(line).map(|d| d.0) {
+            Some("if") | Some("ifdef") | Some("ifndef") => depth += 1,
+            // the rest of the file is used when the guard is already defined
+            Some("else") | Some("elif") if depth == 1 => return None,
+            Some("endif") => {
+                depth -= 1;
+                if depth == 0 && idx != lines.len() - 1 {
+                    return None;
                 }
             }
+            _ => {}
         }
     }
+
+    return Some(guard);
 }
 
-pub fn is_ident_char(cur: u8) -> bool {
-    (cur >= b'a' && cur <= b'z')
-        || (cur >= b'A' && cur <= b'Z')
-        || cur == b'_'
-        || (cur >= b'0' && cur <= b'9')
+/// `chain` is every (file, include directive) in the cycle, in order; the last
+/// file includes the first.
+pub fn include_cycle(files: &FileDb, chain: &[(u32, CodeLoc)]) -> Error {
+    let name = |file: u32| files.name(file).unwrap_or("<unknown>");
+
+    let mut message = String::from("include cycle detected: ");
+    let mut sections = Vec::new();
+    for (idx, &(file, loc)) in chain.iter().enumerate() {
+        let (next, _) = chain[(idx + 1) % chain.len()];
+        message.push_str(name(file));
+        message.push_str(" includes ");
+
+        sections.push(ErrorSection {
+            location: loc,
+            message: format!("{} includes {} here", name(file), name(next)),
+        });
+    }
+    message.push_str(name(chain[0].0));
+
+    return Error::new(message, sections);
+}
+
+/// How many includes of a chain to show in errors; the chain can be as long as
+/// `LexLimits::max_include_depth`
+const MAX_CHAIN_SECTIONS: usize = 8;
+
+/// Sections for the innermost includes of `chain`, which is every (file, location
+/// it's lexed up to) from the file being compiled inwards
+fn chain_sections(files: &FileDb, chain: &[(u32, CodeLoc)]) -> Vec<ErrorSection> {
+    let name = |file: u32| files.name(file).unwrap_or("<unknown>");
+    let links = chain.windows(2).map(|pair| (pair[0], pair[1].0));
+    let links: Vec<_> = links.collect();
+    let skip = links.len().saturating_sub(MAX_CHAIN_SECTIONS);
+
+    let mut sections = Vec::new();
+    for ((file, loc), next) in links.into_iter().skip(skip) {
+        sections.push(ErrorSection {
+            location: loc,
+            message: format!("{} includes {} here", name(file), name(next)),
+        });
+    }
+
+    return sections;
+}
+
+/// `chain` ends with the file that would go over the limit
+pub fn include_too_deep(files: &FileDb, chain: &[(u32, CodeLoc)], limit: u32) -> Error {
+    let name = |file: u32| files.name(file).unwrap_or("<unknown>");
+    let message = format!(
+        "includes are nested more than {} deep, starting from {}",
+        limit,
+        name(chain[0].0)
+    );
+
+    return Error::new(message, chain_sections(files, chain));
+}
+
+/// `chain` ends with the file that was being lexed when the limit was reached
+pub fn too_many_tokens(files: &FileDb, chain: &[(u32, CodeLoc)], limit: u32) -> Error {
+    let name = |file: u32| files.name(file).unwrap_or("<unknown>");
+    let message = format!(
+        "{} expands to more than {} tokens, after includes and macros",
+        name(chain[0].0),
+        limit
+    );
+
+    let mut sections = chain_sections(files, chain);
+    let &(file, location) = chain.last().unwrap();
+    sections.push(ErrorSection {
+        location,
+        message: format!("the limit was reached here, in {}", name(file)),
+    });
+
+    return Error::new(message, sections);
 }
 
 #[inline]
-pub fn expected_newline(
-    directive_name: &'static str,
-    begin: usize,
-    current: usize,
-    file: u32,
-) -> Error {
+pub fn expected_newline(directive_name: &'static str, loc: CodeLoc) -> Error {
     return error!(
         &format!("expected newline after {} directive", directive_name),
-        l(begin as u32, current as u32, file),
-        "directive here"
+        loc, "directive here"
     );
 }
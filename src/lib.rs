@@ -6,20 +6,39 @@
 extern crate alloc;
 
 #[macro_use]
-mod util;
+pub mod util;
 
 #[macro_use]
-mod runtime;
+pub mod runtime;
 
 mod assembler;
 mod ast;
 mod buckets;
-mod filedb;
+pub mod callgraph;
+pub mod debugger;
+pub mod filedb;
+pub mod formatter;
+pub mod frames;
+pub mod highlight;
+pub mod incremental;
+mod interner;
+pub mod layout;
 mod lexer;
+pub mod lsp;
+pub mod native;
+mod optimizer;
+pub mod outline;
 mod parser;
+pub mod query;
+pub mod repl;
 mod tc_ast;
 mod tc_structs;
+pub mod test_runner;
+pub mod timings;
 mod type_checker;
+pub mod unused;
+pub mod warnings;
+pub mod wasm_emit;
 
 #[cfg(target_arch = "wasm32")]
 mod wasm;
@@ -34,42 +53,420 @@ use filedb::FileDb;
 use runtime::*;
 use util::*;
 
+pub use assembler::Sanitizers;
+pub use lexer::LexLimits;
+
 #[cfg(target_arch = "wasm32")]
 pub use wasm::run;
 
 fn compile_filter<'a, In, T>(
-    mut a: impl FnMut(In) -> Result<T, Error> + 'a,
+    mut a: impl FnMut(In) -> Result<T, Vec<Error>> + 'a,
     errs: &'a mut Vec<Error>,
 ) -> impl FnMut(In) -> Option<T> + 'a {
     return move |idx| match a(idx) {
         Ok(t) => return Some(t),
         Err(e) => {
-            errs.push(e);
+            errs.extend(e);
             return None;
         }
     };
 }
 
 fn compile(env: &FileDb) -> Result<BinaryData, Vec<Error>> {
+    return compile_with_options(env, &CompileOptions::default());
+}
+
+/// Compiles the program to bytecode, recording how long each phase takes in `timings`
+pub fn compile_timed(
+    env: &FileDb,
+    options: &CompileOptions,
+    timings: &mut timings::Timings,
+) -> Result<BinaryData, Vec<Error>> {
+    let (program, _) = compile_with(env, timings, options)?;
+    return Ok(program);
+}
+
+/// Compiles the program with runtime checks from `sanitize` built in
+fn compile_sanitized(
+    env: &FileDb,
+    sanitize: assembler::Sanitizers,
+) -> Result<BinaryData, Vec<Error>> {
+    let options = CompileOptions {
+        sanitize,
+        ..Default::default()
+    };
+
+    return compile_with_options(env, &options);
+}
+
+/// Compiles the program along with the information `debugger` needs to evaluate
+/// expressions against it while it runs
+fn compile_debug(env: &FileDb) -> Result<(BinaryData, assembler::DebugInfo), Vec<Error>> {
+    let mut timings = timings::Timings::disabled();
+    let options = CompileOptions {
+        debug: true,
+        ..Default::default()
+    };
+
+    let (program, debug) = compile_with(env, &mut timings, &options)?;
+    return Ok((program, debug.unwrap()));
+}
+
+fn compile_with_options(env: &FileDb, options: &CompileOptions) -> Result<BinaryData, Vec<Error>> {
+    return compile_timed(env, options, &mut timings::Timings::disabled());
+}
+
+/// Compiles arbitrary bytes as a C file, for fuzzing. Bytes that aren't UTF-8
+/// are replaced first. Whatever the input, this should return errors rather
+/// than panic; a panic here is a bug.
+///
+/// Only the input itself goes through the pipeline, both as-is and optimized;
+/// the bundled libc isn't compiled or linked, which keeps each run fast.
+pub fn fuzz_compile(bytes: &[u8]) -> Result<(), Vec<Error>> {
+    let source = String::from_utf8_lossy(bytes);
+    let mut files = FileDb::new();
+    let message = |e| format!("couldn't add `fuzz.c`: {}", e);
+    let file = files
+        .add("fuzz.c", &source)
+        .map_err(|e| vec![error!(message(e))])?;
+
+    let mut lexer = lexer::Lexer::new(&files);
+    let (id, tokens) = lexer.lex(file).map_err(|e| vec![e])?;
+    let env = parser::parse(id, tokens)?;
+    let mut tu = type_checker::check_tree(&files, env.file, &lexer.symbols, &env.tree)?;
+    assembler::Assembler::new()
+        .add_file(&files, &tu)
+        .map_err(|e| vec![e])?;
+
+    optimizer::inline(&mut tu);
+    optimizer::optimize(&mut tu);
+    assembler::Assembler::new()
+        .add_file(&files, &tu)
+        .map_err(|e| vec![e])?;
+    return Ok(());
+}
+
+/// A compiled program, for whichever backend `CompileOptions::backend` picked
+#[derive(Debug, Clone)]
+pub enum Program {
+    Bytecode(BinaryData),
+    Native(native::NativeProgram),
+    Wasm(Vec<u8>),     // a WebAssembly module; see `wasm_emit` for what it imports
+    CallGraph(String), // Graphviz source, for `--emit=callgraph`
+    Layout(String),    // struct layouts as text, for `--emit=layout`
+    Frames(String),    // stack frame sizes as text, for `--emit=frames`
+}
+
+/// How a program run with `Program::run_captured` went
+#[derive(Debug, Clone)]
+pub struct RunResult {
+    pub exit_code: Result<i32, IError>,
+    pub stdout: String,
+    pub stderr: String, // includes the error, if the run ended with one
+    pub instructions_executed: u64, // for native code too, though it doesn't move the kernel's clock
+    pub wall_time: u64,             // microseconds, according to the kernel's clock
+}
+
+impl Program {
+    /// Runs the program on a new kernel, with no files and an empty stdin
+    pub fn run_captured(&self) -> RunResult {
+        return self.run_captured_in(&mut Kernel::new(Vec::new()));
+    }
+
+    /// Runs the program on `kernel`, so that its files, limits and clock apply.
+    /// Native programs check every one of the kernel's `Limits`, with a fresh
+    /// `max_ops` for each run, and count their instructions in the result, but
+    /// can't see its files, and don't count towards its `total_ops` or clock.
+    /// Anything else ends with a `NotRunnable` error.
+    pub fn run_captured_in(&self, kernel: &mut Kernel) -> RunResult {
+        let (start, start_ops) = (kernel.now(), kernel.total_ops);
+        let (exit_code, stdout, stderr, ops) = match self {
+            Program::Bytecode(binary) => {
+                let exit_code = kernel.run(binary);
+                let (stdout, stderr) = kernel.term_streams();
+                (exit_code, stdout, stderr, kernel.total_ops - start_ops)
+            }
+            Program::Native(program) => {
+                let mut host = native::NativeHost::default();
+                let exit_code = program.run_with(&kernel.limits, &mut host);
+                let stdout = String::from_utf8_lossy(&host.stdout).into_owned();
+                let mut stderr = String::from_utf8_lossy(&host.stderr).into_owned();
+                if let Err(e) = &exit_code {
+                    stderr += &format!("{}: {}\n", e.short_name, e.message);
+                }
+
+                (exit_code, stdout, stderr, host.ops)
+            }
+            _ => {
+                let e = ierror!("NotRunnable", "only bytecode and native code can be run");
+                let stderr = format!("{}: {}\n", e.short_name, e.message);
+                (Err(e), String::new(), stderr, 0)
+            }
+        };
+
+        return RunResult {
+            exit_code,
+            stdout,
+            stderr,
+            instructions_executed: ops,
+            wall_time: kernel.now() - start,
+        };
+    }
+}
+
+/// What `compile_program` produces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    Program,
+    CallGraph,
+    Layout,
+    Frames,
+}
+
+impl Default for Emit {
+    fn default() -> Self {
+        return Emit::Program;
+    }
+}
+
+/// Compiles the program for the backend, or produces the report, that `options` asks for
+pub fn compile_program(env: &FileDb, options: &CompileOptions) -> Result<Program, Vec<Error>> {
+    return compile_program_timed(env, options, &mut timings::Timings::disabled());
+}
+
+/// Like `compile_program`, but records how long each phase takes in `timings`
+pub fn compile_program_timed(
+    env: &FileDb,
+    options: &CompileOptions,
+    timings: &mut timings::Timings,
+) -> Result<Program, Vec<Error>> {
+    if options.emit == Emit::CallGraph {
+        let graph = callgraph::call_graph(env)?;
+        return Ok(Program::CallGraph(graph.to_dot()));
+    }
+
+    if options.emit == Emit::Layout {
+        let layouts = layout::layouts(env)?;
+        return Ok(Program::Layout(layout::to_text(&layouts)));
+    }
+
+    if options.emit == Emit::Frames {
+        let report = frames::frames(env)?;
+        return Ok(Program::Frames(report.to_text()));
+    }
+
+    let program = compile_timed(env, options, timings)?;
+    return match options.backend {
+        native::Backend::Interpreter => Ok(Program::Bytecode(program)),
+        native::Backend::Native => match native::lower(&program) {
+            Ok(native) => Ok(Program::Native(native)),
+            Err(err) => Err(vec![err]),
+        },
+        native::Backend::Wasm => match wasm_emit::emit(&program) {
+            Ok(module) => Ok(Program::Wasm(module)),
+            Err(err) => Err(vec![err]),
+        },
+    };
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompileOptions {
+    pub debug: bool,
+    pub sanitize: assembler::Sanitizers,
+    pub opt_level: u8,            // 0 or 1; see `optimizer`
+    pub no_inline: bool, // at -O1, keep calls to small functions, so they show up in stack traces
+    pub backend: native::Backend, // the native and wasm backends are experimental
+    pub emit: Emit,
+    pub gnu_extensions: bool,  // `--std=gnu11`; see `Lexer::gnu_extensions`
+    pub entry: Option<String>, // `--entry=fib` starts at `fib` instead of `main` and prints its return value
+    pub entry_args: Vec<String>, // `--args=10,2.5`, passed to the entry function
+    pub lex_limits: lexer::LexLimits, // `--max-include-depth=200` and `--max-tokens=4194304`
+    pub limits: Limits, // `--max-ops=`, `--max-stack=`, `--max-heap=` and `--max-call-depth=`
+    pub host_clock: bool, // `--clock=host`, for whoever runs the program; see `Kernel::use_host_clock`
+    pub defines: Vec<(String, Option<String>)>, // `-DNAME=value`, or `-UNAME` for `None`, in order
+    pub warnings: warnings::WarningOptions, // `-Wshadow`, for `warnings::warnings_with`
+    pub include_paths: Vec<String>, // `-Ivendor/include`, searched before `FileDb::include_paths`
+    pub color: term::ColorChoice, // `--color=never`; see `EmitConfig::new`
+    pub ignored_flags: Vec<String>, // why `parse_flag` ignored a flag, like `-Wall`
+}
+
+impl CompileOptions {
+    /// Applies a command line flag, like `-O1` or `--sanitize=alignment`
+    pub fn parse_flag(&mut self, flag: &str) -> Result<(), String> {
+        if flag.starts_with("--sanitize=") {
+            self.sanitize = assembler::Sanitizers::parse(&flag["--sanitize=".len()..])?;
+        } else if flag.starts_with("--backend=") {
+            self.backend = native::parse_backend(&flag["--backend=".len()..])?;
+        } else if flag.starts_with("--emit=") {
+            let (emit, backend) = match &flag["--emit=".len()..] {
+                "bytecode" => (Emit::Program, native::Backend::Interpreter),
+                "wasm" => (Emit::Program, native::Backend::Wasm),
+                "callgraph" => (Emit::CallGraph, self.backend),
+                "layout" => (Emit::Layout, self.backend),
+                "frames" => (Emit::Frames, self.backend),
+                emit => {
+                    return Err(format!(
+                        "unknown output `{}`, expected bytecode, wasm, callgraph, layout or frames",
+                        emit
+                    ))
+                }
+            };
+
+            self.emit = emit;
+            self.backend = backend;
+        } else if flag.starts_with("--std=") {
+            self.gnu_extensions = match &flag["--std=".len()..] {
+                "c99" | "c11" => false,
+                "gnu99" | "gnu11" => true,
+                std => {
+                    return Err(format!(
+                        "unknown standard `{}`, expected c99, c11, gnu99 or gnu11",
+                        std
+                    ))
+                }
+            };
+        } else if flag.starts_with("--entry=") {
+            self.entry = Some(flag["--entry=".len()..].to_string());
+        } else if flag.starts_with("--args=") {
+            let args = flag["--args=".len()..].split(',').map(|a| a.trim());
+            self.entry_args = args
+                .filter(|a| !a.is_empty())
+                .map(|a| a.to_string())
+                .collect();
+        } else if flag.starts_with("--color=") {
+            let color = &flag["--color=".len()..];
+            self.color = term::ColorChoice::from_flag(color).ok_or_else(|| {
+                format!(
+                    "unknown color choice `{}`, expected auto, always or never",
+                    color
+                )
+            })?;
+        } else if flag.starts_with("--clock=") {
+            self.host_clock = match &flag["--clock=".len()..] {
+                "virtual" => false,
+                "host" => true,
+                clock => {
+                    return Err(format!(
+                        "unknown clock `{}`, expected virtual or host",
+                        clock
+                    ))
+                }
+            };
+        } else if flag.starts_with("--max-include-depth=") {
+            let depth = &flag["--max-include-depth=".len()..];
+            self.lex_limits.max_include_depth = parse_limit(depth)?;
+        } else if flag.starts_with("--max-tokens=") {
+            self.lex_limits.max_tokens = parse_limit(&flag["--max-tokens=".len()..])?;
+        } else if flag.starts_with("--max-ops=") {
+            self.limits.max_ops = parse_limit(&flag["--max-ops=".len()..])?;
+        } else if flag.starts_with("--max-call-depth=") {
+            let depth = &flag["--max-call-depth=".len()..];
+            self.limits.max_call_depth = parse_limit::<u32>(depth)? as usize;
+        } else if flag.starts_with("--max-stack=") {
+            let bytes = &flag["--max-stack=".len()..];
+            self.limits.max_stack_bytes = parse_limit::<u32>(bytes)? as usize;
+        } else if flag.starts_with("--max-heap=") {
+            let bytes = &flag["--max-heap=".len()..];
+            self.limits.max_heap_bytes = parse_limit::<u32>(bytes)? as usize;
+        } else if flag.starts_with("-D") {
+            let define = &flag["-D".len()..];
+            let (name, value) = match define.find('=') {
+                Some(idx) => (&define[..idx], &define[(idx + 1)..]),
+                None => (define, "1"),
+            };
+
+            if name.is_empty() {
+                return Err("expected a macro name after `-D`".to_string());
+            }
+
+            self.defines
+                .push((name.to_string(), Some(value.to_string())));
+        } else if flag.starts_with("-U") {
+            let name = &flag["-U".len()..];
+            if name.is_empty() {
+                return Err("expected a macro name after `-U`".to_string());
+            }
+
+            self.defines.push((name.to_string(), None));
+        } else if flag.starts_with("-I") {
+            let dir = &flag["-I".len()..];
+            if dir.is_empty() {
+                return Err("expected a directory after `-I`".to_string());
+            }
+
+            let dir = dir.trim_end_matches('/');
+            self.include_paths
+                .push(if dir == "" { "/" } else { dir }.to_string());
+        } else if flag.starts_with("-W") {
+            let (name, on) = match flag.strip_prefix("-Wno-") {
+                Some(name) => (name, false),
+                None => (&flag["-W".len()..], true),
+            };
+
+            match name {
+                "shadow" => self.warnings.shadow = on,
+                // `-Wall` and friends are common in build scripts, so they shouldn't stop a compile
+                _ => self.ignored_flags.push(format!(
+                    "unknown warning `{}`, expected shadow; ignoring `{}`",
+                    name, flag
+                )),
+            }
+        } else if flag == "-fno-inline" {
+            self.no_inline = true;
+        } else if flag == "-finline" {
+            self.no_inline = false;
+        } else if flag.starts_with("-O") {
+            self.opt_level = optimizer::parse_opt_level(flag)?;
+        } else {
+            return Err(format!("unknown flag `{}`", flag));
+        }
+
+        return Ok(());
+    }
+}
+
+fn parse_limit<T: core::str::FromStr + From<u8> + PartialEq>(limit: &str) -> Result<T, String> {
+    return match limit.parse() {
+        Ok(limit) if limit != T::from(0) => Ok(limit),
+        _ => Err(format!("expected a positive number, got `{}`", limit)),
+    };
+}
+
+/// Lexes, parses, and type checks every file in `env` that isn't a header, then
+/// optimizes them if `options` asks for it
+fn check_files(
+    env: &FileDb,
+    timings: &mut timings::Timings,
+    options: &CompileOptions,
+) -> Result<(interner::Symbols, Vec<tc_ast::TranslationUnit>), Vec<Error>> {
     let mut errors: Vec<Error> = Vec::new();
     let mut lexer = lexer::Lexer::new(env);
+    configure_lexer(&mut lexer, options)?;
 
+    let start = timings.now();
     let files = env.impls().into_iter();
     let lexed: Vec<_> = files
-        .filter_map(compile_filter(|idx| lexer.lex(idx), &mut errors))
+        .filter_map(compile_filter(
+            |idx| lexer.lex(idx).map_err(|e| vec![e]),
+            &mut errors,
+        ))
         .collect();
+    timings.record("lex", timings.now() - start, lexer.buckets.used_bytes());
 
     if errors.len() != 0 {
         return Err(errors);
     }
 
+    let start = timings.now();
     let parsed: Vec<_> = lexed
         .into_iter()
         .filter_map(compile_filter(
-            |(id, toks, locs)| parser::parse(id, toks, locs),
+            |(id, tokens)| parser::parse(id, tokens),
             &mut errors,
         ))
         .collect();
+    let bytes = parsed.iter().map(|env| env.buckets.used_bytes()).sum();
+    timings.record("parse", timings.now() - start, bytes);
 
     let symbols = lexer.symbols();
 
@@ -77,34 +474,251 @@ fn compile(env: &FileDb) -> Result<BinaryData, Vec<Error>> {
         return Err(errors);
     }
 
-    let map = |env: parser::ParseEnv| type_checker::check_tree(env.file, &symbols, &env.tree);
-    let checked: Vec<_> = parsed
+    let files = env;
+    let start = timings.now();
+    let map = |env: parser::ParseEnv| check_parsed(files, &symbols, env);
+    let mut checked: Vec<_> = parsed
         .into_iter()
         .filter_map(compile_filter(map, &mut errors))
         .collect();
+    let bytes = checked.iter().map(|tu| tu.buckets.used_bytes()).sum();
+    timings.record("typecheck", timings.now() - start, bytes);
 
     if errors.len() != 0 {
         return Err(errors);
     }
 
-    let mut assembler = assembler::Assembler::new();
+    if options.opt_level >= 1 {
+        let start = timings.now();
+        for tu in &mut checked {
+            optimize_unit(tu, options);
+        }
+
+        let bytes = checked.iter().map(|tu| tu.buckets.used_bytes()).sum();
+        timings.record("optimize", timings.now() - start, bytes);
+    }
+
+    return Ok((symbols, checked));
+}
+
+/// Sets up `lexer` with the `-D`, `-I`, and other flags in `options`
+fn configure_lexer(lexer: &mut lexer::Lexer, options: &CompileOptions) -> Result<(), Vec<Error>> {
+    lexer.gnu_extensions = options.gnu_extensions;
+    lexer.limits = options.lex_limits;
+    lexer.include_paths = options.include_paths.clone();
+    for (name, value) in &options.defines {
+        match value {
+            Some(value) => lexer.define(name, value).map_err(|e| vec![e])?,
+            None => lexer.undefine(name),
+        }
+    }
+
+    return Ok(());
+}
+
+fn check_parsed(
+    files: &FileDb,
+    symbols: &interner::Symbols,
+    env: parser::ParseEnv,
+) -> Result<tc_ast::TranslationUnit, Vec<Error>> {
+    let options = type_checker::CheckOptions {
+        gnu_extensions: env.tokens.gnu_extensions,
+        ..Default::default()
+    };
+
+    return type_checker::check_tree_with(files, env.file, symbols, &env.tree, &options);
+}
+
+/// Runs the optimizations `options` asks for on a checked translation unit
+fn optimize_unit(tu: &mut tc_ast::TranslationUnit, options: &CompileOptions) {
+    if options.opt_level == 0 {
+        return;
+    }
+
+    if !options.no_inline {
+        optimizer::inline(tu);
+    }
+
+    optimizer::optimize(tu);
+}
+
+/// An assembler that builds the program the way `options` asks for
+fn new_assembler(
+    symbols: &interner::Symbols,
+    options: &CompileOptions,
+) -> Result<assembler::Assembler, Vec<Error>> {
+    let mut assembler = match options.debug {
+        true => assembler::Assembler::with_debug_info(symbols.clone()),
+        false => assembler::Assembler::new(),
+    };
+    assembler.sanitize = options.sanitize;
+    if let Some(name) = &options.entry {
+        assembler.entry = Some(assembler::EntryPoint {
+            name: name.clone(),
+            ident: symbols.from_str(name).opt(),
+            printer: symbols.from_str("__tci_print_entry").opt(),
+            args: options.entry_args.clone(),
+        });
+    } else if options.entry_args.len() != 0 {
+        return Err(vec![error!(
+            "`--args` needs a function to pass them to with `--entry`"
+        )]);
+    }
+
+    if options.opt_level >= 1 {
+        assembler.peephole = assembler::PEEPHOLE_RULES.to_vec();
+        assembler.share_slots = true;
+    }
+
+    return Ok(assembler);
+}
+
+fn compile_with(
+    env: &FileDb,
+    timings: &mut timings::Timings,
+    options: &CompileOptions,
+) -> Result<(BinaryData, Option<assembler::DebugInfo>), Vec<Error>> {
+    let (symbols, checked) = check_files(env, timings, options)?;
+
+    let start = timings.now();
+    let mut assembler = new_assembler(&symbols, options)?;
     for tu in checked {
-        match assembler.add_file(tu) {
+        match assembler.add_file(env, &tu) {
             Ok(_) => {}
             Err(err) => return Err(vec![err]),
         }
     }
 
-    let program = match assembler.assemble(env) {
-        Ok(x) => x,
-        Err(err) => return Err(vec![err]),
+    let bytes = assembler.buckets.used_bytes();
+    let debug = assembler.debug_info();
+    let program = assembler.assemble(env, &symbols)?;
+    timings.record("assemble", timings.now() - start, bytes);
+
+    return Ok((program, debug));
+}
+
+pub struct EmitConfig {
+    pub color: term::ColorChoice,
+    pub is_terminal: bool, // whether the writer ends up on a terminal, for `ColorChoice::Auto`
+    pub max_errors: usize,
+}
+
+impl EmitConfig {
+    /// Renders errors with the `--color` that `options` asked for
+    pub fn new(options: &CompileOptions, is_terminal: bool) -> Self {
+        return Self {
+            color: options.color,
+            is_terminal,
+            ..Default::default()
+        };
+    }
+}
+
+impl Default for EmitConfig {
+    fn default() -> Self {
+        return Self {
+            color: term::ColorChoice::Auto,
+            is_terminal: false,
+            max_errors: 20,
+        };
+    }
+}
+
+/// Compiles `source` on its own as `file_name` and renders its errors the way
+/// `emit_err` would, without color or compiler source locations. Returns an empty string if it compiles.
+/// Snapshot tests of error messages compare against this.
+pub fn diagnostics(file_name: &str, source: &str) -> String {
+    let mut files = FileDb::new();
+    if let Err(e) = files.add(file_name, source) {
+        let message = format!("couldn't add `{}`: {}", file_name, e);
+        return render_diagnostics(vec![error!(message)], &files);
+    }
+
+    return diagnostics_with(&files, &CompileOptions::default());
+}
+
+/// Renders the errors from compiling every file in `files` with `options`
+pub fn diagnostics_with(files: &FileDb, options: &CompileOptions) -> String {
+    return match compile_with_options(files, options) {
+        Ok(_) => String::new(),
+        Err(errs) => render_diagnostics(errs, files),
     };
+}
 
-    return Ok(program);
+/// Renders `errs` for `diagnostics`, without color or compiler source locations
+fn render_diagnostics(mut errs: Vec<Error>, files: &FileDb) -> String {
+    // Debug builds say where in the compiler an error came from, which would
+    // make every snapshot depend on line numbers in this crate
+    for err in &mut errs {
+        if let Some(idx) = err.message.find(" (in compiler at ") {
+            err.message.truncate(idx);
+        }
+    }
+
+    let config = EmitConfig {
+        color: term::ColorChoice::Never,
+        ..Default::default()
+    };
+
+    let mut out = StringWriter::new();
+    emit_err_with(&errs, files, &config, &mut out);
+    return out.into_string();
+}
+
+/// Warnings about the program in `files`, from `warnings::warnings_with` with the
+/// `-W` flags in `options`, and then from `unused::unused`. A program that doesn't
+/// compile has none; its errors come first.
+pub fn program_warnings(files: &FileDb, options: &CompileOptions) -> Vec<Error> {
+    let mut warnings = warnings::warnings_with(files, &options.warnings).unwrap_or(Vec::new());
+    warnings.extend(unused::unused(files).unwrap_or(Vec::new()));
+    return warnings;
+}
+
+/// Renders `warnings` the way `emit_err_with` renders errors, with `warning: ` in
+/// front of each message so they can't be mistaken for errors
+pub fn emit_warnings(
+    warnings: Vec<Error>,
+    files: &FileDb,
+    config: &EmitConfig,
+    writer: &mut impl core::fmt::Write,
+) {
+    let warnings: Vec<Error> = warnings
+        .into_iter()
+        .map(|w| Error::new(format!("warning: {}", w.message), w.sections))
+        .collect();
+    emit_err_with(&warnings, files, config, writer);
 }
 
 fn emit_err(errs: &[Error], files: &FileDb, writer: &mut impl core::fmt::Write) {
+    emit_err_with(errs, files, &EmitConfig::default(), writer);
+}
+
+/// Renders `errs`, skipping repeats of the same error (e.g. one found in a header
+/// that several files include) and stopping after `config.max_errors` of them.
+pub fn emit_err_with(
+    errs: &[Error],
+    files: &FileDb,
+    config: &EmitConfig,
+    writer: &mut impl core::fmt::Write,
+) {
+    let color = config.color.should_color(config.is_terminal);
+    let mut unique: Vec<&Error> = Vec::new();
     for err in errs {
-        err.render(files, writer).unwrap();
+        if !unique.contains(&err) {
+            unique.push(err);
+        }
+    }
+
+    for err in unique.iter().take(config.max_errors) {
+        err.render_colored(files, writer, color).unwrap();
+    }
+
+    if unique.len() > config.max_errors {
+        let hidden = unique.len() - config.max_errors;
+        let message = format!("too many errors; {} more not shown", hidden);
+        term::Diagnostic::new()
+            .with_message(message)
+            .render_colored(files, writer, color)
+            .unwrap();
     }
 }
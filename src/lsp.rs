@@ -0,0 +1,560 @@
+//! Language Server Protocol front-end. The server doesn't own any I/O; whoever
+//! hosts it (e.g. `tci lsp`) feeds it bytes from stdin with `recv` and writes
+//! the bytes from `take_output` to stdout.
+
+use crate::filedb::*;
+use crate::highlight::*;
+use crate::query::*;
+use crate::util::*;
+use serde_json::{json, Value};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const REQUEST_FAILED: i64 = -32803;
+
+/// Longest header block that's waited on; anything longer without the blank line
+/// that ends it isn't a header
+const MAX_HEADER_LEN: usize = 4096;
+
+/// Longest message body that's buffered; a longer one is skipped without being
+/// kept in memory
+const MAX_BODY_LEN: usize = 1 << 28;
+
+/// Reads a file that isn't open in the editor, or returns `None` if there isn't one
+pub type ReadFile = fn(&str) -> Option<Vec<u8>>;
+
+pub struct LspServer {
+    docs: Vec<(String, String)>, // (uri, text)
+    input: Vec<u8>,
+    skip: usize, // bytes left of a body that's too long to handle
+    output: Vec<u8>,
+    pub shutdown: bool,
+    pub exited: bool,
+    pub read_file: Option<ReadFile>, // for headers that aren't open, e.g. from disk
+}
+
+impl LspServer {
+    pub fn new() -> Self {
+        return Self {
+            docs: Vec::new(),
+            input: Vec::new(),
+            skip: 0,
+            output: Vec::new(),
+            shutdown: false,
+            exited: false,
+            read_file: None,
+        };
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        return core::mem::replace(&mut self.output, Vec::new());
+    }
+
+    /// Buffers `bytes` and handles every complete message received so far. A
+    /// header block without a valid `Content-Length` is thrown away, since
+    /// there's no telling where its body ends, and a body longer than
+    /// `MAX_BODY_LEN` is skipped.
+    pub fn recv(&mut self, bytes: &[u8]) {
+        let skipped = self.skip.min(bytes.len());
+        self.skip -= skipped;
+        self.input.extend_from_slice(&bytes[skipped..]);
+
+        while let Some(header) = parse_header(&self.input) {
+            let (header_len, body_len) = match header {
+                Ok(lens) => lens,
+                Err(header_len) => {
+                    self.input.drain(..header_len);
+                    self.send_error(PARSE_ERROR, "expected a Content-Length header");
+                    continue;
+                }
+            };
+
+            let len = match header_len.checked_add(body_len) {
+                Some(len) if body_len <= MAX_BODY_LEN => len,
+                _ => {
+                    self.input.drain(..header_len);
+                    let skipped = body_len.min(self.input.len());
+                    self.input.drain(..skipped);
+                    self.skip = body_len - skipped;
+                    self.send_error(PARSE_ERROR, "message is too long");
+                    continue;
+                }
+            };
+
+            if self.input.len() < len {
+                return;
+            }
+
+            let body: Vec<u8> = self.input.drain(..len).collect();
+            match serde_json::from_slice::<Value>(&body[header_len..]) {
+                Ok(message) => self.handle(&message),
+                Err(_) => self.send_error(PARSE_ERROR, "couldn't parse message"),
+            }
+        }
+    }
+
+    pub fn handle(&mut self, message: &Value) {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let id = &message["id"];
+
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1, // full document sync
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "renameProvider": true,
+                    "semanticTokensProvider": {
+                        "legend": {
+                            "tokenTypes": HighlightKind::ALL.iter().map(|k| k.lsp_name()).collect::<Vec<_>>(),
+                            "tokenModifiers": [],
+                        },
+                        "full": true,
+                    },
+                },
+                "serverInfo": { "name": "tci" },
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "exit" => {
+                self.exited = true;
+                return;
+            }
+            "textDocument/didOpen" => {
+                let doc = &params["textDocument"];
+                match (doc["uri"].as_str(), doc["text"].as_str()) {
+                    (Some(uri), Some(text)) => return self.set_doc(uri, text),
+                    _ => return,
+                }
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str();
+                let changes = params["contentChanges"].as_array();
+                let text = changes.and_then(|c| c.last()).map(|c| c["text"].as_str());
+                match (uri, text) {
+                    (Some(uri), Some(Some(text))) => return self.set_doc(uri, text),
+                    _ => return,
+                }
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    self.docs.retain(|(u, _)| u != uri);
+                    self.send_diagnostics(uri, Vec::new());
+                    self.publish_diagnostics();
+                }
+                return;
+            }
+            "textDocument/hover" => self.hover(params),
+            "textDocument/definition" => self.definition(params),
+            "textDocument/rename" => self.rename(params),
+            "textDocument/semanticTokens/full" => self.semantic_tokens(params),
+            _ => {
+                if id.is_null() {
+                    return; // unknown notification
+                }
+
+                Err((
+                    METHOD_NOT_FOUND,
+                    format!("method {:?} not supported", method),
+                ))
+            }
+        };
+
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+
+        self.send(response);
+    }
+
+    /// Keeps `text` as the source of `uri` and publishes diagnostics, unless the
+    /// documents can't be put in a `FileDb` with it, e.g. because it's too big, or
+    /// because another document has the same path. Then the document stays how it
+    /// was, and the editor shows why. Opening and changing documents are
+    /// notifications, so there's no request to respond to with an error.
+    fn set_doc(&mut self, uri: &str, text: &str) {
+        let prev = self.docs.clone();
+        match self.docs.iter_mut().find(|(doc_uri, _)| doc_uri == uri) {
+            Some((_, doc_text)) => *doc_text = text.to_string(),
+            None => self.docs.push((uri.to_string(), text.to_string())),
+        }
+
+        if let Err((_, message)) = self.file_db() {
+            self.docs = prev;
+            return self.send(json!({
+                "jsonrpc": "2.0",
+                "method": "window/showMessage",
+                "params": { "type": 1, "message": message }, // 1 is an error
+            }));
+        }
+
+        self.publish_diagnostics();
+    }
+
+    fn file_db(&self) -> Result<(FileDb, Vec<u32>), (i64, String)> {
+        let mut files = FileDb::new();
+        let mut ids = Vec::with_capacity(self.docs.len());
+        let first = files.files.len();
+        for (uri, text) in &self.docs {
+            match files.add(uri_to_path(uri), text) {
+                Ok(id) => ids.push(id),
+                Err(e) => return Err((REQUEST_FAILED, format!("couldn't open {}: {}", uri, e))),
+            }
+        }
+
+        // A header that can't be added is reported where it's included, when compiling
+        if let Some(read) = self.read_file {
+            let dirs = files.include_paths.clone();
+            let _ = files.add_includes(first, &dirs, read);
+        }
+
+        return Ok((files, ids));
+    }
+
+    fn doc_uri(&self, ids: &[u32], file: u32) -> Option<&str> {
+        let idx = ids.iter().position(|id| *id == file)?;
+        return Some(&self.docs[idx].0);
+    }
+
+    fn publish_diagnostics(&mut self) {
+        // `set_doc` only keeps documents that fit in a `FileDb`
+        let (files, ids) = match self.file_db() {
+            Ok(db) => db,
+            Err(_) => return,
+        };
+        let mut diagnostics: Vec<Vec<Value>> = self.docs.iter().map(|_| Vec::new()).collect();
+
+        // Warnings are only worth showing once the program compiles
+        let (errs, severity) = match crate::compile(&files) {
+            Err(errs) => (errs, 1),
+            Ok(_) => {
+                let options = crate::CompileOptions::default();
+                (crate::program_warnings(&files, &options), 2)
+            }
+        };
+
+        for err in errs {
+            let loc = err.sections.first().map(|s| s.location);
+            let doc = loc.and_then(|loc| ids.iter().position(|id| *id == loc.file));
+
+            // errors in system headers or without a location go on the first document
+            let (doc, range) = match (doc, loc) {
+                (Some(doc), Some(loc)) => (doc, loc_to_range(&files, loc)),
+                _ => (0, json!({ "start": pos(0, 0), "end": pos(0, 0) })),
+            };
+
+            let mut message = err.message.clone();
+            for section in &err.sections {
+                message.push_str("\n");
+                message.push_str(&files.loc_to_string(section.location));
+                message.push_str(": ");
+                message.push_str(&section.message);
+            }
+
+            if let Some(list) = diagnostics.get_mut(doc) {
+                list.push(json!({
+                    "range": range,
+                    "severity": severity,
+                    "source": "tci",
+                    "message": message,
+                }));
+            }
+        }
+
+        for (idx, list) in diagnostics.into_iter().enumerate() {
+            let uri = self.docs[idx].0.clone();
+            self.send_diagnostics(&uri, list);
+        }
+    }
+
+    fn send_diagnostics(&mut self, uri: &str, diagnostics: Vec<Value>) {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }));
+    }
+
+    /// The files open in the editor, and the file and byte offset of the
+    /// position in `params`
+    fn position(
+        &self,
+        params: &Value,
+    ) -> Result<Option<(FileDb, Vec<u32>, u32, u32)>, (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str();
+        let line = params["position"]["line"].as_u64();
+        let character = params["position"]["character"].as_u64();
+        let (uri, line, character) = match (uri, line, character) {
+            (Some(u), Some(l), Some(c)) => (u, l as usize, c as usize),
+            _ => {
+                return Err((
+                    INVALID_PARAMS,
+                    "expected a text document position".to_string(),
+                ))
+            }
+        };
+
+        let (files, ids) = self.file_db()?;
+        let idx = match self.docs.iter().position(|(u, _)| u == uri) {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+        let file = ids[idx];
+
+        let range = match files.line_range(file, line) {
+            Some(range) => range,
+            None => return Ok(None),
+        };
+
+        // Past the end of the line is the end of the line, like the spec says
+        let text = &files.source(file).unwrap()[range.clone()];
+        let text = text.trim_end_matches(&['\r', '\n'][..]);
+        let offset = range.start + utf16_to_byte(text, character);
+
+        return Ok(Some((files, ids, file, offset as u32)));
+    }
+
+    fn symbol_at_position(
+        &self,
+        params: &Value,
+    ) -> Result<Option<(SymbolInfo, FileDb, Vec<u32>)>, (i64, String)> {
+        let (files, ids, file, offset) = match self.position(params)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        // Compile errors aren't reported here, they're already published as diagnostics
+        let info = match symbol_at(&files, file, offset) {
+            Ok(Some(info)) => info,
+            Ok(None) | Err(_) => return Ok(None),
+        };
+
+        return Ok(Some((info, files, ids)));
+    }
+
+    fn hover(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (info, files, _) = match self.symbol_at_position(params)? {
+            Some(found) => found,
+            None => return Ok(Value::Null),
+        };
+
+        let value = format!(
+            "```c\n{} {}\n```\ndeclared at {}",
+            info.ty,
+            info.name,
+            files.loc_to_string(info.defn_loc)
+        );
+
+        return Ok(json!({ "contents": { "kind": "markdown", "value": value } }));
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (info, files, ids) = match self.symbol_at_position(params)? {
+            Some(found) => found,
+            None => return Ok(Value::Null),
+        };
+
+        let uri = match self.doc_uri(&ids, info.defn_loc.file) {
+            Some(uri) => uri,
+            None => return Ok(Value::Null), // defined in a system header
+        };
+
+        return Ok(json!({ "uri": uri, "range": loc_to_range(&files, info.defn_loc) }));
+    }
+
+    fn rename(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let new_name = match params["newName"].as_str() {
+            Some(name) => name,
+            None => return Err((INVALID_PARAMS, "expected a new name".to_string())),
+        };
+
+        let (files, ids, file, offset) = match self.position(params)? {
+            Some(found) => found,
+            None => return Ok(Value::Null),
+        };
+
+        let edits = match rename(&files, file, offset, new_name) {
+            Ok(edits) => edits,
+            Err(errs) => return Err((REQUEST_FAILED, errs[0].message.clone())),
+        };
+
+        let mut changes = serde_json::Map::new();
+        for edit in edits {
+            let uri = match self.doc_uri(&ids, edit.loc.file) {
+                Some(uri) => uri,
+                None => continue, // system headers can't be edited
+            };
+
+            let edit = json!({ "range": loc_to_range(&files, edit.loc), "newText": edit.new_text });
+            let list = changes.entry(uri).or_insert(json!([]));
+            list.as_array_mut().unwrap().push(edit);
+        }
+
+        return Ok(json!({ "changes": changes }));
+    }
+
+    fn semantic_tokens(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let uri = match params["textDocument"]["uri"].as_str() {
+            Some(uri) => uri,
+            None => return Err((INVALID_PARAMS, "expected a text document".to_string())),
+        };
+
+        let (files, ids) = self.file_db()?;
+        let file = match self.docs.iter().position(|(u, _)| u == uri) {
+            Some(idx) => ids[idx],
+            None => return Ok(Value::Null),
+        };
+
+        // Files that don't parse get no highlighting beyond what the editor does itself.
+        // Tokens are sent relative to each other, so they have to be in order, and
+        // ones that overlap the one before them, e.g. from macro expansions, are
+        // dropped.
+        let mut highlights = highlight(&files, file).unwrap_or(Vec::new());
+        highlights.retain(|h| h.loc.file == file);
+        highlights.sort_by_key(|h| (h.loc.start, h.loc.end));
+
+        // Each token is 5 numbers: line and start relative to the previous token,
+        // length, type, and modifiers. Tokens can't span lines, so multi-line
+        // comments are split up.
+        let mut data = Vec::new();
+        let (mut prev_line, mut prev_start, mut prev_end) = (0, 0, 0);
+        for h in highlights {
+            if (h.loc.start as usize) < prev_end {
+                continue;
+            }
+
+            prev_end = h.loc.end as usize;
+            let kind = HighlightKind::ALL
+                .iter()
+                .position(|k| *k == h.kind)
+                .unwrap();
+            let first = files.line_index(file, h.loc.start as usize).unwrap_or(0);
+            let last = files.line_index(file, h.loc.end as usize).unwrap_or(first);
+            for line in first..=last {
+                let range = match files.line_range(file, line) {
+                    Some(range) => range,
+                    None => break,
+                };
+
+                let start = range.start.max(h.loc.start as usize);
+                let end = range.end.min(h.loc.end as usize);
+                let line_text = &files.source(file).unwrap()[range.start..end];
+                let text = &line_text[(start - range.start)..];
+                let len = utf16_len(text.trim_end_matches(&['\r', '\n'][..]));
+                if len == 0 {
+                    continue;
+                }
+
+                let start = utf16_len(&line_text[..(start - range.start)]);
+                let delta_start = if line == prev_line {
+                    start - prev_start
+                } else {
+                    start
+                };
+                data.extend_from_slice(&[line - prev_line, delta_start, len, kind, 0]);
+                prev_line = line;
+                prev_start = start;
+            }
+        }
+
+        return Ok(json!({ "data": data }));
+    }
+
+    /// Reports an error that isn't a response to any request
+    fn send_error(&mut self, code: i64, message: &str) {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": { "code": code, "message": message },
+        }));
+    }
+
+    fn send(&mut self, message: Value) {
+        let body = serde_json::to_vec(&message).unwrap();
+        let header = format!("Content-Length: {}\r\n\r\n", body.len());
+        self.output.extend_from_slice(header.as_bytes());
+        self.output.extend_from_slice(&body);
+    }
+}
+
+/// Returns the length of the header block and the length of the body it announces,
+/// or `None` if the rest of the header block hasn't been received yet. A header
+/// block without a valid `Content-Length` is an error with its length, so that it
+/// can be skipped.
+fn parse_header(input: &[u8]) -> Option<Result<(usize, usize), usize>> {
+    let end = match input.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None if input.len() > MAX_HEADER_LEN => return Some(Err(input.len())),
+        None => return None,
+    };
+
+    let header = match core::str::from_utf8(&input[..end]) {
+        Ok(header) => header,
+        Err(_) => return Some(Err(end + 4)),
+    };
+
+    let mut body_len = None;
+    for line in header.split("\r\n") {
+        let mut parts = line.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name, value),
+            _ => return Some(Err(end + 4)),
+        };
+
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            body_len = value.trim().parse().ok();
+        }
+    }
+
+    return Some(body_len.map(|len| (end + 4, len)).ok_or(end + 4));
+}
+
+fn uri_to_path(uri: &str) -> &str {
+    return uri.strip_prefix("file://").unwrap_or(uri);
+}
+
+fn pos(line: usize, character: usize) -> Value {
+    return json!({ "line": line, "character": character });
+}
+
+// LSP columns count UTF-16 code units, not bytes
+fn offset_to_pos(files: &FileDb, file: u32, offset: u32) -> Value {
+    let line = files.line_index(file, offset as usize).unwrap_or(0);
+    let start = files.line_range(file, line).map(|r| r.start).unwrap_or(0);
+    let source = files.source(file).unwrap_or("");
+    let text = source.get(start..offset as usize).unwrap_or("");
+    return pos(line, utf16_len(text));
+}
+
+fn utf16_len(text: &str) -> usize {
+    return text.chars().map(|c| c.len_utf16()).sum();
+}
+
+/// The byte offset in `line` of the UTF-16 column `character`, or the end of the
+/// line if it's past it. A column in the middle of a character is that character.
+fn utf16_to_byte(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (idx, c) in line.char_indices() {
+        units += c.len_utf16();
+        if units > character {
+            return idx;
+        }
+    }
+
+    return line.len();
+}
+
+fn loc_to_range(files: &FileDb, loc: CodeLoc) -> Value {
+    let start = offset_to_pos(files, loc.file, loc.start);
+    let end = offset_to_pos(files, loc.file, loc.end);
+    return json!({ "start": start, "end": end });
+}
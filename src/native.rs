@@ -0,0 +1,1907 @@
+//! An experimental backend that compiles assembled programs to x86-64 machine code,
+//! one template per instruction. The code keeps the interpreter's model of memory:
+//! values go on a byte-addressed expression stack, locals are variables on a stack
+//! of their own, and pointers are still `VarPointer`s, which get turned into
+//! addresses on every load and store. Pointers, offsets and stack limits are all
+//! checked, so a program can't touch memory outside of its own buffers or write to
+//! the string literals it shares with the binary, and every instruction counts towards `Limits::max_ops`, like in the interpreter. Floats use
+//! SSE. System calls, the heap, and errors the program raises itself are handled
+//! by calling back into `host_call`, the same way `wasm_emit` uses host imports.
+
+use crate::runtime::*;
+use crate::util::*;
+use crate::wasm_emit::{conversion, float_op, Num};
+use core::convert::TryFrom;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Interpreter,
+    Native,
+    Wasm,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        return Self::Interpreter;
+    }
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        return match self {
+            Self::Interpreter => "interpreter",
+            Self::Native => "native",
+            Self::Wasm => "WebAssembly",
+        };
+    }
+}
+
+/// Parses the value of a `--backend=` flag
+pub fn parse_backend(name: &str) -> Result<Backend, String> {
+    return match name {
+        "interpreter" => Ok(Backend::Interpreter),
+        "native" => Ok(Backend::Native),
+        "wasm" => Ok(Backend::Wasm),
+        _ => Err(format!(
+            "unknown backend `{}`, expected interpreter, native or wasm",
+            name
+        )),
+    };
+}
+
+// Words of the context that the code runs with; see `NativeProgram::memory`
+const SAVED_RSP: usize = 0;
+const RSP_LIMIT: usize = 1;
+const CALL_BYTES: usize = 2;
+const EXIT_CODE: usize = 3;
+const EXPRS: usize = 4;
+const EXPRS_LIMIT: usize = 5;
+const SLOTS: usize = 6;
+const SLOTS_LEN: usize = 7;
+const VARS: usize = 8;
+const VARS_END: usize = 9;
+const HEAP: usize = 10;
+const HEAP_TOP: usize = 11;
+const HEAP_END: usize = 12;
+const HEAP_VARS: usize = 13; // start and end of each heap variable, or zeros once it's freed
+const HEAP_COUNT: usize = 14;
+const HOST: usize = 15; // `host_call`
+const HOST_DATA: usize = 16; // the `NativeHost` of the run
+const SAVED_EXPRS: usize = 17; // registers `host_call` needs, saved before calling it
+const SAVED_VAR_COUNT: usize = 18;
+const SAVED_VARS_TOP: usize = 19;
+const VAR_COUNT: usize = 20; // number of binary variables
+const OPS_LEFT: usize = 21; // instructions the program can still run; see `Limits::max_ops`
+const TABLES: usize = 22; // the address of each binary variable, its code, its end, then if it's read-only
+
+// How much of the expression stack every instruction has to push to
+pub const EXPRS_MARGIN: usize = 4096;
+pub const EXPRS_SIZE: usize = EXPRS_MARGIN * 16;
+pub const MAX_STACK_VARS: usize = 4001;
+pub const MAX_HEAP_VARS: usize = 10_000;
+
+// Returned from the entry point; `wasm_emit` reports faults with the same codes
+pub const EXIT: u32 = 0;
+pub const STACK_OVERFLOW: u32 = 1;
+pub const DIVIDE_BY_ZERO: u32 = 2;
+pub const INVALID_POINTER: u32 = 3;
+pub const INVALID_CALL: u32 = 4;
+pub const UNSUPPORTED_ECALL: u32 = 5;
+pub const OP_LIMIT: u32 = 6; // only native code counts instructions
+pub const READ_ONLY_WRITE: u32 = 7;
+const STATUS_COUNT: usize = 8;
+
+// Only `wasm_emit` reports these; `host_call` reports heap errors itself
+pub const HEAP_TOO_LARGE: u32 = 8;
+pub const INVALID_FREE: u32 = 9;
+pub const DOUBLE_FREE: u32 = 10;
+
+// Only `host_call` returns these
+pub const HOST_ERROR: u32 = 11; // the error is in the `NativeHost`
+const RESUME: u32 = !0;
+
+#[derive(Debug, Clone)]
+pub struct NativeProgram {
+    pub binary: BinaryData,
+    pub code: Vec<u8>, // entry point is at offset 0, and is `extern "C" fn(*mut u64) -> u32`
+    pub funcs: Vec<u32>, // code offset of each binary variable that's a function, or !0
+}
+
+/// Buffers for one run of a native program. They're allocated by the host, so the
+/// generated code never has to.
+pub struct NativeMemory {
+    pub data: Vec<u8>,
+    pub exprs: Vec<u8>,
+    pub slots: Vec<u64>,
+    pub vars: Vec<u8>,
+    pub heap: Vec<u8>,
+    pub heap_vars: Vec<u64>,
+    pub context: Vec<u64>, // what to pass to the entry point
+}
+
+/// What a run wrote to the terminal, how many instructions it ran, and the error
+/// that stopped it, if it was one that the host raised
+#[derive(Debug, Default)]
+pub struct NativeHost {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub ops: u64, // counted like `Limits::max_ops` counts them
+    error: Option<IError>,
+}
+
+impl NativeProgram {
+    /// Memory for running the program after its code has been copied to `code`
+    pub fn memory(&self, code: usize, limits: &Limits) -> NativeMemory {
+        let mut memory = NativeMemory {
+            data: self.binary.data.clone(),
+            exprs: vec![0; EXPRS_SIZE],
+            slots: vec![0; MAX_STACK_VARS],
+            vars: vec![0; limits.max_stack_bytes],
+            heap: vec![0; limits.max_heap_bytes],
+            heap_vars: vec![0; MAX_HEAP_VARS * 2],
+            context: vec![0; TABLES],
+        };
+
+        let (exprs, vars) = (memory.exprs.as_ptr() as u64, memory.vars.as_ptr() as u64);
+        let heap = memory.heap.as_ptr() as u64;
+        let ctx = &mut memory.context;
+        ctx[CALL_BYTES] = (limits.max_call_depth as u64 + 2) * 16;
+        ctx[EXPRS] = exprs;
+        ctx[EXPRS_LIMIT] = exprs + (memory.exprs.len() - EXPRS_MARGIN) as u64;
+        ctx[SLOTS] = memory.slots.as_ptr() as u64;
+        ctx[SLOTS_LEN] = memory.slots.len() as u64;
+        ctx[VARS] = vars;
+        ctx[VARS_END] = vars + memory.vars.len() as u64;
+        ctx[HEAP] = heap;
+        ctx[HEAP_TOP] = heap;
+        ctx[HEAP_END] = heap + memory.heap.len() as u64;
+        ctx[HEAP_VARS] = memory.heap_vars.as_ptr() as u64;
+        ctx[HOST] = host_call as extern "C" fn(*mut u64, u32) -> u32 as usize as u64;
+        ctx[VAR_COUNT] = self.binary.vars.len() as u64;
+        ctx[OPS_LEFT] = limits.max_ops;
+
+        let data = memory.data.as_ptr() as u64;
+        for var in &self.binary.vars {
+            ctx.push(data + var.idx as u64);
+        }
+
+        for &func in &self.funcs {
+            ctx.push(if func == !0 {
+                0
+            } else {
+                (code + func as usize) as u64
+            });
+        }
+
+        let vars = &self.binary.vars;
+        for (idx, var) in vars.iter().enumerate() {
+            let end = vars.get(idx + 1).map(|v| v.idx);
+            ctx.push(data + end.unwrap_or(memory.data.len()) as u64);
+        }
+
+        for var in vars {
+            ctx.push(var.meta as u64);
+        }
+
+        return memory;
+    }
+
+    /// The result of a run with `limits` that returned `status`, the same as
+    /// `Kernel::run` would give
+    pub fn exit(
+        &self,
+        status: u32,
+        limits: &Limits,
+        memory: &NativeMemory,
+        host: &mut NativeHost,
+    ) -> Result<i32, IError> {
+        // The count wraps around when it runs out
+        host.ops = match status {
+            OP_LIMIT => limits.max_ops,
+            _ => limits.max_ops - memory.context[OPS_LEFT],
+        };
+
+        if status == EXIT {
+            return Ok(memory.context[EXIT_CODE] as u32 as i32);
+        }
+
+        if status == OP_LIMIT {
+            return Err(op_limit(limits.max_ops));
+        }
+
+        if let (HOST_ERROR, Some(error)) = (status, host.error.take()) {
+            return Err(error);
+        }
+
+        return Err(fault(status));
+    }
+
+    /// Runs the program, writing what it prints to a new `NativeHost`
+    pub fn run(&self, limits: &Limits) -> Result<i32, IError> {
+        return self.run_with(limits, &mut NativeHost::default());
+    }
+
+    /// Copies the program's code into executable memory and runs it. The pages are
+    /// only ever writable or executable, never both.
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    pub fn run_with(&self, limits: &Limits, host: &mut NativeHost) -> Result<i32, IError> {
+        extern "C" {
+            fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, off: i64)
+                -> *mut u8;
+            fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+            fn munmap(addr: *mut u8, len: usize) -> i32;
+        }
+
+        const PROT_READ_WRITE: i32 = 0x3;
+        const PROT_READ_EXEC: i32 = 0x5;
+        const MAP_PRIVATE_ANONYMOUS: i32 = 0x22;
+
+        let len = self.code.len();
+        unsafe {
+            let code = mmap(
+                core::ptr::null_mut(),
+                len,
+                PROT_READ_WRITE,
+                MAP_PRIVATE_ANONYMOUS,
+                -1,
+                0,
+            );
+            if code as isize == -1 {
+                return Err(ierror!(
+                    "NotRunnable",
+                    "couldn't get executable memory for native code"
+                ));
+            }
+
+            core::ptr::copy_nonoverlapping(self.code.as_ptr(), code, len);
+            if mprotect(code, len, PROT_READ_EXEC) != 0 {
+                munmap(code, len);
+                return Err(ierror!(
+                    "NotRunnable",
+                    "couldn't make the native code executable"
+                ));
+            }
+
+            let mut memory = self.memory(code as usize, limits);
+            memory.context[HOST_DATA] = host as *mut NativeHost as u64;
+            let entry: extern "C" fn(*mut u64) -> u32 = core::mem::transmute(code);
+            let status = entry(memory.context.as_mut_ptr());
+            munmap(code, len);
+
+            return self.exit(status, limits, &memory, host);
+        }
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+    pub fn run_with(&self, limits: &Limits, host: &mut NativeHost) -> Result<i32, IError> {
+        return Err(ierror!(
+            "NotRunnable",
+            "native code can only run on x86-64 Linux"
+        ));
+    }
+}
+
+/// The error for a status other than `EXIT`
+pub fn fault(status: u32) -> IError {
+    return match status {
+        STACK_OVERFLOW => ierror!("StackOverflow", "compiled code ran out of stack space"),
+        DIVIDE_BY_ZERO => divide_by_zero(),
+        INVALID_POINTER => ierror!(
+            "InvalidPointer",
+            "compiled code used a pointer that doesn't point to valid memory"
+        ),
+        INVALID_CALL => ierror!(
+            "InvalidCall",
+            "compiled code called something that isn't a function"
+        ),
+        UNSUPPORTED_ECALL => ierror!(
+            "UnsupportedEcall",
+            "native code made a system call the native backend doesn't support"
+        ),
+        READ_ONLY_WRITE => ierror!(
+            "ReadOnlyWrite",
+            "compiled code tried to modify a string literal"
+        ),
+        HEAP_TOO_LARGE => ierror!("HeapTooLarge", "compiled code ran out of heap space"),
+        INVALID_FREE => ierror!(
+            "InvalidFreeTarget",
+            "compiled code tried to free something that isn't from the heap"
+        ),
+        DOUBLE_FREE => ierror!(
+            "DoubleFree",
+            "compiled code tried to free something that has already been freed"
+        ),
+        _ => ierror!(
+            "InvalidStatus",
+            "compiled code returned unknown status {}",
+            status
+        ),
+    };
+}
+
+/// What native code calls for everything it doesn't do itself: system calls, the
+/// heap, errors, and float remainders. The code saves the registers this needs to
+/// the context first. Returns `RESUME` to keep going, or the status to stop with.
+extern "C" fn host_call(context: *mut u64, op: u32) -> u32 {
+    let mut call = HostCall { context };
+    let result = match Opcode::try_from(op as u8) {
+        Ok(op) => call.op(op),
+        Err(error) => Err(error),
+    };
+
+    return match result {
+        Ok(status) => status,
+        Err(error) => {
+            call.host().error = Some(error);
+            HOST_ERROR
+        }
+    };
+}
+
+/// The program's memory, as seen from `host_call`. Everything goes through the
+/// context's pointers, the same way the generated code finds it.
+struct HostCall {
+    context: *mut u64,
+}
+
+impl HostCall {
+    fn word(&self, word: usize) -> u64 {
+        return unsafe { *self.context.add(word) };
+    }
+
+    fn set_word(&mut self, word: usize, value: u64) {
+        unsafe { *self.context.add(word) = value };
+    }
+
+    fn host(&mut self) -> &mut NativeHost {
+        // Safety: `run_with` points this at the host for the whole run
+        return unsafe { &mut *(self.word(HOST_DATA) as *mut NativeHost) };
+    }
+
+    fn pop<T: MemValue>(&mut self) -> Result<T, IError> {
+        let (top, bottom) = (self.word(SAVED_EXPRS), self.word(EXPRS));
+        if top - bottom < T::SIZE as u64 {
+            return Err(expr_stack_too_short((top - bottom) as usize, T::SIZE));
+        }
+
+        let top = top - T::SIZE as u64;
+        self.set_word(SAVED_EXPRS, top);
+        let bytes = unsafe { core::slice::from_raw_parts(top as *const u8, T::SIZE) };
+        return Ok(T::from_bytes(bytes));
+    }
+
+    /// The code checked for `EXPRS_MARGIN` bytes of space before calling the host
+    fn push<T: MemValue>(&mut self, value: T) {
+        let top = self.word(SAVED_EXPRS);
+        let bytes = unsafe { core::slice::from_raw_parts_mut(top as *mut u8, T::SIZE) };
+        value.to_bytes(bytes);
+        self.set_word(SAVED_EXPRS, top + T::SIZE as u64);
+    }
+
+    /// The start and end address of the variable `ptr` points into, like `decode_ptr`
+    fn bounds(&self, ptr: VarPointer) -> Option<(u64, u64)> {
+        let idx = ptr.var_idx();
+        if idx == 0 {
+            return None;
+        }
+
+        if ptr.is_binary() {
+            let count = self.word(VAR_COUNT) as usize;
+            if idx > count {
+                return None;
+            }
+
+            let ends = TABLES + 2 * count;
+            return Some((self.word(TABLES + idx - 1), self.word(ends + idx - 1)));
+        }
+
+        if ptr.is_stack() {
+            let count = self.word(SAVED_VAR_COUNT) as usize;
+            if idx > count {
+                return None;
+            }
+
+            let slots = self.word(SLOTS) as *const u64;
+            let start = unsafe { *slots.add(idx - 1) };
+            let end = match idx < count {
+                true => unsafe { *slots.add(idx) },
+                false => self.word(SAVED_VARS_TOP),
+            };
+            return Some((start, end));
+        }
+
+        if ptr.is_heap() && idx as u64 <= self.word(HEAP_COUNT) {
+            let vars = self.word(HEAP_VARS) as *const u64;
+            let (start, end) = unsafe { (*vars.add(idx * 2 - 2), *vars.add(idx * 2 - 1)) };
+            return Some((start, end)).filter(|_| start != 0);
+        }
+
+        return None;
+    }
+
+    fn read<'b>(&self, ptr: VarPointer, len: u32) -> Result<&'b [u8], IError> {
+        let (start, end) = self.bounds(ptr).ok_or_else(|| invalid_ptr(ptr))?;
+        let addr = start + ptr.offset() as u64;
+        if addr + len as u64 > end {
+            return Err(invalid_ptr(ptr));
+        }
+
+        return Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) });
+    }
+
+    fn cstring<'b>(&self, ptr: VarPointer) -> Result<&'b [u8], IError> {
+        let (start, end) = self.bounds(ptr).ok_or_else(|| invalid_ptr(ptr))?;
+        let addr = start + ptr.offset() as u64;
+        if addr > end {
+            return Err(invalid_ptr(ptr));
+        }
+
+        let len = (end - addr) as usize;
+        let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+        return match bytes.iter().position(|&byte| byte == 0) {
+            Some(len) => Ok(&bytes[..len]),
+            None => ierr!(
+                "MissingNullTerminator",
+                "valid C strings should end in '\\0' character"
+            ),
+        };
+    }
+
+    fn op(&mut self, op: Opcode) -> Result<u32, IError> {
+        match op {
+            Opcode::Ecall => return self.ecall(),
+
+            Opcode::HeapAlloc => {
+                let _skip: u32 = self.pop()?;
+                let size: u64 = self.pop()?;
+                let (count, top) = (self.word(HEAP_COUNT), self.word(HEAP_TOP));
+                if size > self.word(HEAP_END) - top {
+                    return ierr!(
+                        "HeapTooLarge",
+                        "heap size would be over {} bytes after this allocation",
+                        self.word(HEAP_END) - self.word(HEAP)
+                    );
+                }
+
+                if count as usize >= MAX_HEAP_VARS {
+                    return ierr!(
+                        "TooManyAllocations",
+                        "too many allocations; the limit is {}",
+                        MAX_HEAP_VARS
+                    );
+                }
+
+                // New memory starts out as garbage, like in the interpreter
+                unsafe { core::ptr::write_bytes(top as *mut u8, !0, size as usize) };
+                let vars = self.word(HEAP_VARS) as *mut u64;
+                unsafe { *vars.add(count as usize * 2) = top };
+                unsafe { *vars.add(count as usize * 2 + 1) = top + size };
+                self.set_word(HEAP_TOP, top + size);
+                self.set_word(HEAP_COUNT, count + 1);
+                self.push(VarPointer::new_heap(count as u32 + 1, 0));
+            }
+            Opcode::HeapDealloc | Opcode::HeapMove => {
+                let _skip: u32 = self.pop()?;
+                let ptr: VarPointer = self.pop()?;
+                if ptr.var_idx() == 0 || ptr.var_idx() as u64 > self.word(HEAP_COUNT) {
+                    return Err(invalid_ptr(ptr));
+                }
+
+                if !ptr.is_heap() {
+                    return ierr!(
+                        "InvalidFreeTarget",
+                        "freed a pointer that wasn't from the heap"
+                    );
+                }
+
+                // Memory is never reused, so a freed variable just has no bounds
+                let vars = self.word(HEAP_VARS) as *mut u64;
+                let idx = ptr.var_idx() * 2 - 2;
+                if unsafe { *vars.add(idx) } == 0 {
+                    return ierr!(
+                        "DoubleFree",
+                        "tried to free something that has already been freed"
+                    );
+                }
+
+                unsafe { *vars.add(idx) = 0 };
+                unsafe { *vars.add(idx + 1) = 0 };
+                self.push(0u64);
+            }
+            Opcode::AllocBegin => {
+                let ptr: VarPointer = self.pop()?;
+                match self.read(ptr, 1) {
+                    Ok(_) => self.push(ptr.with_offset(0)),
+                    Err(_) => self.push(0u64),
+                }
+            }
+            Opcode::AllocEnd => {
+                let ptr: VarPointer = self.pop()?;
+                match self.bounds(ptr) {
+                    Some((start, end)) => self.push(ptr.with_offset((end - start) as u32)),
+                    None => self.push(0u64),
+                }
+            }
+
+            Opcode::PushDyn => {
+                let ptr: VarPointer = self.pop()?;
+                let size: u32 = self.pop()?;
+                if size as usize > EXPRS_MARGIN {
+                    return Ok(STACK_OVERFLOW);
+                }
+
+                let (bytes, top) = (self.read(ptr, size)?, self.word(SAVED_EXPRS));
+                let to = top as *mut u8;
+                unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), to, bytes.len()) };
+                self.set_word(SAVED_EXPRS, top + size as u64);
+            }
+            Opcode::Throw => {
+                let _skip: u32 = self.pop()?;
+                let message_ptr: VarPointer = self.pop()?;
+                let name_ptr: VarPointer = self.pop()?;
+
+                let mut message = String::new();
+                string_append_utf8_lossy(&mut message, self.cstring(message_ptr)?);
+                let mut name = String::new();
+                string_append_utf8_lossy(&mut name, self.cstring(name_ptr)?);
+                return Err(IError::new(name, message));
+            }
+            Opcode::AssertStr => {
+                let ptr: VarPointer = self.pop()?;
+                self.cstring(ptr)?;
+            }
+
+            Opcode::ModF32 => {
+                let (b, a): (f32, f32) = (self.pop()?, self.pop()?);
+                self.push(a % b);
+            }
+            Opcode::ModF64 => {
+                let (b, a): (f64, f64) = (self.pop()?, self.pop()?);
+                self.push(a % b);
+            }
+
+            op => {
+                return ierr!(
+                    "InvalidHostCall",
+                    "native code called the host for {:?} (this is an error in TCI)",
+                    op
+                )
+            }
+        }
+
+        return Ok(RESUME);
+    }
+
+    /// System calls that don't need the kernel's files or processes; the terminal
+    /// is the host's buffers, and standard input is always empty
+    fn ecall(&mut self) -> Result<u32, IError> {
+        let ecall: u32 = self.pop()?;
+        if ecall >= HOST_ECALL_BASE {
+            return Ok(UNSUPPORTED_ECALL);
+        }
+
+        match Ecall::from_u32(ecall) {
+            Some(Ecall::Exit) => {
+                let code: i32 = self.pop()?;
+                self.set_word(EXIT_CODE, code as u32 as u64);
+                return Ok(EXIT);
+            }
+            Some(Ecall::WriteFd) => {
+                let len: u32 = self.pop()?;
+                let buf: VarPointer = self.pop()?;
+                let _begin: u32 = self.pop()?;
+                let fd: u32 = self.pop()?;
+                let result = self.write(fd, buf, len)?;
+                self.push(result);
+            }
+            Some(Ecall::AppendFd) => {
+                let len: u32 = self.pop()?;
+                let buf: VarPointer = self.pop()?;
+                let fd: u32 = self.pop()?;
+                let result = self.write(fd, buf, len)?;
+                self.push(result);
+            }
+            Some(Ecall::ReadFd) => {
+                let len: u32 = self.pop()?;
+                let buf: VarPointer = self.pop()?;
+                let _begin: u32 = self.pop()?;
+                let fd: u32 = self.pop()?;
+                let result = match fd {
+                    0 => self.read(buf, len).map(|_| 0)?,
+                    1 => EcallError::ReadTermOut.to_u64(),
+                    2 => EcallError::ReadTermErr.to_u64(),
+                    3 => EcallError::ReadTermLog.to_u64(),
+                    _ => EcallError::DoesntExist.to_u64(),
+                };
+                self.push(result);
+            }
+            Some(Ecall::CloseFd) => {
+                let fd: u32 = self.pop()?;
+                let result = match fd {
+                    0..=3 => 0,
+                    _ => EcallError::DoesntExist.to_u64(),
+                };
+                self.push(result);
+            }
+            Some(Ecall::Time) => self.push(VIRTUAL_EPOCH),
+            Some(Ecall::CpuTime) => self.push(0u64),
+            _ => return Ok(UNSUPPORTED_ECALL),
+        }
+
+        return Ok(RESUME);
+    }
+
+    fn write(&mut self, fd: u32, buf: VarPointer, len: u32) -> Result<u64, IError> {
+        let bytes = self.read(buf, len)?;
+        let host = self.host();
+        let out = match fd {
+            0 => return Ok(EcallError::WriteTermIn.to_u64()),
+            1 => &mut host.stdout,
+            2 | 3 => &mut host.stderr,
+            _ => return Ok(EcallError::DoesntExist.to_u64()),
+        };
+
+        out.extend_from_slice(bytes);
+        return Ok(0);
+    }
+}
+
+/// A decoded instruction
+#[derive(Clone, Copy)]
+pub struct Op<'a> {
+    pub offset: u32,
+    pub op: Opcode,
+    pub operand: &'a [u8],
+    pub loc: CodeLoc,
+}
+
+impl<'a> Op<'a> {
+    pub fn operand<T: MemValue>(&self) -> T {
+        return read(self.operand);
+    }
+}
+
+// Operands in the binary aren't aligned
+pub fn read<T: MemValue>(bytes: &[u8]) -> T {
+    return T::from_bytes(&bytes[..T::SIZE]);
+}
+
+/// Decodes the code reachable from the code that calls `main`, by binary variable.
+/// Functions are found through the pointers to them that the code makes, so every
+/// function the program could call has to be supported, even if it never actually
+/// gets called.
+pub fn decode_program(
+    binary: &BinaryData,
+    backend: Backend,
+) -> Result<HashMap<u32, Vec<Op<'_>>>, Error> {
+    let var_count = binary.vars.len() as u32;
+    let mut decoded = HashMap::new();
+    let mut seen = HashMap::new();
+    let mut queue = vec![1];
+
+    while let Some(var) = queue.pop() {
+        if seen.insert(var, ()).is_some() {
+            continue;
+        }
+
+        let ops = match decode(binary, var, backend)? {
+            Some(ops) => ops,
+            None => continue,
+        };
+
+        for op in &ops {
+            if op.op != Opcode::Make64 {
+                continue;
+            }
+
+            let ptr: VarPointer = op.operand();
+            let target = ptr.var_idx() as u32;
+            if ptr.is_binary() && ptr.offset() == 0 && target != 0 && target <= var_count {
+                queue.push(target);
+            }
+        }
+
+        decoded.insert(var, ops);
+    }
+
+    return Ok(decoded);
+}
+
+/// Compiles the program to machine code
+pub fn lower(binary: &BinaryData) -> Result<NativeProgram, Error> {
+    let var_count = binary.vars.len() as u32;
+    let decoded = decode_program(binary, Backend::Native)?;
+
+    let mut asm = X64::new();
+    let mut funcs = Vec::new();
+    for var in 1..=var_count {
+        match decoded.get(&var) {
+            Some(_) => funcs.push(asm.label()),
+            _ => funcs.push(!0),
+        }
+    }
+
+    let status: Vec<usize> = (0..STATUS_COUNT).map(|_| asm.label()).collect();
+    let (exit, decode_ptr, decode_store) = (asm.label(), asm.label(), asm.label());
+    let mut lowerer = Lowerer {
+        asm,
+        status,
+        exit,
+        decode_ptr,
+        decode_store,
+        var_count,
+    };
+
+    lowerer.entry(funcs[0]);
+    lowerer.helpers();
+    for var in 1..=var_count {
+        if let Some(ops) = decoded.get(&var) {
+            lowerer.asm.bind(funcs[var as usize - 1]);
+            lowerer.func(ops);
+        }
+    }
+
+    let asm = lowerer.asm.finish();
+    let offset = |label: usize| match label {
+        label if label == !0 => !0,
+        label => asm.labels[label].unwrap() as u32,
+    };
+    let funcs = funcs.into_iter().map(offset).collect();
+
+    return Ok(NativeProgram {
+        binary: binary.clone(),
+        code: asm.code,
+        funcs,
+    });
+}
+
+pub fn unsupported(message: String, loc: CodeLoc) -> Error {
+    if loc == NO_FILE {
+        return error!(message);
+    }
+
+    return error!(message, loc, "needed here");
+}
+
+/// Decodes a binary variable, or returns `None` if it isn't code. The first
+/// instruction that isn't supported is only an error if the rest of it is code.
+fn decode(binary: &BinaryData, var: u32, backend: Backend) -> Result<Option<Vec<Op<'_>>>, Error> {
+    let begin = binary.vars[var as usize - 1].idx;
+    let end = binary.vars.get(var as usize).map(|v| v.idx);
+    let data = &binary.data[begin..end.unwrap_or(binary.data.len())];
+
+    let (mut ops, mut pos, mut loc): (Vec<Op>, usize, CodeLoc) = (Vec::new(), 0, NO_FILE);
+    let mut error = None;
+    while pos < data.len() {
+        let op = match Opcode::try_from(data[pos]) {
+            Ok(op) => op,
+            Err(_) => return Ok(None),
+        };
+
+        let operand = match data.get((pos + 1)..(pos + 1 + op.operand_size())) {
+            Some(operand) => operand,
+            None => return Ok(None),
+        };
+
+        // Everything but the code that calls `main` starts with a function header
+        if (op == Opcode::Func) != (pos == 0 && var != 1) {
+            return Ok(None);
+        }
+
+        let message = match op {
+            Opcode::Func => {
+                loc = read(&operand[LinkName::SIZE..]);
+                None
+            }
+            Opcode::Loc => {
+                loc = read(operand);
+                None
+            }
+            op if !is_supported(op, backend) => Some(format!(
+                "the {} backend doesn't support {:?} yet",
+                backend.name(),
+                op
+            )),
+            op if backend == Backend::Native && pushed_len(op, operand) > EXPRS_MARGIN => {
+                Some(format!(
+                    "the native backend doesn't support values larger than {} bytes yet",
+                    EXPRS_MARGIN
+                ))
+            }
+            _ => None,
+        };
+
+        if let (Some(message), None) = (message, &error) {
+            error = Some(unsupported(message, loc));
+        }
+
+        let offset = pos as u32;
+        ops.push(Op {
+            offset,
+            op,
+            operand,
+            loc,
+        });
+        pos += 1 + operand.len();
+    }
+
+    for target in ops.iter().filter_map(jump_target) {
+        let in_var = target.var_idx() as u32 == var;
+        if !in_var || !ops.iter().any(|op| op.offset == target.offset()) {
+            return Ok(None);
+        }
+    }
+
+    return match error {
+        Some(error) => Err(error),
+        None => Ok(Some(ops)),
+    };
+}
+
+/// The most an instruction pushes to the expression stack at once, if it's more
+/// than a register's worth
+fn pushed_len(op: Opcode, operand: &[u8]) -> usize {
+    return match op {
+        Opcode::Get | Opcode::PushUndef | Opcode::Dup => read::<u32>(operand) as usize,
+        Opcode::Swap => read::<u32>(operand) as usize + read::<u32>(&operand[4..]) as usize,
+        _ => 0,
+    };
+}
+
+pub fn jump_target(op: &Op) -> Option<VarPointer> {
+    return match int_op(op.op) {
+        Some((IntOp::JumpIfZero, _, _)) | Some((IntOp::JumpIfNotZero, _, _)) => Some(op.operand()),
+        _ if op.op == Opcode::Jump => Some(op.operand()),
+        _ => None,
+    };
+}
+
+fn is_supported(op: Opcode, backend: Backend) -> bool {
+    use Opcode::*;
+
+    return match op {
+        Func | Loc | StackAlloc | StackDealloc => true,
+        Make8 | Make16 | Make32 | Make64 | MakeFp | MakeSp => true,
+        PushUndef | Pop | Swap | Dup => true,
+        SExtend8To16 | SExtend8To32 | SExtend8To64 => true,
+        SExtend16To32 | SExtend16To64 | SExtend32To64 => true,
+        ZExtend8To16 | ZExtend8To32 | ZExtend8To64 => true,
+        ZExtend16To32 | ZExtend16To64 | ZExtend32To64 => true,
+        Get | Set => true,
+        Jump | Ret | Call | Ecall => true,
+
+        // These call the host; WebAssembly has no float remainder to call it for
+        PushDyn | Throw | AssertStr => true,
+        ModF32 | ModF64 => backend == Backend::Native,
+        AllocBegin | AllocEnd | HeapAlloc | HeapDealloc | HeapMove => true,
+
+        _ => int_op(op).is_some() || conversion(op).is_some() || float_op(op).is_some(),
+    };
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IntOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    LShift,
+    RShift,
+    Lt,
+    Leq,
+    Eq,
+    Neq,
+    BoolNorm,
+    BoolNot,
+    BitNot,
+    JumpIfZero,
+    JumpIfNotZero,
+}
+
+/// The operation, width, and signedness of an integer instruction
+pub fn int_op(op: Opcode) -> Option<(IntOp, u32, bool)> {
+    use Opcode::*;
+
+    let (int_op, width, signed) = match op {
+        Add8 => (IntOp::Add, 1, false),
+        Add16 => (IntOp::Add, 2, false),
+        Add32 => (IntOp::Add, 4, false),
+        Add64 => (IntOp::Add, 8, false),
+
+        SubI8 | SubU8 => (IntOp::Sub, 1, false),
+        SubI16 | SubU16 => (IntOp::Sub, 2, false),
+        SubI32 | SubU32 => (IntOp::Sub, 4, false),
+        SubI64 | SubU64 => (IntOp::Sub, 8, false),
+
+        MulI8 | MulU8 => (IntOp::Mul, 1, false),
+        MulI16 | MulU16 => (IntOp::Mul, 2, false),
+        MulI32 | MulU32 => (IntOp::Mul, 4, false),
+        MulI64 | MulU64 => (IntOp::Mul, 8, false),
+
+        DivI8 => (IntOp::Div, 1, true),
+        DivU8 => (IntOp::Div, 1, false),
+        DivI16 => (IntOp::Div, 2, true),
+        DivU16 => (IntOp::Div, 2, false),
+        DivI32 => (IntOp::Div, 4, true),
+        DivU32 => (IntOp::Div, 4, false),
+        DivI64 => (IntOp::Div, 8, true),
+        DivU64 => (IntOp::Div, 8, false),
+
+        ModI8 => (IntOp::Mod, 1, true),
+        ModU8 => (IntOp::Mod, 1, false),
+        ModI16 => (IntOp::Mod, 2, true),
+        ModU16 => (IntOp::Mod, 2, false),
+        ModI32 => (IntOp::Mod, 4, true),
+        ModU32 => (IntOp::Mod, 4, false),
+        ModI64 => (IntOp::Mod, 8, true),
+        ModU64 => (IntOp::Mod, 8, false),
+
+        CompLtI8 => (IntOp::Lt, 1, true),
+        CompLtU8 => (IntOp::Lt, 1, false),
+        CompLtI16 => (IntOp::Lt, 2, true),
+        CompLtU16 => (IntOp::Lt, 2, false),
+        CompLtI32 => (IntOp::Lt, 4, true),
+        CompLtU32 => (IntOp::Lt, 4, false),
+        CompLtI64 => (IntOp::Lt, 8, true),
+        CompLtU64 => (IntOp::Lt, 8, false),
+
+        CompLeqI8 => (IntOp::Leq, 1, true),
+        CompLeqU8 => (IntOp::Leq, 1, false),
+        CompLeqI16 => (IntOp::Leq, 2, true),
+        CompLeqU16 => (IntOp::Leq, 2, false),
+        CompLeqI32 => (IntOp::Leq, 4, true),
+        CompLeqU32 => (IntOp::Leq, 4, false),
+        CompLeqI64 => (IntOp::Leq, 8, true),
+        CompLeqU64 => (IntOp::Leq, 8, false),
+
+        CompEq8 => (IntOp::Eq, 1, false),
+        CompEq16 => (IntOp::Eq, 2, false),
+        CompEq32 => (IntOp::Eq, 4, false),
+        CompEq64 => (IntOp::Eq, 8, false),
+
+        CompNeq8 => (IntOp::Neq, 1, false),
+        CompNeq16 => (IntOp::Neq, 2, false),
+        CompNeq32 => (IntOp::Neq, 4, false),
+        CompNeq64 => (IntOp::Neq, 8, false),
+
+        RShiftI8 => (IntOp::RShift, 1, true),
+        RShiftU8 => (IntOp::RShift, 1, false),
+        RShiftI16 => (IntOp::RShift, 2, true),
+        RShiftU16 => (IntOp::RShift, 2, false),
+        RShiftI32 => (IntOp::RShift, 4, true),
+        RShiftU32 => (IntOp::RShift, 4, false),
+        RShiftI64 => (IntOp::RShift, 8, true),
+        RShiftU64 => (IntOp::RShift, 8, false),
+
+        LShiftI8 | LShiftU8 => (IntOp::LShift, 1, false),
+        LShiftI16 | LShiftU16 => (IntOp::LShift, 2, false),
+        LShiftI32 | LShiftU32 => (IntOp::LShift, 4, false),
+        LShiftI64 | LShiftU64 => (IntOp::LShift, 8, false),
+
+        BitAnd8 => (IntOp::And, 1, false),
+        BitAnd16 => (IntOp::And, 2, false),
+        BitAnd32 => (IntOp::And, 4, false),
+        BitAnd64 => (IntOp::And, 8, false),
+
+        BitOr8 => (IntOp::Or, 1, false),
+        BitOr16 => (IntOp::Or, 2, false),
+        BitOr32 => (IntOp::Or, 4, false),
+        BitOr64 => (IntOp::Or, 8, false),
+
+        BitXor8 => (IntOp::Xor, 1, false),
+        BitXor16 => (IntOp::Xor, 2, false),
+        BitXor32 => (IntOp::Xor, 4, false),
+        BitXor64 => (IntOp::Xor, 8, false),
+
+        BitNot8 => (IntOp::BitNot, 1, false),
+        BitNot16 => (IntOp::BitNot, 2, false),
+        BitNot32 => (IntOp::BitNot, 4, false),
+        BitNot64 => (IntOp::BitNot, 8, false),
+
+        BoolNorm8 => (IntOp::BoolNorm, 1, false),
+        BoolNorm16 => (IntOp::BoolNorm, 2, false),
+        BoolNorm32 => (IntOp::BoolNorm, 4, false),
+        BoolNorm64 => (IntOp::BoolNorm, 8, false),
+
+        BoolNot8 => (IntOp::BoolNot, 1, false),
+        BoolNot16 => (IntOp::BoolNot, 2, false),
+        BoolNot32 => (IntOp::BoolNot, 4, false),
+        BoolNot64 => (IntOp::BoolNot, 8, false),
+
+        JumpIfZero8 => (IntOp::JumpIfZero, 1, false),
+        JumpIfZero16 => (IntOp::JumpIfZero, 2, false),
+        JumpIfZero32 => (IntOp::JumpIfZero, 4, false),
+        JumpIfZero64 => (IntOp::JumpIfZero, 8, false),
+
+        JumpIfNotZero8 => (IntOp::JumpIfNotZero, 1, false),
+        JumpIfNotZero16 => (IntOp::JumpIfNotZero, 2, false),
+        JumpIfNotZero32 => (IntOp::JumpIfNotZero, 4, false),
+        JumpIfNotZero64 => (IntOp::JumpIfNotZero, 8, false),
+
+        _ => return None,
+    };
+
+    return Some((int_op, width, signed));
+}
+
+/// The source width, destination width, and signedness of an extension
+pub fn extension(op: Opcode) -> Option<(u32, u32, bool)> {
+    use Opcode::*;
+
+    return Some(match op {
+        SExtend8To16 => (1, 2, true),
+        SExtend8To32 => (1, 4, true),
+        SExtend8To64 => (1, 8, true),
+        SExtend16To32 => (2, 4, true),
+        SExtend16To64 => (2, 8, true),
+        SExtend32To64 => (4, 8, true),
+        ZExtend8To16 => (1, 2, false),
+        ZExtend8To32 => (1, 4, false),
+        ZExtend8To64 => (1, 8, false),
+        ZExtend16To32 => (2, 4, false),
+        ZExtend16To64 => (2, 8, false),
+        ZExtend32To64 => (4, 8, false),
+        _ => return None,
+    });
+}
+
+// Registers; the ones from RBX on are callee-saved and hold the program's state
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3; // frame pointer, as a stack variable index
+const RSP: u8 = 4;
+const RBP: u8 = 5; // top of the stack variables' data
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R12: u8 = 12; // address of each stack variable
+const R13: u8 = 13; // top of the expression stack
+const R14: u8 = 14; // number of stack variables
+const R15: u8 = 15; // context
+const XMM0: u8 = 0;
+const XMM1: u8 = 1;
+
+// Condition codes
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_BE: u8 = 0x6;
+const CC_A: u8 = 0x7;
+const CC_S: u8 = 0x8;
+const CC_P: u8 = 0xA;
+const CC_NP: u8 = 0xB;
+const CC_L: u8 = 0xC;
+const CC_LE: u8 = 0xE;
+
+// Opcode extensions for the 0x81 (immediate) and 0xD3/0xC1 (shift) groups
+const ADD: u8 = 0;
+const AND: u8 = 4;
+const SUB: u8 = 5;
+const CMP: u8 = 7;
+const SHL: u8 = 4;
+const SHR: u8 = 5;
+const SAR: u8 = 7;
+
+fn ctx(word: usize) -> i32 {
+    return (word * 8) as i32;
+}
+
+/// Just enough of an x86-64 encoder for the templates
+struct X64 {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, usize)>, // rel32 position, label
+}
+
+impl X64 {
+    fn new() -> Self {
+        return Self {
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+        };
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels.push(None);
+        return self.labels.len() - 1;
+    }
+
+    fn bind(&mut self, label: usize) {
+        self.labels[label] = Some(self.code.len());
+    }
+
+    fn finish(mut self) -> Self {
+        for &(pos, label) in &self.fixups {
+            let target = self.labels[label].unwrap() as i64;
+            let rel = (target - (pos as i64 + 4)) as i32;
+            self.code[pos..(pos + 4)].copy_from_slice(&rel.to_le_bytes());
+        }
+
+        return self;
+    }
+
+    fn rel32(&mut self, label: usize) {
+        self.fixups.push((self.code.len(), label));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+
+    fn rex(&mut self, w: bool, reg: u8, index: u8, base: u8) {
+        let rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | (base >> 3);
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    /// An instruction on two registers
+    fn rr(&mut self, w: bool, opcode: &[u8], reg: u8, rm: u8) {
+        self.rex(w, reg, 0, rm);
+        self.code.extend_from_slice(opcode);
+        self.code.push(0xC0 | (reg & 7) << 3 | (rm & 7));
+    }
+
+    /// An instruction on a register and `[base + index * 8 + disp]`
+    fn rm(&mut self, w: bool, opcode: &[u8], reg: u8, base: u8, index: Option<u8>, disp: i32) {
+        self.rex(w, reg, index.unwrap_or(0), base);
+        self.code.extend_from_slice(opcode);
+        match index {
+            Some(index) => {
+                self.code.push(0x84 | (reg & 7) << 3);
+                self.code.push(0xC0 | (index & 7) << 3 | (base & 7));
+            }
+            None if base & 7 == RSP => {
+                self.code.push(0x84 | (reg & 7) << 3);
+                self.code.push(0x24);
+            }
+            None => self.code.push(0x80 | (reg & 7) << 3 | (base & 7)),
+        }
+
+        self.code.extend_from_slice(&disp.to_le_bytes());
+    }
+
+    fn load(&mut self, width: u32, dst: u8, base: u8, disp: i32) {
+        match width {
+            1 => self.rm(false, &[0x0F, 0xB6], dst, base, None, disp),
+            2 => self.rm(false, &[0x0F, 0xB7], dst, base, None, disp),
+            4 => self.rm(false, &[0x8B], dst, base, None, disp),
+            _ => self.rm(true, &[0x8B], dst, base, None, disp),
+        }
+    }
+
+    fn store(&mut self, width: u32, src: u8, base: u8, disp: i32) {
+        match width {
+            1 => self.rm(false, &[0x88], src, base, None, disp),
+            2 => {
+                self.code.push(0x66);
+                self.rm(false, &[0x89], src, base, None, disp);
+            }
+            4 => self.rm(false, &[0x89], src, base, None, disp),
+            _ => self.rm(true, &[0x89], src, base, None, disp),
+        }
+    }
+
+    fn mov(&mut self, dst: u8, src: u8) {
+        self.rr(true, &[0x89], src, dst);
+    }
+
+    fn mov_imm32(&mut self, dst: u8, imm: u32) {
+        self.rex(false, 0, 0, dst);
+        self.code.push(0xB8 | (dst & 7));
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    fn mov_imm64(&mut self, dst: u8, imm: u64) {
+        self.rex(true, 0, 0, dst);
+        self.code.push(0xB8 | (dst & 7));
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// `add`, `and`, `sub`, or `cmp` with an immediate
+    fn alu_imm(&mut self, w: bool, ext: u8, reg: u8, imm: i32) {
+        self.rr(w, &[0x81], ext, reg);
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// `dst = dst <op> src`, where `opcode` is the `r/m, reg` form
+    fn alu(&mut self, w: bool, opcode: u8, dst: u8, src: u8) {
+        self.rr(w, &[opcode], src, dst);
+    }
+
+    fn shift_imm(&mut self, ext: u8, reg: u8, imm: u8) {
+        self.rr(true, &[0xC1], ext, reg);
+        self.code.push(imm);
+    }
+
+    fn movsx(&mut self, from: u32, to_64: bool, dst: u8, src: u8) {
+        match from {
+            1 => self.rr(to_64, &[0x0F, 0xBE], dst, src),
+            2 => self.rr(to_64, &[0x0F, 0xBF], dst, src),
+            _ => self.rr(true, &[0x63], dst, src),
+        }
+    }
+
+    fn setcc(&mut self, cc: u8) {
+        self.rr(false, &[0x0F, 0x90 | cc], 0, RAX);
+        self.rr(false, &[0x0F, 0xB6], RAX, RAX);
+    }
+
+    fn jcc(&mut self, cc: u8, label: usize) {
+        self.code.extend_from_slice(&[0x0F, 0x80 | cc]);
+        self.rel32(label);
+    }
+
+    fn jmp(&mut self, label: usize) {
+        self.code.push(0xE9);
+        self.rel32(label);
+    }
+
+    fn call(&mut self, label: usize) {
+        self.code.push(0xE8);
+        self.rel32(label);
+    }
+
+    fn push(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.code.push(0x50 | (reg & 7));
+    }
+
+    fn pop(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.code.push(0x58 | (reg & 7));
+    }
+
+    /// `bt` (`ext` 4) or `bts` (`ext` 5)
+    fn bit(&mut self, ext: u8, reg: u8, bit: u8) {
+        self.rr(true, &[0x0F, 0xBA], ext, reg);
+        self.code.push(bit);
+    }
+
+    /// An SSE instruction on two registers; which of them are XMM registers
+    /// depends on the instruction
+    fn sse(&mut self, prefix: Option<u8>, w: bool, opcode: u8, reg: u8, rm: u8) {
+        self.code.extend(prefix);
+        self.rr(w, &[0x0F, opcode], reg, rm);
+    }
+
+    fn movq_to_xmm(&mut self, dst: u8, src: u8) {
+        self.sse(Some(0x66), true, 0x6E, dst, src);
+    }
+
+    fn movq_from_xmm(&mut self, dst: u8, src: u8) {
+        self.sse(Some(0x66), true, 0x7E, src, dst);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+}
+
+/// The prefix that makes an SSE instruction work on a scalar of `width` bytes
+fn scalar(width: u32) -> Option<u8> {
+    return Some(if width == 8 { 0xF2 } else { 0xF3 });
+}
+
+const REP_MOVSB: &[u8] = &[0xF3, 0xA4];
+const REP_STOSB: &[u8] = &[0xF3, 0xAA];
+const RET: &[u8] = &[0xC3];
+
+struct Lowerer {
+    asm: X64,
+    status: Vec<usize>,
+    exit: usize,
+    decode_ptr: usize,
+    decode_store: usize, // `decode_ptr`, but string literals are invalid
+    var_count: u32,
+}
+
+impl Lowerer {
+    fn pop(&mut self, width: u32, reg: u8) {
+        self.asm.alu_imm(true, SUB, R13, width as i32);
+        self.asm.load(width, reg, R13, 0);
+    }
+
+    fn push(&mut self, width: u32, reg: u8) {
+        self.asm.store(width, reg, R13, 0);
+        self.asm.alu_imm(true, ADD, R13, width as i32);
+    }
+
+    /// Copies `len` bytes from `src` to `dst`, which are both addresses in registers
+    fn copy(&mut self, dst: u8, src: u8, len: u32) {
+        self.asm.mov(RSI, src);
+        self.asm.mov(RDI, dst);
+        self.asm.mov_imm32(RCX, len);
+        self.asm.bytes(REP_MOVSB);
+    }
+
+    fn entry(&mut self, init: usize) {
+        let asm = &mut self.asm;
+        for &reg in &[RBX, RBP, R12, R13, R14, R15] {
+            asm.push(reg);
+        }
+
+        asm.mov(R15, RDI);
+        asm.store(8, RSP, R15, ctx(SAVED_RSP));
+        asm.mov(RAX, RSP);
+        asm.rm(true, &[0x2B], RAX, R15, None, ctx(CALL_BYTES));
+        asm.store(8, RAX, R15, ctx(RSP_LIMIT));
+
+        asm.load(8, R13, R15, ctx(EXPRS));
+        asm.load(8, R12, R15, ctx(SLOTS));
+        asm.load(8, RBP, R15, ctx(VARS));
+        asm.alu(true, 0x31, R14, R14);
+        asm.mov_imm32(RBX, 1);
+
+        asm.call(init);
+        asm.mov_imm32(RAX, INVALID_CALL);
+        asm.jmp(self.exit);
+    }
+
+    /// Shared code: the ways out of the program, and turning the pointer in RAX
+    /// into an address, as long as the RSI bytes there are all in the same variable
+    fn helpers(&mut self) {
+        let asm = &mut self.asm;
+        asm.bind(self.exit);
+        asm.load(8, RSP, R15, ctx(SAVED_RSP));
+        for &reg in &[R15, R14, R13, R12, RBP, RBX] {
+            asm.pop(reg);
+        }
+        asm.bytes(RET);
+
+        for status in 1..STATUS_COUNT {
+            asm.bind(self.status[status]);
+            asm.mov_imm32(RAX, status as u32);
+            asm.jmp(self.exit);
+        }
+
+        let invalid = self.status[INVALID_POINTER as usize];
+        let (binary, heap, top) = (asm.label(), asm.label(), asm.label());
+        asm.bind(self.decode_ptr);
+        asm.mov(RCX, RAX);
+        asm.shift_imm(SHR, RCX, 32);
+        asm.rr(false, &[0x89], RAX, RDX);
+        asm.bit(4, RAX, 63);
+        asm.jcc(CC_B, binary);
+        asm.bit(4, RAX, 62);
+        asm.jcc(CC_AE, heap);
+
+        asm.alu_imm(false, AND, RCX, 0xFFFF);
+        asm.alu(false, 0x85, RCX, RCX);
+        asm.jcc(CC_E, invalid);
+        asm.alu(true, 0x39, RCX, R14);
+        asm.jcc(CC_A, invalid);
+        asm.rm(true, &[0x8B], RAX, R12, Some(RCX), -8);
+        asm.alu(true, 0x01, RAX, RDX);
+
+        // A stack variable ends where the next one starts, or at the top of the stack
+        asm.mov(RDX, RAX);
+        asm.alu(true, 0x01, RDX, RSI);
+        asm.mov(RDI, RBP);
+        asm.alu(true, 0x39, RCX, R14);
+        asm.jcc(CC_AE, top);
+        asm.rm(true, &[0x8B], RDI, R12, Some(RCX), 0);
+        asm.bind(top);
+        asm.alu(true, 0x39, RDX, RDI);
+        asm.jcc(CC_A, invalid);
+        asm.bytes(RET);
+
+        asm.bind(binary);
+        asm.alu_imm(false, AND, RCX, 0x3FFF_FFFF);
+        asm.alu(false, 0x85, RCX, RCX);
+        asm.jcc(CC_E, invalid);
+        asm.alu_imm(true, CMP, RCX, self.var_count as i32);
+        asm.jcc(CC_A, invalid);
+        asm.rm(true, &[0x8B], RAX, R15, Some(RCX), ctx(TABLES - 1));
+        asm.alu(true, 0x01, RAX, RDX);
+
+        let ends = ctx(TABLES + 2 * self.var_count as usize - 1);
+        asm.mov(RDX, RAX);
+        asm.alu(true, 0x01, RDX, RSI);
+        asm.rm(true, &[0x3B], RDX, R15, Some(RCX), ends);
+        asm.jcc(CC_A, invalid);
+        asm.bytes(RET);
+
+        // A freed heap variable starts and ends at 0, so nothing fits in it
+        asm.bind(heap);
+        asm.alu(false, 0x85, RCX, RCX);
+        asm.jcc(CC_E, invalid);
+        asm.rm(true, &[0x3B], RCX, R15, None, ctx(HEAP_COUNT));
+        asm.jcc(CC_A, invalid);
+        asm.alu(true, 0x01, RCX, RCX);
+        asm.load(8, RDI, R15, ctx(HEAP_VARS));
+        asm.rm(true, &[0x8B], RAX, RDI, Some(RCX), -16);
+        asm.alu(true, 0x01, RAX, RDX);
+
+        asm.mov(RDX, RAX);
+        asm.alu(true, 0x01, RDX, RSI);
+        asm.rm(true, &[0x3B], RDX, RDI, Some(RCX), -8);
+        asm.jcc(CC_A, invalid);
+        asm.bytes(RET);
+
+        // Anything that isn't a valid binary variable is left to `decode_ptr`
+        let read_only = ctx(TABLES + 3 * self.var_count as usize - 1);
+        asm.bind(self.decode_store);
+        asm.bit(4, RAX, 63);
+        asm.jcc(CC_AE, self.decode_ptr);
+        asm.mov(RCX, RAX);
+        asm.shift_imm(SHR, RCX, 32);
+        asm.alu_imm(false, AND, RCX, 0x3FFF_FFFF);
+        asm.alu_imm(true, CMP, RCX, self.var_count as i32);
+        asm.jcc(CC_A, self.decode_ptr);
+        asm.alu(false, 0x31, RDX, RDX);
+        asm.rm(true, &[0x3B], RDX, R15, Some(RCX), read_only);
+        asm.jcc(CC_NE, self.status[READ_ONLY_WRITE as usize]);
+        asm.jmp(self.decode_ptr);
+    }
+
+    /// Calls `host_call` for `op`, which finds its operands on the expression stack
+    fn host(&mut self, op: Opcode) {
+        let asm = &mut self.asm;
+        asm.store(8, R13, R15, ctx(SAVED_EXPRS));
+        asm.store(8, R14, R15, ctx(SAVED_VAR_COUNT));
+        asm.store(8, RBP, R15, ctx(SAVED_VARS_TOP));
+        asm.mov(RDI, R15);
+        asm.mov_imm32(RSI, op as u8 as u32);
+        asm.rm(false, &[0xFF], 2, R15, None, ctx(HOST));
+        asm.load(8, R13, R15, ctx(SAVED_EXPRS));
+        asm.alu_imm(false, CMP, RAX, RESUME as i32);
+        asm.jcc(CC_NE, self.exit);
+    }
+
+    fn func(&mut self, ops: &[Op]) {
+        let mut targets = HashMap::new();
+        for target in ops.iter().filter_map(jump_target) {
+            let asm = &mut self.asm;
+            targets
+                .entry(target.offset())
+                .or_insert_with(|| asm.label());
+        }
+
+        for op in ops {
+            if let Some(&label) = targets.get(&op.offset) {
+                self.asm.bind(label);
+            }
+
+            self.op(op, &targets);
+        }
+    }
+
+    fn op(&mut self, op: &Op, targets: &HashMap<u32, usize>) {
+        if op.op != Opcode::Func && op.op != Opcode::Loc {
+            let overflow = self.status[STACK_OVERFLOW as usize];
+            self.asm.rm(true, &[0x3B], R13, R15, None, ctx(EXPRS_LIMIT));
+            self.asm.jcc(CC_A, overflow);
+
+            // Counting every instruction, like the interpreter does, so loops can't hang
+            let limit = self.status[OP_LIMIT as usize];
+            self.asm.rm(true, &[0x81], SUB, R15, None, ctx(OPS_LEFT));
+            self.asm.bytes(&1u32.to_le_bytes());
+            self.asm.jcc(CC_B, limit);
+        }
+
+        if let Some((from, to, signed)) = extension(op.op) {
+            self.pop(from, RAX);
+            if signed {
+                self.asm.movsx(from, true, RAX, RAX);
+            }
+
+            self.push(to, RAX);
+            return;
+        }
+
+        if let Some((int_op, width, signed)) = int_op(op.op) {
+            self.int_op(int_op, width, signed, op, targets);
+            return;
+        }
+
+        if let Some((float_op, width)) = float_op(op.op) {
+            self.float_op(float_op, width);
+            return;
+        }
+
+        if let Some((from, to)) = conversion(op.op) {
+            self.convert(from, to);
+            return;
+        }
+
+        match op.op {
+            Opcode::Func | Opcode::Loc => {}
+
+            Opcode::StackAlloc => {
+                let (len, overflow) = (op.operand::<u32>(), self.status[STACK_OVERFLOW as usize]);
+                let asm = &mut self.asm;
+                asm.rm(true, &[0x3B], R14, R15, None, ctx(SLOTS_LEN));
+                asm.jcc(CC_AE, overflow);
+                asm.mov(RAX, RBP);
+                asm.alu_imm(true, ADD, RAX, len as i32);
+                asm.rm(true, &[0x3B], RAX, R15, None, ctx(VARS_END));
+                asm.jcc(CC_A, overflow);
+
+                asm.rm(true, &[0x89], RBP, R12, Some(R14), 0);
+                asm.alu_imm(true, ADD, R14, 1);
+                asm.mov(RDI, RBP);
+                asm.mov_imm32(RCX, len);
+                asm.alu(false, 0x31, RAX, RAX);
+                asm.bytes(REP_STOSB);
+                asm.mov(RBP, RDI);
+            }
+            Opcode::StackDealloc => {
+                self.asm.alu_imm(true, SUB, R14, 1);
+                self.asm.rm(true, &[0x8B], RBP, R12, Some(R14), 0);
+            }
+
+            Opcode::Make8 => {
+                self.asm.mov_imm32(RAX, op.operand::<u8>() as u32);
+                self.push(1, RAX);
+            }
+            Opcode::Make16 => {
+                self.asm.mov_imm32(RAX, op.operand::<u16>() as u32);
+                self.push(2, RAX);
+            }
+            Opcode::Make32 => {
+                self.asm.mov_imm32(RAX, op.operand::<u32>());
+                self.push(4, RAX);
+            }
+            Opcode::Make64 => {
+                self.asm.mov_imm64(RAX, op.operand::<u64>());
+                self.push(8, RAX);
+            }
+            Opcode::MakeFp | Opcode::MakeSp => {
+                let base = if op.op == Opcode::MakeFp { RBX } else { R14 };
+                self.asm.mov(RAX, base);
+                self.asm.alu_imm(true, ADD, RAX, op.operand::<i16>() as i32);
+                self.asm.shift_imm(SHL, RAX, 32);
+                self.asm.bit(5, RAX, 62);
+                self.push(8, RAX);
+            }
+
+            Opcode::PushUndef => {
+                let len = op.operand::<u32>();
+                self.asm.mov(RDI, R13);
+                self.asm.mov_imm32(RCX, len);
+                self.asm.alu(false, 0x31, RAX, RAX);
+                self.asm.bytes(REP_STOSB);
+                self.asm.alu_imm(true, ADD, R13, len as i32);
+            }
+            Opcode::Pop => self.asm.alu_imm(true, SUB, R13, op.operand::<u32>() as i32),
+            Opcode::Dup => {
+                let len = op.operand::<u32>();
+                self.asm.mov(RAX, R13);
+                self.asm.alu_imm(true, SUB, RAX, len as i32);
+                self.copy(R13, RAX, len);
+                self.asm.alu_imm(true, ADD, R13, len as i32);
+            }
+            Opcode::Swap => {
+                // Both go above the stack, and then back down in the other order
+                let (top, bottom): (u32, u32) = (op.operand(), read(&op.operand[4..]));
+                self.asm.mov(RAX, R13);
+                self.asm.alu_imm(true, SUB, RAX, top as i32);
+                self.copy(R13, RAX, top);
+                self.asm.alu_imm(true, SUB, RSI, (top + bottom) as i32);
+                self.asm.mov_imm32(RCX, bottom);
+                self.asm.bytes(REP_MOVSB);
+
+                self.asm.mov(RAX, R13);
+                self.asm.alu_imm(true, SUB, RAX, (top + bottom) as i32);
+                self.copy(RAX, R13, top + bottom);
+            }
+
+            Opcode::Get => {
+                let len = op.operand::<u32>();
+                self.pop(8, RAX);
+                self.asm.mov_imm32(RSI, len);
+                self.asm.call(self.decode_ptr);
+                self.copy(R13, RAX, len);
+                self.asm.alu_imm(true, ADD, R13, len as i32);
+            }
+            Opcode::Set => {
+                let len = op.operand::<u32>();
+                self.pop(8, RAX);
+                self.asm.mov_imm32(RSI, len);
+                self.asm.call(self.decode_store);
+                self.asm.alu_imm(true, SUB, R13, len as i32);
+                self.copy(RAX, R13, len);
+            }
+
+            Opcode::Jump => {
+                let target: VarPointer = op.operand();
+                self.asm.jmp(targets[&target.offset()]);
+            }
+            Opcode::Ret => self.asm.bytes(RET),
+            Opcode::Call => {
+                let invalid = self.status[INVALID_CALL as usize];
+                let overflow = self.status[STACK_OVERFLOW as usize];
+                self.pop(8, RAX);
+
+                let asm = &mut self.asm;
+                asm.mov(RCX, RAX);
+                asm.shift_imm(SHR, RCX, 32);
+                asm.bit(4, RAX, 63);
+                asm.jcc(CC_AE, invalid);
+                asm.alu(false, 0x85, RAX, RAX);
+                asm.jcc(CC_NE, invalid);
+                asm.alu_imm(false, AND, RCX, 0x3FFF_FFFF);
+                asm.alu(false, 0x85, RCX, RCX);
+                asm.jcc(CC_E, invalid);
+                asm.alu_imm(true, CMP, RCX, self.var_count as i32);
+                asm.jcc(CC_A, invalid);
+
+                let table = ctx(TABLES + self.var_count as usize - 1);
+                asm.rm(true, &[0x8B], RAX, R15, Some(RCX), table);
+                asm.alu(true, 0x85, RAX, RAX);
+                asm.jcc(CC_E, invalid);
+
+                asm.rm(true, &[0x3B], RSP, R15, None, ctx(RSP_LIMIT));
+                asm.jcc(CC_B, overflow);
+
+                asm.push(RBX);
+                asm.mov(RBX, R14);
+                asm.alu_imm(true, ADD, RBX, 1);
+                asm.rr(false, &[0xFF], 2, RAX);
+                asm.pop(RBX);
+            }
+            Opcode::Ecall | Opcode::PushDyn | Opcode::Throw | Opcode::AssertStr => self.host(op.op),
+            Opcode::AllocBegin | Opcode::AllocEnd => self.host(op.op),
+            Opcode::HeapAlloc | Opcode::HeapDealloc | Opcode::HeapMove => self.host(op.op),
+            Opcode::ModF32 | Opcode::ModF64 => self.host(op.op),
+
+            op => unreachable!("{:?} should've been rejected by `decode`", op),
+        }
+    }
+
+    fn int_op(
+        &mut self,
+        op: IntOp,
+        width: u32,
+        signed: bool,
+        at: &Op,
+        targets: &HashMap<u32, usize>,
+    ) {
+        let w = width == 8;
+        match op {
+            IntOp::BoolNorm | IntOp::BoolNot => {
+                self.pop(width, RAX);
+                self.asm.alu(true, 0x85, RAX, RAX);
+                self.asm
+                    .setcc(if op == IntOp::BoolNorm { CC_NE } else { CC_E });
+                self.push(1, RAX);
+                return;
+            }
+            IntOp::JumpIfZero | IntOp::JumpIfNotZero => {
+                let target: VarPointer = at.operand();
+                self.pop(width, RAX);
+                self.asm.alu(true, 0x85, RAX, RAX);
+                let cc = if op == IntOp::JumpIfZero { CC_E } else { CC_NE };
+                self.asm.jcc(cc, targets[&target.offset()]);
+                return;
+            }
+            IntOp::BitNot => {
+                self.pop(width, RAX);
+                self.asm.rr(w, &[0xF7], 2, RAX);
+                self.push(width, RAX);
+                return;
+            }
+            _ => {}
+        }
+
+        // Shift amounts are always a byte
+        match op {
+            IntOp::LShift | IntOp::RShift => self.pop(1, RCX),
+            _ => self.pop(width, RCX),
+        }
+        self.pop(width, RAX);
+
+        if signed && width < 4 {
+            self.asm.movsx(width, false, RAX, RAX);
+            if op != IntOp::RShift {
+                self.asm.movsx(width, false, RCX, RCX);
+            }
+        }
+
+        match op {
+            IntOp::Add => self.asm.alu(w, 0x01, RAX, RCX),
+            IntOp::Sub => self.asm.alu(w, 0x29, RAX, RCX),
+            IntOp::And => self.asm.alu(w, 0x21, RAX, RCX),
+            IntOp::Or => self.asm.alu(w, 0x09, RAX, RCX),
+            IntOp::Xor => self.asm.alu(w, 0x31, RAX, RCX),
+            IntOp::Mul => self.asm.rr(w, &[0x0F, 0xAF], RAX, RCX),
+            IntOp::LShift | IntOp::RShift => {
+                self.asm.alu_imm(false, AND, RCX, width as i32 * 8 - 1);
+                let ext = match (op, signed) {
+                    (IntOp::LShift, _) => SHL,
+                    (_, true) => SAR,
+                    (_, false) => SHR,
+                };
+                self.asm.rr(w, &[0xD3], ext, RAX);
+            }
+            IntOp::Lt | IntOp::Leq | IntOp::Eq | IntOp::Neq => {
+                self.asm.alu(w, 0x39, RAX, RCX);
+                let cc = match (op, signed) {
+                    (IntOp::Lt, true) => CC_L,
+                    (IntOp::Lt, false) => CC_B,
+                    (IntOp::Leq, true) => CC_LE,
+                    (IntOp::Leq, false) => CC_BE,
+                    (IntOp::Eq, _) => CC_E,
+                    _ => CC_NE,
+                };
+                self.asm.setcc(cc);
+                self.push(1, RAX);
+                return;
+            }
+            IntOp::Div | IntOp::Mod => self.div(op, w, signed),
+            _ => unreachable!(),
+        }
+
+        self.push(width, RAX);
+    }
+
+    /// Divides RAX by RCX, which have been extended to at least 32 bits. Dividing the
+    /// smallest value by -1 wraps, like the interpreter, instead of faulting.
+    fn div(&mut self, op: IntOp, w: bool, signed: bool) {
+        let asm = &mut self.asm;
+        asm.alu(w, 0x85, RCX, RCX);
+        asm.jcc(CC_E, self.status[DIVIDE_BY_ZERO as usize]);
+
+        let (divide, done) = (asm.label(), asm.label());
+        if signed {
+            asm.alu_imm(w, CMP, RCX, -1);
+            asm.jcc(CC_NE, divide);
+            match op {
+                IntOp::Div => asm.rr(w, &[0xF7], 3, RAX),
+                _ => asm.alu(false, 0x31, RAX, RAX),
+            }
+            asm.jmp(done);
+        }
+
+        asm.bind(divide);
+        if signed {
+            asm.rex(w, 0, 0, 0);
+            asm.bytes(&[0x99]); // cdq or cqo
+            asm.rr(w, &[0xF7], 7, RCX);
+        } else {
+            asm.alu(false, 0x31, RDX, RDX);
+            asm.rr(w, &[0xF7], 6, RCX);
+        }
+
+        if op == IntOp::Mod {
+            asm.mov(RAX, RDX);
+        }
+
+        asm.bind(done);
+    }
+
+    /// Arithmetic and comparisons on floats, which go through XMM0 and XMM1.
+    /// Comparisons with NaN are false, except for `!=`.
+    fn float_op(&mut self, op: IntOp, width: u32) {
+        self.pop(width, RCX);
+        self.pop(width, RAX);
+
+        let asm = &mut self.asm;
+        asm.movq_to_xmm(XMM0, RAX);
+        asm.movq_to_xmm(XMM1, RCX);
+
+        let opcode = match op {
+            IntOp::Add => 0x58,
+            IntOp::Sub => 0x5C,
+            IntOp::Mul => 0x59,
+            IntOp::Div => 0x5E,
+            _ => {
+                // ucomiss or ucomisd
+                let prefix = Some(0x66).filter(|_| width == 8);
+                match op {
+                    IntOp::Lt | IntOp::Leq => {
+                        asm.sse(prefix, false, 0x2E, XMM1, XMM0);
+                        asm.setcc(if op == IntOp::Lt { CC_A } else { CC_AE });
+                    }
+                    IntOp::Eq => {
+                        asm.sse(prefix, false, 0x2E, XMM0, XMM1);
+                        asm.setcc(CC_E);
+                        asm.mov(RDX, RAX);
+                        asm.setcc(CC_NP);
+                        asm.alu(false, 0x21, RAX, RDX);
+                    }
+                    _ => {
+                        asm.sse(prefix, false, 0x2E, XMM0, XMM1);
+                        asm.setcc(CC_NE);
+                        asm.mov(RDX, RAX);
+                        asm.setcc(CC_P);
+                        asm.alu(false, 0x09, RAX, RDX);
+                    }
+                }
+
+                self.push(1, RAX);
+                return;
+            }
+        };
+
+        asm.sse(scalar(width), false, opcode, XMM0, XMM1);
+        asm.movq_from_xmm(RAX, XMM0);
+        self.push(width, RAX);
+    }
+
+    /// Conversions between floats and integers, or floats of different widths
+    fn convert(&mut self, from: Num, to: Num) {
+        match (from, to) {
+            (Num::Int(width, signed), Num::Float(to)) => {
+                self.pop(width, RAX);
+                if signed && width < 8 {
+                    self.asm.movsx(width, true, RAX, RAX);
+                }
+
+                if width == 8 && !signed {
+                    self.u64_to_float(to);
+                } else {
+                    self.asm.sse(scalar(to), true, 0x2A, XMM0, RAX);
+                }
+
+                self.asm.movq_from_xmm(RAX, XMM0);
+                self.push(to, RAX);
+            }
+            (Num::Float(from), Num::Int(width, signed)) => {
+                self.pop(from, RAX);
+                self.asm.movq_to_xmm(XMM0, RAX);
+                if width == 8 && !signed {
+                    self.float_to_u64(from);
+                } else {
+                    self.asm.sse(scalar(from), true, 0x2C, RAX, XMM0);
+                }
+
+                self.push(width, RAX);
+            }
+            (Num::Float(from), Num::Float(to)) => {
+                self.pop(from, RAX);
+                self.asm.movq_to_xmm(XMM0, RAX);
+                self.asm.sse(scalar(from), false, 0x5A, XMM0, XMM0);
+                self.asm.movq_from_xmm(RAX, XMM0);
+                self.push(to, RAX);
+            }
+            (Num::Int(..), Num::Int(..)) => unreachable!(),
+        }
+    }
+
+    /// Converts RAX to a float in XMM0. Values with the top bit set are halved,
+    /// keeping the bottom bit so that they round the same way, and then doubled.
+    fn u64_to_float(&mut self, width: u32) {
+        let asm = &mut self.asm;
+        let (big, done) = (asm.label(), asm.label());
+        asm.alu(true, 0x85, RAX, RAX);
+        asm.jcc(CC_S, big);
+        asm.sse(scalar(width), true, 0x2A, XMM0, RAX);
+        asm.jmp(done);
+
+        asm.bind(big);
+        asm.mov(RCX, RAX);
+        asm.shift_imm(SHR, RCX, 1);
+        asm.alu_imm(false, AND, RAX, 1);
+        asm.alu(true, 0x09, RCX, RAX);
+        asm.sse(scalar(width), true, 0x2A, XMM0, RCX);
+        asm.sse(scalar(width), false, 0x58, XMM0, XMM0);
+        asm.bind(done);
+    }
+
+    /// Converts XMM0 to an integer in RAX. Values from 2^63 up have 2^63 taken off
+    /// first, and put back as the top bit.
+    fn float_to_u64(&mut self, width: u32) {
+        let asm = &mut self.asm;
+        let (big, done) = (asm.label(), asm.label());
+        match width {
+            8 => asm.mov_imm64(RCX, 0x43E0_0000_0000_0000),
+            _ => asm.mov_imm32(RCX, 0x5F00_0000),
+        }
+        asm.movq_to_xmm(XMM1, RCX);
+        asm.sse(Some(0x66).filter(|_| width == 8), false, 0x2E, XMM0, XMM1);
+        asm.jcc(CC_AE, big);
+        asm.sse(scalar(width), true, 0x2C, RAX, XMM0);
+        asm.jmp(done);
+
+        asm.bind(big);
+        asm.sse(scalar(width), false, 0x5C, XMM0, XMM1);
+        asm.sse(scalar(width), true, 0x2C, RAX, XMM0);
+        asm.bit(5, RAX, 63);
+        asm.bind(done);
+    }
+}
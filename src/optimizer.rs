@@ -0,0 +1,1000 @@
+//! Optimizations on the type checker's output, run before assembly at `-O1`.
+//! Constant expressions are folded, branches on constant conditions become plain
+//! jumps (or disappear), and statements that can't be reached are dropped. Anything
+//! whose behavior is undefined, like signed overflow or dividing by zero, is left
+//! alone so that the runtime still reports it.
+
+use crate::ast::BinOp;
+use crate::buckets::*;
+use crate::tc_ast::*;
+use crate::util::*;
+
+/// Parses an optimization flag, `-O0` or `-O1`
+pub fn parse_opt_level(flag: &str) -> Result<u8, String> {
+    return match flag {
+        "-O0" => Ok(0),
+        "-O1" => Ok(1),
+        _ => Err(format!("unknown optimization level `{}`", flag)),
+    };
+}
+
+pub fn optimize(tu: &mut TranslationUnit) {
+    let alloc = &tu.buckets;
+    for func in tu.functions.values_mut() {
+        if let Some(defn) = &mut func.defn {
+            // Statement expressions point at their ops by index, and run them out of
+            // order, so removing ops from around them isn't safe
+            let stmt_expr = |op: &TCOpcode| matches!(op.kind, TCOpcodeKind::StmtExpr { .. });
+            if defn.ops.iter().any(stmt_expr) {
+                continue;
+            }
+
+            defn.ops = optimize_ops(alloc, defn.ops);
+        }
+    }
+}
+
+/// Functions whose whole body is `return <expression>;` get inlined, as long as the
+/// expression has at most this many nodes and doesn't call anything
+const INLINE_MAX_NODES: u32 = 24;
+
+struct Inlinable {
+    params: Vec<TCType>,
+    body: TCExpr,
+}
+
+/// Inlines calls to tiny leaf functions defined in the same file. Arguments are
+/// stored in new locals of the caller, which stand in for the callee's parameters.
+pub fn inline(tu: &mut TranslationUnit) {
+    let mut inlinable = HashMap::new();
+    for (&ident, func) in &tu.functions {
+        if let Some(callee) = inlinable_func(func) {
+            inlinable.insert(ident, callee);
+        }
+    }
+
+    if inlinable.len() == 0 {
+        return;
+    }
+
+    let alloc = &tu.buckets;
+    for func in tu.functions.values_mut() {
+        if let Some(defn) = &mut func.defn {
+            inline_calls(alloc, defn, &inlinable);
+        }
+    }
+}
+
+fn inlinable_func(func: &TCFunction) -> Option<Inlinable> {
+    let defn = func.defn.as_ref()?;
+    if func.func_type.params?.varargs {
+        return None;
+    }
+
+    let mut params = Vec::new();
+    let mut body = None;
+    for (idx, op) in defn.ops.iter().enumerate() {
+        match op.kind {
+            TCOpcodeKind::ScopeBegin(vars, _) if idx == 0 => {
+                if vars.len() as u32 != defn.param_count {
+                    return None;
+                }
+
+                for label in 0..defn.param_count {
+                    params.push(*vars.get(&label)?);
+                }
+            }
+            TCOpcodeKind::ScopeBegin(vars, _) if vars.len() == 0 => {}
+            TCOpcodeKind::ScopeEnd { .. } => {}
+            TCOpcodeKind::RetVal(expr) if body.is_none() => body = Some(expr),
+            _ => return None,
+        }
+    }
+
+    let body = body?;
+    let mut nodes = 0;
+    let mut calls = false;
+    visit(&body, &mut |e| {
+        nodes += 1;
+        calls |= matches!(e.kind, TCExprKind::Call { .. });
+    });
+
+    if calls || nodes > INLINE_MAX_NODES {
+        return None;
+    }
+
+    return Some(Inlinable { params, body });
+}
+
+fn inline_calls(
+    alloc: &impl Allocator<'static>,
+    defn: &mut TCFuncDefn,
+    inlinable: &HashMap<u32, Inlinable>,
+) {
+    let mut temps = Vec::new();
+    let mut next_label = defn.sym_count;
+    let mut inline = |e: &TCExpr| inline_expr(alloc, e, inlinable, &mut temps, &mut next_label);
+
+    let mut ops = defn.ops.to_vec();
+    for op in &mut ops {
+        match &mut op.kind {
+            TCOpcodeKind::GotoIfZero { cond, .. } | TCOpcodeKind::GotoIfNotZero { cond, .. } => {
+                *cond = inline(cond)
+            }
+            TCOpcodeKind::Switch { expr, .. } => *expr = inline(expr),
+            TCOpcodeKind::Expr(expr) | TCOpcodeKind::RetVal(expr) => *expr = inline(expr),
+            _ => {}
+        }
+    }
+
+    if temps.len() == 0 {
+        return;
+    }
+
+    // The temporaries live in the function's outermost scope
+    if let TCOpcodeKind::ScopeBegin(vars, parent) = ops[0].kind {
+        let capa = (vars.len() + temps.len()) * 3 / 2;
+        let vars = vars.into_iter().map(|(&l, &ty)| (l, ty)).chain(temps);
+        ops[0].kind = TCOpcodeKind::ScopeBegin(HashRef::new_iter(alloc, capa, vars), parent);
+    }
+
+    if let Some(TCOpcode {
+        kind: TCOpcodeKind::ScopeEnd { count, .. },
+        ..
+    }) = ops.last_mut()
+    {
+        *count += next_label - defn.sym_count;
+    }
+
+    defn.sym_count = next_label;
+    defn.ops = alloc.add_array(ops);
+}
+
+fn inline_expr(
+    alloc: &impl Allocator<'static>,
+    expr: &TCExpr,
+    inlinable: &HashMap<u32, Inlinable>,
+    temps: &mut Vec<(u32, TCType)>,
+    next_label: &mut u32,
+) -> TCExpr {
+    let expr = map_children(alloc, expr, &mut |e| {
+        inline_expr(alloc, e, inlinable, temps, next_label)
+    });
+
+    let (callee, args) = match expr.kind {
+        TCExprKind::Call { func, params } => match func.kind {
+            TCExprKind::FunctionIdent { ident } => match inlinable.get(&ident) {
+                Some(callee) => (callee, params),
+                None => return expr,
+            },
+            _ => return expr,
+        },
+        _ => return expr,
+    };
+
+    if args.len() != callee.params.len() {
+        return expr;
+    }
+
+    let mut exprs = Vec::new();
+    let mut labels = Vec::new();
+    for (arg, &ty) in args.iter().zip(callee.params.iter()) {
+        if arg.ty.to_prim_type().is_none() || arg.ty.to_prim_type() != ty.to_prim_type() {
+            return expr;
+        }
+
+        labels.push(*next_label);
+        temps.push((*next_label, ty));
+        *next_label += 1;
+
+        let target = TCAssignTarget {
+            kind: TCAssignTargetKind::LocalIdent {
+                label: *next_label - 1,
+            },
+            defn_loc: expr.loc,
+            loc: arg.loc,
+            ty,
+            offset: 0,
+            bitfield: None,
+        };
+
+        let value = alloc.add(*arg);
+        let kind = TCExprKind::Assign { target, value };
+        exprs.push(TCExpr {
+            kind,
+            ty,
+            loc: arg.loc,
+        });
+    }
+
+    // A call evaluates its arguments right to left, so they're stored in that order
+    exprs.reverse();
+    exprs.push(relabel(alloc, &callee.body, &labels));
+    let kind = TCExprKind::ParenList(alloc.add_array(exprs));
+    return TCExpr { kind, ..expr };
+}
+
+/// `expr` with local `n` replaced by local `labels[n]`
+fn relabel(alloc: &impl Allocator<'static>, expr: &TCExpr, labels: &[u32]) -> TCExpr {
+    let mut expr = map_children(alloc, expr, &mut |e| relabel(alloc, e, labels));
+    let target = |target: &mut TCAssignTarget| {
+        if let TCAssignTargetKind::LocalIdent { label } = &mut target.kind {
+            *label = labels[*label as usize];
+        }
+    };
+
+    match &mut expr.kind {
+        TCExprKind::LocalIdent { label } => *label = labels[*label as usize],
+        TCExprKind::Assign { target: t, .. } | TCExprKind::MutAssign { target: t, .. } => target(t),
+        TCExprKind::PostIncr { value, .. } | TCExprKind::PostDecr { value, .. } => target(value),
+        TCExprKind::Ref(t) => target(t),
+        _ => {}
+    }
+
+    return expr;
+}
+
+fn optimize_ops(alloc: &impl Allocator<'static>, ops: &[TCOpcode]) -> &'static [TCOpcode] {
+    let mut out = Vec::with_capacity(ops.len());
+    let mut new_idx = Vec::with_capacity(ops.len());
+    let mut reachable = true;
+
+    for op in ops {
+        new_idx.push(out.len() as u32);
+
+        let kind = match op.kind {
+            TCOpcodeKind::ScopeBegin(..) | TCOpcodeKind::ScopeEnd { .. } => op.kind,
+            TCOpcodeKind::StmtExpr { .. } => op.kind,
+            TCOpcodeKind::Label { .. } => {
+                reachable = true;
+                op.kind
+            }
+            _ if !reachable => continue,
+
+            TCOpcodeKind::Goto { .. } => {
+                reachable = false;
+                op.kind
+            }
+            TCOpcodeKind::GotoIfZero {
+                cond,
+                cond_ty,
+                goto,
+                scope_idx,
+            } => {
+                let cond = fold(alloc, &cond);
+                match int_value(&cond.kind) {
+                    Some(0) => {
+                        reachable = false;
+                        TCOpcodeKind::Goto { goto, scope_idx }
+                    }
+                    Some(_) => continue,
+                    None => TCOpcodeKind::GotoIfZero {
+                        cond,
+                        cond_ty,
+                        goto,
+                        scope_idx,
+                    },
+                }
+            }
+            TCOpcodeKind::GotoIfNotZero {
+                cond,
+                cond_ty,
+                goto,
+                scope_idx,
+            } => {
+                let cond = fold(alloc, &cond);
+                match int_value(&cond.kind) {
+                    Some(0) => continue,
+                    Some(_) => {
+                        reachable = false;
+                        TCOpcodeKind::Goto { goto, scope_idx }
+                    }
+                    None => TCOpcodeKind::GotoIfNotZero {
+                        cond,
+                        cond_ty,
+                        goto,
+                        scope_idx,
+                    },
+                }
+            }
+            TCOpcodeKind::Switch {
+                expr,
+                cases,
+                default,
+            } => {
+                reachable = false;
+                let expr = fold(alloc, &expr);
+                TCOpcodeKind::Switch {
+                    expr,
+                    cases,
+                    default,
+                }
+            }
+
+            TCOpcodeKind::Expr(expr) => {
+                let expr = fold(alloc, &expr);
+                if is_literal(&expr.kind) {
+                    continue;
+                }
+
+                TCOpcodeKind::Expr(expr)
+            }
+            TCOpcodeKind::Ret => {
+                reachable = false;
+                op.kind
+            }
+            TCOpcodeKind::RetVal(val) => {
+                reachable = false;
+                TCOpcodeKind::RetVal(fold(alloc, &val))
+            }
+        };
+
+        out.push(TCOpcode { kind, loc: op.loc });
+    }
+
+    // Scopes refer to each other by index, so they need to be renumbered
+    let remap = |idx: u32| new_idx.get(idx as usize).map(|&i| i).unwrap_or(idx);
+    for op in &mut out {
+        match &mut op.kind {
+            TCOpcodeKind::Label { scope_idx, .. }
+            | TCOpcodeKind::Goto { scope_idx, .. }
+            | TCOpcodeKind::GotoIfZero { scope_idx, .. }
+            | TCOpcodeKind::GotoIfNotZero { scope_idx, .. } => *scope_idx = remap(*scope_idx),
+            TCOpcodeKind::ScopeBegin(_, parent) => *parent = remap(*parent),
+            TCOpcodeKind::ScopeEnd { begin, .. } => *begin = remap(*begin),
+            _ => {}
+        }
+    }
+
+    return alloc.add_array(out);
+}
+
+/// Folds the constant parts of `expr`
+pub fn fold(alloc: &impl Allocator<'static>, expr: &TCExpr) -> TCExpr {
+    let expr = map_children(alloc, expr, &mut |e| fold(alloc, e));
+
+    let kind = match expr.kind {
+        TCExprKind::BinOp {
+            op,
+            op_type,
+            left,
+            right,
+        } => {
+            let values = int_value(&left.kind).zip(int_value(&right.kind));
+            let folded = values.and_then(|(l, r)| fold_bin_op(op, op_type, l, r));
+            folded.and_then(|value| int_lit(expr.ty, value))
+        }
+        TCExprKind::UnaryOp {
+            op,
+            op_type,
+            operand,
+        } => {
+            let value = int_value(&operand.kind);
+            let folded = value.and_then(|value| fold_un_op(op, op_type, value));
+            folded.and_then(|value| int_lit(expr.ty, value))
+        }
+        TCExprKind::Conv { from, expr: e, .. } => {
+            let value = int_value(&e.kind).filter(|_| !from.is_floating_pt());
+            value.and_then(|value| int_lit(expr.ty, value))
+        }
+        TCExprKind::Ternary {
+            condition,
+            if_true,
+            if_false,
+            ..
+        } => match int_value(&condition.kind) {
+            Some(0) => return *if_false,
+            Some(_) => return *if_true,
+            None => None,
+        },
+        _ => None,
+    };
+
+    return match kind {
+        Some(kind) => TCExpr { kind, ..expr },
+        None => expr,
+    };
+}
+
+/// Rebuilds `expr` with `f` applied to each of its subexpressions
+fn map_children(
+    alloc: &impl Allocator<'static>,
+    expr: &TCExpr,
+    f: &mut dyn FnMut(&TCExpr) -> TCExpr,
+) -> TCExpr {
+    let mut add = |e: &TCExpr| -> &'static TCExpr { alloc.add(f(e)) };
+    macro_rules! add_all {
+        ($exprs:expr) => {{
+            let exprs: Vec<TCExpr> = $exprs.iter().map(|e| *add(e)).collect();
+            alloc.add_array(exprs)
+        }};
+    }
+    macro_rules! target {
+        ($target:expr) => {{
+            let target: TCAssignTarget = $target;
+            let kind = match target.kind {
+                TCAssignTargetKind::Ptr(ptr) => TCAssignTargetKind::Ptr(add(ptr)),
+                kind => kind,
+            };
+
+            TCAssignTarget { kind, ..target }
+        }};
+    }
+
+    let kind = match expr.kind {
+        TCExprKind::BinOp {
+            op,
+            op_type,
+            left,
+            right,
+        } => TCExprKind::BinOp {
+            op,
+            op_type,
+            left: add(left),
+            right: add(right),
+        },
+        TCExprKind::UnaryOp {
+            op,
+            op_type,
+            operand,
+        } => TCExprKind::UnaryOp {
+            op,
+            op_type,
+            operand: add(operand),
+        },
+        TCExprKind::Conv { from, to, expr } => TCExprKind::Conv {
+            from,
+            to,
+            expr: add(expr),
+        },
+        TCExprKind::Ternary {
+            condition,
+            cond_ty,
+            if_true,
+            if_false,
+        } => TCExprKind::Ternary {
+            condition: add(condition),
+            cond_ty,
+            if_true: add(if_true),
+            if_false: add(if_false),
+        },
+
+        TCExprKind::TypePun(e) => TCExprKind::TypePun(add(e)),
+        TCExprKind::StructLit {
+            fields,
+            offsets,
+            size,
+        } => {
+            let fields = add_all!(fields);
+            TCExprKind::StructLit {
+                fields,
+                offsets,
+                size,
+            }
+        }
+        TCExprKind::ParenList(exprs) => TCExprKind::ParenList(add_all!(exprs)),
+        TCExprKind::Assign { target, value } => TCExprKind::Assign {
+            target: target!(target),
+            value: add(value),
+        },
+        TCExprKind::MutAssign {
+            target,
+            value,
+            op,
+            op_type,
+        } => TCExprKind::MutAssign {
+            target: target!(target),
+            value: add(value),
+            op,
+            op_type,
+        },
+        TCExprKind::PostIncr { incr_ty, value } => TCExprKind::PostIncr {
+            incr_ty,
+            value: target!(value),
+        },
+        TCExprKind::PostDecr { decr_ty, value } => TCExprKind::PostDecr {
+            decr_ty,
+            value: target!(value),
+        },
+        TCExprKind::Member {
+            base,
+            offset,
+            bitfield,
+        } => TCExprKind::Member {
+            base: add(base),
+            offset,
+            bitfield,
+        },
+        TCExprKind::PtrMember {
+            base,
+            offset,
+            bitfield,
+        } => TCExprKind::PtrMember {
+            base: add(base),
+            offset,
+            bitfield,
+        },
+        TCExprKind::Ref(target) => TCExprKind::Ref(target!(target)),
+        TCExprKind::Deref(e) => TCExprKind::Deref(add(e)),
+        TCExprKind::Call { func, params } => TCExprKind::Call {
+            func: add(func),
+            params: add_all!(params),
+        },
+        TCExprKind::Builtin(TCBuiltin::Push(e)) => TCExprKind::Builtin(TCBuiltin::Push(add(e))),
+
+        kind => kind,
+    };
+
+    return TCExpr { kind, ..*expr };
+}
+
+fn fold_bin_op(op: BinOp, op_type: TCPrimType, l: i128, r: i128) -> Option<i128> {
+    let (l, r) = (truncate(op_type, l)?, truncate(op_type, r)?);
+    let bits = op_type.size() as i128 * 8;
+
+    let value = match op {
+        BinOp::Add => l + r,
+        BinOp::Sub => l - r,
+        BinOp::Mul => l.checked_mul(r)?,
+        BinOp::Div | BinOp::Mod if r == 0 => return None,
+        BinOp::Div => l / r,
+        BinOp::Mod if truncate(op_type, l / r)? != l / r => return None,
+        BinOp::Mod => l % r,
+
+        BinOp::Lt => return Some((l < r) as i128),
+        BinOp::Gt => return Some((l > r) as i128),
+        BinOp::Leq => return Some((l <= r) as i128),
+        BinOp::Geq => return Some((l >= r) as i128),
+        BinOp::Eq => return Some((l == r) as i128),
+        BinOp::Neq => return Some((l != r) as i128),
+
+        BinOp::BitAnd => l & r,
+        BinOp::BitOr => l | r,
+        BinOp::BitXor => l ^ r,
+        BinOp::LShift | BinOp::RShift if r < 0 || r >= bits => return None,
+        BinOp::LShift if l < 0 => return None,
+        BinOp::LShift => l << r,
+        BinOp::RShift => l >> r,
+
+        BinOp::Index | BinOp::BoolAnd | BinOp::BoolOr => return None,
+    };
+
+    return wrap(op_type, value);
+}
+
+fn fold_un_op(op: TCUnaryOp, op_type: TCPrimType, value: i128) -> Option<i128> {
+    let value = truncate(op_type, value)?;
+    return match op {
+        TCUnaryOp::Neg => wrap(op_type, -value),
+        TCUnaryOp::BitNot => wrap(op_type, !value),
+        TCUnaryOp::BoolNorm => Some((value != 0) as i128),
+        TCUnaryOp::BoolNot => Some((value == 0) as i128),
+    };
+}
+
+/// The result of an operation on `op_type`, or `None` if it overflows a signed type
+fn wrap(op_type: TCPrimType, value: i128) -> Option<i128> {
+    let wrapped = truncate(op_type, value)?;
+    if op_type.signed() && wrapped != value {
+        return None;
+    }
+
+    return Some(wrapped);
+}
+
+/// `value` converted to `ty` the way a C cast would, if `ty` is an integer type
+fn truncate(ty: TCPrimType, value: i128) -> Option<i128> {
+    let value = match ty {
+        TCPrimType::I8 => value as i8 as i128,
+        TCPrimType::U8 => value as u8 as i128,
+        TCPrimType::I16 => value as i16 as i128,
+        TCPrimType::U16 => value as u16 as i128,
+        TCPrimType::I32 => value as i32 as i128,
+        TCPrimType::U32 => value as u32 as i128,
+        TCPrimType::I64 => value as i64 as i128,
+        TCPrimType::U64 => value as u64 as i128,
+        _ => return None,
+    };
+
+    return Some(value);
+}
+
+fn int_lit(ty: TCType, value: i128) -> Option<TCExprKind> {
+    let kind = match ty.to_prim_type()? {
+        TCPrimType::I8 => TCExprKind::I8Lit(value as i8),
+        TCPrimType::U8 => TCExprKind::U8Lit(value as u8),
+        TCPrimType::I16 => TCExprKind::I16Lit(value as i16),
+        TCPrimType::U16 => TCExprKind::U16Lit(value as u16),
+        TCPrimType::I32 => TCExprKind::I32Lit(value as i32),
+        TCPrimType::U32 => TCExprKind::U32Lit(value as u32),
+        TCPrimType::I64 => TCExprKind::I64Lit(value as i64),
+        TCPrimType::U64 => TCExprKind::U64Lit(value as u64),
+        _ => return None,
+    };
+
+    return Some(kind);
+}
+
+/// The value of an integer literal
+pub fn int_value(kind: &TCExprKind) -> Option<i128> {
+    let value = match *kind {
+        TCExprKind::I8Lit(v) => v as i128,
+        TCExprKind::U8Lit(v) => v as i128,
+        TCExprKind::I16Lit(v) => v as i128,
+        TCExprKind::U16Lit(v) => v as i128,
+        TCExprKind::I32Lit(v) => v as i128,
+        TCExprKind::U32Lit(v) => v as i128,
+        TCExprKind::I64Lit(v) => v as i128,
+        TCExprKind::U64Lit(v) => v as i128,
+        _ => return None,
+    };
+
+    return Some(value);
+}
+
+/// Whether `expr` is built only from integer literals, so that `fold` only leaves
+/// it unfolded if it overflows or divides by zero
+pub fn is_int_constant(expr: &TCExpr) -> bool {
+    return match expr.kind {
+        TCExprKind::BinOp {
+            op: BinOp::Index | BinOp::BoolAnd | BinOp::BoolOr,
+            ..
+        } => false,
+        TCExprKind::BinOp { left, right, .. } => is_int_constant(left) && is_int_constant(right),
+        TCExprKind::UnaryOp { operand, .. } => is_int_constant(operand),
+        TCExprKind::Conv { from, expr, .. } => !from.is_floating_pt() && is_int_constant(expr),
+        TCExprKind::Ternary {
+            condition,
+            if_true,
+            if_false,
+            ..
+        } => is_int_constant(condition) && is_int_constant(if_true) && is_int_constant(if_false),
+        ref kind => int_value(kind).is_some(),
+    };
+}
+
+fn is_literal(kind: &TCExprKind) -> bool {
+    return match kind {
+        TCExprKind::F32Lit(_) | TCExprKind::F64Lit(_) => true,
+        kind => int_value(kind).is_some(),
+    };
+}
+
+/// Locals that can share a stack slot with another local in the same scope, mapped
+/// to the local whose slot they use. Only scalars whose address is never taken are
+/// shared, and only when their live ranges in `defn.ops` don't overlap; a jump
+/// backwards stretches the range of anything live across it to cover the whole loop.
+/// Temporaries that live inside one expression, like the ones inlining adds, are
+/// tracked by where they are in it, so that they can share with each other too.
+pub fn shared_slots(defn: &TCFuncDefn) -> HashMap<u32, u32> {
+    let mut ranges: HashMap<u32, (usize, usize)> = HashMap::new();
+    let mut temp_spans: HashMap<u32, (usize, (u32, u32))> = HashMap::new();
+    let mut escaped = Vec::new();
+    let mut label_pos = HashMap::new();
+    let mut jumps = Vec::new();
+
+    for (idx, op) in defn.ops.iter().enumerate() {
+        let mut exprs = Vec::new();
+        match &op.kind {
+            TCOpcodeKind::Label { label, .. } => {
+                label_pos.insert(*label, idx);
+            }
+            TCOpcodeKind::Goto { goto, .. } => jumps.push((idx, *goto)),
+            TCOpcodeKind::GotoIfZero { cond, goto, .. }
+            | TCOpcodeKind::GotoIfNotZero { cond, goto, .. } => {
+                jumps.push((idx, *goto));
+                exprs.push(*cond);
+            }
+            TCOpcodeKind::Switch {
+                expr,
+                cases,
+                default,
+            } => {
+                jumps.push((idx, *default));
+                for (case, goto) in *cases {
+                    jumps.push((idx, *goto));
+                    exprs.push(*case);
+                }
+                exprs.push(*expr);
+            }
+            TCOpcodeKind::Expr(expr) | TCOpcodeKind::RetVal(expr) => exprs.push(*expr),
+            TCOpcodeKind::ScopeBegin(..) | TCOpcodeKind::ScopeEnd { .. } | TCOpcodeKind::Ret => {}
+
+            // Statement expressions run their ops in the middle of another op, which
+            // the ranges here can't describe
+            TCOpcodeKind::StmtExpr { .. } => return HashMap::new(),
+        }
+
+        let mut mention = |label: u32, address_taken: bool| {
+            if address_taken {
+                escaped.push(label);
+            }
+
+            let range = ranges.entry(label).or_insert((idx, idx));
+            range.1 = idx;
+        };
+
+        for expr in &exprs {
+            visit_locals(expr, &mut mention);
+        }
+
+        if let [expr] = &exprs[..] {
+            for (var, span) in scoped_temps(expr) {
+                temp_spans.insert(var, (idx, span));
+            }
+        }
+    }
+
+    let mut loops = Vec::new();
+    for (from, goto) in jumps {
+        match label_pos.get(&goto) {
+            Some(&to) if to < from => loops.push((to, from)),
+            _ => {}
+        }
+    }
+
+    let mut shared = HashMap::new();
+    for (begin, op) in defn.ops.iter().enumerate() {
+        let vars = match op.kind {
+            TCOpcodeKind::ScopeBegin(vars, _) => vars,
+            _ => continue,
+        };
+
+        let mut candidates = Vec::new();
+        for (&var, ty) in vars {
+            let scalar = ty.to_prim_type().is_some() && !ty.is_array();
+            if var < defn.param_count || !scalar || escaped.contains(&var) {
+                continue;
+            }
+
+            let (mut start, mut end) = match ranges.get(&var) {
+                Some(&range) => range,
+                None => continue,
+            };
+
+            // Positions are (op, subexpression), so that temporaries in the same op
+            // can be told apart
+            let size = ty.repr_size();
+            match temp_spans.get(&var) {
+                Some(&(idx, (first, last))) if (start, end) == (idx, idx) => {
+                    candidates.push(((idx, first), (idx, last), var, size));
+                    continue;
+                }
+                _ => {}
+            }
+
+            // A local read before it's written still has the value it started with
+            if !is_plain_store(&defn.ops[start], var) {
+                start = begin;
+            }
+
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for &(to, from) in &loops {
+                    if start <= from && to <= end && (to < start || from > end) {
+                        start = core::cmp::min(start, to);
+                        end = core::cmp::max(end, from);
+                        changed = true;
+                    }
+                }
+            }
+
+            candidates.push(((start, 0), (end, u32::MAX), var, size));
+        }
+
+        candidates.sort_by_key(|&(start, _, var, _)| (start, var));
+
+        let mut slots: Vec<(u32, u32, (usize, u32))> = Vec::new(); // (owner, size, end)
+        for (start, end, var, size) in candidates {
+            let free = slots.iter_mut().find(|s| s.1 == size && s.2 < start);
+            match free {
+                Some(slot) => {
+                    shared.insert(var, slot.0);
+                    slot.2 = end;
+                }
+                None => slots.push((var, size, end)),
+            }
+        }
+    }
+
+    return shared;
+}
+
+/// Locals that `expr` only uses inside one `ParenList`, which stores to them before
+/// anything reads them, mapped to the positions the list takes up in `expr`. A
+/// position counts subexpressions in the order `visit` calls `f` on them, and the
+/// code for a subexpression runs all at once, so nothing outside the list can see
+/// these locals.
+fn scoped_temps(expr: &TCExpr) -> HashMap<u32, (u32, u32)> {
+    let mut mentions = HashMap::new();
+    visit_locals(expr, &mut |label, _| {
+        *mentions.entry(label).or_insert(0) += 1
+    });
+
+    let mut temps = HashMap::new();
+    let mut pos = 0;
+    visit(expr, &mut |list| {
+        pos += 1;
+        let exprs = match list.kind {
+            TCExprKind::ParenList(exprs) => exprs,
+            _ => return,
+        };
+
+        let mut len = 0;
+        let mut inside = HashMap::new();
+        visit(list, &mut |_| len += 1);
+        visit_locals(list, &mut |label, _| *inside.entry(label).or_insert(0) += 1);
+
+        let mut seen = Vec::new();
+        for e in exprs {
+            if let TCExprKind::Assign { target, value } = e.kind {
+                match target.kind {
+                    TCAssignTargetKind::LocalIdent { label }
+                        if !seen.contains(&label)
+                            && inside.get(&label) == mentions.get(&label)
+                            && !temps.contains_key(&label) =>
+                    {
+                        let mut reads = false;
+                        visit_locals(value, &mut |l, _| reads |= l == label);
+                        if !reads {
+                            temps.insert(label, (pos, pos + len - 1));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            visit_locals(e, &mut |label, _| seen.push(label));
+        }
+    });
+
+    return temps;
+}
+
+/// Whether `op` writes to `var` without reading it first
+fn is_plain_store(op: &TCOpcode, var: u32) -> bool {
+    let (target, value) = match op.kind {
+        TCOpcodeKind::Expr(TCExpr {
+            kind: TCExprKind::Assign { target, value },
+            ..
+        }) => (target, value),
+        _ => return false,
+    };
+
+    match target.kind {
+        TCAssignTargetKind::LocalIdent { label } if label == var => {}
+        _ => return false,
+    }
+
+    let mut reads = false;
+    visit_locals(value, &mut |label, _| reads |= label == var);
+    return !reads;
+}
+
+/// Calls `f` with every local `expr` mentions, and whether it takes its address
+fn visit_locals(expr: &TCExpr, f: &mut impl FnMut(u32, bool)) {
+    visit(expr, &mut |e| {
+        let (target, address_taken) = match &e.kind {
+            TCExprKind::LocalIdent { label } => return f(*label, e.ty.is_array()),
+            TCExprKind::Assign { target, .. } | TCExprKind::MutAssign { target, .. } => {
+                (target, false)
+            }
+            TCExprKind::PostIncr { value, .. } | TCExprKind::PostDecr { value, .. } => {
+                (value, false)
+            }
+            TCExprKind::Ref(target) => (target, true),
+
+            TCExprKind::Uninit
+            | TCExprKind::I8Lit(_)
+            | TCExprKind::U8Lit(_)
+            | TCExprKind::I16Lit(_)
+            | TCExprKind::U16Lit(_)
+            | TCExprKind::I32Lit(_)
+            | TCExprKind::U32Lit(_)
+            | TCExprKind::I64Lit(_)
+            | TCExprKind::U64Lit(_)
+            | TCExprKind::F32Lit(_)
+            | TCExprKind::F64Lit(_)
+            | TCExprKind::StringLit(_)
+            | TCExprKind::GlobalIdent { .. }
+            | TCExprKind::FunctionIdent { .. }
+            | TCExprKind::TypePun(_)
+            | TCExprKind::ArrayInit { .. }
+            | TCExprKind::StructLit { .. }
+            | TCExprKind::ParenList(_)
+            | TCExprKind::BinOp { .. }
+            | TCExprKind::UnaryOp { .. }
+            | TCExprKind::Conv { .. }
+            | TCExprKind::Ternary { .. }
+            | TCExprKind::Member { .. }
+            | TCExprKind::PtrMember { .. }
+            | TCExprKind::Deref(_)
+            | TCExprKind::Call { .. }
+            | TCExprKind::Builtin(_)
+            | TCExprKind::StmtExpr { .. } => return,
+        };
+
+        if let TCAssignTargetKind::LocalIdent { label } = target.kind {
+            f(label, address_taken);
+        }
+    });
+}
+
+/// Calls `f` on `expr` and each of its subexpressions
+pub fn visit(expr: &TCExpr, f: &mut impl FnMut(&TCExpr)) {
+    f(expr);
+
+    let target = |t: &TCAssignTarget, f: &mut _| {
+        if let TCAssignTargetKind::Ptr(ptr) = t.kind {
+            visit(ptr, f);
+        }
+    };
+
+    match &expr.kind {
+        TCExprKind::TypePun(e) | TCExprKind::Deref(e) => visit(e, f),
+        TCExprKind::ArrayInit { elems, elem_ty } => {
+            for &(kind, loc) in *elems {
+                let ty = *elem_ty;
+                visit(&TCExpr { kind, ty, loc }, f);
+            }
+        }
+        TCExprKind::StructLit { fields: exprs, .. } | TCExprKind::ParenList(exprs) => {
+            for e in *exprs {
+                visit(e, f);
+            }
+        }
+        TCExprKind::BinOp { left, right, .. } => {
+            visit(left, f);
+            visit(right, f);
+        }
+        TCExprKind::UnaryOp { operand: e, .. } | TCExprKind::Conv { expr: e, .. } => visit(e, f),
+        TCExprKind::Assign { target: t, value }
+        | TCExprKind::MutAssign {
+            target: t, value, ..
+        } => {
+            target(t, f);
+            visit(value, f);
+        }
+        TCExprKind::PostIncr { value: t, .. } | TCExprKind::PostDecr { value: t, .. } => {
+            target(t, f)
+        }
+        TCExprKind::Ternary {
+            condition,
+            if_true,
+            if_false,
+            ..
+        } => {
+            visit(condition, f);
+            visit(if_true, f);
+            visit(if_false, f);
+        }
+        TCExprKind::Member { base, .. } | TCExprKind::PtrMember { base, .. } => visit(base, f),
+        TCExprKind::Ref(t) => target(t, f),
+        TCExprKind::Call { func, params } => {
+            visit(func, f);
+            for param in *params {
+                visit(param, f);
+            }
+        }
+        TCExprKind::Builtin(TCBuiltin::Push(e)) => visit(e, f),
+
+        // The ops of a statement expression aren't part of the expression
+        TCExprKind::Builtin(TCBuiltin::Opcode(_)) | TCExprKind::StmtExpr { .. } => {}
+        TCExprKind::Uninit
+        | TCExprKind::I8Lit(_)
+        | TCExprKind::U8Lit(_)
+        | TCExprKind::I16Lit(_)
+        | TCExprKind::U16Lit(_)
+        | TCExprKind::I32Lit(_)
+        | TCExprKind::U32Lit(_)
+        | TCExprKind::I64Lit(_)
+        | TCExprKind::U64Lit(_)
+        | TCExprKind::F32Lit(_)
+        | TCExprKind::F64Lit(_)
+        | TCExprKind::StringLit(_)
+        | TCExprKind::LocalIdent { .. }
+        | TCExprKind::GlobalIdent { .. }
+        | TCExprKind::FunctionIdent { .. } => {}
+    }
+}
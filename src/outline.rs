@@ -0,0 +1,170 @@
+//! Outline of the functions, structs, typedefs, and globals declared in a file,
+//! behind `tci outline`. Editors use it to list symbols, and grading scripts to
+//! check that the functions an assignment asks for exist with the right
+//! signatures.
+
+use crate::ast::*;
+use crate::filedb::*;
+use crate::formatter::*;
+use crate::lexer::*;
+use crate::parser::*;
+use crate::util::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutlineKind {
+    Function,
+    Prototype, // a function declared without a body
+    Struct,
+    Union,
+    Typedef,
+    Global,
+}
+
+impl OutlineKind {
+    pub fn name(self) -> &'static str {
+        return match self {
+            OutlineKind::Function => "function",
+            OutlineKind::Prototype => "prototype",
+            OutlineKind::Struct => "struct",
+            OutlineKind::Union => "union",
+            OutlineKind::Typedef => "typedef",
+            OutlineKind::Global => "global",
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct OutlineItem {
+    pub kind: OutlineKind,
+    pub name: String,
+    pub signature: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Outline {
+    pub file: String,
+    pub items: Vec<OutlineItem>,
+}
+
+impl Outline {
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string(self).unwrap();
+    }
+
+    /// One item per line, like `main.c:3:1: function int main()`
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for item in &self.items {
+            out += &format!(
+                "{}:{}:{}: {} {}\n",
+                self.file,
+                item.line,
+                item.column,
+                item.kind.name(),
+                item.signature
+            );
+        }
+
+        return out;
+    }
+}
+
+/// Lists what `file` declares at the top level, in source order. Declarations
+/// that come from headers it includes are left out.
+pub fn outline(files: &FileDb, file: u32) -> Result<Outline, Vec<Error>> {
+    let mut lexer = Lexer::new(files);
+    let (id, tokens) = lexer.lex(file).map_err(|e| vec![e])?;
+    let env = parse(id, tokens)?;
+
+    let source = files.source(file).unwrap();
+    let mut sigs = Signatures::new(source, file, &lexer.symbols);
+    let mut items = Vec::new();
+    let mut add = |kind, name: &str, signature, loc: CodeLoc| {
+        let location = files.location(file, loc.start as usize).unwrap();
+        items.push(OutlineItem {
+            kind,
+            name: name.to_string(),
+            signature,
+            line: location.line_number,
+            column: location.column_number,
+        });
+    };
+
+    for stmt in env.tree.iter().filter(|stmt| stmt.loc.file == file) {
+        let decl = match &stmt.kind {
+            GlobalStatementKind::Declaration(decl) => decl,
+            GlobalStatementKind::FunctionDefinition(func) => {
+                let name = lexer.symbols.to_str(func.ident).unwrap();
+                add(OutlineKind::Function, name, sigs.function(func), func.loc);
+                continue;
+            }
+            GlobalStatementKind::Pragma(_) => continue,
+        };
+
+        let mut is_typedef = false;
+        for spec in decl.specifiers {
+            let ty = match spec.kind {
+                DeclarationSpecifierKind::Typedef => {
+                    is_typedef = true;
+                    continue;
+                }
+                DeclarationSpecifierKind::TypeSpecifier(ty) => ty,
+                _ => continue,
+            };
+
+            let (kind, st) = match ty {
+                TypeSpecifier::Struct(st) => (OutlineKind::Struct, st),
+                TypeSpecifier::Union(st) => (OutlineKind::Union, st),
+                _ => continue,
+            };
+
+            if let StructTypeKind::NamedDecl { ident, .. } = st.kind {
+                let name = lexer.symbols.to_str(ident).unwrap();
+                add(kind, name, sigs.type_specifier(&ty, spec.loc), st.loc);
+            }
+        }
+
+        for init in decl.declarators {
+            let (name, is_function) = match declarator_name(&init.declarator) {
+                Some(found) => found,
+                None => continue,
+            };
+
+            let kind = match (is_typedef, is_function) {
+                (true, _) => OutlineKind::Typedef,
+                (false, true) => OutlineKind::Prototype,
+                (false, false) => OutlineKind::Global,
+            };
+
+            let name = lexer.symbols.to_str(name).unwrap();
+            let signature = sigs.declarator(decl.specifiers, &init.declarator);
+            add(kind, name, signature, decl.loc);
+        }
+    }
+
+    return Ok(Outline {
+        file: files.name(file).unwrap().to_string(),
+        items,
+    });
+}
+
+/// The name a declarator declares, and whether it's a function's name rather
+/// than e.g. a function pointer's
+fn declarator_name(decl: &Declarator) -> Option<(u32, bool)> {
+    return match decl.kind {
+        DeclaratorKind::Abstract => None,
+        DeclaratorKind::Declarator(inner) => Some((declarator_name(inner)?.0, false)),
+        DeclaratorKind::Identifier(id) => {
+            let is_function = match decl.derived.first().map(|d| d.kind) {
+                Some(DerivedDeclaratorKind::Function(_)) => true,
+                Some(DerivedDeclaratorKind::EmptyFunction) => true,
+                _ => false,
+            };
+
+            Some((id, is_function))
+        }
+    };
+}
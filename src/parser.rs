@@ -1,5 +1,6 @@
 use crate::ast::*;
 use crate::buckets::*;
+use crate::interner::*;
 use crate::lexer::*;
 use crate::util::*;
 use core::cell::RefCell;
@@ -16,7 +17,7 @@ pub enum LiteralType {
 pub struct ParseEnv {
     pub file: u32,
     pub symbol_is_type: RefCell<Vec<HashMap<u32, bool>>>, // true is type
-    pub locs: Vec<CodeLoc>,
+    pub tokens: TokenBuf,
     pub buckets: BucketListFactory,
     pub tree: Vec<GlobalStatement>,
 }
@@ -28,17 +29,21 @@ impl Drop for ParseEnv {
 }
 
 impl ParseEnv {
-    pub fn new(file: u32, locs: Vec<CodeLoc>) -> Self {
+    pub fn new(file: u32, tokens: TokenBuf) -> Self {
         Self {
             file,
             // TODO This is a hack to work around stuff in rust-peg
             symbol_is_type: RefCell::new(vec![HashMap::new()]),
-            locs,
+            tokens,
             tree: Vec::new(),
             buckets: BucketListFactory::new(),
         }
     }
 
+    pub fn loc(&self, pos: usize) -> CodeLoc {
+        return self.tokens.loc(pos);
+    }
+
     pub fn enter_scope(&self) {
         self.symbol_is_type.borrow_mut().push(HashMap::new());
     }
@@ -85,28 +90,448 @@ pub fn concat<E>(mut a: Vec<E>, b: Vec<E>) -> Vec<E> {
     return a;
 }
 
-pub fn parse(file: u32, toks: Vec<TokenKind>, locs: Vec<CodeLoc>) -> Result<ParseEnv, Error> {
-    let mut parser = ParseEnv::new(file, locs);
-    match c_parser::translation_unit(&toks, &mut parser) {
-        Ok(tree) => {
-            parser.tree = tree;
+pub fn parse(file: u32, tokens: TokenBuf) -> Result<ParseEnv, Vec<Error>> {
+    let mut parser = ParseEnv::new(file, tokens);
+    let mut errors = Vec::new();
+    let mut tree = Vec::new();
+    let mut len = parser.tokens.len();
+    let mut recovered_at = None; // where the last missing token was inserted
+
+    // Parse as many global statements as we can; when one fails, report it and
+    // skip to the end of the statement (the next ';' or '}' at the same nesting
+    // level) before trying again. Inside a function body, only the statement
+    // that failed is skipped, so later ones in the same function are checked too.
+    loop {
+        let begin = parser.tokens.start;
+        let (stmts, end) = match c_parser::translation_unit_prefix(&parser.tokens, &parser) {
+            Ok(prefix) => prefix,
+            Err(err) => (Vec::new(), begin),
+        };
+
+        tree.extend(stmts);
+        if end >= len {
+            break;
         }
+
+        parser.symbol_is_type.borrow_mut().truncate(1);
+        parser.tokens.start = end;
+        let err_pos = match c_parser::translation_unit(&parser.tokens, &parser) {
+            Ok(_) => len, // shouldn't happen
+            Err(err) => match explain_parse_error(&parser.tokens, end, err.location) {
+                Some((error, skip_to)) => {
+                    errors.push(error);
+                    skip_to
+                }
+                None if recovered_at.map(|r| r < err.location).unwrap_or(true) => {
+                    // Pretend the missing token is there and parse the statement
+                    // again, so that one typo doesn't hide the rest of the function
+                    let recovery = insert_missing(&mut parser.tokens, end, &err);
+                    if let Some((error, at)) = recovery {
+                        errors.push(error);
+                        recovered_at = Some(at);
+                        len = parser.tokens.len();
+                        parser.symbol_is_type.borrow_mut().truncate(1);
+                        parser.tokens.start = end;
+                        continue;
+                    }
+
+                    let pos = core::cmp::min(err.location, len - 1);
+                    errors.push(error!(
+                        &format!("expected set: {}", err.expected),
+                        parser.loc(pos),
+                        format!(
+                            "unexpected token '{:?}' found here",
+                            parser.tokens.kind(pos)
+                        )
+                    ));
+
+                    if skip_statement(&mut parser.tokens, end, err.location) {
+                        parser.symbol_is_type.borrow_mut().truncate(1);
+                        parser.tokens.start = end;
+                        continue;
+                    }
+
+                    err.location
+                }
+                None => {
+                    let pos = core::cmp::min(err.location, len - 1);
+                    errors.push(error!(
+                        &format!("expected set: {}", err.expected),
+                        parser.loc(pos),
+                        format!(
+                            "unexpected token '{:?}' found here",
+                            parser.tokens.kind(pos)
+                        )
+                    ));
+
+                    if skip_statement(&mut parser.tokens, end, err.location) {
+                        parser.symbol_is_type.borrow_mut().truncate(1);
+                        parser.tokens.start = end;
+                        continue;
+                    }
+
+                    err.location
+                }
+            },
+        };
+
+        parser.symbol_is_type.borrow_mut().truncate(1);
+        parser.tokens.start = sync_point(&parser.tokens, end, err_pos);
+    }
+
+    parser.tokens.start = 0;
+    if errors.len() != 0 {
+        return Err(errors);
+    }
+
+    parser.tree = tree;
+    return Ok(parser);
+}
+
+/// Parses a lone expression, e.g. one typed into the debugger. `typenames` are the
+/// global typedef names of the translation unit the expression is evaluated in.
+pub fn parse_expr(
+    file: u32,
+    tokens: TokenBuf,
+    typenames: HashMap<u32, bool>,
+) -> Result<(ParseEnv, Expr), Error> {
+    let parser = ParseEnv::new(file, tokens);
+    *parser.symbol_is_type.borrow_mut() = vec![typenames];
+    if parser.tokens.len() == 0 {
+        return Err(error!("expected an expression"));
+    }
+
+    let expr = match c_parser::expr_only(&parser.tokens, &parser) {
+        Ok(expr) => expr,
         Err(err) => {
+            let pos = core::cmp::min(err.location, parser.tokens.len().saturating_sub(1));
             return Err(error!(
                 &format!("expected set: {}", err.expected),
-                parser.locs[err.location],
-                format!("unexpected token '{:?}' found here", toks[err.location])
+                parser.loc(pos),
+                format!(
+                    "unexpected token '{:?}' found here",
+                    parser.tokens.kind(pos)
+                )
             ));
         }
+    };
+
+    return Ok((parser, expr));
+}
+
+/// Inserts a `;` or `}` that the statement starting at `begin` is missing, if
+/// that's what `err` looks like, and returns the error for it along with where
+/// the token went. A `;` is only inserted before a token that's separated from
+/// the previous one by whitespace, which is where a statement would usually end.
+pub fn insert_missing(
+    tokens: &mut TokenBuf,
+    begin: usize,
+    err: &peg::error::ParseError<usize>,
+) -> Option<(Error, usize)> {
+    let expected = |name| err.expected.tokens().any(|t| t == name);
+    let is_space = |idx| tokens.kind(idx) == TokenKind::Whitespace;
+    let at = err.location;
+
+    if (at..tokens.len()).all(is_space) && expected("RBrace") {
+        let mut open = Vec::new();
+        for idx in begin..tokens.len() {
+            match tokens.kind(idx) {
+                TokenKind::LBrace => open.push(idx),
+                TokenKind::RBrace => {
+                    open.pop();
+                }
+                _ => {}
+            }
+        }
+
+        let brace = *open.last()?;
+        let error = error!(
+            "expected '}' at end of file",
+            tokens.loc(brace),
+            "this block is never closed"
+        );
+
+        let at = tokens.len();
+        tokens.insert(at, TokenKind::RBrace, tokens.loc(at - 1));
+        return Some((error, at));
     }
 
-    return Ok(parser);
+    if at == 0 || at >= tokens.len() || !is_space(at - 1) || !expected("Semicolon") {
+        return None;
+    }
+
+    let prev = (begin..at).rev().find(|&idx| !is_space(idx))?;
+    let message = match tokens.kind(prev) {
+        TokenKind::RBrace => "expected ';' after declaration",
+        _ => "expected ';' after expression",
+    };
+    let error = error!(message, tokens.loc(prev), "add a ';' after this");
+
+    tokens.insert(at, TokenKind::Semicolon, tokens.loc(prev));
+    return Some((error, at));
+}
+
+/// Error for a parse failure that has a more specific explanation than the
+/// expected set, along with where to start looking for the next statement
+fn explain_parse_error(tokens: &TokenBuf, begin: usize, err: usize) -> Option<(Error, usize)> {
+    if let Some((error, body)) = knr_definition(tokens, begin, err) {
+        return Some((error, body)); // skip the whole definition instead of just the first declaration
+    }
+
+    return gnu_extension(tokens, begin, err).map(|error| (error, err));
+}
+
+/// Error for a GNU extension used without `--std=gnu11`, if that's why parsing
+/// the global statement starting at `begin` stopped at `err`. Without the flag,
+/// `({` is a syntax error and `typeof(x)` looks like a call, so the generic
+/// errors for these are confusing.
+pub fn gnu_extension(tokens: &TokenBuf, begin: usize, err: usize) -> Option<Error> {
+    if tokens.gnu_extensions {
+        return None;
+    }
+
+    let is_typeof = |idx| match tokens.kind(idx) {
+        TokenKind::Ident(id) => {
+            id == BuiltinSymbol::Typeof as u32 || id == BuiltinSymbol::GnuTypeof as u32
+        }
+        _ => false,
+    };
+
+    let end = core::cmp::min(err + 1, tokens.len());
+    if let Some(idx) = (begin..end).find(|&idx| is_typeof(idx)) {
+        let message = "`typeof` is a GNU extension";
+        return Some(error!(
+            message,
+            tokens.loc(idx),
+            "compile with `--std=gnu11` to use it"
+        ));
+    }
+
+    if err >= tokens.len() || tokens.kind(err) != TokenKind::LBrace {
+        return None;
+    }
+
+    let mut before = (0..err).rev().map(|idx| tokens.kind(idx));
+    let mut before = before.filter(|&kind| kind != TokenKind::Whitespace);
+    if before.next() != Some(TokenKind::LParen) {
+        return None;
+    }
+
+    // After a name or a closing bracket, the `(` starts a call or a parameter list,
+    // like in `int g( { }`, and `{` can't start an expression there
+    match before.next() {
+        Some(TokenKind::Ident(_)) | Some(TokenKind::RParen) | Some(TokenKind::RBracket) => {
+            return None;
+        }
+        _ => {}
+    }
+
+    return Some(error!(
+        "statement expressions are a GNU extension",
+        tokens.loc(err),
+        "compile with `--std=gnu11` to use them"
+    ));
+}
+
+/// Error for an old-style definition like `int f(a, b) int a; int b; { ... }`,
+/// if the global statement starting at `begin` is one, along with the index of
+/// the function body. TCI only supports prototypes, and the generic parse error
+/// for these doesn't say why.
+pub fn knr_definition(tokens: &TokenBuf, begin: usize, err: usize) -> Option<(Error, usize)> {
+    let mut toks = (begin..tokens.len()).filter(|&idx| tokens.kind(idx) != TokenKind::Whitespace);
+    let mut prev = None;
+    let lparen = loop {
+        let idx = toks.next()?;
+        match tokens.kind(idx) {
+            TokenKind::LParen => break idx,
+            TokenKind::Semicolon | TokenKind::LBrace | TokenKind::Eq => return None,
+            kind => prev = Some(kind),
+        }
+    };
+
+    match prev {
+        Some(TokenKind::Ident(_)) => {}
+        _ => return None,
+    }
+
+    let rparen = loop {
+        match tokens.kind(toks.next()?) {
+            TokenKind::Ident(_) => {}
+            _ => return None,
+        }
+
+        let idx = toks.next()?;
+        match tokens.kind(idx) {
+            TokenKind::Comma => {}
+            TokenKind::RParen => break idx,
+            _ => return None,
+        }
+    };
+
+    let after = toks.next()?;
+    if err < lparen || tokens.kind(after) == TokenKind::Semicolon {
+        return None;
+    }
+
+    let mut error = error!(
+        "old-style (K&R) function definitions aren't supported",
+        l_from(tokens.loc(lparen), tokens.loc(rparen)),
+        "parameters need their types here, like `int f(int a, char *b)`"
+    );
+
+    let (mut last_decl, mut body) = (None, tokens.len());
+    for idx in core::iter::once(after).chain(toks) {
+        match tokens.kind(idx) {
+            TokenKind::LBrace => {
+                body = idx;
+                break;
+            }
+            _ => last_decl = Some(idx),
+        }
+    }
+
+    if let Some(last) = last_decl {
+        error.sections.push(ErrorSection {
+            location: l_from(tokens.loc(after), tokens.loc(last)),
+            message: "move these declarations into the parameter list".to_string(),
+        });
+    }
+
+    return Some((error, body));
+}
+
+/// Turns the statement around `err` into whitespace, if `err` is inside the braces
+/// of the global statement starting at `begin`. The statement goes back to the
+/// last `;`, `{` or `}` outside of parentheses, and up to the next `;`, or to the
+/// next brace, which is kept. Returns false if there's nothing to skip.
+pub fn skip_statement(tokens: &mut TokenBuf, begin: usize, err: usize) -> bool {
+    if err >= tokens.len() || tokens.kind(err) == TokenKind::Whitespace {
+        return false;
+    }
+
+    let mut braces = 0i32;
+    for idx in begin..err {
+        match tokens.kind(idx) {
+            TokenKind::LBrace => braces += 1,
+            TokenKind::RBrace => braces -= 1,
+            _ => {}
+        }
+    }
+
+    if braces <= 0 {
+        return false;
+    }
+
+    let (mut start, mut parens) = (begin, 0);
+    for idx in (begin..err).rev() {
+        match tokens.kind(idx) {
+            TokenKind::RParen | TokenKind::RBracket => parens += 1,
+            TokenKind::LParen | TokenKind::LBracket if parens > 0 => parens -= 1,
+            TokenKind::Semicolon if parens > 0 => {}
+            TokenKind::Semicolon | TokenKind::LBrace | TokenKind::RBrace => {
+                start = idx + 1;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    let (mut end, mut parens) = (tokens.len(), 0);
+    for idx in err..tokens.len() {
+        match tokens.kind(idx) {
+            TokenKind::LParen | TokenKind::LBracket => parens += 1,
+            TokenKind::RParen | TokenKind::RBracket => parens -= 1,
+            TokenKind::Semicolon if parens > 0 => {}
+            TokenKind::Semicolon => {
+                end = idx + 1;
+                break;
+            }
+            TokenKind::LBrace | TokenKind::RBrace => {
+                end = idx;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    if (start..end).all(|idx| tokens.kind(idx) == TokenKind::Whitespace) {
+        return false;
+    }
+
+    tokens.blank(start..end);
+    return true;
+}
+
+/// Index of the token after the end of the global statement that starts at
+/// `begin` and has an error at `err`. Parentheses and brackets are only counted
+/// between braces, so one that's never closed can't hide the rest of the file.
+pub fn sync_point(tokens: &TokenBuf, begin: usize, err: usize) -> usize {
+    let (mut braces, mut parens) = (0i32, 0i32);
+    for idx in begin..tokens.len() {
+        let tok = tokens.kind(idx);
+        match tok {
+            TokenKind::LBrace => {
+                braces += 1;
+                parens = 0;
+            }
+            TokenKind::RBrace => {
+                braces -= 1;
+                parens = 0;
+            }
+            TokenKind::LParen | TokenKind::LBracket => parens += 1,
+            TokenKind::RParen | TokenKind::RBracket => parens -= 1,
+            _ => {}
+        }
+
+        if idx < err || braces > 0 || parens > 0 {
+            continue;
+        }
+
+        match tok {
+            TokenKind::Semicolon | TokenKind::RBrace => return idx + 1,
+            _ => {}
+        }
+    }
+
+    return tokens.len();
+}
+
+impl peg::Parse for TokenBuf {
+    type PositionRepr = usize;
+
+    fn start(&self) -> usize {
+        return self.start;
+    }
+
+    fn position_repr(&self, pos: usize) -> usize {
+        return pos;
+    }
+}
+
+impl peg::ParseElem for TokenBuf {
+    type Element = TokenKind;
+
+    fn parse_elem(&self, pos: usize) -> peg::RuleResult<TokenKind> {
+        if pos < self.len() {
+            return peg::RuleResult::Matched(pos + 1, self.kind(pos));
+        }
+
+        return peg::RuleResult::Failed;
+    }
+}
+
+impl TokenBuf {
+    /// Matches the rest of the tokens without looking at them, like `[_]*` but
+    /// without the cost of going through them every time parsing stops early
+    fn skip_rest(&self, pos: usize) -> peg::RuleResult<()> {
+        return peg::RuleResult::Matched(self.len(), ());
+    }
 }
 
 peg::parser! {
 
 // Translated from https://github.com/vickenty/lang-c/blob/master/grammar.rustpeg
-pub grammar c_parser(env: &ParseEnv) for [TokenKind] {
+pub grammar c_parser(env: &ParseEnv) for TokenBuf {
 
 use TokenKind::*;
 use NumChar::*;
@@ -117,12 +542,12 @@ rule list0<E>(x: rule<E>) -> (Vec<E>, CodeLoc) = pos:position!() v:(x() ** w())
     if pos == pos2 {
         (v, NO_FILE)
     } else {
-        (v, l_from(env.locs[pos], env.locs[pos2 - 1]))
+        (v, l_from(env.loc(pos), env.loc(pos2 - 1)))
     }
 }
 
 rule list1<E>(x: rule<E>) -> (Vec<E>, CodeLoc) = pos:position!() v:(x() ++ w()) pos2:position!() {
-    (v, l_from(env.locs[pos], env.locs[pos2 - 1]))
+    (v, l_from(env.loc(pos), env.loc(pos2 - 1)))
 }
 
 rule cs0<E>(x: rule<E>) -> (Vec<E>, CodeLoc) =
@@ -130,13 +555,13 @@ rule cs0<E>(x: rule<E>) -> (Vec<E>, CodeLoc) =
     if pos == pos2 {
         (v, NO_FILE)
     } else {
-        (v, l_from(env.locs[pos], env.locs[pos2 - 1]))
+        (v, l_from(env.loc(pos), env.loc(pos2 - 1)))
     }
 }
 
 rule cs1<E>(x: rule<E>) -> (Vec<E>, CodeLoc) =
     pos:position!() v:(x() ++ (w() [Comma] w())) pos2:position!() {
-    (v, l_from(env.locs[pos], env.locs[pos2 - 1]))
+    (v, l_from(env.loc(pos), env.loc(pos2 - 1)))
 }
 
 rule list_010<E>(b: rule<E>, s: rule<E>, a: rule<E>) -> (Vec<E>, CodeLoc) =
@@ -144,7 +569,7 @@ rule list_010<E>(b: rule<E>, s: rule<E>, a: rule<E>) -> (Vec<E>, CodeLoc) =
 {
     let (mut before, mut begin_loc) = before;
     let (mut after, mut end_loc) = after;
-    let single_loc = env.locs[pos];
+    let single_loc = env.loc(pos);
     if begin_loc == NO_FILE {
         begin_loc = single_loc;
     }
@@ -169,20 +594,32 @@ rule list_ge1_n<E>(a: rule<E>, b: rule<E>) -> (Vec<E>, CodeLoc) = v:list_010(<b(
 
 rule scoped<E>(e: rule<E>) -> E = ({ env.enter_scope(); }) e:e()? {? env.leave_scope(); e.ok_or("") }
 
-rule pragma() -> (&'static str, CodeLoc) = pos:position!() n:$[Pragma(_)] {
-    match n[0] {
-        Pragma(n) => (n.as_str(), env.locs[pos]),
+rule pragma() -> (&'static str, CodeLoc) = pos:position!() [Pragma(_)] {
+    match env.tokens.kind(pos) {
+        Pragma(n) => (n.as_str(), env.loc(pos)),
         _ => unreachable!(),
     }
 }
 
-rule raw_ident() -> (u32, CodeLoc) = pos:position!() n:$[Ident(_)] {
-    match n[0] {
-        Ident(n) => (n, env.locs[pos]),
+rule raw_ident() -> (u32, CodeLoc) = pos:position!() [Ident(_)] {
+    match env.tokens.kind(pos) {
+        Ident(n) => (n, env.loc(pos)),
         _ => unreachable!(),
     }
 }
 
+// Only matches when GNU extensions are on
+rule gnu() = {? if env.tokens.gnu_extensions { Ok(()) } else { Err("<GNU extension>") } }
+
+rule typeof_keyword() = gnu() i:raw_ident() {?
+    let (typeof_, gnu_typeof) = (BuiltinSymbol::Typeof as u32, BuiltinSymbol::GnuTypeof as u32);
+    if i.0 == typeof_ || i.0 == gnu_typeof {
+        Ok(())
+    } else {
+        Err("typeof")
+    }
+}
+
 rule ident() -> (u32, CodeLoc) = i:raw_ident() {?
     if !env.is_typename(i.0) {
         Ok(i)
@@ -287,11 +724,11 @@ rule dec_number_type() -> LiteralType = parts:dec_number_type_part()* {?
 rule float_number() -> Expr =
     pos:position!() bef:float_number_lit_seq() [IntChar(_E)]
     dash:[Dash]? aft:float_number_lit_seq() [IntChar(_F)] pos2:position!() {?
-        let loc = l_from(env.locs[pos], env.locs[pos2 - 1]);
+        let loc = l_from(env.loc(pos), env.loc(pos2 - 1));
 
-        let mult = if dash.is_some() { -1f32 } else { 1f32 };
-        let opt = str::parse::<f32>(&bef).ok().zip(str::parse::<f32>(&aft).ok());
-        opt.map(|(bef, aft)| bef.powf(mult * aft)).ok_or("exponential").map(|float| {
+        let sign = if dash.is_some() { "-" } else { "" };
+        let lit = format!("{}e{}{}", bef, sign, aft);
+        str::parse::<f32>(&lit).map_err(|_| "exponential").map(|float| {
             Expr {
                 kind: ExprKind::FloatLit(float),
                 loc,
@@ -300,11 +737,11 @@ rule float_number() -> Expr =
     } /
     pos:position!() bef:float_number_lit_seq() [IntChar(_E)]
     dash:[Dash]? aft:float_number_lit_seq() pos2:position!() {?
-        let loc = l_from(env.locs[pos], env.locs[pos2 - 1]);
+        let loc = l_from(env.loc(pos), env.loc(pos2 - 1));
 
-        let mult = if dash.is_some() { -1f64 } else { 1f64 };
-        let opt = str::parse::<f64>(&bef).ok().zip(str::parse::<f64>(&aft).ok());
-        opt.map(|(bef, aft)| bef.powf(mult * aft)).ok_or("exponential").map(|double| {
+        let sign = if dash.is_some() { "-" } else { "" };
+        let lit = format!("{}e{}{}", bef, sign, aft);
+        str::parse::<f64>(&lit).map_err(|_| "exponential").map(|double| {
             Expr {
                 kind: ExprKind::DoubleLit(double),
                 loc,
@@ -312,7 +749,7 @@ rule float_number() -> Expr =
         })
     } /
     pos:position!() n:float_number_lit_seq() [IntChar(_F)] pos2:position!() {?
-        let loc = l_from(env.locs[pos], env.locs[pos2 - 1]);
+        let loc = l_from(env.loc(pos), env.loc(pos2 - 1));
 
         str::parse::<f32>(&n).map_err(|e| "float constant").map(|float| {
             Expr {
@@ -322,7 +759,7 @@ rule float_number() -> Expr =
         })
     } /
     pos:position!() n:float_number_lit_seq_strict() pos2:position!() {?
-        let loc = l_from(env.locs[pos], env.locs[pos2 - 1]);
+        let loc = l_from(env.loc(pos), env.loc(pos2 - 1));
 
         str::parse::<f64>(&n).map_err(|e| "double constant").map(|double| {
             Expr {
@@ -335,7 +772,7 @@ rule float_number() -> Expr =
 rule dec_number() -> Expr =
     pos:position!() [IntChar(_0)] [IntChar(_X)] n:hex_number_lit_seq()
     ty:dec_number_type() pos2:position!() {?
-        let loc = l_from(env.locs[pos], env.locs[pos2 - 1]);
+        let loc = l_from(env.loc(pos), env.loc(pos2 - 1));
 
         let kind = match ty {
             LiteralType::Int => i32::from_str_radix(&n ,16).map(|n| ExprKind::IntLit(n)),
@@ -349,7 +786,7 @@ rule dec_number() -> Expr =
         kind.map_err(|e| "hex integer constant").map(|kind| Expr { kind, loc })
     } /
     pos:position!() n:number_lit_seq() ty:dec_number_type() pos2:position!() {?
-        let loc = l_from(env.locs[pos], env.locs[pos2 - 1]);
+        let loc = l_from(env.loc(pos), env.loc(pos2 - 1));
 
         let kind = match ty {
             LiteralType::Int => i32::from_str_radix(&n ,10).map(|n| ExprKind::IntLit(n)),
@@ -363,23 +800,20 @@ rule dec_number() -> Expr =
         kind.map_err(|e| "integer constant").map(|kind| Expr { kind, loc })
     }
 
-rule char() -> (i8, CodeLoc) = pos:position!() n:$[CharLit(_)] {
-    match n[0] {
-        CharLit(n) => (n, env.locs[pos]),
+rule char() -> (i8, CodeLoc) = pos:position!() [CharLit(_)] {
+    match env.tokens.kind(pos) {
+        CharLit(n) => (n, env.loc(pos)),
         _ => unreachable!(),
     }
 }
 
-rule string() -> (&'static str, CodeLoc) = pos:position!() n:$([StringLit(_)] ++ w()) pos2:position!() {
+rule string() -> (&'static str, CodeLoc) = pos:position!() ([StringLit(_)] ++ w()) pos2:position!() {
     let mut string = String::new();
-    let loc = l_from(env.locs[pos], env.locs[pos2 - 1]);
-    for token in n {
-        let s = match token {
-            StringLit(s) => s,
-            _ => unreachable!(),
-        };
-
-        string.push_str(s.as_str());
+    let loc = l_from(env.loc(pos), env.loc(pos2 - 1));
+    for idx in pos..pos2 {
+        if let StringLit(s) = env.tokens.kind(idx) {
+            string.push_str(s.as_str());
+        }
     }
 
     (env.buckets.add_str(&string), loc)
@@ -403,6 +837,17 @@ rule constant_expr() -> Expr =
             kind: ExprKind::CharLit(n),
             loc,
         }
+    } /
+    pos:position!() [MultiCharLit(_)] {
+        let n = match env.tokens.kind(pos) {
+            MultiCharLit(n) => n,
+            _ => unreachable!(),
+        };
+
+        Expr {
+            kind: ExprKind::IntLit(n),
+            loc: env.loc(pos),
+        }
     }
 
 rule atom() -> Expr =
@@ -415,15 +860,21 @@ rule atom() -> Expr =
             loc,
         }
     } /
+    pos:position!() [LParen] w() gnu() b:scoped(<compound_statement()>) w() pos2:position!() [RParen] {
+        Expr {
+            kind: ExprKind::StmtExpr(b),
+            loc: l_from(env.loc(pos), env.loc(pos2)),
+        }
+    } /
     pos:position!() [LParen] w() e:expr() w() pos2:position!() [RParen] {
         Expr {
             kind: e.kind,
-            loc: l_from(env.locs[pos], env.locs[pos2]),
+            loc: l_from(env.loc(pos), env.loc(pos2)),
         }
     } /
     pos:position!() [Sizeof] w() [LParen]
     w() t:type_name() w() pos2:position!() [RParen] {
-        Expr { loc: l_from(env.locs[pos], env.locs[pos2]), kind: ExprKind::SizeofTy(t)  }
+        Expr { loc: l_from(env.loc(pos), env.loc(pos2)), kind: ExprKind::SizeofTy(t)  }
     }
 
 
@@ -616,42 +1067,42 @@ rule assignment_expr() -> Expr = precedence! {
 rule cast_expr() -> Expr =
     pos:position!() [LParen] w() t:type_name() w() [RParen] w() x:cast_expr() {
         let x = env.buckets.add(x);
-        Expr { loc: l_from(env.locs[pos], x.loc), kind: ExprKind::Cast { to: t, from: x } }
+        Expr { loc: l_from(env.loc(pos), x.loc), kind: ExprKind::Cast { to: t, from: x } }
     } /
     prefix_expr()
 
 rule prefix_expr() -> Expr =
     pos:position!() [Amp] w() x:cast_expr() {
         let x = env.buckets.add(x);
-        Expr { loc: l_from(env.locs[pos], x.loc), kind: ExprKind::UnaryOp(UnaryOp::Ref, x)  }
+        Expr { loc: l_from(env.loc(pos), x.loc), kind: ExprKind::UnaryOp(UnaryOp::Ref, x)  }
     } /
     pos:position!() [Star] w() x:cast_expr() {
         let x = env.buckets.add(x);
-        Expr { loc: l_from(env.locs[pos], x.loc), kind: ExprKind::UnaryOp(UnaryOp::Deref, x)  }
+        Expr { loc: l_from(env.loc(pos), x.loc), kind: ExprKind::UnaryOp(UnaryOp::Deref, x)  }
     } /
     pos:position!() [Sizeof] w() x:prefix_expr() {
         let x = env.buckets.add(x);
-        Expr { loc: l_from(env.locs[pos], x.loc), kind: ExprKind::SizeofExpr(x)  }
+        Expr { loc: l_from(env.loc(pos), x.loc), kind: ExprKind::SizeofExpr(x)  }
     } /
     pos:position!() [Bang] w() x:cast_expr() {
         let x = env.buckets.add(x);
-        Expr { loc: l_from(env.locs[pos], x.loc), kind: ExprKind::UnaryOp(UnaryOp::BoolNot, x)  }
+        Expr { loc: l_from(env.loc(pos), x.loc), kind: ExprKind::UnaryOp(UnaryOp::BoolNot, x)  }
     } /
     pos:position!() [Tilde] w() x:cast_expr() {
         let x = env.buckets.add(x);
-        Expr { loc: l_from(env.locs[pos], x.loc), kind: ExprKind::UnaryOp(UnaryOp::BitNot, x)  }
+        Expr { loc: l_from(env.loc(pos), x.loc), kind: ExprKind::UnaryOp(UnaryOp::BitNot, x)  }
     } /
     pos:position!() [Dash] w() x:cast_expr() {
         let x = env.buckets.add(x);
-        Expr { loc: l_from(env.locs[pos], x.loc), kind: ExprKind::UnaryOp(UnaryOp::Neg, x)  }
+        Expr { loc: l_from(env.loc(pos), x.loc), kind: ExprKind::UnaryOp(UnaryOp::Neg, x)  }
     } /
     pos:position!() [DashDash] w() x:cast_expr() {
         let x = env.buckets.add(x);
-        Expr { loc: l_from(env.locs[pos], x.loc), kind: ExprKind::UnaryOp(UnaryOp::PreDecr, x)  }
+        Expr { loc: l_from(env.loc(pos), x.loc), kind: ExprKind::UnaryOp(UnaryOp::PreDecr, x)  }
     } /
     pos:position!() [PlusPlus] w() x:cast_expr() {
         let x = env.buckets.add(x);
-        Expr { loc: l_from(env.locs[pos], x.loc), kind: ExprKind::UnaryOp(UnaryOp::PreIncr, x)  }
+        Expr { loc: l_from(env.loc(pos), x.loc), kind: ExprKind::UnaryOp(UnaryOp::PreIncr, x)  }
     } /
     postfix_expr()
 
@@ -659,21 +1110,21 @@ rule postfix_expr() -> Expr = precedence! {
     // Postfix
     x:(@) w() [LParen] w() c:cs0(<assignment_expr()>) w() pos:position!() [RParen] {
         let (c, _) = c;
-        let loc = l_from(x.loc, env.locs[pos]);
+        let loc = l_from(x.loc, env.loc(pos));
         let function = env.buckets.add(x);
         let params = env.buckets.add_array(c);
         Expr { loc, kind: ExprKind::Call { function, params } }
     }
     x:(@) w() pos:position!() [DashDash] {
-        let loc = l_from(x.loc, env.locs[pos]);
+        let loc = l_from(x.loc, env.loc(pos));
         Expr { loc, kind: ExprKind::UnaryOp(UnaryOp::PostDecr, env.buckets.add(x)) }
     }
     x:(@) w() pos:position!() [PlusPlus] {
-        let loc = l_from(x.loc, env.locs[pos]);
+        let loc = l_from(x.loc, env.loc(pos));
         Expr { loc, kind: ExprKind::UnaryOp(UnaryOp::PostIncr, env.buckets.add(x)) }
     }
     x:(@) w() [LBracket] w() y:expr() w() pos:position!() [RBracket] {
-        let loc = l_from(x.loc, env.locs[pos]);
+        let loc = l_from(x.loc, env.loc(pos));
         let (x, y) = env.buckets.add((x, y));
         Expr { loc, kind: ExprKind::BinOp(BinOp::Index, x, y) }
     }
@@ -691,6 +1142,10 @@ rule postfix_expr() -> Expr = precedence! {
     }
 
     --
+    pos:position!() [LParen] w() t:type_name() w() [RParen] w() l:initializer_list() {
+        let (items, loc) = l;
+        Expr { loc: l_from(env.loc(pos), loc), kind: ExprKind::CompoundLit { ty: t, items } }
+    }
     n:atom() { n }
 }
 
@@ -706,6 +1161,8 @@ rule expr() -> Expr = list:cs1(<assignment_expr()>) { // TODO alllocations!
     }
 }
 
+pub rule expr_only() -> Expr = w() e:expr() w() { e }
+
 pub rule declaration() -> Declaration = d:declaration1() w() [Semicolon] {
     Declaration {
         loc: d.2,
@@ -790,13 +1247,13 @@ rule decl_spec_unique_type0() -> DeclarationSpecifier =
     pos:position!() s:type_specifier_unique() pos2:position!()
 {
     DeclarationSpecifier {
-        loc: l_from(env.locs[pos], env.locs[pos2 - 1]),
+        loc: l_from(env.loc(pos), env.loc(pos2 - 1)),
         kind: DeclarationSpecifierKind::TypeSpecifier(s)
     }
 }
 
 rule decl_spec_nonunique_type0() -> DeclarationSpecifier = pos:position!() s:type_specifier_nonunique() {
-    DeclarationSpecifier { loc: env.locs[pos], kind: DeclarationSpecifierKind::TypeSpecifier(s) }
+    DeclarationSpecifier { loc: env.loc(pos), kind: DeclarationSpecifierKind::TypeSpecifier(s) }
 }
 
 rule declaration_init_declarators() -> (Vec<InitDeclarator>, CodeLoc) = cs0(<init_declarator()>)
@@ -842,19 +1299,19 @@ rule storage_class_specifier() -> DeclarationSpecifier =
     pos:position!() [Register] {
         DeclarationSpecifier {
             kind: DeclarationSpecifierKind::Register,
-            loc: env.locs[pos],
+            loc: env.loc(pos),
         }
     } /
     pos:position!() [Extern] {
         DeclarationSpecifier {
             kind: DeclarationSpecifierKind::Extern,
-            loc: env.locs[pos],
+            loc: env.loc(pos),
         }
     } /
     pos:position!() [Static] {
         DeclarationSpecifier {
             kind: DeclarationSpecifierKind::Static,
-            loc: env.locs[pos],
+            loc: env.loc(pos),
         }
     }
 
@@ -862,7 +1319,7 @@ rule storage_class_typedef() -> DeclarationSpecifier =
     pos:position!() [Typedef] {
         DeclarationSpecifier {
             kind: DeclarationSpecifierKind::Typedef,
-            loc: env.locs[pos],
+            loc: env.loc(pos),
         }
     }
 
@@ -881,14 +1338,14 @@ rule type_specifier_unique() -> TypeSpecifier =
                     ident,
                     declarations,
                 },
-                loc: l_from(env.locs[pos], loc),
+                loc: l_from(env.loc(pos), loc),
             })
         } else {
             TypeSpecifier::Struct(StructType {
                 kind: StructTypeKind::UnnamedDecl {
                     declarations,
                 },
-                loc: l_from(env.locs[pos], loc),
+                loc: l_from(env.loc(pos), loc),
             })
         }
     } /
@@ -901,14 +1358,14 @@ rule type_specifier_unique() -> TypeSpecifier =
                     ident,
                     declarations,
                 },
-                loc: l_from(env.locs[pos], loc),
+                loc: l_from(env.loc(pos), loc),
             })
         } else {
             TypeSpecifier::Union(StructType {
                 kind: StructTypeKind::UnnamedDecl {
                     declarations,
                 },
-                loc: l_from(env.locs[pos], loc),
+                loc: l_from(env.loc(pos), loc),
             })
         }
     } /
@@ -917,7 +1374,7 @@ rule type_specifier_unique() -> TypeSpecifier =
 
         TypeSpecifier::Struct(StructType {
             kind: StructTypeKind::Named(id),
-            loc: l_from(env.locs[pos], loc),
+            loc: l_from(env.loc(pos), loc),
         })
     } /
     pos:position!() [Union] w() id:raw_ident() {
@@ -925,12 +1382,18 @@ rule type_specifier_unique() -> TypeSpecifier =
 
         TypeSpecifier::Union(StructType {
             kind: StructTypeKind::Named(id),
-            loc: l_from(env.locs[pos], loc),
+            loc: l_from(env.loc(pos), loc),
         })
     } /
     t:typedef_name() {
         let (t, loc) = t;
         TypeSpecifier::Ident(t)
+    } /
+    typeof_keyword() w() [LParen] w() t:type_name() w() [RParen] {
+        TypeSpecifier::TypeofTy(env.buckets.add(t))
+    } /
+    typeof_keyword() w() [LParen] w() e:expr() w() [RParen] {
+        TypeSpecifier::TypeofExpr(env.buckets.add(e))
     }
 
 
@@ -940,7 +1403,7 @@ rule struct_body() -> (&'static [StructField], CodeLoc) =
         let (d, _) = d;
         let d = env.buckets.add_array(d);
 
-        (d, l_from(env.locs[pos], env.locs[pos2]))
+        (d, l_from(env.loc(pos), env.loc(pos2)))
     }
 
 rule struct_field() -> StructField =
@@ -951,18 +1414,40 @@ rule struct_field() -> StructField =
         StructField {
             specifiers: env.buckets.add_array(s),
             declarators: env.buckets.add_array(d),
-            loc: l_from(loc, env.locs[pos2]),
+            loc: l_from(loc, env.loc(pos2)),
         }
     }
 
 rule struct_declarator() -> StructDeclarator =
-    d:declarator() {
+    d:declarator() w() b:bit_width()? {
+        let (bit_width, loc) = match b {
+            Some(e) => (Some(&*env.buckets.add(e)), l_from(d.loc, e.loc)),
+            None => (None, d.loc),
+        };
+
         StructDeclarator {
             declarator: d,
-            loc: d.loc,
+            bit_width,
+            loc,
+        }
+    } /
+    pos:position!() e:bit_width() {
+        let loc = l_from(env.loc(pos), e.loc);
+        let declarator = Declarator {
+            kind: DeclaratorKind::Abstract,
+            derived: &[],
+            loc,
+        };
+
+        StructDeclarator {
+            declarator,
+            bit_width: Some(env.buckets.add(e)),
+            loc,
         }
     }
 
+rule bit_width() -> Expr = [Colon] w() e:assignment_expr() { e }
+
 rule type_specifier_nonunique() -> TypeSpecifier =
     pos:position!() [Char] { TypeSpecifier::Char } /
     pos:position!() [Short] { TypeSpecifier::Short } /
@@ -982,7 +1467,7 @@ rule specifier_qualifier_unique_type0() -> SpecifierQualifier =
 {
     SpecifierQualifier {
         kind: SpecifierQualifierKind::TypeSpecifier(s),
-        loc: l_from(env.locs[pos], env.locs[pos2 - 1]),
+        loc: l_from(env.loc(pos), env.loc(pos2 - 1)),
     }
 }
 
@@ -990,7 +1475,7 @@ rule specifier_qualifier_nonunique_type0() -> SpecifierQualifier = pos:position!
 {
     SpecifierQualifier {
         kind: SpecifierQualifierKind::TypeSpecifier(s),
-        loc: env.locs[pos],
+        loc: env.loc(pos),
     }
 }
 
@@ -1005,19 +1490,19 @@ rule type_qualifier() -> TypeQualifier =
     pos:position!() [Restrict] {
         TypeQualifier {
             kind: TypeQualifierKind::Restrict,
-            loc: env.locs[pos],
+            loc: env.loc(pos),
         }
     } /
     pos:position!() [Const] {
         TypeQualifier {
             kind: TypeQualifierKind::Const,
-            loc: env.locs[pos],
+            loc: env.loc(pos),
         }
     } /
     pos:position!() [Volatile] {
         TypeQualifier {
             kind: TypeQualifierKind::Volatile,
-            loc: env.locs[pos],
+            loc: env.loc(pos),
         }
     }
 
@@ -1054,7 +1539,7 @@ rule direct_declarator() -> Declarator =
         Declarator {
            kind: DeclaratorKind::Declarator(env.buckets.add(d)),
            derived: &[],
-           loc: l_from(env.locs[pos], env.locs[pos2]),
+           loc: l_from(env.loc(pos), env.loc(pos2)),
         }
     }
 
@@ -1062,7 +1547,7 @@ rule derived_declarator() -> DerivedDeclarator  =
     pos:position!() [LBracket] w() a:array_declarator() w() pos2:position!() [RBracket] {
         DerivedDeclarator {
             kind: DerivedDeclaratorKind::Array(a),
-            loc: l_from(env.locs[pos], env.locs[pos2]),
+            loc: l_from(env.loc(pos), env.loc(pos2)),
         }
     } /
     f:scoped(<function_declarator()>) {
@@ -1074,7 +1559,7 @@ rule derived_declarator() -> DerivedDeclarator  =
     pos:position!() [LParen] w() pos2:position!() [RParen] {
         DerivedDeclarator {
             kind: DerivedDeclaratorKind::EmptyFunction,
-            loc: l_from(env.locs[pos], env.locs[pos2]),
+            loc: l_from(env.loc(pos), env.loc(pos2)),
         }
     }
 
@@ -1112,7 +1597,7 @@ rule function_declarator() -> FunctionDeclarator =
     {
         let (params, mut loc) = params;
         let varargs = varargs.is_some();
-        loc = l_from(env.locs[pos], env.locs[pos2]);
+        loc = l_from(env.loc(pos), env.loc(pos2));
 
         FunctionDeclarator {
             parameters: env.buckets.add_array(params),
@@ -1123,7 +1608,7 @@ rule function_declarator() -> FunctionDeclarator =
 
 rule pointer() -> DerivedDeclarator = pos:position!() [Star] w() q:list0(<type_qualifier()>) {
     let (q, mut end_loc) = q;
-    let loc = env.locs[pos];
+    let loc = env.loc(pos);
     if end_loc == NO_FILE {
         end_loc = loc;
     }
@@ -1137,7 +1622,7 @@ rule pointer() -> DerivedDeclarator = pos:position!() [Star] w() q:list0(<type_q
 
 rule pointer_quals() -> PointerQuals = pos:position!() [Star] w() q:list0(<type_qualifier()>) {
     let (q, mut end_loc) = q;
-    let loc = env.locs[pos];
+    let loc = env.loc(pos);
     if end_loc == NO_FILE {
         end_loc = loc;
     }
@@ -1191,7 +1676,7 @@ rule type_name() -> TypeName = s:specifier_qualifiers() w() d:abstract_declarato
 rule function_specifier() -> DeclarationSpecifier = pos:position!() [Inline] {
     DeclarationSpecifier {
         kind: DeclarationSpecifierKind::Inline,
-        loc: env.locs[pos],
+        loc: env.loc(pos),
     }
 }
 
@@ -1235,7 +1720,7 @@ rule direct_abstract_declarator() -> Declarator =
     Declarator {
         kind: DeclaratorKind::Declarator(env.buckets.add(d)),
         derived: &[],
-        loc: l_from(env.locs[pos], env.locs[pos2]),
+        loc: l_from(env.loc(pos), env.loc(pos2)),
     }
 }
 
@@ -1243,13 +1728,13 @@ rule derived_abstract_declarator() -> DerivedDeclarator =
     pos:position!() [LBracket] w() a:abstract_array_declarator() w() pos2:position!() [RBracket] {
         DerivedDeclarator {
             kind: DerivedDeclaratorKind::Array(a),
-            loc: l_from(env.locs[pos], env.locs[pos2]),
+            loc: l_from(env.loc(pos), env.loc(pos2)),
         }
     } /
     pos:position!() [LParen] w() a:abstract_function_declarator() w() pos2:position!() [RParen] {
         DerivedDeclarator {
             kind: DerivedDeclaratorKind::Function(a),
-            loc: l_from(env.locs[pos], env.locs[pos2]),
+            loc: l_from(env.loc(pos), env.loc(pos2)),
         }
     }
 
@@ -1283,7 +1768,7 @@ rule abstract_function_declarator() -> FunctionDeclarator =
         let (p, mut loc) = p;
         let varargs = varargs.is_some();
         if varargs {
-            loc = l_from(loc, env.locs[pos + 1]);
+            loc = l_from(loc, env.loc(pos + 1));
         }
 
         FunctionDeclarator {
@@ -1296,7 +1781,7 @@ rule abstract_function_declarator() -> FunctionDeclarator =
         FunctionDeclarator {
             parameters: &[],
             varargs: false,
-            loc: env.locs[pos],
+            loc: env.loc(pos),
         }
     }
 
@@ -1314,19 +1799,31 @@ rule initializer() -> Initializer =
     e:assignment_expr() {
         Initializer {
             kind: InitializerKind::Expr(env.buckets.add(e)),
+            field: None,
             loc: e.loc,
         }
     } /
+    l:initializer_list() {
+        let (items, loc) = l;
+        Initializer {
+            kind: InitializerKind::List(items),
+            field: None,
+            loc,
+        }
+    }
+
+rule initializer_list() -> (&'static [Initializer], CodeLoc) =
     pos:position!() [LBrace] w() i:cs1(<initializer_list_item()>) w()
     [Comma]? w() pos2:position!() [RBrace]
     {
-        Initializer {
-            kind: InitializerKind::List(env.buckets.add_array(i.0)),
-            loc: l_from(env.locs[pos], env.locs[pos2]),
-        }
+        (env.buckets.add_array(i.0), l_from(env.loc(pos), env.loc(pos2)))
     }
 
-rule initializer_list_item() -> Expr = assignment_expr()
+rule initializer_list_item() -> Initializer =
+    [Dot] w() f:raw_ident() w() [Eq] w() i:initializer() {
+        Initializer { field: Some(f), ..i }
+    } /
+    initializer()
 
 pub rule statement() -> Statement =
     labeled_statement() /
@@ -1341,7 +1838,7 @@ pub rule statement() -> Statement =
     scoped(<iteration_statement()>) /
     jump_statement() /
     pos:position!() [Semicolon] {
-        let loc = env.locs[pos];
+        let loc = env.loc(pos);
         Statement {
             kind: StatementKind::Block(Block { stmts: &[], loc }),
             loc,
@@ -1366,7 +1863,7 @@ rule labeled_statement() -> Statement =
     } /
     pos:position!() [Case] w() i:assignment_expr() w() [Colon] w() s:statement() {
         Statement {
-            loc: l_from(env.locs[pos], s.loc),
+            loc: l_from(env.loc(pos), s.loc),
             kind: StatementKind::CaseLabeled {
                 case_value: i,
                 labeled: env.buckets.add(s),
@@ -1375,7 +1872,7 @@ rule labeled_statement() -> Statement =
     } /
     pos:position!() w() [Default] w() [Colon] w() s:statement() {
         Statement {
-            loc: l_from(env.locs[pos], s.loc),
+            loc: l_from(env.loc(pos), s.loc),
             kind: StatementKind::DefaultCaseLabeled(env.buckets.add(s))
         }
     }
@@ -1391,7 +1888,7 @@ rule compound_statement() -> Block =
 
     Block{
         stmts: env.buckets.add_array(block),
-        loc: l_from(env.locs[pos], env.locs[pos2]),
+        loc: l_from(env.loc(pos), env.loc(pos2)),
     }
 }
 
@@ -1428,7 +1925,7 @@ rule selection_statement() -> Statement =
     pos:position!() [If] w() [LParen] w() e:expr()
     w() [RParen] w() a:statement() w() b:else_statement()?
     {
-        let mut loc = l_from(env.locs[pos], a.loc);
+        let mut loc = l_from(env.loc(pos), a.loc);
         if let Some(else_stmt) = b {
             loc = l_from(loc, else_stmt.loc);
         }
@@ -1444,7 +1941,7 @@ rule selection_statement() -> Statement =
     } /
     pos:position!() [Switch] w() [LParen] w() e:expr() w()
     [RParen] w() a:statement() {
-        let mut loc = l_from(env.locs[pos], a.loc);
+        let mut loc = l_from(env.loc(pos), a.loc);
 
         Statement {
             kind: StatementKind::Switch {
@@ -1469,7 +1966,7 @@ rule iteration_statement() -> Statement =
 rule while_statement() -> Statement =
     pos:position!() [While] w() [LParen] w() e:expr() w()
     [RParen] w() s:statement() {
-        let loc = l_from(env.locs[pos], s.loc);
+        let loc = l_from(env.loc(pos), s.loc);
 
         Statement {
             kind: StatementKind::While {
@@ -1484,7 +1981,7 @@ rule do_while_statement() -> Statement =
     pos:position!() [Do] w() s:statement() w() [While] w()
     [LParen] w() e:expr() w() [RParen] w()
     pos2:position!() [Semicolon] {
-        let loc = l_from(env.locs[pos], env.locs[pos2]);
+        let loc = l_from(env.loc(pos), env.loc(pos2));
 
         Statement {
             kind: StatementKind::DoWhile {
@@ -1499,7 +1996,7 @@ rule for_statement() -> Statement =
     pos:position!() [For] w() [LParen] w() a:expr()? w()
     [Semicolon] w() b:expr()? w() [Semicolon] w() e:expr()?
     w() [RParen] w() s:statement() {
-        let loc = l_from(env.locs[pos], s.loc);
+        let loc = l_from(env.loc(pos), s.loc);
 
         Statement {
             kind: StatementKind::For {
@@ -1514,7 +2011,7 @@ rule for_statement() -> Statement =
     pos:position!() [For] w() [LParen] w() a:declaration()
     w() b:expr()? w() [Semicolon] w() c:expr()? w() [RParen]
     w() s:statement() {
-        let loc = l_from(env.locs[pos], s.loc);
+        let loc = l_from(env.loc(pos), s.loc);
 
         Statement {
             kind: StatementKind::ForDecl {
@@ -1535,7 +2032,7 @@ rule for_statement() -> Statement =
 rule jump_statement() -> Statement =
     pos:position!() [Goto] w() i:raw_ident() w() pos2:position!() [Semicolon] {
         let (i, label_loc) = i;
-        let loc = l_from(env.locs[pos], env.locs[pos2]);
+        let loc = l_from(env.loc(pos), env.loc(pos2));
         Statement {
             kind: StatementKind::Goto {
                 label: i,
@@ -1545,7 +2042,7 @@ rule jump_statement() -> Statement =
         }
     } /
     pos:position!() [Continue] w() pos2:position!() [Semicolon] {
-        let loc = l_from(env.locs[pos], env.locs[pos2]);
+        let loc = l_from(env.loc(pos), env.loc(pos2));
 
         Statement {
             kind: StatementKind::Continue,
@@ -1553,7 +2050,7 @@ rule jump_statement() -> Statement =
         }
     } /
     pos:position!() [Break] w() pos2:position!() [Semicolon] {
-        let loc = l_from(env.locs[pos], env.locs[pos2]);
+        let loc = l_from(env.loc(pos), env.loc(pos2));
 
         Statement {
             kind: StatementKind::Break,
@@ -1561,7 +2058,7 @@ rule jump_statement() -> Statement =
         }
     } /
     pos:position!() [Return] w() pos2:position!() [Semicolon] {
-        let loc = l_from(env.locs[pos], env.locs[pos2]);
+        let loc = l_from(env.loc(pos), env.loc(pos2));
 
         Statement {
             kind: StatementKind::Ret,
@@ -1569,7 +2066,7 @@ rule jump_statement() -> Statement =
         }
     } /
     pos:position!() [Return] w() e:expr() w() pos2:position!() [Semicolon] {
-        let loc = l_from(env.locs[pos], env.locs[pos2]);
+        let loc = l_from(env.loc(pos), env.loc(pos2));
 
         Statement {
             kind: StatementKind::RetVal(e),
@@ -1585,6 +2082,12 @@ pub rule translation_unit() -> Vec<GlobalStatement> = w() tu:(external_declarati
     tu
 }
 
+// parses as many global statements as possible, returning the position it stopped at
+pub rule translation_unit_prefix() -> (Vec<GlobalStatement>, usize) =
+    w() tu:(external_declaration() ** w()) w() pos:position!() ##skip_rest() {
+    (tu, pos)
+}
+
 rule external_declaration() -> GlobalStatement =
     d:declaration() {
         GlobalStatement {
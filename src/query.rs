@@ -0,0 +1,331 @@
+use crate::filedb::*;
+use crate::interner::*;
+use crate::lexer::*;
+use crate::parser::*;
+use crate::tc_ast::*;
+use crate::type_checker::*;
+use crate::util::*;
+use crate::CompileOptions;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolInfo {
+    pub name: String,
+    pub ty: String,
+    pub defn_loc: CodeLoc,
+    pub refs: Vec<CodeLoc>,
+}
+
+/// Finds the symbol covering `offset` in `file`, along with its type, where it's
+/// defined, and every location in the translation unit that references it.
+pub fn symbol_at(files: &FileDb, file: u32, offset: u32) -> Result<Option<SymbolInfo>, Vec<Error>> {
+    for impl_file in files.impls() {
+        let mut lexer = Lexer::new(files);
+        let (id, tokens) = lexer.lex(impl_file).map_err(|e| vec![e])?;
+        let env = parse(id, tokens)?;
+        let symbols = lexer.symbols();
+        let tu = check_tree(files, env.file, &symbols, &env.tree)?;
+
+        if let Some(info) = symbol_in_tu(&tu, &symbols, file, offset) {
+            return Ok(Some(info));
+        }
+    }
+
+    return Ok(None);
+}
+
+pub fn symbol_in_tu(
+    tu: &TranslationUnit,
+    symbols: &Symbols,
+    file: u32,
+    offset: u32,
+) -> Option<SymbolInfo> {
+    let contains = |r: &&TCSymbolRef| {
+        let loc = r.loc;
+        loc.file == file && loc.start <= offset && offset < loc.end
+    };
+    let len = |r: &&TCSymbolRef| r.loc.end - r.loc.start;
+    let found = *tu.refs.iter().filter(contains).min_by_key(len)?;
+
+    let defn_loc = match found.scope {
+        TCSymbolScope::Local(loc) => loc,
+        TCSymbolScope::Global => {
+            let func = tu.functions.get(&found.ident);
+            let defn = func.map(|f| f.defn.map(|d| d.loc).unwrap_or(f.decl_loc));
+            let var = tu.vars.get(&found.ident).map(|v| v.loc);
+            defn.or(var).unwrap_or(found.loc)
+        }
+    };
+
+    let refs = tu.refs.iter();
+    let refs = refs.filter(|r| r.ident == found.ident && r.scope == found.scope);
+    let refs = refs.map(|r| r.loc).collect();
+
+    return Some(SymbolInfo {
+        name: symbols.to_str(found.ident).unwrap().to_string(),
+        ty: found.ty.display(symbols),
+        defn_loc,
+        refs,
+    });
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+    pub loc: CodeLoc,
+    pub new_text: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RenameTarget {
+    Local(CodeLoc),                  // keyed by the location of the declaration
+    Global { only_in: Option<u32> }, // static functions and globals stay in their file
+    Tag,
+    Typedef,
+}
+
+struct Unit {
+    env: ParseEnv,
+    symbols: Symbols,
+    tu: TranslationUnit,
+}
+
+impl Unit {
+    /// The narrowest reference to `ident` covering `loc`
+    fn ref_at(&self, ident: u32, loc: CodeLoc) -> Option<&TCSymbolRef> {
+        let refs = self
+            .tu
+            .refs
+            .iter()
+            .filter(|r| r.ident == ident && within(r.loc, loc));
+        return refs.min_by_key(|r| r.loc.end - r.loc.start);
+    }
+
+    fn is_typedef(&self, ident: u32) -> bool {
+        return self.tu.typedefs.keys().any(|&(id, _)| id == ident);
+    }
+
+    /// Identifier tokens in the unit as (index, ident, the token before it)
+    fn idents(&self) -> Vec<(usize, u32, TokenKind)> {
+        let mut out = Vec::new();
+        let mut prev = TokenKind::Whitespace;
+        for idx in 0..self.env.tokens.len() {
+            let kind = self.env.tokens.kind(idx);
+            if kind == TokenKind::Whitespace {
+                continue;
+            }
+
+            if let TokenKind::Ident(id) = kind {
+                out.push((idx, id, prev));
+            }
+            prev = kind;
+        }
+
+        return out;
+    }
+
+    fn classify(&self, ident: u32, loc: CodeLoc, prev: TokenKind) -> Option<RenameTarget> {
+        if let TokenKind::Struct | TokenKind::Union | TokenKind::Enum = prev {
+            return Some(RenameTarget::Tag);
+        }
+
+        if let TokenKind::Dot | TokenKind::Arrow = prev {
+            return None;
+        }
+
+        if let Some(r) = self.ref_at(ident, loc) {
+            if let TCSymbolScope::Local(decl) = r.scope {
+                return Some(RenameTarget::Local(decl));
+            }
+
+            let func = self.tu.functions.get(&ident).map(|f| f.is_static);
+            let var = self.tu.vars.get(&ident).map(|v| v.init.is_static());
+            let is_static = func.or(var).unwrap_or(false);
+            let only_in = if is_static { Some(self.env.file) } else { None };
+            return Some(RenameTarget::Global { only_in });
+        }
+
+        if self.is_typedef(ident) {
+            return Some(RenameTarget::Typedef);
+        }
+
+        return None;
+    }
+
+    fn matches(&self, target: RenameTarget, ident: u32, loc: CodeLoc, prev: TokenKind) -> bool {
+        let found = match self.classify(ident, loc, prev) {
+            Some(found) => found,
+            None => return false,
+        };
+
+        return match (target, found) {
+            (
+                RenameTarget::Global {
+                    only_in: Some(file),
+                },
+                _,
+            ) if file != self.env.file => false,
+            (RenameTarget::Global { .. }, RenameTarget::Global { .. }) => true,
+            (target, found) => target == found,
+        };
+    }
+
+    /// Where `new` is already declared such that renaming `old` to it would change
+    /// what some name refers to
+    fn collision(&self, target: RenameTarget, old: u32, new: u32) -> Option<CodeLoc> {
+        let defns = self.tu.functions.values().filter_map(|f| f.defn);
+        let local = |defn: TCFuncDefn| defn.locals.iter().find(|(id, _)| *id == new);
+
+        match target {
+            RenameTarget::Tag => {
+                let mut tags = self.idents().into_iter().filter(|&(_, id, prev)| {
+                    let is_tag =
+                        matches!(prev, TokenKind::Struct | TokenKind::Union | TokenKind::Enum);
+                    id == new && is_tag
+                });
+                return tags.next().map(|(idx, _, _)| self.env.tokens.loc(idx));
+            }
+            RenameTarget::Local(decl) => {
+                let defn = defns.clone().find(|d| within(d.loc, decl))?;
+                if let Some((_, var)) = local(defn) {
+                    return Some(var.loc);
+                }
+
+                // Globals used in the function would be shadowed
+                let mut refs = self.tu.refs.iter().filter(|r| within(defn.loc, r.loc));
+                if let Some(r) = refs.find(|r| r.ident == new) {
+                    return Some(r.loc);
+                }
+            }
+            RenameTarget::Global { .. } | RenameTarget::Typedef => {
+                let func = self.tu.functions.get(&new).map(|f| f.decl_loc);
+                let var = self.tu.vars.get(&new).map(|v| v.loc);
+                if let Some(loc) = func.or(var) {
+                    return Some(loc);
+                }
+
+                // Locals in functions that use the old name would shadow it
+                for defn in defns {
+                    let mut uses = self.idents().into_iter().filter(|&(idx, id, prev)| {
+                        let loc = self.env.tokens.loc(idx);
+                        id == old && within(defn.loc, loc) && self.matches(target, id, loc, prev)
+                    });
+
+                    match local(defn) {
+                        Some((_, var)) if uses.next().is_some() => return Some(var.loc),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let typedef = self.tu.typedefs.keys().find(|&&(id, _)| id == new);
+        return typedef.map(|&(_, loc)| loc);
+    }
+}
+
+fn within(outer: CodeLoc, inner: CodeLoc) -> bool {
+    return outer.file == inner.file && outer.start <= inner.start && inner.end <= outer.end;
+}
+
+/// Edits that rename the local, parameter, function, global, struct tag, or
+/// typedef at `offset` in `file`, in every file of the program. Returns no edits
+/// if there's nothing there to rename, and an error if `new_name` isn't a valid
+/// name or is already declared where it would change what a name refers to.
+pub fn rename(
+    files: &FileDb,
+    file: u32,
+    offset: u32,
+    new_name: &str,
+) -> Result<Vec<TextEdit>, Vec<Error>> {
+    return rename_with(files, file, offset, new_name, &CompileOptions::default());
+}
+
+/// Like `rename`, but with the `-D`, `-U`, `--std` and other flags in `options`,
+/// so that the names inside `#ifdef`s are the ones that get compiled
+pub fn rename_with(
+    files: &FileDb,
+    file: u32,
+    offset: u32,
+    new_name: &str,
+    options: &CompileOptions,
+) -> Result<Vec<TextEdit>, Vec<Error>> {
+    let mut units = Vec::new();
+    for impl_file in files.impls() {
+        let mut lexer = Lexer::new(files);
+        crate::configure_lexer(&mut lexer, options)?;
+        let (id, tokens) = lexer.lex(impl_file).map_err(|e| vec![e])?;
+        let env = parse(id, tokens)?;
+        let symbols = lexer.symbols();
+        let check_options = CheckOptions {
+            gnu_extensions: env.tokens.gnu_extensions,
+            ..Default::default()
+        };
+        let tu = check_tree_with(files, env.file, &symbols, &env.tree, &check_options)?;
+        units.push(Unit { env, symbols, tu });
+    }
+
+    let mut found = None;
+    'units: for unit in &units {
+        for (idx, ident, prev) in unit.idents() {
+            let loc = unit.env.tokens.loc(idx);
+            if loc.file != file || offset < loc.start || loc.end <= offset {
+                continue;
+            }
+
+            match unit.classify(ident, loc, prev) {
+                Some(target) => found = Some((unit, target, ident, loc)),
+                None => return Ok(Vec::new()),
+            }
+            break 'units;
+        }
+    }
+
+    let (unit, target, old_id, loc) = match found {
+        Some(found) => found,
+        None => return Ok(Vec::new()),
+    };
+    let old_name = unit.symbols.to_str(old_id).unwrap();
+
+    let mut chars = new_name.bytes();
+    let valid = chars.next().map(|c| c == b'_' || c.is_ascii_alphabetic()) == Some(true);
+    if !valid || !chars.all(is_ident_char) || KEYWORDS.contains(&new_name) {
+        let message = format!("`{}` isn't a valid name", new_name);
+        return Err(error!(message, loc, format!("renaming `{}`", old_name)).into());
+    }
+
+    let mut edits = Vec::new();
+    for unit in &units {
+        let old = match unit.symbols.from_str(old_name).opt() {
+            Some(old) => old,
+            None => continue, // this file doesn't mention it
+        };
+
+        if let Some(new) = unit.symbols.from_str(new_name).opt() {
+            if let Some(existing) = unit.collision(target, old, new) {
+                let message = format!("can't rename `{}` to `{}`", old_name, new_name);
+                return Err(error!(
+                    message,
+                    loc,
+                    format!("renaming `{}`", old_name),
+                    existing,
+                    format!("`{}` is already declared here", new_name)
+                )
+                .into());
+            }
+        }
+
+        for (idx, ident, prev) in unit.idents() {
+            let loc = unit.env.tokens.loc(idx);
+            if ident == old && unit.matches(target, ident, loc, prev) {
+                edits.push(TextEdit {
+                    loc,
+                    new_text: new_name.to_string(),
+                });
+            }
+        }
+    }
+
+    // Headers are part of every file that includes them
+    edits.sort_by_key(|e| (e.loc.file, e.loc.start));
+    edits.dedup();
+    return Ok(edits);
+}
@@ -0,0 +1,361 @@
+//! An interactive session of C. Each input is a declaration, which goes at file
+//! scope, or a statement, which goes at the end of `main`. The session is one
+//! program, paused at the end of `main` in between inputs. Each input is checked
+//! along with the ones before it, but only the code it adds gets assembled, and
+//! only its own statement runs, so output and reads from stdin happen once.
+//! Inputs that are expressions, with or without a `;` after them, also show
+//! their value.
+//!
+//! Declarations are kept in a header that the file with `main` includes, so that
+//! adding one doesn't move the statements that already ran. An input that doesn't
+//! compile, or that fails while it runs, is taken back out, and the program goes
+//! back to how it was before the input. So is one that runs for longer than
+//! `Repl::max_ops` instructions, so that an infinite loop doesn't end the session.
+
+use crate::assembler::Assembler;
+use crate::debugger::{self, EvalResult};
+use crate::filedb::FileDb;
+use crate::interner::{BuiltinSymbol, Symbols};
+use crate::lexer::Lexer;
+use crate::parser;
+use crate::runtime::*;
+use crate::tc_ast::*;
+use crate::type_checker;
+use crate::util::*;
+use crate::{check_files, emit_err, timings, CompileOptions};
+use core::mem;
+
+pub const REPL_FILE: &str = "repl.c";
+pub const REPL_DECLS: &str = "repl.h";
+
+/// How many instructions an input can run by default before it's stopped
+pub const REPL_MAX_OPS: u64 = 10_000_000;
+
+/// Statements that can end in `}` without being a function definition
+const BLOCK_KEYWORDS: [&str; 6] = ["if", "for", "while", "do", "switch", "{"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplOutput {
+    pub output: String,            // stdout and stderr, in the order they were written
+    pub value: Option<EvalResult>, // `None` for declarations, and expressions of type `void`
+}
+
+pub struct Repl {
+    pub files: FileDb,
+    pub limits: Limits, // for the whole session; set them before the first input
+    pub max_ops: u64,   // for each input
+    decls: Vec<String>,
+    stmts: Vec<String>,
+    session: Option<Session>,
+}
+
+/// The program the inputs so far have built up
+struct Session {
+    asm: Assembler, // everything assembled so far, so that inputs only add to it
+    symbols: Symbols,
+    kernel: Kernel,
+    proc: u32,
+    pc: VarPointer,  // where `main` is paused
+    main_ops: usize, // ops at the start of `main` that have run already
+    tail_ops: usize, // ops at the end of `main`, for the `return 0;` after the inputs
+}
+
+enum Failure {
+    Compile(Vec<Error>),
+    Runtime(String),
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        return Self {
+            files: FileDb::new(),
+            limits: Limits::DEFAULT,
+            max_ops: REPL_MAX_OPS,
+            decls: Vec::new(),
+            stmts: Vec::new(),
+            session: None,
+        };
+    }
+
+    /// Runs `input`, and keeps it for later inputs if it compiles and runs to the
+    /// end. Errors are rendered like the compiler's.
+    pub fn eval(&mut self, input: &str) -> Result<ReplOutput, String> {
+        let input = input.trim();
+        if input.is_empty() {
+            let output = String::new();
+            return Ok(ReplOutput {
+                output,
+                value: None,
+            });
+        }
+
+        if self.session.is_none() {
+            let session = self.start().map_err(|errs| self.render(&errs))?;
+            self.session = Some(session);
+        }
+
+        let is_expr = !input.starts_with('#') && !input.ends_with(';') && !input.ends_with('}');
+        let (mut decls, mut stmts) = (self.decls.clone(), self.stmts.clone());
+
+        // Preprocessor lines go at file scope. Other inputs ending in `;` or `}`
+        // are declarations if they compile as one, and statements otherwise. A
+        // statement ending in `;` shows the value of its last expression, so that
+        // `x + 2;` works like `x + 2`.
+        let result = if input.starts_with('#') {
+            decls.push(input.to_string());
+            self.run(&decls, &stmts, false)
+        } else if is_expr {
+            stmts.push(format!("{};", input));
+            self.run(&decls, &stmts, true)
+        } else {
+            decls.push(input.to_string());
+            match self.run(&decls, &stmts, false) {
+                Err(Failure::Compile(decl_errs)) => {
+                    decls.pop();
+                    stmts.push(input.to_string());
+                    let is_block = BLOCK_KEYWORDS.iter().any(|k| input.starts_with(k));
+                    match self.run(&decls, &stmts, input.ends_with(';')) {
+                        Err(Failure::Compile(_)) if input.ends_with('}') && !is_block => {
+                            stmts.pop();
+                            decls.push(input.to_string());
+                            write_sources(&mut self.files, &decls, &stmts);
+                            Err(Failure::Compile(decl_errs))
+                        }
+                        result => result,
+                    }
+                }
+                result => result,
+            }
+        };
+
+        let value = match result {
+            Ok(value) => value,
+            Err(failure) => {
+                let message = match failure {
+                    Failure::Compile(errs) => self.render(&errs),
+                    Failure::Runtime(message) => message,
+                };
+
+                write_sources(&mut self.files, &self.decls, &self.stmts);
+                return Err(message);
+            }
+        };
+
+        let output = self.session.as_mut().unwrap().kernel.term_out();
+        self.decls = decls;
+        self.stmts = stmts;
+        return Ok(ReplOutput { output, value });
+    }
+
+    fn render(&self, errs: &[Error]) -> String {
+        let mut out = StringWriter::new();
+        emit_err(errs, &self.files, &mut out);
+        return out.into_string();
+    }
+
+    /// Builds the program with no inputs, and runs it up to the end of `main`
+    fn start(&mut self) -> Result<Session, Vec<Error>> {
+        let (file, end) = write_sources(&mut self.files, &[], &[]);
+
+        let options = CompileOptions {
+            debug: true,
+            ..Default::default()
+        };
+        let mut timings = timings::Timings::disabled();
+        let (symbols, checked) = check_files(&self.files, &mut timings, &options)?;
+
+        let mut asm = Assembler::with_debug_info(symbols.clone());
+        let mut main_len = 0;
+        for tu in checked {
+            if tu.file == file {
+                let main = &tu.functions[&(BuiltinSymbol::Main as u32)];
+                main_len = main.defn.unwrap().ops.len();
+            }
+
+            asm.add_file(&self.files, &tu).map_err(|e| vec![e])?;
+        }
+        asm.link_program(&self.files, &symbols)?;
+
+        let mut kernel = Kernel::new(Vec::new());
+        kernel.limits = self.limits;
+        let proc = kernel.load_term_program(&asm.data);
+        while kernel.loc().file != file || kernel.loc().start < end {
+            if kernel.active_count == 0 {
+                return Err(vec![error!("the program stopped before the end of `main`")]);
+            }
+
+            if let Err(e) = kernel.run_op_count(1) {
+                return Err(vec![error!(format!("{}: {}", e.short_name, e.message))]);
+            }
+        }
+
+        let pc = kernel.memory(proc).unwrap().pc;
+        return Ok(Session {
+            asm,
+            symbols,
+            kernel,
+            proc,
+            pc,
+            main_ops: 1, // the scope of `main`
+            tail_ops: main_len - 1,
+        });
+    }
+
+    /// Adds what's new in `decls` and `stmts` to the program, and runs the new
+    /// statement if there is one. Undoes all of it if anything goes wrong.
+    fn run(
+        &mut self,
+        decls: &[String],
+        stmts: &[String],
+        keep_value: bool,
+    ) -> Result<Option<EvalResult>, Failure> {
+        let (file, _) = write_sources(&mut self.files, decls, stmts);
+        let new_stmt = stmts.len() != self.stmts.len();
+
+        let session = self.session.as_mut().unwrap();
+        let checkpoint = session.asm.checkpoint();
+        let snapshot = session.kernel.snapshot();
+        let result = session.run(&self.files, file, new_stmt, keep_value, self.max_ops);
+        if result.is_err() {
+            session.asm.rollback(checkpoint);
+            session.kernel.restore(&snapshot).unwrap();
+            mem::drop(session.kernel.term_out());
+        }
+
+        return result;
+    }
+}
+
+impl Session {
+    fn run(
+        &mut self,
+        files: &FileDb,
+        file: u32,
+        new_stmt: bool,
+        keep_value: bool,
+        max_ops: u64,
+    ) -> Result<Option<EvalResult>, Failure> {
+        // String literals in the tokens point into the lexer's buckets, so it has to
+        // outlive them
+        let mut lexer = Lexer::with_symbols(files, self.symbols.clone());
+        let (_, tokens) = lexer.lex(file).map_err(|e| Failure::Compile(vec![e]))?;
+        let env = parser::parse(file, tokens).map_err(Failure::Compile)?;
+        let tu = type_checker::check_tree(files, file, &lexer.symbols, &env.tree);
+        let tu = tu.map_err(Failure::Compile)?;
+
+        if let Some(debug) = &mut self.asm.debug {
+            debug.symbols = lexer.symbols.clone();
+        }
+        self.asm
+            .add_file(files, &tu)
+            .map_err(|e| Failure::Compile(vec![e]))?;
+
+        let mut snippet = None;
+        if new_stmt {
+            let main_ident = BuiltinSymbol::Main as u32;
+            let main = tu.functions[&main_ident].defn.unwrap();
+            let end = main.ops.len() - self.tail_ops;
+            let value_ty = match main.ops[end - 1].kind {
+                TCOpcodeKind::Expr(expr) if keep_value => Some(expr.ty),
+                _ => None,
+            };
+
+            let ops = self.main_ops..end;
+            let code = self
+                .asm
+                .add_snippet(&tu, main_ident, ops, value_ty.is_some());
+            snippet = Some((code, end, value_ty));
+        }
+
+        self.asm.link().map_err(Failure::Compile)?;
+        self.symbols = lexer.symbols.clone();
+
+        let memory = self.kernel.memory_mut(self.proc).unwrap();
+        memory.extend_binary(&self.asm.data);
+
+        let ((ptr, len), end, value_ty) = match snippet {
+            Some(snippet) => snippet,
+            None => return Ok(None),
+        };
+
+        memory.jump(ptr);
+        self.run_to(files, ptr.with_offset(len), max_ops)?;
+
+        let memory = self.kernel.memory_mut(self.proc).unwrap();
+        let bytes = mem::replace(&mut memory.expr_stack, Vec::new());
+        memory.jump(self.pc);
+        self.main_ops = end;
+
+        let value = value_ty.filter(|ty| !ty.is_void()).map(|ty| EvalResult {
+            ty: ty.display(&lexer.symbols),
+            value: debugger::format_value(&ty, &bytes),
+        });
+        return Ok(value);
+    }
+
+    /// Steps the program until it gets to `end`, or until it's run `max_ops`
+    /// instructions
+    fn run_to(&mut self, files: &FileDb, end: VarPointer, max_ops: u64) -> Result<(), Failure> {
+        let mut ops = 0;
+        loop {
+            if let Some(code) = self.kernel.exit_status(self.proc) {
+                let message = format!("the program exited with code {}\n", code);
+                return Err(Failure::Runtime(message));
+            }
+
+            if self.kernel.active_count == 0 {
+                let message = "the program is blocked waiting for input\n".to_string();
+                return Err(Failure::Runtime(message));
+            }
+
+            if self.kernel.memory(self.proc).unwrap().pc == end {
+                return Ok(());
+            }
+
+            if ops == max_ops {
+                let message = format!(
+                    "stopped after {} instructions; the input was undone\n",
+                    max_ops
+                );
+                return Err(Failure::Runtime(message));
+            }
+
+            if let Err(e) = self.kernel.run_op_count(1) {
+                let memory = self.kernel.memory(self.proc).unwrap();
+                return Err(Failure::Runtime(print_error(&e, memory, files)));
+            }
+
+            ops += 1;
+        }
+    }
+}
+
+/// Writes the session's source into `files`. Returns the file with `main`, and
+/// where the statements in it end.
+fn write_sources(files: &mut FileDb, decls: &[String], stmts: &[String]) -> (u32, u32) {
+    let mut header = String::new();
+    for decl in decls {
+        header += decl;
+        header += "\n";
+    }
+    files.set_scratch(REPL_DECLS, &header);
+
+    let mut source = format!("#include \"{}\"\nint main() {{\n", REPL_DECLS);
+    for stmt in stmts {
+        source += stmt;
+        source += "\n";
+    }
+
+    let end = source.len() as u32;
+    source += "return 0;\n}\n";
+
+    let file = match files.names.get(&(false, REPL_FILE)) {
+        Some(&id) => {
+            files.replace(id, &source).unwrap();
+            id
+        }
+        None => files.add(REPL_FILE, &source).unwrap(),
+    };
+
+    return (file, end);
+}
@@ -0,0 +1,112 @@
+//! Statement coverage for the kernel. The runtime only knows about source locations,
+//! so it counts how many times execution entered each one; lining those up with the
+//! statement locations the assembler records for debug builds gives line coverage.
+
+use crate::filedb::{FileDb, FileType};
+use crate::util::*;
+use alloc::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineCoverage {
+    pub line: u32, // 1-indexed
+    pub count: u64,
+}
+
+pub struct Coverage {
+    pub hits: HashMap<CodeLoc, u64>,
+    last: CodeLoc,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        return Self {
+            hits: HashMap::new(),
+            last: NO_FILE,
+        };
+    }
+
+    pub fn record(&mut self, loc: CodeLoc) {
+        if loc == self.last {
+            return;
+        }
+
+        self.last = loc;
+        *self.hits.entry(loc).or_insert(0) += 1;
+    }
+
+    /// Every line of user code that has a statement or was executed, grouped by file.
+    /// A line's count is the count of the location on it that ran the most.
+    pub fn lines(&self, files: &FileDb, stmts: &[CodeLoc]) -> Vec<(u32, Vec<LineCoverage>)> {
+        let line_of = |loc: CodeLoc| -> Option<(u32, u32)> {
+            let file = files.files.get(loc.file as usize)?;
+            if let FileType::System = file.ty {
+                return None;
+            }
+
+            let line = files.line_index(loc.file, loc.start as usize)?;
+            return Some((loc.file, line as u32 + 1));
+        };
+
+        let mut lines = BTreeMap::new();
+        for key in stmts.iter().filter_map(|&loc| line_of(loc)) {
+            lines.entry(key).or_insert(0);
+        }
+
+        for (&loc, &count) in &self.hits {
+            if let Some(key) = line_of(loc) {
+                let line = lines.entry(key).or_insert(0);
+                *line = core::cmp::max(*line, count);
+            }
+        }
+
+        let mut out: Vec<(u32, Vec<LineCoverage>)> = Vec::new();
+        for ((file, line), count) in lines {
+            let line = LineCoverage { line, count };
+            match out.last_mut() {
+                Some((f, lines)) if *f == file => lines.push(line),
+                _ => out.push((file, vec![line])),
+            }
+        }
+
+        return out;
+    }
+
+    /// Coverage in the lcov tracefile format
+    pub fn lcov(&self, files: &FileDb, stmts: &[CodeLoc]) -> String {
+        let mut out = StringWriter::new();
+        for (file, lines) in self.lines(files, stmts) {
+            writeln!(out, "SF:{}", files.name(file).unwrap()).unwrap();
+            for l in &lines {
+                writeln!(out, "DA:{},{}", l.line, l.count).unwrap();
+            }
+
+            let hit = lines.iter().filter(|l| l.count != 0).count();
+            writeln!(out, "LF:{}\nLH:{}\nend_of_record", lines.len(), hit).unwrap();
+        }
+
+        return out.into_string();
+    }
+
+    /// The source of `file` with each line prefixed by how many times it ran, like
+    /// gcov: `-` for lines without statements and `#####` for ones that never ran
+    pub fn annotate(&self, files: &FileDb, stmts: &[CodeLoc], file: u32) -> String {
+        let lines = self.lines(files, stmts);
+        let lines = lines.iter().find(|(f, _)| *f == file);
+        let lines = lines.map(|(_, lines)| &lines[..]).unwrap_or(&[]);
+
+        let mut out = StringWriter::new();
+        let source = files.source(file).unwrap_or("");
+        for (idx, text) in source.lines().enumerate() {
+            let line = idx as u32 + 1;
+            match lines.iter().find(|l| l.line == line) {
+                Some(l) if l.count == 0 => write!(out, "{:>9}", "#####").unwrap(),
+                Some(l) => write!(out, "{:>9}", l.count).unwrap(),
+                None => write!(out, "{:>9}", "-").unwrap(),
+            }
+
+            writeln!(out, ":{:>5}:{}", line, text).unwrap();
+        }
+
+        return out.into_string();
+    }
+}
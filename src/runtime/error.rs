@@ -21,6 +21,7 @@ pub fn render_err(error: &IError, stack_trace: &Vec<CallFrame>, files: &FileDb)
 pub struct IError {
     pub short_name: String,
     pub message: String,
+    pub notes: Vec<(CodeLoc, String)>, // other places in the program the error is about
 }
 
 impl IError {
@@ -28,8 +29,18 @@ impl IError {
         Self {
             short_name,
             message,
+            notes: Vec::new(),
         }
     }
+
+    /// Points out `loc`, unless it's `NO_FILE`
+    pub fn with_note(mut self, loc: CodeLoc, message: &str) -> Self {
+        if loc != NO_FILE {
+            self.notes.push((loc, message.to_string()));
+        }
+
+        return self;
+    }
 }
 
 #[allow(unused_macros)]
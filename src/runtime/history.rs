@@ -0,0 +1,216 @@
+//! Execution history, for stepping a process backwards. Rather than snapshotting all
+//! of memory at every step, each step keeps the registers `Memory` had when the step
+//! began, the bytes that stores during the step overwrote, and how the step changed
+//! the heap, stack and call stack tables.
+
+use super::memory::*;
+use super::types::*;
+use crate::util::*;
+
+/// The tables in `Memory` that aren't covered by its change log
+#[derive(Debug, Clone)]
+struct Tables {
+    heap: Vec<Var<AllocInfo>>,
+    expr_stack: Vec<u8>,
+    stack: Vec<Var<()>>,
+    callstack: Vec<CallFrame>,
+}
+
+impl Tables {
+    fn new(memory: &Memory) -> Self {
+        return Self {
+            heap: memory.heap.clone(),
+            expr_stack: memory.expr_stack.clone(),
+            stack: memory.stack.clone(),
+            callstack: memory.callstack.clone(),
+        };
+    }
+}
+
+/// How to undo what a step did to a table. These tables mostly change at the end,
+/// so only the entries after the first one that changed are kept.
+#[derive(Debug, Clone)]
+struct TableDiff<T> {
+    keep: usize,
+    old: Vec<T>, // what came after the first `keep` entries before the step
+}
+
+impl<T: Clone + PartialEq> TableDiff<T> {
+    /// The diff from `prev` to `now`. `prev` is updated to match `now`.
+    fn update(prev: &mut Vec<T>, now: &[T]) -> Self {
+        let keep = prev.iter().zip(now).take_while(|(p, n)| p == n).count();
+        let old = prev.split_off(keep);
+        prev.extend_from_slice(&now[keep..]);
+        return Self { keep, old };
+    }
+
+    fn undo(self, table: &mut Vec<T>) {
+        table.truncate(self.keep);
+        table.extend(self.old);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TableDiffs {
+    heap: TableDiff<Var<AllocInfo>>,
+    expr_stack: TableDiff<u8>,
+    stack: TableDiff<Var<()>>,
+    callstack: TableDiff<CallFrame>,
+}
+
+#[derive(Debug, Clone)]
+struct Step {
+    freed: usize,
+    current_func: LinkName,
+    fp: u16,
+    pc: VarPointer,
+    loc: CodeLoc,
+    stack_len: usize,
+    shared_len: usize,
+
+    changes: Vec<MemoryChange>,
+    diffs: Option<TableDiffs>, // `None` until the step is over
+}
+
+impl Step {
+    fn new(memory: &Memory) -> Self {
+        return Self {
+            freed: memory.freed,
+            current_func: memory.current_func,
+            fp: memory.fp,
+            pc: memory.pc,
+            loc: memory.loc,
+            stack_len: memory.stack_data.len(),
+            shared_len: memory.shared_data.len(),
+            changes: Vec::new(),
+            diffs: None,
+        };
+    }
+}
+
+/// A ring buffer of the most recent steps a process took. The runtime only knows
+/// about source locations, so a step is the run of instructions executed between
+/// two changes of `Memory::loc`.
+#[derive(Debug, Clone)]
+pub struct History {
+    steps: VecDeque<Step>,
+    capacity: usize,
+    tables: Tables, // as they were when the step that's running began
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        let tables = Tables {
+            heap: Vec::new(),
+            expr_stack: Vec::new(),
+            stack: Vec::new(),
+            callstack: Vec::new(),
+        };
+
+        return Self {
+            steps: VecDeque::new(),
+            capacity: core::cmp::max(capacity, 1),
+            tables,
+        };
+    }
+
+    /// Number of steps recorded, including the one currently running
+    pub fn len(&self) -> usize {
+        return self.steps.len();
+    }
+
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+
+    /// Call before every instruction; starts a new step whenever the location changed.
+    /// `memory.change_log` must be set for the history to be able to undo stores.
+    pub fn record(&mut self, memory: &mut Memory) {
+        if let Some(step) = self.steps.back() {
+            if step.loc == memory.loc {
+                return;
+            }
+        }
+
+        self.begin_step(memory);
+    }
+
+    fn begin_step(&mut self, memory: &mut Memory) {
+        if !self.end_step(memory) {
+            // Nothing was running, so `tables` isn't up to date
+            self.tables = Tables::new(memory);
+        }
+
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+
+        self.steps.push_back(Step::new(memory));
+    }
+
+    /// Finishes the step that's running, if there is one. Returns whether there was.
+    fn end_step(&mut self, memory: &mut Memory) -> bool {
+        let step = match self.steps.back_mut() {
+            Some(step) if step.diffs.is_none() => step,
+            _ => return false,
+        };
+
+        if let Some(log) = &mut memory.change_log {
+            step.changes.append(log);
+        }
+
+        let tables = &mut self.tables;
+        step.diffs = Some(TableDiffs {
+            heap: TableDiff::update(&mut tables.heap, &memory.heap),
+            expr_stack: TableDiff::update(&mut tables.expr_stack, &memory.expr_stack),
+            stack: TableDiff::update(&mut tables.stack, &memory.stack),
+            callstack: TableDiff::update(&mut tables.callstack, &memory.callstack),
+        });
+
+        return true;
+    }
+
+    /// Rewinds `memory` to the start of the step `steps` steps before the one
+    /// currently running; `step_back(memory, 0)` restarts the current step. Returns
+    /// how many steps were actually rewound, which is less than `steps` if the
+    /// history doesn't go back that far.
+    pub fn step_back(&mut self, memory: &mut Memory, steps: u32) -> u32 {
+        self.end_step(memory);
+
+        let mut undone = 0;
+        while undone <= steps {
+            let step = match self.steps.pop_back() {
+                Some(step) => step,
+                None => break,
+            };
+
+            for change in step.changes.iter().rev() {
+                memory.undo_change(change);
+            }
+
+            if let Some(diffs) = step.diffs {
+                diffs.heap.undo(&mut memory.heap);
+                diffs.expr_stack.undo(&mut memory.expr_stack);
+                diffs.stack.undo(&mut memory.stack);
+                diffs.callstack.undo(&mut memory.callstack);
+            }
+
+            memory.freed = step.freed;
+            memory.current_func = step.current_func;
+            memory.fp = step.fp;
+            memory.pc = step.pc;
+            memory.loc = step.loc;
+            memory.stack_data.resize(step.stack_len, 0);
+            memory.shared_data.resize(step.shared_len, 0);
+
+            undone += 1;
+        }
+
+        if undone == 0 {
+            return 0;
+        }
+
+        self.begin_step(memory);
+        return undone - 1;
+    }
+}
@@ -0,0 +1,162 @@
+//! Functions written in Rust by the program embedding TCI, that C programs can call
+//! like any other function. Each one gets a C wrapper that passes its arguments to
+//! the kernel through an ecall in the host range, and a declaration in
+//! `<tci_host.h>`.
+
+use super::error::*;
+use super::memory::*;
+use super::types::*;
+use crate::filedb::FileDb;
+use crate::util::*;
+
+pub const HOST_HEADER: &str = "tci_host.h";
+pub const HOST_SOURCE: &str = "tci_host.c";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostType {
+    Void, // only for return values
+    Long,
+    Pointer, // `void *`; the host gets the raw bits of a `VarPointer`
+}
+
+impl HostType {
+    fn c_name(self) -> &'static str {
+        return match self {
+            HostType::Void => "void",
+            HostType::Long => "long",
+            HostType::Pointer => "void *",
+        };
+    }
+}
+
+/// Gets the memory of the process that called it, and the arguments it was passed
+pub type HostCallback = Box<dyn FnMut(&mut Memory, &[u64]) -> Result<u64, IError>>;
+
+pub struct HostFn {
+    pub name: String,
+    pub params: Vec<HostType>,
+    pub ret: HostType,
+    pub func: HostCallback,
+}
+
+pub struct HostFns {
+    pub fns: Vec<HostFn>,
+}
+
+impl HostFns {
+    pub fn new() -> Self {
+        return Self { fns: Vec::new() };
+    }
+
+    /// Makes `func` callable from C as `name`. Register everything before adding
+    /// the functions to a `FileDb` with `add_to`, and give the same `HostFns` to the
+    /// kernel that runs the program.
+    pub fn register<F>(&mut self, name: &str, params: &[HostType], ret: HostType, func: F)
+    where
+        F: FnMut(&mut Memory, &[u64]) -> Result<u64, IError> + 'static,
+    {
+        self.fns.push(HostFn {
+            name: name.to_string(),
+            params: params.to_vec(),
+            ret,
+            func: Box::new(func),
+        });
+    }
+
+    /// Declarations of every registered function, e.g. `void draw_pixel(long x0,
+    /// long x1, long x2);`
+    pub fn header(&self) -> String {
+        let mut out = "#ifndef __TCI_HOST_H\n#define __TCI_HOST_H\n\n".to_string();
+        for func in &self.fns {
+            out += &format!("{};\n", prototype(func));
+        }
+
+        out += "\n#endif\n";
+        return out;
+    }
+
+    /// Definitions that forward each call to the kernel
+    pub fn source(&self) -> String {
+        let mut out = format!("#include <tci.h>\n#include <{}>\n", HOST_HEADER);
+        for (id, func) in self.fns.iter().enumerate() {
+            let count = func.params.len();
+            let args = (0..count).map(|idx| format!("(long)x{}", idx));
+            let args: Vec<String> = args.collect();
+            let args = match count {
+                0 => "0".to_string(),
+                _ => args.join(", "),
+            };
+
+            let call = format!("tci_ecall(TCI_ECALL_HOST + {}U, args, {})", id, count);
+            let call = match func.ret {
+                HostType::Void => format!("{};", call),
+                ret => format!("return ({}){};", ret.c_name(), call),
+            };
+
+            out += &format!(
+                "\n{} {{\n  long args[{}] = {{{}}};\n  {}\n}}\n",
+                prototype(func),
+                count.max(1),
+                args,
+                call
+            );
+        }
+
+        return out;
+    }
+
+    /// Adds `<tci_host.h>` and the definitions it declares to `files`, replacing
+    /// the ones from an earlier call
+    pub fn add_to(&self, files: &mut FileDb) -> Result<(), &'static str> {
+        let header = files.set_scratch(HOST_HEADER, &self.header());
+        let name = files.files[header as usize].name;
+        files.names.insert((true, name), header);
+
+        let source = self.source();
+        return match files.names.get(&(false, HOST_SOURCE)) {
+            Some(&id) => files.replace(id, &source),
+            None => files.add(HOST_SOURCE, &source).map(|_| ()),
+        };
+    }
+
+    /// Runs the function `id` for a process, with `count` arguments in the array
+    /// at `args`
+    pub fn call(
+        &mut self,
+        memory: &mut Memory,
+        id: u32,
+        args: VarPointer,
+        count: u32,
+    ) -> Result<u64, IError> {
+        let or_else = || ierror!("InvalidHostCall", "there's no host function {}", id);
+        let func = self.fns.get_mut(id as usize).ok_or_else(or_else)?;
+        if func.params.len() != count as usize {
+            return Err(ierror!(
+                "InvalidHostCall",
+                "`{}` takes {} arguments, but was passed {}",
+                func.name,
+                func.params.len(),
+                count
+            ));
+        }
+
+        let mut values = Vec::with_capacity(count as usize);
+        for idx in 0..count {
+            values.push(memory.read::<u64>(args.with_offset(args.offset() + idx * 8))?);
+        }
+
+        return (func.func)(memory, &values);
+    }
+}
+
+fn prototype(func: &HostFn) -> String {
+    let params = func.params.iter().enumerate();
+    let params = params.map(|(idx, ty)| format!("{} x{}", ty.c_name(), idx));
+    let params: Vec<String> = params.collect();
+    let params = match params.len() {
+        0 => "void".to_string(),
+        _ => params.join(", "),
+    };
+
+    return format!("{} {}({})", func.ret.c_name(), func.name, params);
+}
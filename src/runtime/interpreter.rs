@@ -1,11 +1,71 @@
 use super::error::*;
+use super::history::*;
 use super::memory::*;
+use super::profile::*;
 use super::types::*;
 use crate::util::*;
 
 pub fn run_op_count(memory: &mut Memory, count: u32) -> (u32, Result<Option<EcallExt>, IError>) {
     for idx in 0..count {
         match run_op(memory) {
+            Ok(None) if memory.watch_hit.is_some() => return (idx + 1, Ok(None)),
+            Ok(None) => {}
+            Ok(Some(ecall)) => return (idx + 1, Ok(Some(ecall))),
+            Err(ierr) => return (idx, Err(ierr)),
+        }
+    }
+
+    return (count, Ok(None));
+}
+
+/// Same as `run_op_count`, but counts each instruction against the function it ran in
+pub fn run_op_count_profiled(
+    memory: &mut Memory,
+    count: u32,
+    profile: &mut Profile,
+) -> (u32, Result<Option<EcallExt>, IError>) {
+    for idx in 0..count {
+        profile.record_op(memory.current_func, memory.loc);
+        match run_op(memory) {
+            Ok(None) if memory.watch_hit.is_some() => return (idx + 1, Ok(None)),
+            Ok(None) => {}
+            Ok(Some(ecall)) => return (idx + 1, Ok(Some(ecall))),
+            Err(ierr) => return (idx, Err(ierr)),
+        }
+    }
+
+    return (count, Ok(None));
+}
+
+/// Like `run_op_count`, but calls `observe` before every instruction
+pub fn run_op_count_observed(
+    memory: &mut Memory,
+    count: u32,
+    mut observe: impl FnMut(&mut Memory),
+) -> (u32, Result<Option<EcallExt>, IError>) {
+    for idx in 0..count {
+        observe(memory);
+        match run_op(memory) {
+            Ok(None) if memory.watch_hit.is_some() => return (idx + 1, Ok(None)),
+            Ok(None) => {}
+            Ok(Some(ecall)) => return (idx + 1, Ok(Some(ecall))),
+            Err(ierr) => return (idx, Err(ierr)),
+        }
+    }
+
+    return (count, Ok(None));
+}
+
+/// Same as `run_op_count`, but records each step in `history` so it can be undone
+pub fn run_op_count_recorded(
+    memory: &mut Memory,
+    count: u32,
+    history: &mut History,
+) -> (u32, Result<Option<EcallExt>, IError>) {
+    for idx in 0..count {
+        history.record(memory);
+        match run_op(memory) {
+            Ok(None) if memory.watch_hit.is_some() => return (idx + 1, Ok(None)),
             Ok(None) => {}
             Ok(Some(ecall)) => return (idx + 1, Ok(Some(ecall))),
             Err(ierr) => return (idx, Err(ierr)),
@@ -16,9 +76,9 @@ pub fn run_op_count(memory: &mut Memory, count: u32) -> (u32, Result<Option<Ecal
 }
 
 pub fn run_op(memory: &mut Memory) -> Result<Option<EcallExt>, IError> {
-    let op: Opcode = memory.read_pc()?;
+    let instr = memory.fetch()?;
 
-    match op {
+    match instr.op {
         Opcode::Func => {
             // this opcode is handled by Memory
             return Err(ierror!(
@@ -27,11 +87,11 @@ pub fn run_op(memory: &mut Memory) -> Result<Option<EcallExt>, IError> {
             ));
         }
         Opcode::Loc => {
-            let loc = memory.read_pc()?;
+            let loc = instr.operand();
             memory.set_loc(loc);
         }
         Opcode::StackAlloc => {
-            let bytes: u32 = memory.read_pc()?;
+            let bytes: u32 = instr.operand();
             memory.add_stack_var(bytes)?;
         }
         Opcode::StackDealloc => {
@@ -39,51 +99,49 @@ pub fn run_op(memory: &mut Memory) -> Result<Option<EcallExt>, IError> {
         }
 
         Opcode::Make8 => {
-            let val: u8 = memory.read_pc()?;
+            let val: u8 = instr.operand();
             memory.push(val);
         }
         Opcode::Make16 => {
-            let val: u16 = memory.read_pc()?;
+            let val: u16 = instr.operand();
             memory.push(val);
         }
         Opcode::Make32 => {
-            let val: u32 = memory.read_pc()?;
+            let val: u32 = instr.operand();
             memory.push(val);
         }
         Opcode::Make64 => {
-            let val: u64 = memory.read_pc()?;
+            let val: u64 = instr.operand();
             memory.push(val);
         }
         Opcode::MakeSp => {
-            let var_offset: i16 = memory.read_pc()?;
+            let var_offset: i16 = instr.operand();
             let stack_len = memory.stack.len() as u16;
             let var = (stack_len as i16 + var_offset) as u16;
 
             memory.push(VarPointer::new_stack(var, 0));
         }
         Opcode::MakeFp => {
-            let var_offset: i16 = memory.read_pc()?;
+            let var_offset: i16 = instr.operand();
             let var = (memory.fp as i16 + var_offset) as u16;
 
             memory.push(VarPointer::new_stack(var, 0));
         }
 
         Opcode::PushUndef => {
-            let bytes: u32 = memory.read_pc()?;
-            let stack_len = memory.expr_stack.len();
-            memory.expr_stack.resize(stack_len + bytes as usize, 0);
+            memory.push_undef(instr.operand())?;
         }
         Opcode::Pop => {
-            let bytes = memory.read_pc()?;
+            let bytes = instr.operand();
             memory.pop_bytes(bytes)?;
         }
         Opcode::Swap => {
-            let top_bytes = memory.read_pc()?;
-            let bottom_bytes = memory.read_pc()?;
+            let top_bytes = instr.operand();
+            let bottom_bytes = instr.operand_at(4);
             memory.swap_bytes(top_bytes, bottom_bytes)?;
         }
         Opcode::Dup => {
-            let bytes = memory.read_pc()?;
+            let bytes = instr.operand();
             memory.dup_bytes(bytes)?;
         }
         Opcode::PushDyn => {
@@ -282,13 +340,13 @@ pub fn run_op(memory: &mut Memory) -> Result<Option<EcallExt>, IError> {
         }
 
         Opcode::Get => {
-            let bytes = memory.read_pc()?;
+            let bytes = instr.operand();
             let ptr: VarPointer = memory.pop()?;
 
             memory.read_bytes_to_stack(ptr, bytes)?;
         }
         Opcode::Set => {
-            let bytes = memory.read_pc()?;
+            let bytes = instr.operand();
             let ptr: VarPointer = memory.pop()?;
             memory.write_bytes_from_stack(ptr, bytes)?;
         }
@@ -615,115 +673,163 @@ pub fn run_op(memory: &mut Memory) -> Result<Option<EcallExt>, IError> {
             memory.push(word1.wrapping_mul(word2));
         }
         Opcode::MulF32 => {
-            let word2: i32 = memory.pop()?;
-            let word1: i32 = memory.pop()?;
+            let word2: f32 = memory.pop()?;
+            let word1: f32 = memory.pop()?;
             memory.push(word1 * word2);
         }
         Opcode::MulF64 => {
-            let word2: u64 = memory.pop()?;
-            let word1: u64 = memory.pop()?;
+            let word2: f64 = memory.pop()?;
+            let word1: f64 = memory.pop()?;
             memory.push(word1 * word2);
         }
 
         Opcode::DivU8 => {
             let word2: u8 = memory.pop()?;
             let word1: u8 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1.wrapping_div(word2));
         }
         Opcode::DivI8 => {
             let word2: i8 = memory.pop()?;
             let word1: i8 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1.wrapping_div(word2));
         }
         Opcode::DivI16 => {
             let word2: i16 = memory.pop()?;
             let word1: i16 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1.wrapping_div(word2));
         }
         Opcode::DivU16 => {
             let word2: u16 = memory.pop()?;
             let word1: u16 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1.wrapping_div(word2));
         }
         Opcode::DivU32 => {
             let word2: u32 = memory.pop()?;
             let word1: u32 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1.wrapping_div(word2));
         }
         Opcode::DivI32 => {
             let word2: i32 = memory.pop()?;
             let word1: i32 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1.wrapping_div(word2));
         }
         Opcode::DivI64 => {
             let word2: i64 = memory.pop()?;
             let word1: i64 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1.wrapping_div(word2));
         }
         Opcode::DivU64 => {
             let word2: u64 = memory.pop()?;
             let word1: u64 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1.wrapping_div(word2));
         }
         Opcode::DivF32 => {
-            let word2: i32 = memory.pop()?;
-            let word1: i32 = memory.pop()?;
+            let word2: f32 = memory.pop()?;
+            let word1: f32 = memory.pop()?;
             memory.push(word1 / word2);
         }
         Opcode::DivF64 => {
-            let word2: u64 = memory.pop()?;
-            let word1: u64 = memory.pop()?;
+            let word2: f64 = memory.pop()?;
+            let word1: f64 = memory.pop()?;
             memory.push(word1 / word2);
         }
 
         Opcode::ModU8 => {
             let word2: u8 = memory.pop()?;
             let word1: u8 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1 % word2);
         }
         Opcode::ModI8 => {
             let word2: i8 = memory.pop()?;
             let word1: i8 = memory.pop()?;
-            memory.push(word1 % word2);
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
+            memory.push(word1.wrapping_rem(word2));
         }
         Opcode::ModI16 => {
             let word2: i16 = memory.pop()?;
             let word1: i16 = memory.pop()?;
-            memory.push(word1 % word2);
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
+            memory.push(word1.wrapping_rem(word2));
         }
         Opcode::ModU16 => {
             let word2: u16 = memory.pop()?;
             let word1: u16 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1 % word2);
         }
         Opcode::ModU32 => {
             let word2: u32 = memory.pop()?;
             let word1: u32 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1 % word2);
         }
         Opcode::ModI32 => {
             let word2: i32 = memory.pop()?;
             let word1: i32 = memory.pop()?;
-            memory.push(word1 % word2);
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
+            memory.push(word1.wrapping_rem(word2));
         }
         Opcode::ModI64 => {
             let word2: i64 = memory.pop()?;
             let word1: i64 = memory.pop()?;
-            memory.push(word1 % word2);
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
+            memory.push(word1.wrapping_rem(word2));
         }
         Opcode::ModU64 => {
             let word2: u64 = memory.pop()?;
             let word1: u64 = memory.pop()?;
+            if word2 == 0 {
+                return Err(divide_by_zero());
+            }
             memory.push(word1 % word2);
         }
         Opcode::ModF32 => {
-            let word2: i32 = memory.pop()?;
-            let word1: i32 = memory.pop()?;
+            let word2: f32 = memory.pop()?;
+            let word1: f32 = memory.pop()?;
             memory.push(word1 % word2);
         }
         Opcode::ModF64 => {
-            let word2: u64 = memory.pop()?;
-            let word1: u64 = memory.pop()?;
+            let word2: f64 = memory.pop()?;
+            let word1: f64 = memory.pop()?;
             memory.push(word1 % word2);
         }
 
@@ -890,33 +996,33 @@ pub fn run_op(memory: &mut Memory) -> Result<Option<EcallExt>, IError> {
         }
 
         Opcode::Jump => {
-            let target = memory.read_pc()?;
+            let target = instr.operand();
             memory.jump(target);
         }
 
         Opcode::JumpIfZero8 => {
-            let target = memory.read_pc()?;
+            let target = instr.operand();
             let value: u8 = memory.pop()?;
             if value == 0 {
                 memory.jump(target);
             }
         }
         Opcode::JumpIfZero16 => {
-            let target = memory.read_pc()?;
+            let target = instr.operand();
             let value: u16 = memory.pop()?;
             if value == 0 {
                 memory.jump(target);
             }
         }
         Opcode::JumpIfZero32 => {
-            let target = memory.read_pc()?;
+            let target = instr.operand();
             let value: u32 = memory.pop()?;
             if value == 0 {
                 memory.jump(target);
             }
         }
         Opcode::JumpIfZero64 => {
-            let target = memory.read_pc()?;
+            let target = instr.operand();
             let value: u64 = memory.pop()?;
             if value == 0 {
                 memory.jump(target);
@@ -924,28 +1030,28 @@ pub fn run_op(memory: &mut Memory) -> Result<Option<EcallExt>, IError> {
         }
 
         Opcode::JumpIfNotZero8 => {
-            let target = memory.read_pc()?;
+            let target = instr.operand();
             let value: u8 = memory.pop()?;
             if value != 0 {
                 memory.jump(target);
             }
         }
         Opcode::JumpIfNotZero16 => {
-            let target = memory.read_pc()?;
+            let target = instr.operand();
             let value: u16 = memory.pop()?;
             if value != 0 {
                 memory.jump(target);
             }
         }
         Opcode::JumpIfNotZero32 => {
-            let target = memory.read_pc()?;
+            let target = instr.operand();
             let value: u32 = memory.pop()?;
             if value != 0 {
                 memory.jump(target);
             }
         }
         Opcode::JumpIfNotZero64 => {
-            let target = memory.read_pc()?;
+            let target = instr.operand();
             let value: u64 = memory.pop()?;
             if value != 0 {
                 memory.jump(target);
@@ -1009,59 +1115,276 @@ pub fn run_op(memory: &mut Memory) -> Result<Option<EcallExt>, IError> {
             memory.free(ptr, skip)?;
             memory.push(0u64);
         }
+        Opcode::HeapMove => {
+            let skip: u32 = memory.pop()?;
+            let ptr: VarPointer = memory.pop()?;
+            memory.free(ptr, skip)?;
+            memory.heap[ptr.var_idx() - 1].meta.moved = true;
+            memory.push(0u64);
+        }
 
-        Opcode::Ecall => match memory.pop()? {
-            Ecall::Exit => {
-                let exit: i32 = memory.pop()?;
-                return Ok(Some(EcallExt::Exit(exit)));
+        Opcode::Ecall => {
+            let ecall: u32 = memory.pop()?;
+            if ecall >= HOST_ECALL_BASE {
+                let count: u32 = memory.pop()?;
+                let args: VarPointer = memory.pop()?;
+                let id = ecall - HOST_ECALL_BASE;
+                return Ok(Some(EcallExt::Host { id, args, count }));
             }
 
-            Ecall::OpenFd => {
-                let open_mode: OpenMode = memory.pop()?;
-                let name: VarPointer = memory.pop()?;
-                return Ok(Some(EcallExt::OpenFd { name, open_mode }));
-            }
-            Ecall::ReadFd => {
-                let len: u32 = memory.pop()?;
-                let buf: VarPointer = memory.pop()?;
-                let begin: u32 = memory.pop()?;
-                let fd: u32 = memory.pop()?;
+            match to_ecall(ecall)? {
+                Ecall::Exit => {
+                    let exit: i32 = memory.pop()?;
+                    return Ok(Some(EcallExt::Exit(exit)));
+                }
 
-                #[rustfmt::skip]
+                Ecall::OpenFd => {
+                    let open_mode: u32 = memory.pop()?;
+                    let or_else = || {
+                        ierror!(
+                            "InvalidOpenMode",
+                            "{} isn't a mode to open a file in",
+                            open_mode
+                        )
+                    };
+                    let open_mode = OpenMode::from_u32(open_mode).ok_or_else(or_else)?;
+                    let name: VarPointer = memory.pop()?;
+                    return Ok(Some(EcallExt::OpenFd { name, open_mode }));
+                }
+                Ecall::ReadFd => {
+                    let len: u32 = memory.pop()?;
+                    let buf: VarPointer = memory.pop()?;
+                    let begin: u32 = memory.pop()?;
+                    let fd: u32 = memory.pop()?;
+
+                    #[rustfmt::skip]
                 return Ok(Some(EcallExt::ReadFd { len, buf, begin, fd, }));
-            }
-            Ecall::WriteFd => {
-                let len: u32 = memory.pop()?;
-                let buf: VarPointer = memory.pop()?;
-                let begin: u32 = memory.pop()?;
-                let fd: u32 = memory.pop()?;
+                }
+                Ecall::WriteFd => {
+                    let len: u32 = memory.pop()?;
+                    let buf: VarPointer = memory.pop()?;
+                    let begin: u32 = memory.pop()?;
+                    let fd: u32 = memory.pop()?;
 
-                #[rustfmt::skip]
+                    #[rustfmt::skip]
                 return Ok(Some(EcallExt::WriteFd { buf, len, begin, fd }));
-            }
-            Ecall::AppendFd => {
-                let len: u32 = memory.pop()?;
-                let buf: VarPointer = memory.pop()?;
-                let fd: u32 = memory.pop()?;
+                }
+                Ecall::AppendFd => {
+                    let len: u32 = memory.pop()?;
+                    let buf: VarPointer = memory.pop()?;
+                    let fd: u32 = memory.pop()?;
 
-                #[rustfmt::skip]
+                    #[rustfmt::skip]
                 return Ok(Some(EcallExt::AppendFd { buf, len, fd }));
-            }
+                }
+
+                Ecall::Fork => return Ok(Some(EcallExt::Fork)),
+                Ecall::Execve => {
+                    let path: VarPointer = memory.pop()?;
+                    return Ok(Some(EcallExt::Execve { path }));
+                }
+                Ecall::WaitPid => {
+                    let options: i32 = memory.pop()?;
+                    let status: VarPointer = memory.pop()?;
+                    let pid: i32 = memory.pop()?;
+
+                    #[rustfmt::skip]
+                return Ok(Some(EcallExt::WaitPid { pid, status, options }));
+                }
+
+                Ecall::Pipe => {
+                    let fds: VarPointer = memory.pop()?;
+                    return Ok(Some(EcallExt::Pipe { fds }));
+                }
+                Ecall::CloseFd => {
+                    let fd: u32 = memory.pop()?;
+                    return Ok(Some(EcallExt::CloseFd { fd }));
+                }
+                Ecall::Dup2 => {
+                    let new: u32 = memory.pop()?;
+                    let old: u32 = memory.pop()?;
+                    return Ok(Some(EcallExt::Dup2 { old, new }));
+                }
+                Ecall::PollFd => {
+                    let fd: u32 = memory.pop()?;
+                    return Ok(Some(EcallExt::PollFd { fd }));
+                }
+                Ecall::Time => return Ok(Some(EcallExt::Time)),
+                Ecall::CpuTime => return Ok(Some(EcallExt::CpuTime)),
+                Ecall::Signal => {
+                    let trampoline: VarPointer = memory.pop()?;
+                    let handler: VarPointer = memory.pop()?;
+                    let sig: i32 = memory.pop()?;
 
-            call => {
-                return ierr!(
-                    "InvalidEnviromentCall",
-                    "invalid ecall value of {}",
-                    call as u32
-                )
+                    #[rustfmt::skip]
+                return Ok(Some(EcallExt::Signal { sig, handler, trampoline }));
+                }
+                Ecall::SignalReturn => return Ok(Some(EcallExt::SignalReturn)),
+                Ecall::SetJmp => {
+                    let buf: VarPointer = memory.pop()?;
+                    return Ok(Some(EcallExt::SetJmp { buf }));
+                }
+                Ecall::LongJmp => {
+                    let val: i32 = memory.pop()?;
+                    let buf: VarPointer = memory.pop()?;
+                    return Ok(Some(EcallExt::LongJmp { buf, val }));
+                }
+
+                call => {
+                    return ierr!(
+                        "InvalidEnviromentCall",
+                        "invalid ecall value of {}",
+                        call as u32
+                    )
+                }
             }
-        },
+        }
 
         Opcode::AssertStr => {
             let string = memory.pop()?;
             memory.cstring_bytes(string)?;
         }
+        Opcode::AssertAlign => {
+            let align: u32 = instr.operand();
+            let ptr: VarPointer = memory.pop()?;
+            if ptr.offset() % align != 0 {
+                return Err(ierror!(
+                    "MisalignedAccess",
+                    "accessed a {}-byte value through the pointer {}, whose offset of {} \
+                     isn't a multiple of {}",
+                    align,
+                    ptr,
+                    ptr.offset(),
+                    align
+                ));
+            }
+
+            memory.push(ptr);
+        }
+        Opcode::AssertNoOverflow => {
+            let op: CheckedOp = instr.operand();
+            let bytes: u32 = instr.operand_at(1);
+            check_overflow(memory, op, bytes)?;
+        }
+        Opcode::AssertInObject => {
+            let op: CheckedOp = instr.operand();
+            check_in_object(memory, op)?;
+        }
+        Opcode::AssertSameObject => {
+            let right = VarPointer::from(peek_u64(memory, 0)?);
+            let left = VarPointer::from(peek_u64(memory, 1)?);
+            if !left.is_null() && !right.is_null() && !left.same_var(right) {
+                let (left_kind, right_kind) = (memory.object_kind(left), memory.object_kind(right));
+                let another = if left_kind == right_kind {
+                    "another"
+                } else {
+                    "a"
+                };
+                return Err(ierror!(
+                    "PointerProvenance",
+                    "the pointers {} and {} point into different objects (a {} and {} {}), \
+                     so subtracting or ordering them doesn't mean anything; only pointers into \
+                     the same object can be subtracted or compared with <, >, <=, or >=",
+                    left,
+                    right,
+                    left_kind,
+                    another,
+                    right_kind
+                ));
+            }
+        }
     }
 
     return Ok(None);
 }
+
+/// Errors if `op` on the `bytes`-wide signed integers on top of the stack would
+/// overflow. The operands are left on the stack for the operation itself.
+fn check_overflow(memory: &Memory, op: CheckedOp, bytes: u32) -> Result<(), IError> {
+    use core::convert::TryInto;
+
+    let peek = |depth: usize| -> Result<i128, IError> {
+        let (len, bytes) = (memory.expr_stack.len(), bytes as usize);
+        let start = len.checked_sub((depth + 1) * bytes);
+        let start = start.ok_or_else(|| expr_stack_too_short(len, (depth + 1) * bytes))?;
+        let word = &memory.expr_stack[start..(start + bytes)];
+        if bytes == 4 {
+            return Ok(i32::from_le_bytes(word.try_into().unwrap()) as i128);
+        }
+
+        return Ok(i64::from_le_bytes(word.try_into().unwrap()) as i128);
+    };
+
+    let b = peek(0)?;
+    let (a, result, symbol) = match op {
+        CheckedOp::Neg => (0, -b, "-"),
+        CheckedOp::Add => (peek(1)?, peek(1)? + b, "+"),
+        CheckedOp::Sub => (peek(1)?, peek(1)? - b, "-"),
+        CheckedOp::Mul => (peek(1)?, peek(1)? * b, "*"),
+        _ if b == 0 => return Ok(()), // the division reports this itself
+        CheckedOp::Div => (peek(1)?, peek(1)? / b, "/"),
+        CheckedOp::Mod => (peek(1)?, peek(1)? / b, "%"),
+    };
+
+    let bits = bytes * 8;
+    let (min, max) = (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1);
+    if min <= result && result <= max {
+        return Ok(());
+    }
+
+    let expr = match op {
+        CheckedOp::Neg => format!("-({})", b),
+        _ => format!("{} {} {}", a, symbol, b),
+    };
+
+    return Err(ierror!(
+        "SignedOverflow",
+        "{} overflows a {}-bit signed integer",
+        expr,
+        bits
+    ));
+}
+
+/// The 8 bytes `depth` words down from the top of the stack
+fn peek_u64(memory: &Memory, depth: usize) -> Result<u64, IError> {
+    let len = memory.expr_stack.len();
+    let start = len.checked_sub((depth + 1) * 8);
+    let start = start.ok_or_else(|| expr_stack_too_short(len, (depth + 1) * 8))?;
+    return Ok(u64::from_bytes(&memory.expr_stack[start..(start + 8)]));
+}
+
+/// Errors if `op` on the pointer and byte offset on top of the stack would move
+/// the pointer out of the object it points into and into another one. A pointer
+/// can go past the end of its object, but TCI keeps the object in the pointer's
+/// upper bits, which only change when the offset wraps around. The operands are
+/// left on the stack for the operation itself.
+fn check_in_object(memory: &Memory, op: CheckedOp) -> Result<(), IError> {
+    let (ptr, bytes) = (VarPointer::from(peek_u64(memory, 1)?), peek_u64(memory, 0)?);
+    let (moved, delta) = match op {
+        CheckedOp::Sub => (ptr.sub(bytes), -(bytes as i64 as i128)),
+        _ => (ptr.add(bytes), bytes as i64 as i128),
+    };
+
+    if ptr.is_null() || ptr.same_var(moved) {
+        return Ok(());
+    }
+
+    return Err(ierror!(
+        "PointerProvenance",
+        "{} {} {} bytes moves a pointer into a {} so far that it points into a different \
+         object; pointers can only move around inside the object they point into",
+        ptr,
+        if delta < 0 { "-" } else { "+" },
+        delta.abs(),
+        memory.object_kind(ptr)
+    ));
+}
+
+pub fn divide_by_zero() -> IError {
+    return ierror!("DivideByZero", "integer division by zero");
+}
+
+fn to_ecall(ecall: u32) -> Result<Ecall, IError> {
+    let or_else = || ierror!("InvalidEcall", "{} isn't a system call", ecall);
+    return Ecall::from_u32(ecall).ok_or_else(or_else);
+}
@@ -1,11 +1,26 @@
+use super::coverage::*;
 use super::error::*;
 use super::fs::*;
+use super::history::*;
+use super::host::*;
 use super::interpreter::*;
 use super::memory::*;
+use super::profile::*;
+use super::snapshot::*;
+use super::trace::*;
 use super::types::*;
 use crate::util::*;
 use core::mem;
 
+/// Descriptors 0 through 3 of processes on the terminal: stdin, stdout, stderr,
+/// and stdlog
+const TERM_FDS: [FdKind; 4] = [
+    FdKind::TermIn,
+    FdKind::TermOut,
+    FdKind::TermErr,
+    FdKind::TermLog,
+];
+
 #[derive(Debug, Clone, Copy)]
 pub enum IRtStat {
     // internal runtime status
@@ -20,17 +35,134 @@ pub enum KernStat {
     Errored(u32),
 }
 
+/// An in-memory pipe. Writes never block; reads block while the pipe is empty and
+/// some process still has the write end open.
+#[derive(Debug, Clone)]
+pub struct Pipe {
+    pub data: Vec<u8>,
+    pub begin: usize,
+    pub readers: u32,
+    pub writers: u32,
+}
+
+impl Pipe {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            begin: 0,
+            readers: 1,
+            writers: 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.data.len() - self.begin;
+    }
+
+    /// Removes up to `len` bytes from the front of the pipe and copies them into `out`
+    pub fn read(&mut self, len: u32, out: &mut Vec<u8>) {
+        let end = core::cmp::min(self.data.len(), self.begin + len as usize);
+        out.extend_from_slice(&self.data[self.begin..end]);
+
+        self.begin = end;
+        if end == self.data.len() {
+            self.begin = 0;
+            self.data.clear();
+        }
+    }
+}
+
+pub fn acquire_fd(pipes: &mut Vec<Pipe>, fd: FdKind) {
+    match fd {
+        FdKind::PipeRead(pipe) => pipes[pipe as usize].readers += 1,
+        FdKind::PipeWrite(pipe) => pipes[pipe as usize].writers += 1,
+        _ => {}
+    }
+}
+
+pub fn release_fd(pipes: &mut Vec<Pipe>, fd: FdKind) {
+    match fd {
+        FdKind::PipeRead(pipe) => pipes[pipe as usize].readers -= 1,
+        FdKind::PipeWrite(pipe) => pipes[pipe as usize].writers -= 1,
+        _ => {}
+    }
+}
+
+// ABI matters here. These are linked to /lib/header/signal.h
+pub const SIGFPE: i32 = 8;
+pub const SIGSEGV: i32 = 11;
+pub const NSIG: usize = 32;
+
 pub struct Process {
     pub memory: Memory,
     pub status: IRtStat,
+    pub op_count: u64,
+
+    pub parent: u32, // !0 if the process wasn't forked
+    pub reaped: bool,
+    pub pending: Option<EcallExt>, // ecall to retry once a blocked process wakes up
+    pub history: Option<History>,
+
+    pub signals: [VarPointer; NSIG], // handlers installed with `signal()`
+    pub trampoline: VarPointer,      // calls a signal handler for the kernel
+    pub fault: Option<IError>,       // error to exit with once the running handler returns
 }
 
 impl Process {
-    pub fn new(binary: &BinaryData) -> Self {
+    pub fn new(binary: &BinaryData, limits: Limits) -> Self {
+        let mut memory = Memory::new(binary);
+        memory.limits = limits;
+
         Self {
-            memory: Memory::new(binary),
+            memory,
             status: IRtStat::Running,
+            op_count: 0,
+
+            parent: !0,
+            reaped: false,
+            pending: None,
+            history: None,
+
+            signals: [VarPointer::from(0); NSIG],
+            trampoline: VarPointer::from(0),
+            fault: None,
+        }
+    }
+
+    /// Starts the handler the process installed for the signal `err` raises, if it
+    /// has one. Faults can't be resumed, so once the handler returns the process
+    /// exits with `err` anyways.
+    pub fn deliver_fault(&mut self, err: IError) -> Result<(), IError> {
+        let sig = match &*err.short_name {
+            "DivideByZero" => SIGFPE,
+            "InvalidPointer" | "NullPointer" | "ReadOnlyWrite" => SIGSEGV,
+            _ => return Err(err),
+        };
+
+        // SIG_DFL and SIG_IGN are both null pointers, give or take an offset
+        let handler = self.signals[sig as usize];
+        if self.fault.is_some() || handler.var_idx() == 0 {
+            return Err(err);
+        }
+
+        let (memory, trampoline) = (&mut self.memory, self.trampoline);
+        memory.expr_stack.clear();
+        let mut call = || -> Result<(), IError> {
+            memory.add_stack_var(0)?;
+            let handler_param = memory.add_stack_var(8)?;
+            memory.write_bytes(handler_param, &to_mem_bytes(handler))?;
+            let sig_param = memory.add_stack_var(4)?;
+            memory.write_bytes(sig_param, &to_mem_bytes(sig))?;
+            memory.add_stack_var(0)?;
+            return memory.call(trampoline);
+        };
+
+        if let Err(_) = call() {
+            return Err(err);
         }
+
+        self.fault = Some(err);
+        return Ok(());
     }
 }
 
@@ -38,17 +170,81 @@ pub struct Kernel {
     pub files: FileSystem,
     pub in_begin: usize,
     pub input: Vec<u8>,
+    // while this is set, reading from an empty terminal parks the process until more
+    // input is written, instead of returning end-of-file
+    pub stdin_open: bool,
     pub output: TaggedMultiArray<WriteEvt, u8>,
-    // pub pipes: TaggedMultiVec<usize, u8>,
+    pub pipes: Vec<Pipe>,
     pub processes: TaggedMultiVec<Process, FdKind>,
+    pub programs: HashMap<String, BinaryData>, // binaries that `execve` can load
 
     pub term_proc: u32,
     pub current_proc: u32,
     pub current_proc_op_count: u32,
     pub active_count: u32,
+    pub time_slice: u32, // ops a process runs before the next one is scheduled
+
+    pub clock: ClockMode,
+    pub total_ops: u64, // instructions run by every process, for the virtual clock
+
+    /// Wall-clock time after which running stops with a `DeadlineExceeded` error.
+    /// Processes are left as they are, so moving the deadline lets them continue.
+    pub deadline: Option<Deadline>,
+
+    pub profile: Option<Profile>,
+    pub coverage: Option<Coverage>,
+    pub trace: TraceMode,
+    traced_input: Vec<u8>,
+    pub limits: Limits, // applied to processes as they're loaded
+    pub host_fns: HostFns,
+
+    // process and watchpoint that stopped the last run; see `Memory::watch`
+    pub watch_hit: Option<(u32, WatchHit)>,
 }
 
+/// Where `time` and `gettimeofday` get the time from
+#[derive(Debug, Clone, Copy)]
+pub enum ClockMode {
+    /// Starts at `VIRTUAL_EPOCH` and advances a microsecond per instruction, so that
+    /// programs that print or seed with the time behave the same on every run
+    Virtual,
+    /// Microseconds since the Unix epoch, supplied by the host
+    Host(fn() -> u64),
+}
+
+/// A point in wall-clock time, for `Kernel::deadline`. The runtime is `no_std`,
+/// so the host supplies the clock. It's separate from `ClockMode`, so that a
+/// program on the virtual clock still can't run past its deadline.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    pub clock: fn() -> u64, // monotonic microseconds, e.g. from `std::time::Instant`
+    pub at: u64,            // in `clock`'s microseconds
+}
+
+impl Deadline {
+    /// The deadline `micros` microseconds from now, according to `clock`
+    pub fn after(clock: fn() -> u64, micros: u64) -> Self {
+        return Self {
+            clock,
+            at: clock().saturating_add(micros),
+        };
+    }
+
+    pub fn passed(&self) -> bool {
+        return (self.clock)() >= self.at;
+    }
+}
+
+pub const VIRTUAL_EPOCH: u64 = 1_609_459_200_000_000; // 2021-01-01 00:00:00 UTC, in microseconds
 const PROC_MAX_OP_COUNT: u32 = 5000;
+const MAX_PROCESSES: usize = 256;
+const WNOHANG: i32 = 1; // linked to /lib/header/sys/wait.h
+
+// linked to /lib/header/poll.h
+const POLLIN: u64 = 0x1;
+const POLLOUT: u64 = 0x4;
+const POLLHUP: u64 = 0x10;
+const POLLNVAL: u64 = 0x20;
 
 impl Kernel {
     pub fn new(files: Vec<(String, u32, Vec<u8>)>) -> Self {
@@ -56,17 +252,259 @@ impl Kernel {
             files: FileSystem::new(files),
             in_begin: 0,
             input: Vec::new(),
+            stdin_open: false,
             output: TaggedMultiArray::new(),
 
             processes: TaggedMultiVec::new(),
+            programs: HashMap::new(),
+            pipes: Vec::new(),
 
             term_proc: !0,
             current_proc: !0,
             current_proc_op_count: 0,
             active_count: 0,
+            time_slice: PROC_MAX_OP_COUNT,
+
+            clock: ClockMode::Virtual,
+            total_ops: 0,
+            deadline: None,
+
+            profile: None,
+            coverage: None,
+            trace: TraceMode::Off,
+            traced_input: Vec::new(),
+            limits: Limits::DEFAULT,
+            host_fns: HostFns::new(),
+
+            watch_hit: None,
         }
     }
 
+    /// Record every ecall made from now on; get the trace with `take_trace`
+    pub fn record(&mut self) {
+        self.trace = TraceMode::Record(Trace::new());
+    }
+
+    /// Play back a recorded trace: terminal input comes from the trace instead of
+    /// from `write`, and every other ecall is checked against what was recorded.
+    pub fn replay(&mut self, trace: Trace) {
+        self.trace = TraceMode::Replay(trace, 0);
+    }
+
+    pub fn take_trace(&mut self) -> Option<Trace> {
+        return match mem::replace(&mut self.trace, TraceMode::Off) {
+            TraceMode::Record(trace) => Some(trace),
+            TraceMode::Replay(trace, _) => Some(trace),
+            TraceMode::Off => None,
+        };
+    }
+
+    /// Serializes the state of every process, pipe, and file, so that it can be
+    /// restored later with `restore`. Profiling, tracing, and output events that
+    /// haven't been collected yet aren't included.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = SnapshotWriter::new();
+
+        out.put(self.files.files.len() as u64);
+        for file in &self.files.files {
+            out.put(*file.tag);
+            out.put_slice(file.data);
+        }
+        out.put(self.files.names.len() as u64);
+        for (name, id) in &self.files.names {
+            out.put_str(name);
+            out.put(*id);
+        }
+        out.put(self.files.size as u64);
+
+        out.put(self.in_begin as u64);
+        out.put_slice(&self.input);
+        out.put(self.stdin_open);
+
+        out.put(self.processes.len() as u64);
+        for proc in &self.processes {
+            let tag = proc.tag;
+            tag.memory.write_snapshot(&mut out);
+            out.put(tag.status);
+            out.put(tag.op_count);
+            out.put(tag.parent);
+            out.put(tag.reaped);
+            out.put(tag.pending);
+            out.put(tag.signals);
+            out.put(tag.trampoline);
+            out.put(tag.fault.is_some());
+            if let Some(fault) = &tag.fault {
+                out.put_str(&fault.short_name);
+                out.put_str(&fault.message);
+            }
+            out.put_slice(proc.data);
+        }
+
+        out.put(self.programs.len() as u64);
+        for (path, binary) in &self.programs {
+            out.put_str(path);
+            out.put_slice(&binary.data);
+            out.put_slice(&binary.vars);
+        }
+
+        out.put(self.pipes.len() as u64);
+        for pipe in &self.pipes {
+            out.put_slice(&pipe.data);
+            out.put(pipe.begin as u64);
+            out.put(pipe.readers);
+            out.put(pipe.writers);
+        }
+
+        out.put(self.term_proc);
+        out.put(self.current_proc);
+        out.put(self.current_proc_op_count);
+        out.put(self.active_count);
+        out.put(self.time_slice);
+        out.put(self.limits);
+        out.put(self.total_ops);
+
+        return out.bytes;
+    }
+
+    /// Replaces the kernel's state with a snapshot from `snapshot`
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), IError> {
+        let mut snap = SnapshotReader::new(bytes)?;
+
+        let mut files = FileSystem::new(Vec::new());
+        for _ in 0..snap.get::<u64>()? {
+            let tag: bool = snap.get()?;
+            files.files.push(tag, snap.get_vec()?);
+        }
+        for _ in 0..snap.get::<u64>()? {
+            let name = snap.get_string()?;
+            files.names.insert(name, snap.get()?);
+        }
+        files.size = snap.get::<u64>()? as usize;
+
+        let in_begin = snap.get::<u64>()? as usize;
+        let (input, stdin_open) = (snap.get_vec()?, snap.get()?);
+
+        let mut processes = TaggedMultiVec::new();
+        for _ in 0..snap.get::<u64>()? {
+            let proc = Process {
+                memory: Memory::read_snapshot(&mut snap)?,
+                status: snap.get()?,
+                op_count: snap.get()?,
+                parent: snap.get()?,
+                reaped: snap.get()?,
+                pending: snap.get()?,
+                history: None,
+
+                signals: snap.get()?,
+                trampoline: snap.get()?,
+                fault: match snap.get()? {
+                    true => Some(IError::new(snap.get_string()?, snap.get_string()?)),
+                    false => None,
+                },
+            };
+            processes.push(proc, snap.get_vec()?);
+        }
+
+        let mut programs = HashMap::new();
+        for _ in 0..snap.get::<u64>()? {
+            let path = snap.get_string()?;
+            let (data, vars) = (snap.get_vec()?, snap.get_vec()?);
+            let strings = HashMap::new(); // only used while assembling
+            let binary = BinaryData {
+                data,
+                vars,
+                strings,
+            };
+            programs.insert(path, binary);
+        }
+
+        let mut pipes = Vec::new();
+        for _ in 0..snap.get::<u64>()? {
+            pipes.push(Pipe {
+                data: snap.get_vec()?,
+                begin: snap.get::<u64>()? as usize,
+                readers: snap.get()?,
+                writers: snap.get()?,
+            });
+        }
+
+        let (term_proc, current_proc) = (snap.get()?, snap.get()?);
+        let (current_proc_op_count, active_count) = (snap.get()?, snap.get()?);
+        let (time_slice, limits) = (snap.get()?, snap.get()?);
+        let total_ops = snap.get()?;
+        if !snap.is_done() {
+            return Err(ierror!("InvalidSnapshot", "snapshot has trailing data"));
+        }
+
+        // Everything that indexes into something else has to be in range, since
+        // running the kernel trusts it
+        let count = processes.len();
+        let valid_proc = |id: u32| id == !0 || (id as usize) < count;
+        if in_begin > input.len() {
+            return Err(inconsistent("input position"));
+        }
+        if pipes.iter().any(|pipe| pipe.begin > pipe.data.len()) {
+            return Err(inconsistent("pipe position"));
+        }
+        if !valid_proc(term_proc) || !valid_proc(current_proc) {
+            return Err(inconsistent("process id"));
+        }
+
+        for idx in 0..count {
+            let proc = processes.get_mut(idx).unwrap().into_tag_mut();
+            if !valid_proc(proc.parent) {
+                return Err(inconsistent("parent process id"));
+            }
+
+            let running = !matches!(proc.status, IRtStat::Exited(_));
+            proc.memory.check_snapshot(running)?;
+        }
+
+        self.term_proc = term_proc;
+        self.current_proc = current_proc;
+        self.current_proc_op_count = current_proc_op_count;
+        self.active_count = active_count;
+        self.time_slice = time_slice;
+        self.limits = limits;
+        self.total_ops = total_ops;
+
+        self.files = files;
+        self.in_begin = in_begin;
+        self.input = input;
+        self.stdin_open = stdin_open;
+        self.processes = processes;
+        self.programs = programs;
+        self.pipes = pipes;
+        self.watch_hit = None;
+        return Ok(());
+    }
+
+    /// Count instructions per function and time ecalls using `clock`; read the
+    /// results from `self.profile` once the program exits.
+    pub fn enable_profiling(&mut self, clock: fn() -> u64) {
+        self.profile = Some(Profile::new(clock));
+    }
+
+    /// Count how many times each source location is entered; turn the counts into a
+    /// report with the statement locations in the program's `DebugInfo`.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    /// Use the host's time for `time` and `gettimeofday`, e.g. for interactive use.
+    /// `clock` returns microseconds since the Unix epoch.
+    pub fn use_host_clock(&mut self, clock: fn() -> u64) {
+        self.clock = ClockMode::Host(clock);
+    }
+
+    /// Microseconds since the Unix epoch, according to `self.clock`
+    pub fn now(&self) -> u64 {
+        return match self.clock {
+            ClockMode::Virtual => VIRTUAL_EPOCH + self.total_ops,
+            ClockMode::Host(clock) => clock(),
+        };
+    }
+
     pub fn loc(&self) -> CodeLoc {
         if self.current_proc == !0 {
             return NO_FILE;
@@ -85,13 +523,68 @@ impl Kernel {
         return Some(&tag.memory);
     }
 
+    /// Memory of the process `proc`, for reading values out of a running program
+    pub fn memory(&self, proc: u32) -> Result<&Memory, IError> {
+        let or_else = || no_process(proc);
+        let proc = self.processes.get(proc as usize).ok_or_else(or_else)?;
+        return Ok(&proc.tag.memory);
+    }
+
+    /// Memory of the process `proc`, for tools that change a paused program, like
+    /// the REPL adding code to it
+    pub fn memory_mut(&mut self, proc: u32) -> Result<&mut Memory, IError> {
+        let or_else = || no_process(proc);
+        let proc = self.processes.get_mut(proc as usize).ok_or_else(or_else)?;
+        return Ok(&mut proc.into_tag_mut().memory);
+    }
+
+    /// The C string at `ptr` in the process `proc`, without its null terminator.
+    /// Bytes that aren't UTF-8 are replaced.
+    pub fn read_cstring(&self, proc: u32, ptr: VarPointer) -> Result<String, IError> {
+        let bytes = self.memory(proc)?.cstring_bytes(ptr)?;
+        return Ok(String::from_utf8_lossy(bytes).into_owned());
+    }
+
+    pub fn read_bytes(&self, proc: u32, ptr: VarPointer, len: u32) -> Result<Vec<u8>, IError> {
+        return Ok(self.memory(proc)?.read_bytes(ptr, len)?.to_vec());
+    }
+
+    pub fn read_value<T: MemValue>(&self, proc: u32, ptr: VarPointer) -> Result<T, IError> {
+        return self.memory(proc)?.read(ptr);
+    }
+
+    /// Writes `bytes` into memory the process `proc` owns, with the same checks
+    /// as a store the program makes itself; read-only and freed memory can't be
+    /// written to, and writes can't go past the end of a variable.
+    pub fn write_bytes(&mut self, proc: u32, ptr: VarPointer, bytes: &[u8]) -> Result<(), IError> {
+        let or_else = || no_process(proc);
+        let mut proc = self.processes.get_mut(proc as usize).ok_or_else(or_else)?;
+        return proc.tag_mut().memory.write_bytes(ptr, bytes);
+    }
+
+    pub fn write_value<T: MemValue>(
+        &mut self,
+        proc: u32,
+        ptr: VarPointer,
+        value: T,
+    ) -> Result<(), IError> {
+        return self.write_bytes(proc, ptr, &to_mem_bytes(value));
+    }
+
     pub fn load_term_program(&mut self, binary: &BinaryData) -> u32 {
         if self.term_proc != !0 {
             let mut prev = self.processes.get_mut(self.term_proc as usize).unwrap();
-            match &mut prev.tag_mut().status {
+            let status = &mut prev.tag_mut().status;
+            match status {
                 IRtStat::Exited(_) => {}
-                x => *x = IRtStat::Exited(1),
+                IRtStat::Blocked => *status = IRtStat::Exited(1),
+                IRtStat::Running => {
+                    *status = IRtStat::Exited(1);
+                    self.active_count -= 1;
+                }
             }
+
+            self.exited(self.term_proc);
         }
 
         self.term_proc = self.processes.len() as u32;
@@ -99,15 +592,125 @@ impl Kernel {
             self.current_proc = self.term_proc;
         }
 
-        let (i, o, proc) = (FdKind::TermIn, FdKind::TermOut, Process::new(binary));
+        let proc = Process::new(binary, self.limits);
         self.in_begin = 0;
         self.input.clear();
         mem::drop(mem::replace(&mut self.output, TaggedMultiArray::new()));
-        self.processes.push(proc, vec![i, o, o, o]);
+        self.processes.push(proc, TERM_FDS.to_vec());
         self.active_count += 1;
         return self.term_proc;
     }
 
+    /// Adds a process running `binary` alongside the others, sharing the terminal
+    /// for its output. Returns the new process's id.
+    pub fn spawn(&mut self, binary: &BinaryData) -> u32 {
+        let proc_id = self.processes.len() as u32;
+        if self.current_proc == !0 {
+            self.current_proc = proc_id;
+        }
+
+        let proc = Process::new(binary, self.limits);
+        self.processes.push(proc, TERM_FDS.to_vec());
+        self.active_count += 1;
+        return proc_id;
+    }
+
+    /// Makes `binary` available to `execve` under `path`
+    pub fn install(&mut self, path: &str, binary: BinaryData) {
+        self.programs.insert(path.to_string(), binary);
+    }
+
+    /// Starts recording the last `capacity` steps `proc` takes, so that it can be
+    /// rewound with `step_back`
+    pub fn enable_history(&mut self, proc: u32, capacity: usize) {
+        let mut proc = self.processes.get_mut(proc as usize).unwrap();
+        let proc = proc.tag_mut();
+        proc.memory.change_log = Some(Vec::new());
+        proc.history = Some(History::new(capacity));
+    }
+
+    /// Rewinds `proc` to the start of the step `steps` steps before the one it's
+    /// in; see `History::step_back`. Only the process's memory is rewound, so output
+    /// and files written in the meantime stay as they are.
+    pub fn step_back(&mut self, proc_id: u32, steps: u32) -> Result<u32, IError> {
+        let or_else = || ierror!("InvalidProcess", "process {} doesn't exist", proc_id);
+        let mut proc = self
+            .processes
+            .get_mut(proc_id as usize)
+            .ok_or_else(or_else)?;
+        let proc = proc.tag_mut();
+
+        let history = match &mut proc.history {
+            Some(history) => history,
+            None => {
+                return Err(ierror!(
+                    "NoHistory",
+                    "history isn't being recorded for process {}",
+                    proc_id
+                ))
+            }
+        };
+
+        if history.len() == 0 {
+            return Ok(0);
+        }
+
+        match proc.status {
+            IRtStat::Running => {}
+            IRtStat::Blocked => {
+                proc.status = IRtStat::Running;
+                proc.pending = None; // the ecall runs again when the process gets back to it
+                self.active_count += 1;
+            }
+            IRtStat::Exited(_) => {
+                return Err(ierror!(
+                    "ProcessExited",
+                    "process {} has already exited",
+                    proc_id
+                ))
+            }
+        }
+
+        return Ok(history.step_back(&mut proc.memory, steps));
+    }
+
+    pub fn exit_status(&self, proc: u32) -> Option<i32> {
+        let proc = self.processes.get(proc as usize)?;
+        return match proc.tag.status {
+            IRtStat::Exited(c) => Some(c),
+            _ => None,
+        };
+    }
+
+    /// Runs every process until none of them can make progress, scheduling them
+    /// round-robin every `time_slice` ops. A runtime error only stops the process
+    /// that caused it; the result for each process is in order of process id.
+    pub fn run_all(&mut self) -> Vec<Result<i32, IError>> {
+        let mut errors = HashMap::new();
+        while self.active_count != 0 {
+            if let Err(e) = self.run_op_count(!0) {
+                errors.insert(self.current_proc, e);
+            }
+        }
+
+        let mut results = Vec::new();
+        for proc_id in 0..(self.processes.len() as u32) {
+            let result = match (errors.remove(&proc_id), self.exit_status(proc_id)) {
+                (Some(e), _) => Err(e),
+                (None, Some(code)) => Ok(code),
+                (None, None) => Err(ierror!(
+                    "Blocked",
+                    "process {} is blocked and can't make progress",
+                    proc_id
+                )),
+            };
+
+            results.push(result);
+        }
+
+        return results;
+    }
+
     pub fn run(&mut self, binary: &BinaryData) -> Result<i32, IError> {
         let proc_id = self.load_term_program(binary);
 
@@ -117,10 +720,41 @@ impl Kernel {
                 return Ok(c);
             }
 
-            self.run_op_count(!0)?;
+            if self.active_count == 0 {
+                let e = ierror!(
+                    "Deadlock",
+                    "every process is blocked, so the program can't make progress"
+                );
+                self.report_error(None, &e);
+                return Err(e);
+            }
+
+            // Errors are reported on stderr. In forked children they only end the
+            // child; its parent sees the failure through waitpid
+            if let Err(e) = self.run_op_count(!0) {
+                if self.current_proc == proc_id {
+                    self.report_error(None, &e);
+                    return Err(e);
+                }
+
+                self.report_error(Some(self.current_proc), &e);
+            }
         }
     }
 
+    /// Writes `e` to stderr, naming the process if it isn't the one `run` started
+    fn report_error(&mut self, proc_id: Option<u32>, e: &IError) {
+        let mut out = StringWriter::new();
+        let name = &e.short_name;
+        match proc_id {
+            None => write!(out, "{}: {}\n", name, e.message).unwrap(),
+            Some(id) => write!(out, "process {}: {}: {}\n", id, name, e.message).unwrap(),
+        }
+
+        self.output
+            .push_from(WriteEvt::StderrWrite, out.to_string().as_bytes());
+    }
+
     pub fn run_debug(&mut self, binary: &BinaryData) -> Result<i32, IError> {
         let proc_id = self.load_term_program(binary);
         let mut out = StringWriter::new();
@@ -143,6 +777,12 @@ impl Kernel {
 
     pub fn run_op_count(&mut self, mut count: u32) -> Result<(), IError> {
         while count > 0 && self.active_count != 0 {
+            if let Some(deadline) = self.deadline {
+                if deadline.passed() {
+                    return Err(deadline_exceeded());
+                }
+            }
+
             let mut proc = match self.processes.get_mut(self.current_proc as usize) {
                 Some(p) => p,
                 None => {
@@ -155,10 +795,12 @@ impl Kernel {
 
             match proc.tag().status {
                 IRtStat::Running => {}
-                _ => {
+                status => {
                     self.current_proc_op_count = 0;
-                    if self.current_proc == self.term_proc {
-                        self.term_proc = !0;
+                    if let IRtStat::Exited(_) = status {
+                        if self.current_proc == self.term_proc {
+                            self.term_proc = !0;
+                        }
                     }
 
                     self.current_proc += 1;
@@ -169,24 +811,73 @@ impl Kernel {
                 }
             }
 
-            let ops_allowed = core::cmp::min(count, PROC_MAX_OP_COUNT - self.current_proc_op_count);
-            let (ran_count, res) = run_op_count(&mut proc.tag_mut().memory, ops_allowed);
+            let ops_allowed = core::cmp::min(
+                count,
+                self.time_slice.saturating_sub(self.current_proc_op_count),
+            );
+            let (max_ops, op_count) = (proc.tag().memory.limits.max_ops, proc.tag().op_count);
+            let ops_left = core::cmp::min(max_ops - op_count, ops_allowed as u64) as u32;
+
+            let Process {
+                memory,
+                pending,
+                history,
+                ..
+            } = proc.tag_mut();
+            let pending = pending.take();
+            let (ran_count, res) = match (&mut self.profile, history, &mut self.coverage) {
+                _ if pending.is_some() => (0, Ok(pending)),
+                _ if op_count == max_ops => (0, Err(op_limit(max_ops))),
+                (None, Some(history), None) => run_op_count_recorded(memory, ops_left, history),
+                (Some(profile), None, None) => run_op_count_profiled(memory, ops_left, profile),
+                (None, None, None) => run_op_count(memory, ops_left),
+                (profile, history, coverage) => run_op_count_observed(memory, ops_left, |memory| {
+                    if let Some(profile) = profile {
+                        profile.record_op(memory.current_func, memory.loc);
+                    }
+
+                    if let Some(history) = history {
+                        history.record(memory);
+                    }
+
+                    if let Some(coverage) = coverage {
+                        coverage.record(memory.loc);
+                    }
+                }),
+            };
+            proc.tag_mut().op_count += ran_count as u64;
+            self.total_ops += ran_count as u64;
             self.current_proc_op_count += ran_count;
             count -= ran_count;
 
             match res {
                 Err(e) => {
+                    let e = match proc.tag_mut().deliver_fault(e) {
+                        Ok(()) => continue,
+                        Err(e) => e,
+                    };
+
                     proc.tag_mut().status = IRtStat::Exited(1);
                     self.active_count -= 1;
                     if self.current_proc == self.term_proc {
                         self.term_proc = !0;
                     }
 
+                    self.exited(self.current_proc);
                     return Err(e);
                 }
                 Ok(Some(ecall)) => {
-                    let res = self.ecall(self.current_proc, ecall);
+                    let (name, start) = (ecall.name(), self.profile.as_ref().map(|p| p.now()));
+                    let res = self.traced_ecall(self.current_proc, ecall);
+                    if let (Some(profile), Some(start)) = (&mut self.profile, start) {
+                        let elapsed = profile.now() - start;
+                        profile.record_ecall(name, elapsed);
+                    }
+
                     let mut proc = self.processes.get_mut(self.current_proc as usize).unwrap();
+                    if let Some(hit) = proc.tag_mut().memory.watch_hit.take() {
+                        self.watch_hit = Some((self.current_proc, hit));
+                    }
 
                     match res {
                         Ok(IRtStat::Blocked) => {
@@ -196,6 +887,7 @@ impl Kernel {
                         Ok(IRtStat::Exited(exit)) => {
                             self.active_count -= 1;
                             proc.tag_mut().status = IRtStat::Exited(exit);
+                            self.exited(self.current_proc);
                         }
                         Ok(IRtStat::Running) => {}
                         Err(e) => {
@@ -207,20 +899,22 @@ impl Kernel {
                             let mut proc =
                                 self.processes.get_mut(self.current_proc as usize).unwrap();
                             proc.tag_mut().status = IRtStat::Exited(1);
+                            self.exited(self.current_proc);
                             return Err(e);
                         }
                     }
 
                     return Ok(());
                 }
-                Ok(None) => {}
+                Ok(None) => {
+                    if let Some(hit) = proc.tag_mut().memory.watch_hit.take() {
+                        self.watch_hit = Some((self.current_proc, hit));
+                        return Ok(());
+                    }
+                }
             }
 
             self.current_proc_op_count = 0;
-            if self.current_proc == self.term_proc {
-                self.term_proc = !0;
-            }
-
             self.current_proc += 1;
             if self.current_proc as usize == self.processes.len() {
                 self.current_proc = 0;
@@ -230,12 +924,408 @@ impl Kernel {
         return Ok(());
     }
 
+    /// Closes everything `proc` had open and wakes up processes that might have
+    /// been waiting on it; call this whenever a process exits.
+    fn exited(&mut self, proc: u32) {
+        let mut proc = self.processes.get_mut(proc as usize).unwrap();
+        while let Some(fd) = proc.pop() {
+            release_fd(&mut self.pipes, fd);
+        }
+
+        self.wake_blocked();
+    }
+
+    /// Lets every process blocked in an ecall run again so it can retry the ecall,
+    /// e.g. `waitpid` after a child exits or `read` after a pipe gets data.
+    fn wake_blocked(&mut self) {
+        for idx in 0..self.processes.len() {
+            let mut proc = self.processes.get_mut(idx).unwrap();
+            let proc = proc.tag_mut();
+            if let (IRtStat::Blocked, Some(_)) = (proc.status, &proc.pending) {
+                proc.status = IRtStat::Running;
+                self.active_count += 1;
+            }
+        }
+    }
+
+    fn pipe(&mut self, proc: u32, fds: VarPointer) -> Result<IRtStat, IError> {
+        let mut proc = self.processes.get_mut(proc as usize).unwrap();
+        let (read_fd, write_fd) = (proc.len() as i32, proc.len() as i32 + 1);
+        let mut ends = to_mem_bytes(read_fd);
+        ends.extend(to_mem_bytes(write_fd));
+        proc.tag_mut().memory.write_bytes(fds, &ends)?;
+
+        let pipe = self.pipes.len() as u32;
+        self.pipes.push(Pipe::new());
+        proc.push(FdKind::PipeRead(pipe));
+        proc.push(FdKind::PipeWrite(pipe));
+        proc.tag_mut().memory.push(0u64);
+        return Ok(IRtStat::Running);
+    }
+
+    fn close(&mut self, proc: u32, fd: u32) -> Result<IRtStat, IError> {
+        let mut proc = self.processes.get_mut(proc as usize).unwrap();
+        let slot = match proc.get_mut(fd as usize) {
+            Some(slot) => slot,
+            None => {
+                proc.tag_mut().memory.push(EcallError::DoesntExist.to_u64());
+                return Ok(IRtStat::Running);
+            }
+        };
+
+        let fd = mem::replace(slot, FdKind::Closed);
+        proc.tag_mut().memory.push(0u64);
+        release_fd(&mut self.pipes, fd);
+        self.wake_blocked();
+        return Ok(IRtStat::Running);
+    }
+
+    fn dup2(&mut self, proc: u32, old: u32, new: u32) -> Result<IRtStat, IError> {
+        let mut proc = self.processes.get_mut(proc as usize).unwrap();
+        let fd = match proc.get(old as usize) {
+            Some(FdKind::Closed) | None => {
+                proc.tag_mut().memory.push(EcallError::DoesntExist.to_u64());
+                return Ok(IRtStat::Running);
+            }
+            Some(fd) => *fd,
+        };
+
+        while proc.len() <= new as usize {
+            proc.push(FdKind::Closed);
+        }
+
+        let prev = mem::replace(&mut proc[new as usize], fd);
+        proc.tag_mut().memory.push(new as u64);
+
+        acquire_fd(&mut self.pipes, fd);
+        release_fd(&mut self.pipes, prev);
+        self.wake_blocked();
+        return Ok(IRtStat::Running);
+    }
+
+    fn fork(&mut self, parent: u32) -> Result<IRtStat, IError> {
+        let proc = self.processes.get(parent as usize).unwrap();
+        if self.processes.len() >= MAX_PROCESSES {
+            let mut proc = self.processes.get_mut(parent as usize).unwrap();
+            proc.tag_mut().memory.push(!0u64);
+            return Ok(IRtStat::Running);
+        }
+
+        let (mut memory, fds) = (proc.tag.memory.clone(), proc.data.to_vec());
+        let (signals, trampoline) = (proc.tag.signals, proc.tag.trampoline);
+        memory.change_log = None;
+        memory.push(0u64);
+        for fd in &fds {
+            acquire_fd(&mut self.pipes, *fd);
+        }
+
+        let child_id = self.processes.len() as u32;
+        let child = Process {
+            memory,
+            status: IRtStat::Running,
+            op_count: 0,
+            parent,
+            reaped: false,
+            pending: None,
+            history: None,
+
+            signals,
+            trampoline,
+            fault: None,
+        };
+        self.processes.push(child, fds);
+        self.active_count += 1;
+
+        let mut proc = self.processes.get_mut(parent as usize).unwrap();
+        proc.tag_mut().memory.push(child_id as u64);
+        return Ok(IRtStat::Running);
+    }
+
+    fn execve(&mut self, proc: u32, path: VarPointer) -> Result<IRtStat, IError> {
+        let (programs, mut proc) = (
+            &self.programs,
+            self.processes.get_mut(proc as usize).unwrap(),
+        );
+        let path = proc.tag().memory.cstring_bytes(path)?;
+        let path = core::str::from_utf8(path).ok();
+        let binary = match path.and_then(|path| programs.get(path)) {
+            Some(binary) => binary,
+            None => {
+                proc.tag_mut().memory.push(!0u64);
+                return Ok(IRtStat::Running);
+            }
+        };
+
+        let (limits, log) = (proc.tag().memory.limits, &proc.tag().memory.change_log);
+        let change_log = log.as_ref().map(|_| Vec::new());
+        let proc = proc.tag_mut();
+        proc.memory = Memory::new(binary);
+        proc.memory.limits = limits;
+        proc.memory.change_log = change_log;
+        proc.signals = [VarPointer::from(0); NSIG];
+        if let Some(history) = &mut proc.history {
+            history.clear(); // the old program's steps can't be undone anymore
+        }
+
+        return Ok(IRtStat::Running);
+    }
+
+    fn waitpid(&mut self, parent: u32, req: EcallExt) -> Result<IRtStat, IError> {
+        let (pid, status, options) = match req {
+            EcallExt::WaitPid {
+                pid,
+                status,
+                options,
+            } => (pid, status, options),
+            _ => unreachable!(),
+        };
+
+        let mut waiting = false;
+        let mut exited = None;
+        for idx in 0..self.processes.len() {
+            let child = self.processes.get(idx).unwrap().tag;
+            if child.parent != parent || child.reaped || (pid != -1 && pid as usize != idx) {
+                continue;
+            }
+
+            if let IRtStat::Exited(code) = child.status {
+                exited = Some((idx, code));
+                break;
+            }
+
+            waiting = true;
+        }
+
+        if let Some((idx, code)) = exited {
+            let mut child = self.processes.get_mut(idx).unwrap();
+            child.tag_mut().reaped = true;
+
+            let mut proc = self.processes.get_mut(parent as usize).unwrap();
+            if status.var_idx() != 0 {
+                let status_code: i32 = (code & 0xff) << 8;
+                let bytes = to_mem_bytes(status_code);
+                proc.tag_mut().memory.write_bytes(status, &bytes)?;
+            }
+
+            proc.tag_mut().memory.push(idx as u64);
+            return Ok(IRtStat::Running);
+        }
+
+        let mut proc = self.processes.get_mut(parent as usize).unwrap();
+        if !waiting {
+            proc.tag_mut().memory.push(!0u64); // no children to wait for
+            return Ok(IRtStat::Running);
+        }
+
+        if options & WNOHANG != 0 {
+            proc.tag_mut().memory.push(0u64);
+            return Ok(IRtStat::Running);
+        }
+
+        proc.tag_mut().pending = Some(req);
+        return Ok(IRtStat::Blocked);
+    }
+
+    /// Reports whether `fd` can be read from or written to without blocking
+    fn poll_fd(&mut self, proc: u32, fd: u32) -> Result<IRtStat, IError> {
+        let mut proc = self.processes.get_mut(proc as usize).unwrap();
+        let events = match proc.get(fd as usize) {
+            None | Some(FdKind::Closed) => POLLNVAL,
+            Some(FdKind::TermIn) => {
+                let has_input = self.in_begin != self.input.len();
+                match (has_input, self.stdin_open) {
+                    (true, _) => POLLIN,
+                    (false, true) => 0,
+                    (false, false) => POLLIN | POLLHUP,
+                }
+            }
+            Some(FdKind::PipeRead(pipe)) => {
+                let pipe = &self.pipes[*pipe as usize];
+                let readable = if pipe.len() != 0 { POLLIN } else { 0 };
+                let hangup = if pipe.writers == 0 { POLLHUP } else { 0 };
+                readable | hangup
+            }
+            Some(FdKind::PipeWrite(pipe)) => match self.pipes[*pipe as usize].readers {
+                0 => POLLHUP,
+                _ => POLLOUT,
+            },
+            Some(FdKind::FileSys(_)) => POLLIN | POLLOUT,
+            Some(_) => POLLOUT,
+        };
+
+        proc.tag_mut().memory.push(events);
+        return Ok(IRtStat::Running);
+    }
+
+    /// Runs an ecall, recording or replaying it if a trace is active
+    fn traced_ecall(&mut self, proc_id: u32, req: EcallExt) -> Result<IRtStat, IError> {
+        let proc = self.processes.get(proc_id as usize).unwrap();
+        let stack_len = proc.tag.memory.expr_stack.len();
+        let reads_term = match req {
+            EcallExt::ReadFd { fd, .. } | EcallExt::PollFd { fd } => {
+                let fd_kind = proc.data.get(fd as usize);
+                if let Some(FdKind::TermIn) = fd_kind {
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        };
+        let reads_host = match (req, self.clock) {
+            (EcallExt::Time, ClockMode::Host(_)) => true,
+            _ => false,
+        };
+
+        let name = req.name();
+        let replayed = reads_term || reads_host;
+        if let (TraceMode::Replay(trace, next), true) = (&mut self.trace, replayed) {
+            let event = trace.events.get(*next);
+            let event = match event {
+                Some(e) if e.proc == proc_id && e.ecall == name => e,
+                e => return Err(replay_diverged(e, proc_id, name)),
+            };
+            *next += 1;
+
+            let mut proc = self.processes.get_mut(proc_id as usize).unwrap();
+            let memory = &mut proc.tag_mut().memory;
+            if let EcallExt::ReadFd { buf, .. } = req {
+                memory.write_bytes(buf, &event.input)?;
+            }
+
+            memory.expr_stack.extend_from_slice(&event.result);
+            return Ok(IRtStat::Running);
+        }
+
+        let status = self.ecall(proc_id, req)?;
+        if let IRtStat::Blocked = status {
+            return Ok(status); // recorded once it actually finishes
+        }
+
+        let proc = self.processes.get(proc_id as usize).unwrap();
+        let result = proc.tag.memory.expr_stack.get(stack_len..).unwrap_or(&[]);
+        match &mut self.trace {
+            TraceMode::Off => {}
+            TraceMode::Record(trace) => {
+                let input = mem::replace(&mut self.traced_input, Vec::new());
+                trace.events.push(TraceEvent {
+                    proc: proc_id,
+                    ecall: name.to_string(),
+                    input,
+                    result: result.to_vec(),
+                });
+            }
+            TraceMode::Replay(trace, next) => {
+                let event = trace.events.get(*next);
+                match event {
+                    Some(e) if e.proc == proc_id && e.ecall == name => {
+                        if e.result != result {
+                            return Err(ierror!(
+                                "ReplayDiverged",
+                                "`{}` ecall from process {} returned something different \
+                                 than when it was recorded",
+                                name,
+                                proc_id
+                            ));
+                        }
+                    }
+                    e => return Err(replay_diverged(e, proc_id, name)),
+                }
+                *next += 1;
+            }
+        }
+
+        return Ok(status);
+    }
+
     #[inline]
     pub fn ecall(&mut self, proc: u32, req: EcallExt) -> Result<IRtStat, IError> {
+        match req {
+            EcallExt::Fork => return self.fork(proc),
+            EcallExt::Execve { path } => return self.execve(proc, path),
+            EcallExt::WaitPid { .. } => return self.waitpid(proc, req),
+            EcallExt::Pipe { fds } => return self.pipe(proc, fds),
+            EcallExt::CloseFd { fd } => return self.close(proc, fd),
+            EcallExt::Dup2 { old, new } => return self.dup2(proc, old, new),
+            EcallExt::PollFd { fd } => return self.poll_fd(proc, fd),
+            EcallExt::Time => {
+                let now = self.now();
+                let mut proc = self.processes.get_mut(proc as usize).unwrap();
+                proc.tag_mut().memory.push(now);
+                return Ok(IRtStat::Running);
+            }
+            EcallExt::CpuTime => {
+                let mut proc = self.processes.get_mut(proc as usize).unwrap();
+                let op_count = proc.tag().op_count;
+                proc.tag_mut().memory.push(op_count);
+                return Ok(IRtStat::Running);
+            }
+            EcallExt::Signal {
+                sig,
+                handler,
+                trampoline,
+            } => {
+                let mut proc = self.processes.get_mut(proc as usize).unwrap();
+                let proc = proc.tag_mut();
+                if let Some(slot) = proc.signals.get_mut(sig as usize) {
+                    *slot = handler;
+                }
+
+                proc.trampoline = trampoline;
+                proc.memory.push(0u64);
+                return Ok(IRtStat::Running);
+            }
+            EcallExt::SignalReturn => {
+                let mut proc = self.processes.get_mut(proc as usize).unwrap();
+                let or_else = || {
+                    ierror!(
+                        "InvalidSignalReturn",
+                        "returned from a signal handler that wasn't running"
+                    )
+                };
+                return Err(proc.tag_mut().fault.take().ok_or_else(or_else)?);
+            }
+            EcallExt::SetJmp { buf } => {
+                let mut proc = self.processes.get_mut(proc as usize).unwrap();
+                let memory = &mut proc.tag_mut().memory;
+                let target = memory.jump_target();
+                memory.write_bytes(buf, &to_mem_bytes(target))?;
+                memory.push(0u64);
+                return Ok(IRtStat::Running);
+            }
+            EcallExt::LongJmp { buf, val } => {
+                let mut proc = self.processes.get_mut(proc as usize).unwrap();
+                let memory = &mut proc.tag_mut().memory;
+                let target: JumpTarget = memory.read(buf)?;
+                memory.long_jump(target)?;
+
+                // setjmp returns 1 instead if longjmp is passed 0
+                memory.push(if val == 0 { 1u64 } else { val as u64 });
+                return Ok(IRtStat::Running);
+            }
+            EcallExt::Host { id, args, count } => {
+                let mut proc = self.processes.get_mut(proc as usize).unwrap();
+                let memory = &mut proc.tag_mut().memory;
+                let result = self.host_fns.call(memory, id, args, count)?;
+                memory.push(result);
+                return Ok(IRtStat::Running);
+            }
+            _ => {}
+        }
+
         let mut proc = self.processes.get_mut(proc as usize).unwrap();
 
         match req {
             EcallExt::Exit(exit) => return Ok(IRtStat::Exited(exit)),
+            EcallExt::Fork | EcallExt::Execve { .. } | EcallExt::WaitPid { .. } => unreachable!(),
+            EcallExt::Pipe { .. } | EcallExt::CloseFd { .. } | EcallExt::Dup2 { .. } => {
+                unreachable!()
+            }
+            EcallExt::PollFd { .. } => unreachable!(),
+            EcallExt::Time | EcallExt::CpuTime => unreachable!(),
+            EcallExt::Signal { .. } | EcallExt::SignalReturn => unreachable!(),
+            EcallExt::SetJmp { .. } | EcallExt::LongJmp { .. } => unreachable!(),
+            EcallExt::Host { .. } => unreachable!(),
 
             EcallExt::OpenFd { name, open_mode } => {
                 let bytes = proc.tag().memory.cstring_bytes(name)?;
@@ -282,9 +1372,19 @@ impl Kernel {
                 let to_ret = match fd_info {
                     None => EcallError::DoesntExist.to_u64(),
                     Some(FdKind::TermIn) => {
-                        let end = core::cmp::min(self.input.len(), len as usize);
+                        if self.in_begin == self.input.len() && self.stdin_open {
+                            #[rustfmt::skip]
+                            let req = EcallExt::ReadFd { len, buf, begin, fd };
+                            proc.tag_mut().pending = Some(req);
+                            return Ok(IRtStat::Blocked);
+                        }
+
+                        let end = core::cmp::min(self.input.len(), self.in_begin + len as usize);
                         let bytes = &self.input[(self.in_begin as usize)..end];
                         proc.tag_mut().memory.write_bytes(buf, bytes)?;
+                        if let TraceMode::Record(_) = self.trace {
+                            self.traced_input.extend_from_slice(bytes);
+                        }
 
                         let begin = self.in_begin as usize;
                         self.in_begin = if end == self.input.len() {
@@ -308,6 +1408,23 @@ impl Kernel {
                             Err(e) => e.to_u64(),
                         }
                     }
+                    Some(FdKind::PipeRead(pipe)) => {
+                        let pipe = &mut self.pipes[*pipe as usize];
+                        if pipe.len() == 0 && pipe.writers != 0 {
+                            #[rustfmt::skip]
+                            let req = EcallExt::ReadFd { len, buf, begin, fd };
+                            proc.tag_mut().pending = Some(req);
+                            return Ok(IRtStat::Blocked);
+                        }
+
+                        let mut bytes = Vec::new();
+                        pipe.read(len, &mut bytes);
+                        proc.tag_mut().memory.write_bytes(buf, &bytes)?;
+                        bytes.len() as u64
+                    }
+                    Some(FdKind::PipeWrite(_)) | Some(FdKind::Closed) => {
+                        EcallError::DoesntExist.to_u64()
+                    }
                     _ => unimplemented!(),
                 };
 
@@ -321,7 +1438,8 @@ impl Kernel {
                 begin,
                 fd,
             } => {
-                match proc.get(fd as usize).map(|a| *a) {
+                let fd_kind = proc.get(fd as usize).map(|a| *a);
+                match fd_kind {
                     None => {
                         proc.tag_mut().memory.push(EcallError::DoesntExist.to_u64());
                     }
@@ -353,14 +1471,32 @@ impl Kernel {
                         let buf = proc.tag().memory.read_bytes(buf, len)?;
                         self.output.push_from(WriteEvt::WriteFd { begin, fd }, buf);
                     }
+                    Some(FdKind::PipeWrite(pipe)) => {
+                        let pipe = &mut self.pipes[pipe as usize];
+                        if pipe.readers == 0 {
+                            proc.tag_mut().memory.push(EcallError::BrokenPipe.to_u64());
+                        } else {
+                            let buf = proc.tag().memory.read_bytes(buf, len)?;
+                            pipe.data.extend_from_slice(buf);
+                            proc.tag_mut().memory.push(len as u64);
+                        }
+                    }
+                    Some(FdKind::PipeRead(_)) | Some(FdKind::Closed) => {
+                        proc.tag_mut().memory.push(EcallError::DoesntExist.to_u64());
+                    }
                     _ => unimplemented!(),
                 }
 
+                if let Some(FdKind::PipeWrite(_)) = fd_kind {
+                    self.wake_blocked();
+                }
+
                 return Ok(IRtStat::Running);
             }
 
             EcallExt::AppendFd { buf, len, fd } => {
-                match proc.get(fd as usize).map(|a| *a) {
+                let fd_kind = proc.get(fd as usize).map(|a| *a);
+                match fd_kind {
                     None => proc.tag_mut().memory.push(EcallError::DoesntExist.to_u64()),
                     Some(FdKind::TermIn) => {
                         proc.tag_mut().memory.push(EcallError::WriteTermIn.to_u64());
@@ -390,9 +1526,26 @@ impl Kernel {
                         let buf = proc.tag().memory.read_bytes(buf, len)?;
                         self.output.push_from(WriteEvt::AppendFd { fd }, buf);
                     }
+                    Some(FdKind::PipeWrite(pipe)) => {
+                        let pipe = &mut self.pipes[pipe as usize];
+                        if pipe.readers == 0 {
+                            proc.tag_mut().memory.push(EcallError::BrokenPipe.to_u64());
+                        } else {
+                            let buf = proc.tag().memory.read_bytes(buf, len)?;
+                            pipe.data.extend_from_slice(buf);
+                            proc.tag_mut().memory.push(len as u64);
+                        }
+                    }
+                    Some(FdKind::PipeRead(_)) | Some(FdKind::Closed) => {
+                        proc.tag_mut().memory.push(EcallError::DoesntExist.to_u64());
+                    }
                     _ => unimplemented!(),
                 }
 
+                if let Some(FdKind::PipeWrite(_)) = fd_kind {
+                    self.wake_blocked();
+                }
+
                 return Ok(IRtStat::Running);
             }
         }
@@ -402,6 +1555,26 @@ impl Kernel {
         return mem::replace(&mut self.output, TaggedMultiArray::new());
     }
 
+    /// Terminal output so far, split into what was written to stdout and what was
+    /// written to stderr or stdlog, along with errors reported by `run`. Like
+    /// `term_out`, this clears the events; use `events` to keep them in order.
+    pub fn term_streams(&mut self) -> (String, String) {
+        let (mut stdout, mut stderr) = (StringWriter::new(), StringWriter::new());
+
+        for TE(tag, s) in &self.output {
+            match tag {
+                WriteEvt::StdoutWrite => write_utf8_lossy(&mut stdout, s).unwrap(),
+                WriteEvt::StderrWrite => write_utf8_lossy(&mut stderr, s).unwrap(),
+                WriteEvt::StdlogWrite => write_utf8_lossy(&mut stderr, s).unwrap(),
+                _ => {}
+            }
+        }
+
+        mem::drop(mem::replace(&mut self.output, TaggedMultiArray::new()));
+
+        return (stdout.into_string(), stderr.into_string());
+    }
+
     pub fn term_out(&mut self) -> String {
         let mut out = StringWriter::new();
 
@@ -425,11 +1598,19 @@ impl Kernel {
     fn write(&mut self, s: &[u8]) -> core::fmt::Result {
         if self.term_proc != !0 {
             self.input.extend(s);
+            self.wake_blocked();
         }
 
         self.output.push_from(WriteEvt::StdinWrite, s);
         return Ok(());
     }
+
+    /// Signals end-of-file on the terminal; processes waiting on it wake up and read
+    /// whatever is left.
+    pub fn close_stdin(&mut self) {
+        self.stdin_open = false;
+        self.wake_blocked();
+    }
 }
 
 impl Write for Kernel {
@@ -437,3 +1618,22 @@ impl Write for Kernel {
         return self.write(s.as_bytes());
     }
 }
+
+pub fn no_process(proc: u32) -> IError {
+    return ierror!("InvalidProcess", "there's no process with id {}", proc);
+}
+
+pub fn deadline_exceeded() -> IError {
+    return ierror!(
+        "DeadlineExceeded",
+        "program was still running at its deadline"
+    );
+}
+
+pub fn op_limit(max_ops: u64) -> IError {
+    return ierror!(
+        "InstructionLimit",
+        "program ran for more than {} instructions",
+        max_ops
+    );
+}
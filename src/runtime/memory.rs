@@ -1,13 +1,16 @@
 use super::error::*;
+use super::snapshot::*;
 use super::types::*;
 use crate::util::*;
+use core::convert::TryFrom;
 use core::mem;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct AllocInfo {
     pub alloc_loc: CodeLoc,
     pub free_loc: CodeLoc,
     pub len: n32,
+    pub moved: bool, // freed by `realloc`, which copied it to a new block
 }
 
 impl AllocInfo {
@@ -16,14 +19,278 @@ impl AllocInfo {
             alloc_loc,
             free_loc: NO_FILE,
             len: n32::NULL,
+            moved: false,
         }
     }
 }
 
-#[derive(Debug)]
+/// A value the interpreter can keep in memory. Memory is little-endian no matter
+/// what the host is, so the bytes a C program sees through a `char *`, a union,
+/// or `memcpy` are the same everywhere. Every conversion between values and the
+/// bytes in memory or on the expression stack goes through here.
+pub trait MemValue: Copy {
+    const SIZE: usize;
+
+    /// Writes the value into `out`, which is exactly `SIZE` bytes long
+    fn to_bytes(self, out: &mut [u8]);
+
+    /// Reads a value from `bytes`, which is exactly `SIZE` bytes long
+    fn from_bytes(bytes: &[u8]) -> Self;
+
+    /// Like `from_bytes`, but for types where not every bit pattern is a value
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, IError> {
+        return Ok(Self::from_bytes(bytes));
+    }
+}
+
+macro_rules! mem_value {
+    ($($ty:ty),*) => {
+        $(
+            impl MemValue for $ty {
+                const SIZE: usize = mem::size_of::<$ty>();
+
+                fn to_bytes(self, out: &mut [u8]) {
+                    out.copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn from_bytes(bytes: &[u8]) -> Self {
+                    let mut buf = [0u8; mem::size_of::<$ty>()];
+                    buf.copy_from_slice(bytes);
+                    return <$ty>::from_le_bytes(buf);
+                }
+            }
+        )*
+    };
+}
+
+mem_value!(u8, i8, u16, i16, u32, i32, u64, i64, f32, f64);
+
+impl MemValue for () {
+    const SIZE: usize = 0;
+
+    fn to_bytes(self, _out: &mut [u8]) {}
+
+    fn from_bytes(_bytes: &[u8]) -> Self {}
+}
+
+impl MemValue for VarPointer {
+    const SIZE: usize = 8;
+
+    fn to_bytes(self, out: &mut [u8]) {
+        u64::from(self).to_bytes(out);
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        return VarPointer::from(u64::from_bytes(bytes));
+    }
+}
+
+// Code can be jumped to from memory a program wrote, so reading an opcode from
+// code goes through `try_from_bytes`. `from_bytes` is only for bytes TCI wrote.
+impl MemValue for Opcode {
+    const SIZE: usize = 1;
+
+    fn to_bytes(self, out: &mut [u8]) {
+        out[0] = self as u8;
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        return Self::try_from_bytes(bytes).unwrap();
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, IError> {
+        return Opcode::try_from(bytes[0]);
+    }
+}
+
+impl MemValue for CheckedOp {
+    const SIZE: usize = 1;
+
+    fn to_bytes(self, out: &mut [u8]) {
+        out[0] = self as u8;
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        return Self::try_from_bytes(bytes).unwrap();
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, IError> {
+        return CheckedOp::try_from(bytes[0]);
+    }
+}
+
+impl MemValue for n32 {
+    const SIZE: usize = 4;
+
+    fn to_bytes(self, out: &mut [u8]) {
+        self.data.to_bytes(out);
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        return n32 {
+            data: u32::from_bytes(bytes),
+        };
+    }
+}
+
+impl MemValue for CodeLoc {
+    const SIZE: usize = 12;
+
+    fn to_bytes(self, out: &mut [u8]) {
+        self.start.to_bytes(&mut out[0..4]);
+        self.end.to_bytes(&mut out[4..8]);
+        self.file.to_bytes(&mut out[8..12]);
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        return CodeLoc {
+            start: u32::from_bytes(&bytes[0..4]),
+            end: u32::from_bytes(&bytes[4..8]),
+            file: u32::from_bytes(&bytes[8..12]),
+        };
+    }
+}
+
+impl MemValue for LinkName {
+    const SIZE: usize = 8;
+
+    fn to_bytes(self, out: &mut [u8]) {
+        self.name.to_bytes(&mut out[0..4]);
+        self.file.to_bytes(&mut out[4..8]);
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        return LinkName {
+            name: u32::from_bytes(&bytes[0..4]),
+            file: n32::from_bytes(&bytes[4..8]),
+        };
+    }
+}
+
+/// Encodes `value` the way memory holds it
+pub fn to_mem_bytes<T: MemValue>(value: T) -> Vec<u8> {
+    let mut out = vec![0; T::SIZE];
+    value.to_bytes(&mut out);
+    return out;
+}
+
+/// Resource limits for a single process. Going over any of them stops the program
+/// with a runtime error instead of hanging or exhausting the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_ops: u64, // instructions executed, over the life of the process
+    pub max_call_depth: usize,
+    pub max_stack_bytes: usize,
+    pub max_heap_bytes: usize,
+}
+
+impl Limits {
+    pub const DEFAULT: Self = Self {
+        max_ops: !0,
+        max_call_depth: 1000,
+        max_stack_bytes: 1024 * 8,
+        max_heap_bytes: 1024 * 1024 * 16,
+    };
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        return Self::DEFAULT;
+    }
+}
+
+/// The bytes a store overwrote, so that it can be undone later
+#[derive(Debug, Clone)]
+pub struct MemoryChange {
+    pub stack: bool, // whether `idx` is into `stack_data` or `shared_data`
+    pub idx: usize,
+    pub old: Vec<u8>,
+}
+
+/// A range of memory that execution should stop after writing to
+#[derive(Debug, Clone, Copy)]
+pub struct Watchpoint {
+    pub ptr: VarPointer,
+    pub len: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WatchHit {
+    pub watch: Watchpoint,
+    pub ptr: VarPointer, // the write that hit the watchpoint
+    pub len: u32,
+    pub loc: CodeLoc,
+}
+
+/// Where `setjmp` was called from, as saved in the caller's `jmp_buf`. It can only be
+/// jumped to while the function that called `setjmp` is still running, and only
+/// if `pc` is one of the memory's `jump_sites`, since programs can write anything
+/// to a `jmp_buf`.
+#[derive(Debug, Clone, Copy)]
+pub struct JumpTarget {
+    pub pc: VarPointer,
+    pub func: LinkName,
+    pub depth: u32,     // length of the callstack
+    pub stack_len: u32, // number of stack variables
+    pub expr_len: u32,
+    pub fp: u16,
+}
+
+// Fits in a `jmp_buf`, which is `uint64_t[8]`
+impl MemValue for JumpTarget {
+    const SIZE: usize = 30;
+
+    fn to_bytes(self, out: &mut [u8]) {
+        self.pc.to_bytes(&mut out[0..8]);
+        self.func.to_bytes(&mut out[8..16]);
+        self.depth.to_bytes(&mut out[16..20]);
+        self.stack_len.to_bytes(&mut out[20..24]);
+        self.expr_len.to_bytes(&mut out[24..28]);
+        self.fp.to_bytes(&mut out[28..30]);
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        return JumpTarget {
+            pc: VarPointer::from_bytes(&bytes[0..8]),
+            func: LinkName::from_bytes(&bytes[8..16]),
+            depth: u32::from_bytes(&bytes[16..20]),
+            stack_len: u32::from_bytes(&bytes[20..24]),
+            expr_len: u32::from_bytes(&bytes[24..28]),
+            fp: u16::from_bytes(&bytes[28..30]),
+        };
+    }
+}
+
+/// A place `setjmp` returned to, recorded when it was called
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JumpSite {
+    pub pc: VarPointer,
+    pub func: LinkName,
+    pub loc: CodeLoc,
+}
+
+/// An instruction with its operand, as `Memory::fetch` reads it
+#[derive(Debug, Clone, Copy)]
+pub struct DecodedOp {
+    pub op: Opcode,
+    size: u8,          // of the whole instruction, in code
+    operand: [u8; 12], // enough for any operand but the one `Func` has
+}
+
+impl DecodedOp {
+    pub fn operand<T: MemValue>(&self) -> T {
+        return self.operand_at(0);
+    }
+
+    pub fn operand_at<T: MemValue>(&self, offset: usize) -> T {
+        return T::from_bytes(&self.operand[offset..(offset + T::SIZE)]);
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Memory {
     pub shared_data: Vec<u8>,
-    pub binary: Vec<Var<()>>,
+    pub binary: Vec<Var<bool>>, // meta is whether the variable is read-only
     pub heap: Vec<Var<AllocInfo>>,
     pub freed: usize,
 
@@ -36,6 +303,24 @@ pub struct Memory {
     pub fp: u16,
     pub pc: VarPointer,
     pub loc: CodeLoc,
+    pub jump_sites: Vec<JumpSite>, // every place setjmp has been called from
+
+    // (pointer to the start, begin, end) of the function `pc` is in, so that reading
+    // code doesn't have to look up the function's bounds every time
+    code: Option<(VarPointer, usize, usize)>,
+
+    // instructions that have run, by binary variable and then offset, so that
+    // running them again doesn't have to decode them again
+    decoded: Vec<Vec<Option<DecodedOp>>>,
+
+    pub limits: Limits,
+
+    // while this is set, every store to `stack_data` or `shared_data` records what it
+    // overwrote here
+    pub change_log: Option<Vec<MemoryChange>>,
+
+    pub watchpoints: Vec<Watchpoint>,
+    pub watch_hit: Option<WatchHit>, // set by a store to a watched range
 }
 
 impl Memory {
@@ -55,9 +340,125 @@ impl Memory {
             loc: NO_FILE,
             fp: 1,
             pc: VarPointer::new_binary(1, 0),
+            jump_sites: Vec::new(),
+
+            code: None,
+            decoded: Vec::new(),
+
+            limits: Limits::DEFAULT,
+
+            change_log: None,
+
+            watchpoints: Vec::new(),
+            watch_hit: None,
         }
     }
 
+    /// Adds the variables at the end of `data` that this memory doesn't have yet.
+    /// `data` has to be this memory's binary with more added to it, like what
+    /// the assembler produces when the debugger or REPL compiles more code.
+    pub fn extend_binary(&mut self, data: &BinaryData) {
+        // New variables go between the binary and the heap, so heap pointers stay valid
+        let heap_begin = self.heap.get(0).map(|v| v.idx);
+        let heap_begin = heap_begin.unwrap_or(self.shared_data.len());
+        let inserted = data.data.len() - heap_begin;
+        let tail = self.shared_data.split_off(heap_begin);
+        self.shared_data.extend_from_slice(&data.data[heap_begin..]);
+        self.shared_data.extend(tail);
+        for var in &mut self.heap {
+            var.idx += inserted;
+        }
+
+        self.binary
+            .extend_from_slice(&data.vars[self.binary.len()..]);
+    }
+
+    pub fn write_snapshot(&self, out: &mut SnapshotWriter) {
+        out.put_slice(&self.shared_data);
+        out.put_slice(&self.binary);
+        out.put_slice(&self.heap);
+        out.put(self.freed as u64);
+
+        out.put_slice(&self.expr_stack);
+        out.put_slice(&self.stack_data);
+        out.put_slice(&self.stack);
+        out.put_slice(&self.callstack);
+        out.put(self.current_func);
+        out.put(self.fp);
+        out.put(self.pc);
+        out.put(self.loc);
+        out.put_slice(&self.jump_sites);
+
+        out.put(self.limits);
+    }
+
+    pub fn read_snapshot(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        return Ok(Self {
+            shared_data: snap.get_vec()?,
+            binary: snap.get_vec()?,
+            heap: snap.get_vec()?,
+            freed: snap.get::<u64>()? as usize,
+
+            expr_stack: snap.get_vec()?,
+            stack_data: snap.get_vec()?,
+            stack: snap.get_vec()?,
+            callstack: snap.get_vec()?,
+            current_func: snap.get()?,
+            fp: snap.get()?,
+            pc: snap.get()?,
+            loc: snap.get()?,
+            jump_sites: snap.get_vec()?,
+
+            code: None,
+            decoded: Vec::new(),
+
+            limits: snap.get()?,
+
+            change_log: None,
+
+            watchpoints: Vec::new(),
+            watch_hit: None,
+        });
+    }
+
+    /// Checks what `read_snapshot` can't check value by value: that variables are
+    /// inside their buffers, that the expression stack is within its limit, and,
+    /// if the process can still run, that there's an instruction at `pc`
+    pub fn check_snapshot(&mut self, running: bool) -> Result<(), IError> {
+        fn in_order<T>(vars: &[Var<T>], len: usize) -> bool {
+            let mut prev = 0;
+            return vars.iter().all(|var| {
+                let valid = prev <= var.idx && var.idx <= len;
+                prev = var.idx;
+                valid
+            });
+        }
+
+        let binary_end = self.heap.get(0).map(|v| v.idx);
+        let binary_end = binary_end.unwrap_or(self.shared_data.len());
+        if !in_order(&self.binary, binary_end) || !in_order(&self.heap, self.shared_data.len()) {
+            return Err(inconsistent("variable"));
+        }
+
+        if !in_order(&self.stack, self.stack_data.len()) {
+            return Err(inconsistent("stack variable"));
+        }
+
+        if self.expr_stack.len() > self.limits.max_stack_bytes {
+            return Err(inconsistent("expression stack"));
+        }
+
+        if running {
+            let pc = self.pc;
+            let decoded = self.decode_pc();
+            self.pc = pc;
+            self.code = None;
+            decoded.map_err(|_| inconsistent("program counter"))?;
+        }
+
+        return Ok(());
+    }
+
     pub fn ret(&mut self) -> Result<(), IError> {
         let or_else = || ierror!("InvalidReturn", "returned when not in a function");
         let frame = self.callstack.pop().ok_or_else(or_else)?;
@@ -71,8 +472,12 @@ impl Memory {
     }
 
     pub fn call(&mut self, new_pc: VarPointer) -> Result<(), IError> {
-        if self.callstack.len() > 1000 {
-            return Err(ierror!("StackOverflow", "maximum number of calls reached"));
+        if self.callstack.len() > self.limits.max_call_depth {
+            return Err(ierror!(
+                "StackOverflow",
+                "maximum call depth of {} reached",
+                self.limits.max_call_depth
+            ));
         }
 
         self.callstack.push(CallFrame::new(
@@ -103,50 +508,204 @@ impl Memory {
         self.pc = pc;
     }
 
+    /// Where the function `name` is defined, from the header at the start of its code
+    pub fn func_loc(&self, name: LinkName) -> Option<CodeLoc> {
+        let (name_offset, loc_offset) = (1, 1 + LinkName::SIZE as u32);
+        for idx in 0..self.binary.len() {
+            let ptr = VarPointer::new_binary(idx as u32 + 1, 0);
+            match self.read::<u8>(ptr) {
+                Ok(op) if op == Opcode::Func as u8 => {}
+                _ => continue,
+            }
+
+            match self.read::<LinkName>(ptr.with_offset(name_offset)) {
+                Ok(func) if func == name => {}
+                _ => continue,
+            }
+
+            return self.read(ptr.with_offset(loc_offset)).ok();
+        }
+
+        return None;
+    }
+
+    /// Where the `setjmp` that just ran will return to, which `long_jump` can
+    /// then go back to
+    pub fn jump_target(&mut self) -> JumpTarget {
+        let site = JumpSite {
+            pc: self.pc,
+            func: self.current_func,
+            loc: self.loc,
+        };
+
+        if !self.jump_sites.contains(&site) {
+            self.jump_sites.push(site);
+        }
+
+        return JumpTarget {
+            pc: self.pc,
+            func: self.current_func,
+            depth: self.callstack.len() as u32,
+            stack_len: self.stack.len() as u32,
+            expr_len: self.expr_stack.len() as u32,
+            fp: self.fp,
+        };
+    }
+
+    /// Unwinds the stack back to `target`
+    pub fn long_jump(&mut self, target: JumpTarget) -> Result<(), IError> {
+        let site = self.jump_sites.iter().find(|s| s.pc == target.pc);
+        let site = match site {
+            Some(&site) if site.func == target.func => site,
+            _ => {
+                return Err(ierror!(
+                    "InvalidLongJump",
+                    "called longjmp with a jmp_buf that wasn't filled in by setjmp"
+                ))
+            }
+        };
+
+        let depth = target.depth as usize;
+        let frame = match self.callstack.get(depth) {
+            _ if depth == self.callstack.len() => Some((self.current_func, self.fp)),
+            Some(frame) => Some((frame.name, frame.fp)),
+            None => None,
+        };
+
+        let stack_len = target.stack_len as usize;
+        let valid = frame == Some((target.func, target.fp)) && stack_len <= self.stack.len();
+        if !valid || target.expr_len as usize > self.expr_stack.len() {
+            return Err(ierror!(
+                "InvalidLongJump",
+                "called longjmp with a jmp_buf whose setjmp call has already returned"
+            ));
+        }
+
+        self.callstack.truncate(depth);
+        while self.stack.len() > stack_len {
+            self.pop_stack_var()?;
+        }
+
+        self.expr_stack.truncate(target.expr_len as usize);
+        self.current_func = target.func;
+        self.fp = target.fp;
+        self.pc = site.pc;
+        self.loc = site.loc;
+        return Ok(());
+    }
+
     pub fn set_loc(&mut self, loc: CodeLoc) {
         self.loc = loc;
     }
 
-    pub fn read_pc<T: Copy>(&mut self) -> Result<T, IError> {
-        if self.pc.var_idx() == 0 {
-            return Err(invalid_ptr(self.pc));
+    /// Reads the instruction at `pc`, and moves `pc` past it
+    pub fn fetch(&mut self) -> Result<DecodedOp, IError> {
+        let (pc, offset) = (self.pc, self.pc.offset() as usize);
+        if pc.is_binary() {
+            let code = self.decoded.get(pc.var_idx().wrapping_sub(1));
+            if let Some(&Some(op)) = code.and_then(|code| code.get(offset)) {
+                self.pc = pc.add(op.size as u64);
+                return Ok(op);
+            }
         }
 
-        let var_idx = self.pc.var_idx() - 1;
-        let or_else = || invalid_ptr(self.pc);
+        let op = self.decode_pc()?;
 
-        let from_bytes = if self.pc.is_binary() {
-            let lower = self.binary.get(var_idx).ok_or_else(or_else)?.idx;
-            let upper = self.binary.get(var_idx + 1).map(|a| a.idx);
-            let heap_lower = self.heap.get(0).map(|a| a.idx);
-            let upper = upper.or(heap_lower).unwrap_or(self.shared_data.len());
+        // `decode_pc` only succeeds in a binary variable, and leaves its bounds in `code`
+        let (var, (_, begin, end)) = (pc.var_idx() - 1, self.code.unwrap());
+        if self.decoded.len() <= var {
+            self.decoded.resize(var + 1, Vec::new());
+        }
 
-            &self.shared_data[lower..upper]
-        } else {
+        let code = &mut self.decoded[var];
+        if code.is_empty() {
+            code.resize(end - begin, None);
+        }
+
+        code[offset] = Some(op);
+        return Ok(op);
+    }
+
+    fn decode_pc(&mut self) -> Result<DecodedOp, IError> {
+        let start = self.pc;
+        let op: Opcode = self.read_pc()?;
+        let mut decoded = DecodedOp {
+            op,
+            size: 1,
+            operand: [0; 12],
+        };
+
+        // `run_op` doesn't need the operand of `Func`, which is too big to keep
+        if op == Opcode::Func {
+            return Ok(decoded);
+        }
+
+        let len = op.operand_size();
+        decoded.operand[..len].copy_from_slice(self.read_pc_bytes(len)?);
+        if op == Opcode::AssertNoOverflow || op == Opcode::AssertInObject {
+            CheckedOp::try_from_bytes(&decoded.operand)?;
+        }
+
+        decoded.size = (self.pc.offset() - start.offset()) as u8;
+        return Ok(decoded);
+    }
+
+    pub fn read_pc<T: MemValue>(&mut self) -> Result<T, IError> {
+        return T::try_from_bytes(self.read_pc_bytes(T::SIZE)?);
+    }
+
+    fn read_pc_bytes(&mut self, len: usize) -> Result<&[u8], IError> {
+        let (begin, end) = match self.code {
+            Some((ptr, begin, end)) if ptr.same_var(self.pc) => (begin, end),
+            _ => {
+                let code = self.code_bounds(self.pc)?;
+                self.code = Some(code);
+                (code.1, code.2)
+            }
+        };
+
+        let (from_len, ptr) = ((end - begin) as u32, self.pc);
+        let range = (begin + ptr.offset() as usize)..(begin + ptr.offset() as usize + len);
+        if range.end > end {
+            return Err(invalid_offset(from_len, ptr, len as u32));
+        }
+
+        self.pc = self.pc.add(len as u64);
+        return Ok(&self.shared_data[range]);
+    }
+
+    /// Bounds in `shared_data` of the binary variable that `pc` points into. Binary
+    /// variables never move, so this only needs to be recomputed when `pc` leaves it.
+    fn code_bounds(&self, pc: VarPointer) -> Result<(VarPointer, usize, usize), IError> {
+        if pc.var_idx() == 0 {
+            return Err(invalid_ptr(pc));
+        }
+
+        if !pc.is_binary() {
             return Err(ierror!(
                 "PermissionDenied",
                 "tried to execute memory outside of functions"
             ));
-        };
+        }
 
-        let (len, from_len, ptr) = (mem::size_of::<T>(), from_bytes.len() as u32, self.pc);
-        let range = (self.pc.offset() as usize)..(ptr.offset() as usize + len);
-        let or_else = move || invalid_offset(from_len, ptr, len as u32);
-        let from_bytes = from_bytes.get(range).ok_or_else(or_else)?;
+        let var_idx = pc.var_idx() - 1;
+        let or_else = || invalid_ptr(pc);
+        let lower = self.binary.get(var_idx).ok_or_else(or_else)?.idx;
+        let upper = self.binary.get(var_idx + 1).map(|a| a.idx);
+        let heap_lower = self.heap.get(0).map(|a| a.idx);
+        let upper = upper.or(heap_lower).unwrap_or(self.shared_data.len());
 
-        self.pc = self.pc.add(len as u64);
-        let mut out = mem::MaybeUninit::uninit();
-        unsafe { any_as_u8_slice_mut(&mut out).copy_from_slice(from_bytes) };
-        return Ok(unsafe { out.assume_init() });
+        return Ok((pc.with_offset(0), lower, upper));
     }
 
     pub fn add_stack_var(&mut self, len: u32) -> Result<VarPointer, IError> {
         let stack_len = self.stack_data.len();
         let new_len = stack_len + len as usize;
-        if new_len > 1024 * 8 {
+        if new_len > self.limits.max_stack_bytes {
             return Err(ierror!(
                 "StackOverflow",
-                "stack size would be over 8KB after this declaration"
+                "stack size would be over {} bytes after this declaration",
+                self.limits.max_stack_bytes
             ));
         }
 
@@ -183,10 +742,11 @@ impl Memory {
         let heap_begin_o = self.heap.get(0).map(|a| a.idx);
         let heap_begin = heap_begin_o.unwrap_or(self.shared_data.len());
 
-        if data_len - heap_begin + len > 1024 * 1024 * 16 {
+        if data_len - heap_begin + len > self.limits.max_heap_bytes {
             return Err(ierror!(
                 "HeapTooLarge",
-                "heap size would be over 16MB after this allocation"
+                "heap size would be over {} bytes after this allocation",
+                self.limits.max_heap_bytes
             ));
         }
 
@@ -219,11 +779,19 @@ impl Memory {
         }
 
         let var = self.heap.get(var_idx).ok_or_else(or_else)?;
-        if var.meta.len != n32::NULL {
-            return Err(ierror!(
+        if var.meta.len != n32::NULL && var.meta.moved {
+            let error = ierror!(
+                "DoubleFree",
+                "tried to free something that realloc already freed when it moved the block; \
+                 free the pointer realloc returned instead"
+            );
+            return Err(error.with_note(var.meta.free_loc, "moved by this call to realloc"));
+        } else if var.meta.len != n32::NULL {
+            let error = ierror!(
                 "DoubleFree",
                 "tried to free something that has already been freed"
-            ));
+            );
+            return Err(error.with_note(var.meta.free_loc, "freed here"));
         }
 
         let upper = self.heap.get(var_idx + 1).map(|a| a.idx);
@@ -235,6 +803,10 @@ impl Memory {
         var.meta.len = (upper - var.idx).into();
         self.freed += upper - var.idx;
 
+        if self.watchpoints.len() != 0 {
+            self.watchpoints.retain(|w| !w.ptr.same_var(ptr));
+        }
+
         if self.freed * 2 >= self.shared_data.len() - self.heap[0].idx {
             self.coallesce_heap();
         }
@@ -248,6 +820,7 @@ impl Memory {
         }
 
         let mut write_to = self.heap[0].idx;
+        self.log_change(false, write_to, self.shared_data.len() - write_to);
         for idx in 0..self.heap.len() {
             let end = self.heap.get(idx + 1).map(|a| a.idx);
             let end = end.unwrap_or(self.shared_data.len());
@@ -272,6 +845,12 @@ impl Memory {
     pub fn pop_stack_var(&mut self) -> Result<(), IError> {
         let or_else = || empty_stack();
         let var = self.stack.pop().ok_or_else(or_else)?;
+        if self.watchpoints.len() != 0 {
+            let popped = VarPointer::new_stack(self.stack.len() as u16 + 1, 0);
+            self.watchpoints.retain(|w| !w.ptr.same_var(popped));
+        }
+
+        self.log_change(true, var.idx, self.stack_data.len() - var.idx);
         self.stack_data.resize(var.idx, 0);
 
         return Ok(());
@@ -312,9 +891,26 @@ impl Memory {
         return Some(ptr.with_offset(offset as u32));
     }
 
+    /// What kind of object `ptr` points into, for error messages
+    pub fn object_kind(&self, ptr: VarPointer) -> &'static str {
+        let var_idx = ptr.var_idx().wrapping_sub(1);
+        if ptr.is_null() {
+            return "NULL";
+        } else if ptr.is_stack() {
+            return "local variable";
+        } else if ptr.is_heap() {
+            return "block from malloc";
+        }
+
+        return match self.binary.get(var_idx) {
+            Some(var) if var.meta => "string literal",
+            _ => "global variable",
+        };
+    }
+
     pub fn read_bytes(&self, ptr: VarPointer, len: u32) -> Result<&[u8], IError> {
         if ptr.var_idx() == 0 {
-            return Err(invalid_ptr(ptr));
+            return Err(deref_error(ptr));
         }
 
         let var_idx = ptr.var_idx() - 1;
@@ -329,7 +925,7 @@ impl Memory {
         } else if ptr.is_heap() {
             let lower_var = self.heap.get(var_idx).ok_or_else(or_else)?;
             if lower_var.meta.len != n32::NULL {
-                return Err(freed_ptr(ptr));
+                return Err(freed_ptr(ptr, &lower_var.meta));
             }
 
             let lower = lower_var.idx;
@@ -353,60 +949,149 @@ impl Memory {
         return Ok(from_bytes);
     }
 
-    pub fn read<T: Copy>(&self, ptr: VarPointer) -> Result<T, IError> {
-        let len = mem::size_of::<T>() as u32;
-        let from_bytes = self.read_bytes(ptr, len)?;
-
-        let mut out = mem::MaybeUninit::uninit();
-        unsafe { any_as_u8_slice_mut(&mut out).copy_from_slice(from_bytes) };
-        return Ok(unsafe { out.assume_init() });
+    pub fn read<T: MemValue>(&self, ptr: VarPointer) -> Result<T, IError> {
+        let from_bytes = self.read_bytes(ptr, T::SIZE as u32)?;
+        return Ok(T::from_bytes(from_bytes));
     }
 
     pub fn write_bytes(&mut self, ptr: VarPointer, buffer: &[u8]) -> Result<(), IError> {
+        let (stack, lower, upper) = self.var_bounds(ptr)?;
+
+        let range = (lower + ptr.offset() as usize)..(lower + ptr.offset() as usize + buffer.len());
+        if range.end > upper {
+            return Err(invalid_offset(
+                (upper - lower) as u32,
+                ptr,
+                buffer.len() as u32,
+            ));
+        }
+
+        if self.watchpoints.len() != 0 {
+            self.check_watchpoints(ptr, buffer.len() as u32);
+        }
+
+        self.log_change(stack, range.start, buffer.len());
+        self.forget_code(ptr);
+        let to_bytes = match stack {
+            true => &mut self.stack_data[range],
+            false => &mut self.shared_data[range],
+        };
+        to_bytes.copy_from_slice(buffer);
+        return Ok(());
+    }
+
+    /// Whether the variable `ptr` points into is on the stack, and its bounds in
+    /// `stack_data` or `shared_data`. Errors if the variable can't be written to.
+    fn var_bounds(&self, ptr: VarPointer) -> Result<(bool, usize, usize), IError> {
         if ptr.var_idx() == 0 {
-            return Err(invalid_ptr(ptr));
+            return Err(deref_error(ptr));
         }
 
         let var_idx = ptr.var_idx() - 1;
         let or_else = || invalid_ptr(ptr);
 
-        let to_bytes = if ptr.is_stack() {
+        if ptr.is_stack() {
             let lower = self.stack.get(var_idx).ok_or_else(or_else)?.idx;
             let upper = self.stack.get(var_idx + 1).map(|a| a.idx);
             let upper = upper.unwrap_or(self.stack_data.len());
 
-            &mut self.stack_data[lower..upper]
+            return Ok((true, lower, upper));
         } else if ptr.is_heap() {
             let lower_var = self.heap.get(var_idx).ok_or_else(or_else)?;
             if lower_var.meta.len != n32::NULL {
-                return Err(freed_ptr(ptr));
+                return Err(freed_ptr(ptr, &lower_var.meta));
             }
 
             let lower = lower_var.idx;
             let upper = self.heap.get(var_idx + 1).map(|a| a.idx);
             let upper = upper.unwrap_or(self.shared_data.len());
 
-            &mut self.shared_data[lower..upper]
+            return Ok((false, lower, upper));
         } else {
-            let lower = self.binary.get(var_idx).ok_or_else(or_else)?.idx;
+            let lower_var = self.binary.get(var_idx).ok_or_else(or_else)?;
+            if lower_var.meta {
+                return Err(readonly_ptr(ptr));
+            }
+
+            let lower = lower_var.idx;
             let upper = self.binary.get(var_idx + 1).map(|a| a.idx);
             let heap_lower = self.heap.get(0).map(|a| a.idx);
             let upper = upper.or(heap_lower).unwrap_or(self.shared_data.len());
 
-            &mut self.shared_data[lower..upper]
+            return Ok((false, lower, upper));
+        }
+    }
+
+    /// Throws out the decoded instructions of the variable `ptr` points into, for
+    /// when it's written to
+    fn forget_code(&mut self, ptr: VarPointer) {
+        if ptr.is_binary() {
+            if let Some(code) = self.decoded.get_mut(ptr.var_idx() - 1) {
+                code.clear();
+            }
+        }
+    }
+
+    fn log_change(&mut self, stack: bool, idx: usize, len: usize) {
+        if let Some(log) = &mut self.change_log {
+            let data = if stack {
+                &self.stack_data
+            } else {
+                &self.shared_data
+            };
+            let old = data[idx..(idx + len)].to_vec();
+            log.push(MemoryChange { stack, idx, old });
+        }
+    }
+
+    fn check_watchpoints(&mut self, ptr: VarPointer, len: u32) {
+        let (begin, end) = (ptr.offset(), ptr.offset() + len);
+        for &watch in &self.watchpoints {
+            let (watch_begin, watch_end) = (watch.ptr.offset(), watch.ptr.offset() + watch.len);
+            if watch.ptr.same_var(ptr) && begin < watch_end && watch_begin < end {
+                let loc = self.loc;
+                self.watch_hit = Some(WatchHit {
+                    watch,
+                    ptr,
+                    len,
+                    loc,
+                });
+                return;
+            }
+        }
+    }
+
+    /// Stops execution after any write to the `len` bytes at `ptr`. Watchpoints on
+    /// locals and heap allocations are removed when they go out of scope or are freed.
+    pub fn watch(&mut self, ptr: VarPointer, len: u32) {
+        self.watchpoints.push(Watchpoint { ptr, len });
+    }
+
+    pub fn unwatch(&mut self, ptr: VarPointer) {
+        let watched = |w: &Watchpoint| w.ptr.same_var(ptr) && w.ptr.offset() == ptr.offset();
+        self.watchpoints.retain(|w| !watched(w));
+    }
+
+    /// Puts back the bytes that `change` overwrote
+    pub fn undo_change(&mut self, change: &MemoryChange) {
+        let data = match change.stack {
+            true => &mut self.stack_data,
+            false => {
+                self.decoded.clear();
+                &mut self.shared_data
+            }
         };
 
-        let to_len = to_bytes.len() as u32;
-        let range = (ptr.offset() as usize)..(ptr.offset() as usize + buffer.len());
-        let or_else = move || invalid_offset(to_len, ptr, buffer.len() as u32);
-        let to_bytes = to_bytes.get_mut(range).ok_or_else(or_else)?;
-        to_bytes.copy_from_slice(buffer);
-        return Ok(());
+        let end = change.idx + change.old.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[change.idx..end].copy_from_slice(&change.old);
     }
 
     pub fn cstring_bytes(&self, ptr: VarPointer) -> Result<&[u8], IError> {
         if ptr.var_idx() == 0 {
-            return Err(invalid_ptr(ptr));
+            return Err(deref_error(ptr));
         }
 
         let var_idx = ptr.var_idx() - 1;
@@ -421,7 +1106,7 @@ impl Memory {
         } else if ptr.is_heap() {
             let lower_var = self.heap.get(var_idx).ok_or_else(or_else)?;
             if lower_var.meta.len != n32::NULL {
-                return Err(freed_ptr(ptr));
+                return Err(freed_ptr(ptr, &lower_var.meta));
             }
 
             let lower = lower_var.idx;
@@ -462,7 +1147,7 @@ impl Memory {
 
     pub fn read_bytes_to_stack(&mut self, ptr: VarPointer, len: u32) -> Result<(), IError> {
         if ptr.var_idx() == 0 {
-            return Err(invalid_ptr(ptr));
+            return Err(deref_error(ptr));
         }
 
         let var_idx = ptr.var_idx() - 1;
@@ -477,7 +1162,7 @@ impl Memory {
         } else if ptr.is_heap() {
             let lower_var = self.heap.get(var_idx).ok_or_else(or_else)?;
             if lower_var.meta.len != n32::NULL {
-                return Err(freed_ptr(ptr));
+                return Err(freed_ptr(ptr, &lower_var.meta));
             }
 
             let lower = lower_var.idx;
@@ -503,48 +1188,29 @@ impl Memory {
     }
 
     pub fn write_bytes_from_stack(&mut self, ptr: VarPointer, len: u32) -> Result<(), IError> {
-        if ptr.var_idx() == 0 {
-            return Err(invalid_ptr(ptr));
-        }
-
-        let var_idx = ptr.var_idx() - 1;
-        let or_else = || invalid_ptr(ptr);
-
-        let to_bytes = if ptr.is_stack() {
-            let lower = self.stack.get(var_idx).ok_or_else(or_else)?.idx;
-            let upper = self.stack.get(var_idx + 1).map(|a| a.idx);
-            let upper = upper.unwrap_or(self.stack_data.len());
-
-            &mut self.stack_data[lower..upper]
-        } else if ptr.is_heap() {
-            let lower_var = self.heap.get(var_idx).ok_or_else(or_else)?;
-            if lower_var.meta.len != n32::NULL {
-                return Err(freed_ptr(ptr));
-            }
-
-            let lower = lower_var.idx;
-            let upper = self.heap.get(var_idx + 1).map(|a| a.idx);
-            let upper = upper.unwrap_or(self.shared_data.len());
-
-            &mut self.shared_data[lower..upper]
-        } else {
-            let lower = self.binary.get(var_idx).ok_or_else(or_else)?.idx;
-            let upper = self.binary.get(var_idx + 1).map(|a| a.idx);
-            let heap_lower = self.heap.get(0).map(|a| a.idx);
-            let upper = upper.or(heap_lower).unwrap_or(self.shared_data.len());
-
-            &mut self.shared_data[lower..upper]
-        };
+        let (stack, lower, upper) = self.var_bounds(ptr)?;
 
-        let to_len = to_bytes.len();
-        let range = (ptr.offset() as usize)..(ptr.offset() as usize + len as usize);
-        let or_else = move || invalid_offset(to_len as u32, ptr, len);
-        let to_bytes = to_bytes.get_mut(range).ok_or_else(or_else)?;
+        let range = (lower + ptr.offset() as usize)..(lower + ptr.offset() as usize + len as usize);
+        if range.end > upper {
+            return Err(invalid_offset((upper - lower) as u32, ptr, len));
+        }
 
         let stack_len = self.expr_stack.len();
         if len as usize > stack_len {
             return Err(expr_stack_too_short(stack_len, len as usize));
         }
+
+        if self.watchpoints.len() != 0 {
+            self.check_watchpoints(ptr, len);
+        }
+
+        self.log_change(stack, range.start, len as usize);
+        self.forget_code(ptr);
+        let to_bytes = match stack {
+            true => &mut self.stack_data[range],
+            false => &mut self.shared_data[range],
+        };
+
         let new_stack_len = stack_len - len as usize;
         let from_bytes = &self.expr_stack[new_stack_len..];
         to_bytes.copy_from_slice(from_bytes);
@@ -575,6 +1241,23 @@ impl Memory {
         return Ok(());
     }
 
+    /// Pushes `bytes` zeros, e.g. to make room for a return value. The expression
+    /// stack is otherwise bounded by the sizes of variables, so this is where it's
+    /// capped at `limits.max_stack_bytes`.
+    pub fn push_undef(&mut self, bytes: u32) -> Result<(), IError> {
+        let new_len = self.expr_stack.len() + bytes as usize;
+        if new_len > self.limits.max_stack_bytes {
+            return Err(ierror!(
+                "StackOverflow",
+                "expression stack would be over {} bytes",
+                self.limits.max_stack_bytes
+            ));
+        }
+
+        self.expr_stack.resize(new_len, 0);
+        return Ok(());
+    }
+
     pub fn dup_bytes(&mut self, bytes: u32) -> Result<(), IError> {
         let bytes = bytes as usize;
         let (stack_len, new_len) = (self.expr_stack.len(), self.expr_stack.len() + bytes);
@@ -591,24 +1274,40 @@ impl Memory {
         return Ok(());
     }
 
-    pub fn pop<T: Copy>(&mut self) -> Result<T, IError> {
-        let (len, stack_len) = (mem::size_of::<T>(), self.expr_stack.len());
+    pub fn pop<T: MemValue>(&mut self) -> Result<T, IError> {
+        let (len, stack_len) = (T::SIZE, self.expr_stack.len());
         if len > stack_len {
             return Err(expr_stack_too_short(stack_len, len));
         }
 
-        let from_bytes = &self.expr_stack[(stack_len - len)..];
+        let out = T::from_bytes(&self.expr_stack[(stack_len - len)..]);
+        self.expr_stack.truncate(stack_len - len);
+        return Ok(out);
+    }
 
-        let mut out = mem::MaybeUninit::uninit();
-        unsafe { any_as_u8_slice_mut(&mut out).copy_from_slice(from_bytes) };
-        self.expr_stack.resize(stack_len - len, 0);
-        return Ok(unsafe { out.assume_init() });
+    pub fn push<T: MemValue>(&mut self, t: T) {
+        let stack_len = self.expr_stack.len();
+        self.expr_stack.resize(stack_len + T::SIZE, 0);
+        t.to_bytes(&mut self.expr_stack[stack_len..]);
     }
+}
 
-    pub fn push<T: Copy>(&mut self, t: T) {
-        let from_bytes = any_as_u8_slice(&t);
-        self.expr_stack.extend_from_slice(from_bytes);
+/// Error for dereferencing a pointer that isn't in any variable
+pub fn deref_error(ptr: VarPointer) -> IError {
+    if !ptr.is_null() {
+        return invalid_ptr(ptr);
+    }
+
+    // an offset from NULL, like `ptr->field` when `ptr` is NULL
+    if ptr.offset() != 0 {
+        return ierror!(
+            "NullPointer",
+            "null pointer dereference (at offset {} from NULL)",
+            ptr.offset()
+        );
     }
+
+    return ierror!("NullPointer", "null pointer dereference");
 }
 
 pub fn invalid_ptr(ptr: VarPointer) -> IError {
@@ -619,12 +1318,31 @@ pub fn invalid_ptr(ptr: VarPointer) -> IError {
     );
 }
 
-pub fn freed_ptr(ptr: VarPointer) -> IError {
-    return ierror!(
+pub fn freed_ptr(ptr: VarPointer, info: &AllocInfo) -> IError {
+    if info.moved {
+        let error = ierror!(
+            "InvalidPointer",
+            "the pointer {} points to memory that realloc freed when it moved the block \
+             somewhere else; use the pointer realloc returned instead",
+            ptr
+        );
+        return error.with_note(info.free_loc, "moved by this call to realloc");
+    }
+
+    let error = ierror!(
         "InvalidPointer",
         "the pointer {} points to freed memory",
         ptr
     );
+    return error.with_note(info.free_loc, "freed here");
+}
+
+pub fn readonly_ptr(ptr: VarPointer) -> IError {
+    return ierror!(
+        "ReadOnlyWrite",
+        "the pointer {} points to a string literal, which can't be modified",
+        ptr
+    );
 }
 
 pub fn invalid_offset(valid_len: u32, ptr: VarPointer, len: u32) -> IError {
@@ -1,22 +1,34 @@
 #[macro_use]
 pub mod error;
 
+pub mod coverage;
 pub mod fs;
+pub mod history;
+pub mod host;
 pub mod interpreter;
 pub mod kernel;
 pub mod memory;
+pub mod profile;
+pub mod snapshot;
+pub mod trace;
 pub mod types;
 
 pub use error::*;
 pub use fs::*;
+pub use host::*;
 pub use interpreter::*;
 pub use kernel::*;
 pub use memory::*;
+pub use trace::*;
 pub use types::*;
 
 use crate::filedb::FileDb;
 use alloc::string::String;
 
+// Long stack traces only show this many of their outermost and innermost frames
+const TRACE_HEAD: usize = 3;
+const TRACE_TAIL: usize = 8;
+
 pub fn print_error(err: &IError, memory: &Memory, files: &FileDb) -> String {
     use crate::util::term::*;
     use crate::util::*;
@@ -25,7 +37,21 @@ pub fn print_error(err: &IError, memory: &Memory, files: &FileDb) -> String {
 
     write!(out, "{}: {}\n", err.short_name, err.message).unwrap();
 
-    for frame in memory.callstack.iter().skip(1) {
+    if err.short_name == "StackOverflow" {
+        write_recursion_note(&mut out, memory, files);
+    }
+
+    let frames = memory.callstack.get(1..).unwrap_or(&[]);
+    let omitted = frames.len().saturating_sub(TRACE_HEAD + TRACE_TAIL);
+    for (idx, frame) in frames.iter().enumerate() {
+        if omitted != 0 && idx >= TRACE_HEAD && idx < TRACE_HEAD + omitted {
+            if idx == TRACE_HEAD {
+                write!(out, "... {} frames omitted ...\n", omitted).unwrap();
+            }
+
+            continue;
+        }
+
         Diagnostic::new()
             .with_labels(vec![Label::new(frame.loc.file, frame.loc)])
             .render(files, &mut out)
@@ -39,5 +65,48 @@ pub fn print_error(err: &IError, memory: &Memory, files: &FileDb) -> String {
             .unwrap();
     }
 
+    for (loc, message) in &err.notes {
+        let label = Label::new(loc.file, *loc).with_message(message);
+        Diagnostic::new()
+            .with_labels(vec![label])
+            .render(files, &mut out)
+            .unwrap();
+    }
+
     return out.to_string();
 }
+
+/// Names the function that shows up on the stack the most, which is almost always
+/// the one recursing without end
+fn write_recursion_note(out: &mut impl core::fmt::Write, memory: &Memory, files: &FileDb) {
+    use crate::util::*;
+
+    let mut counts = HashMap::new();
+    let names = memory.callstack.iter().map(|f| f.name);
+    for name in names.chain(core::iter::once(memory.current_func)) {
+        *counts.entry(name).or_insert(0usize) += 1;
+    }
+
+    let most = counts.into_iter().max_by_key(|&(_, count)| count);
+    let (name, count) = match most {
+        Some((name, count)) if count > 1 => (name, count),
+        _ => return,
+    };
+
+    let loc = match memory.func_loc(name) {
+        Some(loc) if loc != NO_FILE => loc,
+        _ => return,
+    };
+
+    let source = files.source(loc.file).unwrap_or("");
+    let defn = source.get(loc.start as usize..).unwrap_or("");
+    let defn = defn.split('{').next().unwrap().trim();
+    let defn = defn.lines().next().unwrap_or("").trim();
+    let at = files.loc_to_string(loc);
+    write!(
+        out,
+        "note: `{}` ({}) is on the stack {} times; it probably recurses without a base case\n",
+        defn, at, count
+    )
+    .unwrap();
+}
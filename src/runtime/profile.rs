@@ -0,0 +1,101 @@
+//! Instruction and ecall profiling for the kernel. Like `Timings`, the host supplies
+//! the clock, since the runtime is `no_std`.
+
+use super::types::*;
+use crate::filedb::FileDb;
+use crate::util::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FuncProfile {
+    pub func: LinkName,
+    pub loc: CodeLoc, // first location the function was seen executing at
+    pub ops: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EcallProfile {
+    pub name: &'static str,
+    pub count: u64,
+    pub elapsed: u64,
+}
+
+pub struct Profile {
+    clock: fn() -> u64,
+    pub funcs: HashMap<LinkName, FuncProfile>,
+    pub ecalls: Vec<EcallProfile>,
+}
+
+impl Profile {
+    pub fn new(clock: fn() -> u64) -> Self {
+        return Self {
+            clock,
+            funcs: HashMap::new(),
+            ecalls: Vec::new(),
+        };
+    }
+
+    pub fn now(&self) -> u64 {
+        return (self.clock)();
+    }
+
+    pub fn record_op(&mut self, func: LinkName, loc: CodeLoc) {
+        let entry = self.funcs.entry(func);
+        let prof = entry.or_insert(FuncProfile { func, loc, ops: 0 });
+        prof.ops += 1;
+    }
+
+    pub fn record_ecall(&mut self, name: &'static str, elapsed: u64) {
+        if let Some(ecall) = self.ecalls.iter_mut().find(|e| e.name == name) {
+            ecall.count += 1;
+            ecall.elapsed += elapsed;
+            return;
+        }
+
+        self.ecalls.push(EcallProfile {
+            name,
+            count: 1,
+            elapsed,
+        });
+    }
+
+    /// Functions sorted by the number of instructions they executed, most first
+    pub fn sorted_funcs(&self) -> Vec<FuncProfile> {
+        let mut funcs: Vec<FuncProfile> = self.funcs.values().map(|f| *f).collect();
+        funcs.sort_by_key(|f| (core::cmp::Reverse(f.ops), f.loc.file, f.loc.start));
+        return funcs;
+    }
+
+    pub fn report(&self, files: &FileDb) -> String {
+        let mut out = StringWriter::new();
+        let funcs = self.sorted_funcs();
+        let total: u64 = funcs.iter().map(|f| f.ops).sum();
+
+        writeln!(out, "{:>12} {:>6}  {}", "ops", "%", "function").unwrap();
+        for f in &funcs {
+            let percent = f.ops as f64 * 100.0 / total as f64;
+            write!(out, "{:>12} {:>6.2}  ", f.ops, percent).unwrap();
+
+            if f.loc == NO_FILE {
+                writeln!(out, "<startup>").unwrap();
+                continue;
+            }
+
+            let mut line = StringWriter::new();
+            files.display_loc(&mut line, f.loc).unwrap();
+            let line = line.into_string();
+            let line = line.lines().next().unwrap_or("").trim();
+            let line = line.split('{').next().unwrap().trim();
+            writeln!(out, "{} ({})", line, files.loc_to_string(f.loc)).unwrap();
+        }
+
+        let mut ecalls = self.ecalls.clone();
+        ecalls.sort_by_key(|e| core::cmp::Reverse(e.elapsed));
+
+        writeln!(out, "\n{:>12} {:>12}  {}", "calls", "time", "ecall").unwrap();
+        for e in &ecalls {
+            writeln!(out, "{:>12} {:>12}  {}", e.count, e.elapsed, e.name).unwrap();
+        }
+
+        return out.into_string();
+    }
+}
@@ -0,0 +1,542 @@
+//! A flat binary encoding for runtime state. Every field is written on its own,
+//! little-endian like `Memory`, and snapshots can come from anywhere, so reading
+//! one back checks that each value is valid for its type.
+
+use super::error::*;
+use super::kernel::{IRtStat, NSIG};
+use super::memory::*;
+use super::types::*;
+use crate::util::*;
+
+const MAGIC: &[u8; 4] = b"TCIS";
+const VERSION: u32 = 6;
+
+/// A value that can be saved in a snapshot
+pub trait Snap: Sized {
+    fn write(&self, out: &mut SnapshotWriter);
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError>;
+}
+
+pub struct SnapshotWriter {
+    pub bytes: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> Self {
+        let mut writer = Self { bytes: Vec::new() };
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.put(VERSION);
+        return writer;
+    }
+
+    pub fn put<T: Snap>(&mut self, t: T) {
+        t.write(self);
+    }
+
+    pub fn put_slice<T: Snap>(&mut self, slice: &[T]) {
+        self.put(slice.len() as u64);
+        for t in slice {
+            t.write(self);
+        }
+    }
+
+    pub fn put_str(&mut self, s: &str) {
+        self.put_slice(s.as_bytes());
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, IError> {
+        let mut reader = Self { bytes, pos: 0 };
+        if bytes.get(0..4) != Some(&MAGIC[..]) {
+            return Err(ierror!("InvalidSnapshot", "data isn't a TCI snapshot"));
+        }
+
+        reader.pos = 4;
+        let version: u32 = reader.get()?;
+        if version != VERSION {
+            return Err(ierror!(
+                "InvalidSnapshot",
+                "snapshot is version {}, but this TCI reads version {}",
+                version,
+                VERSION
+            ));
+        }
+
+        return Ok(reader);
+    }
+
+    pub fn get<T: Snap>(&mut self) -> Result<T, IError> {
+        return T::read(self);
+    }
+
+    pub fn get_vec<T: Snap>(&mut self) -> Result<Vec<T>, IError> {
+        let len: u64 = self.get()?;
+
+        // Every value is at least a byte, so this doesn't allocate more than the
+        // snapshot could hold
+        if len > (self.bytes.len() - self.pos) as u64 {
+            return Err(ended());
+        }
+
+        let mut out = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            out.push(self.get()?);
+        }
+
+        return Ok(out);
+    }
+
+    pub fn get_string(&mut self) -> Result<String, IError> {
+        let bytes = self.get_vec::<u8>()?;
+        let or_else = |_| ierror!("InvalidSnapshot", "snapshot contained invalid UTF-8");
+        return String::from_utf8(bytes).map_err(or_else);
+    }
+
+    pub fn is_done(&self) -> bool {
+        return self.pos == self.bytes.len();
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], IError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..(self.pos + len))
+            .ok_or_else(ended)?;
+        self.pos += len;
+        return Ok(bytes);
+    }
+}
+
+fn ended() -> IError {
+    return ierror!("InvalidSnapshot", "snapshot ended unexpectedly");
+}
+
+/// Error for a snapshot whose values are each valid, but don't fit together
+pub fn inconsistent(what: &str) -> IError {
+    return ierror!("InvalidSnapshot", "snapshot has an invalid {}", what);
+}
+
+fn invalid(what: &str, value: u64) -> IError {
+    return ierror!(
+        "InvalidSnapshot",
+        "snapshot contained {} as {}",
+        value,
+        what
+    );
+}
+
+macro_rules! snap_mem_value {
+    ($($ty:ty),*) => {
+        $(
+            impl Snap for $ty {
+                fn write(&self, out: &mut SnapshotWriter) {
+                    let mut bytes = [0u8; <$ty as MemValue>::SIZE];
+                    self.to_bytes(&mut bytes);
+                    out.bytes.extend_from_slice(&bytes);
+                }
+
+                fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+                    return <$ty>::try_from_bytes(snap.take(<$ty as MemValue>::SIZE)?);
+                }
+            }
+        )*
+    };
+}
+
+snap_mem_value!(u8, i8, u16, i16, u32, i32, u64, i64, VarPointer, LinkName, CodeLoc, n32);
+
+impl Snap for bool {
+    fn write(&self, out: &mut SnapshotWriter) {
+        out.put(*self as u8);
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        return match snap.get::<u8>()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(invalid("a bool", value as u64)),
+        };
+    }
+}
+
+impl Snap for usize {
+    fn write(&self, out: &mut SnapshotWriter) {
+        out.put(*self as u64);
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        let value: u64 = snap.get()?;
+        if value > usize::MAX as u64 {
+            return Err(invalid("a size", value));
+        }
+
+        return Ok(value as usize);
+    }
+}
+
+impl Snap for () {
+    fn write(&self, _out: &mut SnapshotWriter) {}
+
+    fn read(_snap: &mut SnapshotReader) -> Result<Self, IError> {
+        return Ok(());
+    }
+}
+
+impl<T: Snap> Snap for Option<T> {
+    fn write(&self, out: &mut SnapshotWriter) {
+        out.put(self.is_some());
+        if let Some(t) = self {
+            t.write(out);
+        }
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        return match snap.get()? {
+            true => Ok(Some(snap.get()?)),
+            false => Ok(None),
+        };
+    }
+}
+
+impl Snap for [VarPointer; NSIG] {
+    fn write(&self, out: &mut SnapshotWriter) {
+        for ptr in self {
+            out.put(*ptr);
+        }
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        let mut out = [VarPointer::from(0u64); NSIG];
+        for ptr in out.iter_mut() {
+            *ptr = snap.get()?;
+        }
+
+        return Ok(out);
+    }
+}
+
+impl<T: Snap> Snap for Var<T> {
+    fn write(&self, out: &mut SnapshotWriter) {
+        out.put(self.idx);
+        self.meta.write(out);
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        return Ok(Var::new(snap.get()?, snap.get()?));
+    }
+}
+
+impl Snap for AllocInfo {
+    fn write(&self, out: &mut SnapshotWriter) {
+        out.put(self.alloc_loc);
+        out.put(self.free_loc);
+        out.put(self.len);
+        out.put(self.moved);
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        return Ok(AllocInfo {
+            alloc_loc: snap.get()?,
+            free_loc: snap.get()?,
+            len: snap.get()?,
+            moved: snap.get()?,
+        });
+    }
+}
+
+impl Snap for CallFrame {
+    fn write(&self, out: &mut SnapshotWriter) {
+        out.put(self.name);
+        out.put(self.loc);
+        out.put(self.fp);
+        out.put(self.pc);
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        let (name, loc) = (snap.get()?, snap.get()?);
+        return Ok(CallFrame::new(name, loc, snap.get()?, snap.get()?));
+    }
+}
+
+impl Snap for JumpSite {
+    fn write(&self, out: &mut SnapshotWriter) {
+        out.put(self.pc);
+        out.put(self.func);
+        out.put(self.loc);
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        return Ok(JumpSite {
+            pc: snap.get()?,
+            func: snap.get()?,
+            loc: snap.get()?,
+        });
+    }
+}
+
+impl Snap for Limits {
+    fn write(&self, out: &mut SnapshotWriter) {
+        out.put(self.max_ops);
+        out.put(self.max_call_depth);
+        out.put(self.max_stack_bytes);
+        out.put(self.max_heap_bytes);
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        return Ok(Limits {
+            max_ops: snap.get()?,
+            max_call_depth: snap.get()?,
+            max_stack_bytes: snap.get()?,
+            max_heap_bytes: snap.get()?,
+        });
+    }
+}
+
+impl Snap for IRtStat {
+    fn write(&self, out: &mut SnapshotWriter) {
+        match *self {
+            IRtStat::Running => out.put(0u8),
+            IRtStat::Blocked => out.put(1u8),
+            IRtStat::Exited(code) => {
+                out.put(2u8);
+                out.put(code);
+            }
+        }
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        return match snap.get::<u8>()? {
+            0 => Ok(IRtStat::Running),
+            1 => Ok(IRtStat::Blocked),
+            2 => Ok(IRtStat::Exited(snap.get()?)),
+            tag => Err(invalid("a process status", tag as u64)),
+        };
+    }
+}
+
+impl Snap for OpenMode {
+    fn write(&self, out: &mut SnapshotWriter) {
+        out.put(*self as u32);
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        let value: u32 = snap.get()?;
+        let or_else = || invalid("an open mode", value as u64);
+        return OpenMode::from_u32(value).ok_or_else(or_else);
+    }
+}
+
+impl Snap for EcallExt {
+    fn write(&self, out: &mut SnapshotWriter) {
+        match *self {
+            EcallExt::Exit(code) => {
+                out.put(0u8);
+                out.put(code);
+            }
+            EcallExt::OpenFd { name, open_mode } => {
+                out.put(1u8);
+                out.put(name);
+                out.put(open_mode);
+            }
+            EcallExt::ReadFd {
+                len,
+                buf,
+                begin,
+                fd,
+            } => {
+                out.put(2u8);
+                out.put(len);
+                out.put(buf);
+                out.put(begin);
+                out.put(fd);
+            }
+            EcallExt::WriteFd {
+                buf,
+                len,
+                begin,
+                fd,
+            } => {
+                out.put(3u8);
+                out.put(buf);
+                out.put(len);
+                out.put(begin);
+                out.put(fd);
+            }
+            EcallExt::AppendFd { buf, len, fd } => {
+                out.put(4u8);
+                out.put(buf);
+                out.put(len);
+                out.put(fd);
+            }
+            EcallExt::Fork => out.put(5u8),
+            EcallExt::Execve { path } => {
+                out.put(6u8);
+                out.put(path);
+            }
+            EcallExt::WaitPid {
+                pid,
+                status,
+                options,
+            } => {
+                out.put(7u8);
+                out.put(pid);
+                out.put(status);
+                out.put(options);
+            }
+            EcallExt::Pipe { fds } => {
+                out.put(8u8);
+                out.put(fds);
+            }
+            EcallExt::CloseFd { fd } => {
+                out.put(9u8);
+                out.put(fd);
+            }
+            EcallExt::Dup2 { old, new } => {
+                out.put(10u8);
+                out.put(old);
+                out.put(new);
+            }
+            EcallExt::PollFd { fd } => {
+                out.put(11u8);
+                out.put(fd);
+            }
+            EcallExt::Time => out.put(12u8),
+            EcallExt::CpuTime => out.put(13u8),
+            EcallExt::Signal {
+                sig,
+                handler,
+                trampoline,
+            } => {
+                out.put(14u8);
+                out.put(sig);
+                out.put(handler);
+                out.put(trampoline);
+            }
+            EcallExt::SignalReturn => out.put(15u8),
+            EcallExt::SetJmp { buf } => {
+                out.put(16u8);
+                out.put(buf);
+            }
+            EcallExt::LongJmp { buf, val } => {
+                out.put(17u8);
+                out.put(buf);
+                out.put(val);
+            }
+            EcallExt::Host { id, args, count } => {
+                out.put(18u8);
+                out.put(id);
+                out.put(args);
+                out.put(count);
+            }
+        }
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        let ecall = match snap.get::<u8>()? {
+            0 => EcallExt::Exit(snap.get()?),
+            1 => EcallExt::OpenFd {
+                name: snap.get()?,
+                open_mode: snap.get()?,
+            },
+            2 => EcallExt::ReadFd {
+                len: snap.get()?,
+                buf: snap.get()?,
+                begin: snap.get()?,
+                fd: snap.get()?,
+            },
+            3 => EcallExt::WriteFd {
+                buf: snap.get()?,
+                len: snap.get()?,
+                begin: snap.get()?,
+                fd: snap.get()?,
+            },
+            4 => EcallExt::AppendFd {
+                buf: snap.get()?,
+                len: snap.get()?,
+                fd: snap.get()?,
+            },
+            5 => EcallExt::Fork,
+            6 => EcallExt::Execve { path: snap.get()? },
+            7 => EcallExt::WaitPid {
+                pid: snap.get()?,
+                status: snap.get()?,
+                options: snap.get()?,
+            },
+            8 => EcallExt::Pipe { fds: snap.get()? },
+            9 => EcallExt::CloseFd { fd: snap.get()? },
+            10 => EcallExt::Dup2 {
+                old: snap.get()?,
+                new: snap.get()?,
+            },
+            11 => EcallExt::PollFd { fd: snap.get()? },
+            12 => EcallExt::Time,
+            13 => EcallExt::CpuTime,
+            14 => EcallExt::Signal {
+                sig: snap.get()?,
+                handler: snap.get()?,
+                trampoline: snap.get()?,
+            },
+            15 => EcallExt::SignalReturn,
+            16 => EcallExt::SetJmp { buf: snap.get()? },
+            17 => EcallExt::LongJmp {
+                buf: snap.get()?,
+                val: snap.get()?,
+            },
+            18 => EcallExt::Host {
+                id: snap.get()?,
+                args: snap.get()?,
+                count: snap.get()?,
+            },
+            tag => return Err(invalid("a system call", tag as u64)),
+        };
+
+        return Ok(ecall);
+    }
+}
+
+impl Snap for FdKind {
+    fn write(&self, out: &mut SnapshotWriter) {
+        let (tag, id) = match *self {
+            FdKind::TermIn => (0u8, 0),
+            FdKind::TermOut => (1, 0),
+            FdKind::TermErr => (2, 0),
+            FdKind::TermLog => (3, 0),
+            FdKind::FileSys(id) => (4, id),
+            FdKind::ProcessStdin(id) => (5, id),
+            FdKind::ProcessStdout(id) => (6, id),
+            FdKind::ProcessStderr(id) => (7, id),
+            FdKind::PipeRead(id) => (8, id),
+            FdKind::PipeWrite(id) => (9, id),
+            FdKind::Closed => (10, 0),
+        };
+
+        out.put(tag);
+        out.put(id);
+    }
+
+    fn read(snap: &mut SnapshotReader) -> Result<Self, IError> {
+        let (tag, id): (u8, u32) = (snap.get()?, snap.get()?);
+        let fd = match tag {
+            0 => FdKind::TermIn,
+            1 => FdKind::TermOut,
+            2 => FdKind::TermErr,
+            3 => FdKind::TermLog,
+            4 => FdKind::FileSys(id),
+            5 => FdKind::ProcessStdin(id),
+            6 => FdKind::ProcessStdout(id),
+            7 => FdKind::ProcessStderr(id),
+            8 => FdKind::PipeRead(id),
+            9 => FdKind::PipeWrite(id),
+            10 => FdKind::Closed,
+            _ => return Err(invalid("a file descriptor", tag as u64)),
+        };
+
+        return Ok(fd);
+    }
+}
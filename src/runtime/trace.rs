@@ -0,0 +1,58 @@
+//! Ecall traces, so that a run that depended on live terminal input can be played
+//! back later without it.
+
+use super::error::*;
+use crate::util::*;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TraceEvent {
+    pub proc: u32,
+    pub ecall: String,
+    pub input: Vec<u8>,  // bytes read from the terminal during the ecall
+    pub result: Vec<u8>, // bytes the ecall pushed onto the process's expression stack
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Trace {
+    pub events: Vec<TraceEvent>,
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        return Self { events: Vec::new() };
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        return serde_json::to_vec(self).unwrap();
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, IError> {
+        let or_else = |e: serde_json::Error| ierror!("InvalidTrace", "couldn't read trace: {}", e);
+        return serde_json::from_slice(bytes).map_err(or_else);
+    }
+}
+
+pub enum TraceMode {
+    Off,
+    Record(Trace),
+    Replay(Trace, usize), // index of the next event to replay
+}
+
+pub fn replay_diverged(event: Option<&TraceEvent>, proc: u32, ecall: &str) -> IError {
+    return match event {
+        Some(e) => ierror!(
+            "ReplayDiverged",
+            "process {} made a `{}` ecall, but the trace has process {} making a `{}` ecall",
+            proc,
+            ecall,
+            e.proc,
+            e.ecall
+        ),
+        None => ierror!(
+            "ReplayDiverged",
+            "process {} made a `{}` ecall after the trace ended",
+            proc,
+            ecall
+        ),
+    };
+}
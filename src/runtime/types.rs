@@ -1,4 +1,7 @@
+use super::error::IError;
+use super::memory::MemValue;
 use crate::util::*;
+use core::convert::TryFrom;
 use core::{fmt, mem};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -16,7 +19,10 @@ impl<T> Var<T> {
 #[derive(Debug, Clone, PartialEq)]
 pub struct BinaryData {
     pub data: Vec<u8>,
-    pub vars: Vec<Var<()>>,
+    pub vars: Vec<Var<bool>>, // meta is whether the variable is read-only
+
+    // string literals, so that identical ones share the same read-only variable
+    pub strings: HashMap<String, u32>,
 }
 
 impl BinaryData {
@@ -24,6 +30,7 @@ impl BinaryData {
         Self {
             data: Vec::new(),
             vars: Vec::new(),
+            strings: HashMap::new(),
         }
     }
 
@@ -34,25 +41,35 @@ impl BinaryData {
             self.data.push(0);
         }
 
-        self.vars.push(Var::new(data_len, ()));
+        self.vars.push(Var::new(data_len, false));
         return VarPointer::new_binary(self.vars.len() as u32, 0);
     }
 
     pub fn add_data(&mut self, data: &mut Vec<u8>) -> VarPointer {
         let data_len = self.data.len();
         self.data.append(data);
-        self.vars.push(Var::new(data_len, ()));
+        self.vars.push(Var::new(data_len, false));
         return VarPointer::new_binary(self.vars.len() as u32, 0);
     }
 
-    pub fn add_slice(&mut self, data: &[u8]) -> VarPointer {
+    /// Adds a NUL-terminated string literal, or returns the one that's already
+    /// there. Writing through the returned pointer is a runtime error.
+    pub fn add_string(&mut self, string: &str) -> VarPointer {
+        if let Some(&idx) = self.strings.get(string) {
+            return VarPointer::new_binary(idx, 0);
+        }
+
         let data_len = self.data.len();
-        self.data.extend_from_slice(data);
-        self.vars.push(Var::new(data_len, ()));
-        return VarPointer::new_binary(self.vars.len() as u32, 0);
+        self.data.extend_from_slice(string.as_bytes());
+        self.data.push(0);
+        self.vars.push(Var::new(data_len, true));
+
+        let idx = self.vars.len() as u32;
+        self.strings.insert(string.to_string(), idx);
+        return VarPointer::new_binary(idx, 0);
     }
 
-    pub fn read<T: Copy>(&mut self, ptr: VarPointer) -> Option<T> {
+    pub fn read<T: MemValue>(&mut self, ptr: VarPointer) -> Option<T> {
         if ptr.var_idx() == 0 {
             return None;
         }
@@ -63,15 +80,11 @@ impl BinaryData {
         let upper = upper.unwrap_or(self.data.len());
 
         let data = &mut self.data[lower..upper];
-        let (idx, len) = (ptr.offset() as usize, mem::size_of::<T>());
-        let from_bytes = data.get(idx..(idx + len))?;
-
-        let mut out = mem::MaybeUninit::uninit();
-        unsafe { any_as_u8_slice_mut(&mut out).copy_from_slice(from_bytes) };
-        return Some(unsafe { out.assume_init() });
+        let (idx, len) = (ptr.offset() as usize, T::SIZE);
+        return Some(T::from_bytes(data.get(idx..(idx + len))?));
     }
 
-    pub fn write<T: Copy>(&mut self, ptr: VarPointer, t: T) {
+    pub fn write<T: MemValue>(&mut self, ptr: VarPointer, t: T) {
         if ptr.var_idx() == 0 {
             panic!("passed in nullish pointer");
         }
@@ -82,15 +95,12 @@ impl BinaryData {
         let upper = upper.unwrap_or(self.data.len());
 
         let data = &mut self.data[lower..upper];
-        let (idx, len) = (ptr.offset() as usize, mem::size_of::<T>());
-        let to_bytes = data.get_mut(idx..(idx + len)).unwrap();
-
-        let from_bytes = any_as_u8_slice(&t);
-        to_bytes.copy_from_slice(from_bytes);
+        let (idx, len) = (ptr.offset() as usize, T::SIZE);
+        t.to_bytes(data.get_mut(idx..(idx + len)).unwrap());
     }
 }
 
-#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct VarPointer(u64);
 
 impl fmt::Display for VarPointer {
@@ -104,6 +114,18 @@ impl fmt::Debug for VarPointer {
         return write!(formatter, "0x{:0>16x}", self.0);
     }
 }
+impl From<u64> for VarPointer {
+    fn from(ptr: u64) -> Self {
+        return Self(ptr);
+    }
+}
+
+impl From<VarPointer> for u64 {
+    fn from(ptr: VarPointer) -> Self {
+        return ptr.0;
+    }
+}
+
 impl VarPointer {
     pub const BINARY_BIT: u64 = 1u64 << 63;
     pub const STACK_BIT: u64 = 1u64 << 62;
@@ -136,6 +158,11 @@ impl VarPointer {
         return Self(Self::BINARY_BIT | idx | offset);
     }
 
+    /// Whether this is NULL, or an offset from NULL
+    pub fn is_null(self) -> bool {
+        return self.0 & Self::TOP_BITS == 0;
+    }
+
     pub fn is_stack(self) -> bool {
         return (self.0 & Self::RESERVED_BITS) == Self::STACK_BIT;
     }
@@ -171,6 +198,11 @@ impl VarPointer {
         return Self((self.0 & Self::TOP_BITS) | (offset as u64));
     }
 
+    /// Whether both pointers point into the same variable
+    pub fn same_var(self, other: Self) -> bool {
+        return (self.0 & Self::TOP_BITS) == (other.0 & Self::TOP_BITS);
+    }
+
     pub fn offset(self) -> u32 {
         return (self.0 & Self::BOTTOM_BITS) as u32;
     }
@@ -210,7 +242,7 @@ impl LinkName {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct CallFrame {
     pub name: LinkName,
     pub loc: CodeLoc,
@@ -454,12 +486,101 @@ pub enum Opcode {
     AllocEnd,
     HeapAlloc,
     HeapDealloc,
+    HeapMove, // `HeapDealloc` for the old block when `realloc` moves it
 
     Throw,
 
     Ecall,
 
     AssertStr,
+    AssertAlign,
+    AssertNoOverflow,
+    AssertInObject,
+    AssertSameObject,
+}
+
+impl Opcode {
+    pub const LAST: Opcode = Opcode::AssertSameObject;
+
+    /// How many bytes of operand follow the opcode in code
+    pub fn operand_size(self) -> usize {
+        return match self {
+            Opcode::Func => LinkName::SIZE + CodeLoc::SIZE,
+            Opcode::Loc => CodeLoc::SIZE,
+            Opcode::Make8 => 1,
+            Opcode::Make16 | Opcode::MakeFp | Opcode::MakeSp => 2,
+            Opcode::Make32 => 4,
+            Opcode::Make64 => 8,
+            Opcode::StackAlloc | Opcode::PushUndef | Opcode::Pop | Opcode::Dup => 4,
+            Opcode::Get | Opcode::Set | Opcode::AssertAlign => 4,
+            Opcode::Swap => 8,
+            Opcode::AssertNoOverflow => 5,
+            Opcode::AssertInObject => 1,
+            Opcode::Jump => mem::size_of::<VarPointer>(),
+            Opcode::JumpIfZero8 | Opcode::JumpIfZero16 => mem::size_of::<VarPointer>(),
+            Opcode::JumpIfZero32 | Opcode::JumpIfZero64 => mem::size_of::<VarPointer>(),
+            Opcode::JumpIfNotZero8 | Opcode::JumpIfNotZero16 => mem::size_of::<VarPointer>(),
+            Opcode::JumpIfNotZero32 | Opcode::JumpIfNotZero64 => mem::size_of::<VarPointer>(),
+            _ => 0,
+        };
+    }
+}
+
+/// Code is written by the assembler, but `pc` can still end up in memory a program
+/// wrote to, through a cast function pointer, so each byte is checked before it's
+/// used as an opcode
+impl TryFrom<u8> for Opcode {
+    type Error = IError;
+
+    fn try_from(byte: u8) -> Result<Self, IError> {
+        if byte > Opcode::LAST as u8 {
+            return Err(ierror!(
+                "InvalidOpcode",
+                "ran into the byte {}, which isn't an instruction",
+                byte
+            ));
+        }
+
+        // Safety: `Opcode` is `repr(u8)` and numbered from 0 to `LAST`
+        return Ok(unsafe { mem::transmute::<u8, Opcode>(byte) });
+    }
+}
+
+/// Signed arithmetic that `Opcode::AssertNoOverflow` checks; `Add` and `Sub` are
+/// also the pointer arithmetic that `Opcode::AssertInObject` checks
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckedOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Neg,
+}
+
+impl TryFrom<u8> for CheckedOp {
+    type Error = IError;
+
+    fn try_from(byte: u8) -> Result<Self, IError> {
+        let op = match byte {
+            0 => CheckedOp::Add,
+            1 => CheckedOp::Sub,
+            2 => CheckedOp::Mul,
+            3 => CheckedOp::Div,
+            4 => CheckedOp::Mod,
+            5 => CheckedOp::Neg,
+            _ => {
+                return Err(ierror!(
+                    "InvalidOpcode",
+                    "ran into the byte {}, which isn't a checked operation",
+                    byte
+                ))
+            }
+        };
+
+        return Ok(op);
+    }
 }
 
 // ABI matters here. This enum is linked to /lib/header/tci.h
@@ -482,9 +603,73 @@ pub enum Ecall {
     WriteFd,
     /// append to a file descriptor
     AppendFd,
+
+    /// clone the current process; returns the child's id to the parent and 0 to the child
+    Fork = 8,
+    /// replace the current process's program with one installed in the kernel
+    Execve,
+    /// wait for a child process to exit
+    WaitPid,
+
+    /// create a pipe, writing its read and write ends to an `int[2]`
+    Pipe,
+    /// close a file descriptor
+    CloseFd,
+    /// make one file descriptor refer to the same thing as another
+    Dup2,
+    /// check whether a file descriptor is ready, without blocking
+    PollFd,
+
+    /// get the time, in microseconds since the Unix epoch
+    Time,
+    /// get the processor time the process has used, in microseconds
+    CpuTime,
+
+    /// install a handler for a signal the kernel raises when the process faults
+    Signal,
+    /// finish running a fault handler, terminating the process
+    SignalReturn,
+
+    /// save where the program is into a `jmp_buf`
+    SetJmp,
+    /// go back to where a `jmp_buf` was saved
+    LongJmp,
 }
 
-#[derive(Debug, Clone)]
+/// Ecalls from here up call the host function `ecall - HOST_ECALL_BASE`; see
+/// `HostFns`. Linked to /lib/header/tci.h
+pub const HOST_ECALL_BASE: u32 = 1024;
+
+impl Ecall {
+    const ALL: [Ecall; 20] = [
+        Ecall::Exit,
+        Ecall::Argc,
+        Ecall::Argv,
+        Ecall::OpenFd,
+        Ecall::ReadFd,
+        Ecall::WriteFd,
+        Ecall::AppendFd,
+        Ecall::Fork,
+        Ecall::Execve,
+        Ecall::WaitPid,
+        Ecall::Pipe,
+        Ecall::CloseFd,
+        Ecall::Dup2,
+        Ecall::PollFd,
+        Ecall::Time,
+        Ecall::CpuTime,
+        Ecall::Signal,
+        Ecall::SignalReturn,
+        Ecall::SetJmp,
+        Ecall::LongJmp,
+    ];
+
+    pub fn from_u32(value: u32) -> Option<Self> {
+        return Self::ALL.iter().copied().find(|&e| e as u32 == value);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum EcallExt {
     Exit(i32),
 
@@ -509,6 +694,80 @@ pub enum EcallExt {
         len: u32,
         fd: u32,
     },
+
+    Fork,
+    Execve {
+        path: VarPointer,
+    },
+    WaitPid {
+        pid: i32,
+        status: VarPointer,
+        options: i32,
+    },
+
+    Pipe {
+        fds: VarPointer,
+    },
+    CloseFd {
+        fd: u32,
+    },
+    Dup2 {
+        old: u32,
+        new: u32,
+    },
+    PollFd {
+        fd: u32,
+    },
+
+    Time,
+    CpuTime,
+
+    Signal {
+        sig: i32,
+        handler: VarPointer,
+        trampoline: VarPointer,
+    },
+    SignalReturn,
+
+    SetJmp {
+        buf: VarPointer,
+    },
+    LongJmp {
+        buf: VarPointer,
+        val: i32,
+    },
+
+    Host {
+        id: u32,
+        args: VarPointer,
+        count: u32,
+    },
+}
+
+impl EcallExt {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            EcallExt::Exit(_) => "exit",
+            EcallExt::OpenFd { .. } => "open_fd",
+            EcallExt::ReadFd { .. } => "read_fd",
+            EcallExt::WriteFd { .. } => "write_fd",
+            EcallExt::AppendFd { .. } => "append_fd",
+            EcallExt::Fork => "fork",
+            EcallExt::Execve { .. } => "execve",
+            EcallExt::WaitPid { .. } => "waitpid",
+            EcallExt::Pipe { .. } => "pipe",
+            EcallExt::CloseFd { .. } => "close_fd",
+            EcallExt::Dup2 { .. } => "dup2",
+            EcallExt::PollFd { .. } => "poll_fd",
+            EcallExt::Time => "time",
+            EcallExt::CpuTime => "cpu_time",
+            EcallExt::Signal { .. } => "signal",
+            EcallExt::SignalReturn => "signal_return",
+            EcallExt::SetJmp { .. } => "setjmp",
+            EcallExt::LongJmp { .. } => "longjmp",
+            EcallExt::Host { .. } => "host",
+        };
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -542,6 +801,9 @@ pub enum EcallError {
     StreamLen = 10,
 
     InvalidOpenMode = 11,
+
+    // Pipes
+    BrokenPipe = 12,
 }
 
 impl EcallError {
@@ -559,6 +821,17 @@ pub enum OpenMode {
     CreateClear = 2,
 }
 
+impl OpenMode {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        return match value {
+            0 => Some(OpenMode::Read),
+            1 => Some(OpenMode::Create),
+            2 => Some(OpenMode::CreateClear),
+            _ => None,
+        };
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum FdKind {
     TermIn,
@@ -570,4 +843,8 @@ pub enum FdKind {
     ProcessStdin(u32),
     ProcessStdout(u32),
     ProcessStderr(u32),
+
+    PipeRead(u32),
+    PipeWrite(u32),
+    Closed,
 }
@@ -1,11 +1,12 @@
 use crate::buckets::*;
-use crate::filedb::*;
+use crate::interner::*;
 use crate::runtime::Opcode;
 use crate::util::*;
 use core::fmt::Write;
 use serde::Serialize;
 
 pub use crate::ast::{BinOp, UnaryOp};
+use crate::ast::{TypeQualifier, TypeQualifierKind};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TCIdent {
@@ -125,6 +126,12 @@ pub enum TCOpcodeKind {
         default: u32,
     },
 
+    // The ops before `end` belong to a statement expression, and run wherever
+    // the expression is evaluated rather than here
+    StmtExpr {
+        end: u32,
+    },
+
     Expr(TCExpr),
     Ret,
     RetVal(TCExpr),
@@ -154,6 +161,7 @@ impl TCOpcode {
                     ty,
                     loc,
                     offset: 0,
+                    bitfield: None,
                 },
                 value: alloc.add(init_expr),
             },
@@ -244,10 +252,41 @@ impl TCTypeBase {
     }
 }
 
+/// Qualifiers on a pointer, like the `restrict` in `char *restrict s`. They're
+/// kept around, but don't change how anything type checks or runs yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct TCQualifiers {
+    pub is_const: bool,
+    pub is_volatile: bool,
+    pub is_restrict: bool,
+}
+
+impl TCQualifiers {
+    pub const NONE: Self = Self {
+        is_const: false,
+        is_volatile: false,
+        is_restrict: false,
+    };
+
+    pub fn new(quals: &[TypeQualifier]) -> Self {
+        let mut out = Self::NONE;
+        for qual in quals {
+            match qual.kind {
+                TypeQualifierKind::Const => out.is_const = true,
+                TypeQualifierKind::Volatile => out.is_volatile = true,
+                TypeQualifierKind::Restrict => out.is_restrict = true,
+                TypeQualifierKind::Atomic => {} // not parsed yet
+            }
+        }
+
+        return out;
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Hash, Serialize)]
 #[serde(tag = "modifier", content = "data")]
 pub enum TCTypeModifier {
-    Pointer, // TODO add qualifiers
+    Pointer(TCQualifiers),
     Array(u32),
     VariableArray,
     BeginParam(TCType),
@@ -257,6 +296,16 @@ pub enum TCTypeModifier {
     UnknownParams,
 }
 
+impl TCTypeModifier {
+    /// Qualifiers don't affect whether types are compatible, at least for now
+    pub fn unqualified(self) -> Self {
+        return match self {
+            TCTypeModifier::Pointer(_) => TCTypeModifier::Pointer(TCQualifiers::NONE),
+            modifier => modifier,
+        };
+    }
+}
+
 pub struct TCTypeRef<'a> {
     pub base: TCTypeBase,
     pub mods: &'a [TCTypeModifier],
@@ -311,8 +360,31 @@ pub trait TCTy {
         }
     }
 
-    // TODO make this nicer
+    /// The type as it was written, e.g. `size_t`
     fn display(&self, symbols: &Symbols) -> String {
+        return self.display_with(symbols, false);
+    }
+
+    /// The type as it was written, followed by what it resolves to if it uses a
+    /// typedef, e.g. `size_t (aka unsigned long)`. For error messages.
+    fn display_aka(&self, symbols: &Symbols) -> String {
+        let spelled = self.display_with(symbols, false);
+        let resolved = self.display_with(symbols, true);
+        if spelled == resolved {
+            return spelled;
+        }
+
+        return format!("{} (aka {})", spelled, resolved);
+    }
+
+    /// Writes out the type, replacing typedef names with what they refer to if
+    /// `resolve` is set
+    fn display_with(&self, symbols: &Symbols, resolve: bool) -> String {
+        if resolve && self.get_typedef().is_some() {
+            return self.expand_typedef().display_with(symbols, true);
+        }
+
+        // TODO make this nicer
         let mut writer = StringWriter::new();
 
         match self.base() {
@@ -335,7 +407,9 @@ pub trait TCTy {
                 write!(writer, "union {}", symbols.to_str(ident).unwrap())
             }
             TCTypeBase::UnnamedUnion { .. } => write!(writer, "anonymous union"),
-            TCTypeBase::InternalTypedef(def) => write!(writer, "{}", def.display(symbols)), // TODO fix this
+            TCTypeBase::InternalTypedef(def) => {
+                write!(writer, "{}", def.display_with(symbols, resolve)) // TODO fix this
+            }
             TCTypeBase::Typedef { typedef, .. } => {
                 write!(writer, "{}", symbols.to_str(typedef.0).unwrap())
             }
@@ -345,7 +419,7 @@ pub trait TCTy {
         let mut is_func: Option<()> = None;
         for modifier in self.mods() {
             match modifier {
-                TCTypeModifier::Pointer => {
+                TCTypeModifier::Pointer(_) => {
                     is_func.take().map(|_| write!(writer, ")"));
                     write!(writer, "*")
                 }
@@ -359,13 +433,13 @@ pub trait TCTy {
                 }
                 TCTypeModifier::BeginParam(ty) => {
                     if is_func.replace(()).is_some() {
-                        write!(writer, ")({}", ty.display(symbols))
+                        write!(writer, ")({}", ty.display_with(symbols, resolve))
                     } else {
-                        write!(writer, "({}", ty.display(symbols))
+                        write!(writer, "({}", ty.display_with(symbols, resolve))
                     }
                 }
                 TCTypeModifier::Param(ty) => {
-                    write!(writer, ", {}", ty.display(symbols))
+                    write!(writer, ", {}", ty.display_with(symbols, resolve))
                 }
                 TCTypeModifier::NoParams => {
                     is_func.take().map(|_| write!(writer, ")"));
@@ -461,7 +535,7 @@ pub trait TCTy {
             return true;
         }
 
-        if let TCTypeModifier::Pointer = self.mods()[0] {
+        if let TCTypeModifier::Pointer(_) = self.mods()[0] {
             let (base, mods) = (self.base(), &self.mods()[1..]);
 
             if (TCTypeRef { base, mods }).is_function() {
@@ -489,7 +563,7 @@ pub trait TCTy {
             return false;
         }
 
-        return let_expr!(TCTypeModifier::Pointer = self.mods()[0])
+        return let_expr!(TCTypeModifier::Pointer(_) = self.mods()[0])
             || let_expr!(TCTypeModifier::Array(_) = self.mods()[0])
             || let_expr!(TCTypeModifier::VariableArray = self.mods()[0]);
     }
@@ -546,7 +620,7 @@ pub trait TCTy {
     fn is_complete(&self) -> bool {
         if let Some(first) = self.mods().first() {
             match first {
-                TCTypeModifier::Pointer => return true,
+                TCTypeModifier::Pointer(_) => return true,
                 TCTypeModifier::BeginParam(_)
                 | TCTypeModifier::NoParams
                 | TCTypeModifier::UnknownParams => return true,
@@ -574,7 +648,7 @@ pub trait TCTy {
     fn repr_size(&self) -> u32 {
         for modifier in self.mods() {
             match modifier {
-                TCTypeModifier::Pointer => return 8,
+                TCTypeModifier::Pointer(_) => return 8,
                 TCTypeModifier::BeginParam(_)
                 | TCTypeModifier::NoParams
                 | TCTypeModifier::UnknownParams => return 8,
@@ -602,7 +676,7 @@ pub trait TCTy {
     fn align(&self) -> n32 {
         for modifier in self.mods() {
             match modifier {
-                TCTypeModifier::Pointer => return 8u32.into(),
+                TCTypeModifier::Pointer(_) => return 8u32.into(),
                 TCTypeModifier::BeginParam(_)
                 | TCTypeModifier::NoParams
                 | TCTypeModifier::UnknownParams => return n32::NULL,
@@ -620,7 +694,7 @@ pub trait TCTy {
         let mut is_array = false;
         for modifier in self.mods() {
             match modifier {
-                TCTypeModifier::Pointer => {
+                TCTypeModifier::Pointer(_) => {
                     if is_array {
                         return (multiplier * 8).into();
                     } else {
@@ -651,7 +725,7 @@ pub trait TCTy {
     fn to_prim_type(&self) -> Option<TCPrimType> {
         for modifier in self.mods() {
             match modifier {
-                TCTypeModifier::Pointer => {
+                TCTypeModifier::Pointer(_) => {
                     let deref = TCTypeRef {
                         base: self.base(),
                         mods: &self.mods()[1..],
@@ -707,7 +781,7 @@ pub trait TCTy {
             let to_ret = TCTypeRef { base, mods };
 
             match first {
-                TCTypeModifier::Pointer => {
+                TCTypeModifier::Pointer(_) => {
                     if to_ret.is_function() {
                         let mods = self.mods();
                         return Some(TCTypeRef { base, mods });
@@ -779,7 +853,7 @@ pub trait TCTy {
                 }
             };
 
-            if l != r {
+            if l.unqualified() != r.unqualified() {
                 return false;
             }
         }
@@ -937,7 +1011,7 @@ impl TCType {
             let to_ret = TCType { base, mods };
 
             match first {
-                TCTypeModifier::Pointer => {
+                TCTypeModifier::Pointer(_) => {
                     if to_ret.is_function() {
                         return Some(*self);
                     }
@@ -1033,7 +1107,7 @@ impl TCType {
     pub fn new_ptr(base: TCTypeBase) -> Self {
         TCType {
             base,
-            mods: &[TCTypeModifier::Pointer],
+            mods: &[TCTypeModifier::Pointer(TCQualifiers::NONE)],
         }
     }
 }
@@ -1071,7 +1145,7 @@ impl TCTypeOwned {
                 }
                 TCTypeModifier::Array(_) | TCTypeModifier::VariableArray => {
                     if found_func {
-                        *modifier = TCTypeModifier::Pointer;
+                        *modifier = TCTypeModifier::Pointer(TCQualifiers::NONE);
                     }
                 }
                 _ => {}
@@ -1081,13 +1155,14 @@ impl TCTypeOwned {
 
     pub fn canonicalize_param(&mut self) {
         if self.is_function() {
-            self.mods.insert(0, TCTypeModifier::Pointer);
+            self.mods
+                .insert(0, TCTypeModifier::Pointer(TCQualifiers::NONE));
         }
 
         for modifier in &mut self.mods {
             match modifier {
                 TCTypeModifier::Array(_) | TCTypeModifier::VariableArray => {
-                    *modifier = TCTypeModifier::Pointer;
+                    *modifier = TCTypeModifier::Pointer(TCQualifiers::NONE);
                 }
                 _ => {}
             }
@@ -1178,8 +1253,11 @@ pub enum TCExprKind {
         elems: &'static [(TCExprKind, CodeLoc)],
         elem_ty: TCType,
     },
+    /// Each field's value is written at its offset, keeping only the given
+    /// number of low bytes; bitfields sharing bytes are packed into one value
     StructLit {
         fields: &'static [TCExpr],
+        offsets: &'static [(u32, u32)],
         size: u32,
     },
     ParenList(&'static [TCExpr]),
@@ -1234,10 +1312,12 @@ pub enum TCExprKind {
     Member {
         base: &'static TCExpr,
         offset: u32,
+        bitfield: Option<TCBitfield>,
     },
     PtrMember {
         base: &'static TCExpr,
         offset: u32,
+        bitfield: Option<TCBitfield>,
     },
 
     Ref(TCAssignTarget),
@@ -1248,6 +1328,14 @@ pub enum TCExprKind {
         params: &'static [TCExpr],
     },
     Builtin(TCBuiltin),
+
+    // GNU `({ ... })`. `begin` is the index of its `TCOpcodeKind::StmtExpr` in the
+    // function's ops, and `value` is the index of the `Expr` op that gives the
+    // result, if it has one.
+    StmtExpr {
+        begin: u32,
+        value: n32,
+    },
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -1257,6 +1345,34 @@ pub struct TCExpr {
     pub loc: CodeLoc,
 }
 
+impl TCExpr {
+    /// Whether this is a null pointer constant, i.e. an integer constant that's 0,
+    /// or one that's been cast to `void *`
+    pub fn is_null_ptr_constant(&self) -> bool {
+        let zero = match self.kind {
+            TCExprKind::I8Lit(i) => i == 0,
+            TCExprKind::U8Lit(i) => i == 0,
+            TCExprKind::I16Lit(i) => i == 0,
+            TCExprKind::U16Lit(i) => i == 0,
+            TCExprKind::I32Lit(i) => i == 0,
+            TCExprKind::U32Lit(i) => i == 0,
+            TCExprKind::I64Lit(i) => i == 0,
+            TCExprKind::U64Lit(i) => i == 0,
+            _ => false,
+        };
+
+        if !zero {
+            return false;
+        }
+
+        if self.ty.is_integer() {
+            return true;
+        }
+
+        return self.ty.deref().map(|ty| ty.is_void()).unwrap_or(false);
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum TCAssignTargetKind {
     LocalIdent { label: u32 },
@@ -1271,6 +1387,7 @@ pub struct TCAssignTarget {
     pub loc: CodeLoc,
     pub ty: TCType,
     pub offset: u32,
+    pub bitfield: Option<TCBitfield>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -1393,6 +1510,20 @@ pub struct TCGlobalVar {
     pub loc: CodeLoc,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TCSymbolScope {
+    Global,
+    Local(CodeLoc), // keyed by the location of the declaration
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TCSymbolRef {
+    pub ident: u32,
+    pub scope: TCSymbolScope,
+    pub ty: TCType,
+    pub loc: CodeLoc,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct TCStaticInternalVar {
     pub init: TCExprKind,
@@ -1406,6 +1537,8 @@ pub struct TCFuncDefn {
     pub sym_count: u32,
     pub label_count: u32,
     pub ops: &'static [TCOpcode],
+    pub locals: &'static [(u32, TCVar)], // (ident, var) of every parameter and local
+    pub is_inline: bool,
     pub loc: CodeLoc,
 }
 
@@ -1432,6 +1565,7 @@ pub struct TCParamsDeclarator {
 
 pub struct TCFunctionDeclarator {
     pub is_static: bool,
+    pub is_inline: bool,
     pub return_type: TCType,
     pub ident: u32,
     pub params: Option<TCParamsDeclarator>,
@@ -1449,6 +1583,10 @@ pub struct TranslationUnit {
     pub var_count: u32,
     pub vars: HashMap<u32, TCGlobalVar>,
     pub static_internal_vars: HashMap<CodeLoc, TCStaticInternalVar>,
+
+    pub refs: Vec<TCSymbolRef>,
+    pub aggregates: Vec<TCAggregate>, // in the order they're defined
+    pub warnings: Vec<Error>,         // only for shadowed variables; see `CheckOptions`
 }
 
 pub struct TCDecl {
@@ -1463,13 +1601,24 @@ pub struct TCStructField {
     pub name: u32,
     pub ty: TCType,
     pub offset: u32,
+    pub bitfield: Option<TCBitfield>,
     pub loc: CodeLoc,
 }
 
+/// The bits a bitfield takes up in its storage unit, which is a value of the
+/// field's type at the field's offset. Bits are counted from the least
+/// significant one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TCBitfield {
+    pub offset: u32,
+    pub width: u32,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct TCStructDefn {
     pub fields: &'static [TCStructField],
     pub loc: CodeLoc,
+    pub poisoned: bool, // a member had an error, so it was left out
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -1479,6 +1628,15 @@ pub struct TCStruct {
     pub decl_loc: CodeLoc,
 }
 
+/// A struct or union definition, as it's laid out in memory
+#[derive(Debug, Clone, Copy)]
+pub struct TCAggregate {
+    pub ident: n32, // NULL if it's anonymous
+    pub is_union: bool,
+    pub sa: SizeAlign,
+    pub defn: TCStructDefn,
+}
+
 pub enum DeclarationResult {
     Typedef {
         ty: TCType,
@@ -1507,6 +1665,10 @@ impl TranslationUnit {
             var_count: 0,
             static_internal_vars: HashMap::new(),
             vars: HashMap::new(),
+
+            refs: Vec::new(),
+            aggregates: Vec::new(),
+            warnings: Vec::new(),
         }
     }
 }
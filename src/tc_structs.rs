@@ -1,7 +1,9 @@
 use crate::buckets::*;
-use crate::filedb::*;
+use crate::interner::*;
+use crate::optimizer::{fold, int_value, is_int_constant};
 use crate::tc_ast::*;
 use crate::util::*;
+use core::cell::Cell;
 
 pub struct ContBr {
     pub cont: u32,
@@ -14,6 +16,7 @@ pub struct FuncEnv {
     pub translate_gotos: Vec<u32>,
     pub next_label: u32,
     pub next_symbol_label: u32,
+    pub locals: Vec<(u32, TCVar)>,
 
     // const fields
     pub return_type: TCType,
@@ -39,6 +42,7 @@ pub enum TypeEnvKind<'a> {
         cases: Vec<(TCExpr, u32)>,
         default: n32,
         default_loc: CodeLoc,
+        loc: CodeLoc, // the switch's expression
         parent: *mut TypeEnv<'a>,
         global: *mut TypeEnv<'a>,
     },
@@ -56,6 +60,21 @@ pub struct TypeEnv<'a> {
 pub struct GlobalTypeEnv<'a> {
     tu: TranslationUnit,
     symbols: &'a Symbols,
+    pub current_func: n32,
+    pub errors: Vec<Error>,
+    pub unknown_uses: Vec<(u32, CodeLoc)>,
+    pub prototypes: HashMap<u32, (CodeLoc, CodeLoc)>, // (signature, declarator) of each function definition
+    pub gnu_extensions: bool, // `--std=gnu11`, where `void` is 1 byte like in GCC
+    pub warn_shadow: bool,    // `-Wshadow`; the warnings go in `TranslationUnit::warnings`
+}
+
+/// The global scope a translation unit leaves behind once it's checked, so that more
+/// code can be checked against it without checking the whole unit again
+pub struct GlobalScope {
+    pub tu: TranslationUnit,
+    pub structs: HashMap<LabelOrLoc, TCStruct>,
+    pub unions: HashMap<LabelOrLoc, TCStruct>,
+    pub typedefs: HashMap<u32, (&'static TCType, CodeLoc)>,
 }
 
 pub struct LocalTypeEnv<'a> {
@@ -84,6 +103,7 @@ impl FuncEnv {
             translate_gotos: Vec::new(),
             next_label: 0,
             next_symbol_label: 0,
+            locals: Vec::new(),
             return_type,
             decl_loc,
         };
@@ -110,6 +130,12 @@ impl<'a> TypeEnv<'a> {
             kind: TypeEnvKind::Global(GlobalTypeEnv {
                 tu: TranslationUnit::new(file),
                 symbols,
+                current_func: n32::NULL,
+                errors: Vec::new(),
+                unknown_uses: Vec::new(),
+                prototypes: HashMap::new(),
+                gnu_extensions: false,
+                warn_shadow: false,
             }),
             structs: HashMap::new(),
             unions: HashMap::new(),
@@ -128,6 +154,36 @@ impl<'a> TypeEnv<'a> {
         };
     }
 
+    /// The global scope of a translation unit that's already been checked
+    pub fn from_scope(scope: GlobalScope, symbols: &'a Symbols) -> Self {
+        let mut env = Self::global(scope.tu.file, symbols);
+        env.structs = scope.structs;
+        env.unions = scope.unions;
+        env.typedefs = scope.typedefs;
+        if let TypeEnvKind::Global(globals) = &mut env.kind {
+            globals.tu = scope.tu;
+        }
+
+        return env;
+    }
+
+    pub fn scope(mut self) -> GlobalScope {
+        let structs = core::mem::replace(&mut self.structs, HashMap::new());
+        let unions = core::mem::replace(&mut self.unions, HashMap::new());
+        let typedefs = core::mem::replace(&mut self.typedefs, HashMap::new());
+        return GlobalScope {
+            tu: self.tu(),
+            structs,
+            unions,
+            typedefs,
+        };
+    }
+
+    pub fn func_defn(&self, ident: u32) -> Option<TCFuncDefn> {
+        let tu = &self.globals().0.tu;
+        return tu.functions.get(&ident).and_then(|f| f.defn);
+    }
+
     pub fn symbols(&self) -> &Symbols {
         return &self.globals().0.symbols;
     }
@@ -164,6 +220,14 @@ impl<'a> TypeEnv<'a> {
         return global_env;
     }
 
+    /// Records an error to report once checking is done. Errors from using a
+    /// poisoned struct are dropped.
+    pub fn add_error(&mut self, err: Error) {
+        if err != poisoned_struct_use() {
+            self.globals_mut().errors.push(err);
+        }
+    }
+
     pub fn is_global(&self) -> bool {
         match self.kind {
             TypeEnvKind::Global { .. } => true,
@@ -282,7 +346,10 @@ impl<'a> TypeEnv<'a> {
             return Err(error!(
                 "switch expression's type must be an integer type",
                 expr.loc,
-                format!("expression has type {}", expr.ty.display(self.symbols()))
+                format!(
+                    "expression has type {}",
+                    expr.ty.display_aka(self.symbols())
+                )
             ));
         }
 
@@ -315,6 +382,7 @@ impl<'a> TypeEnv<'a> {
             cases: Vec::new(),
             default: n32::NULL,
             default_loc: NO_FILE,
+            loc: expr.loc,
             parent: self,
             global,
         };
@@ -344,6 +412,21 @@ impl<'a> TypeEnv<'a> {
         Ok((sel, break_label))
     }
 
+    /// Where the innermost `switch` around this scope is, if there is one
+    pub fn enclosing_switch(&self) -> Option<CodeLoc> {
+        let mut c_env: *const TypeEnv = self;
+
+        while !c_env.is_null() {
+            match unsafe { &(*c_env).kind } {
+                TypeEnvKind::Global { .. } => break,
+                TypeEnvKind::LocalSwitch { loc, .. } => return Some(*loc),
+                TypeEnvKind::Local { parent, .. } => c_env = *parent,
+            }
+        }
+
+        return None;
+    }
+
     pub fn default(&mut self, env: &mut FuncEnv, loc: CodeLoc) -> Result<(), Error> {
         let mut c_env: *mut TypeEnv = self;
 
@@ -395,7 +478,10 @@ impl<'a> TypeEnv<'a> {
             return Err(error!(
                 "case expression's type must be an integer type",
                 expr.loc,
-                format!("expression has type {}", expr.ty.display(self.symbols()))
+                format!(
+                    "expression has type {}",
+                    expr.ty.display_aka(self.symbols())
+                )
             ));
         }
 
@@ -424,14 +510,44 @@ impl<'a> TypeEnv<'a> {
                     expr.loc,
                     format!(
                         "case expression (type={}) couldn't be converted to {}",
-                        expr.ty.display(self.symbols()),
-                        ty.display(self.symbols())
+                        expr.ty.display_aka(self.symbols()),
+                        ty.display_aka(self.symbols())
                     )
                 )
             };
             let expr = self
                 .assign_convert(ty, expr, expr.loc)
                 .ok_or_else(or_else)?;
+
+            // Cases are compared after converting to the switch's type, so
+            // e.g. `case 'a':` and `case 97:` are the same case
+            let expr = fold(&*self, &expr);
+            let value = match int_value(&expr.kind) {
+                Some(value) => value,
+                None if is_int_constant(&expr) => {
+                    return Err(error!(
+                        "case value overflows",
+                        expr.loc, "this overflows or divides by zero"
+                    ))
+                }
+                None => {
+                    return Err(error!(
+                        "case value must be a constant",
+                        expr.loc, "this isn't known until the program runs"
+                    ))
+                }
+            };
+
+            let same = cases
+                .iter()
+                .find(|(c, _)| int_value(&c.kind) == Some(value));
+            if let Some((first, _)) = same {
+                return Err(error!(
+                    format!("case value {} is used twice in the same switch", value),
+                    first.loc, "first used here", expr.loc, "used again here"
+                ));
+            }
+
             let label = env.label();
             cases.push((expr, label));
             let op = TCOpcode {
@@ -776,14 +892,25 @@ impl<'a> TypeEnv<'a> {
             LabelOrLoc::Ident(ident) => ident,
             LabelOrLoc::Loc(loc) => {
                 let tc_struct = self.unions.get_mut(&id).unwrap();
-                tc_struct.defn = Some(TCStructDefn { fields, loc });
+                let defn = TCStructDefn {
+                    fields,
+                    loc,
+                    poisoned: false,
+                };
+                tc_struct.defn = Some(defn);
                 tc_struct.sa = sa;
+                self.add_aggregate(n32::NULL, true, sa, defn);
                 return Ok(TCTypeBase::UnnamedUnion { loc, sa });
             }
         };
 
         let loc = self.unions_in_progress.remove(&ident).unwrap();
-        let defn = Some(TCStructDefn { fields, loc });
+        let aggregate = TCStructDefn {
+            fields,
+            loc,
+            poisoned: false,
+        };
+        let defn = Some(aggregate);
         match self.unions.entry(id) {
             Entry::Vacant(v) => {
                 let decl_loc = loc;
@@ -802,9 +929,20 @@ impl<'a> TypeEnv<'a> {
             }
         }
 
+        self.add_aggregate(ident.into(), true, sa, aggregate);
         return Ok(TCTypeBase::NamedUnion { ident, sa });
     }
 
+    fn add_aggregate(&mut self, ident: n32, is_union: bool, sa: SizeAlign, defn: TCStructDefn) {
+        let aggregates = &mut self.globals_mut().tu.aggregates;
+        aggregates.push(TCAggregate {
+            ident,
+            is_union,
+            sa,
+            defn,
+        });
+    }
+
     pub fn check_union_decl(&mut self, ident: u32, decl_loc: CodeLoc) -> TCTypeBase {
         let label = LabelOrLoc::Ident(ident);
         if let Some(sa) = self.search_scopes(|te| te.unions.get(&label).map(|a| a.sa)) {
@@ -843,6 +981,7 @@ impl<'a> TypeEnv<'a> {
         id: LabelOrLoc,
         sa: SizeAlign,
         fields: Vec<TCStructField>,
+        poisoned: bool,
     ) -> Result<TCTypeBase, Error> {
         debug_assert!(sa.size != n32::NULL);
         debug_assert!(sa.align != n32::NULL);
@@ -853,14 +992,25 @@ impl<'a> TypeEnv<'a> {
             LabelOrLoc::Ident(ident) => ident,
             LabelOrLoc::Loc(loc) => {
                 let tc_struct = self.structs.get_mut(&id).unwrap();
-                tc_struct.defn = Some(TCStructDefn { fields, loc });
+                let defn = TCStructDefn {
+                    fields,
+                    loc,
+                    poisoned,
+                };
+                tc_struct.defn = Some(defn);
                 tc_struct.sa = sa;
+                self.add_aggregate(n32::NULL, false, sa, defn);
                 return Ok(TCTypeBase::UnnamedStruct { loc, sa });
             }
         };
 
         let loc = self.structs_in_progress.remove(&ident).unwrap();
-        let defn = Some(TCStructDefn { fields, loc });
+        let aggregate = TCStructDefn {
+            fields,
+            loc,
+            poisoned,
+        };
+        let defn = Some(aggregate);
         match self.structs.entry(id) {
             Entry::Vacant(v) => {
                 let decl_loc = loc;
@@ -879,6 +1029,7 @@ impl<'a> TypeEnv<'a> {
             }
         }
 
+        self.add_aggregate(ident.into(), false, sa, aggregate);
         return Ok(TCTypeBase::NamedStruct { ident, sa });
     }
 
@@ -898,6 +1049,12 @@ impl<'a> TypeEnv<'a> {
         return opt.flatten().map(|d| d.fields);
     }
 
+    /// Whether the struct's definition had a member with an error
+    pub fn struct_poisoned(&self, id: LabelOrLoc) -> bool {
+        let opt = self.search_scopes(|env| env.structs.get(&id).map(|a| a.defn));
+        return opt.flatten().map(|d| d.poisoned).unwrap_or(false);
+    }
+
     pub fn get_union_fields(&self, id: LabelOrLoc) -> Option<&'static [TCStructField]> {
         let opt = self.search_scopes(|env| env.unions.get(&id).map(|a| a.defn));
         return opt.flatten().map(|d| d.fields);
@@ -918,12 +1075,14 @@ impl<'a> TypeEnv<'a> {
     }
 
     pub fn add_param(&mut self, env: &mut FuncEnv, param: &TCParamDecl) -> Result<(), Error> {
+        let TCParamDecl { ty, ident, loc } = *param;
+        self.check_shadow(ident, loc);
+
         let symbols = match &mut self.kind {
             TypeEnvKind::Local { symbols, .. } => symbols,
             _ => unreachable!(),
         };
 
-        let TCParamDecl { ty, ident, loc } = *param;
         let symbol_label = LabelOrLoc::Ident(env.symbol());
 
         let tc_var = TCVar {
@@ -936,9 +1095,34 @@ impl<'a> TypeEnv<'a> {
             return Err(variable_redeclaration(prev.loc, loc));
         }
 
+        env.locals.push((ident, tc_var));
+
+        self.add_ref(ident, TCSymbolScope::Local(loc), ty, loc);
         return Ok(());
     }
 
+    /// Adds an unnamed local to the current scope, for a compound literal. It's
+    /// allocated with the scope's other locals and lives until the scope ends.
+    pub fn add_temp(&mut self, env: &mut FuncEnv, ty: TCType, loc: CodeLoc) -> u32 {
+        let symbols = match &mut self.kind {
+            TypeEnvKind::Local { symbols, .. } => symbols,
+            TypeEnvKind::LocalSwitch { symbols, .. } => symbols,
+            _ => unreachable!(),
+        };
+
+        let label = env.symbol();
+        let tc_var = TCVar {
+            symbol_label: LabelOrLoc::Ident(label),
+            ty,
+            loc,
+        };
+
+        // Temporaries don't have a name, so they get an ident no symbol can have
+        symbols.insert(!label, tc_var);
+        env.locals.push((!label, tc_var));
+        return label;
+    }
+
     pub fn add_var(&mut self, env: Option<&mut FuncEnv>, decl: &TCDecl) -> Result<(), Error> {
         let env = if let Some(env) = env {
             env
@@ -950,7 +1134,12 @@ impl<'a> TypeEnv<'a> {
         let TCDecl { ty, loc, .. } = *decl;
 
         let (symbol_label, init_expr) = match init {
-            TCDeclInit::Extern | TCDeclInit::ExternInit(_) => unimplemented!(),
+            TCDeclInit::Extern | TCDeclInit::ExternInit(_) => {
+                return Err(error!(
+                    "extern declarations inside functions aren't supported",
+                    loc, "declared here; move this to the top level of the file"
+                ));
+            }
             TCDeclInit::Static(init) => {
                 let global_env = self.globals_mut();
                 let global_ident = LabelOrLoc::Loc(loc);
@@ -983,6 +1172,12 @@ impl<'a> TypeEnv<'a> {
             loc,
         };
 
+        if decl.ty.func_parts_strict().is_none() {
+            self.check_shadow(ident, loc);
+        }
+
+        self.add_ref(ident, TCSymbolScope::Local(loc), ty, loc);
+
         let symbols = match &mut self.kind {
             TypeEnvKind::Local { symbols, .. } => symbols,
             TypeEnvKind::LocalSwitch { symbols, .. } => symbols,
@@ -993,6 +1188,7 @@ impl<'a> TypeEnv<'a> {
             Entry::Occupied(o) => o,
             Entry::Vacant(v) => {
                 v.insert(tc_var);
+                env.locals.push((ident, tc_var));
 
                 if let Some((label, init_expr)) = init_expr {
                     let op = TCOpcode::init_local(self, label, init_expr, ty, loc);
@@ -1039,6 +1235,50 @@ impl<'a> TypeEnv<'a> {
         return Ok(());
     }
 
+    /// With `-Wshadow`, warns when a local declared at `loc` hides a variable of
+    /// the same name from an enclosing scope, or a global variable
+    fn check_shadow(&mut self, ident: u32, loc: CodeLoc) {
+        if !self.globals().0.warn_shadow {
+            return;
+        }
+
+        let in_scope = match &self.kind {
+            TypeEnvKind::Local { symbols, .. } => symbols.contains_key(&ident),
+            TypeEnvKind::LocalSwitch { symbols, .. } => symbols.contains_key(&ident),
+            TypeEnvKind::Global(_) => return,
+        };
+
+        // Declaring it again in the same scope is a redeclaration, not shadowing
+        if in_scope {
+            return;
+        }
+
+        let local = self.search_local_scopes(|sel| sel.symbols.get(&ident).map(|v| v.loc));
+        let outer = match local {
+            Some(prev) => Some((prev, "a variable from an outer scope")),
+            None => {
+                let global = self.globals().0.tu.vars.get(&ident);
+                let global = global.filter(|v| v.ty.func_parts_strict().is_none());
+                global.map(|v| (v.loc, "a global variable"))
+            }
+        };
+
+        let (prev, kind) = match outer {
+            Some(outer) => outer,
+            None => return,
+        };
+
+        let name = self.symbols().to_str(ident).unwrap();
+        let warning = error!(
+            format!("declaration of `{}` shadows {}", name, kind),
+            loc,
+            format!("this `{}` hides the other one", name),
+            prev,
+            "the other declaration is here"
+        );
+        self.globals_mut().tu.warnings.push(warning);
+    }
+
     pub fn add_global(global_env: &mut GlobalTypeEnv, decl: &TCDecl) -> Result<(), Error> {
         let global_var = TCGlobalVar {
             init: decl.init,
@@ -1047,6 +1287,12 @@ impl<'a> TypeEnv<'a> {
             var_idx: global_env.tu.var_count,
         };
         global_env.tu.var_count += 1;
+        global_env.tu.refs.push(TCSymbolRef {
+            ident: decl.ident,
+            scope: TCSymbolScope::Global,
+            ty: decl.ty,
+            loc: decl.loc,
+        });
 
         let mut prev = match global_env.tu.vars.entry(decl.ident) {
             Entry::Occupied(o) => o,
@@ -1131,7 +1377,12 @@ impl<'a> TypeEnv<'a> {
         return Ok(());
     }
 
-    pub fn complete_func_defn(&mut self, ident: u32, mut env: FuncEnv) -> Result<(), Error> {
+    pub fn complete_func_defn(
+        &mut self,
+        ident: u32,
+        is_inline: bool,
+        mut env: FuncEnv,
+    ) -> Result<(), Error> {
         let global_env = match &mut self.kind {
             TypeEnvKind::Global(g) => g,
             _ => unreachable!(),
@@ -1177,19 +1428,34 @@ impl<'a> TypeEnv<'a> {
             *goto = *label;
         }
 
+        check_stmt_expr_jumps(&env.ops)?;
+
         func.defn = Some(TCFuncDefn {
             ops: global_env.tu.buckets.add_array(env.ops),
+            locals: global_env.tu.buckets.add_array(env.locals),
             sym_count: env.next_symbol_label,
             label_count: env.next_label,
             param_count,
+            is_inline,
             loc: env.decl_loc,
         });
         return Ok(());
     }
 
-    pub fn ident(&self, ident: u32, loc: CodeLoc) -> Result<TCExpr, Error> {
+    pub fn add_ref(&mut self, ident: u32, scope: TCSymbolScope, ty: TCType, loc: CodeLoc) {
+        let refs = &mut self.globals_mut().tu.refs;
+        refs.push(TCSymbolRef {
+            ident,
+            scope,
+            ty,
+            loc,
+        });
+    }
+
+    pub fn ident(&mut self, ident: u32, loc: CodeLoc) -> Result<TCExpr, Error> {
         // search locals
         if let Some(tc_var) = self.search_local_scopes(|sel| sel.symbols.get(&ident).map(|a| *a)) {
+            self.add_ref(ident, TCSymbolScope::Local(tc_var.loc), tc_var.ty, loc);
             match tc_var.symbol_label {
                 LabelOrLoc::Ident(label) => {
                     return Ok(TCExpr {
@@ -1214,7 +1480,8 @@ impl<'a> TypeEnv<'a> {
 
         // search globals
         let (global_env, _) = self.globals();
-        if let Some(global_var) = global_env.tu.vars.get(&ident) {
+        if let Some(global_var) = global_env.tu.vars.get(&ident).map(|a| *a) {
+            self.add_ref(ident, TCSymbolScope::Global, global_var.ty, loc);
             if global_var.ty.is_function() {
                 return Ok(TCExpr {
                     kind: TCExprKind::FunctionIdent { ident },
@@ -1232,12 +1499,25 @@ impl<'a> TypeEnv<'a> {
             });
         }
 
-        return Err(error!("couldn't find symbol", loc, "symbol used here"));
+        let is_func_name =
+            ident == BuiltinSymbol::Func as u32 || ident == BuiltinSymbol::Function as u32;
+        let current_func = self.globals().0.current_func;
+        if is_func_name && current_func != n32::NULL {
+            let name = self.symbols().to_str(current_func.into()).unwrap();
+            return Ok(TCExpr {
+                kind: TCExprKind::StringLit(self.add_str(name)),
+                ty: TCType::new_ptr(TCTypeBase::I8),
+                loc,
+            });
+        }
+
+        return Err(self.unknown_symbol(ident, loc));
     }
 
-    pub fn assign_ident(&self, ident: u32, loc: CodeLoc) -> Result<TCAssignTarget, Error> {
+    pub fn assign_ident(&mut self, ident: u32, loc: CodeLoc) -> Result<TCAssignTarget, Error> {
         // search locals
         if let Some(tc_var) = self.search_local_scopes(|sel| sel.symbols.get(&ident).map(|a| *a)) {
+            self.add_ref(ident, TCSymbolScope::Local(tc_var.loc), tc_var.ty, loc);
             if tc_var.ty.is_function() {
                 return Err(error!(
                     "can't assign to function type",
@@ -1253,6 +1533,7 @@ impl<'a> TypeEnv<'a> {
                         ty: tc_var.ty,
                         loc,
                         offset: 0,
+                        bitfield: None,
                     });
                 }
                 LabelOrLoc::Loc(label) => {
@@ -1266,6 +1547,7 @@ impl<'a> TypeEnv<'a> {
                         ty: tc_var.ty,
                         loc,
                         offset: 0,
+                        bitfield: None,
                     });
                 }
             }
@@ -1273,7 +1555,8 @@ impl<'a> TypeEnv<'a> {
 
         // search globals
         let (global_env, _) = self.globals();
-        if let Some(tc_var) = global_env.tu.vars.get(&ident) {
+        if let Some(tc_var) = global_env.tu.vars.get(&ident).map(|a| *a) {
+            self.add_ref(ident, TCSymbolScope::Global, tc_var.ty, loc);
             if tc_var.ty.is_function() {
                 return Err(error!(
                     "can't assign to function type",
@@ -1289,21 +1572,99 @@ impl<'a> TypeEnv<'a> {
                 ty: tc_var.ty,
                 loc,
                 offset: 0,
+                bitfield: None,
             });
         }
 
-        return Err(error!("couldn't find symbol", loc, "symbol used here"));
+        return Err(self.unknown_symbol(ident, loc));
+    }
+
+    /// Error for a name that isn't in scope, suggesting the closest visible name
+    /// (locals, globals, functions, or typedefs) if there's one that's close enough.
+    pub fn unknown_symbol(&mut self, ident: u32, loc: CodeLoc) -> Error {
+        self.globals_mut().unknown_uses.push((ident, loc));
+
+        let symbols = self.symbols();
+        let name = symbols.to_str(ident).unwrap_or("");
+        let max_distance = core::cmp::max(1, (name.len() + 2) / 3);
+        let best: Cell<Option<(usize, u32, CodeLoc)>> = Cell::new(None);
+
+        let consider = |id: u32, defn_loc: CodeLoc| {
+            let candidate = match symbols.to_str(id) {
+                Some(c) if id != ident => c,
+                _ => return,
+            };
+
+            let distance = edit_distance(name, candidate);
+            let closer = best.get().map(|(d, _, _)| distance < d).unwrap_or(true);
+            if distance <= max_distance && closer {
+                best.set(Some((distance, id, defn_loc)));
+            }
+        };
+
+        // inner scopes are searched first, so they win ties
+        self.search_local_scopes(|sel| {
+            sel.symbols
+                .iter()
+                .for_each(|(id, var)| consider(*id, var.loc));
+            return None::<()>;
+        });
+        for (id, var) in &self.globals().0.tu.vars {
+            consider(*id, var.loc);
+        }
+        self.search_scopes(|sel| {
+            sel.typedefs
+                .iter()
+                .for_each(|(id, (_, td_loc))| consider(*id, *td_loc));
+            return None::<()>;
+        });
+
+        if let Some((_, id, defn_loc)) = best.get() {
+            let candidate = symbols.to_str(id).unwrap();
+            return error!(
+                "couldn't find symbol",
+                loc,
+                format!("symbol used here; did you mean `{}`?", candidate),
+                defn_loc,
+                format!("`{}` declared here", candidate)
+            );
+        }
+
+        return error!("couldn't find symbol", loc, "symbol used here");
     }
 
     pub fn add_typedef(&mut self, ty: TCType, id: u32, loc: CodeLoc) {
-        self.typedefs.insert(id, (self.add(ty), loc));
+        let ty = self.add(ty);
+        self.typedefs.insert(id, (ty, loc));
+        self.globals_mut().tu.typedefs.insert((id, loc), *ty);
     }
 
+    /// Implicit conversion, like in an assignment or when passing a parameter.
+    /// Integers and pointers don't implicitly convert to each other, except for
+    /// null pointer constants.
     pub fn assign_convert(&self, ty: TCType, expr: TCExpr, loc: CodeLoc) -> Option<TCExpr> {
         if TCType::ty_eq(&ty, &expr.ty) {
             return Some(expr);
         }
 
+        if ty.is_pointer() && expr.ty.is_integer() && !expr.is_null_ptr_constant() {
+            return None;
+        }
+
+        let from_ptr = expr.ty.is_pointer() || expr.ty.is_array() || expr.ty.is_function();
+        if ty.is_integer() && from_ptr {
+            return None;
+        }
+
+        return self.cast_convert(ty, expr, loc);
+    }
+
+    /// Explicit conversion, i.e. a cast
+    pub fn cast_convert(&self, ty: TCType, expr: TCExpr, loc: CodeLoc) -> Option<TCExpr> {
+        if TCType::ty_eq(&ty, &expr.ty) {
+            return Some(expr);
+        }
+
         if ty.is_void() {
             let mut exprs = vec![expr];
             exprs.push(TCExpr {
@@ -1335,9 +1696,18 @@ impl<'a> TypeEnv<'a> {
             (I32Lit(i), F32) => F32Lit(i as f32),
             (I32Lit(i), F64) => F64Lit(i as f64),
             (I32Lit(i), Pointer { .. }) => U64Lit(i as u64),
+            (U32Lit(i), Pointer { .. }) => U64Lit(i as u64),
+            (I64Lit(i), Pointer { .. }) => U64Lit(i as u64),
+            (U64Lit(i), Pointer { .. }) => U64Lit(i),
 
             (kind, to) => {
                 let from = expr.ty.to_prim_type()?;
+                match (from, to) {
+                    (Pointer { .. }, F32) | (Pointer { .. }, F64) => return None,
+                    (F32, Pointer { .. }) | (F64, Pointer { .. }) => return None,
+                    _ => {}
+                }
+
                 let expr = self.add(expr);
 
                 if core::mem::discriminant(&from) == core::mem::discriminant(&to) {
@@ -1371,6 +1741,12 @@ impl<'a> TypeEnv<'a> {
     // pub fn ty_size(&self, ty: &impl TCTy) -> n32 {}
 }
 
+/// Stands in for an error from using a member that was left out of a poisoned
+/// struct; it isn't reported, since the struct's definition already was
+pub fn poisoned_struct_use() -> Error {
+    return error!("uses a member left out of a struct because of an error");
+}
+
 pub fn mismatched_return_types(prev_loc: CodeLoc, decl_loc: CodeLoc) -> Error {
     return error!(
         "mismatched declared return types",
@@ -1398,3 +1774,65 @@ pub fn variable_redeclaration(prev: CodeLoc, new: CodeLoc) -> Error {
         prev, "previous declaration here", new, "new variable of same name declared here"
     );
 }
+
+/// Statement expressions run in the middle of evaluating another expression, so
+/// control can't leave or enter one except by running it from start to finish
+fn check_stmt_expr_jumps(ops: &[TCOpcode]) -> Result<(), Error> {
+    let mut label_pos = HashMap::new();
+    for (idx, op) in ops.iter().enumerate() {
+        if let TCOpcodeKind::Label { label, .. } = op.kind {
+            label_pos.insert(label, idx);
+        }
+    }
+
+    for (begin, op) in ops.iter().enumerate() {
+        let end = match op.kind {
+            TCOpcodeKind::StmtExpr { end } => end as usize,
+            _ => continue,
+        };
+
+        let inside = |idx: usize| begin < idx && idx < end;
+        for (idx, jump) in ops.iter().enumerate() {
+            let mut targets = Vec::new();
+            match jump.kind {
+                TCOpcodeKind::Ret | TCOpcodeKind::RetVal(_) if inside(idx) => {
+                    return Err(error!(
+                        "can't return from inside a statement expression",
+                        jump.loc, "return found here", op.loc, "statement expression here"
+                    ));
+                }
+                TCOpcodeKind::Goto { goto, .. }
+                | TCOpcodeKind::GotoIfZero { goto, .. }
+                | TCOpcodeKind::GotoIfNotZero { goto, .. } => targets.push(goto),
+                TCOpcodeKind::Switch { cases, default, .. } => {
+                    targets.push(default);
+                    targets.extend(cases.iter().map(|&(_, goto)| goto));
+                }
+                _ => continue,
+            }
+
+            for goto in targets {
+                let target = match label_pos.get(&goto) {
+                    Some(&target) => target,
+                    None => continue,
+                };
+
+                if inside(idx) && !inside(target) {
+                    return Err(error!(
+                        "can't jump out of a statement expression",
+                        jump.loc, "jump found here", op.loc, "statement expression here"
+                    ));
+                }
+
+                if !inside(idx) && inside(target) {
+                    return Err(error!(
+                        "can't jump into a statement expression",
+                        jump.loc, "jump found here", ops[target].loc, "jumps to here"
+                    ));
+                }
+            }
+        }
+    }
+
+    return Ok(());
+}
@@ -1,15 +1,22 @@
+use crate::assembler::Instr;
 use crate::filedb::*;
 use crate::runtime::*;
 use crate::util::*;
-use crate::{compile, emit_err};
+use crate::CompileOptions;
+use crate::{assembler, debugger, emit_err, formatter, native, Program};
+use crate::{compile, compile_debug, compile_program, compile_sanitized, compile_with_options};
 use interloc::*;
 use std::fs::{read_dir, read_to_string};
 
 fn test_file_should_succeed(files: &FileDb, output_file: Option<&str>) {
+    test_file_sanitized(files, output_file, assembler::Sanitizers::default());
+}
+
+fn test_file_sanitized(files: &FileDb, output_file: Option<&str>, sanitize: assembler::Sanitizers) {
     let info = before_alloc();
     let mut writer = StringWriter::new();
 
-    let program = match compile(files) {
+    let program = match compile_sanitized(files, sanitize) {
         Ok(program) => program,
         Err(errs) => {
             emit_err(&errs, &files, &mut writer);
@@ -98,8 +105,57 @@ fn test_file_should_succeed(files: &FileDb, output_file: Option<&str>) {
 //     assert_eq!(err.short_name, expected_err);
 // }
 
+/// Microseconds since the Unix epoch, for timing phases and profiling
+fn host_clock() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    return now.as_micros() as u64;
+}
+
+/// `body` framed the way the language server reads and writes messages
+fn lsp_message(body: &str) -> String {
+    return format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+}
+
+/// The files of the test program `name` in `lib/test`, and the output it should
+/// print, if there's a file for that
+fn load_fixture(name: &str) -> (FileDb, Option<String>) {
+    use std::path::Path;
+
+    let file_path = format!("lib/test/{}.c", name);
+    let folder_path = format!("lib/test/{}", name);
+
+    let mut files = FileDb::new();
+    if Path::new(&file_path).exists() {
+        let out_path = format!("lib/test/{}.c.out", name);
+        files
+            .add(&file_path, &read_to_string(&file_path).unwrap())
+            .unwrap();
+        return (files, Some(out_path));
+    }
+
+    let mut out_path = None;
+    for entry in read_dir(Path::new(&folder_path)).unwrap() {
+        let path = entry.unwrap().path();
+        let file_path = path.to_str().unwrap();
+        if file_path.ends_with(".out") {
+            out_path = Some(file_path.to_string());
+            continue;
+        }
+
+        files
+            .add(file_path, &read_to_string(file_path).unwrap())
+            .unwrap();
+    }
+
+    return (files, out_path);
+}
+
 macro_rules! gen_test_should_succeed {
     ( $( $ident:ident ),* ) => {
+        /// Every test program in `lib/test` that should run to completion
+        const FIXTURES: &[&str] = &[$( stringify!($ident) ),*];
+
         $(
             gen_test_should_succeed!(@S, $ident);
         )*
@@ -107,33 +163,8 @@ macro_rules! gen_test_should_succeed {
     (@S, $ident:ident) => {
             #[test]
             fn $ident() {
-                use std::path::Path;
-
-                let file_path = concat!("lib/test/", stringify!($ident), ".c");
-                let folder_path = concat!("lib/test/", stringify!($ident));
-
-                let mut files = FileDb::new();
-                if Path::new(file_path).exists() {
-                    let out_path = concat!("lib/test/", stringify!($ident), ".c.out");
-                    files.add(file_path, &read_to_string(file_path).unwrap()).unwrap();
-                    test_file_should_succeed(&files, Some(out_path));
-                    return;
-                }
-
-                let mut out_path = None;
-                for entry in read_dir(Path::new(folder_path)).unwrap() {
-                    let path = entry.unwrap().path();
-                    let file_path = path.to_str().unwrap();
-                    if file_path.ends_with(".out") {
-                        out_path = Some(path);
-                        continue;
-                    }
-
-                    files.add(file_path, &read_to_string(file_path).unwrap()).unwrap();
-                }
-
-                let out_path_ref = out_path.as_ref().map(|a| a.to_str()).flatten();
-                test_file_should_succeed(&files, out_path_ref);
+                let (files, out_path) = load_fixture(stringify!($ident));
+                test_file_should_succeed(&files, out_path.as_deref());
             }
     };
 
@@ -167,9 +198,5751 @@ gen_test_should_succeed!(
     statics,
     memory,
     files,
-    tree_hashing
+    tree_hashing,
+    predefined_macros,
+    conditionals,
+    anonymous_members,
+    bitfields,
+    bitfield_init,
+    flexible_array,
+    array_init,
+    string_init,
+    null_pointer,
+    pointer_compare,
+    pointer_casts,
+    qualifiers,
+    line_splicing
 );
 
+#[test]
+fn format_source() {
+    let source = r#"#include <stdio.h>
+#define TWICE(x) ((x) * 2)
+
+
+// Adds things up
+struct pair{int a;   /* first */ int *b;};
+int sum(struct pair*p,int n){
+int total=0; // running total
+for(int i=0;i<n;i++){total+=p[i].a*(2+*p[i].b);}
+
+
+  if(total>10)return TWICE(total);
+  else if (!total) return -1;
+  switch(n){case 1:total++;break;default:{total--;}}
+    /* done */
+return total;}
+"#;
+
+    let expected = r#"#include <stdio.h>
+#define TWICE(x) ((x) * 2)
+
+// Adds things up
+struct pair {
+    int a; /* first */
+    int *b;
+};
+int sum(struct pair *p, int n) {
+    int total = 0; // running total
+    for (int i = 0; i < n; i++) {
+        total += p[i].a * (2 + *p[i].b);
+    }
+
+    if(total>10)return TWICE(total);
+    else if (!total) return -1;
+    switch (n) {
+    case 1:
+        total++;
+        break;
+    default: {
+        total--;
+    }
+    }
+    /* done */
+    return total;
+}
+"#;
+
+    let mut files = FileDb::new();
+    let file = files.add("main.c", source).unwrap();
+    let formatted = formatter::format(&files, file).unwrap();
+    assert_eq!(formatted, expected);
+
+    let mut files = FileDb::new();
+    let file = files.add("main.c", &formatted).unwrap();
+    assert_eq!(formatter::format(&files, file).unwrap(), expected);
+
+    // Files that only lex with some flags are formatted with them
+    let source = "#ifndef SIZE\n#error SIZE is required\n#endif\nint  main(){return SIZE;}\n";
+    let mut files = FileDb::new();
+    let file = files.add("main.c", source).unwrap();
+    assert!(formatter::format(&files, file).is_err());
+    let mut options = CompileOptions::default();
+    options.parse_flag("-DSIZE=4").unwrap();
+    let formatted = formatter::format_with(&files, file, &options).unwrap();
+    assert!(formatted.starts_with("#ifndef SIZE\n"), "{}", formatted);
+    assert!(formatted.contains("int main() {"), "{}", formatted);
+}
+
+/// Formatting a test program shouldn't change what it does
+#[test]
+fn format_fixtures() {
+    // `__LINE__` changes when lines get joined or split
+    let fixtures = FIXTURES.iter().filter(|&&name| name != "predefined_macros");
+    for name in fixtures {
+        let (mut files, out_path) = load_fixture(name);
+        for file in files.impls() {
+            if files.is_system(file) {
+                continue;
+            }
+
+            let formatted = formatter::format(&files, file).unwrap();
+            files.replace(file, &formatted).unwrap();
+        }
+
+        test_file_should_succeed(&files, out_path.as_deref());
+    }
+}
+
+#[test]
+fn outline_file() {
+    let source = r#"#include <stdlib.h>
+struct pair { int a; int *b; };
+typedef struct pair Pair;
+static int counter = 0, *last;
+int (*callback)(int);
+int sum(Pair *p, int n);
+
+int sum(Pair *p, int n) {
+    return p->a + n;
+}
+"#;
+
+    let mut files = FileDb::new();
+    let file = files.add("main.c", source).unwrap();
+    let outline = crate::outline::outline(&files, file).unwrap();
+
+    let expected = r#"main.c:2:1: struct struct pair { int a; int *b; }
+main.c:3:1: typedef typedef struct pair Pair
+main.c:4:1: global static int counter
+main.c:4:1: global static int *last
+main.c:5:1: global int (*callback)(int)
+main.c:6:1: prototype int sum(Pair *p, int n)
+main.c:8:1: function int sum(Pair *p, int n)
+"#;
+    assert_eq!(outline.to_text(), expected);
+
+    let json = outline.to_json();
+    assert!(json.starts_with(r#"{"file":"main.c","items":[{"kind":"struct","name":"pair""#));
+    assert!(json.contains(r#"{"kind":"function","name":"sum","signature":"int sum(Pair *p, int n)","line":8,"column":1}"#));
+}
+
+#[test]
+fn semantic_highlighting() {
+    use crate::highlight::*;
+
+    let source = r#"#define LIMIT 10
+typedef struct node { int value; } Node;
+int total; /* running sum */
+int add(Node *n, int by) {
+    int next = n->value + by;
+    total = next * LIMIT;
+    return add(n, 2.5) + 'c';
+}
+"#;
+
+    let mut files = FileDb::new();
+    let file = files.add("main.c", source).unwrap();
+    let highlights = highlight(&files, file).unwrap();
+
+    let mut out = String::new();
+    for h in &highlights {
+        let (start, end) = (h.loc.start as usize, h.loc.end as usize);
+        out += &format!("{} {}\n", h.kind.lsp_name(), &source[start..end]);
+    }
+
+    let expected = r#"macro LIMIT
+keyword typedef
+keyword struct
+type node
+keyword int
+property value
+type Node
+keyword int
+variable total
+comment /* running sum */
+keyword int
+function add
+type Node
+parameter n
+keyword int
+parameter by
+keyword int
+variable next
+parameter n
+property value
+parameter by
+variable total
+variable next
+macro LIMIT
+keyword return
+function add
+parameter n
+number 2.5
+string 'c'
+"#;
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn call_graph() {
+    let source = r#"#include <stdio.h>
+struct ops { int (*run)(int); };
+int twice(int x) { return x * 2; }
+int fact(int n) { return n <= 1 ? 1 : n * fact(n - 1); }
+int is_odd(int n);
+int is_even(int n) { return n == 0 ? 1 : is_odd(n - 1); }
+int is_odd(int n) { return n == 0 ? 0 : is_even(n - 1); }
+int apply(int (*f)(int), int x) { return f(x); }
+int (*global_op)(int) = fact;
+int run(struct ops *ops) { return ops->run(1); }
+int main() {
+    struct ops ops = { twice };
+    printf("%d\n", apply(twice, 3) + global_op(4) + is_even(4) + run(&ops));
+    return 0;
+}
+"#;
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+
+    let mut options = CompileOptions::default();
+    options.parse_flag("--emit=callgraph").unwrap();
+    let dot = match compile_program(&files, &options) {
+        Ok(Program::CallGraph(dot)) => dot,
+        _ => panic!("expected a call graph"),
+    };
+
+    let expected = r#"digraph callgraph {
+    "apply";
+    "fact" [color=red];
+    "is_even" [color=red];
+    "is_odd" [color=red];
+    "main";
+    "run";
+    "twice";
+    "?" [shape=box];
+    "printf" [shape=box];
+    "apply" -> "twice" [style=dashed];
+    "fact" -> "fact" [color=red];
+    "is_even" -> "is_odd" [color=red];
+    "is_odd" -> "is_even" [color=red];
+    "main" -> "apply";
+    "main" -> "fact" [style=dashed];
+    "main" -> "is_even";
+    "main" -> "printf";
+    "main" -> "run";
+    "run" -> "?" [style=dashed];
+}
+"#;
+    assert_eq!(dot, expected);
+
+    options.parse_flag("--emit=bytecode").unwrap();
+    assert_eq!(options.emit, crate::Emit::Program);
+}
+
+#[test]
+fn unused_code() {
+    let header = "#define SCALE 3\nstruct point { int x; };\n";
+    let main = r#"#include <stdio.h>
+#include <signal.h>
+#include <string.h>
+#include "point.h"
+static int square(int x) { return x * x; }
+static int cube(int x) { return square(x) * x; }
+static void on_interrupt(int sig) {}
+int helper(void) { return 1; }
+int main() {
+    signal(SIGINT, on_interrupt);
+    return square(2) - 4;
+}
+"#;
+
+    let mut files = FileDb::new();
+    files.add("point.h", header).unwrap();
+    files.add("main.c", main).unwrap();
+    let warnings = crate::unused::unused(&files).unwrap();
+
+    let mut out = String::new();
+    for warning in &warnings {
+        let loc = files.loc_to_string(warning.sections[0].location);
+        let message = warning.message.split(" (in compiler").next().unwrap();
+        out += &format!("{}: {}; {}\n", loc, message, warning.sections[0].message);
+    }
+
+    let expected = r#"main.c:1: nothing from `stdio.h` is used; remove this `#include`
+main.c:3: nothing from `string.h` is used; remove this `#include`
+main.c:4: nothing from `point.h` is used; remove this `#include`
+main.c:6: function `cube` is never called; remove it, or call it from code that `main` runs
+"#;
+    assert_eq!(out, expected);
+
+    // The command line and the language server show these with the other warnings
+    let all = crate::program_warnings(&files, &CompileOptions::default());
+    assert_eq!(all.len(), 4);
+    assert!(all[3]
+        .message
+        .starts_with("function `cube` is never called"));
+
+    let main = "#include \"point.h\"\nint main() { struct point p = { SCALE }; return p.x - 3; }\n";
+    let mut files = FileDb::new();
+    files.add("point.h", header).unwrap();
+    files.add("main.c", main).unwrap();
+    assert_eq!(crate::unused::unused(&files).unwrap().len(), 0);
+}
+
+#[test]
+fn format_string_warnings() {
+    let main = r#"#include <stdio.h>
+int main() {
+    char name[8] = "tci";
+    long count = 3;
+    float ratio = 0.5f;
+    printf("%s has %ld files, %.2f%% done\n", name, count, ratio);
+    printf("%d\n", name);
+    printf("%s and %*d\n", name, 3);
+    printf("%c %c\n", 'a', 'b', 'c');
+    scanf("%d %lf %[^\n]", &count, &ratio, name);
+    scanf("%*d %ld %7s", &count, name);
+    printf("%y\n", 1);
+    return 0;
+}
+"#;
+
+    let mut files = FileDb::new();
+    files.add("main.c", main).unwrap();
+    let warnings = crate::warnings::warnings(&files).unwrap();
+
+    let mut out = String::new();
+    for warning in &warnings {
+        let loc = files.loc_to_string(warning.sections[0].location);
+        let message = warning.message.split(" (in compiler").next().unwrap();
+        out += &format!("{}: {}; {}\n", loc, message, warning.sections[0].message);
+    }
+
+    let expected = r#"main.c:7: `%d` expects an argument of type `int`, but this one has type `char[8]`; argument here
+main.c:8: `%*d` in the format string has no argument to go with it; `printf` needs another argument
+main.c:9: `printf` was passed more arguments than its format string uses; this argument isn't used
+main.c:10: `%d` expects an argument of type `int*`, but this one has type `long*`; argument here
+main.c:10: `%lf` expects an argument of type `double*`, but this one has type `float*`; argument here
+main.c:12: unknown conversion `%y` in format string; format string here
+"#;
+    assert_eq!(out, expected);
+
+    let warnings = crate::program_warnings(&files, &CompileOptions::default());
+    assert_eq!(warnings.len(), 6);
+    let mut out = String::new();
+    crate::emit_warnings(warnings, &files, &crate::EmitConfig::default(), &mut out);
+    assert!(out.starts_with("warning: `%d` expects an argument of type `int`"));
+    assert_eq!(out.matches("warning: ").count(), 6);
+}
+
+#[test]
+fn condition_warnings() {
+    let main = r#"#define IS_ZERO(x) x == 0
+#define SET(x) x = 1
+int next(void);
+int main() {
+    int x = 0, flags = 6, c;
+    if (x = 5) x++;
+    if ((x = 5)) x++;
+    while ((c = next()) && (x = c)) {}
+    for (int i = 0; x = i; i++) {}
+    if (flags & 4 == 4) x++;
+    if ((flags & 4) == 4) x++;
+    if (flags == 2 | x) x++;
+    int y = 1 << x + 1;
+    int z = flags & IS_ZERO(x);
+    if (SET(x)) x++;
+    return y ? x : z;
+}
+int next(void) { return 0; }
+"#;
+
+    let mut files = FileDb::new();
+    files.add("main.c", main).unwrap();
+    let warnings = crate::warnings::warnings(&files).unwrap();
+
+    let mut out = String::new();
+    for warning in &warnings {
+        let loc = files.loc_to_string(warning.sections[0].location);
+        let message = warning.message.split(" (in compiler").next().unwrap();
+        out += &format!("{}: {}\n", loc, message);
+    }
+
+    let expected = r#"main.c:6: assignment used as a condition
+main.c:9: assignment used as a condition
+main.c:10: `==` is evaluated before `&`
+main.c:12: `==` is evaluated before `|`
+main.c:13: `+` is evaluated before `<<`
+"#;
+    assert_eq!(out, expected);
+
+    let label = &warnings[0].sections[0].message;
+    assert!(label.starts_with("use `==` to compare"));
+}
+
+#[test]
+fn warning_directives() {
+    let main = "#include \"config.h\"\n#warning main is out of date\nint main() { return 0; }\n";
+    let mut files = FileDb::new();
+    files.add("main.c", main).unwrap();
+    files
+        .add("config.h", "#warning   config.h is deprecated\n")
+        .unwrap();
+    let warnings = crate::warnings::warnings(&files).unwrap();
+
+    let mut out = String::new();
+    for warning in &warnings {
+        let loc = files.loc_to_string(warning.sections[0].location);
+        let message = warning.message.split(" (in compiler").next().unwrap();
+        out += &format!("{}: {}; {}\n", loc, message, warning.sections[0].message);
+    }
+
+    let expected = concat!(
+        "main.c:2: main is out of date; #warning directive here\n",
+        "config.h:1: config.h is deprecated; #warning directive here\n"
+    );
+    assert_eq!(out, expected);
+
+    // `tci` and `tci test` print these with `emit_warnings`
+    let warnings = crate::program_warnings(&files, &CompileOptions::default());
+    let mut out = String::new();
+    crate::emit_warnings(warnings, &files, &crate::EmitConfig::default(), &mut out);
+    assert!(out.starts_with("warning: main is out of date"));
+    assert!(out.contains("warning: config.h is deprecated"));
+
+    // The language server publishes them as warnings, not errors
+    use crate::lsp::*;
+    let mut server = LspServer::new();
+    let open = r##"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///main.c","languageId":"c","version":1,"text":"#warning main is out of date\nint main() { return 0; }\n"}}}"##;
+    server.recv(format!("Content-Length: {}\r\n\r\n{}", open.len(), open).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains("main is out of date"));
+    assert!(out.contains(r#""severity":2"#));
+}
+
+#[test]
+fn shadowing_warnings() {
+    let main = r#"int count = 0;
+int sum(int n) {
+    int total = 0;
+    for (int i = 0; i < n; i++) {
+        int n = i * 2;
+        total += n;
+    }
+    {
+        int total = 1;
+        int count = total;
+    }
+    int i = 3;
+    return total + i;
+}
+int get(int count) { return count; }
+int main() { return sum(2) + get(1); }
+"#;
+
+    let mut files = FileDb::new();
+    files.add("main.c", main).unwrap();
+    assert_eq!(crate::warnings::warnings(&files).unwrap().len(), 0);
+
+    let mut options = CompileOptions::default();
+    options.parse_flag("-Wshadow").unwrap();
+    let warnings = crate::warnings::warnings_with(&files, &options.warnings).unwrap();
+
+    let mut out = String::new();
+    for warning in &warnings {
+        let inner = files.loc_to_string(warning.sections[0].location);
+        let outer = files.loc_to_string(warning.sections[1].location);
+        let message = warning.message.split(" (in compiler").next().unwrap();
+        out += &format!("{}: {}; {}\n", inner, message, outer);
+    }
+
+    let expected = r#"main.c:5: declaration of `n` shadows a variable from an outer scope; main.c:2
+main.c:9: declaration of `total` shadows a variable from an outer scope; main.c:3
+main.c:10: declaration of `count` shadows a global variable; main.c:1
+main.c:15: declaration of `count` shadows a global variable; main.c:1
+"#;
+    assert_eq!(out, expected);
+
+    assert_eq!(
+        warnings[0].sections[0].message,
+        "this `n` hides the other one"
+    );
+    assert_eq!(
+        warnings[0].sections[1].message,
+        "the other declaration is here"
+    );
+
+    options.parse_flag("-Wno-shadow").unwrap();
+    assert!(!options.warnings.shadow);
+    options.parse_flag("-Wshadows").unwrap();
+    options.parse_flag("-Wall").unwrap();
+    assert_eq!(
+        options.ignored_flags,
+        vec![
+            "unknown warning `shadows`, expected shadow; ignoring `-Wshadows`".to_string(),
+            "unknown warning `all`, expected shadow; ignoring `-Wall`".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_runner() {
+    use crate::test_runner::*;
+
+    let echo = "#include <stdio.h>\nint main() { int x; scanf(\"%d\", &x); printf(\"%d\\n\", x * 2); return 0; }\n";
+    let entries = [
+        ("double.c", echo),
+        ("double.in", "21\n"),
+        ("double.out", "42\n"),
+        ("wrong.c", echo),
+        ("wrong.in", "1\n"),
+        ("wrong.c.out", "3\n"),
+        ("broken.c", "int main() { return x; }\n"),
+        (
+            "exit.c",
+            "int main() { int x; if (x = 3) return x; return 0; }\n",
+        ),
+        ("spin.c", "int main() { while (1); }\n"),
+        ("notes.txt", "not a test"),
+    ];
+
+    let cases = cases_from_dir("", &entries);
+    let names: Vec<_> = cases.iter().map(|c| &*c.name).collect();
+    assert_eq!(
+        names,
+        ["broken.c", "double.c", "exit.c", "spin.c", "wrong.c"]
+    );
+
+    let limits = Limits {
+        max_ops: 100_000,
+        ..Limits::DEFAULT
+    };
+    let headers = Headers {
+        include_path: "",
+        read: &|_| None,
+    };
+    let summary = run_tests(&cases, &CompileOptions::default(), limits, &headers);
+    assert_eq!((summary.passed(), summary.failed()), (1, 4));
+    assert_eq!(summary.results[1].stdout, "42\n");
+    assert_eq!(summary.results[1].warnings, "");
+    assert!(summary.results[2]
+        .warnings
+        .starts_with("warning: assignment used as a condition"));
+    match &summary.results[0].outcome {
+        TestOutcome::CompileError(errs) => assert!(errs.contains("broken.c")),
+        outcome => panic!("{:?}", outcome),
+    }
+
+    let tap = summary.to_tap();
+    assert!(tap.starts_with("TAP version 13\n1..5\nnot ok 1 - broken.c\n"));
+    assert!(tap.contains("ok 2 - double.c\n"));
+    assert!(tap.contains("not ok 3 - exit.c\n  ---\n  message: \"exited with code 3\"\n  ...\n"));
+    assert!(tap.contains("not ok 4 - spin.c\n  ---\n  message: \"InstructionLimit"));
+    assert!(tap.contains("    expected: \"3\\n\"\n    actual:   \"2\\n\"\n"));
+
+    let junit = summary.to_junit();
+    assert!(junit.contains("<testsuite name=\"tci\" tests=\"5\" failures=\"4\">"));
+    assert!(junit.contains("  <testcase name=\"double.c\"/>\n"));
+    assert!(junit.contains("    <failure message=\"exited with code 3\"/>\n"));
+    assert!(junit.contains("expected: &quot;3\\n&quot;"));
+}
+
+#[test]
+fn test_runner_includes() {
+    use crate::test_runner::*;
+
+    let entries = [
+        (
+            "add.c",
+            "#include \"add.h\"\n#include <vendor.h>\nint main() { return add(1, 2) - THREE; }\n",
+        ),
+        ("add.h", "int add(int a, int b) { return a + b; }\n"),
+        (
+            "missing.c",
+            "#include \"missing.h\"\nint main() { return 0; }\n",
+        ),
+    ];
+    let cases = cases_from_dir("tests/", &entries);
+    assert_eq!(cases[0].path, "tests/add.c");
+
+    let read = |path: &str| match path {
+        "tests/add.h" => Some(entries[1].1.as_bytes().to_vec()),
+        "vendor/vendor.h" => Some(b"#define THREE 3\n".to_vec()),
+        _ => None,
+    };
+    let headers = Headers {
+        include_path: "vendor",
+        read: &read,
+    };
+    let summary = run_tests(
+        &cases,
+        &CompileOptions::default(),
+        Limits::DEFAULT,
+        &headers,
+    );
+    assert_eq!(summary.results[0].outcome, TestOutcome::Pass);
+    match &summary.results[1].outcome {
+        TestOutcome::CompileError(errs) => assert!(errs.contains("missing.h")),
+        outcome => panic!("{:?}", outcome),
+    }
+
+    // Sources are decoded like `tci a.c` does, and ones that can't be fail
+    let bad: [(&str, &[u8]); 1] = [("bad.c", b"int main() { return 0; }\xff\n")];
+    let summary = run_tests(
+        &cases_from_dir("", &bad),
+        &CompileOptions::default(),
+        Limits::DEFAULT,
+        &headers,
+    );
+    match &summary.results[0].outcome {
+        TestOutcome::CompileError(errs) => assert!(errs.contains("bad.c")),
+        outcome => panic!("{:?}", outcome),
+    }
+}
+
+#[test]
+fn symbol_query() {
+    use crate::query::*;
+
+    let source = "int counter = 0;\nint bump(int by) {\n  counter += by;\n  return counter;\n}\nint main() { return bump(1); }\n";
+    let mut files = FileDb::new();
+    let file = files.add("main.c", source).unwrap();
+
+    let offset = source.rfind("counter").unwrap() as u32;
+    let info = symbol_at(&files, file, offset).unwrap().unwrap();
+    assert_eq!(info.name, "counter");
+    assert_eq!(info.ty, "int");
+    assert_eq!(info.defn_loc.start, 4);
+    assert_eq!(info.refs.len(), 3);
+
+    let offset = source.find("by)").unwrap() as u32;
+    let info = symbol_at(&files, file, offset).unwrap().unwrap();
+    assert_eq!(info.name, "by");
+    assert_eq!(info.refs.len(), 2);
+
+    assert!(symbol_at(&files, file, 0).unwrap().is_none());
+}
+
+#[test]
+fn rename_symbol() {
+    use crate::query::*;
+
+    let header = "typedef struct pair { int a; } Pair;\nint sum(Pair *p, int n);\n";
+    let main = "#include \"pair.h\"\nint main() {\n  Pair p = { 1 };\n  int total = sum(&p, 2);\n  return total;\n}\n";
+    let sum =
+        "#include \"pair.h\"\nint sum(Pair *p, int n) { struct pair q = *p; return q.a + n; }\n";
+
+    let mut files = FileDb::new();
+    let header_id = files.add("pair.h", header).unwrap();
+    let main_id = files.add("main.c", main).unwrap();
+    let sum_id = files.add("sum.c", sum).unwrap();
+
+    let apply = |edits: &[TextEdit], file: u32| {
+        let mut text = files.source(file).unwrap().to_string();
+        for edit in edits.iter().rev().filter(|e| e.loc.file == file) {
+            text.replace_range(
+                edit.loc.start as usize..edit.loc.end as usize,
+                &edit.new_text,
+            );
+        }
+        return text;
+    };
+
+    let offset = main.find("sum").unwrap() as u32;
+    let edits = rename(&files, main_id, offset, "add").unwrap();
+    assert_eq!(edits.len(), 3);
+    assert_eq!(apply(&edits, header_id), header.replace("sum", "add"));
+    assert!(apply(&edits, sum_id).starts_with("#include \"pair.h\"\nint add(Pair"));
+
+    let offset = main.find("total").unwrap() as u32;
+    let edits = rename(&files, main_id, offset, "result").unwrap();
+    assert_eq!(apply(&edits, main_id), main.replace("total", "result"));
+
+    let offset = sum.find("n)").unwrap() as u32;
+    let edits = rename(&files, sum_id, offset, "count").unwrap();
+    assert_eq!(edits.len(), 2);
+
+    let offset = header.find("pair").unwrap() as u32;
+    let edits = rename(&files, header_id, offset, "couple").unwrap();
+    assert_eq!(
+        apply(&edits, sum_id),
+        sum.replace("struct pair", "struct couple")
+    );
+
+    let offset = main.find("Pair").unwrap() as u32;
+    let edits = rename(&files, main_id, offset, "Couple").unwrap();
+    assert_eq!(edits.len(), 4);
+    assert_eq!(apply(&edits, sum_id), sum.replace("Pair", "Couple"));
+
+    let errors = rename(&files, main_id, offset, "main").unwrap_err();
+    assert!(errors[0]
+        .message
+        .starts_with("can't rename `Pair` to `main`"));
+    let offset = main.find("total").unwrap() as u32;
+    let errors = rename(&files, main_id, offset, "p").unwrap_err();
+    assert!(errors[0].message.starts_with("can't rename `total` to `p`"));
+    let errors = rename(&files, main_id, offset, "return").unwrap_err();
+    assert!(errors[0].message.starts_with("`return` isn't a valid name"));
+
+    assert!(rename(&files, main_id, 0, "x").unwrap().is_empty());
+
+    // Code inside `#ifdef`s is renamed the way it's compiled
+    let source =
+        "int count = 0;\nint main() {\n#ifdef DEBUG\n  count++;\n#endif\n  return count;\n}\n";
+    let mut files = FileDb::new();
+    let id = files.add("main.c", source).unwrap();
+    let edits = rename(&files, id, 4, "total").unwrap();
+    assert_eq!(edits.len(), 2);
+
+    let mut options = CompileOptions::default();
+    options.parse_flag("-DDEBUG").unwrap();
+    let edits = rename_with(&files, id, 4, "total", &options).unwrap();
+    let starts: Vec<u32> = edits.iter().map(|e| e.loc.start).collect();
+    assert_eq!(starts, [4, 43, 68]);
+}
+
+#[test]
+fn lsp_hover_and_diagnostics() {
+    use crate::lsp::*;
+
+    let mut server = LspServer::new();
+    let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///main.c","languageId":"c","version":1,"text":"int x = 1;\nint main() { return y; }\n"}}}"#;
+    server.recv(lsp_message(open).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains("publishDiagnostics"));
+    assert!(out.contains("couldn't find symbol"));
+
+    let change = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///main.c","version":2},"contentChanges":[{"text":"int x = 1;\nint main() { return x; }\n"}]}}"#;
+    let hover = r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///main.c"},"position":{"line":1,"character":20}}}"#;
+    let full = lsp_message(change) + &lsp_message(hover);
+    let (first, second) = full.as_bytes().split_at(30);
+    server.recv(first);
+    server.recv(second);
+
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains(r#""diagnostics":[]"#));
+    assert!(out.contains("int x"));
+
+    let tokens = r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/semanticTokens/full","params":{"textDocument":{"uri":"file:///main.c"}}}"#;
+    server.recv(lsp_message(tokens).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains(
+        r#""data":[0,0,3,0,0,0,4,1,4,0,0,4,1,8,0,1,0,3,0,0,0,4,4,2,0,0,9,6,0,0,0,7,1,4,0]"#
+    ));
+
+    let rename = r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/rename","params":{"textDocument":{"uri":"file:///main.c"},"position":{"line":0,"character":4},"newName":"count"}}"#;
+    server.recv(lsp_message(rename).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains(r#""newText":"count","range":{"end":{"character":5,"line":0},"start":{"character":4,"line":0}}"#));
+    assert!(out.contains(r#""start":{"character":20,"line":1}"#));
+}
+
+#[test]
+fn lsp_warnings() {
+    use crate::lsp::*;
+
+    let mut server = LspServer::new();
+    let open = r##"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///main.c","languageId":"c","version":1,"text":"#include <stdio.h>\nint main() { printf(\"%d\\n\", \"x\"); return 0; }\n"}}}"##;
+    server.recv(lsp_message(open).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains("`%d` expects an argument of type `int`"));
+    assert!(out.contains(r#""severity":2"#));
+    assert!(!out.contains(r#""severity":1"#));
+}
+
+#[test]
+fn lsp_headers() {
+    use crate::lsp::*;
+
+    // Headers that aren't open come from `read_file`, next to the file that includes them
+    let open = r##"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///src/main.c","languageId":"c","version":1,"text":"#include \"util.h\"\nint main() { return ANSWER - 42; }\n"}}}"##;
+    let mut server = LspServer::new();
+    server.recv(lsp_message(open).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains(r#""severity":1"#));
+
+    let mut server = LspServer::new();
+    server.read_file = Some(|path| match path {
+        "/src/util.h" => Some(b"#define ANSWER 42\n".to_vec()),
+        _ => None,
+    });
+    server.recv(lsp_message(open).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains(r#""diagnostics":[]"#));
+}
+
+#[test]
+fn lsp_utf16_positions() {
+    use crate::lsp::*;
+
+    // `é` is 2 bytes but 1 UTF-16 unit, and `𝄞` is 4 bytes but 2 units
+    let mut server = LspServer::new();
+    let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///main.c","languageId":"c","version":1,"text":"int x; // é𝄞\nint main() { /* é */ return x; }\n"}}}"#;
+    server.recv(lsp_message(open).as_bytes());
+    server.take_output();
+
+    let hover = r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///main.c"},"position":{"line":1,"character":28}}}"#;
+    server.recv(lsp_message(hover).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains("int x"));
+
+    // Columns past the end of the line, even huge ones, are the end of the line
+    let far = hover.replace("28", "4294967295");
+    server.recv(lsp_message(&far).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.ends_with(r#"{"id":1,"jsonrpc":"2.0","result":null}"#));
+
+    let rename = r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/rename","params":{"textDocument":{"uri":"file:///main.c"},"position":{"line":1,"character":28},"newName":"y"}}"#;
+    server.recv(lsp_message(rename).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains(r#""start":{"character":28,"line":1}"#));
+
+    let tokens = r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/semanticTokens/full","params":{"textDocument":{"uri":"file:///main.c"}}}"#;
+    server.recv(lsp_message(tokens).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains(r#""data":[0,0,3,0,0,0,4,1,4,0,0,3,6,"#));
+}
+
+#[test]
+fn lsp_bad_input() {
+    use crate::lsp::*;
+
+    // Headers without a usable length are skipped, and what comes after still works
+    let mut server = LspServer::new();
+    let shutdown = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+    let input = "Content-Type: text\r\n\r\nContent-Length: lots\r\n\r\n".to_string();
+    server.recv((input + &lsp_message(shutdown)).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert_eq!(out.matches("expected a Content-Length header").count(), 2);
+    assert!(out.contains(r#"{"id":1,"jsonrpc":"2.0","result":null}"#));
+
+    server.recv(&[b'x'; 5000]);
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains("expected a Content-Length header"));
+    server.recv(lsp_message(shutdown).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.ends_with(r#"{"id":1,"jsonrpc":"2.0","result":null}"#));
+
+    // Bodies that are too long are skipped as they come in, without overflowing
+    let mut skipping = LspServer::new();
+    let huge = format!("Content-Length: {}\r\n\r\n", usize::MAX);
+    skipping.recv((huge + "{}").as_bytes());
+    let out = String::from_utf8(skipping.take_output()).unwrap();
+    assert!(out.contains("message is too long"));
+    let mut skipping = LspServer::new();
+    skipping.recv(b"Content-Length: 1000000000\r\n\r\n{}");
+    skipping.recv(&[b'x'; 5000]);
+    let out = String::from_utf8(skipping.take_output()).unwrap();
+    assert_eq!(out.matches("message is too long").count(), 1);
+
+    // Both of these are at the path /main.c, so the second one can't be opened
+    let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///main.c","languageId":"c","version":1,"text":"int main() { return 0; }\n"}}}"#;
+    server.recv(lsp_message(open).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains(r#""diagnostics":[]"#));
+
+    let open = open.replace("file:///main.c", "/main.c");
+    server.recv(lsp_message(&open).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains("window/showMessage"));
+    assert!(out.contains("couldn't open /main.c: already exists"));
+    assert!(!out.contains("publishDiagnostics"));
+    assert!(!out.contains(r#""id""#));
+
+    let change = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"/main.c","version":2},"contentChanges":[{"text":"int main() { return y; }\n"}]}}"#;
+    server.recv(lsp_message(change).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert!(out.contains("couldn't open /main.c: already exists"));
+    assert!(!out.contains(r#""id""#));
+
+    let hover = r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{"textDocument":{"uri":"/main.c"},"position":{"line":0,"character":5}}}"#;
+    server.recv(lsp_message(hover).as_bytes());
+    let out = String::from_utf8(server.take_output()).unwrap();
+    assert_eq!(
+        out,
+        lsp_message(r#"{"id":2,"jsonrpc":"2.0","result":null}"#)
+    );
+}
+
+#[test]
+fn incremental_recheck() {
+    use crate::incremental::*;
+
+    let mut files = FileDb::new();
+    let header = files.add("value.h", "int value();\n").unwrap();
+    let main = files.add(
+        "main.c",
+        "#include \"value.h\"\nint main() { return value() - 3; }\n",
+    );
+    let value = files.add("value.c", "int value() { return 3; }\n").unwrap();
+    let main = main.unwrap();
+
+    let mut inc = Incremental::new();
+    assert!(inc.compile(&files).is_ok());
+    assert_eq!(inc.stale(&files).len(), 0);
+
+    files.replace(value, "int value() { return 4; }\n").unwrap();
+    inc.invalidate(value);
+    assert_eq!(inc.stale(&files), vec![value]);
+
+    let program = inc.compile(&files).unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    assert_eq!(runtime.run(&program).unwrap(), 1);
+
+    // Replacing a file frees its previous source instead of growing the database
+    let used = files.buckets.used_bytes();
+    for _ in 0..100 {
+        let source =
+            "#ifndef OFFSET\n#define OFFSET 0\n#endif\nint value() { return 4 + OFFSET; }\n";
+        files.replace(value, source).unwrap();
+    }
+    assert_eq!(files.buckets.used_bytes(), used);
+    inc.invalidate(value);
+
+    // Different flags check every file again with the new ones
+    let mut options = CompileOptions::default();
+    options.parse_flag("-DOFFSET=2").unwrap();
+    let program = inc.compile_with_options(&files, &options).unwrap();
+    assert_eq!(Kernel::new(Vec::new()).run(&program).unwrap(), 3);
+    assert_eq!(inc.stale(&files).len(), 0);
+
+    let program = inc.compile(&files).unwrap();
+    assert_eq!(Kernel::new(Vec::new()).run(&program).unwrap(), 1);
+
+    // Flags that only the assembler or the command line use keep what's cached,
+    // so a file that wasn't invalidated isn't checked again
+    files.replace(value, "int value() { return 5; }\n").unwrap();
+    let mut options = CompileOptions::default();
+    options.parse_flag("--color=never").unwrap();
+    options.parse_flag("--max-ops=100000").unwrap();
+    let program = inc.compile_with_options(&files, &options).unwrap();
+    assert_eq!(Kernel::new(Vec::new()).run(&program).unwrap(), 1);
+    inc.invalidate(value);
+    let program = inc.compile_with_options(&files, &options).unwrap();
+    assert_eq!(Kernel::new(Vec::new()).run(&program).unwrap(), 2);
+
+    files.replace(header, "int value(int a);\n").unwrap();
+    inc.invalidate(header);
+    assert_eq!(inc.stale(&files), vec![main]);
+    assert!(inc.compile(&files).is_err());
+}
+
+#[test]
+fn include_cycle_path() {
+    let mut files = FileDb::new();
+    let a = files.add("a.h", "#include \"b.h\"\n").unwrap();
+    let b = files.add("b.h", "#include \"a.h\"\n").unwrap();
+    let main = files.add("main.c", "#include \"a.h\"\nint main() { return 0; }\n");
+    let main = main.unwrap();
+
+    let errs = match compile(&files) {
+        Ok(_) => panic!("should have failed"),
+        Err(errs) => errs,
+    };
+    assert!(errs[0].message.contains("a.h includes b.h includes a.h"));
+    assert_eq!(errs[0].sections.len(), 2);
+
+    let graph = files.include_graph();
+    assert_eq!(graph.includes(main)[0].to, a);
+    assert_eq!(graph.included_by(a).len(), 2);
+    assert_eq!(graph.dependents(b), vec![a, main]);
+}
+
+#[test]
+fn lex_limits() {
+    use crate::lexer::*;
+
+    let mut files = FileDb::new();
+    for idx in 0..10 {
+        let source = format!("#include \"h{}.h\"\n", idx + 1);
+        files.add(&format!("h{}.h", idx), &source).unwrap();
+    }
+    files.add("h10.h", "int deepest;\n").unwrap();
+    let main = files.add("main.c", "#include \"h0.h\"\n").unwrap();
+
+    let source = concat!(
+        "#define A 1 + 1 + 1 + 1 + 1 + 1 + 1 + 1 + 1 + 1\n",
+        "#define B A + A + A + A + A + A + A + A + A + A\n",
+        "#define C B + B + B + B + B + B + B + B + B + B\n",
+        "int a = C;\n"
+    );
+    let big = files.add("big.h", source).unwrap();
+    let big_main = files.add("big.c", "#include \"big.h\"\n").unwrap();
+
+    let mut lexer = Lexer::new(&files);
+    assert!(lexer.lex(main).is_ok());
+
+    lexer.limits.max_include_depth = 6;
+    let err = match lexer.lex(main) {
+        Ok(_) => panic!("should have failed"),
+        Err(err) => err,
+    };
+    assert_eq!(
+        err.message,
+        "includes are nested more than 6 deep, starting from main.c"
+    );
+    let labels: Vec<_> = err.sections.iter().map(|s| s.message.as_str()).collect();
+    assert_eq!(
+        labels,
+        vec![
+            "main.c includes h0.h here",
+            "h0.h includes h1.h here",
+            "h1.h includes h2.h here",
+            "h2.h includes h3.h here",
+            "h3.h includes h4.h here",
+            "h4.h includes h5.h here",
+        ]
+    );
+
+    let mut lexer = Lexer::new(&files);
+    assert!(lexer.lex(big_main).is_ok());
+
+    lexer.limits.max_tokens = 1000;
+    let err = match lexer.lex(big_main) {
+        Ok(_) => panic!("should have failed"),
+        Err(err) => err,
+    };
+    assert_eq!(
+        err.message,
+        "big.c expands to more than 1000 tokens, after includes and macros"
+    );
+    assert_eq!(err.sections[0].message, "big.c includes big.h here");
+    assert_eq!(
+        err.sections[1].message,
+        "the limit was reached here, in big.h"
+    );
+    assert_eq!(err.sections[1].location.file, big);
+
+    let mut options = CompileOptions::default();
+    options.parse_flag("--max-include-depth=6").unwrap();
+    options.parse_flag("--max-tokens=1000").unwrap();
+    assert_eq!(options.lex_limits.max_include_depth, 6);
+    assert_eq!(options.lex_limits.max_tokens, 1000);
+    assert!(options.parse_flag("--max-tokens=0").is_err());
+
+    options.parse_flag("--max-ops=5000000000").unwrap();
+    options.parse_flag("--max-call-depth=50").unwrap();
+    options.parse_flag("--max-stack=4096").unwrap();
+    options.parse_flag("--max-heap=65536").unwrap();
+    let limits = Limits {
+        max_ops: 5_000_000_000,
+        max_call_depth: 50,
+        max_stack_bytes: 4096,
+        max_heap_bytes: 65536,
+    };
+    assert_eq!(options.limits, limits);
+    assert!(options.parse_flag("--max-ops=-1").is_err());
+    assert!(options.parse_flag("--max-heap=0").is_err());
+    assert!(options.parse_flag("--max-stack=lots").is_err());
+}
+
+#[test]
+fn command_line_defines() {
+    let source = concat!(
+        "#include <string.h>\n",
+        "int main() {\n",
+        "  int total = LEVEL * 10 + (int) strlen(NAME) EMPTY;\n",
+        "#ifdef VERBOSE\n",
+        "  total += 100;\n",
+        "#endif\n",
+        "#ifdef __TCI__\n",
+        "  total += 1000 * (__SIZEOF_POINTER__ == sizeof(void *));\n",
+        "  total += 1000 * (__BYTE_ORDER__ == __ORDER_BIG_ENDIAN__);\n",
+        "#endif\n",
+        "  return total;\n",
+        "}\n"
+    );
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let run = |flags: &[&str]| {
+        let mut options = CompileOptions::default();
+        for flag in flags {
+            options.parse_flag(flag).unwrap();
+        }
+
+        let program =
+            compile_with_options(&files, &options).map_err(|errs| errs[0].message.clone())?;
+        let code = Kernel::new(Vec::new()).run(&program).unwrap();
+        return Ok::<i32, String>(code);
+    };
+
+    let flags = ["-DLEVEL=3", "-DNAME=\"abcd\"", "-DEMPTY=", "-DVERBOSE"];
+    assert_eq!(run(&flags), Ok(1134));
+    assert_eq!(
+        run(&[&flags[..], &["-UVERBOSE", "-U__TCI__"]].concat()),
+        Ok(34)
+    );
+    assert_eq!(run(&[&flags[..], &["-DLEVEL=(2 + 2)"]].concat()), Ok(1144));
+    assert!(run(&["-DNAME=\"a\"", "-DEMPTY="]).is_err());
+
+    let err = run(&["-D2X=1"]).unwrap_err();
+    assert!(err.starts_with("can't define `2X` from the command line; it isn't an identifier"));
+    let mut options = CompileOptions::default();
+    assert!(options.parse_flag("-D").is_err());
+    assert!(options.parse_flag("-U").is_err());
+}
+
+#[test]
+fn include_search_paths() {
+    let mut files = FileDb::new();
+    files.add_include_path("vendor/include/");
+    files.add_include_paths(":project/include");
+    files
+        .add("vendor/include/value.h", "#define VALUE 1\n")
+        .unwrap();
+    files
+        .add("project/include/other.h", "#define OTHER 1\n")
+        .unwrap();
+    let source = "#include <value.h>\n#include \"other.h\"\n#include \"stdio.h\"\nint main() { return VALUE - OTHER; }\n";
+    files.add("src/main.c", source).unwrap();
+
+    test_file_should_succeed(&files, None);
+}
+
+#[test]
+fn include_flag() {
+    let mut files = FileDb::new();
+    files.add_include_path("project/include");
+    files
+        .add("vendor/include/value.h", "#define VALUE 1\n")
+        .unwrap();
+    files
+        .add("project/include/value.h", "#define VALUE 2\n")
+        .unwrap();
+    let source = "#include \"value.h\"\n#include <value.h>\nint main() { return VALUE; }\n";
+    files.add("src/main.c", source).unwrap();
+
+    // `-I` directories come before the ones added to the `FileDb`
+    let mut options = CompileOptions::default();
+    options.parse_flag("-Ivendor/include/").unwrap();
+    let program = compile_with_options(&files, &options).unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    assert_eq!(runtime.run(&program).unwrap(), 1);
+
+    let program = compile(&files).ok().unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    assert_eq!(runtime.run(&program).unwrap(), 2);
+
+    assert!(options.parse_flag("-I").is_err());
+}
+
+#[test]
+fn user_system_headers() {
+    let source = "#include <stdio.h>\n#ifndef USER_STDIO\n#define USER_STDIO 1\n#endif\nint main() { return USER_STDIO; }\n";
+    let mut files = FileDb::new();
+    files.add_include_path("include");
+    files
+        .add("include/stdio.h", "#define USER_STDIO 0\n")
+        .unwrap();
+    files.add("main.c", source).unwrap();
+
+    test_file_should_succeed(&files, None);
+
+    files.system_headers = SystemHeaders::PreferBundled;
+    let program = compile(&files).ok().unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    assert_eq!(runtime.run(&program).unwrap(), 1);
+}
+
+#[test]
+fn include_once() {
+    use crate::lexer::*;
+
+    let guarded = "/* license */\n#ifndef GUARDED_H\n#define GUARDED_H\n#ifdef X\n#endif\nint guarded;\n#endif // GUARDED_H\n";
+    assert_eq!(include_guard(guarded), Some("GUARDED_H"));
+    assert_eq!(
+        include_guard("#ifndef A\n#define A\n#endif\nint a;\n"),
+        None
+    );
+    assert_eq!(include_guard("#ifndef A\n#define B\n#endif\n"), None);
+
+    let mut files = FileDb::new();
+    files.add("guarded.h", guarded).unwrap();
+    files
+        .add("once.h", "#pragma once\n#include \"cycle.h\"\nint once;\n")
+        .unwrap();
+    files.add("cycle.h", "#include \"once.h\"\n").unwrap();
+    let source = "#include \"guarded.h\"\n#include \"guarded.h\"\n#include \"once.h\"\n#include \"once.h\"\nint main() { return 0; }\n";
+    let main = files.add("main.c", source).unwrap();
+
+    let mut lexer = Lexer::new(&files);
+    let (_, tokens) = lexer.lex(main).unwrap();
+    let count = |name: &str| {
+        let id = lexer.symbols.to_symbol[name];
+        tokens.iter().filter(|t| *t == TokenKind::Ident(id)).count()
+    };
+    assert_eq!(count("guarded"), 1);
+    assert_eq!(count("once"), 1);
+
+    test_file_should_succeed(&files, None);
+}
+
+/// A header with an `#else` for its guard does something the second time it's
+/// included, so it can't be skipped
+#[test]
+fn include_guard_with_else() {
+    use crate::lexer::*;
+
+    let header = "#ifndef A_H\n#define A_H\nint a_val = 1;\n#else\nint b_val = 2;\n#endif\n";
+    assert_eq!(include_guard(header), None);
+    let elif = "#ifndef A_H\n#define A_H\n#elif B\n#endif\n";
+    assert_eq!(include_guard(elif), None);
+    let nested = "#ifndef A_H\n#define A_H\n#ifdef B\n#else\n#endif\n#endif\n";
+    assert_eq!(include_guard(nested), Some("A_H"));
+
+    let mut files = FileDb::new();
+    files.add("a.h", header).unwrap();
+    let source = "#include \"a.h\"\n#include \"a.h\"\nint main() { return a_val + b_val; }\n";
+    files.add("main.c", source).unwrap();
+
+    let program = compile(&files).unwrap();
+    assert_eq!(Kernel::new(Vec::new()).run(&program).unwrap(), 3);
+}
+
+#[test]
+fn error_directive() {
+    let source = "#ifdef NOT_DEFINED\n#error should be skipped\n#if defined(ALSO_NOT)\n#endif\n#else\n#warning kept\n#endif\n#error   unsupported platform  \nint main() { return 0; }\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+
+    let errs = match compile(&files) {
+        Ok(_) => panic!("should have failed"),
+        Err(errs) => errs,
+    };
+    assert!(errs[0].message.starts_with("unsupported platform"));
+    assert_eq!(
+        errs[0].sections[0].location.start as usize,
+        source.rfind("#error").unwrap()
+    );
+}
+
+#[test]
+fn error_recovery() {
+    let parse_errors = "int a = ;\nint f() { return 1 +; }\nint b = 2;\nint main() { return b }\n";
+    let mut files = FileDb::new();
+    files.add("main.c", parse_errors).unwrap();
+    let errs = compile(&files).err().unwrap();
+    assert_eq!(errs.len(), 3);
+
+    // Every statement that doesn't parse is reported, even when one of them
+    // never closes its parentheses
+    let bad_statements = "int main() {\n  int a = ;\n  int b = 1 +;\n  foo(;\n  return 0;\n}\nint h() { int y = ; return 0; }\n";
+    let mut files = FileDb::new();
+    let main = files.add("main.c", bad_statements).unwrap();
+    let errs = compile(&files).err().unwrap();
+    let lines: Vec<_> = errs
+        .iter()
+        .map(|e| files.line_index(main, e.sections[0].location.start as usize))
+        .collect();
+    assert_eq!(lines, vec![Some(1), Some(2), Some(3), Some(6)]);
+
+    let type_errors =
+        "int main() {\n  int a = x;\n  a = y;\n  return 0;\n}\nint g() { return z; }\n";
+    let mut files = FileDb::new();
+    files.add("main.c", type_errors).unwrap();
+    let errs = compile(&files).err().unwrap();
+    assert_eq!(errs.len(), 3);
+
+    // The struct is still defined without `y`, and uses of `y` aren't reported
+    let bad_member = "struct p {\n  int x;\n  struct missing y;\n};\n\
+        int len(struct p *p) { return p->x + p->y; }\n\
+        int main() {\n  struct p p = {.y = 1};\n  p.y = 2;\n  return len(&p) + p.x;\n}\n";
+    let mut files = FileDb::new();
+    files.add("main.c", bad_member).unwrap();
+    let errs = compile(&files).err().unwrap();
+    assert_eq!(errs.len(), 1);
+    assert!(errs[0]
+        .message
+        .starts_with("declared struct member of incomplete type"));
+
+    // A variable whose initializer has an error is still declared with its type
+    let bad_init = "int limit = \"ten\";\nint total = 3;\nint p;\n\
+        int main() {\n  int *q = 5;\n  int *r = q;\n  return limit + total + *r;\n}\n";
+    let mut files = FileDb::new();
+    files.add("main.c", bad_init).unwrap();
+    let errs = compile(&files).err().unwrap();
+    assert_eq!(errs.len(), 2);
+    for err in &errs {
+        assert!(err.message.starts_with("incompatible"), "{}", err.message);
+    }
+
+    // Each error is recovered from without going through the rest of the file
+    let mut many_errors = String::new();
+    for idx in 0..2000 {
+        many_errors += &format!(
+            "int a{} = ;\nint f{}() {{ int x = 1\n return x; }}\n",
+            idx, idx
+        );
+    }
+    let mut files = FileDb::new();
+    let main = files.add("main.c", &many_errors).unwrap();
+    let errs = compile(&files).err().unwrap();
+    assert_eq!(errs.len(), 4000);
+    let last = errs.last().unwrap().sections[0].location;
+    assert_eq!(
+        files.line_index(main, last.start as usize),
+        Some(3 * 1999 + 1)
+    );
+}
+
+#[test]
+fn knr_definitions() {
+    use crate::diagnostics;
+
+    let source = "int add(a, b)\nint a;\nint b;\n{\n  return a + b;\n}\nint main() { return 0; }\n";
+    let expected = r#"old-style (K&R) function definitions aren't supported
+  ┌─ main.c:1:8
+  |  
+1 |   int add(a, b)
+  |          ^^^^^^ parameters need their types here, like `int f(int a, char *b)`
+2 | ╭ int a;
+3 | | int b;
+  | ╰------^ move these declarations into the parameter list
+"#;
+    assert_eq!(diagnostics("main.c", source), expected);
+
+    let mut files = FileDb::new();
+    files
+        .add(
+            "main.c",
+            "int id(a) { return a; }\nint main() { return 0; }\n",
+        )
+        .unwrap();
+    let errs = compile(&files).unwrap_err();
+    assert_eq!(errs.len(), 1);
+    assert_eq!(errs[0].sections.len(), 1);
+}
+
+#[test]
+fn missing_semicolon_recovery() {
+    use crate::diagnostics;
+
+    let source = "int main() {\n  int a = 1\n  a = a + 2\n  return a;\n}\n";
+    let expected = r#"expected ';' after expression
+  ┌─ main.c:2:11
+  |
+2 |   int a = 1
+  |           ^ add a ';' after this
+expected ';' after expression
+  ┌─ main.c:3:11
+  |
+3 |   a = a + 2
+  |           ^ add a ';' after this
+"#;
+    assert_eq!(diagnostics("main.c", source), expected);
+
+    let source = "struct s { int a; }\nint f() {\n  if (1) {\n    return 2;\n  }\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let errs = compile(&files).unwrap_err();
+    assert_eq!(errs.len(), 2);
+    assert!(errs[0]
+        .message
+        .starts_with("expected ';' after declaration"));
+    assert!(errs[1].message.starts_with("expected '}' at end of file"));
+    let brace = source.find("{\n  if").unwrap() as u32;
+    assert_eq!(errs[1].sections[0].location.start, brace);
+}
+
+#[test]
+fn gnu_extensions() {
+    use crate::{diagnostics, diagnostics_with};
+
+    let source = r#"
+#define max(a, b) ({ typeof(a) _a = (a); typeof(b) _b = (b); _a > _b ? _a : _b; })
+
+int add(int a, int b) { return a + b; }
+
+int main() {
+  int i = 3;
+  __typeof__(i + 1L) big = 10;
+  typeof(int *) ptr = &i;
+  int total = add(({ int t = *ptr; t * 2; }), max(i * 3, 2));
+  int sum = ({
+    int s = 0;
+    for (int k = 0; k < 4; k++)
+      s += k;
+    s;
+  });
+  ({ i = 7; });
+  return total + sum + i + (int)sizeof(big) + max(1, 2);
+}
+"#;
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let mut options = CompileOptions::default();
+    options.parse_flag("--std=gnu11").unwrap();
+    for &opt_level in &[0, 1] {
+        options.opt_level = opt_level;
+        let program = compile_with_options(&files, &options).unwrap();
+        let status = Kernel::new(Vec::new()).run(&program).unwrap();
+        assert_eq!(status, 15 + 6 + 7 + 8 + 2);
+    }
+
+    let out = diagnostics_with(&files, &CompileOptions::default());
+    assert!(out.starts_with("`typeof` is a GNU extension"), "{}", out);
+
+    let source = "int main() {\n  int a = ({ 2; });\n  return a;\n}\n";
+    let expected = r#"statement expressions are a GNU extension
+  ┌─ main.c:2:12
+  |
+2 |   int a = ({ 2; });
+  |            ^ compile with `--std=gnu11` to use them
+"#;
+    assert_eq!(diagnostics("main.c", source), expected);
+
+    // A `{` after the `(` of a parameter list is just a syntax error
+    let out = diagnostics("main.c", "int g( { }\nint main() { return 0; }\n");
+    assert!(!out.contains("statement expressions"), "{}", out);
+    assert!(out.starts_with("expected set: one of"), "{}", out);
+    assert!(out.contains("RParen"), "{}", out);
+
+    let source = "int x = ({ 1; });\nint main() { return x; }\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let out = diagnostics_with(&files, &options);
+    assert!(out.starts_with("statement expressions can only be used inside a function"));
+
+    let jumps = [
+        (
+            "int f(int x) { return ({ if (x) return 1; 2; }); }",
+            "can't return from",
+        ),
+        (
+            "int f() { while (1) { int y = ({ break; 1; }); } return 0; }",
+            "can't jump out of",
+        ),
+        (
+            "int f() { goto in; return ({ in: 1; 2; }); }",
+            "can't jump into",
+        ),
+    ];
+    for &(source, message) in &jumps {
+        let mut files = FileDb::new();
+        files.add("main.c", source).unwrap();
+        let out = diagnostics_with(&files, &options);
+        assert!(out.starts_with(message), "{}", out);
+    }
+
+    assert!(options.parse_flag("--std=c11").is_ok());
+    assert!(!options.gnu_extensions);
+    assert!(options.parse_flag("--std=c89").is_err());
+}
+
+#[test]
+fn void_pointer_arithmetic() {
+    use crate::diagnostics_with;
+
+    let source = r#"
+int main() {
+  char buf[4] = {1, 2, 3, 4};
+  void *p = buf;
+  void *q = p + 2;
+  p++;
+  q -= 1;
+  return *(char *)q * 10 + *(char *)p + (int)sizeof(void) + (int)(q - p);
+}
+"#;
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let mut options = CompileOptions::default();
+    options.parse_flag("--std=gnu11").unwrap();
+    let program = compile_with_options(&files, &options).unwrap();
+    let status = Kernel::new(Vec::new()).run(&program).unwrap();
+    assert_eq!(status, 20 + 2 + 1 + 0);
+
+    let out = diagnostics_with(&files, &CompileOptions::default());
+    assert!(
+        out.starts_with("can't do arithmetic on a `void*`"),
+        "{}",
+        out
+    );
+    assert!(out.contains("cast this to `char*`, or compile with `--std=gnu11`"));
+
+    let errors = [
+        (
+            "int main() { return sizeof(void); }",
+            "can't take the size of `void`",
+        ),
+        (
+            "void f(); int main() { return sizeof(f()); }",
+            "can't take the size of `void`",
+        ),
+        (
+            "int main() { void *p = 0; p++; return 0; }",
+            "can't do arithmetic on a `void*`",
+        ),
+        (
+            "int main() { void *p = 0; p += 2; return 0; }",
+            "can't do arithmetic on a `void*`",
+        ),
+        (
+            "int main() { void *p = 0, *q = 0; return q - p; }",
+            "can't do arithmetic on a `void*`",
+        ),
+        (
+            "int main() { void *p = 0; *p; return 0; }",
+            "can't dereference a `void*`",
+        ),
+    ];
+    for &(source, message) in &errors {
+        let mut files = FileDb::new();
+        files.add("main.c", source).unwrap();
+        let out = diagnostics_with(&files, &CompileOptions::default());
+        assert!(out.starts_with(message), "{}", out);
+    }
+
+    let mut files = FileDb::new();
+    files
+        .add("main.c", "int main() { void *p = 0; return *p; }")
+        .unwrap();
+    let out = diagnostics_with(&files, &options);
+    assert!(out.starts_with("can't dereference a `void*`"), "{}", out);
+}
+
+#[test]
+fn switch_case_values() {
+    let source = r#"
+int classify(char c) {
+  switch (c) {
+    case 'a': return 1;
+    case 1 << 2: return 2;
+    case sizeof(int) + 1: return 3;
+    default: return 0;
+  }
+}
+int main() { return classify('a') * 100 + classify(4) * 10 + classify(5); }
+"#;
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+    assert_eq!(Kernel::new(Vec::new()).run(&program).unwrap(), 123);
+
+    let source = "int f(int x) {\n  switch (x) {\n    case 'a': return 1;\n    case 90 + 7: return 2;\n  }\n  return 0;\n}\n";
+    let expected = r#"case value 97 is used twice in the same switch
+  ┌─ main.c:3:10
+  |
+3 |     case 'a': return 1;
+  |          ^^^ first used here
+4 |     case 90 + 7: return 2;
+  |          ^^^^^^ used again here
+"#;
+    assert_eq!(crate::diagnostics("main.c", source), expected);
+
+    let source = "int f(int x, int y) { switch (x) { case y: return 1; } return 0; }";
+    let out = crate::diagnostics("main.c", source);
+    assert!(out.starts_with("case value must be a constant"), "{}", out);
+    assert!(out.contains("this isn't known until the program runs"));
+
+    for case in &["1 << 33", "2147483647 + 1", "1 / 0"] {
+        let source = format!(
+            "int f(int x) {{ switch (x) {{ case {}: return 1; }} }}",
+            case
+        );
+        let out = crate::diagnostics("main.c", &source);
+        assert!(out.starts_with("case value overflows"), "{}", out);
+        assert!(out.contains("this overflows or divides by zero"));
+    }
+}
+
+#[test]
+fn break_continue_outside_loop() {
+    let source =
+        "int f(int x) {\n  switch (x) {\n    case 1:\n      continue;\n  }\n  return 0;\n}\n";
+    let expected = r#"continue statement not within a loop
+  ┌─ main.c:4:7
+  |
+2 |   switch (x) {
+  |           ^ this switch isn't inside a loop, and `continue` doesn't apply to it
+3 |     case 1:
+4 |       continue;
+  |       ^^^^^^^^^ continue used here
+"#;
+    assert_eq!(crate::diagnostics("main.c", source), expected);
+
+    let source = "int f(int x) {\n  if (x)\n    break;\n  return 0;\n}\n";
+    let out = crate::diagnostics("main.c", source);
+    assert!(
+        out.starts_with("break statement not within loop or switch"),
+        "{}",
+        out
+    );
+    assert!(out.contains("this function has no loop or switch around it"));
+
+    let source = "int f(int x) {\n  while (x) {\n    switch (x) {\n      case 1: x--; continue;\n      default: break;\n    }\n    break;\n  }\n  return x;\n}\nint main() { return f(3); }\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+    assert_eq!(Kernel::new(Vec::new()).run(&program).unwrap(), 3);
+}
+
+#[test]
+fn did_you_mean() {
+    let source = "int counter;\nint main() {\n  int count = 0;\n  return coutn;\n}\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let errs = compile(&files).err().unwrap();
+    assert_eq!(errs.len(), 1);
+
+    let sections = &errs[0].sections;
+    assert_eq!(sections.len(), 2);
+    assert!(sections[0].message.contains("did you mean `count`?"));
+    let decl = source.find("count =").unwrap() as u32;
+    assert_eq!(sections[1].location.start, decl);
+
+    let source = "int main() {\n  int count = 0;\n  return zebra;\n}\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let errs = compile(&files).err().unwrap();
+    assert_eq!(errs[0].sections.len(), 1);
+}
+
+#[test]
+fn forward_declaration_note() {
+    let source =
+        "int main() {\n  return add(1, 2);\n}\nint add(int a, int b) {\n  return a + b;\n}\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let errs = compile(&files).err().unwrap();
+    assert_eq!(errs.len(), 1);
+
+    let note = errs[0].sections.last().unwrap();
+    assert!(note.message.contains("`int add(int a, int b);`"));
+    assert_eq!(note.location.file, errs[0].sections[0].location.file);
+}
+
+#[test]
+fn forward_declaration_declarators() {
+    let source = concat!(
+        "int a() {\n  return later(0, 0);\n}\n",
+        "int b() {\n  return *later2(\"a\");\n}\n",
+        "int c() {\n  return later3(0);\n}\n",
+        "int later(int a[3], int (*g)(int)) {\n  return 0;\n}\n",
+        "const char *later2(const char *s) {\n  return s;\n}\n",
+        "int later3(int (*m)[4]) {\n  return 0;\n}\n",
+    );
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let errs = compile(&files).err().unwrap();
+    let notes: Vec<_> = errs
+        .iter()
+        .map(|err| err.sections.last().unwrap().message.as_str())
+        .collect();
+
+    let expected = [
+        "`int later(int a[3], int (*g)(int));`",
+        "`const char *later2(const char *s);`",
+        "`int later3(int (*m)[4]);`",
+    ];
+    for proto in expected.iter() {
+        assert!(notes.iter().any(|note| note.contains(proto)), "{:?}", notes);
+    }
+}
+
+#[test]
+fn emit_dedup_and_limit() {
+    use crate::util::term::ColorChoice;
+    use crate::{emit_err_with, EmitConfig};
+
+    let mut files = FileDb::new();
+    files.add("bad.h", "int f() { return y; }\n").unwrap();
+    files.add("a.c", "#include \"bad.h\"\n").unwrap();
+    files
+        .add("b.c", "#include \"bad.h\"\nint main() { return 0; }\n")
+        .unwrap();
+    let errs = compile(&files).err().unwrap();
+    assert_eq!(errs.len(), 2);
+
+    let mut writer = StringWriter::new();
+    emit_err_with(&errs, &files, &EmitConfig::default(), &mut writer);
+    let out = writer.into_string();
+    assert_eq!(out.matches("couldn't find symbol").count(), 1);
+    assert!(!out.contains("\x1b["));
+
+    let source = "int main() {\n  a = 1;\n  b = 2;\n  c = 3;\n  return 0;\n}\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let errs = compile(&files).err().unwrap();
+    let config = EmitConfig {
+        color: ColorChoice::from_flag("always").unwrap(),
+        is_terminal: false,
+        max_errors: 2,
+    };
+
+    let mut writer = StringWriter::new();
+    emit_err_with(&errs, &files, &config, &mut writer);
+    let out = writer.into_string();
+    assert_eq!(out.matches("couldn't find symbol").count(), 2);
+    assert!(out.contains("too many errors; 1 more not shown"));
+    assert!(out.contains("\x1b["));
+
+    let mut options = CompileOptions::default();
+    let mut writer = StringWriter::new();
+    emit_err_with(&errs, &files, &EmitConfig::new(&options, true), &mut writer);
+    assert!(writer.into_string().contains("\x1b["));
+
+    options.parse_flag("--color=never").unwrap();
+    let mut writer = StringWriter::new();
+    emit_err_with(&errs, &files, &EmitConfig::new(&options, true), &mut writer);
+    assert!(!writer.into_string().contains("\x1b["));
+    assert!(options.parse_flag("--color=sometimes").is_err());
+}
+
+#[test]
+fn diagnostics_snapshot() {
+    use crate::diagnostics;
+
+    assert_eq!(diagnostics("ok.c", "int main() { return 0; }\n"), "");
+
+    let source = "int main() {\n  return coutn;\n}\n";
+    let expected = r#"couldn't find symbol
+  ┌─ main.c:2:10
+  |
+2 |   return coutn;
+  |          ^^^^^ symbol used here
+"#;
+    assert_eq!(diagnostics("main.c", source), expected);
+}
+
+#[test]
+fn source_encoding() {
+    let mut files = FileDb::new();
+    let bom = b"\xEF\xBB\xBFint main() { return 3; }\n";
+    let id = files.add_bytes("bom.c", bom).unwrap();
+    assert_eq!(files.source(id).unwrap(), "int main() { return 3; }\n");
+    let program = compile(&files).unwrap();
+    assert_eq!(Kernel::new(Vec::new()).run(&program).unwrap(), 3);
+
+    let mut files = FileDb::new();
+    let id = files.add("bom.c", "\u{FEFF}int x;\n").unwrap();
+    assert_eq!(files.source(id).unwrap(), "int x;\n");
+
+    let latin1 = b"int main() {\n  // caf\xE9\n  return 0;\n}\n";
+    let err = files.add_bytes("latin1.c", latin1).unwrap_err();
+    assert!(err
+        .message
+        .starts_with("`latin1.c` isn't valid UTF-8: bad byte 0xE9 at offset 21 (line 2)"));
+    assert_eq!(files.name(files.files.len() as u32 - 1), Some("bom.c"));
+
+    let utf16 = b"\xFF\xFEi\x00n\x00t\x00";
+    let err = files.add_bytes("wide.c", utf16).unwrap_err();
+    assert!(err
+        .message
+        .starts_with("`wide.c` is UTF-16; save it as UTF-8"));
+
+    let err = files.add_bytes("bom.c", b"int y;\n").unwrap_err();
+    assert!(err
+        .message
+        .starts_with("couldn't add `bom.c`: already exists"));
+
+    // Locations are u32 byte offsets, which would wrap around in bigger files
+    assert!(check_file_size(MAX_FILE_SIZE).is_ok());
+    let err = check_file_size(MAX_FILE_SIZE + 1).unwrap_err();
+    assert_eq!(err, "too large; files can be at most 4 GiB");
+}
+
+#[test]
+fn display_columns() {
+    use crate::diagnostics;
+
+    let source = "int main() {\n\tint x; /* 漢字 é */ return nope;\n}\n";
+    let mut files = FileDb::new();
+    let id = files.add("tabs.c", source).unwrap();
+    let nope = source.find("nope").unwrap();
+    let location = files.location(id, nope).unwrap();
+    assert_eq!((location.line_number, location.column_number), (2, 36));
+
+    files.tab_width = 4;
+    assert_eq!(files.location(id, nope).unwrap().column_number, 32);
+
+    let expected = "couldn't find symbol\n  ┌─ tabs.c:2:36\n  |\n\
+                    2 |         int x; /* 漢字 é */ return nope;\n  \
+                    |                                    ^^^^ symbol used here\n";
+    assert_eq!(diagnostics("tabs.c", source), expected);
+}
+
+#[test]
+fn macro_expansion_table() {
+    use crate::lexer::*;
+
+    let source = "#define SQ(x) ((x) * (x))\n#define AREA(r) (3 * SQ(r))\n\
+                  int main() { return AREA(2); }\n";
+    let mut files = FileDb::new();
+    let id = files.add("main.c", source).unwrap();
+    Lexer::new(&files).lex(id).unwrap();
+    Lexer::new(&files).lex(id).unwrap();
+
+    let table = files.expansions.borrow().clone();
+    let names: Vec<_> = table.expansions.iter().map(|e| &*e.name).collect();
+    assert_eq!(names, vec!["AREA", "SQ"]);
+
+    let use_start = source.find("AREA(2)").unwrap() as u32;
+    let site = l(use_start, use_start + "AREA(2)".len() as u32, id);
+    assert_eq!(table.expansions[0].site, site);
+    assert_eq!(table.expansions[1].site, site);
+    assert_eq!(table.expansions[1].parent, Some(0));
+    assert_eq!(
+        table.containing(l(use_start + 5, use_start + 6, id)),
+        Some(0)
+    );
+    assert_eq!(table.containing(l(0, 3, id)), None);
+
+    let messages: Vec<_> = table.sections(1).into_iter().map(|s| s.message).collect();
+    let expected = vec![
+        "in expansion of macro `SQ` defined here",
+        "in expansion of macro `AREA` defined here",
+        "macro `AREA` used here",
+    ];
+    assert_eq!(messages, expected);
+
+    files.replace(id, "int main() { return 0; }\n").unwrap();
+    assert!(files.expansions.borrow().expansions.is_empty());
+}
+
+#[test]
+fn macro_backtrace() {
+    use crate::diagnostics;
+    use crate::lexer::*;
+
+    let source = "#define SQ(x) ((x) * nope)\n#define AREA(r) (3 * SQ(r))\n\
+                  int main() { return AREA(2); }\n";
+    let expected = "couldn't find symbol\n  ┌─ sq.c:3:21\n  |\n\
+                    1 | #define SQ(x) ((x) * nope)\n  \
+                    | ^^^^^^^^^^^^^^^^^^^^^^^^^^ in expansion of macro `SQ` defined here\n\
+                    2 | #define AREA(r) (3 * SQ(r))\n  \
+                    | ^^^^^^^^^^^^^^^^^^^^^^^^^^^ in expansion of macro `AREA` defined here\n\
+                    3 | int main() { return AREA(2); }\n  \
+                    |                     ^^^^^^^ symbol used here\n";
+    assert_eq!(diagnostics("sq.c", source), expected);
+
+    let mut files = FileDb::new();
+    let id = files.add("sq.c", source).unwrap();
+    let (_, tokens) = Lexer::new(&files).lex(id).unwrap();
+    let provenance: Vec<_> = (0..tokens.len()).map(|i| tokens.expansion(i)).collect();
+    let kinds: Vec<_> = tokens.iter().collect();
+    let three = kinds.iter().position(|k| *k == num_char(b'3')).unwrap();
+    let is_ident = |k: &TokenKind| matches!(k, TokenKind::Ident(_));
+    let nope = kinds.iter().rposition(is_ident).unwrap();
+    assert_eq!(provenance[0], None);
+    assert_eq!(provenance[three], Some(0));
+    assert_eq!(provenance[nope], Some(1));
+    assert_eq!(provenance[kinds.len() - 1], None);
+}
+
+#[test]
+fn typedef_aka() {
+    use crate::diagnostics;
+
+    let source = "typedef unsigned long my_size;\ntypedef struct point { int x; } Point;\n\
+                  int main() {\n  Point p;\n  my_size n = p;\n  return 0;\n}\n";
+    let expected = [
+        "couldn't convert value to target type",
+        "  ┌─ aka.c:5:11",
+        "  |",
+        "5 |   my_size n = p;",
+        "  |           ^   ^ value found here (type is Point (aka struct point))",
+        "  |           |    ",
+        "  |           target type found here (type is my_size (aka unsigned long))",
+        "",
+    ];
+    assert_eq!(diagnostics("aka.c", source), expected.join("\n"));
+
+    // Types without typedefs don't repeat themselves
+    let source = "int main() {\n  struct s { int x; } v;\n  long n = v;\n  return 0;\n}\n";
+    let out = diagnostics("plain.c", source);
+    assert!(out.contains("(type is struct s)"), "{}", out);
+    assert!(out.contains("(type is long)"), "{}", out);
+}
+
+#[test]
+fn struct_layout() {
+    let source = r#"
+#include <stdio.h>
+
+struct pair {
+    char tag;
+    long value;
+    short small;
+};
+
+typedef struct {
+    int x;
+    char name[5];
+} Point;
+
+union number {
+    char c;
+    double d;
+};
+
+struct buffer {
+    int len;
+    char data[];
+};
+
+int main() {
+    return 0;
+}
+"#;
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+
+    let mut options = CompileOptions::default();
+    options.parse_flag("--emit=layout").unwrap();
+    let text = match compile_program(&files, &options) {
+        Ok(Program::Layout(text)) => text,
+        _ => panic!("expected struct layouts"),
+    };
+
+    let expected = r#"struct pair (size 24, align 8, 13 bytes of padding)
+  offset  size  align  member
+       0     1      1  char tag
+       1     7         (padding)
+       8     8      8  long value
+      16     2      2  short small
+      18     6         (padding)
+
+Point (size 12, align 4, 3 bytes of padding)
+  offset  size  align  member
+       0     4      4  int x
+       4     5      1  char[5] name
+       9     3         (padding)
+
+union number (size 8, align 8, 0 bytes of padding)
+  offset  size  align  member
+       0     1      1  char c
+       0     8      8  double d
+
+struct buffer (size 4, align 4, 0 bytes of padding)
+  offset  size  align  member
+       0     4      4  int len
+       4     0      1  char[] data
+"#;
+    assert_eq!(text, expected);
+}
+
+#[test]
+fn frame_sizes() {
+    let source = r#"int leaf(char c) {
+    return c;
+}
+
+int middle(int a, int b) {
+    int sum = a + b;
+    {
+        long wide = sum;
+        return leaf(wide);
+    }
+}
+
+int main() {
+    char buf[10];
+    return middle(1, 2);
+}
+"#;
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+
+    let mut options = CompileOptions::default();
+    options.parse_flag("--emit=frames").unwrap();
+    let text = match compile_program(&files, &options) {
+        Ok(Program::Frames(text)) => text,
+        _ => panic!("expected frame sizes"),
+    };
+
+    let expected = r#"function  bytes  slots
+leaf          5      3
+main         14      3
+middle       24      6
+
+deepest call chain: main -> middle -> leaf
+uses 43 of 8192 bytes of stack, and 12 of 4000 stack variables
+"#;
+    assert_eq!(text, expected);
+
+    let source = "int fact(int n) { return n <= 1 ? 1 : n * fact(n - 1); }\nint main() { return fact(5); }\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let text = match compile_program(&files, &options) {
+        Ok(Program::Frames(text)) => text,
+        _ => panic!("expected frame sizes"),
+    };
+
+    assert!(text.ends_with("\nunbounded recursion: main -> fact -> fact -> ...\neach time around uses 8 bytes and 3 stack variables; about 999 times fit before running out of nested calls\n"));
+}
+
+#[test]
+fn bitfield_errors() {
+    use crate::diagnostics;
+
+    let cases = [
+        (
+            "struct s { float f : 3; };",
+            "bitfield has non-integer type",
+        ),
+        (
+            "struct s { char c : 9; };",
+            "bitfield is wider than its type",
+        ),
+        ("struct s { int i : 0; };", "named bitfield has zero width"),
+        (
+            "struct s { int i : 3; };\nint main() { struct s v; int *p = &v.i; return 0; }",
+            "cannot take the address of a bitfield",
+        ),
+    ];
+
+    for (source, message) in cases.iter() {
+        let source = format!("{}\nint f() {{ return 0; }}\n", source);
+        let out = diagnostics("bits.c", &source);
+        assert!(out.starts_with(message), "{}", out);
+    }
+
+    let source = "struct flags {\n  char c;\n  unsigned ready : 1;\n  unsigned mode : 3;\n};\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+
+    let mut options = CompileOptions::default();
+    options.parse_flag("--emit=layout").unwrap();
+    let text = match compile_program(&files, &options) {
+        Ok(Program::Layout(text)) => text,
+        _ => panic!("expected struct layouts"),
+    };
+
+    let expected = r#"struct flags (size 4, align 4, 2 bytes of padding)
+  offset  size  align  member
+       0     1      1  char c
+       1     1      4  unsigned int ready : 1 (from bit 0)
+       1     1      4  unsigned int mode : 3 (from bit 1)
+       2     2         (padding)
+"#;
+    assert_eq!(text, expected);
+}
+
+#[test]
+fn initializer_errors() {
+    use crate::diagnostics;
+
+    let source = "int main() {\n  int b[2][2] = {1, 2, 3, 4};\n  return 0;\n}\n";
+    let expected = r#"array element needs its own initializer list
+  ┌─ main.c:2:18
+  |
+2 |   int b[2][2] = {1, 2, 3, 4};
+  |                  ^ expected an initializer list for int[2]
+"#;
+    assert_eq!(diagnostics("main.c", source), expected);
+
+    let cases = [
+        (
+            "char s[2] = \"abc\";",
+            "string literal is too long for the array",
+        ),
+        (
+            "int s[] = \"abc\";",
+            "can only initialize arrays of characters with a string literal",
+        ),
+    ];
+
+    for (source, message) in cases.iter() {
+        let source = format!("{}\nint f() {{ return 0; }}\n", source);
+        let out = diagnostics("init.c", &source);
+        assert!(out.starts_with(message), "{}", out);
+    }
+}
+
+#[test]
+fn compound_literals() {
+    use crate::diagnostics;
+
+    let source = r#"
+struct point { int x, y; };
+struct rect { struct point min; char tag; struct point max; };
+
+int dot(struct point a, struct point b) { return a.x * b.x + a.y * b.y; }
+int sum(int *xs, int n) { int s = 0; for (int i = 0; i < n; i++) s += xs[i]; return s; }
+
+int main() {
+  int total = dot((struct point){ .x = 1, .y = 2 }, (struct point){3, 4});
+  total += (struct point){ .y = 5 }.y;
+  struct point *p = &(struct point){ .y = 6, .x = 7 };
+  p->x += 1;
+  total += p->x + p->y;
+  total += sum((int[]){1, 2, 3}, 3);
+  for (int i = 0; i < 3; i++) {
+    struct point q = (struct point){ .y = i * 10 };
+    total += q.y;
+  }
+
+  struct rect r = { .max = { .x = 9 }, .tag = 'a' };
+  return total + r.max.x + r.tag - 'a';
+}
+"#;
+
+    let mut files = FileDb::new();
+    let file = files.add("main.c", source).unwrap();
+    let mut options = CompileOptions::default();
+    for &opt_level in &[0, 1] {
+        options.opt_level = opt_level;
+        let program = compile_with_options(&files, &options).unwrap();
+        let status = Kernel::new(Vec::new()).run(&program).unwrap();
+        assert_eq!(status, 11 + 5 + 14 + 6 + 30 + 9);
+    }
+
+    // The temporaries are locals of the function that uses them
+    let (_, debug) = compile_debug(&files).unwrap();
+    let layouts = debug.frame_layouts();
+    let main = layouts.iter().find(|l| l.name == "main").unwrap();
+    let temps = main
+        .locals
+        .iter()
+        .filter(|s| s.name == "(compound literal)");
+    let temps: Vec<_> = temps.map(|s| &*s.ty).collect();
+    assert_eq!(temps.len(), 6);
+    assert!(temps.contains(&"int[3]"));
+
+    let formatted = formatter::format(&files, file).unwrap();
+    assert!(formatted.contains("&(struct point){.y = 6, .x = 7};"));
+
+    let cases = [
+        (
+            "int a[2] = { .x = 1 };",
+            "can only name fields when initializing a struct",
+        ),
+        (
+            "struct point p = { .z = 1 };",
+            "tried to access field that doesn't exist",
+        ),
+        (
+            "struct point p = { .x = 1, .y = 2, .x = 3 };",
+            "field is initialized twice",
+        ),
+        (
+            "struct point *p = &(struct point){ 1, 2 };",
+            "compound literals can only be used inside a function",
+        ),
+    ];
+
+    for (source, message) in cases.iter() {
+        let source = format!(
+            "struct point {{ int x, y; }};\n{}\nint main() {{ return 0; }}\n",
+            source
+        );
+        let out = diagnostics("init.c", &source);
+        assert!(out.starts_with(message), "{}", out);
+    }
+}
+
+#[test]
+fn pointer_comparison_errors() {
+    use crate::diagnostics;
+
+    let source = "int f(int *a, char *c) {\n  return a == c;\n}\n";
+    let expected = r#"comparison of distinct pointer types
+  ┌─ main.c:2:10
+  |
+2 |   return a == c;
+  |          ^    ^ this has the type `char*`
+"#;
+    let out = diagnostics("main.c", source);
+    assert!(out.starts_with(expected), "{}", out);
+    assert!(out.contains("this has the type `int*`"), "{}", out);
+
+    let cases = [
+        (
+            "int *a, b; return a < b;",
+            "ordered comparison between pointer and integer",
+        ),
+        (
+            "int *a; return 1 != a;",
+            "comparison between pointer and integer",
+        ),
+        (
+            "int *a; void *v; return a >= v;",
+            "comparison of distinct pointer types",
+        ),
+        (
+            "int *a; return a < 0;",
+            "ordered comparison between pointer and integer",
+        ),
+        (
+            "struct s { int x; } s; int *a; return a == s;",
+            "invalid operands",
+        ),
+    ];
+
+    for (body, message) in cases.iter() {
+        let source = format!("int f() {{ {} }}\n", body);
+        let out = diagnostics("cmp.c", &source);
+        assert!(out.starts_with(message), "{}", out);
+    }
+}
+
+#[test]
+fn int_pointer_conversion_errors() {
+    use crate::diagnostics;
+
+    let source = "int main() {\n  int x = 1;\n  int *p = x;\n  return 0;\n}\n";
+    let expected = r#"incompatible integer to pointer conversion; did you mean to take the address?
+  ┌─ main.c:3:12
+  |
+3 |   int *p = x;
+  |            ^ value has the type `int`, but `int*` was expected
+"#;
+    assert_eq!(diagnostics("main.c", source), expected);
+
+    let to_ptr = "incompatible integer to pointer conversion";
+    let to_int = "incompatible pointer to integer conversion";
+    let cases = [
+        ("int *p; p = 5; return 0;", to_ptr),
+        ("int *p; int x = p; return x;", to_int),
+        ("int a[2]; long x; x = a; return 0;", to_int),
+        ("int *p; int x = 0; x += p; return x;", to_int),
+        ("int *p; p += p; return 0;", "invalid operands"),
+        ("g(1); return 0;", to_ptr),
+    ];
+
+    for (body, message) in cases.iter() {
+        let source = format!("void g(int *p);\nint f() {{ {} }}\n", body);
+        let out = diagnostics("conv.c", &source);
+        assert!(out.starts_with(message), "{}", out);
+    }
+
+    let source = "int *f(long x) { return x; }\n";
+    assert!(diagnostics("ret.c", source).starts_with(to_ptr));
+}
+
+#[test]
+fn extern_across_files() {
+    use crate::diagnostics_with;
+
+    let mut files = FileDb::new();
+    let main = "extern int count;\nextern int table[3];\nint bump(int by);\n\
+                int main() { bump(2); return count + table[2]; }\n";
+    files.add("main.c", main).unwrap();
+    let other = "int count = 1;\nint table[3] = {1, 2, 3};\n\
+                 int bump(int by) { count += by; return count; }\n";
+    files.add("other.c", other).unwrap();
+
+    let program = compile(&files).unwrap();
+    assert_eq!(Kernel::new(Vec::new()).run(&program).unwrap(), 6);
+
+    let mut files = FileDb::new();
+    let main = "extern int count;\nint bump(void);\n\
+                int main() {\n  count = 2;\n  bump();\n  return count + bump();\n}\n";
+    files.add("main.c", main).unwrap();
+    let other = "extern int count;\nint f() { return count; }\n";
+    files.add("other.c", other).unwrap();
+
+    let out = diagnostics_with(&files, &CompileOptions::default());
+    let var = out.find("couldn't find definition for variable").unwrap();
+    let func = out.find("couldn't find definition for function").unwrap();
+    assert_eq!(out[var..func].matches("used here").count(), 3, "{}", out);
+    assert!(out[var..func].contains("other.c:2:18"), "{}", out);
+    assert_eq!(out[func..].matches("called here").count(), 2, "{}", out);
+}
+
+#[test]
+fn inline_functions_in_headers() {
+    let mut files = FileDb::new();
+    let header = "static inline int square(int x) { return x * x; }\n\
+                  inline int cube(int x) { return x * x * x; }\n\
+                  int other();\n";
+    files.add("math.h", header).unwrap();
+    let main = "#include \"math.h\"\nint main() { return square(3) + cube(2) + other(); }\n";
+    files.add("main.c", main).unwrap();
+    let other = "#include \"math.h\"\nint other() { return square(2) - cube(1); }\n";
+    files.add("other.c", other).unwrap();
+
+    let program = compile(&files).unwrap();
+    assert_eq!(Kernel::new(Vec::new()).run(&program).unwrap(), 20);
+
+    let mut files = FileDb::new();
+    files
+        .add("math.h", "int cube(int x) { return x * x * x; }\n")
+        .unwrap();
+    let main = "#include \"math.h\"\nint main() { return cube(2); }\n";
+    files.add("main.c", main).unwrap();
+    files.add("other.c", "#include \"math.h\"\n").unwrap();
+
+    let errs = compile(&files).unwrap_err();
+    assert!(errs[0].message.starts_with("redefinition of function"));
+}
+
+#[test]
+fn missing_main() {
+    let errors = |source: &str| {
+        let mut files = FileDb::new();
+        files.add("main.c", source).unwrap();
+        let errs = compile(&files).unwrap_err();
+        let labels = errs[0].sections.iter().map(|s| s.message.clone()).collect();
+        return (errs[0].message.clone(), labels);
+    };
+
+    let (message, labels): (String, Vec<String>) =
+        errors("int helper() { return 1; }\nint mian() { return helper(); }\n");
+    assert!(message
+        .starts_with("missing main function definition; the program defines `helper`, `mian`"));
+    assert_eq!(labels, vec!["`mian` is defined here; did you mean `main`?"]);
+
+    let (message, labels) = errors("static int main() { return 0; }\n");
+    assert!(message.starts_with("missing main function definition; the program defines `main`"));
+    assert_eq!(
+        labels,
+        vec!["`main` is static here, so the program can't start at it"]
+    );
+
+    let (_, labels) = errors("int main();\nint max(int a) { return a; }\n");
+    assert_eq!(labels, vec!["`main` is declared here, but never defined"]);
+
+    let (message, labels) = errors("struct Main { int x; };\n");
+    assert!(message
+        .starts_with("missing main function definition; the program doesn't define any functions"));
+    assert_eq!(labels, Vec::<String>::new());
+}
+
+#[test]
+fn duplicate_symbols_name_files() {
+    let mut files = FileDb::new();
+    files
+        .add("shared.h", "int twice(int x) { return 2 * x; }\n")
+        .unwrap();
+    files
+        .add(
+            "a.c",
+            "#include \"shared.h\"\nint main() { return twice(1); }\n",
+        )
+        .unwrap();
+    files.add("b.c", "#include \"shared.h\"\n").unwrap();
+    let errs = compile(&files).unwrap_err();
+    assert!(errs[0]
+        .message
+        .starts_with("redefinition of function; both a.c and b.c define it"));
+    let labels: Vec<_> = errs[0]
+        .sections
+        .iter()
+        .map(|s| s.message.as_str())
+        .collect();
+    assert_eq!(
+        labels,
+        vec![
+            "original definition here, in a.c",
+            "second definition here, in b.c"
+        ]
+    );
+
+    let mut files = FileDb::new();
+    files.add("shared.h", "int total = 1;\n").unwrap();
+    files
+        .add(
+            "a.c",
+            "#include \"shared.h\"\nint main() { return total; }\n",
+        )
+        .unwrap();
+    files.add("b.c", "#include \"shared.h\"\n").unwrap();
+    let errs = compile(&files).unwrap_err();
+    assert!(errs[0]
+        .message
+        .starts_with("defined global variable twice; both a.c and b.c define it"));
+
+    let mut files = FileDb::new();
+    files
+        .add(
+            "a.c",
+            "long twice(long x);\nint main() { return twice(1); }\n",
+        )
+        .unwrap();
+    files
+        .add("b.c", "int twice(int x) { return 2 * x; }\n")
+        .unwrap();
+    let errs = compile(&files).unwrap_err();
+    assert!(errs[0].message.starts_with(
+        "function declaration type doesn't match previous declaration; a.c and b.c disagree"
+    ));
+}
+
+#[test]
+fn flexible_array_members() {
+    use crate::diagnostics;
+
+    let cases = [
+        (
+            "struct s { char data[]; };",
+            "flexible array member in a struct with no other members",
+        ),
+        (
+            "struct s { char data[]; int len; };",
+            "flexible array member has to be the last member of the struct",
+        ),
+        (
+            "struct s { int len; char data[]; };\nint g(struct s *p) { return sizeof(p->data); }",
+            "can't take the size of a flexible array member",
+        ),
+    ];
+
+    for (source, message) in cases.iter() {
+        let source = format!("{}\nint f() {{ return 0; }}\n", source);
+        let out = diagnostics("flex.c", &source);
+        assert!(out.starts_with(message), "{}", out);
+    }
+
+    // Indexing is checked against the allocation, not the struct's size
+    let source = r#"
+#include <stdlib.h>
+struct s { int len; char data[]; };
+int main() {
+    struct s *p = malloc(sizeof(struct s) + 2);
+    p->data[1] = 1;
+    p->data[2] = 2;
+    return 0;
+}
+"#;
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    let err = runtime.run(&program).unwrap_err();
+    assert_eq!(err.short_name, "InvalidPointer");
+    assert!(
+        err.message.contains("the nearest object"),
+        "{}",
+        err.message
+    );
+}
+
+#[test]
+fn string_literal_pool() {
+    let source = r#"
+int main() {
+    char *a = "hello";
+    char *b = "hello";
+    char *c = "world";
+    if (a[4] != b[4] || c[0] != 'w')
+        return 1;
+    a[0] = 'j';
+    return 0;
+}
+"#;
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    // Every literal is stored once, in a read-only variable
+    assert!(program.strings.contains_key("hello"));
+    assert!(program.strings.contains_key("world"));
+    let readonly = program.vars.iter().filter(|var| var.meta).count();
+    assert_eq!(readonly, program.strings.len());
+
+    let mut runtime = Kernel::new(Vec::new());
+    let err = runtime.run(&program).unwrap_err();
+    assert_eq!(err.short_name, "ReadOnlyWrite");
+    assert!(err.message.contains("string literal"), "{}", err.message);
+}
+
+#[test]
+fn null_pointer_dereference() {
+    let cases = [
+        ("int *p = 0; return *p;", "null pointer dereference"),
+        (
+            "int *p = NULL; *p = 1; return 0;",
+            "null pointer dereference",
+        ),
+        (
+            "struct s { int a, b; } *p = NULL; return p->b;",
+            "null pointer dereference (at offset 4 from NULL)",
+        ),
+        (
+            "char *s = NULL; return strlen(s);",
+            "null pointer dereference",
+        ),
+    ];
+
+    for (body, message) in cases.iter() {
+        let source = format!(
+            "#include <stddef.h>\n#include <string.h>\nint main() {{ {} }}\n",
+            body
+        );
+        let mut files = FileDb::new();
+        files.add("main.c", &source).unwrap();
+        let program = compile(&files).unwrap();
+
+        let mut runtime = Kernel::new(Vec::new());
+        let err = runtime.run(&program).unwrap_err();
+        assert_eq!(err.short_name, "NullPointer", "{}", body);
+        assert_eq!(err.message, *message);
+    }
+}
+
+#[test]
+fn fuzz_compile_never_panics() {
+    use crate::fuzz_compile;
+
+    let errors = [
+        "int main() { return sizeof(struct Missing); }\n",
+        "int main() { void *p = 0; return *(int *)(p - 1); }\n",
+        "int main() { float f = 1; return ~f; }\n",
+        "int main() { float f = 1; return (int)(f & 1); }\n",
+        "int main() { float f = 1; f |= 1; return 0; }\n",
+        "int main() { int *p = 0; p *= 2; return 0; }\n",
+        "int main() { int *p = 0; return -p != 0; }\n",
+        "int main() { int *p = 0; return (double)p; }\n",
+        "int main() { extern int x; return x; }\n",
+        "int main() { char *s = \"\\777\"; return 0; }\n",
+        "int main() { char *s = \"\\377\"; return 0; }\n",
+        "#include <stdio.h>\nint main() { printf*(\"x\", 1); return 0; }\n",
+        "int square(int x) { return x * x; }\nint main() { int (f)(int) = square; return 0; }\n",
+        "struct poly { int n; int pts[]; };\nint main() { struct poly p = {1, {2}}; return 0; }\n",
+    ];
+
+    for source in &errors {
+        assert!(fuzz_compile(source.as_bytes()).is_err(), "{}", source);
+    }
+
+    let ok = [
+        "int main() { char c = 1; short s = 1; c--; s--; --c; --s; return c + s; }\n",
+        "int main() { float f = 1; double d = 1; f++; f--; ++d; --d; return !f || d; }\n",
+        "int twice(int x) { return x * 2; }\nint (*op)(int) = twice;\nint main() { return op(1); }\n",
+        "int main() { char *s = \"\\303\\251\"; return s[0] == 0; }\n",
+    ];
+
+    for source in &ok {
+        assert!(fuzz_compile(source.as_bytes()).is_ok(), "{}", source);
+    }
+
+    assert!(fuzz_compile(b"int main() { return 1 \xff\xfe; }\n").is_err());
+    assert!(fuzz_compile(b"int main() { char *s = \"\xff\"; return s[0]; }\n").is_err());
+    assert!(fuzz_compile(b"\x00\x80#include <\xc3").is_err());
+
+    let mut files = FileDb::new();
+    let source = "int twice(int x) { return x * 2; }\nint (*op)(int) = twice;\n\
+                  int main() { float f = 0.5; f++; return op(3) + (f > 1.0 && f < 2.0); }\n";
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+    assert_eq!(Kernel::new(Vec::new()).run(&program).unwrap(), 7);
+}
+
+#[test]
+fn phase_timings() {
+    use crate::compile_timed;
+    use crate::timings::*;
+
+    let mut files = FileDb::new();
+    let source = "#include <stdio.h>\nint main() { printf(\"hi\\n\"); return 0; }\n";
+    files.add("main.c", source).unwrap();
+
+    let mut timings = Timings::new(host_clock);
+    let program = compile_timed(&files, &CompileOptions::default(), &mut timings).unwrap();
+    let code = timings.time("interpret", || Kernel::new(Vec::new()).run(&program));
+    assert_eq!(code.unwrap(), 0);
+
+    let names: Vec<_> = timings.phases.iter().map(|p| p.name).collect();
+    assert_eq!(
+        names,
+        ["lex", "parse", "typecheck", "assemble", "interpret"]
+    );
+    assert!(timings.phases[0].bytes > 0);
+    assert!(timings.table().contains("total"));
+
+    let mut disabled = Timings::disabled();
+    compile_timed(&files, &CompileOptions::default(), &mut disabled).unwrap();
+    assert_eq!(disabled.phases.len(), 0);
+}
+
+#[test]
+fn interner() {
+    use crate::interner::*;
+
+    let mut symbols = Symbols::new();
+    assert_eq!(
+        symbols.from_str("main"),
+        (BuiltinSymbol::Main as u32).into()
+    );
+
+    let int = symbols.add_str("int");
+    assert_eq!(symbols.keyword(int).map(|k| KEYWORDS[k]), Some("int"));
+
+    let count = symbols.add_str("count");
+    assert_eq!(symbols.add_str("count"), count);
+    assert_eq!(symbols.to_str(count), Some("count"));
+    assert_eq!(symbols.keyword(count), None);
+    assert_eq!(symbols.to_str(symbols.len() as u32), None);
+}
+
+#[test]
+fn token_buffer() {
+    use crate::lexer::*;
+
+    let mut files = FileDb::new();
+    files.add("a.h", "int a = 'x';\n").unwrap();
+    let source = "#include \"a.h\"\nchar *s = \"hi\";\nint main() { return 0x1F; }\n";
+    let main = files.add("main.c", source).unwrap();
+
+    let mut lexer = Lexer::new(&files);
+    let (_, tokens) = lexer.lex(main).unwrap();
+    let kinds: Vec<_> = tokens
+        .iter()
+        .filter(|t| *t != TokenKind::Whitespace)
+        .collect();
+    assert_eq!(kinds[0], TokenKind::Int);
+    assert_eq!(kinds[3], TokenKind::CharLit(b'x' as i8));
+    assert!(kinds.contains(&TokenKind::IntChar(NumChar::_X)));
+    assert!(kinds.contains(&TokenKind::IntChar(NumChar::_F)));
+    match kinds[9] {
+        TokenKind::StringLit(s) => assert_eq!(s.as_str(), "hi"),
+        tok => panic!("expected a string, found {:?}", tok),
+    }
+
+    let first = tokens.loc(0);
+    let last = (0..tokens.len())
+        .rev()
+        .find(|&i| tokens.kind(i) != TokenKind::Whitespace);
+    let last = tokens.loc(last.unwrap());
+    assert_ne!(first.file, main);
+    assert_eq!(last.file, main);
+    assert_eq!(&source[(last.start as usize)..(last.end as usize)], "}");
+}
+
+#[test]
+fn character_literals() {
+    use crate::diagnostics;
+
+    let source = "int main() {\n  int ok = 'ab' == 0x6162 && 'abcd' == 0x61626364;\n  \
+                  return ok + sizeof('ab') + ('\\377' == -1) + sizeof('a');\n}\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+    assert_eq!(
+        Kernel::new(Vec::new()).run(&program).unwrap(),
+        1 + 4 + 1 + 1
+    );
+
+    let source = "int main() {\n  return L'x' + 1;\n}\n";
+    let expected = r#"wide character literals aren't supported
+  ┌─ main.c:2:10
+  |
+2 |   return L'x' + 1;
+  |          ^^^^ remove the `L` prefix to make this a plain character literal
+"#;
+    assert_eq!(diagnostics("main.c", source), expected);
+
+    let out = diagnostics("main.c", "char *s = u\"a\\\"b\";\n");
+    assert!(
+        out.starts_with("wide string literals aren't supported"),
+        "{}",
+        out
+    );
+    assert!(out.contains("^^^^^^^ remove the `u` prefix"), "{}", out);
+
+    let out = diagnostics("main.c", "int c = 'abcde';\n");
+    assert!(out.starts_with("character literal is too long"), "{}", out);
+}
+
+#[test]
+fn line_splicing_locations() {
+    let source =
+        "int main() {\n  int x = 1; \\\r\n  x = x +\\\n\\\n 2;\n  return unknown_\\\nname;\n}\n";
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let errs = compile(&files).unwrap_err();
+    assert_eq!(errs.len(), 1);
+
+    let loc = errs[0].sections[0].location;
+    assert_eq!(loc.start as usize, source.find("unknown_").unwrap());
+    assert_eq!(loc.end as usize, source.find("name;").unwrap() + 4);
+}
+
+#[test]
+fn profile_functions() {
+    let mut files = FileDb::new();
+    let source = "#include <stdio.h>\nint square(int x) { return x * x; }\nint main() {\n  int sum = 0;\n  for (int i = 0; i < 100; i++)\n    sum += square(i);\n  printf(\"%d\\n\", sum);\n  return 0;\n}\n";
+    let main = files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.enable_profiling(host_clock);
+    assert_eq!(runtime.run(&program).unwrap(), 0);
+
+    let profile = runtime.profile.as_ref().unwrap();
+    let funcs = profile.sorted_funcs();
+    assert!(funcs.windows(2).all(|w| w[0].ops >= w[1].ops));
+    assert!(funcs.iter().any(|f| f.loc.file == main && f.ops > 100));
+    assert!(profile.ecalls.iter().any(|e| e.name == "append_fd"));
+
+    let report = profile.report(&files);
+    assert!(report.contains("int square(int x) (main.c:2)"));
+    assert!(report.contains("int main() (main.c:3)"));
+
+    // Profiling still counts while history and coverage are being recorded
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.enable_profiling(host_clock);
+    runtime.enable_coverage();
+    let proc_id = runtime.load_term_program(&program);
+    runtime.enable_history(proc_id, 1000);
+    for _ in 0..1000 {
+        runtime.run_op_count(1).unwrap();
+    }
+
+    let profile = runtime.profile.as_ref().unwrap();
+    assert_eq!(profile.funcs.values().map(|f| f.ops).sum::<u64>(), 1000);
+    let hits = &runtime.coverage.as_ref().unwrap().hits;
+    assert!(hits.keys().any(|loc| loc.file == main));
+    assert_eq!(runtime.step_back(proc_id, 5).unwrap(), 5);
+}
+
+#[test]
+fn execution_limits() {
+    fn run_limited(source: &str, limits: Limits) -> Result<i32, IError> {
+        let mut files = FileDb::new();
+        files.add("main.c", source).unwrap();
+        let program = compile(&files).unwrap();
+
+        let mut runtime = Kernel::new(Vec::new());
+        runtime.limits = limits;
+        return runtime.run(&program);
+    }
+
+    let spin = "int main() { while (1); return 0; }\n";
+    let limits = Limits {
+        max_ops: 10_000,
+        ..Limits::DEFAULT
+    };
+    let err = run_limited(spin, limits).unwrap_err();
+    assert_eq!(err.short_name, "InstructionLimit");
+
+    // Native code counts its instructions too
+    let mut files = FileDb::new();
+    files.add("main.c", spin).unwrap();
+    let mut options = CompileOptions::default();
+    options.parse_flag("--backend=native").unwrap();
+    let mut kernel = Kernel::new(Vec::new());
+    kernel.limits = limits;
+    let result = compile_program(&files, &options)
+        .unwrap()
+        .run_captured_in(&mut kernel);
+    assert_eq!(result.exit_code.unwrap_err().short_name, "InstructionLimit");
+    assert_eq!(result.instructions_executed, limits.max_ops);
+
+    let recurse = "int f(int n) { return n == 0 ? 0 : f(n - 1) + 1; }\nint main() { return f(100) != 100; }\n";
+    assert_eq!(run_limited(recurse, Limits::DEFAULT).unwrap(), 0);
+    let limits = Limits {
+        max_call_depth: 50,
+        ..Limits::DEFAULT
+    };
+    let err = run_limited(recurse, limits).unwrap_err();
+    assert_eq!(err.short_name, "StackOverflow");
+
+    let locals = "int f(int n) { char buf[100]; return n == 0 ? 0 : f(n - 1); }\nint main() { return f(100); }\n";
+    assert_eq!(
+        run_limited(locals, Limits::DEFAULT).unwrap_err().short_name,
+        "StackOverflow"
+    );
+    let limits = Limits {
+        max_stack_bytes: 1024 * 64,
+        ..Limits::DEFAULT
+    };
+    assert_eq!(run_limited(locals, limits).unwrap(), 0);
+
+    let alloc = "#include <stdlib.h>\nint main() { char *p = malloc(4096); return p == 0; }\n";
+    assert_eq!(run_limited(alloc, Limits::DEFAULT).unwrap(), 0);
+    let limits = Limits {
+        max_heap_bytes: 1024,
+        ..Limits::DEFAULT
+    };
+    let err = run_limited(alloc, limits).unwrap_err();
+    assert_eq!(err.short_name, "HeapTooLarge");
+
+    // Deadlines are in wall-clock time, even on the virtual clock. They're checked
+    // between time slices, and don't end the program.
+    static NOW: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let clock = || NOW.load(std::sync::atomic::Ordering::SeqCst);
+    let mut files = FileDb::new();
+    files.add("main.c", spin).unwrap();
+    let program = compile(&files).unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.deadline = Some(Deadline::after(clock, 10_000));
+    runtime.load_term_program(&program);
+    runtime.run_op_count(100_000).unwrap();
+    NOW.store(10_000, std::sync::atomic::Ordering::SeqCst);
+    let err = runtime.run_op_count(100_000).unwrap_err();
+    assert_eq!(err.short_name, "DeadlineExceeded");
+    assert_eq!(runtime.total_ops, 100_000);
+    runtime.deadline = None;
+    runtime.run_op_count(100).unwrap();
+    assert_eq!(runtime.active_count, 1);
+}
+
+#[test]
+fn separate_output_streams() {
+    let mut files = FileDb::new();
+    let source = "#include <stdio.h>\nint main() {\n  printf(\"out 1\\n\");\n  fprintf(stderr, \"err %d\\n\", 2);\n  printf(\"out 3\\n\");\n  return 0;\n}\n";
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    assert_eq!(runtime.run(&program).unwrap(), 0);
+    let mut tags = Vec::new();
+    for TE(tag, _) in &runtime.output {
+        tags.push(match tag {
+            WriteEvt::StdoutWrite => "stdout",
+            WriteEvt::StderrWrite => "stderr",
+            _ => "other",
+        });
+    }
+    assert_eq!(tags, vec!["stdout", "stderr", "stdout"]);
+
+    let (stdout, stderr) = runtime.term_streams();
+    assert_eq!(stdout, "out 1\nout 3\n");
+    assert_eq!(stderr, "err 2\n");
+
+    // Runtime errors are reported on stderr too
+    let mut files = FileDb::new();
+    let source = "#include <stdio.h>\nint main() {\n  printf(\"before\\n\");\n  int *p = 0;\n  return *p;\n}\n";
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let err = runtime.run(&program).unwrap_err();
+    let (stdout, stderr) = runtime.term_streams();
+    assert_eq!(stdout, "before\n");
+    assert_eq!(stderr, format!("{}: {}\n", err.short_name, err.message));
+}
+
+#[test]
+fn captured_runs() {
+    let build = |source: &str| {
+        let mut files = FileDb::new();
+        files.add("main.c", source).unwrap();
+        return compile_program(&files, &CompileOptions::default()).unwrap();
+    };
+
+    let program = build("#include <stdio.h>\nint main() {\n  printf(\"hi\\n\");\n  fprintf(stderr, \"oops\\n\");\n  return 3;\n}\n");
+    let result = program.run_captured();
+    assert_eq!(result.exit_code.unwrap(), 3);
+    assert_eq!(result.stdout, "hi\n");
+    assert_eq!(result.stderr, "oops\n");
+    assert!(result.instructions_executed > 0);
+    assert!(result.wall_time > 0);
+
+    let program = build("int main() { int *p = 0; return *p; }\n");
+    let result = program.run_captured();
+    let err = result.exit_code.unwrap_err();
+    assert_eq!(result.stdout, "");
+    assert_eq!(
+        result.stderr,
+        format!("{}: {}\n", err.short_name, err.message)
+    );
+
+    let mut files = FileDb::new();
+    files.add("main.c", "int main() { return 0; }\n").unwrap();
+    let mut options = CompileOptions::default();
+    options.parse_flag("--emit=layout").unwrap();
+    let result = compile_program(&files, &options).unwrap().run_captured();
+    assert_eq!(result.exit_code.unwrap_err().short_name, "NotRunnable");
+    assert_eq!(result.instructions_executed, 0);
+}
+
+#[test]
+fn float_exponent_literals() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "int main() {\n",
+        "  if (1e9 != 1000000000.0) return 1;\n",
+        "  if (2.5e-1 != 0.25) return 2;\n",
+        "  if (1.5e3f != 1500.0f) return 3;\n",
+        "  if (4e-2f != 0.04f) return 4;\n",
+        "  return 0;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+
+    let program = compile_program(&files, &CompileOptions::default()).unwrap();
+    assert_eq!(program.run_captured().exit_code.unwrap(), 0);
+}
+
+#[test]
+fn float_arithmetic() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "int main() {\n",
+        "  float a = 1.5f, b = 4.0f;\n",
+        "  double c = 2.5, d = 0.5;\n",
+        "  if (a * b != 6.0f) return 1;\n",
+        "  if (b / a * 3.0f != 8.0f) return 2;\n",
+        "  if (c * d != 1.25) return 3;\n",
+        "  if (c / d != 5.0) return 4;\n",
+        "  return 0;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+
+    let program = compile_program(&files, &CompileOptions::default()).unwrap();
+    assert_eq!(program.run_captured().exit_code.unwrap(), 0);
+}
+
+#[test]
+fn entry_points() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdio.h>\n",
+        "long fib(int n) { return n < 2 ? n : fib(n - 1) + fib(n - 2); }\n",
+        "static double scale(double x, unsigned char by) { return x * by; }\n",
+        "char *name(void) { return \"tci\"; }\n",
+        "float eighths(void) { return 3.75e-1f; }\n",
+        "void hello(short times) { for (int i = 0; i < times; i++) printf(\"hi\\n\"); }\n",
+        "struct Pair { int a, b; };\n",
+        "struct Pair pair(void) { struct Pair p = {1, 2}; return p; }\n",
+        "int main() { return 1; }\n"
+    );
+    files.add("main.c", source).unwrap();
+
+    let run = |flags: &[&str]| {
+        let mut options = CompileOptions::default();
+        for flag in flags {
+            options.parse_flag(flag).unwrap();
+        }
+
+        return compile_program(&files, &options).map(|program| program.run_captured());
+    };
+
+    let result = run(&["--entry=fib", "--args=10"]).unwrap();
+    assert_eq!(
+        (result.exit_code.unwrap(), result.stdout.as_str()),
+        (0, "55\n")
+    );
+    let result = run(&["--entry=scale", "--args=1.25, 4"]).unwrap();
+    assert_eq!(result.stdout, "5.000000\n");
+    assert_eq!(run(&["--entry=eighths"]).unwrap().stdout, "0.375000\n");
+    let result = run(&["--entry=hello", "--args=2", "-O1"]).unwrap();
+    assert_eq!(result.stdout, "hi\nhi\n");
+    assert_eq!(run(&["--entry=name"]).unwrap().stdout.trim().len(), 16); // the pointer in hex
+    assert_eq!(run(&[]).unwrap().exit_code.unwrap(), 1);
+
+    let err = |flags: &[&str]| run(flags).unwrap_err()[0].message.clone();
+    assert!(err(&["--entry=missing"]).starts_with("no function named `missing`"));
+    assert!(err(&["--entry=fib"]).starts_with("`fib` takes 1 arguments, but was given 0"));
+    assert!(err(&["--entry=fib", "--args=ten"]).starts_with("can't pass `ten` to `fib`"));
+    assert!(err(&["--entry=pair"]).starts_with("can't print what `pair` returns"));
+    assert!(err(&["--args=1"]).starts_with("`--args` needs a function"));
+}
+
+#[test]
+fn multiple_processes() {
+    let build = |source: &str| {
+        let mut files = FileDb::new();
+        files.add("main.c", source).unwrap();
+        return compile(&files).unwrap();
+    };
+
+    let count = build("#include <stdio.h>\nint main() {\n  int sum = 0;\n  for (int i = 0; i < 1000; i++) sum += i;\n  printf(\"sum\\n\");\n  return sum == 499500 ? 3 : 1;\n}\n");
+    let quick = build("#include <stdio.h>\nint main() { printf(\"quick\\n\"); return 7; }\n");
+    let crash = build("int main() { int *p = 0; return *p; }\n");
+
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.time_slice = 100;
+    let ids = [
+        runtime.spawn(&count),
+        runtime.spawn(&quick),
+        runtime.spawn(&crash),
+    ];
+    assert_eq!(ids, [0, 1, 2]);
+
+    let results = runtime.run_all();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].as_ref().unwrap(), &3);
+    assert_eq!(results[1].as_ref().unwrap(), &7);
+    assert_eq!(results[2].as_ref().unwrap_err().short_name, "NullPointer");
+    assert_eq!(runtime.exit_status(1), Some(7));
+
+    // the short program finishes first even though it was spawned second
+    let out = runtime.term_out();
+    assert!(out.find("quick").unwrap() < out.find("sum").unwrap());
+}
+
+#[test]
+fn fork_exec_wait() {
+    let build = |source: &str| {
+        let mut files = FileDb::new();
+        files.add("main.c", source).unwrap();
+        return compile(&files).unwrap();
+    };
+
+    let parent = build(concat!(
+        "#include <stddef.h>\n#include <stdio.h>\n#include <unistd.h>\n#include <sys/wait.h>\n",
+        "int main() {\n",
+        "  int x = 10;\n",
+        "  pid_t pid = fork();\n",
+        "  if (pid == 0) { x += 1; printf(\"child %d\\n\", x); return 5; }\n",
+        "  int status;\n",
+        "  if (waitpid(pid, &status, 0) != pid || !WIFEXITED(status)) return 1;\n",
+        "  printf(\"parent %d %d\\n\", x, WEXITSTATUS(status));\n",
+        "  if (wait(&status) != -1) return 2;\n",
+        "  pid = fork();\n",
+        "  if (pid == 0) { execv(\"/bin/hello\", NULL); return 3; }\n",
+        "  waitpid(-1, &status, 0);\n",
+        "  return WEXITSTATUS(status) == 9 ? 0 : 4;\n",
+        "}\n"
+    ));
+    let hello = build("#include <stdio.h>\nint main() { printf(\"hello\\n\"); return 9; }\n");
+
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.install("/bin/hello", hello);
+    assert_eq!(runtime.run(&parent).unwrap(), 0);
+    assert_eq!(runtime.term_out(), "child 11\nparent 10 5\nhello\n");
+}
+
+#[test]
+fn pipes_and_dup2() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdio.h>\n#include <string.h>\n#include <unistd.h>\n#include <sys/wait.h>\n",
+        "int main() {\n",
+        "  int fds[2];\n",
+        "  if (pipe(fds) != 0) return 1;\n",
+        "  if (fork() == 0) {\n",
+        "    dup2(fds[1], STDOUT_FILENO);\n",
+        "    close(fds[0]);\n",
+        "    close(fds[1]);\n",
+        "    printf(\"through the pipe\\n\");\n",
+        "    fflush(stdout);\n",
+        "    return 0;\n",
+        "  }\n",
+        "  close(fds[1]);\n",
+        "  char buf[64];\n",
+        "  int total = 0, n;\n",
+        "  while ((n = read(fds[0], buf + total, 63 - total)) > 0) total += n;\n",
+        "  buf[total] = 0;\n",
+        "  wait(NULL);\n",
+        "  printf(\"parent got: %s\", buf);\n",
+        "  if (write(fds[0], \"x\", 1) != -1) return 2;\n",
+        "  return n;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    assert_eq!(runtime.run(&program).unwrap(), 0);
+    assert_eq!(runtime.term_out(), "parent got: through the pipe\n");
+}
+
+#[test]
+fn poll_and_blocking_stdin() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdio.h>\n#include <poll.h>\n",
+        "int main() {\n",
+        "  struct pollfd fd = {0, POLLIN, 0};\n",
+        "  printf(\"ready %d\\n\", poll(&fd, 1, 0));\n",
+        "  int x;\n",
+        "  scanf(\"%d\", &x);\n",
+        "  printf(\"got %d, ready %d\\n\", x, poll(&fd, 1, 0));\n",
+        "  return 0;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.stdin_open = true;
+    let proc_id = runtime.load_term_program(&program);
+    while runtime.active_count != 0 {
+        runtime.run_op_count(!0).unwrap();
+    }
+
+    assert_eq!(runtime.exit_status(proc_id), None);
+    assert_eq!(runtime.term_out(), "ready 0\n");
+
+    write!(runtime, "42\n").unwrap();
+    runtime.close_stdin();
+    while runtime.active_count != 0 {
+        runtime.run_op_count(!0).unwrap();
+    }
+
+    assert_eq!(runtime.exit_status(proc_id), Some(0));
+    assert_eq!(runtime.term_out(), "42\ngot 42, ready 1\n");
+}
+
+#[test]
+fn record_and_replay() {
+    let build = |source: &str| {
+        let mut files = FileDb::new();
+        files.add("main.c", source).unwrap();
+        return compile(&files).unwrap();
+    };
+
+    let program = build(concat!(
+        "#include <stdio.h>\n",
+        "int main() {\n",
+        "  int a, b;\n",
+        "  scanf(\"%d %d\", &a, &b);\n",
+        "  printf(\"%d\\n\", a + b);\n",
+        "  return 0;\n",
+        "}\n"
+    ));
+
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.record();
+    let proc_id = runtime.load_term_program(&program);
+    write!(runtime, "3 4\n").unwrap();
+    while runtime.active_count != 0 {
+        runtime.run_op_count(!0).unwrap();
+    }
+    assert_eq!(runtime.exit_status(proc_id), Some(0));
+    assert!(runtime.term_out().ends_with("7\n"));
+
+    let trace = runtime.take_trace().unwrap();
+    let bytes = trace.to_bytes();
+    assert!(trace.events.iter().any(|e| e.input == b"3 4\n"));
+
+    // no live input this time; it all comes from the trace
+    let mut replayed = Kernel::new(Vec::new());
+    replayed.replay(Trace::from_bytes(&bytes).unwrap());
+    assert_eq!(replayed.run(&program).unwrap(), 0);
+    assert_eq!(replayed.term_out(), "7\n");
+
+    let other = build("#include <stdio.h>\nint main() { printf(\"different\\n\"); return 0; }\n");
+    let mut diverged = Kernel::new(Vec::new());
+    diverged.replay(trace);
+    let err = diverged.run(&other).unwrap_err();
+    assert_eq!(err.short_name, "ReplayDiverged");
+}
+
+#[test]
+fn snapshot_restore() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdio.h>\n#include <stdlib.h>\n",
+        "int main() {\n",
+        "  int *squares = malloc(10 * sizeof(int));\n",
+        "  for (int i = 0; i < 10; i++) {\n",
+        "    squares[i] = i * i;\n",
+        "    printf(\"%d\\n\", squares[i]);\n",
+        "  }\n",
+        "  return 0;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    let proc_id = runtime.load_term_program(&program);
+    runtime.run_op_count(2000).unwrap();
+    let before = runtime.term_out();
+    let snapshot = runtime.snapshot();
+
+    while runtime.active_count != 0 {
+        runtime.run_op_count(!0).unwrap();
+    }
+    let rest = runtime.term_out();
+    assert!(before.len() > 0 && rest.len() > 0);
+    assert_eq!(
+        before.clone() + &rest,
+        "0\n1\n4\n9\n16\n25\n36\n49\n64\n81\n"
+    );
+
+    let mut restored = Kernel::new(Vec::new());
+    restored.restore(&snapshot).unwrap();
+    while restored.active_count != 0 {
+        restored.run_op_count(!0).unwrap();
+    }
+    assert_eq!(restored.exit_status(proc_id), Some(0));
+    assert_eq!(restored.term_out(), rest);
+
+    let err = restored
+        .restore(&snapshot[..snapshot.len() - 1])
+        .unwrap_err();
+    assert_eq!(err.short_name, "InvalidSnapshot");
+
+    // After the header, the empty file system, and the empty input comes
+    // whether stdin is open
+    let mut corrupted = snapshot.clone();
+    assert!(corrupted[48] <= 1);
+    corrupted[48] = 7;
+    let err = restored.restore(&corrupted).unwrap_err();
+    assert_eq!(err.short_name, "InvalidSnapshot");
+    assert_eq!(err.message, "snapshot contained 7 as a bool");
+
+    // Before the input is how much of it has been read, which can't be past its end
+    let mut corrupted = snapshot.clone();
+    corrupted[32] = 1;
+    let err = restored.restore(&corrupted).unwrap_err();
+    assert_eq!(err.message, "snapshot has an invalid input position");
+
+    // Every byte of the snapshot either restores or is rejected, and what
+    // restores runs without aborting
+    for idx in 8..corrupted.len().min(4096) {
+        let mut corrupted = snapshot.clone();
+        corrupted[idx] = corrupted[idx].wrapping_add(0x81);
+        let mut runtime = Kernel::new(Vec::new());
+        if runtime.restore(&corrupted).is_ok() {
+            let _ = runtime.run_op_count(1000);
+        }
+    }
+}
+
+#[test]
+fn step_back() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdio.h>\n",
+        "int main() {\n",
+        "  int total = 0;\n",
+        "  for (int i = 1; i <= 5; i++) {\n",
+        "    total += i;\n",
+        "    printf(\"%d\\n\", total);\n",
+        "  }\n",
+        "  return total;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let state = |memory: &Memory| {
+        let data = (memory.stack_data.clone(), memory.shared_data.clone());
+        let tables = (
+            memory.heap.clone(),
+            memory.stack.clone(),
+            memory.callstack.clone(),
+        );
+        let pc = (memory.pc.var_idx(), memory.pc.offset());
+        return (pc, memory.loc, memory.expr_stack.clone(), data, tables);
+    };
+
+    // Only the states that get rewound to are kept; cloning every one of them
+    // takes gigabytes. A step is a run of ops at the same location, so this also
+    // returns the op count each step starts at.
+    let states_at = |program: &BinaryData, ops: &[usize]| {
+        let mut runtime = Kernel::new(Vec::new());
+        let proc_id = runtime.load_term_program(program);
+        let (mut starts, mut states) = (Vec::new(), Vec::new());
+        let mut count = 0;
+        while runtime.active_count != 0 {
+            let memory = &runtime.processes.get(proc_id as usize).unwrap().tag.memory;
+            if starts.last().map(|(_, loc)| *loc) != Some(memory.loc) {
+                starts.push((count, memory.loc));
+            }
+            if ops.contains(&count) {
+                states.push((count, state(memory)));
+            }
+            runtime.run_op_count(1).unwrap();
+            count += 1;
+        }
+        let starts: Vec<_> = starts.into_iter().map(|(count, _)| count).collect();
+        return (count, starts, states);
+    };
+
+    // The op count the step `steps` steps before the one running after `ops` ops
+    // starts at
+    let step_start = |starts: &[usize], ops: usize, steps: usize| {
+        let current = starts.iter().rposition(|&start| start < ops).unwrap();
+        return starts[current - steps];
+    };
+
+    let (op_count, starts, _) = states_at(&program, &[]);
+    let ops = op_count * 2 / 3;
+    let expected = step_start(&starts, ops, 20);
+    let (_, _, states) = states_at(&program, &[expected]);
+
+    let mut runtime = Kernel::new(Vec::new());
+    let proc_id = runtime.load_term_program(&program);
+    let err = runtime.step_back(proc_id, 1).unwrap_err();
+    assert_eq!(err.short_name, "NoHistory");
+
+    runtime.enable_history(proc_id, 1000);
+    for _ in 0..ops {
+        runtime.run_op_count(1).unwrap();
+    }
+
+    assert_eq!(runtime.step_back(proc_id, 20).unwrap(), 20);
+    let rewound = state(&runtime.processes.get(proc_id as usize).unwrap().tag.memory);
+    assert!(states[0] == (expected, rewound));
+
+    while runtime.active_count != 0 {
+        runtime.run_op_count(!0).unwrap();
+    }
+    assert_eq!(runtime.exit_status(proc_id), Some(15));
+
+    let err = runtime.step_back(proc_id, 1).unwrap_err();
+    assert_eq!(err.short_name, "ProcessExited");
+
+    // Calls and the heap get rewound too, including more than once in a row
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdlib.h>\n",
+        "int *push(int *list, int len, int value) {\n",
+        "  int *out = malloc((len + 1) * sizeof(int));\n",
+        "  for (int i = 0; i < len; i++) out[i] = list[i];\n",
+        "  out[len] = value;\n",
+        "  free(list);\n",
+        "  return out;\n",
+        "}\n",
+        "int main() {\n",
+        "  int *list = malloc(sizeof(int));\n",
+        "  list[0] = 0;\n",
+        "  for (int i = 1; i < 6; i++) list = push(list, i, i * i);\n",
+        "  int total = 0;\n",
+        "  for (int i = 0; i < 6; i++) total += list[i];\n",
+        "  return total;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let (op_count, starts, _) = states_at(&program, &[]);
+    let ops = op_count * 2 / 3;
+    let targets = [step_start(&starts, ops, 40), step_start(&starts, ops, 55)];
+    let (_, _, states) = states_at(&program, &targets);
+
+    let mut runtime = Kernel::new(Vec::new());
+    let proc_id = runtime.load_term_program(&program);
+    runtime.enable_history(proc_id, 1000);
+    for _ in 0..ops {
+        runtime.run_op_count(1).unwrap();
+    }
+
+    let mut back = 0;
+    for &steps in &[40, 15, 0] {
+        assert_eq!(runtime.step_back(proc_id, steps).unwrap(), steps);
+        let rewound = state(&runtime.processes.get(proc_id as usize).unwrap().tag.memory);
+        back += steps as usize;
+        let expected = step_start(&starts, ops, back);
+        let idx = states
+            .iter()
+            .position(|(count, _)| *count == expected)
+            .unwrap();
+        assert!(states[idx].1 == rewound);
+    }
+
+    while runtime.active_count != 0 {
+        runtime.run_op_count(!0).unwrap();
+    }
+    assert_eq!(runtime.exit_status(proc_id), Some(55));
+}
+
+#[test]
+fn watchpoints() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdio.h>\n#include <stdlib.h>\n",
+        "int counter;\n",
+        "int main() {\n",
+        "  int local = 1;\n",
+        "  int *heap = malloc(4 * sizeof(int));\n",
+        "  printf(\"%p %p %p\\n\", &counter, &local, heap + 2);\n",
+        "  int wait;\n",
+        "  scanf(\"%d\", &wait);\n",
+        "  for (int i = 0; i < 4; i++) heap[i] = i;\n",
+        "  counter = 5;\n",
+        "  local = 7;\n",
+        "  return local + counter + heap[2];\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.stdin_open = true;
+    let proc_id = runtime.load_term_program(&program);
+    while runtime.active_count != 0 {
+        runtime.run_op_count(!0).unwrap();
+    }
+
+    let out = runtime.term_out();
+    let to_ptr = |p: &str| VarPointer::from(u64::from_str_radix(p, 16).unwrap());
+    let ptrs: Vec<VarPointer> = out.split_whitespace().map(to_ptr).collect();
+    let mut proc = runtime.processes.get_mut(proc_id as usize).unwrap();
+    for &ptr in &ptrs {
+        proc.tag_mut().memory.watch(ptr, 4);
+    }
+
+    write!(runtime, "1\n").unwrap();
+    let mut hits = Vec::new();
+    while runtime.active_count != 0 {
+        runtime.run_op_count(!0).unwrap();
+        if let Some((proc, hit)) = runtime.watch_hit.take() {
+            assert_eq!(proc, proc_id);
+            let line = files.loc_to_string(hit.loc);
+            hits.push((hit.watch.ptr.offset(), hit.ptr.offset(), line));
+        }
+    }
+
+    assert_eq!(runtime.exit_status(proc_id), Some(14));
+    assert_eq!(hits.len(), 3);
+    assert_eq!(hits[0].0, ptrs[2].offset());
+    for (hit, line) in hits.iter().zip(&["main.c:10", "main.c:11", "main.c:12"]) {
+        assert!(hit.2.starts_with(line));
+    }
+}
+
+#[test]
+fn debugger_eval() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdlib.h>\n",
+        "struct Point { int x; int y; };\n",
+        "int scale = 3;\n",
+        "int square(int x) { return x * x; }\n",
+        "int main() {\n",
+        "  int arr[3] = {1, 2, 3};\n",
+        "  struct Point p = {4, 5};\n",
+        "  int local = 6;\n",
+        "  if (local) {\n",
+        "    int local = 10;\n",
+        "    local += 1;\n",
+        "  }\n",
+        "  return arr[1] + p.y + local;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let (program, debug) = compile_debug(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    let proc_id = runtime.load_term_program(&program);
+    let run_to = |runtime: &mut Kernel, files: &FileDb, line: &str| {
+        while runtime.loc() == NO_FILE || !files.loc_to_string(runtime.loc()).starts_with(line) {
+            runtime.run_op_count(1).unwrap();
+        }
+    };
+
+    let mut evaluator = debugger::Evaluator::new();
+    run_to(&mut runtime, &files, "main.c:11");
+    let memory = runtime.cur_mem().unwrap().clone();
+    let result = evaluator.eval(&mut files, &debug, &memory, "local");
+    assert_eq!(result.unwrap().value, "10");
+
+    run_to(&mut runtime, &files, "main.c:13");
+    let memory = runtime.cur_mem().unwrap().clone();
+    let mut eval = |files: &mut FileDb, expr: &str| evaluator.eval(files, &debug, &memory, expr);
+    let mut value = |files: &mut FileDb, expr: &str| eval(files, expr).unwrap().value;
+
+    assert_eq!(value(&mut files, "local"), "6");
+    assert_eq!(value(&mut files, "scale"), "3");
+    assert_eq!(value(&mut files, "arr[1] + local * 2"), "14");
+    assert_eq!(value(&mut files, "p.y"), "5");
+    assert_eq!(value(&mut files, "square(scale) + p.x"), "13");
+    assert_eq!(value(&mut files, "local = 100"), "100");
+    assert_eq!(value(&mut files, "local"), "6");
+    assert_eq!(value(&mut files, "sizeof(struct Point)"), "8");
+    drop(value);
+    assert_eq!(eval(&mut files, "(char) scale").unwrap().ty, "char");
+
+    match eval(&mut files, "missing + 1") {
+        Err(debugger::EvalError::Compile(_)) => {}
+        _ => panic!("expected a compile error"),
+    }
+
+    while runtime.active_count != 0 {
+        runtime.run_op_count(!0).unwrap();
+    }
+    assert_eq!(runtime.exit_status(proc_id), Some(13));
+}
+
+#[test]
+fn frame_layouts() {
+    let source = concat!(
+        "struct Pair { long a; char b; };\n",
+        "double mix(int x, struct Pair p, char *s) {\n",
+        "  static int calls = 0;\n",
+        "  int i = x, j = i;\n",
+        "  if (x) { long k = i; j += k; } else { char c = 'c', d = c; j += d; }\n",
+        "  for (int n = 0; n < j; n++) { short m = n; calls += m; }\n",
+        "  return j + p.a + s[0];\n",
+        "}\n",
+        "int main() { struct Pair p = {1, 2}; return mix(3, p, \"a\") > 0; }\n"
+    );
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let (_, debug) = compile_debug(&files).unwrap();
+    let layouts = debug.frame_layouts();
+    let layout = |name: &str| layouts.iter().find(|l| l.name == name).unwrap().clone();
+
+    let slots = |slots: &[assembler::FrameSlot]| {
+        let slots = slots
+            .iter()
+            .map(|s| format!("{} {} {} {}", s.offset, s.name, s.ty, s.size));
+        return slots.collect::<Vec<_>>();
+    };
+
+    let mix = layout("mix");
+    assert_eq!(mix.ret_size, 8);
+    assert_eq!(
+        slots(&mix.params),
+        vec!["-2 x int 4", "-3 p struct Pair 16", "-4 s char* 8"]
+    );
+    assert_eq!(
+        slots(&mix.locals),
+        vec![
+            "0 i int 4",
+            "1 j int 4",
+            "2 k long 8",
+            "2 c char 1",
+            "3 d char 1",
+            "2 n int 4",
+            "3 m short 2",
+        ]
+    );
+
+    let main = layout("main");
+    assert_eq!(main.ret_size, 4);
+    assert_eq!(slots(&main.params), Vec::<String>::new());
+    assert_eq!(slots(&main.locals), vec!["0 p struct Pair 16"]);
+
+    let json = serde_json::to_string(&main).unwrap();
+    assert!(json.starts_with("{\"name\":\"main\",\"file\":"));
+    assert!(json.ends_with(
+        "\"ret_size\":4,\"params\":[],\"locals\":[{\"name\":\"p\",\"ty\":\"struct Pair\",\"offset\":0,\"size\":16}]}"
+    ));
+}
+
+#[test]
+fn repl_session() {
+    use crate::repl::Repl;
+
+    let mut repl = Repl::new();
+    let out = repl.eval("#include <stdio.h>").unwrap();
+    assert_eq!((out.output.as_str(), out.value), ("", None));
+
+    repl.eval("int square(int x) { return x * x; }").unwrap();
+    repl.eval("int total = 0;").unwrap();
+    repl.eval("int local = square(3);").unwrap();
+    let out = repl.eval("printf(\"local is %d\\n\", local);").unwrap();
+    assert_eq!(out.output, "local is 9\n");
+
+    let out = repl
+        .eval("for (int i = 0; i < 4; i++) { total += i; }")
+        .unwrap();
+    assert_eq!((out.output.as_str(), out.value), ("", None));
+
+    let value = repl.eval("total + local").unwrap().value.unwrap();
+    assert_eq!((value.value.as_str(), value.ty.as_str()), ("15", "int"));
+
+    // Side effects happen once
+    assert_eq!(repl.eval("total++").unwrap().value.unwrap().value, "6");
+    assert_eq!(repl.eval("total").unwrap().value.unwrap().value, "7");
+
+    let out = repl.eval("printf(\"again\\n\")").unwrap();
+    assert_eq!(out.output, "again\n");
+    assert_eq!(out.value.unwrap().ty, "int");
+
+    let err = repl.eval("missing + 1").unwrap_err();
+    assert!(err.contains("missing"));
+    let err = repl.eval("int broken() { return missing; }").unwrap_err();
+    assert!(err.contains("int broken() { return missing; }"));
+    let err = repl.eval("int *p = 0; *p = 1;").unwrap_err();
+    assert!(err.contains("NullPointer"));
+    assert_eq!(
+        repl.eval("square(total)").unwrap().value.unwrap().value,
+        "49"
+    );
+
+    // Earlier statements don't run again, and what they left behind stays put
+    let out = repl.eval("char *name = \"tci\"; int *nums = malloc(8);");
+    assert!(out.unwrap_err().contains("malloc"));
+    repl.eval("#include <stdlib.h>").unwrap();
+    repl.eval("char *name = \"tci\"; int *nums = malloc(8);")
+        .unwrap();
+    repl.eval("nums[1] = total;").unwrap();
+    repl.eval("int sum(int *n) { return n[0] + n[1] + total; }")
+        .unwrap();
+    repl.eval("nums[0] = 1").unwrap();
+    let out = repl.eval("printf(\"%s %d\\n\", name, sum(nums));").unwrap();
+    assert_eq!(out.output, "tci 15\n");
+    assert_eq!(out.value.unwrap().ty, "int");
+
+    // A statement that fails is undone, along with the locals it declared
+    let err = repl.eval("total = 100; int gone = 1; free(nums); free(nums);");
+    assert!(err.unwrap_err().contains("free"));
+    assert_eq!(repl.eval("total").unwrap().value.unwrap().value, "7");
+    assert!(repl.eval("gone").unwrap_err().contains("gone"));
+    assert_eq!(repl.eval("nums[1]").unwrap().value.unwrap().value, "7");
+    let out = repl
+        .eval("for (int i = 0; i < 2; i++) { static int n; printf(\"%d\\n\", n++); }")
+        .unwrap();
+    assert_eq!(out.output, "0\n1\n");
+
+    // A `;` after an expression still shows its value, but not after a declaration
+    let value = repl.eval("total + 2;").unwrap().value.unwrap();
+    assert_eq!((value.value.as_str(), value.ty.as_str()), ("9", "int"));
+    assert_eq!(repl.eval("int later = 3;").unwrap().value, None);
+    assert_eq!(
+        repl.eval("total = later;").unwrap().value.unwrap().value,
+        "3"
+    );
+}
+
+#[test]
+fn repl_op_budget() {
+    use crate::repl::Repl;
+
+    let mut repl = Repl::new();
+    repl.max_ops = 10_000;
+    repl.eval("int count = 1;").unwrap();
+    let err = repl.eval("while (1) { count++; }").unwrap_err();
+    assert_eq!(
+        err,
+        "stopped after 10000 instructions; the input was undone\n"
+    );
+    assert_eq!(repl.eval("count").unwrap().value.unwrap().value, "1");
+}
+
+#[test]
+fn memory_graph() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdlib.h>\n",
+        "struct Node { int value; struct Node *next; };\n",
+        "int main() {\n",
+        "  struct Node *head = 0;\n",
+        "  for (int i = 0; i < 3; i++) {\n",
+        "    struct Node *node = malloc(sizeof(struct Node));\n",
+        "    node->value = i;\n",
+        "    node->next = head;\n",
+        "    head = node;\n",
+        "  }\n",
+        "  int *nums[2];\n",
+        "  nums[0] = &head->value;\n",
+        "  return 0;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let (program, debug) = compile_debug(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    let proc_id = runtime.load_term_program(&program);
+    while runtime.loc() == NO_FILE || !files.loc_to_string(runtime.loc()).starts_with("main.c:13") {
+        runtime.run_op_count(1).unwrap();
+    }
+
+    let graph = debugger::memory_graph(&debug, runtime.cur_mem().unwrap());
+    let node = |name: &str| {
+        graph
+            .nodes
+            .iter()
+            .find(|n| n.name == name)
+            .unwrap()
+            .id
+            .clone()
+    };
+    let edges_from = |id: &str| -> Vec<&debugger::MemoryEdge> {
+        return graph.edges.iter().filter(|e| e.from == id).collect();
+    };
+
+    let names: Vec<&str> = graph.nodes.iter().map(|n| &*n.name).collect();
+    assert_eq!(names.iter().filter(|n| n.len() != 0).count(), 2);
+    assert_eq!(graph.nodes.iter().filter(|n| n.kind == "heap").count(), 3);
+
+    let mut next = edges_from(&node("head"))[0].to.clone();
+    for _ in 0..2 {
+        let edges = edges_from(&next);
+        assert_eq!((edges.len(), edges[0].from_offset), (1, 8));
+        next = edges[0].to.clone();
+    }
+    assert_eq!(edges_from(&next).len(), 0);
+
+    let nums = edges_from(&node("nums"));
+    assert_eq!(nums.len(), 1);
+    assert_eq!(nums[0].to, edges_from(&node("head"))[0].to);
+    assert!(graph.to_json().contains("\"name\":\"nums\""));
+
+    while runtime.active_count != 0 {
+        runtime.run_op_count(!0).unwrap();
+    }
+
+    let process = runtime.processes.get(proc_id as usize).unwrap();
+    let graph = debugger::memory_graph(&debug, &process.tag.memory);
+    assert_eq!(graph.nodes.len(), 3);
+    assert_eq!(graph.edges.len(), 2);
+    assert!(graph.edges.iter().all(|e| e.heuristic));
+
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdlib.h>\n",
+        "struct Node { int value; struct Node *next; };\n",
+        "int main() {\n",
+        "  struct Node *nodes = malloc(2 * sizeof(struct Node));\n",
+        "  nodes[0].next = &nodes[1];\n",
+        "  nodes[1].next = 0;\n",
+        "  long *bits = malloc(sizeof(long));\n",
+        "  *bits = (long) nodes;\n",
+        "  void *raw = malloc(sizeof(struct Node *));\n",
+        "  *(struct Node **) raw = nodes;\n",
+        "  return 0;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let (program, debug) = compile_debug(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.load_term_program(&program);
+    while runtime.loc() == NO_FILE || !files.loc_to_string(runtime.loc()).starts_with("main.c:11") {
+        runtime.run_op_count(1).unwrap();
+    }
+
+    let graph = debugger::memory_graph(&debug, runtime.cur_mem().unwrap());
+    let edge = |from: &str| {
+        let id = &graph.nodes.iter().find(|n| n.name == from).unwrap().id;
+        let edge = graph.edges.iter().find(|e| &e.from == id).unwrap();
+        assert!(!edge.heuristic);
+        edge.to.clone()
+    };
+    let edges_from = |id: &str| -> Vec<(u32, String, u32, bool)> {
+        let edges = graph.edges.iter().filter(|e| e.from == id);
+        return edges
+            .map(|e| (e.from_offset, e.to.clone(), e.to_offset, e.heuristic))
+            .collect();
+    };
+
+    let nodes = edge("nodes");
+    assert_eq!(edges_from(&nodes), vec![(8, nodes.clone(), 16, false)]);
+    assert_eq!(edges_from(&edge("bits")), vec![]);
+    assert_eq!(edges_from(&edge("raw")), vec![(0, nodes, 0, true)]);
+}
+
+#[test]
+fn coverage() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdio.h>\n",
+        "int unused(int x) {\n",
+        "  return x + 1;\n",
+        "}\n",
+        "int main() {\n",
+        "  int total = 0;\n",
+        "  for (int i = 0; i < 4; i++) {\n",
+        "    total += i;\n",
+        "  }\n",
+        "  if (total > 100) {\n",
+        "    total = 0;\n",
+        "  }\n",
+        "  return total;\n",
+        "}\n"
+    );
+    let main = files.add("main.c", source).unwrap();
+    let (program, debug) = compile_debug(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.enable_coverage();
+    assert_eq!(runtime.run(&program).unwrap(), 6);
+
+    let coverage = runtime.coverage.as_ref().unwrap();
+    let stmts = debug.stmts();
+    let lcov = coverage.lcov(&files, &stmts);
+    assert!(lcov.starts_with("SF:main.c\n"));
+    for line in &["DA:3,0", "DA:6,1", "DA:8,4", "DA:11,0", "DA:13,1"] {
+        assert!(lcov.lines().any(|l| l == *line), "missing {}", line);
+    }
+    assert!(!lcov.contains("libs/"));
+
+    let annotated = coverage.annotate(&files, &stmts, main);
+    let lines: Vec<&str> = annotated.lines().collect();
+    assert_eq!(lines[2], "    #####:    3:  return x + 1;");
+    assert_eq!(lines[3], "        -:    4:}");
+    assert_eq!(lines[7], "        4:    8:    total += i;");
+}
+
+#[test]
+fn virtual_clock() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdio.h>\n#include <stdlib.h>\n#include <sys/time.h>\n",
+        "int main() {\n",
+        "  time_t start = time(NULL);\n",
+        "  srand(start);\n",
+        "  int a = rand(), b = rand();\n",
+        "  struct timeval tv;\n",
+        "  gettimeofday(&tv, NULL);\n",
+        "  clock_t used = clock();\n",
+        "  printf(\"%ld %d %d %ld %ld\\n\", start, a, b, tv.tv_sec, used);\n",
+        "  srand(1);\n",
+        "  int c = rand();\n",
+        "  srand(1);\n",
+        "  return c == rand() && a <= RAND_MAX && used > 0 && tv.tv_usec < 1000000;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let run = |host: Option<fn() -> u64>| {
+        let mut runtime = Kernel::new(Vec::new());
+        if let Some(clock) = host {
+            runtime.use_host_clock(clock);
+        }
+
+        assert_eq!(runtime.run(&program).unwrap(), 1);
+        return runtime.term_out();
+    };
+
+    let out = run(None);
+    assert_eq!(out, run(None));
+    let fields: Vec<i64> = out.split_whitespace().map(|f| f.parse().unwrap()).collect();
+    assert_eq!(fields[0], 1609459200);
+    assert_eq!(fields[3], 1609459200);
+
+    let out = run(Some(|| 1_700_000_000_123_456));
+    assert!(out.starts_with("1700000000 "));
+
+    let mut options = CompileOptions::default();
+    options.parse_flag("--clock=host").unwrap();
+    assert!(options.host_clock);
+    options.parse_flag("--clock=virtual").unwrap();
+    assert!(!options.host_clock);
+    let err = options.parse_flag("--clock=wall").unwrap_err();
+    assert_eq!(err, "unknown clock `wall`, expected virtual or host");
+}
+
+#[test]
+fn signals() {
+    let run = |body: &str| {
+        let mut files = FileDb::new();
+        let source = format!(
+            concat!(
+                "#include <signal.h>\n#include <stdio.h>\n#include <stdlib.h>\n",
+                "void handler(int sig) {{ printf(\"caught %d\\n\", sig); }}\n",
+                "int main() {{\n{}\n}}\n"
+            ),
+            body
+        );
+        files.add("main.c", &source).unwrap();
+        let program = compile(&files).unwrap();
+
+        let mut runtime = Kernel::new(Vec::new());
+        let res = runtime.run(&program).map_err(|e| e.short_name);
+        return (res, runtime.term_streams().0);
+    };
+
+    let (res, out) = run("int zero = 0;\nreturn 1 / zero;");
+    assert_eq!(res, Err("DivideByZero".to_string()));
+    assert_eq!(out, "");
+
+    let body = "signal(SIGFPE, handler);\nint zero = 0;\nreturn 1 % zero;";
+    let (res, out) = run(body);
+    assert_eq!(res, Err("DivideByZero".to_string()));
+    assert_eq!(out, "caught 8\n");
+
+    let body = "signal(SIGSEGV, handler);\nint *p = NULL;\nreturn *p;";
+    let (res, out) = run(body);
+    assert_eq!(res, Err("NullPointer".to_string()));
+    assert_eq!(out, "caught 11\n");
+
+    let (res, out) = run("signal(SIGABRT, handler);\nabort();\nreturn 0;");
+    assert_eq!(res, Err("Aborted".to_string()));
+    assert_eq!(out, "caught 6\n");
+
+    let body = concat!(
+        "if (signal(SIGINT, handler) != SIG_DFL) return 1;\n",
+        "raise(SIGINT);\n",
+        "signal(SIGTERM, SIG_IGN);\n",
+        "raise(SIGTERM);\n",
+        "return signal(64, handler) == SIG_ERR ? 7 : 0;"
+    );
+    let (res, out) = run(body);
+    assert_eq!(res, Ok(7));
+    assert_eq!(out, "caught 2\n");
+}
+
+#[test]
+fn setjmp_longjmp() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <setjmp.h>\n#include <stdio.h>\n",
+        "jmp_buf env;\n",
+        "int depth(int n) {\n",
+        "  int local[4];\n",
+        "  if (n == 0) longjmp(env, 42);\n",
+        "  return depth(n - 1) + 1;\n",
+        "}\n",
+        "int main() {\n",
+        "  int count = 0;\n",
+        "  jmp_buf loop;\n",
+        "  if (setjmp(loop) < 3) {\n",
+        "    count++;\n",
+        "    longjmp(loop, count);\n",
+        "  }\n",
+        "  int val = setjmp(env);\n",
+        "  if (val == 0) depth(5);\n",
+        "  printf(\"%d %d\\n\", count, val);\n",
+        "  return 0;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    assert_eq!(runtime.run(&program).unwrap(), 0);
+    assert_eq!(runtime.term_out(), "3 42\n");
+
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <setjmp.h>\n",
+        "jmp_buf env;\n",
+        "int save() { return setjmp(env); }\n",
+        "int main() {\n",
+        "  if (save() == 0) longjmp(env, 1);\n",
+        "  return 0;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    let err = runtime.run(&program).unwrap_err();
+    assert_eq!(err.short_name, "InvalidLongJump");
+
+    // A `jmp_buf` is just memory, so its saved pc can point anywhere
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <setjmp.h>\n",
+        "int data = 5;\n",
+        "int main() {\n",
+        "  jmp_buf env;\n",
+        "  if (setjmp(env) != 0) return 1;\n",
+        "  env[0] = (uint64_t)&data;\n",
+        "  longjmp(env, 1);\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    let err = runtime.run(&program).unwrap_err();
+    assert_eq!(err.short_name, "InvalidLongJump");
+    assert_eq!(
+        err.message,
+        "called longjmp with a jmp_buf that wasn't filled in by setjmp"
+    );
+}
+
+#[test]
+fn jump_into_data() {
+    // A function header, then a byte that isn't an opcode
+    let mut files = FileDb::new();
+    let source = concat!(
+        "unsigned char code[32];\n",
+        "int main() {\n",
+        "  code[21] = 255;\n",
+        "  ((void (*)(void))code)();\n",
+        "  return 0;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    let err = runtime.run(&program).unwrap_err();
+    assert_eq!(err.short_name, "InvalidOpcode");
+    assert_eq!(
+        err.message,
+        "ran into the byte 255, which isn't an instruction"
+    );
+}
+
+#[test]
+fn little_endian_memory() {
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdio.h>\n#include <string.h>\n",
+        "struct Pair { short a; int b; };\n",
+        "union Pun { unsigned int word; unsigned char bytes[4]; double d; long l; };\n",
+        "int main() {\n",
+        "  int x = 0x11223344;\n",
+        "  unsigned char *p = (unsigned char *)&x;\n",
+        "  printf(\"%x %x %x %x\\n\", p[0], p[1], p[2], p[3]);\n",
+        "  union Pun pun;\n",
+        "  pun.word = 0x55667788;\n",
+        "  printf(\"%x %x\\n\", pun.bytes[0], pun.bytes[3]);\n",
+        "  pun.d = 1.0;\n",
+        "  printf(\"%lx\\n\", pun.l);\n",
+        "  struct Pair pair = { 0x0102, 0x03040506 };\n",
+        "  unsigned char raw[8];\n",
+        "  memcpy(raw, &pair, sizeof(pair));\n",
+        "  printf(\"%x %x %x %x\\n\", raw[0], raw[1], raw[4], raw[7]);\n",
+        "  struct Pair copy;\n",
+        "  memcpy(&copy, raw, sizeof(copy));\n",
+        "  return copy.b == pair.b && copy.a == pair.a ? 0 : 1;\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    assert_eq!(runtime.run(&program).unwrap(), 0);
+    let expected = "44 33 22 11\n88 55\n3ff0000000000000\n2 1 6 3\n";
+    assert_eq!(runtime.term_out(), expected);
+}
+
+#[test]
+fn embedder_memory_access() {
+    use crate::debugger::{peek, CValue};
+    use crate::lexer::*;
+    use crate::parser::parse;
+    use crate::type_checker::check_tree;
+
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <stdlib.h>\n#include <string.h>\n",
+        "struct Point { int x; unsigned char flags : 3; double scale; char name[4]; };\n",
+        "struct Point *shared;\n",
+        "int main() {\n",
+        "  shared = malloc(sizeof(struct Point));\n",
+        "  char *reply = malloc(8);\n",
+        "  reply[0] = 0;\n",
+        "  shared->x = -3;\n",
+        "  shared->flags = 5;\n",
+        "  shared->scale = 0.5;\n",
+        "  memcpy(shared->name, \"abc\", 4);\n",
+        "  while (reply[0] == 0) {}\n",
+        "  return strlen(reply) + shared->x;\n",
+        "}\n"
+    );
+    let file = files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let mut lexer = Lexer::new(&files);
+    let (id, tokens) = lexer.lex(file).unwrap();
+    let env = parse(id, tokens).unwrap();
+    let tu = check_tree(&files, env.file, &lexer.symbols, &env.tree).unwrap();
+    let ident = lexer.symbols.from_str("shared").opt().unwrap();
+    let point = tu.vars[&ident].ty.deref().unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    let proc = runtime.load_term_program(&program);
+    let (shared, reply) = (VarPointer::new_heap(1, 0), VarPointer::new_heap(2, 0));
+    for _ in 0..100 {
+        if runtime.read_cstring(proc, shared.with_offset(16)).ok() == Some("abc".to_string()) {
+            break;
+        }
+
+        runtime.run_op_count(100).unwrap();
+    }
+
+    let memory = runtime.memory(proc).unwrap();
+    let value = peek(&tu, &lexer.symbols, memory, shared, point).unwrap();
+    let name = CValue::Array(vec![
+        CValue::Int(97),
+        CValue::Int(98),
+        CValue::Int(99),
+        CValue::Int(0),
+    ]);
+    let expected = CValue::Struct(vec![
+        ("x".to_string(), CValue::Int(-3)),
+        ("flags".to_string(), CValue::UInt(5)),
+        ("scale".to_string(), CValue::Float(0.5)),
+        ("name".to_string(), name),
+    ]);
+    assert_eq!(value, expected);
+    assert_eq!(runtime.read_value::<i32>(proc, shared).unwrap(), -3);
+
+    runtime.write_value(proc, shared, 10i32).unwrap();
+    runtime.write_bytes(proc, reply, b"hello\0").unwrap();
+    let err = runtime.write_bytes(proc, reply, b"too long!").unwrap_err();
+    assert_eq!(err.short_name, "InvalidPointer");
+    assert_eq!(runtime.memory(7).unwrap_err().short_name, "InvalidProcess");
+
+    while runtime.active_count != 0 {
+        runtime.run_op_count(!0).unwrap();
+    }
+    assert_eq!(runtime.exit_status(proc), Some(15));
+}
+
+#[test]
+fn host_functions() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let pixels = Rc::new(RefCell::new(Vec::new()));
+    let mut host = HostFns::new();
+    let drawn = pixels.clone();
+    let params = [HostType::Long, HostType::Long, HostType::Long];
+    host.register("draw_pixel", &params, HostType::Void, move |_, args| {
+        drawn
+            .borrow_mut()
+            .push((args[0] as i64, args[1] as i64, args[2]));
+        return Ok(0);
+    });
+    host.register(
+        "name_len",
+        &[HostType::Pointer],
+        HostType::Long,
+        |memory, args| {
+            let name = memory.cstring_bytes(VarPointer::from(args[0]))?;
+            return Ok(name.len() as u64);
+        },
+    );
+    host.register("frame", &[], HostType::Long, |_, _| Ok(7));
+
+    assert_eq!(
+        host.header(),
+        concat!(
+            "#ifndef __TCI_HOST_H\n#define __TCI_HOST_H\n\n",
+            "void draw_pixel(long x0, long x1, long x2);\n",
+            "long name_len(void * x0);\n",
+            "long frame(void);\n",
+            "\n#endif\n"
+        )
+    );
+
+    let mut files = FileDb::new();
+    let source = concat!(
+        "#include <tci_host.h>\n",
+        "int main() {\n",
+        "  for (int i = 0; i < 3; i++) draw_pixel(i, -i, 0xFF);\n",
+        "  return name_len(\"abcd\") * 10 + frame();\n",
+        "}\n"
+    );
+    files.add("main.c", source).unwrap();
+    host.add_to(&mut files).unwrap();
+    let program = compile(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.host_fns = host;
+    assert_eq!(runtime.run(&program).unwrap(), 47);
+    assert_eq!(
+        *pixels.borrow(),
+        vec![(0, 0, 255), (1, -1, 255), (2, -2, 255)]
+    );
+
+    // Only `frame` is registered, so there's no host function 1
+    let mut files = FileDb::new();
+    let source =
+        "#include <tci.h>\nint main() { long a[1]; tci_ecall(TCI_ECALL_HOST + 1, a, 0); }\n";
+    files.add("main.c", source).unwrap();
+    let mut host = HostFns::new();
+    host.register("frame", &[], HostType::Long, |_, _| Ok(7));
+    host.add_to(&mut files).unwrap();
+    let program = compile(&files).unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    runtime.host_fns = host;
+    assert_eq!(
+        runtime.run(&program).unwrap_err().short_name,
+        "InvalidHostCall"
+    );
+}
+
+#[test]
+fn stack_overflow_diagnostic() {
+    let mut files = FileDb::new();
+    let source =
+        "int count(int n) {\n  return count(n + 1) + 1;\n}\nint main() { return count(0); }\n";
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let mut runtime = Kernel::new(Vec::new());
+    let err = runtime.run(&program).unwrap_err();
+    assert_eq!(err.short_name, "StackOverflow");
+
+    let rendered = print_error(&err, runtime.cur_mem().unwrap(), &files);
+    assert!(rendered.contains("note: `int count(int n)` (main.c:1) is on the stack 1000 times"));
+    assert!(rendered.contains("frames omitted ..."));
+    assert!(rendered.lines().count() < 100);
+}
+
+#[test]
+fn stale_pointer_after_realloc() {
+    let run = |body: &str| {
+        let mut files = FileDb::new();
+        let source = format!("#include <stdlib.h>\nint main() {{\n{}\n}}\n", body);
+        files.add("main.c", &source).unwrap();
+        let program = compile(&files).unwrap();
+
+        let mut runtime = Kernel::new(Vec::new());
+        let err = runtime.run(&program).unwrap_err();
+        let rendered = print_error(&err, runtime.cur_mem().unwrap(), &files);
+        return (err, rendered);
+    };
+
+    let stale = concat!(
+        "int *a = malloc(4 * sizeof(int));\n",
+        "int *b = realloc(a, 8 * sizeof(int));\n",
+        "b[0] = 1;\n",
+        "return a[0];"
+    );
+    let (err, rendered) = run(stale);
+    assert_eq!(err.short_name, "InvalidPointer");
+    assert!(err.message.ends_with(
+        "points to memory that realloc freed when it moved the block somewhere else; \
+         use the pointer realloc returned instead"
+    ));
+    assert_eq!(err.notes.len(), 1);
+    assert!(rendered.contains("6 | return a[0];"), "{}", rendered);
+    assert!(rendered.contains("4 | int *b = realloc(a, 8 * sizeof(int));"));
+    assert!(rendered.contains("^ moved by this call to realloc"));
+
+    let (err, rendered) = run("int *a = malloc(4);\nfree(a);\nfree(a);\nreturn 0;");
+    assert_eq!(err.short_name, "DoubleFree");
+    assert!(
+        rendered.contains("4 | free(a);\n  | ^^^^^^^ freed here"),
+        "{}",
+        rendered
+    );
+
+    let (err, _) = run("int *a = malloc(4);\nint *b = realloc(a, 8);\nfree(a);\nreturn 0;");
+    assert_eq!(err.short_name, "DoubleFree");
+    assert!(err.message.contains("realloc already freed"));
+}
+
+#[test]
+fn alignment_sanitizer() {
+    use crate::assembler::Sanitizers;
+
+    let run = |body: &str, sanitize: &str| {
+        let mut files = FileDb::new();
+        let source = format!(
+            concat!(
+                "#include <stdlib.h>\n",
+                "struct Pair {{ char tag; int value; }};\n",
+                "int main() {{\n{}\n}}\n"
+            ),
+            body
+        );
+        files.add("main.c", &source).unwrap();
+        let sanitize = Sanitizers::parse(sanitize).unwrap();
+        let program = compile_sanitized(&files, sanitize).unwrap();
+
+        let mut runtime = Kernel::new(Vec::new());
+        return runtime.run(&program).map_err(|e| e.short_name);
+    };
+
+    let aligned = concat!(
+        "struct Pair *p = malloc(sizeof(struct Pair) * 2);\n",
+        "p[1].value = 3;\n",
+        "p[1].value += 1;\n",
+        "char *bytes = (char *)&p[1];\n",
+        "bytes[1] = 1;\n",
+        "return p[1].value;"
+    );
+    assert_eq!(run(aligned, "alignment"), Ok(4));
+
+    let load = "char *buf = malloc(8);\nint *p = (int *)(buf + 1);\nreturn *p;";
+    assert!(run(load, "").is_ok());
+    assert_eq!(run(load, "alignment"), Err("MisalignedAccess".to_string()));
+
+    let store = "char *buf = malloc(16);\nlong *p = (long *)(buf + 4);\n*p = 1;\nreturn 0;";
+    assert_eq!(run(store, "alignment"), Err("MisalignedAccess".to_string()));
+
+    let incr = "char *buf = malloc(8);\nshort *p = (short *)(buf + 3);\n(*p)++;\nreturn 0;";
+    assert_eq!(run(incr, "alignment"), Err("MisalignedAccess".to_string()));
+
+    assert!(Sanitizers::parse("alignment,bogus").is_err());
+}
+
+#[test]
+fn signed_overflow_sanitizer() {
+    use crate::assembler::Sanitizers;
+
+    let run = |body: &str, sanitize: &str| {
+        let mut files = FileDb::new();
+        let source = format!("#include <limits.h>\nint main() {{\n{}\n}}\n", body);
+        files.add("main.c", &source).unwrap();
+        let sanitize = Sanitizers::parse(sanitize).unwrap();
+        let program = compile_sanitized(&files, sanitize).unwrap();
+
+        let mut runtime = Kernel::new(Vec::new());
+        return runtime.run(&program).map_err(|e| (e.short_name, e.message));
+    };
+
+    let overflow = |message: &str| Err(("SignedOverflow".to_string(), message.to_string()));
+
+    let add = "int a = INT_MAX;\nreturn a + 1 < 0;";
+    assert_eq!(run(add, ""), Ok(1));
+    let message = "2147483647 + 1 overflows a 32-bit signed integer";
+    assert_eq!(run(add, "signed-overflow"), overflow(message));
+
+    let mul = "long a = 1L << 62;\nreturn a * 2 < 0;";
+    let message = "4611686018427387904 * 2 overflows a 64-bit signed integer";
+    assert_eq!(run(mul, "signed-overflow"), overflow(message));
+
+    let neg = "int a = INT_MIN;\nreturn -a;";
+    let message = "-(-2147483648) overflows a 32-bit signed integer";
+    assert_eq!(run(neg, "signed-overflow"), overflow(message));
+
+    let div = "int a = INT_MIN, b = -1;\nreturn a % b;";
+    let message = "-2147483648 % -1 overflows a 32-bit signed integer";
+    assert_eq!(run(div, "signed-overflow"), overflow(message));
+    assert_eq!(run(div, ""), Ok(0));
+
+    let incr = "int a = INT_MAX;\na++;\nreturn 0;";
+    let message = "2147483647 + 1 overflows a 32-bit signed integer";
+    assert_eq!(run(incr, "signed-overflow"), overflow(message));
+
+    let fine = concat!(
+        "unsigned int u = UINT_MAX;\nu++;\n",
+        "int a = INT_MAX - 1;\na += 1;\n",
+        "char c = 127;\nc++;\n",
+        "return u + (a == INT_MAX) + (INT_MIN / 2 < 0);"
+    );
+    assert_eq!(run(fine, "signed-overflow"), Ok(2));
+}
+
+#[test]
+fn pointer_provenance_sanitizer() {
+    use crate::assembler::Sanitizers;
+
+    let run = |body: &str, sanitize: &str| {
+        let mut files = FileDb::new();
+        let source = format!("#include <stdlib.h>\nint main() {{\n{}\n}}\n", body);
+        files.add("main.c", &source).unwrap();
+        let sanitize = Sanitizers::parse(sanitize).unwrap();
+        let program = compile_sanitized(&files, sanitize).unwrap();
+
+        let mut runtime = Kernel::new(Vec::new());
+        return runtime.run(&program).map_err(|e| (e.short_name, e.message));
+    };
+
+    let provenance = |result: Result<i32, (String, String)>| match result {
+        Err((name, message)) => {
+            assert_eq!(name, "PointerProvenance");
+            message
+        }
+        Ok(status) => panic!("exited with {}", status),
+    };
+
+    // Moving back from the start of an object wraps into the one before it
+    let walk = "int a[2] = {1, 2};\nint b[2] = {3, 4};\nint *p = b - 3;\nreturn *(p + 3);";
+    assert_eq!(run(walk, ""), Ok(3));
+    let message = provenance(run(walk, "pointer-provenance"));
+    assert!(message.ends_with(
+        " - 12 bytes moves a pointer into a local variable so far that it points into a \
+         different object; pointers can only move around inside the object they point into"
+    ));
+
+    let index = "char *s = malloc(4);\nreturn s[-1];";
+    let message = provenance(run(index, "pointer-provenance"));
+    assert!(message.contains(" - 1 bytes moves a pointer into a block from malloc"));
+
+    let decr = "int a = 1;\nint *p = &a;\np--;\nreturn 0;";
+    let message = provenance(run(decr, "pointer-provenance"));
+    assert!(message.contains(" - 4 bytes moves a pointer into a local variable"));
+
+    let sub = "int a = 1, b = 2;\nreturn (int)(&b - &a);";
+    let message = provenance(run(sub, "pointer-provenance"));
+    assert!(message
+        .contains("point into different objects (a local variable and another local variable)"));
+
+    let compare = "static int g;\nchar *s = malloc(4);\nreturn (void *)s < (void *)&g;";
+    let message = provenance(run(compare, "pointer-provenance"));
+    assert!(message.contains("(a block from malloc and a global variable)"));
+
+    let fine = concat!(
+        "int a[4] = {1, 2, 3, 4};\nint *end = a + 4, *p = end;\n",
+        "while (p > a) p--;\n",
+        "char *s = \"abc\", *e = s;\nwhile (*e) e++;\n",
+        "return (end - a) + (e - s) + (a <= p) + (p != NULL) + *p;"
+    );
+    assert_eq!(run(fine, "pointer-provenance"), Ok(4 + 3 + 1 + 1 + 1));
+    assert!(
+        Sanitizers::parse("alignment,pointer-provenance")
+            .unwrap()
+            .pointer_provenance
+    );
+
+    // `va_arg` steps from one argument to the next, which are separate locals
+    let mut files = FileDb::new();
+    let source = "#include <stdio.h>\nint main() {\n  printf(\"%d %s %f\\n\", 5, \"x\", 1.5);\n}\n";
+    files.add("main.c", source).unwrap();
+    let sanitize = Sanitizers::parse("pointer-provenance").unwrap();
+    let program = compile_sanitized(&files, sanitize).unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    assert_eq!(runtime.run(&program).map_err(|e| e.message), Ok(0));
+    assert_eq!(runtime.term_out(), "5 x 1.500000\n");
+}
+
+#[test]
+fn pointer_provenance_fixtures() {
+    let sanitize = assembler::Sanitizers::parse("pointer-provenance").unwrap();
+    for name in FIXTURES {
+        // `dyn_array_add_from` subtracts pointers into two different arrays, to
+        // check that their types match, so the sanitizer is right to stop it
+        if *name == "dyn_array_ptr" {
+            continue;
+        }
+
+        let (files, out_path) = load_fixture(name);
+        test_file_sanitized(&files, out_path.as_deref(), sanitize);
+    }
+}
+
+#[test]
+fn constant_folding() {
+    let source = concat!(
+        "#include <stdio.h>\n",
+        "#define DEBUG 0\n",
+        "int classify(int x) {\n",
+        "  if (DEBUG) printf(\"classify %d\\n\", x);\n",
+        "  if (sizeof(int) == 4 && x > (1 << 4) - 6) return 2;\n",
+        "  return x < -(3 * 2) ? 0 : 1;\n",
+        "  printf(\"unreachable\\n\");\n",
+        "}\n",
+        "int main() {\n",
+        "  int total = 0;\n",
+        "  while (1 - 1) total += 100;\n",
+        "  for (int i = 0; i < 20; i++) {\n",
+        "    if (!(DEBUG || 0)) total += classify(i - 10);\n",
+        "    else { printf(\"never\\n\"); continue; }\n",
+        "  }\n",
+        "  unsigned int wrapped = 0u - 1;\n",
+        "  long big = (long)(1 << 30) * 4;\n",
+        "  printf(\"%d %u %ld %d\\n\", total, wrapped, big, (int)(char)300);\n",
+        "  return total % 7;\n",
+        "}\n"
+    );
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+
+    let mut options = CompileOptions::default();
+    let plain = compile_with_options(&files, &options).unwrap();
+    options.parse_flag("-O1").unwrap();
+    let optimized = compile_with_options(&files, &options).unwrap();
+    assert!(optimized.data.len() < plain.data.len());
+
+    let mut outputs = Vec::new();
+    for program in &[plain, optimized] {
+        let mut runtime = Kernel::new(Vec::new());
+        let code = runtime.run(program).unwrap();
+        outputs.push((code, runtime.term_out()));
+    }
+
+    assert_eq!(
+        outputs[0],
+        (16 % 7, "16 4294967295 4294967296 44\n".to_string())
+    );
+    assert_eq!(outputs[0], outputs[1]);
+
+    // Undefined behavior is left for the runtime to catch
+    let overflow = "#include <limits.h>\nint main() { return (INT_MAX + 1) / 0; }\n";
+    let mut files = FileDb::new();
+    files.add("main.c", overflow).unwrap();
+    let program = compile_with_options(&files, &options).unwrap();
+    let mut runtime = Kernel::new(Vec::new());
+    let err = runtime.run(&program).unwrap_err();
+    assert_eq!(err.short_name, "DivideByZero");
+
+    assert!(options.parse_flag("-O3").is_err());
+    assert!(options.parse_flag("--sanitize=alignment").is_ok());
+    assert!(options.sanitize.alignment);
+}
+
+fn peephole_rule(name: &str, code: Vec<Instr>) -> Vec<Instr> {
+    use crate::assembler::{peephole, PEEPHOLE_RULES};
+
+    let rule = PEEPHOLE_RULES
+        .iter()
+        .find(|rule| rule.name == name)
+        .unwrap();
+    return peephole(code, &[*rule]);
+}
+
+fn labeled(mut instr: Instr, label: u32) -> Instr {
+    instr.labels.push(label);
+    return instr;
+}
+
+#[test]
+fn peephole_push_pop() {
+    let code = vec![
+        Instr::with(Opcode::Make32, 7u32),
+        Instr::with(Opcode::Pop, 4u32),
+        Instr::with(Opcode::MakeFp, -2i16),
+        Instr::with(Opcode::Pop, 8u32),
+        Instr::new(Opcode::Ret),
+    ];
+    assert_eq!(
+        peephole_rule("push-pop", code),
+        vec![Instr::new(Opcode::Ret)]
+    );
+
+    // Popping a different number of bytes than were pushed isn't a no-op
+    let code = vec![
+        Instr::with(Opcode::Make32, 7u32),
+        Instr::with(Opcode::Pop, 8u32),
+        Instr::new(Opcode::Ret),
+    ];
+    assert_eq!(peephole_rule("push-pop", code.clone()), code);
+
+    // Neither is jumping straight to the pop
+    let code = vec![
+        Instr::with(Opcode::Make32, 7u32),
+        labeled(Instr::with(Opcode::Pop, 4u32), 0),
+        Instr::new(Opcode::Ret),
+    ];
+    assert_eq!(peephole_rule("push-pop", code.clone()), code);
+}
+
+#[test]
+fn peephole_merge_constants() {
+    let code = vec![
+        labeled(Instr::with(Opcode::Make32, 1u32), 3),
+        Instr::with(Opcode::Make32, 2u32),
+        Instr::new(Opcode::Ret),
+    ];
+
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&1u32.to_le_bytes());
+    bytes[4..].copy_from_slice(&2u32.to_le_bytes());
+    let expected = vec![
+        labeled(Instr::with(Opcode::Make64, u64::from_le_bytes(bytes)), 3),
+        Instr::new(Opcode::Ret),
+    ];
+    assert_eq!(peephole_rule("merge-constants", code), expected);
+
+    let code = vec![
+        Instr::with(Opcode::Make8, 1u8),
+        Instr::with(Opcode::Make8, 2u8),
+        Instr::with(Opcode::Make16, 3u16),
+        Instr::new(Opcode::Ret),
+    ];
+    let merged = peephole_rule("merge-constants", code);
+    assert_eq!(merged.len(), 2);
+    assert_eq!(merged[0].op, Opcode::Make32);
+    assert_eq!(merged[0].operand, vec![1, 2, 3, 0]);
+}
+
+#[test]
+fn peephole_jump_to_next() {
+    let jump = |op, label| Instr::with(op, VarPointer::new_binary(0, label));
+    let code = vec![
+        jump(Opcode::Jump, 1),
+        labeled(jump(Opcode::JumpIfZero32, 2), 1),
+        labeled(Instr::new(Opcode::Ret), 2),
+    ];
+    let expected = vec![
+        labeled(Instr::with(Opcode::Pop, 4u32), 1),
+        labeled(Instr::new(Opcode::Ret), 2),
+    ];
+    assert_eq!(peephole_rule("jump-to-next", code), expected);
+
+    let code = vec![
+        jump(Opcode::Jump, 1),
+        Instr::new(Opcode::Ret),
+        labeled(Instr::new(Opcode::Ret), 1),
+    ];
+    assert_eq!(peephole_rule("jump-to-next", code.clone()), code);
+}
+
+#[test]
+fn peephole_dead_loc() {
+    let loc = |start| CodeLoc {
+        start,
+        end: start + 1,
+        file: 0,
+    };
+    let code = vec![
+        labeled(Instr::with(Opcode::Loc, loc(0)), 0),
+        Instr::with(Opcode::Loc, loc(1)),
+        Instr::with(Opcode::Loc, loc(2)),
+        Instr::new(Opcode::Ret),
+    ];
+    let expected = vec![
+        labeled(Instr::with(Opcode::Loc, loc(2)), 0),
+        Instr::new(Opcode::Ret),
+    ];
+    assert_eq!(peephole_rule("dead-loc", code), expected);
+}
+
+#[test]
+fn peephole_store_without_dup() {
+    let code = vec![
+        Instr::with(Opcode::Make32, 5u32),
+        Instr::with(Opcode::Dup, 4u32),
+        Instr::with(Opcode::MakeFp, 0i16),
+        Instr::with(Opcode::Make64, 4u64),
+        Instr::new(Opcode::Add64),
+        Instr::with(Opcode::Set, 4u32),
+        Instr::with(Opcode::Pop, 4u32),
+        Instr::new(Opcode::Ret),
+    ];
+    let expected = vec![
+        Instr::with(Opcode::Make32, 5u32),
+        Instr::with(Opcode::MakeFp, 0i16),
+        Instr::with(Opcode::Make64, 4u64),
+        Instr::new(Opcode::Add64),
+        Instr::with(Opcode::Set, 4u32),
+        Instr::new(Opcode::Ret),
+    ];
+    assert_eq!(peephole_rule("store-without-dup", code), expected);
+
+    // The `Add64` here uses the copy of the value, so the copy is needed
+    let code = vec![
+        Instr::with(Opcode::Make64, 5u64),
+        Instr::with(Opcode::Dup, 8u32),
+        Instr::new(Opcode::Add64),
+        Instr::with(Opcode::MakeFp, 0i16),
+        Instr::with(Opcode::Set, 8u32),
+        Instr::with(Opcode::Pop, 8u32),
+        Instr::new(Opcode::Ret),
+    ];
+    assert_eq!(peephole_rule("store-without-dup", code.clone()), code);
+}
+
+#[test]
+fn peephole_program() {
+    let source = concat!(
+        "int main() {\n",
+        "  int a[4];\n",
+        "  int total = 0;\n",
+        "  for (int i = 0; i < 4; i++) a[i] = i * i;\n",
+        "  for (int i = 0; i < 4; i++) total += a[i];\n",
+        "  return total;\n",
+        "}\n"
+    );
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+
+    let mut options = CompileOptions::default();
+    let plain = compile_with_options(&files, &options).unwrap();
+    options.opt_level = 1;
+    let optimized = compile_with_options(&files, &options).unwrap();
+    assert!(optimized.data.len() < plain.data.len());
+
+    for program in &[plain, optimized] {
+        let mut runtime = Kernel::new(Vec::new());
+        assert_eq!(runtime.run(program).unwrap(), 14);
+    }
+}
+
+#[test]
+fn shared_stack_slots() {
+    let source = concat!(
+        "long chain(int n) {\n",
+        "  if (n == 0) return 0;\n",
+        "  long a = n; a = a * 2;\n",
+        "  long b = a + 1; b = b * 3;\n",
+        "  long c = b - 4; c = c / 2;\n",
+        "  long d = c + n; d = d % 1000;\n",
+        "  long e = d * 2; long g = e + 1; long h = g - 1; long k = h / 2;\n",
+        "  return k + chain(n - 1);\n",
+        "}\n",
+        "int loops() {\n",
+        "  int total;\n",
+        "  for (int i = 0; i < 10; i++) { int sq = i * i; int last; total += sq + last; last = i; }\n",
+        "  int after = total, z;\n",
+        "  int *p = &z;\n",
+        "  *p = after;\n",
+        "  return z;\n",
+        "}\n",
+        "int main() { return (chain(100) + loops()) % 256; }\n"
+    );
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let plain = compile(&files).unwrap();
+    let mut options = CompileOptions::default();
+    options.opt_level = 1;
+    let optimized = compile_with_options(&files, &options).unwrap();
+
+    let run = |program: &BinaryData, max_stack_bytes| {
+        let mut runtime = Kernel::new(Vec::new());
+        runtime.limits = Limits {
+            max_stack_bytes,
+            ..Limits::DEFAULT
+        };
+        return runtime.run(program).map_err(|e| e.short_name);
+    };
+
+    let expected = run(&plain, 1024 * 64);
+    assert!(expected.is_ok());
+    assert_eq!(run(&optimized, 1024 * 64), expected);
+
+    assert_eq!(run(&plain, 4096), Err("StackOverflow".to_string()));
+    assert_eq!(run(&optimized, 4096), expected);
+
+    // The temporaries for each inlined call are only live inside that call, so
+    // the ones in different calls share
+    let temps = concat!(
+        "long sq(long x) { return x * x; }\n",
+        "long sum(int n) {\n",
+        "  if (n == 0) return 0;\n",
+        "  return sq(n) + sq(n + 1) + sq(n + 2) + sq(n + 3) + sq(n + 4) + sq(n + 5)\n",
+        "    + sq(sq(n) % 7) + sum(n - 1);\n",
+        "}\n",
+        "int main() { return sum(100) % 256; }\n"
+    );
+
+    let mut files = FileDb::new();
+    files.add("main.c", temps).unwrap();
+    let plain = compile(&files).unwrap();
+    let optimized = compile_with_options(&files, &options).unwrap();
+    let expected = run(&plain, 1024 * 64);
+    assert!(expected.is_ok());
+    assert_eq!(run(&optimized, 1024 * 64), expected);
+    assert_eq!(run(&optimized, 3328), expected);
+}
+
+#[test]
+fn inline_small_functions() {
+    let source = concat!(
+        "#include <stdio.h>\n",
+        "int square(int x) { return x * x; }\n",
+        "long clamp(long x, long lo, long hi) { return x < lo ? lo : x > hi ? hi : x; }\n",
+        "int bump(int x) { x += 1; return x; }\n",
+        "int fact(int n) { return n <= 1 ? 1 : n * fact(n - 1); }\n",
+        "void show(int x) { printf(\"%d\\n\", x); }\n",
+        "int say(int x) { printf(\"%d \", x); return x; }\n",
+        "int sub(int a, int b) { return a * 2 + b; }\n",
+        "int main() {\n",
+        "  long sum = 0;\n",
+        "  for (int i = 0; i < 10; i++)\n",
+        "    sum += clamp(square(i), 4, 50) + bump(i);\n",
+        "  show(sum + fact(5));\n",
+        "  int i = 4;\n",
+        "  show(sub(i++, i++));\n",
+        "  show(sub(say(1), say(2)));\n",
+        "  return 0;\n",
+        "}\n"
+    );
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+
+    let run = |flags: &[&str]| {
+        let mut options = CompileOptions::default();
+        for flag in flags {
+            options.parse_flag(flag).unwrap();
+        }
+
+        let program = compile_with_options(&files, &options).unwrap();
+        let mut runtime = Kernel::new(Vec::new());
+        runtime.enable_profiling(host_clock);
+        assert_eq!(runtime.run(&program).unwrap(), 0);
+
+        let report = runtime.profile.as_ref().unwrap().report(&files);
+        return (runtime.term_out(), report);
+    };
+
+    // Arguments are evaluated right to left, whether or not the call is inlined
+    let expected = "422\n14\n2 1 4\n";
+    let (out, report) = run(&[]);
+    assert_eq!(out, expected);
+    assert!(report.contains("int square(int x)"));
+
+    let (out, report) = run(&["-O1"]);
+    assert_eq!(out, expected);
+    assert!(!report.contains("int square(int x)"));
+    assert!(!report.contains("long clamp("));
+    assert!(!report.contains("int sub("));
+    assert!(report.contains("int bump(int x)"));
+    assert!(report.contains("int fact(int n)"));
+    assert!(report.contains("void show(int x)"));
+
+    let (out, report) = run(&["-O1", "-fno-inline"]);
+    assert_eq!(out, expected);
+    assert!(report.contains("int square(int x)"));
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn native_backend() {
+    let run = |source: &str| {
+        let mut files = FileDb::new();
+        files.add("main.c", source).unwrap();
+
+        let mut options = CompileOptions::default();
+        let expected = match compile_program(&files, &options).unwrap() {
+            Program::Bytecode(program) => Kernel::new(Vec::new()).run(&program),
+            _ => panic!("the interpreter is the default"),
+        };
+
+        options.parse_flag("--backend=native").unwrap();
+        let program = match compile_program(&files, &options) {
+            Ok(Program::Native(program)) => program,
+            Ok(_) => panic!("asked for the native backend"),
+            Err(errs) => return Err(errs[0].message.clone()),
+        };
+
+        let mut host = native::NativeHost::default();
+        let result = program.run_with(&Limits::DEFAULT, &mut host);
+        let result = result.map_err(|e| e.short_name);
+        assert_eq!(result, expected.map_err(|e| e.short_name));
+
+        // `run_captured` runs the machine code too, and counts the same instructions
+        let captured = Program::Native(program).run_captured();
+        assert_eq!(captured.exit_code.map_err(|e| e.short_name), result);
+        assert_eq!(captured.stdout.as_bytes(), &host.stdout[..]);
+        assert_eq!(captured.instructions_executed, host.ops);
+        assert!(host.ops > 0);
+        if let Err(name) = &result {
+            assert!(
+                captured.stderr.starts_with(name.as_str()),
+                "{}",
+                captured.stderr
+            );
+        }
+
+        return Ok(result);
+    };
+
+    let program = concat!(
+        "struct Point { int x; long y; char c; };\n",
+        "unsigned char small = 250;\n",
+        "int fib(int n) { return n < 2 ? n : fib(n - 1) + fib(n - 2); }\n",
+        "struct Point make(int x) { struct Point p = { x, x * 2L, 'a' }; return p; }\n",
+        "long sum(int *arr, int len) {\n",
+        "  long total = 0;\n",
+        "  for (int i = 0; i < len; i++) total += arr[i];\n",
+        "  return total;\n",
+        "}\n",
+        "int main() {\n",
+        "  int arr[10];\n",
+        "  for (int i = 0; i < 10; i++) arr[i] = i * i - 20;\n",
+        "  struct Point p = make(7);\n",
+        "  int (*f)(int) = fib;\n",
+        "  short s = -300;\n",
+        "  unsigned u = 4000000000u;\n",
+        "  small += 10;\n",
+        "  long total = sum(arr, 10) + p.y + p.c + f(15) + s / 7 + s % 7;\n",
+        "  total += (u >> 3) % 1000 + small + (-17 >> 2) + (5 << 3) + (s < 0);\n",
+        "  return total;\n",
+        "}\n",
+    );
+    assert_eq!(run(program), Ok(Ok(798)));
+
+    let divide = "int main() { int zero = 0; return 5 / zero; }";
+    assert_eq!(run(divide), Ok(Err("DivideByZero".to_string())));
+
+    let recurse = "int f(int n) { return f(n + 1); }\nint main() { return f(0); }";
+    assert_eq!(run(recurse), Ok(Err("StackOverflow".to_string())));
+
+    let invalid = Ok(Err("InvalidPointer".to_string()));
+    let far = "int main() { int arr[2]; arr[200000000] = 5; return 0; }";
+    assert_eq!(run(far), invalid);
+
+    let past_end = "int main() { int arr[2]; int x = 1; return arr[2]; }";
+    assert_eq!(run(past_end), invalid);
+
+    let global = "long arr[3];\nint main() { long *p = arr; return *(int *)(p + 3); }";
+    assert_eq!(run(global), invalid);
+
+    let straddle = "int main() { char c[3]; return *(int *)c; }";
+    assert_eq!(run(straddle), invalid);
+
+    let big = "struct Big { char data[8192]; };\nstruct Big b;\nint main() { struct Big c = b; return 0; }";
+    let err = run(big).unwrap_err();
+    assert!(err.contains("larger than"), "{}", err);
+
+    let print = "#include <stdio.h>\nint main() { printf(\"%d %.2f\\n\", 3, 1.5); return 0; }";
+    assert_eq!(run(print), Ok(Ok(0)));
+
+    let floats = concat!(
+        "int main() {\n",
+        "  double d = 2.5, zero = 0.0, nan = zero / zero;\n",
+        "  float f = -1.25f;\n",
+        "  int total = (int)(d * 4) + (int)(f * 8) + (int)(d / f) + (int)(f - d);\n",
+        "  total += (d < f) + 2 * (f <= d) + 4 * (nan == nan) + 8 * (nan != nan);\n",
+        "  total += 16 * (nan < d) + 32 * ((float)d == 2.5f) + 64 * (d >= 2.5);\n",
+        "  total += (unsigned char)(d * 10) + (long)-d + (unsigned)(f + 3) + (long)(f * 1e9);\n",
+        "  return total;\n",
+        "}\n",
+    );
+    assert!(run(floats).unwrap().is_ok());
+
+    let heap = concat!(
+        "#include <stdlib.h>\n",
+        "int main() {\n",
+        "  int *p = malloc(10 * sizeof(int));\n",
+        "  for (int i = 0; i < 10; i++) p[i] = i * 3;\n",
+        "  int total = p[9] + p[4];\n",
+        "  free(p);\n",
+        "  return total;\n",
+        "}\n",
+    );
+    assert_eq!(run(heap), Ok(Ok(39)));
+
+    let freed = "#include <stdlib.h>\nint main() { int *p = malloc(4); free(p); return *p; }";
+    assert_eq!(run(freed), invalid);
+
+    let twice = "#include <stdlib.h>\nint main() { int *p = malloc(4); free(p); free(p); }";
+    assert_eq!(run(twice), Ok(Err("DoubleFree".to_string())));
+
+    // String literals are shared, so writing to one can't change the next one
+    let literal = concat!(
+        "#include <stdio.h>\n",
+        "int main() { char *s = \"abc\"; s[0] = 65; char *t = \"abc\"; printf(\"%s\\n\", t); }\n",
+    );
+    assert_eq!(run(literal), Ok(Err("ReadOnlyWrite".to_string())));
+
+    let mut options = CompileOptions::default();
+    assert!(options.parse_flag("--backend=jit").is_err());
+}
+
+#[test]
+fn native_hello_world() {
+    let mut files = FileDb::new();
+    files
+        .add("main.c", include_str!("../lib/test/hello_world.c"))
+        .unwrap();
+
+    let mut options = CompileOptions::default();
+    options.parse_flag("--backend=native").unwrap();
+    let program = match compile_program(&files, &options) {
+        Ok(Program::Native(program)) => program,
+        Ok(_) => panic!("asked for the native backend"),
+        Err(errs) => panic!("{}", errs[0].message),
+    };
+
+    let mut host = native::NativeHost::default();
+    let result = program.run_with(&Limits::DEFAULT, &mut host);
+    assert_eq!(result.map_err(|e| e.short_name), Ok(0));
+    assert_eq!(std::str::from_utf8(&host.stdout), Ok("Hello, world!\n"));
+    assert!(host.stderr.is_empty());
+}
+
+/// Every fixture that doesn't use files does the same thing natively
+#[test]
+fn native_fixtures() {
+    for name in FIXTURES {
+        let (files, _) = load_fixture(name);
+        let mut options = CompileOptions::default();
+        let mut kernel = Kernel::new(Vec::new());
+        let expected = match compile_program(&files, &options).unwrap() {
+            Program::Bytecode(program) => kernel.run(&program).map_err(|e| e.short_name),
+            _ => panic!("the interpreter is the default"),
+        };
+
+        options.parse_flag("--backend=native").unwrap();
+        let program = match compile_program(&files, &options) {
+            Ok(Program::Native(program)) => program,
+            Ok(_) => panic!("asked for the native backend"),
+            Err(errs) => panic!("{}: {}", name, errs[0].message),
+        };
+
+        let mut host = native::NativeHost::default();
+        let result = program.run_with(&Limits::DEFAULT, &mut host);
+        let result = result.map_err(|e| e.short_name);
+        if *name == "files" {
+            assert_eq!(result, Err("UnsupportedEcall".to_string()));
+            continue;
+        }
+
+        assert_eq!(result, expected, "{}", name);
+        assert_eq!(
+            String::from_utf8(host.stdout).unwrap(),
+            kernel.term_out(),
+            "{}",
+            name
+        );
+    }
+}
+
+struct WasmReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> WasmReader<'a> {
+    fn byte(&mut self) -> u8 {
+        assert!(
+            self.pos < self.bytes.len(),
+            "ran past the end at {}",
+            self.pos
+        );
+        self.pos += 1;
+        return self.bytes[self.pos - 1];
+    }
+
+    fn uleb(&mut self) -> u32 {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.byte();
+            value |= ((byte & 0x7F) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                assert!(value <= u32::MAX as u64, "{} doesn't fit in a u32", value);
+                return value as u32;
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> i64 {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.byte();
+            value |= ((byte & 0x7F) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+
+                return value;
+            }
+        }
+    }
+
+    fn name(&mut self) -> String {
+        let len = self.uleb() as usize;
+        let name = &self.bytes[self.pos..(self.pos + len)];
+        self.pos += len;
+        return String::from_utf8(name.to_vec()).unwrap();
+    }
+
+    fn val_type(&mut self) -> u8 {
+        let ty = self.byte();
+        assert!((0x7C..=0x7F).contains(&ty), "bad value type {:#x}", ty);
+        return ty;
+    }
+
+    /// An `i32.const` followed by `end`, the only initializers `wasm_emit` uses
+    fn const_expr(&mut self) -> i32 {
+        assert_eq!(self.byte(), 0x41);
+        let value = self.sleb() as i32;
+        assert_eq!(self.byte(), 0x0B);
+        return value;
+    }
+}
+
+struct WasmBody {
+    locals: usize,
+    code: Vec<u8>,
+    ends: HashMap<usize, usize>, // start of a block's body to just past its `end`
+    elses: HashMap<usize, usize>, // start of an `if`'s body to just past its `else`
+}
+
+#[derive(Debug, PartialEq)]
+enum WasmTrap {
+    Exit(i32),
+    Fault(u32),
+    Throw,
+    Trap(&'static str),
+}
+
+/// Just enough of a WebAssembly engine for the modules `wasm_emit` makes. Loading
+/// one checks its sections and index spaces, and `run` calls `_start` with a host
+/// that can write to stdout and stderr, read an empty stdin, tell the time, and exit.
+#[derive(Default)]
+struct WasmModule {
+    types: Vec<(usize, usize)>, // parameter and result counts
+    signatures: Vec<Vec<u8>>,
+    imports: Vec<String>,
+    funcs: Vec<u32>, // type of each function, imports first
+    bodies: Vec<std::rc::Rc<WasmBody>>,
+    table: Vec<Option<u32>>,
+    globals: Vec<u64>,
+    memory: Vec<u8>,
+    exports: HashMap<String, u32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl WasmModule {
+    fn load(module: &[u8]) -> Self {
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+        let mut wasm = Self::default();
+        let (mut reader, mut last) = (
+            WasmReader {
+                bytes: module,
+                pos: 8,
+            },
+            0,
+        );
+        while reader.pos < module.len() {
+            let id = reader.byte();
+            let len = reader.uleb() as usize;
+            assert!(id > last, "section {} came after section {}", id, last);
+            assert!(
+                reader.pos + len <= module.len(),
+                "section {} is too long",
+                id
+            );
+            last = id;
+
+            let end = reader.pos + len;
+            let mut section = WasmReader {
+                bytes: &module[..end],
+                pos: reader.pos,
+            };
+            wasm.section(id, &mut section);
+            assert_eq!(section.pos, end, "section {} has bytes left over", id);
+            reader.pos = end;
+        }
+
+        assert_eq!(wasm.bodies.len(), wasm.funcs.len() - wasm.imports.len());
+        return wasm;
+    }
+
+    fn section(&mut self, id: u8, reader: &mut WasmReader) {
+        let count = reader.uleb();
+        for _ in 0..count {
+            match id {
+                1 => {
+                    assert_eq!(reader.byte(), 0x60);
+                    let mut signature = Vec::new();
+                    let params = reader.uleb() as usize;
+                    signature.extend((0..params).map(|_| reader.val_type()));
+                    let results = reader.uleb() as usize;
+                    signature.extend((0..results).map(|_| reader.val_type()));
+                    self.types.push((params, results));
+                    self.signatures.push(signature);
+                }
+                2 => {
+                    assert_eq!(reader.name(), "env");
+                    let name = reader.name();
+                    assert_eq!(reader.byte(), 0x00, "{} isn't a function", name);
+                    self.func(reader.uleb());
+                    self.imports.push(name);
+                }
+                3 => self.func(reader.uleb()),
+                4 => {
+                    assert_eq!((reader.byte(), reader.byte()), (0x70, 0x00));
+                    self.table = vec![None; reader.uleb() as usize];
+                }
+                5 => {
+                    assert_eq!(reader.byte(), 0x00);
+                    self.memory = vec![0; reader.uleb() as usize * 0x10000];
+                }
+                6 => {
+                    reader.val_type();
+                    assert!(reader.byte() <= 1);
+                    self.globals.push(reader.const_expr() as u32 as u64);
+                }
+                7 => {
+                    let name = reader.name();
+                    let (kind, idx) = (reader.byte(), reader.uleb());
+                    match kind {
+                        0x00 => assert!((idx as usize) < self.funcs.len()),
+                        0x02 => assert!(idx == 0 && !self.memory.is_empty()),
+                        _ => panic!("unexpected export kind {}", kind),
+                    }
+
+                    assert!(self.exports.insert(name, idx).is_none());
+                }
+                9 => {
+                    assert_eq!(reader.byte(), 0x00);
+                    let offset = reader.const_expr() as usize;
+                    for idx in 0..(reader.uleb() as usize) {
+                        let func = reader.uleb();
+                        assert!((func as usize) < self.funcs.len());
+                        self.table[offset + idx] = Some(func);
+                    }
+                }
+                10 => {
+                    let len = reader.uleb() as usize;
+                    let end = reader.pos + len;
+                    let mut locals =
+                        self.types[self.funcs[self.imports.len() + self.bodies.len()] as usize].0;
+                    for _ in 0..reader.uleb() {
+                        locals += reader.uleb() as usize;
+                        reader.val_type();
+                    }
+
+                    let code = reader.bytes[reader.pos..end].to_vec();
+                    reader.pos = end;
+                    let body = self.check_body(code, locals);
+                    self.bodies.push(std::rc::Rc::new(body));
+                }
+                11 => {
+                    assert_eq!(reader.byte(), 0x00);
+                    let offset = reader.const_expr() as usize;
+                    let len = reader.uleb() as usize;
+                    let data = &reader.bytes[reader.pos..(reader.pos + len)];
+                    self.memory[offset..(offset + len)].copy_from_slice(data);
+                    reader.pos += len;
+                }
+                _ => panic!("unexpected section {}", id),
+            }
+        }
+    }
+
+    fn func(&mut self, ty: u32) {
+        assert!((ty as usize) < self.types.len(), "no type {}", ty);
+        self.funcs.push(ty);
+    }
+
+    /// Checks that every index in `code` is in range and that its blocks nest,
+    /// and finds where each block ends
+    fn check_body(&self, code: Vec<u8>, locals: usize) -> WasmBody {
+        let (mut ends, mut elses) = (HashMap::new(), HashMap::new());
+        let mut blocks: Vec<usize> = Vec::new();
+        let mut reader = WasmReader {
+            bytes: &code,
+            pos: 0,
+        };
+
+        loop {
+            let op = reader.byte();
+            match op {
+                0x02 | 0x03 | 0x04 => {
+                    let ty = reader.byte();
+                    assert!(ty == 0x40 || (0x7C..=0x7F).contains(&ty));
+                    blocks.push(reader.pos);
+                }
+                0x05 => {
+                    elses.insert(*blocks.last().unwrap(), reader.pos);
+                }
+                0x0B => match blocks.pop() {
+                    Some(start) => {
+                        ends.insert(start, reader.pos);
+                    }
+                    None => break,
+                },
+                0x0C | 0x0D => assert!(reader.uleb() as usize <= blocks.len()),
+                0x0E => {
+                    for _ in 0..=reader.uleb() {
+                        assert!(reader.uleb() as usize <= blocks.len());
+                    }
+                }
+                0x10 => assert!((reader.uleb() as usize) < self.funcs.len()),
+                0x11 => {
+                    assert!((reader.uleb() as usize) < self.types.len());
+                    assert_eq!(reader.byte(), 0);
+                }
+                0x20..=0x22 => assert!((reader.uleb() as usize) < locals),
+                0x23 | 0x24 => assert!((reader.uleb() as usize) < self.globals.len()),
+                0x28..=0x3E => {
+                    reader.uleb();
+                    reader.uleb();
+                }
+                0x41 | 0x42 => {
+                    reader.sleb();
+                }
+                0xFC => match reader.uleb() {
+                    0..=7 => {}
+                    0x0A => assert_eq!((reader.byte(), reader.byte()), (0, 0)),
+                    0x0B => assert_eq!(reader.byte(), 0),
+                    sub => panic!("unexpected instruction 0xFC {}", sub),
+                },
+                0x00 | 0x0F | 0x1A | 0x1B | 0x45..=0x66 | 0x6A..=0x78 | 0x7C..=0x8A => {}
+                0x92..=0x95 | 0xA0..=0xA3 | 0xA7 | 0xAC | 0xAD | 0xB2..=0xC1 => {}
+                _ => panic!("unexpected instruction {:#x}", op),
+            }
+        }
+
+        assert_eq!(reader.pos, code.len(), "code after the last `end`");
+        return WasmBody {
+            locals,
+            code,
+            ends,
+            elses,
+        };
+    }
+
+    /// Runs `_start`, returning the exit code
+    fn run(&mut self) -> Result<i32, WasmTrap> {
+        let start = self.exports["_start"];
+        return match self.call(start, &[]) {
+            Err(WasmTrap::Exit(code)) => Ok(code),
+            Err(trap) => Err(trap),
+            Ok(_) => Err(WasmTrap::Trap("returned without exiting")),
+        };
+    }
+
+    fn host(&mut self, func: u32, args: &[u64]) -> Result<Vec<u64>, WasmTrap> {
+        match self.imports[func as usize].as_str() {
+            "fault" => return Err(WasmTrap::Fault(args[0] as u32)),
+            "throw" => return Err(WasmTrap::Throw),
+            _ => {}
+        }
+
+        let sp = args[1] as usize;
+        let word = |wasm: &Self, at: usize, len: usize| {
+            let mut bytes = [0; 8];
+            bytes[..len].copy_from_slice(&wasm.memory[at..(at + len)]);
+            u64::from_le_bytes(bytes)
+        };
+
+        let (fd, buf, len, result_at) = match Ecall::from_u32(args[0] as u32) {
+            Some(Ecall::Exit) => return Err(WasmTrap::Exit(word(self, sp - 4, 4) as i32)),
+            Some(Ecall::Time) | Some(Ecall::CpuTime) => {
+                let time = if args[0] == Ecall::Time as u64 {
+                    VIRTUAL_EPOCH
+                } else {
+                    0
+                };
+                self.memory[sp..(sp + 8)].copy_from_slice(&time.to_le_bytes());
+                return Ok(vec![(sp + 8) as u64]);
+            }
+            Some(Ecall::ReadFd) | Some(Ecall::WriteFd) => (sp - 20, sp - 12, sp - 4, sp - 20),
+            Some(Ecall::AppendFd) => (sp - 16, sp - 12, sp - 4, sp - 16),
+            _ => return Err(WasmTrap::Trap("unsupported ecall")),
+        };
+
+        let (fd, buf, len) = (word(self, fd, 4), word(self, buf, 8), word(self, len, 4));
+        let addr = self.call(self.exports["decode"], &[buf])?[0] as usize;
+        let bytes = self.memory[addr..(addr + len as usize)].to_vec();
+        match fd {
+            0 if args[0] == Ecall::ReadFd as u64 => {} // stdin is always empty
+            1 => self.stdout.extend_from_slice(&bytes),
+            2 | 3 => self.stderr.extend_from_slice(&bytes),
+            _ => return Err(WasmTrap::Trap("bad file descriptor")),
+        }
+
+        self.memory[result_at..(result_at + 8)].copy_from_slice(&0u64.to_le_bytes());
+        return Ok(vec![(result_at + 8) as u64]);
+    }
+
+    fn call(&mut self, func: u32, args: &[u64]) -> Result<Vec<u64>, WasmTrap> {
+        if (func as usize) < self.imports.len() {
+            return self.host(func, args);
+        }
+
+        let body = self.bodies[func as usize - self.imports.len()].clone();
+        let results = self.types[self.funcs[func as usize] as usize].1;
+        let mut locals = args.to_vec();
+        locals.resize(body.locals, 0);
+
+        let (code, mut pc) = (&body.code, 0);
+        let mut stack: Vec<u64> = Vec::new();
+        let mut labels: Vec<(bool, usize, usize, usize)> = Vec::new(); // loop, target, height, arity
+        loop {
+            let mut reader = WasmReader {
+                bytes: code,
+                pos: pc + 1,
+            };
+            let op = code[pc];
+            let mut branch_depth = None;
+            match op {
+                0x00 => return Err(WasmTrap::Trap("unreachable")),
+                0x02 | 0x03 | 0x04 => {
+                    let arity = (reader.byte() != 0x40) as usize;
+                    let start = reader.pos;
+                    let is_loop = op == 0x03;
+                    let target = if is_loop { start } else { body.ends[&start] };
+                    if op == 0x04 && stack.pop().unwrap() as u32 == 0 {
+                        match body.elses.get(&start) {
+                            Some(&after_else) => reader.pos = after_else,
+                            None => {
+                                pc = target;
+                                continue;
+                            }
+                        }
+                    }
+
+                    let arity = if is_loop { 0 } else { arity };
+                    labels.push((is_loop, target, stack.len(), arity));
+                }
+                0x05 => branch_depth = Some(0),
+                0x0B => {
+                    if labels.pop().is_none() {
+                        return Ok(stack.split_off(stack.len() - results));
+                    }
+                }
+                0x0C => branch_depth = Some(reader.uleb()),
+                0x0D => {
+                    let depth = reader.uleb();
+                    if stack.pop().unwrap() as u32 != 0 {
+                        branch_depth = Some(depth);
+                    }
+                }
+                0x0E => {
+                    let targets: Vec<u32> = (0..=reader.uleb()).map(|_| reader.uleb()).collect();
+                    let idx = (stack.pop().unwrap() as u32 as usize).min(targets.len() - 1);
+                    branch_depth = Some(targets[idx]);
+                }
+                0x0F => return Ok(stack.split_off(stack.len() - results)),
+                0x10 | 0x11 => {
+                    let (callee, ty) = match op {
+                        0x10 => {
+                            let callee = reader.uleb();
+                            (callee, self.funcs[callee as usize])
+                        }
+                        _ => {
+                            let ty = reader.uleb();
+                            reader.byte();
+                            let idx = stack.pop().unwrap() as u32 as usize;
+                            let callee = match self.table.get(idx) {
+                                Some(Some(callee)) => *callee,
+                                _ => return Err(WasmTrap::Trap("undefined element")),
+                            };
+
+                            let actual = &self.signatures[self.funcs[callee as usize] as usize];
+                            if *actual != self.signatures[ty as usize] {
+                                return Err(WasmTrap::Trap("indirect call type mismatch"));
+                            }
+
+                            (callee, ty)
+                        }
+                    };
+
+                    let args = stack.split_off(stack.len() - self.types[ty as usize].0);
+                    let values = self.call(callee, &args)?;
+                    stack.extend(values);
+                }
+                0x1A => {
+                    stack.pop().unwrap();
+                }
+                0x1B => {
+                    let (cond, b, a) = (
+                        stack.pop().unwrap(),
+                        stack.pop().unwrap(),
+                        stack.pop().unwrap(),
+                    );
+                    stack.push(if cond as u32 != 0 { a } else { b });
+                }
+                0x20 => stack.push(locals[reader.uleb() as usize]),
+                0x21 => locals[reader.uleb() as usize] = stack.pop().unwrap(),
+                0x22 => locals[reader.uleb() as usize] = *stack.last().unwrap(),
+                0x23 => stack.push(self.globals[reader.uleb() as usize]),
+                0x24 => self.globals[reader.uleb() as usize] = stack.pop().unwrap(),
+                0x28..=0x35 => {
+                    reader.uleb();
+                    let addr = stack.pop().unwrap() as u32 as usize + reader.uleb() as usize;
+                    let (len, signed, wide) = match op {
+                        0x28 => (4, false, false),
+                        0x29 => (8, false, true),
+                        0x2C | 0x2D => (1, op == 0x2C, false),
+                        0x2E | 0x2F => (2, op == 0x2E, false),
+                        0x30 | 0x31 => (1, op == 0x30, true),
+                        0x32 | 0x33 => (2, op == 0x32, true),
+                        0x34 | 0x35 => (4, op == 0x34, true),
+                        _ => panic!("unexpected load {:#x}", op),
+                    };
+
+                    let bytes = match self.memory.get(addr..(addr + len)) {
+                        Some(bytes) => bytes,
+                        None => return Err(WasmTrap::Trap("out of bounds load")),
+                    };
+
+                    let mut value = [0; 8];
+                    value[..len].copy_from_slice(bytes);
+                    let mut value = u64::from_le_bytes(value);
+                    if signed {
+                        let shift = 64 - len * 8;
+                        value = ((value << shift) as i64 >> shift) as u64;
+                    }
+
+                    stack.push(if wide { value } else { value as u32 as u64 });
+                }
+                0x36 | 0x37 | 0x3A | 0x3B => {
+                    reader.uleb();
+                    let value = stack.pop().unwrap();
+                    let addr = stack.pop().unwrap() as u32 as usize + reader.uleb() as usize;
+                    let len = match op {
+                        0x36 => 4,
+                        0x37 => 8,
+                        0x3A => 1,
+                        _ => 2,
+                    };
+
+                    match self.memory.get_mut(addr..(addr + len)) {
+                        Some(bytes) => bytes.copy_from_slice(&value.to_le_bytes()[..len]),
+                        None => return Err(WasmTrap::Trap("out of bounds store")),
+                    }
+                }
+                0x41 => stack.push(reader.sleb() as i32 as u32 as u64),
+                0x42 => stack.push(reader.sleb() as u64),
+                0xFC => match reader.uleb() {
+                    sub @ 0x0A..=0x0B => {
+                        reader.byte();
+                        if sub == 0x0A {
+                            reader.byte();
+                        }
+
+                        // `memory.copy` takes a source where `memory.fill` takes a byte
+                        let len = stack.pop().unwrap() as u32 as usize;
+                        let value = stack.pop().unwrap() as u32 as usize;
+                        let dest = stack.pop().unwrap() as u32 as usize;
+                        let src_end = if sub == 0x0A { value + len } else { 0 };
+                        if dest + len > self.memory.len() || src_end > self.memory.len() {
+                            return Err(WasmTrap::Trap("out of bounds memory access"));
+                        }
+
+                        match sub {
+                            0x0A => self.memory.copy_within(value..src_end, dest),
+                            _ => self.memory[dest..(dest + len)].fill(value as u8),
+                        }
+                    }
+                    sub => {
+                        let value = stack.pop().unwrap();
+                        let (f32, f64) = (f32::from_bits(value as u32), f64::from_bits(value));
+                        stack.push(match sub {
+                            0 => f32 as i32 as u32 as u64,
+                            1 => f32 as u32 as u64,
+                            2 => f64 as i32 as u32 as u64,
+                            3 => f64 as u32 as u64,
+                            4 => f32 as i64 as u64,
+                            5 => f32 as u64,
+                            6 => f64 as i64 as u64,
+                            _ => f64 as u64,
+                        });
+                    }
+                },
+                _ => {
+                    let value = wasm_numeric(op, &mut stack)?;
+                    stack.push(value);
+                }
+            }
+
+            pc = reader.pos;
+            if let Some(depth) = branch_depth {
+                let depth = depth as usize;
+                if depth == labels.len() {
+                    return Ok(stack.split_off(stack.len() - results));
+                }
+
+                let (is_loop, target, height, arity) = labels[labels.len() - 1 - depth];
+                let values = stack.split_off(stack.len() - arity);
+                stack.truncate(height);
+                stack.extend(values);
+                labels.truncate(labels.len() - depth - (!is_loop) as usize);
+                pc = target;
+            }
+        }
+    }
+}
+
+/// Runs one of the numeric instructions, which all pop their operands and push
+/// one result
+fn wasm_numeric(op: u8, stack: &mut Vec<u64>) -> Result<u64, WasmTrap> {
+    let unary = match op {
+        0x45 | 0x50 | 0xA7..=0xC1 => true,
+        _ => false,
+    };
+
+    let b = stack.pop().unwrap();
+    let a = if unary { b } else { stack.pop().unwrap() };
+    let (a32, b32) = (a as u32, b as u32);
+    let (f32a, f32b) = (f32::from_bits(a32), f32::from_bits(b32));
+    let (f64a, f64b) = (f64::from_bits(a), f64::from_bits(b));
+    let trap = |message| Err(WasmTrap::Trap(message));
+
+    return Ok(match op {
+        0x45 => (a32 == 0) as u64,
+        0x46 => (a32 == b32) as u64,
+        0x47 => (a32 != b32) as u64,
+        0x48 => ((a32 as i32) < b32 as i32) as u64,
+        0x49 => (a32 < b32) as u64,
+        0x4A => (a32 as i32 > b32 as i32) as u64,
+        0x4B => (a32 > b32) as u64,
+        0x4C => (a32 as i32 <= b32 as i32) as u64,
+        0x4D => (a32 <= b32) as u64,
+        0x4E => (a32 as i32 >= b32 as i32) as u64,
+        0x4F => (a32 >= b32) as u64,
+        0x50 => (a == 0) as u64,
+        0x51 => (a == b) as u64,
+        0x52 => (a != b) as u64,
+        0x53 => ((a as i64) < b as i64) as u64,
+        0x54 => (a < b) as u64,
+        0x55 => (a as i64 > b as i64) as u64,
+        0x56 => (a > b) as u64,
+        0x57 => (a as i64 <= b as i64) as u64,
+        0x58 => (a <= b) as u64,
+        0x59 => (a as i64 >= b as i64) as u64,
+        0x5A => (a >= b) as u64,
+        0x5B => (f32a == f32b) as u64,
+        0x5C => (f32a != f32b) as u64,
+        0x5D => (f32a < f32b) as u64,
+        0x5E => (f32a > f32b) as u64,
+        0x5F => (f32a <= f32b) as u64,
+        0x60 => (f32a >= f32b) as u64,
+        0x61 => (f64a == f64b) as u64,
+        0x62 => (f64a != f64b) as u64,
+        0x63 => (f64a < f64b) as u64,
+        0x64 => (f64a > f64b) as u64,
+        0x65 => (f64a <= f64b) as u64,
+        0x66 => (f64a >= f64b) as u64,
+
+        0x6D | 0x6E | 0x6F | 0x70 if b32 == 0 => return trap("integer divide by zero"),
+        0x6D if a32 as i32 == i32::MIN && b32 as i32 == -1 => return trap("integer overflow"),
+        0x6A..=0x78 => {
+            (match op {
+                0x6A => a32.wrapping_add(b32),
+                0x6B => a32.wrapping_sub(b32),
+                0x6C => a32.wrapping_mul(b32),
+                0x6D => (a32 as i32 / b32 as i32) as u32,
+                0x6E => a32 / b32,
+                0x6F => (a32 as i32).wrapping_rem(b32 as i32) as u32,
+                0x70 => a32 % b32,
+                0x71 => a32 & b32,
+                0x72 => a32 | b32,
+                0x73 => a32 ^ b32,
+                0x74 => a32.wrapping_shl(b32),
+                0x75 => (a32 as i32).wrapping_shr(b32) as u32,
+                0x76 => a32.wrapping_shr(b32),
+                0x77 => a32.rotate_left(b32 % 32),
+                _ => a32.rotate_right(b32 % 32),
+            }) as u64
+        }
+
+        0x7F | 0x80 | 0x81 | 0x82 if b == 0 => return trap("integer divide by zero"),
+        0x7F if a as i64 == i64::MIN && b as i64 == -1 => return trap("integer overflow"),
+        0x7C => a.wrapping_add(b),
+        0x7D => a.wrapping_sub(b),
+        0x7E => a.wrapping_mul(b),
+        0x7F => (a as i64 / b as i64) as u64,
+        0x80 => a / b,
+        0x81 => (a as i64).wrapping_rem(b as i64) as u64,
+        0x82 => a % b,
+        0x83 => a & b,
+        0x84 => a | b,
+        0x85 => a ^ b,
+        0x86 => a.wrapping_shl(b as u32),
+        0x87 => (a as i64).wrapping_shr(b as u32) as u64,
+        0x88 => a.wrapping_shr(b as u32),
+        0x89 => a.rotate_left((b % 64) as u32),
+        0x8A => a.rotate_right((b % 64) as u32),
+
+        0x92 => (f32a + f32b).to_bits() as u64,
+        0x93 => (f32a - f32b).to_bits() as u64,
+        0x94 => (f32a * f32b).to_bits() as u64,
+        0x95 => (f32a / f32b).to_bits() as u64,
+        0xA0 => (f64a + f64b).to_bits(),
+        0xA1 => (f64a - f64b).to_bits(),
+        0xA2 => (f64a * f64b).to_bits(),
+        0xA3 => (f64a / f64b).to_bits(),
+
+        0xA7 => a32 as u64,
+        0xAC => a32 as i32 as i64 as u64,
+        0xAD => a32 as u64,
+        0xB2 => (a32 as i32 as f32).to_bits() as u64,
+        0xB3 => (a32 as f32).to_bits() as u64,
+        0xB4 => (a as i64 as f32).to_bits() as u64,
+        0xB5 => (a as f32).to_bits() as u64,
+        0xB6 => (f64a as f32).to_bits() as u64,
+        0xB7 => (a32 as i32 as f64).to_bits(),
+        0xB8 => (a32 as f64).to_bits(),
+        0xB9 => (a as i64 as f64).to_bits(),
+        0xBA => (a as f64).to_bits(),
+        0xBB => (f32a as f64).to_bits(),
+        0xBC | 0xBE => a32 as u64,
+        0xBD | 0xBF => a,
+        0xC0 => a32 as u8 as i8 as i32 as u32 as u64,
+        0xC1 => a32 as u16 as i16 as i32 as u32 as u64,
+        _ => panic!("unexpected instruction {:#x}", op),
+    });
+}
+
+#[test]
+fn wasm_backend() {
+    let compile = |source: &str, flags: &[&str]| {
+        let mut files = FileDb::new();
+        files.add("main.c", source).unwrap();
+
+        let mut options = CompileOptions::default();
+        options.parse_flag("--emit=wasm").unwrap();
+        for flag in flags {
+            options.parse_flag(flag).unwrap();
+        }
+
+        return match compile_program(&files, &options) {
+            Ok(Program::Wasm(module)) => Ok(module),
+            Ok(_) => panic!("asked for a WebAssembly module"),
+            Err(errs) => Err(errs[0].message.clone()),
+        };
+    };
+
+    let source = concat!(
+        "#include <stdio.h>\n",
+        "#include <stdlib.h>\n",
+        "int fib(int n) { return n < 2 ? n : fib(n - 1) + fib(n - 2); }\n",
+        "int main() {\n",
+        "  int *arr = malloc(4 * sizeof(int));\n",
+        "  arr[0] = fib(10);\n",
+        "  printf(\"%d %f\\n\", arr[0], arr[0] / 2.0);\n",
+        "  free(arr);\n",
+        "  return 0;\n",
+        "}\n",
+    );
+    let mut wasm = WasmModule::load(&compile(source, &[]).unwrap());
+    assert_eq!(wasm.imports, ["ecall", "fault", "throw"]);
+    for export in &["memory", "decode", "_start"] {
+        assert!(wasm.exports.contains_key(*export), "{}", export);
+    }
+
+    assert_eq!(wasm.run(), Ok(0));
+    assert_eq!(String::from_utf8(wasm.stdout).unwrap(), "55 27.500000\n");
+
+    let hello_world = include_str!("../lib/test/hello_world.c");
+    let mut wasm = WasmModule::load(&compile(hello_world, &[]).unwrap());
+    assert_eq!(wasm.run(), Ok(0));
+    assert_eq!(String::from_utf8(wasm.stdout).unwrap(), "Hello, world!\n");
+    assert!(wasm.stderr.is_empty());
+
+    let literal = concat!(
+        "#include <stdio.h>\n",
+        "int main() { char *s = \"abc\"; s[0] = 65; char *t = \"abc\"; printf(\"%s\\n\", t); }\n",
+    );
+    let mut wasm = WasmModule::load(&compile(literal, &[]).unwrap());
+    assert_eq!(wasm.run(), Err(WasmTrap::Fault(native::READ_ONLY_WRITE)));
+    assert!(wasm.stdout.is_empty());
+
+    let err = compile(source, &["--sanitize=alignment"]).unwrap_err();
+    assert!(err.contains("WebAssembly backend"), "{}", err);
+
+    // Every fixture that doesn't use files does the same thing as in the interpreter
+    for name in FIXTURES {
+        let (files, _) = load_fixture(name);
+        let mut kernel = Kernel::new(Vec::new());
+        let expected = kernel.run(&crate::compile(&files).unwrap());
+        let mut options = CompileOptions::default();
+        options.parse_flag("--emit=wasm").unwrap();
+        let mut wasm = match compile_program(&files, &options) {
+            Ok(Program::Wasm(module)) => WasmModule::load(&module),
+            Ok(_) => panic!("asked for a WebAssembly module"),
+            Err(errs) => panic!("{}: {}", name, errs[0].message),
+        };
+
+        let result = wasm.run();
+        if *name == "files" {
+            assert_eq!(result, Err(WasmTrap::Trap("unsupported ecall")));
+            continue;
+        }
+
+        assert_eq!(result, Ok(expected.unwrap()), "{}", name);
+        assert_eq!(
+            String::from_utf8(wasm.stdout).unwrap(),
+            kernel.term_out(),
+            "{}",
+            name
+        );
+    }
+
+    let mut options = CompileOptions::default();
+    assert!(options.parse_flag("--emit=exe").is_err());
+    options.parse_flag("--emit=bytecode").unwrap();
+    assert_eq!(options.backend, native::Backend::Interpreter);
+}
+
+/// Compiles and runs `source` `runs` times, printing the fastest wall-clock time.
+/// The `bench_*` tests are ignored by default; run them with
+/// `cargo test bench_ -- --ignored --nocapture` to see the numbers.
+fn bench(name: &str, source: &str, runs: u32) -> std::time::Duration {
+    use std::time::Instant;
+
+    let mut files = FileDb::new();
+    files.add("main.c", source).unwrap();
+    let program = compile(&files).unwrap();
+
+    let mut best = None;
+    for _ in 0..runs {
+        let mut runtime = Kernel::new(Vec::new());
+        let start = Instant::now();
+        let code = runtime.run(&program);
+        let elapsed = start.elapsed();
+        assert_eq!(code.unwrap(), 0);
+
+        best = Some(best.map_or(elapsed, |b: std::time::Duration| b.min(elapsed)));
+    }
+
+    let best = best.unwrap();
+    std::println!("bench {}: {:?} (best of {})", name, best, runs);
+    return best;
+}
+
+#[test]
+#[ignore]
+fn bench_tight_loop() {
+    let source = "int main() {\n  int sum = 0;\n  for (int i = 0; i < 100000; i++)\n    sum += i & 7;\n  return sum != 350000;\n}\n";
+    bench("tight_loop", source, 3);
+}
+
+#[test]
+#[ignore]
+fn bench_calls() {
+    let source = "int add(int a, int b) { return a + b; }\nint main() {\n  int sum = 0;\n  for (int i = 0; i < 20000; i++)\n    sum = add(sum, 1);\n  return sum != 20000;\n}\n";
+    bench("calls", source, 3);
+}
+
 // gen_test_runtime_should_fail!((stack_locals, "InvalidPointer"));
 //
 //
@@ -208,9 +5981,11 @@ impl AllocMonitor for TestMonitor {
 
 pub static TEST_MONITOR: TestMonitor = TestMonitor::new();
 
+// wee_alloc never returns freed memory to the OS, so running the whole suite in
+// one process would keep every test's peak allocation around until the end.
 #[global_allocator]
-static GLOBAL: InterAlloc<wee_alloc::WeeAlloc, TestMonitor> = InterAlloc {
-    inner: wee_alloc::WeeAlloc::INIT,
+static GLOBAL: InterAlloc<std::alloc::System, TestMonitor> = InterAlloc {
+    inner: std::alloc::System,
     monitor: &TEST_MONITOR,
 };
 
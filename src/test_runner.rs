@@ -0,0 +1,347 @@
+//! Runs a directory of C programs against their expected output, behind
+//! `tci test dir/`. Each `name.c` is compiled and run on its own; `name.in`, if
+//! present, is its stdin, and its stdout has to match `name.out` (or
+//! `name.c.out`, like the fixtures in `lib/test`). Headers are found the same
+//! way `tci a.c` finds them; see `Headers`. The summary comes out as TAP
+//! or as JUnit XML, so TCI can sit at the core of an autograder.
+
+use crate::filedb::*;
+use crate::runtime::*;
+use crate::util::*;
+use crate::{compile_with_options, emit_err_with, emit_warnings, program_warnings};
+use crate::{CompileOptions, EmitConfig};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+    pub name: String, // file name of the source, e.g. `hello.c`
+    pub path: String, // where the source is, which quoted includes are relative to
+    pub source: Vec<u8>,
+    pub stdin: Option<String>,
+    pub expected: Option<String>, // without one, the program only has to exit with 0
+}
+
+/// Where the headers that tests include come from, like `tci a.c` gets them:
+/// next to the test, then `-I` directories, then `include_path`.
+pub struct Headers<'a> {
+    pub include_path: &'a str, // `:`-separated, like `TCI_INCLUDE_PATH`
+    pub read: &'a dyn Fn(&str) -> Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestOutcome {
+    Pass,
+    CompileError(String),
+    RuntimeError(String),
+    NonzeroExit(i32),
+    WrongOutput { expected: String, actual: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: TestOutcome,
+    pub stdout: String,
+    pub warnings: String, // rendered, like `emit_warnings` does
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestSummary {
+    pub results: Vec<TestResult>,
+}
+
+/// Groups the files of the directory `dir` into test cases, one per `.c` file,
+/// sorted by name. Files that don't belong to a `.c` file are ignored.
+pub fn cases_from_dir<S: AsRef<[u8]>>(dir: &str, entries: &[(&str, S)]) -> Vec<TestCase> {
+    let find = |name: &str| {
+        entries
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, s)| String::from_utf8_lossy(s.as_ref()).into_owned())
+    };
+
+    let mut cases = Vec::new();
+    for (name, source) in entries {
+        if !name.ends_with(".c") {
+            continue;
+        }
+
+        let stem = &name[..name.len() - ".c".len()];
+        let expected = find(&format!("{}.out", stem)).or_else(|| find(&format!("{}.out", name)));
+        let path = match dir.trim_end_matches('/') {
+            "" if dir.starts_with('/') => format!("/{}", name),
+            "" => name.to_string(),
+            dir => format!("{}/{}", dir, name),
+        };
+
+        cases.push(TestCase {
+            name: name.to_string(),
+            path,
+            source: source.as_ref().to_vec(),
+            stdin: find(&format!("{}.in", stem)),
+            expected,
+        });
+    }
+
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+    return cases;
+}
+
+/// Adds the case and the headers it includes to `files`
+fn load(
+    files: &mut FileDb,
+    case: &TestCase,
+    options: &CompileOptions,
+    headers: &Headers,
+) -> Result<(), Error> {
+    files.add_include_paths(headers.include_path);
+
+    let index = files.files.len();
+    files.add_bytes(&case.path, &case.source)?;
+
+    let mut dirs: Vec<String> = options.include_paths.clone();
+    dirs.extend(files.include_paths.iter().cloned());
+    return files.add_includes(index, &dirs, headers.read);
+}
+
+/// Compiles and runs one case. `limits` keeps a program that never finishes from
+/// hanging the whole run.
+pub fn run_case(
+    case: &TestCase,
+    options: &CompileOptions,
+    limits: Limits,
+    headers: &Headers,
+) -> TestResult {
+    let config = EmitConfig::new(options, false);
+    let compile_error = |errs: &[Error], files: &FileDb| {
+        let mut out = StringWriter::new();
+        emit_err_with(errs, files, &config, &mut out);
+        return TestResult {
+            name: case.name.clone(),
+            outcome: TestOutcome::CompileError(out.into_string()),
+            stdout: String::new(),
+            warnings: String::new(),
+        };
+    };
+
+    let mut files = FileDb::new();
+    if let Err(err) = load(&mut files, case, options, headers) {
+        return compile_error(&[err], &files);
+    }
+
+    let program = match compile_with_options(&files, options) {
+        Ok(program) => program,
+        Err(errs) => return compile_error(&errs, &files),
+    };
+
+    let config = EmitConfig::new(options, false);
+    let program = match compile_with_options(&files, options) {
+        Ok(program) => program,
+        Err(errs) => {
+            let mut out = StringWriter::new();
+            emit_err_with(&errs, &files, &config, &mut out);
+            return TestResult {
+                name: case.name.clone(),
+                outcome: TestOutcome::CompileError(out.into_string()),
+                stdout: String::new(),
+                warnings: String::new(),
+            };
+        }
+    };
+
+    let mut warnings = StringWriter::new();
+    emit_warnings(
+        program_warnings(&files, options),
+        &files,
+        &config,
+        &mut warnings,
+    );
+
+    let mut kernel = Kernel::new(Vec::new());
+    kernel.limits = limits;
+    let proc_id = kernel.load_term_program(&program);
+    if let Some(stdin) = &case.stdin {
+        kernel.write_str(stdin).unwrap();
+    }
+
+    let status = loop {
+        if let Some(code) = kernel.exit_status(proc_id) {
+            break Ok(code);
+        }
+
+        if kernel.active_count == 0 {
+            break Err(ierror!("Deadlock", "every process is blocked"));
+        }
+
+        if let Err(err) = kernel.run_op_count(!0) {
+            if kernel.current_proc == proc_id {
+                break Err(err);
+            }
+        }
+    };
+
+    // Only stdout counts; the terminal also echoes stdin and shows stderr
+    let mut stdout = StringWriter::new();
+    for TE(tag, s) in &kernel.events() {
+        if let WriteEvt::StdoutWrite = tag {
+            write_utf8_lossy(&mut stdout, s).unwrap();
+        }
+    }
+    let stdout = stdout.into_string();
+
+    let outcome = match status {
+        Err(err) => {
+            let message = format!("{}: {}", err.short_name, err.message);
+            TestOutcome::RuntimeError(message)
+        }
+        Ok(0) => match &case.expected {
+            Some(expected) if stdout != expected.replace("\r\n", "\n") => {
+                TestOutcome::WrongOutput {
+                    expected: expected.replace("\r\n", "\n"),
+                    actual: stdout.clone(),
+                }
+            }
+            _ => TestOutcome::Pass,
+        },
+        Ok(code) => TestOutcome::NonzeroExit(code),
+    };
+
+    return TestResult {
+        name: case.name.clone(),
+        outcome,
+        stdout,
+        warnings: warnings.into_string(),
+    };
+}
+
+pub fn run_tests(
+    cases: &[TestCase],
+    options: &CompileOptions,
+    limits: Limits,
+    headers: &Headers,
+) -> TestSummary {
+    let results = cases
+        .iter()
+        .map(|case| run_case(case, options, limits, headers))
+        .collect();
+    return TestSummary { results };
+}
+
+impl TestOutcome {
+    /// One line describing why the test failed, or `None` if it passed
+    pub fn reason(&self) -> Option<String> {
+        return match self {
+            TestOutcome::Pass => None,
+            TestOutcome::CompileError(_) => Some("failed to compile".to_string()),
+            TestOutcome::RuntimeError(err) => Some(err.clone()),
+            TestOutcome::NonzeroExit(code) => Some(format!("exited with code {}", code)),
+            TestOutcome::WrongOutput { .. } => Some("output didn't match".to_string()),
+        };
+    }
+
+    fn details(&self) -> Option<String> {
+        return match self {
+            TestOutcome::CompileError(errs) => Some(errs.clone()),
+            TestOutcome::WrongOutput { expected, actual } => {
+                Some(format!("expected: {:?}\nactual:   {:?}", expected, actual))
+            }
+            _ => None,
+        };
+    }
+}
+
+impl TestSummary {
+    pub fn passed(&self) -> usize {
+        let passed = self
+            .results
+            .iter()
+            .filter(|r| r.outcome == TestOutcome::Pass);
+        return passed.count();
+    }
+
+    pub fn failed(&self) -> usize {
+        return self.results.len() - self.passed();
+    }
+
+    /// The summary in the Test Anything Protocol, with failure details as YAML
+    /// diagnostics
+    pub fn to_tap(&self) -> String {
+        let mut out = format!("TAP version 13\n1..{}\n", self.results.len());
+        for (idx, result) in self.results.iter().enumerate() {
+            let reason = match result.outcome.reason() {
+                None => {
+                    out += &format!("ok {} - {}\n", idx + 1, result.name);
+                    continue;
+                }
+                Some(reason) => reason,
+            };
+
+            out += &format!("not ok {} - {}\n", idx + 1, result.name);
+            out += &format!("  ---\n  message: {:?}\n", reason);
+            if let Some(details) = result.outcome.details() {
+                out += "  details: |\n";
+                for line in details.lines() {
+                    out += &format!("    {}\n", line);
+                }
+            }
+
+            out += "  ...\n";
+        }
+
+        return out;
+    }
+
+    /// The summary as a JUnit XML report, which most CI systems can display
+    pub fn to_junit(&self) -> String {
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        out += &format!(
+            "<testsuite name=\"tci\" tests=\"{}\" failures=\"{}\">\n",
+            self.results.len(),
+            self.failed()
+        );
+
+        for result in &self.results {
+            let name = xml_escape(&result.name);
+            let reason = match result.outcome.reason() {
+                None => {
+                    out += &format!("  <testcase name=\"{}\"/>\n", name);
+                    continue;
+                }
+                Some(reason) => reason,
+            };
+
+            out += &format!("  <testcase name=\"{}\">\n", name);
+            let message = xml_escape(&reason);
+            match result.outcome.details() {
+                Some(details) => {
+                    let details = xml_escape(&details);
+                    out += &format!(
+                        "    <failure message=\"{}\">{}</failure>\n",
+                        message, details
+                    );
+                }
+                None => out += &format!("    <failure message=\"{}\"/>\n", message),
+            }
+
+            out += "  </testcase>\n";
+        }
+
+        out += "</testsuite>\n";
+        return out;
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '&' => out += "&amp;",
+            '<' => out += "&lt;",
+            '>' => out += "&gt;",
+            '"' => out += "&quot;",
+            '\'' => out += "&apos;",
+            c => out.push(c),
+        }
+    }
+
+    return out;
+}
@@ -0,0 +1,81 @@
+//! Per-phase instrumentation for the compiler; see `compile_timed`. The crate is
+//! `no_std`, so the host supplies the clock, and times are in whatever unit that
+//! clock counts in. `tci --timings` counts in microseconds, and prints `table`.
+
+use crate::util::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PhaseTiming {
+    pub name: &'static str,
+    pub elapsed: u64,
+    pub bytes: usize, // bytes allocated in the phase's bucket arenas
+}
+
+pub struct Timings {
+    clock: Option<fn() -> u64>,
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl Timings {
+    pub fn new(clock: fn() -> u64) -> Self {
+        return Self {
+            clock: Some(clock),
+            phases: Vec::new(),
+        };
+    }
+
+    pub fn disabled() -> Self {
+        return Self {
+            clock: None,
+            phases: Vec::new(),
+        };
+    }
+
+    pub fn now(&self) -> u64 {
+        return self.clock.map(|clock| clock()).unwrap_or(0);
+    }
+
+    /// Adds `elapsed` and `bytes` to the phase called `name`, creating it if needed
+    pub fn record(&mut self, name: &'static str, elapsed: u64, bytes: usize) {
+        if self.clock.is_none() {
+            return;
+        }
+
+        if let Some(phase) = self.phases.iter_mut().find(|p| p.name == name) {
+            phase.elapsed += elapsed;
+            phase.bytes += bytes;
+            return;
+        }
+
+        self.phases.push(PhaseTiming {
+            name,
+            elapsed,
+            bytes,
+        });
+    }
+
+    /// Runs `f` and records how long it took under `name`
+    pub fn time<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = self.now();
+        let result = f();
+        let elapsed = self.now() - start;
+        self.record(name, elapsed, 0);
+        return result;
+    }
+
+    pub fn table(&self) -> String {
+        let mut out = StringWriter::new();
+        writeln!(out, "{:<12} {:>12} {:>12}", "phase", "time", "bytes").unwrap();
+
+        let (mut elapsed, mut bytes) = (0, 0);
+        for phase in &self.phases {
+            let (name, e, b) = (phase.name, phase.elapsed, phase.bytes);
+            writeln!(out, "{:<12} {:>12} {:>12}", name, e, b).unwrap();
+            elapsed += e;
+            bytes += b;
+        }
+
+        writeln!(out, "{:<12} {:>12} {:>12}", "total", elapsed, bytes).unwrap();
+        return out.to_string();
+    }
+}
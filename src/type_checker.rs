@@ -1,6 +1,7 @@
 use crate::ast::*;
 use crate::buckets::*;
-use crate::filedb::*;
+use crate::filedb::FileDb;
+use crate::interner::*;
 use crate::runtime::Opcode;
 use crate::tc_ast::*;
 use crate::tc_structs::*;
@@ -78,8 +79,12 @@ macro_rules! gen_type_decl_spec {
     };
 }
 
-type BuiltinTransform =
-    for<'a, 'b, 'c> fn(&'a mut TypeEnv<'b>, CodeLoc, &'c [Expr]) -> Result<TCExpr, Error>;
+type BuiltinTransform = for<'a, 'b, 'c, 'd> fn(
+    &'a mut TypeEnv<'b>,
+    Option<&'d mut FuncEnv>,
+    CodeLoc,
+    &'c [Expr],
+) -> Result<TCExpr, Error>;
 
 lazy_static! {
     pub static ref CORRECT_TYPES: HashMap<TypeDeclSpec, TCTypeBase> = {
@@ -126,162 +131,426 @@ lazy_static! {
     pub static ref BUILTINS: HashMap<u32, BuiltinTransform> = {
         let mut m: HashMap<u32, BuiltinTransform> = HashMap::new();
 
-        m.insert(BuiltinSymbol::BuiltinPush as u32, |env, call_loc, args| {
-            if args.len() != 1 {
-                return Err(error!(
-                    "wrong number of arguments to builtin function",
-                    call_loc, "called here"
-                ));
-            }
-
-            let void = TCType::new(TCTypeBase::Void);
-
-            let value = check_expr(&mut *env, &args[0])?;
-            return Ok(TCExpr {
-                kind: TCExprKind::Builtin(TCBuiltin::Push(env.add(value))),
-                ty: void,
-                loc: call_loc,
-            });
-        });
+        m.insert(
+            BuiltinSymbol::BuiltinPush as u32,
+            |env, mut out, call_loc, args| {
+                if args.len() != 1 {
+                    return Err(error!(
+                        "wrong number of arguments to builtin function",
+                        call_loc, "called here"
+                    ));
+                }
 
-        m.insert(BuiltinSymbol::BuiltinOp as u32, |env, call_loc, args| {
-            if args.len() != 2 {
-                return Err(error!(
-                    "wrong number of arguments to builtin function",
-                    call_loc, "called here"
-                ));
-            }
+                let void = TCType::new(TCTypeBase::Void);
 
-            let void = TCType::new(TCTypeBase::Void);
+                let value = check_expr(&mut *env, out.as_deref_mut(), &args[0])?;
+                return Ok(TCExpr {
+                    kind: TCExprKind::Builtin(TCBuiltin::Push(env.add(value))),
+                    ty: void,
+                    loc: call_loc,
+                });
+            },
+        );
 
-            let op = match args[0].kind {
-                ExprKind::StringLit(lit) => lit,
-                _ => {
-                    return Err(error!(
-                        "first opcode argument must be string literal",
-                        args[0].loc, "this argument"
-                    ))
-                }
-            };
-            let ast_ty = match args[1].kind {
-                ExprKind::SizeofTy(ty) => ty,
-                _ => {
+        m.insert(
+            BuiltinSymbol::BuiltinOp as u32,
+            |env, mut out, call_loc, args| {
+                if args.len() != 2 {
                     return Err(error!(
-                        "second opcode argument must be a sizeof(type) expression",
-                        args[1].loc, "this argument"
-                    ))
+                        "wrong number of arguments to builtin function",
+                        call_loc, "called here"
+                    ));
                 }
-            };
 
-            let or_else = |_| {
-                error!(
-                    "opcode argument wasn't a real opcode",
-                    args[0].loc, "this opcode argument"
-                )
-            };
-            let op = op.parse::<Opcode>().map_err(or_else)?;
+                let void = TCType::new(TCTypeBase::Void);
 
-            let base = parse_spec_quals(&mut *env, ast_ty.specifiers)?;
-            let ty = if let Some(decl) = ast_ty.declarator {
-                let (ty, id) = check_decl(&mut *env, base, &decl)?;
-                assert!(id == n32::NULL);
-                ty.to_ref(&*env)
-            } else {
-                TCType { base, mods: &[] }
-            };
+                let op = match args[0].kind {
+                    ExprKind::StringLit(lit) => lit,
+                    _ => {
+                        return Err(error!(
+                            "first opcode argument must be string literal",
+                            args[0].loc, "this argument"
+                        ))
+                    }
+                };
+                let ast_ty = match args[1].kind {
+                    ExprKind::SizeofTy(ty) => ty,
+                    _ => {
+                        return Err(error!(
+                            "second opcode argument must be a sizeof(type) expression",
+                            args[1].loc, "this argument"
+                        ))
+                    }
+                };
 
-            return Ok(TCExpr {
-                kind: TCExprKind::Builtin(TCBuiltin::Opcode(op)),
-                ty,
-                loc: call_loc,
-            });
-        });
+                let or_else = |_| {
+                    error!(
+                        "opcode argument wasn't a real opcode",
+                        args[0].loc, "this opcode argument"
+                    )
+                };
+                let op = op.parse::<Opcode>().map_err(or_else)?;
+
+                let base = parse_spec_quals(&mut *env, out.as_deref_mut(), ast_ty.specifiers)?;
+                let ty = if let Some(decl) = ast_ty.declarator {
+                    let (ty, id) = check_decl(&mut *env, out.as_deref_mut(), base, &decl)?;
+                    assert!(id == n32::NULL);
+                    ty.to_ref(&*env)
+                } else {
+                    TCType { base, mods: &[] }
+                };
+
+                return Ok(TCExpr {
+                    kind: TCExprKind::Builtin(TCBuiltin::Opcode(op)),
+                    ty,
+                    loc: call_loc,
+                });
+            },
+        );
 
         m
     };
 }
 
+/// Checks every global statement, continuing past errors so that independent
+/// mistakes are all reported at once.
 pub fn check_tree(
+    files: &FileDb,
     file: u32,
     symbols: &Symbols,
     tree: &[GlobalStatement],
-) -> Result<TranslationUnit, Error> {
+) -> Result<TranslationUnit, Vec<Error>> {
+    return check_tree_with(files, file, symbols, tree, &CheckOptions::default());
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CheckOptions {
+    pub gnu_extensions: bool, // `--std=gnu11`, where `void` is 1 byte like in GCC
+    pub warn_shadow: bool,    // `-Wshadow`; see `warnings::WarningOptions`
+}
+
+/// Like `check_tree`, but with the semantics and warnings in `options`
+pub fn check_tree_with(
+    files: &FileDb,
+    file: u32,
+    symbols: &Symbols,
+    tree: &[GlobalStatement],
+    options: &CheckOptions,
+) -> Result<TranslationUnit, Vec<Error>> {
     let mut globals = TypeEnv::global(file, symbols);
+    globals.globals_mut().gnu_extensions = options.gnu_extensions;
+    globals.globals_mut().warn_shadow = options.warn_shadow;
 
     for decl in tree {
-        match decl.kind {
-            GlobalStatementKind::Declaration(decl) => check_declaration(&mut globals, None, decl)?,
-            GlobalStatementKind::FunctionDefinition(func) => {
-                let func_decl = check_func_defn_decl(&mut globals, &func)?;
-
-                let base = TCTypeBase::InternalTypedef(globals.add(func_decl.return_type));
-                let mut ty = TCTypeOwned::new(base);
-
-                if let Some(params) = func_decl.params {
-                    if params.params.len() == 0 {
-                        ty.mods.push(TCTypeModifier::NoParams);
-                    } else {
-                        ty.mods
-                            .push(TCTypeModifier::BeginParam(params.params[0].ty));
-                        for param in &params.params[1..] {
-                            ty.mods.push(TCTypeModifier::Param(param.ty));
-                        }
-
-                        if params.varargs {
-                            ty.mods.push(TCTypeModifier::VarargsParam);
-                        }
-                    }
-                } else {
-                    ty.mods.push(TCTypeModifier::UnknownParams);
+        if let Err(err) = check_global_stmt(&mut globals, decl) {
+            globals.add_error(err);
+        }
+
+        globals.globals_mut().current_func = n32::NULL;
+    }
+
+    let mut errors = core::mem::replace(&mut globals.globals_mut().errors, Vec::new());
+    if errors.len() != 0 {
+        add_forward_decl_notes(files, globals.globals().0, &mut errors);
+        return Err(errors);
+    }
+
+    return Ok(globals.tu());
+}
+
+/// Checks every global statement in `tree`, keeping the global scope they make so
+/// that expressions can be checked against it with `check_expr_at`
+pub fn check_scope(
+    file: u32,
+    symbols: &Symbols,
+    tree: &[GlobalStatement],
+) -> Result<GlobalScope, Vec<Error>> {
+    let mut globals = TypeEnv::global(file, symbols);
+
+    for decl in tree {
+        if let Err(err) = check_global_stmt(&mut globals, decl) {
+            globals.add_error(err);
+        }
+
+        globals.globals_mut().current_func = n32::NULL;
+    }
+
+    let errors = core::mem::replace(&mut globals.globals_mut().errors, Vec::new());
+    if errors.len() != 0 {
+        return Err(errors);
+    }
+
+    return Ok(globals.scope());
+}
+
+/// Checks `expr` as though it were written at `loc` in the body of `func`, so that
+/// it can refer to the locals in scope there. The debugger uses this to evaluate
+/// expressions against a paused program.
+pub fn check_expr_at(
+    scope: &mut GlobalScope,
+    symbols: &Symbols,
+    func: u32,
+    loc: CodeLoc,
+    expr: &Expr,
+) -> Result<TCExpr, Error> {
+    let empty = GlobalScope {
+        tu: TranslationUnit::new(!0),
+        structs: HashMap::new(),
+        unions: HashMap::new(),
+        typedefs: HashMap::new(),
+    };
+
+    let mut globals = TypeEnv::from_scope(core::mem::replace(scope, empty), symbols);
+    let tc_expr = check_in_frame(&mut globals, func, loc, expr);
+    *scope = globals.scope();
+    return tc_expr;
+}
+
+fn check_in_frame(
+    globals: &mut TypeEnv,
+    func: u32,
+    loc: CodeLoc,
+    expr: &Expr,
+) -> Result<TCExpr, Error> {
+    let defn = match globals.func_defn(func) {
+        Some(defn) => defn,
+        None => return Err(error!("function isn't defined in this file")),
+    };
+
+    let contains = |scope: CodeLoc| {
+        scope.file == loc.file && scope.start <= loc.start && loc.start < scope.end
+    };
+
+    // Scopes are in the order they were opened, so inner ones shadow outer ones
+    let mut in_scope = HashMap::new();
+    for op in defn.ops {
+        let vars = match op.kind {
+            TCOpcodeKind::ScopeBegin(vars, _) if contains(op.loc) => vars,
+            _ => continue,
+        };
+
+        for (&label, _) in vars {
+            let is_var = |(_, v): &&(u32, TCVar)| v.symbol_label == LabelOrLoc::Ident(label);
+            if let Some(&(ident, var)) = defn.locals.iter().find(is_var) {
+                if var.loc.file != loc.file || var.loc.start <= loc.start {
+                    in_scope.insert(ident, var);
                 }
+            }
+        }
+    }
 
-                let ident = func_decl.ident;
-                let ty = ty.to_ref(&globals);
-                let init = if func_decl.is_static {
-                    TCDeclInit::Static(TCExprKind::FunctionIdent { ident })
-                } else {
-                    TCDeclInit::Default(TCExprKind::FunctionIdent { ident })
-                };
-                let decl = TCDecl {
-                    ty,
-                    init,
-                    ident,
-                    loc: decl.loc,
-                };
-                globals.add_var(None, &decl)?;
+    let mut out = FuncEnv::new(TCType::new(TCTypeBase::Void), defn.loc);
+    let mut locals = globals.child(&mut out, defn.loc);
+    if let TypeEnvKind::Local { symbols, .. } = &mut locals.kind {
+        *symbols = in_scope;
+    }
+
+    locals.globals_mut().current_func = func.into();
+    let tc_expr = check_expr(&mut locals, Some(&mut out), expr);
+    locals.globals_mut().current_func = n32::NULL;
+    return tc_expr;
+}
+
+/// Functions used before their definition get a note suggesting a forward declaration
+fn add_forward_decl_notes(files: &FileDb, globals: &GlobalTypeEnv, errors: &mut Vec<Error>) {
+    for err in errors {
+        let loc = match err.sections.first() {
+            Some(section) => section.location,
+            None => continue,
+        };
+
+        let used = globals
+            .unknown_uses
+            .iter()
+            .find(|(_, use_loc)| *use_loc == loc);
+        let proto = used.and_then(|(ident, _)| globals.prototypes.get(ident));
+        let (signature, defn_loc) = match proto {
+            Some(proto) => proto,
+            None => continue,
+        };
+
+        if defn_loc.file != loc.file || defn_loc.start < loc.start {
+            continue;
+        }
+
+        let proto = match prototype(files, *signature) {
+            Some(proto) => proto,
+            None => continue,
+        };
+
+        err.sections.push(ErrorSection {
+            location: *defn_loc,
+            message: format!(
+                "function is defined here, after its first use; help: declare it earlier with `{};`",
+                proto
+            ),
+        });
+    }
+}
+
+/// The text of a function definition's signature, from its specifiers to the end
+/// of its parameters, without comments and with each run of whitespace made into
+/// one space
+pub fn prototype(files: &FileDb, signature: CodeLoc) -> Option<String> {
+    let source = files.source(signature.file)?;
+    let text = source.get((signature.start as usize)..(signature.end as usize))?;
+    let text = text.replace("\\\r\n", "").replace("\\\n", "");
+
+    let (mut proto, mut rest) = (String::new(), &*text);
+    while let Some(idx) = rest.find('/') {
+        proto.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if rest.starts_with("//") {
+            rest = &rest[rest.find('\n').unwrap_or(rest.len())..];
+        } else if rest.starts_with("/*") {
+            let end = rest[2..]
+                .find("*/")
+                .map(|end| end + 4)
+                .unwrap_or(rest.len());
+            proto.push(' ');
+            rest = &rest[end..];
+        } else {
+            proto.push('/');
+            rest = &rest[1..];
+        }
+    }
+
+    proto.push_str(rest);
+    let words: Vec<&str> = proto.split_whitespace().collect();
+    return Some(words.join(" "));
+}
+
+pub fn check_global_stmt(globals: &mut TypeEnv, decl: &GlobalStatement) -> Result<(), Error> {
+    match decl.kind {
+        GlobalStatementKind::Declaration(decl) => check_declaration(globals, None, decl)?,
+        GlobalStatementKind::FunctionDefinition(func) => {
+            let func_decl = check_func_defn_decl(globals, None, &func)?;
+            let params_end = match func.params {
+                Some(params) => params.loc.end,
+                None => func.statements.loc.start,
+            };
+            let signature = CodeLoc {
+                end: params_end,
+                ..func.loc
+            };
+            let prototypes = &mut globals.globals_mut().prototypes;
+            prototypes.insert(func_decl.ident, (signature, func_decl.loc));
 
-                let mut func_out = FuncEnv::new(func_decl.return_type, func_decl.loc);
-                let mut func_locals = globals.child(&mut func_out, decl.loc);
+            let base = TCTypeBase::InternalTypedef(globals.add(func_decl.return_type));
+            let mut ty = TCTypeOwned::new(base);
 
-                if let Some(params) = func_decl.params {
-                    for param in params.params {
-                        func_locals.add_param(&mut func_out, &param)?;
+            if let Some(params) = func_decl.params {
+                if params.params.len() == 0 {
+                    ty.mods.push(TCTypeModifier::NoParams);
+                } else {
+                    ty.mods
+                        .push(TCTypeModifier::BeginParam(params.params[0].ty));
+                    for param in &params.params[1..] {
+                        ty.mods.push(TCTypeModifier::Param(param.ty));
+                    }
+
+                    if params.varargs {
+                        ty.mods.push(TCTypeModifier::VarargsParam);
                     }
                 }
+            } else {
+                ty.mods.push(TCTypeModifier::UnknownParams);
+            }
+
+            let ident = func_decl.ident;
+            let ty = ty.to_ref(&*globals);
+            let init = if func_decl.is_static {
+                TCDeclInit::Static(TCExprKind::FunctionIdent { ident })
+            } else {
+                TCDeclInit::Default(TCExprKind::FunctionIdent { ident })
+            };
+            let decl = TCDecl {
+                ty,
+                init,
+                ident,
+                loc: decl.loc,
+            };
+            globals.add_var(None, &decl)?;
 
-                check_block(&mut func_locals, &mut func_out, func.statements)?;
-                func_locals.close_scope(&mut func_out);
+            let mut func_out = FuncEnv::new(func_decl.return_type, func_decl.loc);
+            let mut func_locals = globals.child(&mut func_out, decl.loc);
 
-                globals.complete_func_defn(ident, func_out)?;
+            if let Some(params) = func_decl.params {
+                for param in params.params {
+                    func_locals.add_param(&mut func_out, &param)?;
+                }
             }
-            GlobalStatementKind::Pragma(pragma) => {}
+
+            func_locals.globals_mut().current_func = ident.into();
+            check_block(&mut func_locals, &mut func_out, func.statements)?;
+            func_locals.close_scope(&mut func_out);
+
+            globals.complete_func_defn(ident, func_decl.is_inline, func_out)?;
         }
+        GlobalStatementKind::Pragma(pragma) => {}
     }
 
-    return Ok(globals.tu());
+    return Ok(());
 }
 
+/// Errors in a statement are recorded and checking continues with the next one
 pub fn check_block(env: &mut TypeEnv, out: &mut FuncEnv, stmts: Block) -> Result<(), Error> {
     for stmt in stmts.stmts {
-        match stmt.kind {
-            BlockItemKind::Declaration(decl) => check_declaration(env, Some(out), decl)?,
-            BlockItemKind::Statement(stmt) => check_stmt(env, out, stmt)?,
+        let result = match stmt.kind {
+            BlockItemKind::Declaration(decl) => check_declaration(env, Some(out), decl),
+            BlockItemKind::Statement(stmt) => check_stmt(env, out, stmt),
+        };
+
+        if let Err(err) = result {
+            env.add_error(err);
         }
     }
 
     return Ok(());
 }
 
+/// Checks the statements of a statement expression. If the last one is an
+/// expression statement, its value is the value of the whole thing; returns the
+/// index of that statement's op, and the type of the statement expression.
+pub fn check_stmt_expr_body(
+    env: &mut TypeEnv,
+    out: &mut FuncEnv,
+    block: Block,
+) -> Result<(n32, TCType), Error> {
+    let void = TCType::new(TCTypeBase::Void);
+    let (last, stmts) = match block.stmts.split_last() {
+        Some(split) => split,
+        None => return Ok((n32::NULL, void)),
+    };
+
+    check_block(&mut *env, out, Block { stmts, ..block })?;
+
+    let stmt = match last.kind {
+        BlockItemKind::Statement(stmt) => stmt,
+        BlockItemKind::Declaration(_) => {
+            let stmts = core::slice::from_ref(last);
+            check_block(&mut *env, out, Block { stmts, ..block })?;
+            return Ok((n32::NULL, void));
+        }
+    };
+
+    let value = match stmt.kind {
+        StatementKind::Expr(expr) => check_expr(&mut *env, Some(&mut *out), &expr)?,
+        _ => {
+            check_stmt(&mut *env, out, stmt)?;
+            return Ok((n32::NULL, void));
+        }
+    };
+
+    let idx = out.ops.len() as u32;
+    out.ops.push(TCOpcode {
+        kind: TCOpcodeKind::Expr(value),
+        loc: stmt.loc,
+    });
+
+    return Ok((idx.into(), value.ty));
+}
+
 pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Result<(), Error> {
     let mut op = TCOpcode {
         kind: TCOpcodeKind::Ret,
@@ -295,7 +564,7 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
             scope.close_scope(out);
         }
         StatementKind::Expr(expr) => {
-            let tc_expr = check_expr(env, &expr)?;
+            let tc_expr = check_expr(env, Some(&mut *out), &expr)?;
             op.kind = TCOpcodeKind::Expr(tc_expr);
             out.ops.push(op);
         }
@@ -305,8 +574,13 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
             out.ops.push(op);
         }
         StatementKind::RetVal(expr) => {
-            let tc_expr = check_expr(env, &expr)?;
+            let tc_expr = check_expr(env, Some(&mut *out), &expr)?;
             let or_else = || {
+                let (syms, ty) = (env.symbols(), out.return_type);
+                if let Some(err) = int_ptr_conversion_error(syms, ty, &tc_expr) {
+                    return err;
+                }
+
                 error!(
                     "couldn't convert expression to return type",
                     tc_expr.loc, "expression found here"
@@ -325,7 +599,7 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
             if_body,
             else_body,
         } => {
-            let cond = check_expr(&mut *env, &if_cond)?;
+            let cond = check_expr(&mut *env, Some(&mut *out), &if_cond)?;
 
             let else_label = out.label();
 
@@ -362,7 +636,7 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
             let (mut scope, cb) = env.loop_child(out, body.loc);
 
             if let Some(start) = at_start {
-                let start = check_expr(&mut scope, &start)?;
+                let start = check_expr(&mut scope, Some(&mut *out), &start)?;
 
                 out.ops.push(TCOpcode {
                     kind: TCOpcodeKind::Expr(start),
@@ -375,7 +649,7 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
             scope.label(out, begin, body.loc);
 
             if let Some(cond) = condition {
-                let cond = check_expr(&mut scope, &cond)?;
+                let cond = check_expr(&mut scope, Some(&mut *out), &cond)?;
                 let pass_goto = out.label();
 
                 if scope.goto_ifnz(out, cond, pass_goto, cond.loc) {
@@ -391,7 +665,7 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
             scope.label(out, cb.cont, body.loc);
 
             if let Some(post) = post_expr {
-                let post = check_expr(&mut scope, &post)?;
+                let post = check_expr(&mut scope, Some(&mut *out), &post)?;
 
                 out.ops.push(TCOpcode {
                     kind: TCOpcodeKind::Expr(post),
@@ -418,7 +692,7 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
             scope.label(out, begin, body.loc);
 
             if let Some(cond) = condition {
-                let cond = check_expr(&mut scope, &cond)?;
+                let cond = check_expr(&mut scope, Some(&mut *out), &cond)?;
                 let pass_goto = out.label();
 
                 if scope.goto_ifnz(out, cond, pass_goto, cond.loc) {
@@ -434,7 +708,7 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
             scope.label(out, cb.cont, body.loc);
 
             if let Some(post) = post_expr {
-                let post = check_expr(&mut scope, &post)?;
+                let post = check_expr(&mut scope, Some(&mut *out), &post)?;
 
                 out.ops.push(TCOpcode {
                     kind: TCOpcodeKind::Expr(post),
@@ -453,7 +727,7 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
 
             scope.label(out, cb.cont, body.loc);
 
-            let cond = check_expr(&mut scope, &condition)?;
+            let cond = check_expr(&mut scope, Some(&mut *out), &condition)?;
 
             let pass_goto = out.label();
             if scope.goto_ifnz(out, cond, pass_goto, cond.loc) {
@@ -480,7 +754,7 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
 
             scope.label(out, cb.cont, body.loc);
 
-            let cond = check_expr(&mut scope, &condition)?;
+            let cond = check_expr(&mut scope, Some(&mut *out), &condition)?;
 
             if scope.goto_ifz(out, cond, cb.br, cond.loc) {
                 return Err(condition_non_primitive(cond.ty, cond.loc));
@@ -496,23 +770,40 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
         }
         StatementKind::Continue => {
             if env.cont(out, stmt.loc) {
+                let message = "continue statement not within a loop";
+                if let Some(switch_loc) = env.enclosing_switch() {
+                    return Err(error!(
+                        message,
+                        stmt.loc,
+                        "continue used here",
+                        switch_loc,
+                        "this switch isn't inside a loop, and `continue` doesn't apply to it"
+                    ));
+                }
+
                 return Err(error!(
-                    "continue used when not in a loop",
-                    stmt.loc, "continue used here"
+                    message,
+                    stmt.loc,
+                    "continue used here",
+                    out.decl_loc,
+                    "this function has no loop around it"
                 ));
             }
         }
         StatementKind::Break => {
             if env.br(out, stmt.loc) {
                 return Err(error!(
-                    "break used when not in a loop or switch",
-                    stmt.loc, "break used here"
+                    "break statement not within loop or switch",
+                    stmt.loc,
+                    "break used here",
+                    out.decl_loc,
+                    "this function has no loop or switch around it"
                 ));
             }
         }
 
         StatementKind::Switch { expr, body } => {
-            let cond = check_expr(env, &expr)?;
+            let cond = check_expr(env, Some(&mut *out), &expr)?;
             let (mut scope, br) = env.switch(cond, out, body.loc)?;
             check_stmt(&mut scope, out, *body)?;
             scope.close_scope(out);
@@ -522,7 +813,7 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
             case_value,
             labeled,
         } => {
-            let case_value = check_expr(env, &case_value)?;
+            let case_value = check_expr(env, Some(&mut *out), &case_value)?;
             env.case(out, case_value, stmt.loc)?;
             check_stmt(env, out, *labeled)?;
         }
@@ -545,6 +836,7 @@ pub fn check_stmt(env: &mut TypeEnv, out: &mut FuncEnv, stmt: Statement) -> Resu
 
 pub fn parse_union_decl(
     locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
     fields: StructType,
     loc: CodeLoc,
 ) -> Result<TCTypeBase, Error> {
@@ -564,7 +856,7 @@ pub fn parse_union_decl(
     let mut fields: Vec<TCStructField> = Vec::new();
 
     for decl in decls {
-        let base = parse_spec_quals(&mut *locals, decl.specifiers)?;
+        let base = parse_spec_quals(&mut *locals, out.as_deref_mut(), decl.specifiers)?;
         if decl.declarators.len() == 0 {
             let (loc, sa) = match base {
                 TCTypeBase::UnnamedStruct { loc, sa } => (loc, sa),
@@ -595,8 +887,7 @@ pub fn parse_union_decl(
         }
 
         for &declarator in decl.declarators {
-            let (ty, id) = check_decl(locals, base, &declarator.declarator)?;
-            let name: u32 = id.into();
+            let (ty, id) = check_decl(locals, out.as_deref_mut(), base, &declarator.declarator)?;
             let decl_loc = declarator.loc;
 
             let sa_size = ty.size();
@@ -611,13 +902,25 @@ pub fn parse_union_decl(
             let sa_size: u32 = sa_size.into();
             let sa_align: u32 = ty.align().into();
 
+            let bitfield = match declarator.bit_width {
+                Some(width) => {
+                    let width = check_bit_width(&mut *locals, out.as_deref_mut(), ty, id, width)?;
+                    Some(TCBitfield { offset: 0, width })
+                }
+                None => None,
+            };
+
+            if id == n32::NULL {
+                continue;
+            }
+
             align = core::cmp::max(align, sa_align);
             let aligned_size = align_u32(size, sa_align);
             size = core::cmp::max(aligned_size, sa_size);
 
-            let offset = 0;
+            let (name, offset) = (id.into(), 0);
             #[rustfmt::skip]
-            let field = TCStructField { name, ty, offset, loc, };
+            let field = TCStructField { name, ty, offset, bitfield, loc, };
             if let Some(prev) = fields.iter().find(|f| f.name == name) {
                 return Err(error!(
                     "redeclaration of union field",
@@ -637,6 +940,7 @@ pub fn parse_union_decl(
 
 pub fn parse_struct_decl(
     locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
     fields: StructType,
     loc: CodeLoc,
 ) -> Result<TCTypeBase, Error> {
@@ -653,15 +957,26 @@ pub fn parse_struct_decl(
 
     let mut align = 1;
     let mut size = 0;
+    let mut bits = 0; // the same as `size`, but counted in bits
     let mut fields: Vec<TCStructField> = Vec::new();
 
-    if decls.len() == 0 {
-        let sa = sa_new(size, align);
-        return locals.close_struct_defn(label, sa, fields);
+    // A member with an error is left out, and the struct is marked as poisoned so
+    // that using the missing member isn't reported as another error
+    let mut poisoned = false;
+    macro_rules! skip_member {
+        ($err:expr) => {{
+            let err = $err;
+            locals.add_error(err);
+            poisoned = true;
+            continue;
+        }};
     }
 
-    for decl in &decls[..(decls.len() - 1)] {
-        let base = parse_spec_quals(&mut *locals, decl.specifiers)?;
+    for (decl_idx, decl) in decls.iter().enumerate() {
+        let base = match parse_spec_quals(&mut *locals, out.as_deref_mut(), decl.specifiers) {
+            Ok(base) => base,
+            Err(err) => skip_member!(err),
+        };
         if decl.declarators.len() == 0 {
             let (loc, sa) = match base {
                 TCTypeBase::UnnamedStruct { loc, sa } => (loc, sa),
@@ -674,6 +989,7 @@ pub fn parse_struct_decl(
             let offset = align_u32(size, sa_align);
             let sa_size: u32 = sa.size.into();
             size = offset + sa_size;
+            bits = size * 8;
 
             let anon_fields = locals.get_struct_fields(LabelOrLoc::Loc(loc));
             let anon_fields = anon_fields.or_else(|| locals.get_union_fields(LabelOrLoc::Loc(loc)));
@@ -682,7 +998,7 @@ pub fn parse_struct_decl(
                 field.offset += offset;
 
                 if let Some(prev) = fields.iter().find(|f| f.name == field.name) {
-                    return Err(error!(
+                    skip_member!(error!(
                         "redeclaration of struct field",
                         prev.loc, "previous here", field.loc, "redeclaration here"
                     ));
@@ -694,103 +1010,89 @@ pub fn parse_struct_decl(
             continue;
         }
 
-        for &declarator in decl.declarators {
+        for (idx, declarator) in decl.declarators.iter().enumerate() {
             // add field
-            let (ty, id) = check_decl(locals, base, &declarator.declarator)?;
-            let name: u32 = id.into();
+            let (ty, id) =
+                match check_decl(locals, out.as_deref_mut(), base, &declarator.declarator) {
+                    Ok(decl) => decl,
+                    Err(err) => skip_member!(err),
+                };
             let decl_loc = declarator.loc;
 
-            let sa_size = ty.size();
+            let mut sa_size = ty.size();
             if sa_size == n32::NULL {
-                return Err(error!(
-                    "declared struct member of incomplete type",
-                    decl_loc, "declared here"
-                ));
-            }
+                // the last member can be an array of unknown size
+                let is_last = decl_idx + 1 == decls.len() && idx + 1 == decl.declarators.len();
+                if !ty.is_array() {
+                    skip_member!(error!(
+                        "declared struct member of incomplete type",
+                        decl_loc, "declared here"
+                    ));
+                }
 
-            let ty = ty.to_ref(&*locals);
-            let sa_size: u32 = sa_size.into();
-            let sa_align: u32 = ty.align().into();
+                if !is_last {
+                    skip_member!(error!(
+                        "flexible array member has to be the last member of the struct",
+                        decl_loc, "declared here"
+                    ));
+                }
 
-            align = core::cmp::max(align, sa_align);
-            let offset = align_u32(size, sa_align);
-            size = offset + sa_size;
+                if fields.len() == 0 {
+                    skip_member!(error!(
+                        "flexible array member in a struct with no other members",
+                        decl_loc, "declared here"
+                    ));
+                }
 
-            #[rustfmt::skip]
-            let field = TCStructField { name, ty, offset, loc, };
-            if let Some(prev) = fields.iter().find(|f| f.name == name) {
-                return Err(error!(
-                    "redeclaration of struct field",
-                    prev.loc, "previous here", field.loc, "redeclaration here"
-                ));
+                sa_size = 0u32.into();
             }
 
-            fields.push(field);
-        }
-    }
-
-    let decl = *decls.last().unwrap();
-    let base = parse_spec_quals(&mut *locals, decl.specifiers)?;
-    if decl.declarators.len() == 0 {
-        let (loc, sa) = match base {
-            TCTypeBase::UnnamedStruct { loc, sa } => (loc, sa),
-            TCTypeBase::UnnamedUnion { loc, sa } => (loc, sa),
-            _ => {
-                let size = align_u32(size, align);
+            let ty = ty.to_ref(&*locals);
+            let sa_size: u32 = sa_size.into();
+            let sa_align: u32 = ty.align().into();
 
-                let sa = sa_new(size, align);
-                return locals.close_struct_defn(label, sa, fields);
-            }
-        };
+            let (offset, bitfield) = match declarator.bit_width {
+                Some(width) => {
+                    let width =
+                        match check_bit_width(&mut *locals, out.as_deref_mut(), ty, id, width) {
+                            Ok(width) => width,
+                            Err(err) => skip_member!(err),
+                        };
 
-        let sa_align = sa.align.into();
-        align = core::cmp::max(align, sa_align);
-        let offset = align_u32(size, sa_align);
-        let sa_size: u32 = sa.size.into();
-        size = offset + sa_size;
+                    // bitfields are packed together, but don't cross a boundary
+                    // of their type's alignment, and zero-width ones skip to the
+                    // next boundary
+                    let unit_bits = sa_size * 8;
+                    let mut begin = bits;
+                    if width == 0 || begin / unit_bits != (begin + width - 1) / unit_bits {
+                        begin = align_u32(begin, unit_bits);
+                    }
 
-        let anon_fields = locals.get_struct_fields(LabelOrLoc::Loc(loc));
-        let anon_fields = anon_fields.or_else(|| locals.get_union_fields(LabelOrLoc::Loc(loc)));
-        for &field in anon_fields.unwrap() {
-            let mut field = field;
-            field.offset += offset;
+                    bits = begin + width;
+                    size = (bits + 7) / 8;
+                    if id == n32::NULL {
+                        continue;
+                    }
 
-            if let Some(prev) = fields.iter().find(|f| f.name == field.name) {
-                return Err(error!(
-                    "redeclaration of struct field",
-                    prev.loc, "previous here", field.loc, "redeclaration here"
-                ));
-            }
+                    align = core::cmp::max(align, sa_align);
+                    let unit_begin = begin / unit_bits * unit_bits;
+                    let offset = begin - unit_begin;
+                    (unit_begin / 8, Some(TCBitfield { offset, width }))
+                }
+                None => {
+                    align = core::cmp::max(align, sa_align);
+                    let offset = align_u32(size, sa_align);
+                    size = offset + sa_size;
+                    bits = size * 8;
+                    (offset, None)
+                }
+            };
 
-            fields.push(field);
-        }
-    } else {
-        for &declarator in &decl.declarators[..(decl.declarators.len() - 1)] {
-            // add field
-            let (ty, id) = check_decl(locals, base, &declarator.declarator)?;
             let name: u32 = id.into();
-            let decl_loc = declarator.loc;
-
-            let sa_size = ty.size();
-            if sa_size == n32::NULL {
-                return Err(error!(
-                    "declared struct member of incomplete type",
-                    decl_loc, "declared here"
-                ));
-            }
-
-            let ty = ty.to_ref(&*locals);
-            let sa_size: u32 = sa_size.into();
-            let sa_align: u32 = ty.align().into();
-
-            align = core::cmp::max(align, sa_align);
-            let offset = align_u32(size, sa_align);
-            size = offset + sa_size;
-
             #[rustfmt::skip]
-            let field = TCStructField { name, ty, offset, loc, };
+            let field = TCStructField { name, ty, offset, bitfield, loc, };
             if let Some(prev) = fields.iter().find(|f| f.name == name) {
-                return Err(error!(
+                skip_member!(error!(
                     "redeclaration of struct field",
                     prev.loc, "previous here", field.loc, "redeclaration here"
                 ));
@@ -798,52 +1100,96 @@ pub fn parse_struct_decl(
 
             fields.push(field);
         }
+    }
 
-        let declarator = *decl.declarators.last().unwrap();
-        let (ty, id) = check_decl(locals, base, &declarator.declarator)?;
-        let name: u32 = id.into();
-        let decl_loc = declarator.loc;
-
-        let mut sa_size = ty.size();
-        if sa_size == n32::NULL {
-            if !ty.is_array() {
-                return Err(error!(
-                    "declared struct member of incomplete type",
-                    decl_loc, "declared here"
-                ));
-            }
-
-            sa_size = 0u32.into();
-        }
+    let size = align_u32(size, align);
 
-        let ty = ty.to_ref(&*locals);
-        let sa_size: u32 = sa_size.into();
-        let sa_align: u32 = ty.align().into();
+    let sa = sa_new(size, align);
+    return locals.close_struct_defn(label, sa, fields, poisoned);
+}
 
-        align = core::cmp::max(align, sa_align);
-        let offset = align_u32(size, sa_align);
-        size = offset + sa_size;
+/// Checks the width of a bitfield, like the `3` in `unsigned flags : 3;`
+fn check_bit_width(
+    locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
+    ty: TCType,
+    id: n32,
+    width: &Expr,
+) -> Result<u32, Error> {
+    if !ty.is_integer() {
+        return Err(error!(
+            "bitfield has non-integer type",
+            width.loc,
+            format!("bitfield has type {}", ty.display_aka(locals.symbols()))
+        ));
+    }
 
-        #[rustfmt::skip]
-        let field = TCStructField { name, ty, offset, loc, };
-        if let Some(prev) = fields.iter().find(|f| f.name == name) {
+    let expr = eval_expr(check_expr(&mut *locals, out.as_deref_mut(), width)?)?;
+    let value = match expr.kind {
+        TCExprKind::I32Lit(i) => i as i64,
+        TCExprKind::U32Lit(i) => i as i64,
+        TCExprKind::I64Lit(i) => i,
+        TCExprKind::U64Lit(i) => core::cmp::min(i, i64::MAX as u64) as i64,
+        _ => {
             return Err(error!(
-                "redeclaration of struct field",
-                prev.loc, "previous here", field.loc, "redeclaration here"
-            ));
+                "bitfield width isn't a constant integer",
+                width.loc, "width found here"
+            ))
         }
+    };
 
-        fields.push(field);
+    let ty_bits: u32 = ty.size().into();
+    let ty_bits = ty_bits as i64 * 8;
+    if value < 0 {
+        return Err(error!(
+            "bitfield has negative width",
+            width.loc,
+            format!("width is {}", value)
+        ));
     }
 
-    let size = align_u32(size, align);
+    if value > ty_bits {
+        return Err(error!(
+            "bitfield is wider than its type",
+            width.loc,
+            format!(
+                "width is {}, but {} only has {} bits",
+                value,
+                ty.display_aka(locals.symbols()),
+                ty_bits
+            )
+        ));
+    }
 
-    let sa = sa_new(size, align);
-    return locals.close_struct_defn(label, sa, fields);
+    if value == 0 && id != n32::NULL {
+        return Err(error!(
+            "named bitfield has zero width",
+            width.loc, "width found here"
+        ));
+    }
+
+    return Ok(value as u32);
+}
+
+/// The type a type name like `int *` or `struct point` refers to
+pub fn check_type_name(
+    env: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
+    ty: &TypeName,
+) -> Result<TCType, Error> {
+    let base = parse_spec_quals(&mut *env, out.as_deref_mut(), ty.specifiers)?;
+    if let Some(decl) = ty.declarator {
+        let (ty, id) = check_decl(&mut *env, out.as_deref_mut(), base, &decl)?;
+        assert!(id == n32::NULL);
+        return Ok(ty.to_ref(&*env));
+    }
+
+    return Ok(TCType { base, mods: &[] });
 }
 
 pub fn parse_spec_quals(
     locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
     spec_quals: &[SpecifierQualifier],
 ) -> Result<TCTypeBase, Error> {
     let mut ds = TypeDeclSpec::new();
@@ -859,11 +1205,19 @@ pub fn parse_spec_quals(
                 let ty = locals.check_typedef(id, spec_qual.loc)?;
                 return Ok(ty);
             }
+            TypeSpecifier(TySpec::TypeofExpr(expr)) => {
+                let ty = check_expr(&mut *locals, out.as_deref_mut(), expr)?.ty;
+                return Ok(TCTypeBase::InternalTypedef(locals.add(ty)));
+            }
+            TypeSpecifier(TySpec::TypeofTy(ty)) => {
+                let ty = check_type_name(&mut *locals, out.as_deref_mut(), ty)?;
+                return Ok(TCTypeBase::InternalTypedef(locals.add(ty)));
+            }
             TypeSpecifier(TySpec::Union(fields)) => {
-                unimplemented!()
+                return parse_union_decl(&mut *locals, out.as_deref_mut(), fields, spec_qual.loc)
             }
             TypeSpecifier(TySpec::Struct(fields)) => {
-                return parse_struct_decl(&mut *locals, fields, spec_qual.loc)
+                return parse_struct_decl(&mut *locals, out.as_deref_mut(), fields, spec_qual.loc)
             }
 
             TypeSpecifier(TySpec::Void) => {
@@ -914,6 +1268,7 @@ pub fn parse_spec_quals(
 
 pub fn parse_decl_specs(
     locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
     decl_specs: &[DeclarationSpecifier],
 ) -> Result<(StorageClass, TCTypeBase), Error> {
     let mut sc = StorageClass::Default;
@@ -943,11 +1298,25 @@ pub fn parse_decl_specs(
                 let ty = locals.check_typedef(id, decl_spec.loc)?;
                 return Ok((sc, ty));
             }
+            TypeSpecifier(TySpec::TypeofExpr(expr)) => {
+                let ty = check_expr(&mut *locals, out.as_deref_mut(), expr)?.ty;
+                return Ok((sc, TCTypeBase::InternalTypedef(locals.add(ty))));
+            }
+            TypeSpecifier(TySpec::TypeofTy(ty)) => {
+                let ty = check_type_name(&mut *locals, out.as_deref_mut(), ty)?;
+                return Ok((sc, TCTypeBase::InternalTypedef(locals.add(ty))));
+            }
             TypeSpecifier(TySpec::Union(fields)) => {
-                return Ok((sc, parse_union_decl(&mut *locals, fields, decl_spec.loc)?))
+                return Ok((
+                    sc,
+                    parse_union_decl(&mut *locals, out.as_deref_mut(), fields, decl_spec.loc)?,
+                ))
             }
             TypeSpecifier(TySpec::Struct(fields)) => {
-                return Ok((sc, parse_struct_decl(&mut *locals, fields, decl_spec.loc)?))
+                return Ok((
+                    sc,
+                    parse_struct_decl(&mut *locals, out.as_deref_mut(), fields, decl_spec.loc)?,
+                ))
             }
 
             TypeSpecifier(TySpec::Void) => {
@@ -998,14 +1367,19 @@ pub fn parse_decl_specs(
 
 pub fn check_func_defn_decl(
     locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
     decl: &FunctionDefinition,
 ) -> Result<TCFunctionDeclarator, Error> {
-    let (sc, base) = parse_decl_specs(locals, decl.specifiers)?;
+    let (sc, base) = parse_decl_specs(locals, out.as_deref_mut(), decl.specifiers)?;
     let mut rtype = TCTypeOwned::new(base);
+    let is_inline = decl.specifiers.iter().any(|spec| match spec.kind {
+        DeclarationSpecifierKind::Inline => true,
+        _ => false,
+    });
 
     for modifier in decl.pointer {
         // TODO warn when there are qualifiers
-        rtype.mods.push(TCTypeModifier::Pointer);
+        rtype.mods.push(TCTypeModifier::Pointer(TCQualifiers::NONE));
     }
 
     let params_decl = if let Some(params) = decl.params {
@@ -1013,6 +1387,7 @@ pub fn check_func_defn_decl(
     } else {
         return Ok(TCFunctionDeclarator {
             is_static: let_expr!(StorageClass::Static = sc),
+            is_inline,
             return_type: rtype.to_ref(locals),
             ident: decl.ident,
             params: None,
@@ -1020,7 +1395,7 @@ pub fn check_func_defn_decl(
         });
     };
 
-    let params = check_param_types(locals, params_decl.parameters)?;
+    let params = check_param_types(locals, out.as_deref_mut(), params_decl.parameters)?;
     let mut out = Vec::new();
     for (idx, (ty, id)) in params.into_iter().enumerate() {
         if id == n32::NULL {
@@ -1039,6 +1414,7 @@ pub fn check_func_defn_decl(
 
     return Ok(TCFunctionDeclarator {
         is_static: let_expr!(StorageClass::Static = sc),
+        is_inline,
         return_type: rtype.to_ref(&*locals),
         ident: decl.ident,
         params: Some(TCParamsDeclarator {
@@ -1051,10 +1427,11 @@ pub fn check_func_defn_decl(
 
 pub fn check_decl(
     locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
     base: TCTypeBase,
     decl: &Declarator,
 ) -> Result<(TCTypeOwned, n32), Error> {
-    let (ty, id) = check_decl_rec(locals, base, decl)?;
+    let (ty, id) = check_decl_rec(locals, out.as_deref_mut(), base, decl)?;
 
     let mut was_array = false;
     let mut was_function = false;
@@ -1086,7 +1463,7 @@ pub fn check_decl(
                 was_array = false;
             }
             TCTypeModifier::VarargsParam | TCTypeModifier::Param(_) => {}
-            TCTypeModifier::Pointer => {
+            TCTypeModifier::Pointer(_) => {
                 was_array = false;
                 was_function = false;
             }
@@ -1112,12 +1489,13 @@ pub fn check_decl(
 
 pub fn check_param_types(
     locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
     params: &[ParameterDeclaration],
 ) -> Result<Vec<(TCType, n32)>, Error> {
     let param = params[0];
-    let (sc, param_base) = parse_decl_specs(&mut *locals, param.specifiers)?;
+    let (sc, param_base) = parse_decl_specs(&mut *locals, out.as_deref_mut(), param.specifiers)?;
     let (mut param_type, id) = if let Some(decl) = param.declarator {
-        let (tc_type, id) = check_decl(&mut *locals, param_base, &decl)?;
+        let (tc_type, id) = check_decl(&mut *locals, out.as_deref_mut(), param_base, &decl)?;
         (tc_type, id)
     } else {
         (TCTypeOwned::new(param_base), n32::NULL)
@@ -1139,13 +1517,13 @@ pub fn check_param_types(
     param_type.canonicalize_param();
     let param_type = param_type.to_ref(&*locals);
 
-    let mut out = Vec::new();
-    out.push((param_type, id));
+    let mut types = Vec::new();
+    types.push((param_type, id));
 
     for param in &params[1..] {
-        let (sc, base) = parse_decl_specs(&mut *locals, param.specifiers)?;
+        let (sc, base) = parse_decl_specs(&mut *locals, out.as_deref_mut(), param.specifiers)?;
         let (mut param_type, id) = if let Some(decl) = param.declarator {
-            let (tc_type, id) = check_decl(&mut *locals, base, &decl)?;
+            let (tc_type, id) = check_decl(&mut *locals, out.as_deref_mut(), base, &decl)?;
             (tc_type, id)
         } else {
             (TCTypeOwned::new(base), n32::NULL)
@@ -1162,19 +1540,20 @@ pub fn check_param_types(
 
         param_type.canonicalize_param();
         let param_type = param_type.to_ref(&*locals);
-        out.push((param_type, id));
+        types.push((param_type, id));
     }
 
-    return Ok(out);
+    return Ok(types);
 }
 
 pub fn check_decl_rec(
     locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
     base: TCTypeBase,
     decl: &Declarator,
 ) -> Result<(TCTypeOwned, n32), Error> {
     let (mut tc_type, ident) = match decl.kind {
-        DeclaratorKind::Declarator(decl) => check_decl_rec(locals, base, decl)?,
+        DeclaratorKind::Declarator(decl) => check_decl_rec(locals, out.as_deref_mut(), base, decl)?,
         DeclaratorKind::Identifier(ident) => (TCTypeOwned::new(base), ident.into()),
         DeclaratorKind::Abstract => (TCTypeOwned::new(base), n32::NULL),
     };
@@ -1184,19 +1563,14 @@ pub fn check_decl_rec(
     for derived in decl.derived {
         match derived.kind {
             DDK::Array(array_decl) => {
-                if array_decl.qualifiers.len() != 0 {
-                    return Err(error!(
-                        "TCI doesn't handle array qualifiers right now",
-                        array_decl.loc, "qualfiiers found around here"
-                    ));
-                }
-
+                // Qualifiers like `int arr[restrict 3]` can only be on parameters,
+                // which become pointers anyways; they're ignored for now
                 match array_decl.size.kind {
                     ArraySizeKind::Unknown => {
                         tc_type.mods.push(TCTypeModifier::VariableArray);
                     }
                     ArraySizeKind::VariableExpression(expr) => {
-                        let expr = eval_expr(check_expr(locals, expr)?)?;
+                        let expr = eval_expr(check_expr(locals, out.as_deref_mut(), expr)?)?;
                         let loc = expr.loc;
                         let expr = match expr.kind {
                             TCExprKind::U32Lit(i) => i as u64,
@@ -1216,7 +1590,7 @@ pub fn check_decl_rec(
                 }
             }
             DDK::Function(func) => {
-                let params = check_param_types(locals, func.parameters)?;
+                let params = check_param_types(locals, out.as_deref_mut(), func.parameters)?;
                 if params.len() == 0 {
                     tc_type.mods.push(TCTypeModifier::NoParams);
                     continue;
@@ -1234,9 +1608,9 @@ pub fn check_decl_rec(
             DDK::EmptyFunction => {
                 tc_type.mods.push(TCTypeModifier::UnknownParams);
             }
-            DDK::Pointer(ptr_qual) => {
-                // TODO warn when there are qualifiers
-                tc_type.mods.push(TCTypeModifier::Pointer);
+            DDK::Pointer(quals) => {
+                let quals = TCQualifiers::new(quals);
+                tc_type.mods.push(TCTypeModifier::Pointer(quals));
             }
         }
     }
@@ -1244,85 +1618,458 @@ pub fn check_decl_rec(
     return Ok((tc_type, ident));
 }
 
+/// Checks one element of an initializer list, which can be a list itself, e.g.
+/// each `{1, 2}` in `int pairs[][2] = {{1, 2}, {3, 4}};`
+pub fn check_initializer(
+    locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
+    ty: TCType,
+    init: &Initializer,
+) -> Result<TCExpr, Error> {
+    let expr = match init.kind {
+        InitializerKind::Expr(expr) => expr,
+        InitializerKind::List(items) => {
+            let (kind, ty) = check_initializer_list(
+                locals,
+                out.as_deref_mut(),
+                ty.to_ty_owned(),
+                items,
+                init.loc,
+            )?;
+            return Ok(TCExpr {
+                kind,
+                ty,
+                loc: init.loc,
+            });
+        }
+    };
+
+    if let (true, ExprKind::StringLit(string)) = (ty.is_array(), expr.kind) {
+        let (kind, ty) = check_string_init(locals, ty.to_ty_owned(), string, expr.loc)?;
+        return Ok(TCExpr {
+            kind,
+            ty,
+            loc: expr.loc,
+        });
+    }
+
+    if ty.is_array() {
+        return Err(error!(
+            "array element needs its own initializer list",
+            expr.loc,
+            format!(
+                "expected an initializer list for {}",
+                ty.display_aka(locals.symbols())
+            )
+        ));
+    }
+
+    let tc_expr = check_expr(&mut *locals, out.as_deref_mut(), expr)?;
+    let or_else = || conversion_error(locals.symbols(), ty, init.loc, &tc_expr);
+    let tc_expr = locals
+        .assign_convert(ty, tc_expr, tc_expr.loc)
+        .ok_or_else(or_else)?;
+
+    return Ok(tc_expr);
+}
+
+/// `char s[] = "hi"` copies the string into the array. The rest of the array
+/// is zeroed, and the NUL is left out if the array is exactly as long as the
+/// string.
+pub fn check_string_init(
+    locals: &mut TypeEnv,
+    mut target: TCTypeOwned,
+    string: &str,
+    loc: CodeLoc,
+) -> Result<(TCExprKind, TCType), Error> {
+    let elem_ty = target.deref().unwrap().to_ty_owned().to_ref(&*locals);
+    let char_lit = match elem_ty.to_prim_type() {
+        Some(TCPrimType::I8) => |c: u8| TCExprKind::I8Lit(c as i8),
+        Some(TCPrimType::U8) => |c: u8| TCExprKind::U8Lit(c),
+        _ => {
+            return Err(error!(
+                "can only initialize arrays of characters with a string literal",
+                loc,
+                format!("array has type {}", target.display_aka(locals.symbols()))
+            ))
+        }
+    };
+
+    let mut elems: Vec<_> = string.bytes().map(|c| (char_lit(c), loc)).collect();
+    elems.push((char_lit(0), loc));
+
+    match target.array_mod().unwrap() {
+        TCTypeModifier::Array(len) => {
+            if string.len() > *len as usize {
+                return Err(error!(
+                    "string literal is too long for the array",
+                    loc,
+                    format!(
+                        "string has {} characters, but the array only has room for {}",
+                        string.len(),
+                        len
+                    )
+                ));
+            }
+
+            elems.resize(*len as usize, (char_lit(0), loc));
+        }
+        x @ TCTypeModifier::VariableArray => *x = TCTypeModifier::Array(elems.len() as u32),
+        _ => unreachable!(),
+    }
+
+    let elems = locals.add_array(elems);
+    return Ok((
+        TCExprKind::ArrayInit { elems, elem_ty },
+        target.to_ref(&*locals),
+    ));
+}
+
 pub fn check_initializer_list(
     locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
     mut target: TCTypeOwned,
-    init: &[Expr],
+    init: &[Initializer],
     decl_loc: CodeLoc,
 ) -> Result<(TCExprKind, TCType), Error> {
     let deref = target.deref().map(|a| a.to_ty_owned());
     if let Some(array_mod) = target.array_mod() {
         let elem_ty = deref.unwrap().to_ref(&*locals);
+        if elem_ty.size() == n32::NULL {
+            return Err(error!(
+                "array has elements of incomplete type",
+                decl_loc,
+                format!("element type is {}", elem_ty.display_aka(locals.symbols()))
+            ));
+        }
 
         let mut tc_exprs = Vec::new();
-        for expr in init {
-            let tc_expr = check_expr(&mut *locals, expr)?;
-            let or_else = || conversion_error(elem_ty, decl_loc, &tc_expr);
-            let tc_expr = locals
-                .assign_convert(elem_ty, tc_expr, tc_expr.loc)
-                .ok_or_else(or_else)?;
+        for item in init {
+            if let Some((_, loc)) = item.field {
+                return Err(error!(
+                    "can only name fields when initializing a struct",
+                    loc,
+                    format!("this initializes {}", target.display_aka(locals.symbols()))
+                ));
+            }
+
+            let tc_expr = check_initializer(&mut *locals, out.as_deref_mut(), elem_ty, item)?;
             tc_exprs.push((tc_expr.kind, tc_expr.loc));
         }
 
-        let array_init = match array_mod {
-            TCTypeModifier::Array(arr) => {
-                tc_exprs.resize(*arr as usize, (TCExprKind::Uninit, decl_loc));
-                let elems = locals.add_array(tc_exprs);
+        let array_init = match array_mod {
+            TCTypeModifier::Array(arr) => {
+                tc_exprs.resize(*arr as usize, (TCExprKind::Uninit, decl_loc));
+                let elems = locals.add_array(tc_exprs);
+
+                TCExprKind::ArrayInit { elems, elem_ty }
+            }
+            x @ TCTypeModifier::VariableArray => {
+                *x = TCTypeModifier::Array(tc_exprs.len() as u32); // TODO overflow
+                let elems = locals.add_array(tc_exprs);
+
+                TCExprKind::ArrayInit { elems, elem_ty }
+            }
+            _ => unreachable!(),
+        };
+
+        return Ok((array_init, target.to_ref(&*locals)));
+    }
+
+    let target = target.to_ref(&*locals);
+    let or_else = || {
+        error!(
+            "can only use initializer lists on structs and arrays",
+            decl_loc,
+            format!("this has type {}", target.display_aka(locals.symbols()))
+        )
+    };
+
+    let (is_struct, id) = target.get_id_strict().ok_or_else(or_else)?;
+    if !is_struct {
+        return Err(or_else());
+    }
+
+    // `.x = 1` initializes the field `x`, and the items after it go in the
+    // fields after `x`
+    let fields = get_fields(&*locals, target).ok_or_else(or_else)?;
+    let fields = locals.get_struct_fields(id).ok_or_else(or_else)?;
+    let mut items: Vec<Option<&Initializer>> = vec![None; fields.len()];
+    let mut next = 0;
+    for item in init {
+        if let Some((name, loc)) = item.field {
+            next = match fields.iter().position(|f| f.name == name) {
+                Some(found) => found,
+                // The field was left out of a poisoned struct, so its value is too
+                None if locals.struct_poisoned(id) => fields.len(),
+                None => return Err(field_doesnt_exist(target, loc)),
+            };
+        }
+
+        let slot = match items.get_mut(next) {
+            Some(slot) => slot,
+            None => continue,
+        };
+
+        if let Some(prev) = slot {
+            return Err(error!(
+                "field is initialized twice",
+                prev.loc, "first initialized here", item.loc, "initialized again here"
+            ));
+        }
+
+        *slot = Some(item);
+        next += 1;
+    }
+
+    // Fields after the last one initialized are left off, and the ones before
+    // it are left uninitialized like in a declaration without an initializer
+    let written = items.iter().rposition(|i| i.is_some()).map(|i| i + 1);
+    let items = &items[..written.unwrap_or(0)];
+
+    let mut written_fields = Vec::new();
+    let mut offsets = Vec::new();
+    let mut end = 0;
+    let mut idx = 0;
+    while idx < items.len() {
+        let field = &fields[idx];
+        let (value, offset, bytes) = match field.bitfield {
+            // Bitfields are written together, a 64-bit window at a time
+            Some(_) => {
+                let window = bit_position(field) / 64;
+                let begin = idx;
+                while idx < items.len() {
+                    let f = &fields[idx];
+                    if f.bitfield.is_none() || bit_position(f) / 64 != window {
+                        break;
+                    }
+
+                    idx += 1;
+                }
+
+                let fields = fields[begin..idx].iter().zip(&items[begin..idx]);
+                pack_bitfields(&mut *locals, out.as_deref_mut(), fields, decl_loc)?
+            }
+            None => {
+                idx += 1;
+                let value = match items[idx - 1] {
+                    Some(item) if field.ty.size() == n32::NULL => {
+                        return Err(error!(
+                            "a flexible array member can't be initialized",
+                            item.loc, "initialized here"
+                        ));
+                    }
+                    Some(item) => {
+                        check_initializer(&mut *locals, out.as_deref_mut(), field.ty, item)?
+                    }
+                    None => TCExpr {
+                        kind: TCExprKind::Uninit,
+                        ty: field.ty,
+                        loc: decl_loc,
+                    },
+                };
+
+                (value, field.offset, field.ty.size().into())
+            }
+        };
+
+        if offset < end {
+            return Err(error!(
+                "can only use initializer lists on simple structs",
+                decl_loc,
+                format!("this has type {}", target.display_aka(locals.symbols()))
+            ));
+        }
+
+        end = offset + bytes;
+        written_fields.push(value);
+        offsets.push((offset, bytes));
+    }
+
+    let (fields, size) = (locals.add_array(written_fields), target.repr_size());
+    let offsets = locals.add_array(offsets);
+    return Ok((
+        TCExprKind::StructLit {
+            fields,
+            offsets,
+            size,
+        },
+        target,
+    ));
+}
+
+/// Where a bitfield's lowest bit is, counting from the start of its struct
+fn bit_position(field: &TCStructField) -> u32 {
+    return field.offset * 8 + field.bitfield.unwrap().offset;
+}
+
+/// Packs the values of bitfields in the same 64-bit window into one integer, and
+/// returns it along with the byte offset it goes at and how many of its low bytes
+/// to keep. Fields without a value are zeroed.
+fn pack_bitfields<'a>(
+    locals: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
+    fields: impl Iterator<Item = (&'a TCStructField, &'a Option<&'a Initializer>)> + Clone,
+    loc: CodeLoc,
+) -> Result<(TCExpr, u32, u32), Error> {
+    let bits = |f: &TCStructField| bit_position(f)..(bit_position(f) + f.bitfield.unwrap().width);
+    let begin = fields.clone().map(|(f, _)| bits(f).start).min().unwrap() / 8;
+    let end = fields.clone().map(|(f, _)| bits(f).end).max().unwrap();
+    let bytes = (end + 7) / 8 - begin;
+
+    let (ty, prim) = match bytes {
+        1 => (TCType::new(TCTypeBase::U8), TCPrimType::U8),
+        2 => (TCType::new(TCTypeBase::U16), TCPrimType::U16),
+        3 | 4 => (TCType::new(TCTypeBase::U32), TCPrimType::U32),
+        _ => (TCType::new(TCTypeBase::U64), TCPrimType::U64),
+    };
+
+    let lit = |value: u64| {
+        let kind = match prim {
+            TCPrimType::U8 => TCExprKind::U8Lit(value as u8),
+            TCPrimType::U16 => TCExprKind::U16Lit(value as u16),
+            TCPrimType::U32 => TCExprKind::U32Lit(value as u32),
+            _ => TCExprKind::U64Lit(value),
+        };
+
+        return TCExpr { kind, ty, loc };
+    };
+
+    // Constant values are packed here, so that globals can use them too
+    let mut constant = Some(0u64);
+    let mut packed = lit(0);
+    for (field, item) in fields {
+        let item = match item {
+            Some(item) => item,
+            None => continue,
+        };
 
-                TCExprKind::ArrayInit { elems, elem_ty }
-            }
-            x @ TCTypeModifier::VariableArray => {
-                *x = TCTypeModifier::Array(tc_exprs.len() as u32); // TODO overflow
-                let elems = locals.add_array(tc_exprs);
+        let value = check_initializer(&mut *locals, out.as_deref_mut(), field.ty, item)?;
+        let width = field.bitfield.unwrap().width;
+        let mask = if width == 64 { !0 } else { (1u64 << width) - 1 };
+        let shift = bits(field).start - begin * 8;
 
-                TCExprKind::ArrayInit { elems, elem_ty }
-            }
-            _ => unreachable!(),
+        let int = crate::optimizer::int_value(&value.kind);
+        constant = constant
+            .zip(int)
+            .map(|(packed, int)| packed | ((int as u64 & mask) << shift));
+
+        let value = locals.cast_convert(ty, value, value.loc).unwrap();
+        let (left, right) = (locals.add(value), locals.add(lit(mask)));
+        #[rustfmt::skip]
+        let kind = TCExprKind::BinOp { op: BinOp::BitAnd, op_type: prim, left, right };
+        let value = TCExpr { kind, ty, loc };
+
+        let right = TCExpr {
+            kind: TCExprKind::I8Lit(shift as i8),
+            ty: TCType::new(TCTypeBase::I8),
+            loc,
         };
+        let (left, right) = (locals.add(value), locals.add(right));
+        #[rustfmt::skip]
+        let kind = TCExprKind::BinOp { op: BinOp::LShift, op_type: prim, left, right };
+        let value = TCExpr { kind, ty, loc };
 
-        return Ok((array_init, target.to_ref(&*locals)));
+        let (left, right) = (locals.add(packed), locals.add(value));
+        #[rustfmt::skip]
+        let kind = TCExprKind::BinOp { op: BinOp::BitOr, op_type: prim, left, right };
+        packed = TCExpr { kind, ty, loc };
     }
 
-    let target = target.to_ref(&*locals);
-    let or_else = || {
-        error!(
-            "can only use initializer lists on structs and arrays",
-            decl_loc,
-            format!("this has type {}", target.display(locals.symbols()))
-        )
-    };
+    if let Some(constant) = constant {
+        packed = lit(constant);
+    }
 
-    let (is_struct, id) = target.get_id_strict().ok_or_else(or_else)?;
-    if !is_struct {
-        return Err(or_else());
+    return Ok((packed, begin, bytes));
+}
+
+/// `(struct point){ .x = 1 }` initializes an unnamed local, which lives until
+/// the end of the enclosing block. Returns the initialization, whose value is
+/// the literal's, and the local it initializes.
+pub fn check_compound_lit(
+    env: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
+    ty: TypeName,
+    items: &[Initializer],
+    loc: CodeLoc,
+) -> Result<(TCExpr, TCAssignTarget), Error> {
+    if out.is_none() {
+        return Err(error!(
+            "compound literals can only be used inside a function",
+            loc, "compound literal found here"
+        ));
     }
 
-    let mut written_fields = Vec::new();
-    let fields = get_fields(&*locals, target).ok_or_else(or_else)?;
-    let fields = locals.get_struct_fields(id).ok_or_else(or_else)?;
-    let mut offset = None;
-    for (field, expr) in fields.iter().zip(init.iter()) {
-        if let Some(offset) = offset {
-            if field.offset < offset {
-                return Err(error!(
-                    "can only use initializer lists on simple structs",
-                    decl_loc,
-                    format!("this has type {}", target.display(locals.symbols()))
-                ));
-            }
-        }
-        offset = Some(field.offset);
+    let base = parse_spec_quals(&mut *env, out.as_deref_mut(), ty.specifiers)?;
+    let ty = if let Some(decl) = ty.declarator {
+        let (ty, id) = check_decl(&mut *env, out.as_deref_mut(), base, &decl)?;
+        assert!(id == n32::NULL);
+        ty
+    } else {
+        TCType { base, mods: &[] }.to_ty_owned()
+    };
 
-        let tc_expr = check_expr(&mut *locals, expr)?;
-        let or_else = || conversion_error(field.ty, decl_loc, &tc_expr);
-        let tc_expr = locals
-            .assign_convert(field.ty, tc_expr, tc_expr.loc)
-            .ok_or_else(or_else)?;
-        written_fields.push(tc_expr);
+    let (init, ty) = check_initializer_list(&mut *env, out.as_deref_mut(), ty, items, loc)?;
+    if !ty.is_complete() {
+        return Err(error!(
+            "compound literal has incomplete type",
+            loc,
+            format!("this has type {}", ty.display_aka(env.symbols()))
+        ));
     }
 
-    let (fields, size) = (locals.add_array(written_fields), target.repr_size());
-    return Ok((TCExprKind::StructLit { fields, size }, target));
+    let label = env.add_temp(out.unwrap(), ty, loc);
+    let target = TCAssignTarget {
+        kind: TCAssignTargetKind::LocalIdent { label },
+        defn_loc: loc,
+        loc,
+        ty,
+        offset: 0,
+        bitfield: None,
+    };
+
+    let value = env.add(TCExpr {
+        kind: init,
+        ty,
+        loc,
+    });
+    let init = TCExpr {
+        kind: TCExprKind::Assign { target, value },
+        ty,
+        loc,
+    };
+
+    return Ok((init, target));
+}
+
+/// Checks the initializer of a variable of type `ty`, giving its value and the
+/// variable's type, which can be completed by the initializer
+pub fn check_decl_init(
+    locals: &mut TypeEnv,
+    out: Option<&mut FuncEnv>,
+    ty: TCTypeOwned,
+    init: &Initializer,
+    decl_loc: CodeLoc,
+) -> Result<(TCExprKind, TCType), Error> {
+    return match init.kind {
+        InitializerKind::Expr(Expr {
+            kind: ExprKind::StringLit(string),
+            loc,
+        }) if ty.is_array() => check_string_init(&mut *locals, ty, string, *loc),
+        InitializerKind::Expr(expr) => {
+            let tc_expr = check_expr(&mut *locals, out, expr)?;
+            let ty = ty.to_ref(&*locals);
+            let or_else = || conversion_error(locals.symbols(), ty, decl_loc, &tc_expr);
+            let tc_expr = locals
+                .assign_convert(ty, tc_expr, decl_loc)
+                .ok_or_else(or_else)?;
+
+            Ok((tc_expr.kind, ty))
+        }
+
+        // Simple form of initializer lists
+        InitializerKind::List(exprs) => check_initializer_list(locals, out, ty, exprs, decl_loc),
+    };
 }
 
 pub fn check_declaration(
@@ -1330,14 +2077,19 @@ pub fn check_declaration(
     mut out: Option<&mut FuncEnv>,
     declaration: Declaration,
 ) -> Result<(), Error> {
-    let (sc, base) = parse_decl_specs(locals, declaration.specifiers)?;
+    let (sc, base) = parse_decl_specs(locals, out.as_deref_mut(), declaration.specifiers)?;
 
     if let StorageClass::Typedef = sc {
         debug_assert!(declaration.declarators.len() == 1);
         let init_declarator = &declaration.declarators[0];
         debug_assert!(init_declarator.initializer.is_none());
 
-        let (ty, id) = check_decl(&mut *locals, base, &init_declarator.declarator)?;
+        let (ty, id) = check_decl(
+            &mut *locals,
+            out.as_deref_mut(),
+            base,
+            &init_declarator.declarator,
+        )?;
         let (ty, ident) = (ty.to_ref(&*locals), id.into());
         let loc = declaration.loc;
 
@@ -1346,46 +2098,55 @@ pub fn check_declaration(
     }
 
     for decl in declaration.declarators {
-        let (ty, id) = check_decl(&mut *locals, base, &decl.declarator)?;
+        let (ty, id) = check_decl(&mut *locals, out.as_deref_mut(), base, &decl.declarator)?;
         let ident: u32 = id.into();
         let loc = decl.loc;
 
-        let (init, ty) = if let Some(init) = decl.initializer {
-            let (init, ty) = match init.kind {
-                InitializerKind::Expr(expr) => {
-                    let tc_expr = check_expr(&mut *locals, expr)?;
-                    let ty = ty.to_ref(&*locals);
-                    let or_else = || conversion_error(ty, decl.declarator.loc, &tc_expr);
-                    let tc_expr = locals
-                        .assign_convert(ty, tc_expr, decl.declarator.loc)
-                        .ok_or_else(or_else)?;
-
-                    (tc_expr.kind, ty)
-                }
+        if let (true, Some(init)) = (ty.is_function(), decl.initializer) {
+            return Err(error!(
+                "a function can't have an initializer",
+                decl.declarator.loc, "function declared here", init.loc, "initializer here"
+            ));
+        }
 
-                // Simple form of initializer lists
-                InitializerKind::List(exprs) => {
-                    check_initializer_list(&mut *locals, ty, exprs, decl.declarator.loc)?
+        // A variable whose initializer has an error is still declared, so that using
+        // it later isn't reported as another error
+        let init = decl.initializer.map(|init| {
+            let decl_loc = decl.declarator.loc;
+            check_decl_init(
+                &mut *locals,
+                out.as_deref_mut(),
+                ty.clone(),
+                &init,
+                decl_loc,
+            )
+        });
+        let (init, ty) = match init {
+            Some(Ok((init, ty))) => {
+                let init = match sc {
+                    StorageClass::Extern => TCDeclInit::ExternInit(init),
+                    StorageClass::Default => TCDeclInit::Default(init),
+                    StorageClass::Static => TCDeclInit::Static(init),
+                    StorageClass::Typedef => unreachable!(),
+                };
+                (init, ty)
+            }
+            Some(Err(err)) if !ty.is_complete() => return Err(err),
+            init => {
+                if let Some(Err(err)) = init {
+                    locals.add_error(err);
                 }
-            };
 
-            let init = match sc {
-                StorageClass::Extern => TCDeclInit::ExternInit(init),
-                StorageClass::Default => TCDeclInit::Default(init),
-                StorageClass::Static => TCDeclInit::Static(init),
-                StorageClass::Typedef => unreachable!(),
-            };
-            (init, ty)
-        } else {
-            let init = match sc {
-                StorageClass::Extern => TCDeclInit::Extern,
-                StorageClass::Default => TCDeclInit::DefaultUninit,
-                StorageClass::Static => TCDeclInit::Static(TCExprKind::Uninit),
-                StorageClass::Typedef => unreachable!(),
-            };
+                let init = match sc {
+                    StorageClass::Extern => TCDeclInit::Extern,
+                    StorageClass::Default => TCDeclInit::DefaultUninit,
+                    StorageClass::Static => TCDeclInit::Static(TCExprKind::Uninit),
+                    StorageClass::Typedef => unreachable!(),
+                };
 
-            let ty = ty.to_ref(&*locals);
-            (init, ty)
+                let ty = ty.to_ref(&*locals);
+                (init, ty)
+            }
         };
 
         if !ty.is_complete() {
@@ -1420,7 +2181,11 @@ pub fn eval_expr(expr: TCExpr) -> Result<TCExpr, Error> {
     }
 }
 
-pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
+pub fn check_expr(
+    env: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
+    expr: &Expr,
+) -> Result<TCExpr, Error> {
     match expr.kind {
         ExprKind::IntLit(val) => {
             return Ok(TCExpr {
@@ -1483,7 +2248,7 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
         ExprKind::ParenList(exprs) => {
             let mut tc_exprs = Vec::new();
             for expr in exprs {
-                tc_exprs.push(check_expr(env, expr)?);
+                tc_exprs.push(check_expr(env, out.as_deref_mut(), expr)?);
             }
 
             if tc_exprs.len() == 0 {
@@ -1502,17 +2267,51 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
             });
         }
 
+        ExprKind::StmtExpr(block) => {
+            let out = match out {
+                Some(out) => out,
+                None => {
+                    return Err(error!(
+                        "statement expressions can only be used inside a function",
+                        expr.loc, "statement expression found here"
+                    ))
+                }
+            };
+
+            let begin = out.ops.len() as u32;
+            out.ops.push(TCOpcode {
+                kind: TCOpcodeKind::StmtExpr { end: begin },
+                loc: expr.loc,
+            });
+
+            let mut scope = env.child(out, block.loc);
+            let (value, ty) = check_stmt_expr_body(&mut scope, out, block)?;
+            scope.close_scope(out);
+
+            let end = out.ops.len() as u32;
+            out.ops[begin as usize].kind = TCOpcodeKind::StmtExpr { end };
+
+            return Ok(TCExpr {
+                kind: TCExprKind::StmtExpr { begin, value },
+                ty,
+                loc: expr.loc,
+            });
+        }
+        ExprKind::CompoundLit { ty, items } => {
+            let (init, _) = check_compound_lit(&mut *env, out.as_deref_mut(), ty, items, expr.loc)?;
+            return Ok(init);
+        }
         ExprKind::SizeofTy(ast_ty) => {
-            let base = parse_spec_quals(&mut *env, ast_ty.specifiers)?;
+            let base = parse_spec_quals(&mut *env, out.as_deref_mut(), ast_ty.specifiers)?;
             let ty = if let Some(decl) = ast_ty.declarator {
-                let (ty, id) = check_decl(&mut *env, base, &decl)?;
+                let (ty, id) = check_decl(&mut *env, out.as_deref_mut(), base, &decl)?;
                 assert!(id == n32::NULL);
                 ty.to_ref(&*env)
             } else {
                 TCType { base, mods: &[] }
             };
 
-            let size = ty.size().unwrap_or_else(|| ty.repr_size());
+            let size = sizeof(&*env, ty, expr.loc)?;
 
             return Ok(TCExpr {
                 kind: TCExprKind::U64Lit(size as u64),
@@ -1521,8 +2320,17 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
             });
         }
         ExprKind::SizeofExpr(e) => {
-            let expr = check_expr(&mut *env, e)?;
-            let size = expr.ty.size().unwrap_or_else(|| expr.ty.repr_size());
+            let expr = check_expr(&mut *env, out.as_deref_mut(), e)?;
+            if let ExprKind::Member { .. } | ExprKind::PtrMember { .. } = e.kind {
+                if expr.ty.is_array() && expr.ty.size() == n32::NULL {
+                    return Err(error!(
+                        "can't take the size of a flexible array member",
+                        expr.loc, "size taken here"
+                    ));
+                }
+            }
+
+            let size = sizeof(&*env, expr.ty, expr.loc)?;
 
             return Ok(TCExpr {
                 kind: TCExprKind::U64Lit(size as u64),
@@ -1531,17 +2339,21 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
             });
         }
 
-        ExprKind::BinOp(op, l, r) => return check_bin_op(&mut *env, op, l, r, expr.loc),
+        ExprKind::BinOp(op, l, r) => {
+            return check_bin_op(&mut *env, out.as_deref_mut(), op, l, r, expr.loc)
+        }
 
-        ExprKind::UnaryOp(op, operand) => return check_un_op(&mut *env, op, operand, expr.loc),
+        ExprKind::UnaryOp(op, operand) => {
+            return check_un_op(&mut *env, out.as_deref_mut(), op, operand, expr.loc)
+        }
 
         ExprKind::Assign { op, to, val } => {
-            let target = check_assign_target(&mut *env, to)?;
-            let val = check_expr(&mut *env, val)?;
+            let target = check_assign_target(&mut *env, out.as_deref_mut(), to)?;
+            let val = check_expr(&mut *env, out.as_deref_mut(), val)?;
 
             if let AssignOp::MutAssign(op) = op {
                 let or_else = || bin_assign_op_non_primitive(target.ty, target.loc);
-                let op_type = target.ty.to_prim_type().ok_or_else(or_else)?;
+                let op_type = prim_type(env, target.ty).ok_or_else(or_else)?;
 
                 if op == BinOp::LShift || op == BinOp::RShift {
                     if !target.ty.is_integer() || !val.ty.is_integer() {
@@ -1564,10 +2376,35 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
                     });
                 }
 
-                let or_else = || conversion_error(target.ty, to.loc, &val);
-                let val = env
-                    .assign_convert(target.ty, val, expr.loc)
-                    .ok_or_else(or_else)?;
+                let valid = match (op, op_type) {
+                    (BinOp::BitAnd, _) | (BinOp::BitOr, _) | (BinOp::BitXor, _) => {
+                        target.ty.is_integer() && val.ty.is_integer()
+                    }
+                    (BinOp::Add, _) | (BinOp::Sub, _) => true,
+                    (_, TCPrimType::Pointer { .. }) => false,
+                    _ => true,
+                };
+
+                if !valid {
+                    return Err(invalid_bin_op_assign(&target, &val));
+                }
+
+                // `ptr += n` moves the pointer by `n` elements, so `n` has to be an integer
+                let or_else = || conversion_error(env.symbols(), target.ty, to.loc, &val);
+                let val = if let TCPrimType::Pointer { stride } = op_type {
+                    if !val.ty.is_integer() {
+                        return Err(invalid_bin_op_assign(&target, &val));
+                    }
+
+                    if stride == n32::NULL {
+                        return Err(ptr_to_incomplete_type(env.symbols(), target.ty, to.loc));
+                    }
+
+                    env.cast_convert(target.ty, val, expr.loc)
+                } else {
+                    env.assign_convert(target.ty, val, expr.loc)
+                };
+                let val = val.ok_or_else(or_else)?;
                 let value = env.add(val);
                 return Ok(TCExpr {
                     kind: TCExprKind::MutAssign {
@@ -1580,7 +2417,7 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
                     loc: expr.loc,
                 });
             } else {
-                let or_else = || conversion_error(target.ty, to.loc, &val);
+                let or_else = || conversion_error(env.symbols(), target.ty, to.loc, &val);
                 let val = env
                     .assign_convert(target.ty, val, expr.loc)
                     .ok_or_else(or_else)?;
@@ -1595,18 +2432,18 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
         }
 
         ExprKind::Cast { to, from } => {
-            let base = parse_spec_quals(&mut *env, to.specifiers)?;
+            let base = parse_spec_quals(&mut *env, out.as_deref_mut(), to.specifiers)?;
             let ty = if let Some(decl) = to.declarator {
-                let (ty, id) = check_decl(&mut *env, base, &decl)?;
+                let (ty, id) = check_decl(&mut *env, out.as_deref_mut(), base, &decl)?;
                 assert!(id == n32::NULL);
                 ty.to_ref(&*env)
             } else {
                 TCType { base, mods: &[] }
             };
-            let from = check_expr(&mut *env, from)?;
+            let from = check_expr(&mut *env, out.as_deref_mut(), from)?;
 
-            let or_else = || conversion_error(ty, to.loc, &from);
-            return env.assign_convert(ty, from, expr.loc).ok_or_else(or_else);
+            let or_else = || conversion_error(env.symbols(), ty, to.loc, &from);
+            return env.cast_convert(ty, from, expr.loc).ok_or_else(or_else);
         }
 
         ExprKind::Ternary {
@@ -1614,12 +2451,12 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
             if_true,
             if_false,
         } => {
-            let cond = check_expr(&mut *env, condition)?;
+            let cond = check_expr(&mut *env, out.as_deref_mut(), condition)?;
             let or_else = || condition_non_primitive(cond.ty, cond.loc);
             let cond_ty = cond.ty.to_prim_type().ok_or_else(or_else)?;
 
-            let if_true = check_expr(&mut *env, if_true)?;
-            let if_false = check_expr(&mut *env, if_false)?;
+            let if_true = check_expr(&mut *env, out.as_deref_mut(), if_true)?;
+            let if_false = check_expr(&mut *env, out.as_deref_mut(), if_false)?;
 
             let (if_true, if_false) = if TCType::ty_eq(&if_true.ty, &if_false.ty) {
                 (if_true, if_false)
@@ -1643,20 +2480,44 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
         }
 
         ExprKind::Member { base, member } => {
-            let base = check_expr(&mut *env, base)?;
+            let base = check_expr(&mut *env, out.as_deref_mut(), base)?;
             let field = check_field_access(&mut *env, base.ty, member, expr.loc)?;
 
+            // Arrays are used through their address, so when the struct is in
+            // memory, point into it instead of copying the whole struct onto
+            // the stack. This is what makes `s.data` work for a flexible array
+            // member, which isn't part of the struct's value at all.
+            if field.ty.is_array() {
+                match check_assign_target(&mut *env, out.as_deref_mut(), expr) {
+                    Ok(target) => {
+                        return Ok(TCExpr {
+                            kind: TCExprKind::Ref(target),
+                            ty: field.ty,
+                            loc: expr.loc,
+                        });
+                    }
+                    Err(_) if field.ty.size() == n32::NULL => {
+                        return Err(error!(
+                            "can't use the flexible array member of a struct that isn't in memory",
+                            base.loc, "struct found here"
+                        ));
+                    }
+                    Err(_) => {}
+                }
+            }
+
             return Ok(TCExpr {
                 ty: field.ty,
                 loc: expr.loc,
                 kind: TCExprKind::Member {
                     base: env.add(base),
                     offset: field.offset,
+                    bitfield: field.bitfield,
                 },
             });
         }
         ExprKind::PtrMember { base, member } => {
-            let base = check_expr(&mut *env, base)?;
+            let base = check_expr(&mut *env, out.as_deref_mut(), base)?;
             let or_else = || not_a_struct_pointer(env.symbols(), base.ty, base.loc);
             let base_ty = base.ty.deref().ok_or_else(or_else)?;
             let field = check_field_access(&mut *env, base_ty, member, expr.loc)?;
@@ -1667,6 +2528,7 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
                 kind: TCExprKind::PtrMember {
                     base: env.add(base),
                     offset: field.offset,
+                    bitfield: field.bitfield,
                 },
             });
         }
@@ -1674,11 +2536,11 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
         ExprKind::Call { function, params } => {
             if let ExprKind::Ident(id) = function.kind {
                 if let Some(trans) = BUILTINS.get(&id) {
-                    return trans(env, expr.loc, params);
+                    return trans(env, out, expr.loc, params);
                 }
             }
 
-            let func = check_expr(&mut *env, function)?;
+            let func = check_expr(&mut *env, out.as_deref_mut(), function)?;
             let func_type = if let Some(f) = func.ty.to_func_type(&*env) {
                 f
             } else {
@@ -1687,7 +2549,7 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
                     func.loc,
                     format!(
                         "expr found here to be type {}",
-                        func.ty.display(env.symbols())
+                        func.ty.display_aka(env.symbols())
                     )
                 ));
             };
@@ -1706,9 +2568,9 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
 
                 let typed_params = &params[..ftype_params.types.len()];
                 for (idx, param) in typed_params.iter().enumerate() {
-                    let mut expr = check_expr(&mut *env, param)?;
+                    let mut expr = check_expr(&mut *env, out.as_deref_mut(), param)?;
                     let param_type = ftype_params.types[idx];
-                    let or_else = || param_conversion_error(param_type, &expr);
+                    let or_else = || param_conversion_error(env.symbols(), param_type, &expr);
                     expr = env
                         .assign_convert(param_type, expr, expr.loc)
                         .ok_or_else(or_else)?;
@@ -1722,7 +2584,7 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
             };
 
             for param in default_conversion_params {
-                let mut expr = check_expr(env, param)?;
+                let mut expr = check_expr(env, out.as_deref_mut(), param)?;
                 if expr.ty.is_integer() && expr.ty.repr_size() < 4 {
                     expr = env
                         .assign_convert(TCType::new(TCTypeBase::I32), expr, expr.loc)
@@ -1749,6 +2611,7 @@ pub fn check_expr(env: &mut TypeEnv, expr: &Expr) -> Result<TCExpr, Error> {
 
 pub fn check_bin_op(
     env: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
     op: BinOp,
     l: &Expr,
     r: &Expr,
@@ -1756,8 +2619,8 @@ pub fn check_bin_op(
 ) -> Result<TCExpr, Error> {
     match op {
         BinOp::BoolOr => {
-            let l = check_expr(&mut *env, l)?;
-            let r = check_expr(&mut *env, r)?;
+            let l = check_expr(&mut *env, out.as_deref_mut(), l)?;
+            let r = check_expr(&mut *env, out.as_deref_mut(), r)?;
             let or_else = || condition_non_primitive(l.ty, l.loc);
             let cond_ty = l.ty.to_prim_type().ok_or_else(or_else)?;
             let or_else = || condition_non_primitive(r.ty, r.loc);
@@ -1787,8 +2650,8 @@ pub fn check_bin_op(
             });
         }
         BinOp::BoolAnd => {
-            let l = check_expr(&mut *env, l)?;
-            let r = check_expr(&mut *env, r)?;
+            let l = check_expr(&mut *env, out.as_deref_mut(), l)?;
+            let r = check_expr(&mut *env, out.as_deref_mut(), r)?;
             let or_else = || condition_non_primitive(l.ty, l.loc);
             let cond_ty = l.ty.to_prim_type().ok_or_else(or_else)?;
             let or_else = || condition_non_primitive(r.ty, r.loc);
@@ -1818,7 +2681,7 @@ pub fn check_bin_op(
             });
         }
         BinOp::Index => {
-            let sum = check_bin_op(&mut *env, BinOp::Add, l, r, loc)?;
+            let sum = check_bin_op(&mut *env, out.as_deref_mut(), BinOp::Add, l, r, loc)?;
             let or_else = || error!("cannot dereference value", loc, "value found here");
             let ty = sum.ty.deref().ok_or_else(or_else)?;
 
@@ -1831,11 +2694,25 @@ pub fn check_bin_op(
         _ => {}
     }
 
-    let l = check_expr(&mut *env, l)?;
-    let r = check_expr(&mut *env, r)?;
+    let l = check_expr(&mut *env, out.as_deref_mut(), l)?;
+    let r = check_expr(&mut *env, out.as_deref_mut(), r)?;
     let ptype_err =
         |loc: CodeLoc| move || error!("couldn't do operation on value", loc, "value found here");
 
+    let (l, r) = match op {
+        BinOp::Eq | BinOp::Neq => null_to_ptr(env, l, r),
+        _ => (l, r),
+    };
+
+    // A function can only be compared; it's not a pointer until it's converted
+    // to one
+    if l.ty.is_function() || r.ty.is_function() {
+        match op {
+            BinOp::Lt | BinOp::Gt | BinOp::Leq | BinOp::Geq | BinOp::Eq | BinOp::Neq => {}
+            _ => return Err(invalid_bin_op(&l, &r)),
+        }
+    }
+
     if l.ty.is_pointer() || l.ty.is_array() || r.ty.is_pointer() || r.ty.is_array() {
         // allowed operations are addition w/ integer, subtraction w/ integer/pointer
 
@@ -1849,12 +2726,9 @@ pub fn check_bin_op(
                     return Err(invalid_bin_op(&l, &r));
                 };
 
-                let stride = ptr.ty.pointer_stride();
+                let stride = pointer_stride(env, ptr.ty);
                 if stride == n32::NULL {
-                    return Err(error!(
-                        "cannot perform arithmetic on pointer type",
-                        l.loc, "pointer found here"
-                    ));
+                    return Err(ptr_to_incomplete_type(env.symbols(), ptr.ty, ptr.loc));
                 }
 
                 let stride: u32 = stride.into();
@@ -1941,10 +2815,10 @@ pub fn check_bin_op(
                     // pointer subtraction
                     let s = env.symbols();
                     let or_else = |e: TCExpr| move || ptr_to_incomplete_type(s, e.ty, e.loc);
-                    let l_stride = l.ty.pointer_stride().ok_or_else(or_else(l))?;
-                    let r_stride = r.ty.pointer_stride().ok_or_else(or_else(r))?;
+                    let l_stride = pointer_stride(env, l.ty).ok_or_else(or_else(l))?;
+                    let r_stride = pointer_stride(env, r.ty).ok_or_else(or_else(r))?;
                     if l_stride != r_stride {
-                        let (l_td, r_td) = (l.ty.display(s), r.ty.display(s));
+                        let (l_td, r_td) = (l.ty.display_aka(s), r.ty.display_aka(s));
                         return Err(error!(
                             "pointer subtraction performed on pointers to types of different sizes",
                             l.loc,
@@ -1983,8 +2857,10 @@ pub fn check_bin_op(
                     return Ok(result);
                 };
 
-                let stride = ptr.ty.pointer_stride();
-                if stride == n32::NULL {}
+                let stride = pointer_stride(env, ptr.ty);
+                if stride == n32::NULL {
+                    return Err(ptr_to_incomplete_type(env.symbols(), ptr.ty, ptr.loc));
+                }
 
                 let stride: u32 = stride.into();
 
@@ -2062,8 +2938,10 @@ pub fn check_bin_op(
                     ty: ptr.ty,
                 });
             }
-            BinOp::Lt | BinOp::Gt | BinOp::Leq | BinOp::Geq => {}
-            BinOp::Eq | BinOp::Neq => {}
+            BinOp::Lt | BinOp::Gt | BinOp::Leq | BinOp::Geq => {
+                check_ptr_comparison(env, false, &l, &r)?;
+            }
+            BinOp::Eq | BinOp::Neq => check_ptr_comparison(env, true, &l, &r)?,
             _ => return Err(invalid_bin_op(&l, &r)),
         }
     }
@@ -2089,6 +2967,12 @@ pub fn check_bin_op(
         });
     }
 
+    if let BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor = op {
+        if !l.ty.is_integer() || !r.ty.is_integer() {
+            return Err(invalid_bin_op(&l, &r));
+        }
+    }
+
     let (left, right, op_type) = prim_unify(env, l, r)?;
 
     let ty = match op {
@@ -2107,6 +2991,71 @@ pub fn check_bin_op(
     });
 }
 
+/// Pointers can be compared if they point to the same type. `==` and `!=` also
+/// allow `void *` on either side, and null pointer constants (which should've
+/// already gone through `null_to_ptr`).
+fn check_ptr_comparison(env: &TypeEnv, eq: bool, l: &TCExpr, r: &TCExpr) -> Result<(), Error> {
+    let is_ptr = |e: &TCExpr| e.ty.is_pointer() || e.ty.is_array() || e.ty.is_function();
+    let (l_ptr, r_ptr) = (is_ptr(l), is_ptr(r));
+    let syms = env.symbols();
+
+    if l_ptr && r_ptr {
+        let is_void = |e: &TCExpr| e.ty.deref().map(|ty| ty.is_void()).unwrap_or(false);
+        let same = match (l.ty.to_func_type(env), r.ty.to_func_type(env)) {
+            (Some(l_func), Some(r_func)) => l_func == r_func,
+            (None, None) => TCType::ty_eq(&l.ty.deref().unwrap(), &r.ty.deref().unwrap()),
+            _ => false,
+        };
+
+        if same || (eq && (is_void(l) || is_void(r))) {
+            return Ok(());
+        }
+
+        return Err(error!(
+            "comparison of distinct pointer types",
+            l.loc,
+            format!("this has the type `{}`", l.ty.display_aka(syms)),
+            r.loc,
+            format!("this has the type `{}`", r.ty.display_aka(syms))
+        ));
+    }
+
+    let (ptr, other) = if l_ptr { (l, r) } else { (r, l) };
+    if !other.ty.is_integer() {
+        return Err(invalid_bin_op(l, r));
+    }
+
+    let message = if eq {
+        "comparison between pointer and integer"
+    } else {
+        "ordered comparison between pointer and integer"
+    };
+
+    return Err(error!(
+        message,
+        ptr.loc,
+        format!("this has the type `{}`", ptr.ty.display_aka(syms)),
+        other.loc,
+        format!("this has the type `{}`", other.ty.display_aka(syms))
+    ));
+}
+
+/// Gives a null pointer constant the type of the pointer it's compared with, so
+/// that `ptr == 0` and `ptr == NULL` compare pointers
+fn null_to_ptr(env: &TypeEnv, l: TCExpr, r: TCExpr) -> (TCExpr, TCExpr) {
+    if l.ty.is_pointer() && !r.ty.is_pointer() && r.is_null_ptr_constant() {
+        let r = env.assign_convert(l.ty, r, r.loc).unwrap();
+        return (l, r);
+    }
+
+    if r.ty.is_pointer() && !l.ty.is_pointer() && l.is_null_ptr_constant() {
+        let l = env.assign_convert(r.ty, l, l.loc).unwrap();
+        return (l, r);
+    }
+
+    return (l, r);
+}
+
 pub fn prim_unify(
     env: &TypeEnv,
     l: TCExpr,
@@ -2216,26 +3165,59 @@ pub fn check_field_access(
     };
 
     let res = member_info.iter().find(|m| m.name == field);
-    let or_else = || field_doesnt_exist(ty, loc);
+    let or_else = || match is_struct && env.struct_poisoned(id) {
+        true => poisoned_struct_use(),
+        false => field_doesnt_exist(ty, loc),
+    };
     return Ok(*res.ok_or_else(or_else)?);
 }
 
-pub fn check_assign_target(env: &mut TypeEnv, expr: &Expr) -> Result<TCAssignTarget, Error> {
+pub fn check_assign_target(
+    env: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
+    expr: &Expr,
+) -> Result<TCAssignTarget, Error> {
     match &expr.kind {
         ExprKind::Ident(id) => return env.assign_ident(*id, expr.loc),
 
         ExprKind::Member { base, member } => {
-            let mut base = check_assign_target(&mut *env, base)?;
+            let mut base = check_assign_target(&mut *env, out.as_deref_mut(), base)?;
             let field = check_field_access(&mut *env, base.ty, *member, base.loc)?;
 
             base.ty = field.ty;
             base.offset += field.offset;
+            base.bitfield = field.bitfield;
             base.loc = expr.loc;
 
             return Ok(base);
         }
+        ExprKind::CompoundLit { ty, items } => {
+            // Initialize the literal, then point at it
+            let (init, target) =
+                check_compound_lit(&mut *env, out.as_deref_mut(), *ty, items, expr.loc)?;
+            let ptr_ty = TCType::new_ptr(TCTypeBase::InternalTypedef(env.add(target.ty)));
+            let ptr = TCExpr {
+                kind: TCExprKind::Ref(target),
+                ty: ptr_ty,
+                loc: expr.loc,
+            };
+            let ptr = TCExpr {
+                kind: TCExprKind::ParenList(env.add_array(vec![init, ptr])),
+                ty: ptr_ty,
+                loc: expr.loc,
+            };
+
+            return Ok(TCAssignTarget {
+                kind: TCAssignTargetKind::Ptr(env.add(ptr)),
+                offset: 0,
+                bitfield: None,
+                ty: target.ty,
+                defn_loc: expr.loc,
+                loc: expr.loc,
+            });
+        }
         ExprKind::PtrMember { base, member } => {
-            let base = check_expr(&mut *env, base)?;
+            let base = check_expr(&mut *env, out.as_deref_mut(), base)?;
             let or_else = || not_a_struct_pointer(env.symbols(), base.ty, base.loc);
             let base_ty = base.ty.deref().ok_or_else(or_else)?;
             let field = check_field_access(&mut *env, base_ty, *member, expr.loc)?;
@@ -2243,6 +3225,7 @@ pub fn check_assign_target(env: &mut TypeEnv, expr: &Expr) -> Result<TCAssignTar
             return Ok(TCAssignTarget {
                 kind: TCAssignTargetKind::Ptr(env.add(base)),
                 offset: field.offset,
+                bitfield: field.bitfield,
                 ty: field.ty,
                 defn_loc: base.loc,
                 loc: expr.loc,
@@ -2250,7 +3233,7 @@ pub fn check_assign_target(env: &mut TypeEnv, expr: &Expr) -> Result<TCAssignTar
         }
 
         ExprKind::UnaryOp(UnaryOp::Deref, ptr) => {
-            let ptr = check_expr(&mut *env, ptr)?;
+            let ptr = check_expr(&mut *env, out.as_deref_mut(), ptr)?;
             if let TCExprKind::Ref(mut target) = ptr.kind {
                 target.loc = expr.loc;
                 target.defn_loc = ptr.loc;
@@ -2266,10 +3249,18 @@ pub fn check_assign_target(env: &mut TypeEnv, expr: &Expr) -> Result<TCAssignTar
                 defn_loc: ptr.loc,
                 ty,
                 offset: 0,
+                bitfield: None,
             });
         }
         ExprKind::BinOp(BinOp::Index, ptr, offset) => {
-            let sum = check_bin_op(&mut *env, BinOp::Add, ptr, offset, expr.loc)?;
+            let sum = check_bin_op(
+                &mut *env,
+                out.as_deref_mut(),
+                BinOp::Add,
+                ptr,
+                offset,
+                expr.loc,
+            )?;
             let or_else = || error!("cannot dereference value", ptr.loc, "value found here");
             let ty = sum.ty.deref().ok_or_else(or_else)?;
 
@@ -2279,6 +3270,7 @@ pub fn check_assign_target(env: &mut TypeEnv, expr: &Expr) -> Result<TCAssignTar
                 defn_loc: ptr.loc,
                 ty,
                 offset: 0,
+                bitfield: None,
             });
         }
 
@@ -2293,6 +3285,7 @@ pub fn check_assign_target(env: &mut TypeEnv, expr: &Expr) -> Result<TCAssignTar
 
 pub fn check_un_op(
     env: &mut TypeEnv,
+    mut out: Option<&mut FuncEnv>,
     op: UnaryOp,
     obj: &Expr,
     loc: CodeLoc,
@@ -2302,7 +3295,14 @@ pub fn check_un_op(
 
     match op {
         UnaryOp::Ref => {
-            let target = check_assign_target(env, obj)?;
+            let target = check_assign_target(env, out.as_deref_mut(), obj)?;
+            if target.bitfield.is_some() {
+                return Err(error!(
+                    "cannot take the address of a bitfield",
+                    obj.loc, "bitfield found here"
+                ));
+            }
+
             let ty = TCType::new_ptr(TCTypeBase::InternalTypedef(env.add(target.ty)));
 
             return Ok(TCExpr {
@@ -2312,9 +3312,16 @@ pub fn check_un_op(
             });
         }
         UnaryOp::Deref => {
-            let ptr = check_expr(&mut *env, obj)?;
+            let ptr = check_expr(&mut *env, out.as_deref_mut(), obj)?;
             let or_else = || error!("cannot dereference type", ptr.loc, "value found here");
             let ty = ptr.ty.deref().ok_or_else(or_else)?;
+            if ty.is_void() {
+                return Err(error!(
+                    "can't dereference a `void*`",
+                    ptr.loc, "cast this to a pointer to what it points to first"
+                ));
+            }
+
             return Ok(TCExpr {
                 kind: TCExprKind::Deref(env.add(ptr)),
                 ty,
@@ -2323,8 +3330,8 @@ pub fn check_un_op(
         }
 
         UnaryOp::PostDecr => {
-            let value = check_assign_target(env, obj)?;
-            let decr_ty = value.ty.to_prim_type().ok_or_else(ptype_err(value.loc))?;
+            let value = check_assign_target(env, out.as_deref_mut(), obj)?;
+            let decr_ty = prim_type(env, value.ty).ok_or_else(ptype_err(value.loc))?;
 
             if let TCPrimType::Pointer { stride: n32::NULL } = decr_ty {
                 return Err(ptr_to_incomplete_type(env.symbols(), value.ty, loc));
@@ -2337,8 +3344,8 @@ pub fn check_un_op(
             });
         }
         UnaryOp::PostIncr => {
-            let value = check_assign_target(env, obj)?;
-            let incr_ty = value.ty.to_prim_type().ok_or_else(ptype_err(value.loc))?;
+            let value = check_assign_target(env, out.as_deref_mut(), obj)?;
+            let incr_ty = prim_type(env, value.ty).ok_or_else(ptype_err(value.loc))?;
 
             if let TCPrimType::Pointer { stride: n32::NULL } = incr_ty {
                 return Err(ptr_to_incomplete_type(env.symbols(), value.ty, loc));
@@ -2352,23 +3359,27 @@ pub fn check_un_op(
         }
 
         UnaryOp::PreDecr => {
-            let target = check_assign_target(env, obj)?;
+            let target = check_assign_target(env, out.as_deref_mut(), obj)?;
             let or_else = || bin_assign_op_non_primitive(target.ty, target.loc);
-            let op_type = target.ty.to_prim_type().ok_or_else(or_else)?;
+            let op_type = prim_type(env, target.ty).ok_or_else(or_else)?;
 
             let (kind, ty) = match op_type {
                 TCPrimType::I8 => (TCExprKind::I8Lit(1), TCType::new(TCTypeBase::I8)),
+                TCPrimType::U8 => (TCExprKind::U8Lit(1), TCType::new(TCTypeBase::U8)),
+                TCPrimType::I16 => (TCExprKind::I16Lit(1), TCType::new(TCTypeBase::I16)),
+                TCPrimType::U16 => (TCExprKind::U16Lit(1), TCType::new(TCTypeBase::U16)),
                 TCPrimType::I32 => (TCExprKind::I32Lit(1), TCType::new(TCTypeBase::I32)),
                 TCPrimType::U32 => (TCExprKind::U32Lit(1), TCType::new(TCTypeBase::U32)),
+                TCPrimType::I64 => (TCExprKind::I64Lit(1), TCType::new(TCTypeBase::I64)),
                 TCPrimType::U64 => (TCExprKind::U64Lit(1), TCType::new(TCTypeBase::U64)),
+                TCPrimType::F32 => (TCExprKind::F32Lit(1.0), TCType::new(TCTypeBase::F32)),
+                TCPrimType::F64 => (TCExprKind::F64Lit(1.0), TCType::new(TCTypeBase::F64)),
 
                 TCPrimType::Pointer { stride } => {
                     let or_else = || ptr_to_incomplete_type(env.symbols(), target.ty, loc);
                     let stride = stride.ok_or_else(or_else)? as u64;
                     (TCExprKind::U64Lit(stride), TCType::new(TCTypeBase::U64))
                 }
-
-                x => unimplemented!("predecr for {:?}", x),
             };
 
             let loc = obj.loc;
@@ -2383,9 +3394,9 @@ pub fn check_un_op(
             });
         }
         UnaryOp::PreIncr => {
-            let target = check_assign_target(env, obj)?;
+            let target = check_assign_target(env, out.as_deref_mut(), obj)?;
             let or_else = || bin_assign_op_non_primitive(target.ty, target.loc);
-            let op_type = target.ty.to_prim_type().ok_or_else(or_else)?;
+            let op_type = prim_type(env, target.ty).ok_or_else(or_else)?;
 
             let (kind, ty) = match op_type {
                 TCPrimType::I8 => (TCExprKind::I8Lit(1), TCType::new(TCTypeBase::I8)),
@@ -2396,13 +3407,14 @@ pub fn check_un_op(
                 TCPrimType::U32 => (TCExprKind::U32Lit(1), TCType::new(TCTypeBase::U32)),
                 TCPrimType::I64 => (TCExprKind::I64Lit(1), TCType::new(TCTypeBase::I64)),
                 TCPrimType::U64 => (TCExprKind::U64Lit(1), TCType::new(TCTypeBase::U64)),
+                TCPrimType::F32 => (TCExprKind::F32Lit(1.0), TCType::new(TCTypeBase::F32)),
+                TCPrimType::F64 => (TCExprKind::F64Lit(1.0), TCType::new(TCTypeBase::F64)),
 
                 TCPrimType::Pointer { stride } => {
                     let or_else = || ptr_to_incomplete_type(env.symbols(), target.ty, loc);
                     let stride = stride.ok_or_else(or_else)? as u64;
                     (TCExprKind::U64Lit(stride), TCType::new(TCTypeBase::U64))
                 }
-                x => unimplemented!("preincr for {:?}", x),
             };
 
             let loc = obj.loc;
@@ -2418,7 +3430,7 @@ pub fn check_un_op(
         }
 
         UnaryOp::BoolNot => {
-            let operand = check_expr(&mut *env, obj)?;
+            let operand = check_expr(&mut *env, out.as_deref_mut(), obj)?;
             let op_type_o = operand.ty.to_prim_type();
             let op_type = op_type_o.ok_or_else(ptype_err(operand.loc))?;
             let operand = env.add(operand);
@@ -2435,9 +3447,13 @@ pub fn check_un_op(
         }
 
         UnaryOp::Neg => {
-            let operand = check_expr(&mut *env, obj)?;
+            let operand = check_expr(&mut *env, out.as_deref_mut(), obj)?;
             let op_type_o = operand.ty.to_prim_type();
             let op_type = op_type_o.ok_or_else(ptype_err(operand.loc))?;
+            if let TCPrimType::Pointer { .. } = op_type {
+                return Err(ptype_err(operand.loc)());
+            }
+
             let operand = env.add(operand);
 
             return Ok(TCExpr {
@@ -2452,9 +3468,12 @@ pub fn check_un_op(
         }
 
         UnaryOp::BitNot => {
-            let operand = check_expr(env, obj)?;
-            let op_type_o = operand.ty.to_prim_type();
-            let op_type = op_type_o.ok_or_else(ptype_err(operand.loc))?;
+            let operand = check_expr(env, out.as_deref_mut(), obj)?;
+            if !operand.ty.is_integer() {
+                return Err(ptype_err(operand.loc)());
+            }
+
+            let op_type = operand.ty.to_prim_type().unwrap();
             let operand = env.add(operand);
 
             return Ok(TCExpr {
@@ -2481,7 +3500,7 @@ pub fn not_a_struct_pointer(syms: &Symbols, ty: TCType, loc: CodeLoc) -> Error {
     return error!(
         "accessed expression using arrow that was not a struct/union pointer",
         loc,
-        format!("access happened here (type is {})", ty.display(syms))
+        format!("access happened here (type is {})", ty.display_aka(syms))
     );
 }
 
@@ -2489,10 +3508,52 @@ pub fn not_a_struct(syms: &Symbols, ty: TCType, loc: CodeLoc) -> Error {
     return error!(
         "tried to access field of non-struct/union type",
         loc,
-        format!("access happened here (type is {})", ty.display(syms))
+        format!("access happened here (type is {})", ty.display_aka(syms))
     );
 }
 
+/// Size of `ty` for `sizeof`; types without one, like functions, get the size
+/// of the pointer they decay to
+fn sizeof(env: &TypeEnv, ty: TCType, loc: CodeLoc) -> Result<u32, Error> {
+    fn incomplete_aggregate(ty: &TCType) -> bool {
+        if ty.mods.len() != 0 {
+            return false;
+        }
+
+        return match ty.base {
+            TCTypeBase::NamedStruct { sa, .. } | TCTypeBase::UnnamedStruct { sa, .. } => {
+                sa.size == n32::NULL
+            }
+            TCTypeBase::NamedUnion { sa, .. } | TCTypeBase::UnnamedUnion { sa, .. } => {
+                sa.size == n32::NULL
+            }
+            TCTypeBase::Typedef { refers_to, .. } => incomplete_aggregate(refers_to),
+            TCTypeBase::InternalTypedef(def) => incomplete_aggregate(def),
+            _ => false,
+        };
+    }
+
+    if incomplete_aggregate(&ty) {
+        return Err(error!(
+            "can't take the size of an incomplete type",
+            loc, "size taken here"
+        ));
+    }
+
+    if ty.is_void() {
+        if env.globals().0.gnu_extensions {
+            return Ok(1);
+        }
+
+        return Err(error!(
+            "can't take the size of `void`",
+            loc, "`void` has no size; compile with `--std=gnu11` to make it 1, like in GCC"
+        ));
+    }
+
+    return Ok(ty.size().unwrap_or_else(|| ty.repr_size()));
+}
+
 pub fn access_incomplete_struct_type(ty: TCType, loc: CodeLoc) -> Error {
     return error!(
         "tried to access field of incomplete struct type",
@@ -2525,20 +3586,57 @@ pub fn invalid_bin_op_assign(l: &TCAssignTarget, r: &TCExpr) -> Error {
     );
 }
 
-pub fn param_conversion_error(ty: TCType, expr: &TCExpr) -> Error {
+pub fn param_conversion_error(syms: &Symbols, ty: TCType, expr: &TCExpr) -> Error {
+    if let Some(err) = int_ptr_conversion_error(syms, ty, expr) {
+        return err;
+    }
+
     return error!(
-        "couldn't convert value to parameter type",
-        expr.loc, "value found here"
+        format!(
+            "couldn't convert value to parameter type {}",
+            ty.display_aka(syms)
+        ),
+        expr.loc,
+        format!("value found here (type is {})", expr.ty.display_aka(syms))
     );
 }
 
-pub fn conversion_error(ty: TCType, loc: CodeLoc, expr: &TCExpr) -> Error {
+pub fn conversion_error(syms: &Symbols, ty: TCType, loc: CodeLoc, expr: &TCExpr) -> Error {
+    if let Some(err) = int_ptr_conversion_error(syms, ty, expr) {
+        return err;
+    }
+
     return error!(
         "couldn't convert value to target type",
-        loc, "target type found here", expr.loc, "value found here"
+        loc,
+        format!("target type found here (type is {})", ty.display_aka(syms)),
+        expr.loc,
+        format!("value found here (type is {})", expr.ty.display_aka(syms))
     );
 }
 
+/// Implicitly converting between integers and pointers is usually a missing `&`
+/// or `*`. If it's on purpose, it needs a cast, like `(intptr_t)ptr`.
+pub fn int_ptr_conversion_error(syms: &Symbols, ty: TCType, expr: &TCExpr) -> Option<Error> {
+    let message = if ty.is_pointer() && expr.ty.is_integer() {
+        "incompatible integer to pointer conversion; did you mean to take the address?"
+    } else if ty.is_integer() && (expr.ty.is_pointer() || expr.ty.is_array()) {
+        "incompatible pointer to integer conversion; did you mean to dereference it?"
+    } else {
+        return None;
+    };
+
+    return Some(error!(
+        message,
+        expr.loc,
+        format!(
+            "value has the type `{}`, but `{}` was expected",
+            expr.ty.display_aka(syms),
+            ty.display_aka(syms)
+        )
+    ));
+}
+
 pub fn condition_non_primitive(ty: TCType, loc: CodeLoc) -> Error {
     return error!(
         "using condition of non-primitive type",
@@ -2547,12 +3645,41 @@ pub fn condition_non_primitive(ty: TCType, loc: CodeLoc) -> Error {
 }
 
 pub fn ptr_to_incomplete_type(syms: &Symbols, ty: TCType, loc: CodeLoc) -> Error {
+    if ty.deref().map(|ty| ty.is_void()).unwrap_or(false) {
+        return error!(
+            "can't do arithmetic on a `void*`",
+            loc, "`void` has no size; cast this to `char*`, or compile with `--std=gnu11`"
+        );
+    }
+
     return error!(
         "cannot perform arithmetic on pointer type",
         loc, "pointer found here"
     );
 }
 
+/// Bytes between the elements `ty` points to. With `--std=gnu11`, `void` is 1
+/// byte, so `void*` arithmetic works like in GCC.
+fn pointer_stride(env: &TypeEnv, ty: TCType) -> n32 {
+    let stride = ty.pointer_stride();
+    let to_void = ty.deref().map(|ty| ty.is_void()).unwrap_or(false);
+    if stride == n32::NULL && to_void && env.globals().0.gnu_extensions {
+        return 1u32.into();
+    }
+
+    return stride;
+}
+
+/// `to_prim_type`, with pointer strides from `pointer_stride`
+fn prim_type(env: &TypeEnv, ty: TCType) -> Option<TCPrimType> {
+    return match ty.to_prim_type()? {
+        TCPrimType::Pointer { .. } if ty.is_pointer() => Some(TCPrimType::Pointer {
+            stride: pointer_stride(env, ty),
+        }),
+        prim => Some(prim),
+    };
+}
+
 pub fn bitshift_conversion_error(syms: &Symbols, expr: &TCExpr) -> Error {
     return error!(
         "couldn't use value as bitshift size",
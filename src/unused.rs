@@ -0,0 +1,169 @@
+//! Warnings about code the program doesn't need: static functions that nothing
+//! reachable from `main` calls, and `#include`s that nothing in the including
+//! file uses. Each warning's label says how to fix it.
+
+use crate::callgraph::*;
+use crate::filedb::*;
+use crate::lexer::*;
+use crate::parser::*;
+use crate::tc_ast::*;
+use crate::type_checker::*;
+use crate::util::*;
+
+/// Warnings for every file in `files` other than the bundled libc. Functions
+/// that aren't static could be called from outside the program, so they're
+/// never reported; without a `main`, all of them count as used.
+pub fn unused(files: &FileDb) -> Result<Vec<Error>, Vec<Error>> {
+    let graph = call_graph(files)?;
+
+    let mut lexer = Lexer::new(files);
+    let mut units = Vec::new();
+    for file in files.impls() {
+        if files.is_system(file) {
+            continue;
+        }
+
+        let (id, tokens) = lexer.lex(file).map_err(|e| vec![e])?;
+        let env = parse(id, tokens)?;
+        let tu = check_tree(files, env.file, &lexer.symbols, &env.tree)?;
+        units.push((env, tu));
+    }
+
+    let name = |ident: u32| lexer.symbols.to_str(ident).unwrap();
+    let mut reached: Vec<&str> = graph.address_taken.iter().map(|f| &**f).collect();
+    match graph.defined.iter().any(|f| f == "main") {
+        true => reached.push("main"),
+        false => {
+            for (_, tu) in &units {
+                let funcs = tu.functions.iter().filter(|(_, f)| !f.is_static);
+                reached.extend(funcs.map(|(&ident, _)| name(ident)));
+            }
+        }
+    }
+
+    let mut idx = 0;
+    while idx < reached.len() {
+        let caller = reached[idx];
+        for edge in graph.edges.iter().filter(|e| e.caller == caller) {
+            if edge.callee != UNKNOWN && !reached.contains(&&*edge.callee) {
+                reached.push(&edge.callee);
+            }
+        }
+
+        idx += 1;
+    }
+
+    let mut warnings = Vec::new();
+    for (_, tu) in &units {
+        for (&ident, func) in &tu.functions {
+            let defn = match func.defn {
+                Some(defn) if func.is_static => defn,
+                _ => continue,
+            };
+
+            if !reached.contains(&name(ident)) {
+                let message = format!("function `{}` is never called", name(ident));
+                let fix = "remove it, or call it from code that `main` runs";
+                warnings.push(error!(message, defn.loc, fix));
+            }
+        }
+    }
+
+    // An include is used if the including file uses anything declared in the
+    // included file or the files it includes
+    let include_graph = files.include_graph();
+    let includes = include_graph
+        .edges
+        .iter()
+        .filter(|e| !files.is_system(e.from));
+    let includes: Vec<_> = includes.collect();
+    let mut used = vec![false; includes.len()];
+    for (env, tu) in &units {
+        for (idx, edge) in includes.iter().enumerate() {
+            if !used[idx] {
+                used[idx] = uses_include(files, &include_graph, env, tu, edge);
+            }
+        }
+    }
+
+    for (edge, _) in includes.iter().zip(used).filter(|(_, used)| !used) {
+        let message = format!("nothing from `{}` is used", files.name(edge.to).unwrap());
+        warnings.push(error!(message, edge.loc, "remove this `#include`"));
+    }
+
+    warnings.sort_by_key(|w| {
+        w.sections
+            .first()
+            .map(|s| (s.location.file, s.location.start))
+    });
+    return Ok(warnings);
+}
+
+fn uses_include(
+    files: &FileDb,
+    graph: &IncludeGraph,
+    env: &ParseEnv,
+    tu: &TranslationUnit,
+    edge: &IncludeEdge,
+) -> bool {
+    let mut closure = vec![edge.to];
+    let mut idx = 0;
+    while idx < closure.len() {
+        for e in graph.includes(closure[idx]) {
+            if !closure.contains(&e.to) {
+                closure.push(e.to);
+            }
+        }
+
+        idx += 1;
+    }
+
+    let expansions = files.expansions.borrow();
+    let mut expansions = expansions.expansions.iter();
+    if expansions.any(|e| e.site.file == edge.from && closure.contains(&e.def.file)) {
+        return true;
+    }
+
+    let globals = tu.refs.iter().filter(|r| r.scope == TCSymbolScope::Global);
+    let declared: Vec<u32> = globals
+        .clone()
+        .filter(|r| closure.contains(&r.loc.file))
+        .map(|r| r.ident)
+        .collect();
+    if globals
+        .clone()
+        .any(|r| r.loc.file == edge.from && declared.contains(&r.ident))
+    {
+        return true;
+    }
+
+    // Type names: typedefs, and struct and union tags
+    let typedefs = tu
+        .typedefs
+        .keys()
+        .filter(|(_, loc)| closure.contains(&loc.file));
+    let mut types: Vec<u32> = typedefs.map(|&(id, _)| id).collect();
+    let (tokens, mut prev) = (&env.tokens, TokenKind::Whitespace);
+    let mut uses = Vec::new();
+    for idx in 0..tokens.len() {
+        let (kind, loc) = (tokens.kind(idx), tokens.loc(idx));
+        if kind == TokenKind::Whitespace {
+            continue;
+        }
+
+        let before = core::mem::replace(&mut prev, kind);
+        let id = match kind {
+            TokenKind::Ident(id) => id,
+            _ => continue,
+        };
+
+        let is_tag = matches!(before, TokenKind::Struct | TokenKind::Union);
+        if is_tag && closure.contains(&loc.file) {
+            types.push(id);
+        } else if loc.file == edge.from && tokens.expansion(idx).is_none() {
+            uses.push(id);
+        }
+    }
+
+    return uses.iter().any(|id| types.contains(id));
+}
@@ -159,7 +159,7 @@ macro_rules! error {
     }};
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
 pub struct ErrorSection {
     pub location: CodeLoc,
     pub message: String,
@@ -171,7 +171,7 @@ impl Into<Label> for &ErrorSection {
     }
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
 pub struct Error {
     pub message: String,
     pub sections: Vec<ErrorSection>,
@@ -186,10 +186,25 @@ impl Error {
     }
 
     pub fn render(&self, files: &FileDb, out: &mut impl Write) -> fmt::Result {
+        return self.render_colored(files, out, false);
+    }
+
+    pub fn render_colored(&self, files: &FileDb, out: &mut impl Write, color: bool) -> fmt::Result {
+        let mut labels: Vec<Label> = self.sections.iter().map(|x| x.into()).collect();
+
+        // If the error is in code from a macro, also point at the macros it came from
+        if let Some(primary) = self.sections.first() {
+            for section in files.expansion_sections(primary.location) {
+                if !self.sections.iter().any(|s| s.location == section.location) {
+                    labels.push((&section).into());
+                }
+            }
+        }
+
         Diagnostic::new()
             .with_message(&self.message)
-            .with_labels(self.sections.iter().map(|x| x.into()).collect())
-            .render(files, out)
+            .with_labels(labels)
+            .render_colored(files, out, color)
     }
 }
 
@@ -288,10 +303,6 @@ pub fn align_u64(size: u64, align: u64) -> u64 {
 }
 
 // https://stackoverflow.com/questions/28127165/how-to-convert-struct-to-u8
-pub unsafe fn any_as_u8_slice_mut<T: Sized + Copy>(p: &mut T) -> &mut [u8] {
-    core::slice::from_raw_parts_mut(p as *mut T as *mut u8, mem::size_of::<T>())
-}
-
 pub fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     unsafe { core::slice::from_raw_parts(p as *const T as *const u8, mem::size_of::<T>()) }
 }
@@ -619,6 +630,25 @@ impl<'a> Utf8LossyChunksIter<'a> {
     }
 }
 
+/// Levenshtein distance between two strings, counted in bytes
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut row = vec![0; b.len() + 1];
+
+    for (i, ca) in a.iter().enumerate() {
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let replace = prev[j] + (ca != cb) as usize;
+            row[j + 1] = replace.min(prev[j + 1] + 1).min(row[j] + 1);
+        }
+
+        mem::swap(&mut prev, &mut row);
+    }
+
+    return prev[b.len()];
+}
+
 pub fn string_append_utf8_lossy(string: &mut String, bytes: &[u8]) {
     string.reserve(bytes.len());
     let mut iter = Utf8Lossy::from_bytes(bytes).chunks();
@@ -15,6 +15,45 @@ fn count_digits(mut n: usize) -> usize {
     count
 }
 
+/// Whether diagnostics are rendered with ANSI colors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color output only if it's going to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Parses the value of a `--color=auto|always|never` flag
+    pub fn from_flag(value: &str) -> Option<Self> {
+        return match value {
+            "auto" => Some(Self::Auto),
+            "always" => Some(Self::Always),
+            "never" => Some(Self::Never),
+            _ => None,
+        };
+    }
+
+    pub fn should_color(self, is_terminal: bool) -> bool {
+        return match self {
+            Self::Auto => is_terminal,
+            Self::Always => true,
+            Self::Never => false,
+        };
+    }
+}
+
+impl Default for ColorChoice {
+    fn default() -> Self {
+        return Self::Auto;
+    }
+}
+
+const HEADER_STYLE: &str = "\x1b[1;31m";
+const LABEL_STYLE: &str = "\x1b[31m";
+const RESET_STYLE: &str = "\x1b[0m";
+
 /// A label describing an underlined region of code associated with a diagnostic.
 #[derive(Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
@@ -98,6 +137,10 @@ impl Diagnostic {
     }
 
     pub fn render(&self, files: &FileDb, out: &mut impl Write) -> Result {
+        return self.render_colored(files, out, false);
+    }
+
+    pub fn render_colored(&self, files: &FileDb, out: &mut impl Write, color: bool) -> Result {
         use alloc::collections::BTreeMap;
 
         struct LabeledFile<'diagnostic, FileId> {
@@ -137,6 +180,8 @@ impl Diagnostic {
         }
 
         let mut renderer = Renderer::new(out);
+        renderer.color = color;
+        renderer.tab_width = files.tab_width;
 
         // TODO: Make this data structure external, to allow for allocation reuse
         let mut labeled_files = Vec::<LabeledFile<'_, _>>::new();
@@ -163,15 +208,9 @@ impl Diagnostic {
                 .iter_mut()
                 .find(|labeled_file| label.file_id == labeled_file.file_id)
             {
-                Some(labeled_file) => {
-                    // another diagnostic also referenced this file
-                    if labeled_file.start > label.range.start {
-                        // this label has a higher style or has the same style but starts earlier
-                        labeled_file.start = label.range.start;
-                        labeled_file.location = location(files, label.file_id, label.range.start)?;
-                    }
-                    labeled_file
-                }
+                // The first label is the primary one, so the file's location
+                // stays where it points, even if later labels start earlier
+                Some(labeled_file) => labeled_file,
                 None => {
                     // no other diagnostic referenced this file yet
                     labeled_files.push(LabeledFile {
@@ -533,12 +572,27 @@ where
     W: Write,
 {
     writer: &'writer mut W,
+    pub color: bool,
+    pub tab_width: usize,
 }
 
 impl<'writer, W: Write> Renderer<'writer, W> {
     /// Construct a renderer from the given writer and config.
     pub fn new(writer: &'writer mut W) -> Self {
-        Renderer { writer }
+        Renderer {
+            writer,
+            color: false,
+            tab_width: 8,
+        }
+    }
+
+    /// Writes `text`, wrapped in `style` if colors are enabled
+    fn styled(&mut self, style: &str, text: &str) -> Result {
+        if !self.color {
+            return write!(self, "{}", text);
+        }
+
+        return write!(self, "{}{}{}", style, text, RESET_STYLE);
     }
 
     /// Diagnostic header, with code, and message.
@@ -562,7 +616,7 @@ impl<'writer, W: Write> Renderer<'writer, W> {
         // ```text
         // : unexpected type in `+` application
         // ```
-        write!(self, "{}", message)?;
+        self.styled(HEADER_STYLE, message)?;
         writeln!(self)?;
 
         Ok(())
@@ -785,7 +839,7 @@ impl<'writer, W: Write> Renderer<'writer, W> {
             // Write first trailing label message
             if let Some((_, (_, message))) = trailing_label {
                 write!(self, " ")?;
-                write!(self, "{}", message)?;
+                self.styled(LABEL_STYLE, message)?;
             }
             writeln!(self)?;
 
@@ -835,7 +889,7 @@ impl<'writer, W: Write> Renderer<'writer, W> {
                             .char_indices()
                             .take_while(|(byte_index, _)| *byte_index < range.start),
                     )?;
-                    write!(self, "{}", message)?;
+                    self.styled(LABEL_STYLE, message)?;
                     writeln!(self)?;
                 }
             }
@@ -972,7 +1026,7 @@ impl<'writer, W: Write> Renderer<'writer, W> {
     ) -> impl Iterator<Item = (Metrics, char)> {
         use unicode_width::UnicodeWidthChar;
 
-        let tab_width = 2;
+        let tab_width = self.tab_width;
         let mut unicode_column = 0;
 
         char_indices.map(move |(byte_index, ch)| {
@@ -1125,7 +1179,8 @@ impl<'writer, W: Write> Renderer<'writer, W> {
 
         write!(self, "{}", '^')?;
         if !message.is_empty() {
-            write!(self, " {}", message)?;
+            write!(self, " ")?;
+            self.styled(LABEL_STYLE, message)?;
         }
         writeln!(self)?;
         Ok(())
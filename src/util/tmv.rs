@@ -287,6 +287,10 @@ impl<'a, T, E> TMVecMut<'a, T, E> {
         return &mut self.tmv.tags[self.idx].tag;
     }
 
+    pub fn into_tag_mut(self) -> &'a mut T {
+        return &mut self.tmv.tags[self.idx].tag;
+    }
+
     pub fn len(&self) -> usize {
         return self.tmv.tags[self.idx].elem_len;
     }
@@ -1,4 +1,5 @@
 use super::general::*;
+use crate::runtime::MemValue;
 use alloc::alloc::{alloc, dealloc, Layout};
 use core::{fmt, marker, mem, ops, ptr, slice, str};
 
@@ -44,15 +45,15 @@ impl VecU8 {
         Self { data: Vec::new() }
     }
 
-    pub fn push<T: Copy + 'static>(&mut self, t: T) {
-        let from_bytes = any_as_u8_slice(&t);
-        self.data.extend_from_slice(from_bytes);
+    pub fn push<T: MemValue>(&mut self, t: T) {
+        let len = self.data.len();
+        self.data.resize(len + T::SIZE, 0);
+        t.to_bytes(&mut self.data[len..]);
     }
 
-    pub fn push_aligned<T: Copy + 'static>(&mut self, t: T) {
+    pub fn push_aligned<T: MemValue>(&mut self, t: T) {
         self.align(mem::align_of::<T>());
-        let from_bytes = any_as_u8_slice(&t);
-        self.data.extend_from_slice(from_bytes);
+        self.push(t);
     }
 
     pub fn append(&mut self, data: &mut Vec<u8>) {
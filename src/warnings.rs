@@ -0,0 +1,615 @@
+//! Warnings about code that compiles, but probably doesn't do what it says:
+//! calls to `printf` and `scanf` whose arguments don't match their format
+//! string, assignments used as conditions, and operators whose precedence
+//! is easy to get wrong. Only format strings written as a literal are checked.
+//! Variables that shadow another one are only reported with `-Wshadow`, since
+//! plenty of correct code does that on purpose. `#warning` directives are
+//! reported here too.
+
+use crate::ast::*;
+use crate::callgraph::op_exprs;
+use crate::filedb::*;
+use crate::formatter::bin_op_str;
+use crate::interner::Symbols;
+use crate::lexer::*;
+use crate::optimizer::visit;
+use crate::parser::*;
+use crate::tc_ast::*;
+use crate::type_checker::*;
+use crate::util::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Printf,
+    Scanf,
+}
+
+/// Functions that take a format string, and which parameter it is
+const FORMAT_FUNCS: [(&str, usize, Style); 7] = [
+    ("printf", 0, Style::Printf),
+    ("fprintf", 1, Style::Printf),
+    ("sprintf", 1, Style::Printf),
+    ("snprintf", 2, Style::Printf),
+    ("scanf", 0, Style::Scanf),
+    ("fscanf", 1, Style::Scanf),
+    ("sscanf", 1, Style::Scanf),
+];
+
+/// What a conversion expects an argument to be. Varargs are promoted before
+/// they're checked, so `char` and `float` arguments show up as `int` and `double`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arg {
+    Int(u32), // an integer this many bytes wide, signed or not
+    Double,
+    String, // `char *`
+    Pointer,
+    IntPtr(u32),   // a pointer to an integer this many bytes wide
+    FloatPtr(u32), // `float *` or `double *`
+    PointerPtr,
+}
+
+struct Conversion {
+    text: String,                   // e.g. `%-5ld`
+    args: Vec<(Arg, &'static str)>, // with how to write each type, for messages
+}
+
+/// Warnings that are off unless asked for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WarningOptions {
+    pub shadow: bool, // `-Wshadow`, for locals that hide another local or a global
+}
+
+/// Warnings for every file in `files` other than the bundled libc
+pub fn warnings(files: &FileDb) -> Result<Vec<Error>, Vec<Error>> {
+    return warnings_with(files, &WarningOptions::default());
+}
+
+pub fn warnings_with(files: &FileDb, options: &WarningOptions) -> Result<Vec<Error>, Vec<Error>> {
+    let mut lexer = Lexer::new(files);
+    let mut warnings = Vec::new();
+    for file in files.impls() {
+        if files.is_system(file) {
+            continue;
+        }
+
+        let (id, tokens) = lexer.lex(file).map_err(|e| vec![e])?;
+        let env = parse(id, tokens)?;
+        for stmt in &env.tree {
+            match &stmt.kind {
+                GlobalStatementKind::FunctionDefinition(func) => {
+                    check_block(&func.statements, &mut warnings)
+                }
+                GlobalStatementKind::Declaration(decl) => check_decl(decl, &mut warnings),
+                GlobalStatementKind::Pragma(_) => {}
+            }
+        }
+
+        let check_options = CheckOptions {
+            gnu_extensions: env.tokens.gnu_extensions,
+            warn_shadow: options.shadow,
+        };
+        let mut tu = check_tree_with(files, env.file, &lexer.symbols, &env.tree, &check_options)?;
+        warnings.append(&mut tu.warnings);
+
+        for func in tu.functions.values() {
+            let defn = match &func.defn {
+                Some(defn) => defn,
+                None => continue,
+            };
+
+            for op in defn.ops {
+                for expr in op_exprs(op) {
+                    visit(expr, &mut |e| {
+                        check_format(&lexer.symbols, e, &mut warnings)
+                    });
+                }
+            }
+        }
+    }
+
+    warnings.append(&mut lexer.warnings);
+    warnings.sort_by_key(|w| {
+        w.sections
+            .first()
+            .map(|s| (s.location.file, s.location.start))
+    });
+    return Ok(warnings);
+}
+
+fn check_block(block: &Block, warnings: &mut Vec<Error>) {
+    for item in block.stmts {
+        match &item.kind {
+            BlockItemKind::Statement(stmt) => check_stmt(stmt, warnings),
+            BlockItemKind::Declaration(decl) => check_decl(decl, warnings),
+        }
+    }
+}
+
+fn check_decl(decl: &Declaration, warnings: &mut Vec<Error>) {
+    for declarator in decl.declarators {
+        if let Some(init) = &declarator.initializer {
+            check_init(init, warnings);
+        }
+    }
+}
+
+fn check_init(init: &Initializer, warnings: &mut Vec<Error>) {
+    match init.kind {
+        InitializerKind::Expr(expr) => check_expr(expr, warnings),
+        InitializerKind::List(items) => {
+            for item in items {
+                check_init(item, warnings);
+            }
+        }
+    }
+}
+
+fn check_stmt(stmt: &Statement, warnings: &mut Vec<Error>) {
+    match &stmt.kind {
+        StatementKind::Labeled { labeled, .. } => check_stmt(labeled, warnings),
+        StatementKind::CaseLabeled {
+            case_value,
+            labeled,
+        } => {
+            check_expr(case_value, warnings);
+            check_stmt(labeled, warnings);
+        }
+        StatementKind::DefaultCaseLabeled(labeled) => check_stmt(labeled, warnings),
+        StatementKind::Expr(expr) | StatementKind::RetVal(expr) => check_expr(expr, warnings),
+        StatementKind::Branch {
+            if_cond,
+            if_body,
+            else_body,
+        } => {
+            check_cond(if_cond, warnings);
+            check_stmt(if_body, warnings);
+            if let Some(else_body) = else_body {
+                check_stmt(else_body, warnings);
+            }
+        }
+        StatementKind::Block(block) => check_block(block, warnings),
+        StatementKind::For {
+            at_start,
+            condition,
+            post_expr,
+            body,
+        } => {
+            if let Some(at_start) = at_start {
+                check_expr(at_start, warnings);
+            }
+
+            if let Some(condition) = condition {
+                check_cond(condition, warnings);
+            }
+
+            if let Some(post_expr) = post_expr {
+                check_expr(post_expr, warnings);
+            }
+
+            check_stmt(body, warnings);
+        }
+        StatementKind::ForDecl {
+            decl,
+            condition,
+            post_expr,
+            body,
+        } => {
+            check_decl(decl, warnings);
+            if let Some(condition) = condition {
+                check_cond(condition, warnings);
+            }
+
+            if let Some(post_expr) = post_expr {
+                check_expr(post_expr, warnings);
+            }
+
+            check_stmt(body, warnings);
+        }
+        StatementKind::While { condition, body } | StatementKind::DoWhile { condition, body } => {
+            check_cond(condition, warnings);
+            check_stmt(body, warnings);
+        }
+        StatementKind::Switch { expr, body } => {
+            check_expr(expr, warnings);
+            check_stmt(body, warnings);
+        }
+        StatementKind::Goto { .. } | StatementKind::Ret => {}
+        StatementKind::Break | StatementKind::Continue => {}
+    }
+}
+
+/// Checks an expression whose value is used as true or false
+fn check_cond(cond: &Expr, warnings: &mut Vec<Error>) {
+    assign_as_cond(cond, warnings);
+    check_expr(cond, warnings);
+}
+
+/// `if (x = 5)` is usually a typo for `if (x == 5)`. Extra parentheses, like
+/// `while ((c = next()))`, say the assignment is on purpose.
+fn assign_as_cond(cond: &Expr, warnings: &mut Vec<Error>) {
+    if let ExprKind::Assign {
+        op: AssignOp::Assign,
+        to,
+        ..
+    } = cond.kind
+    {
+        if !parenthesized(cond, to) {
+            warnings.push(error!(
+                "assignment used as a condition",
+                cond.loc,
+                "use `==` to compare, or put the assignment in parentheses if it's on purpose"
+            ));
+        }
+    }
+}
+
+fn check_expr(expr: &Expr, warnings: &mut Vec<Error>) {
+    match expr.kind {
+        ExprKind::ParenList(exprs) => {
+            for e in exprs {
+                check_expr(e, warnings);
+            }
+        }
+        ExprKind::BinOp(op, l, r) => {
+            for operand in &[l, r] {
+                if let Some(warning) = precedence_trap(op, operand) {
+                    warnings.push(warning);
+                }
+            }
+
+            check_expr(l, warnings);
+            check_expr(r, warnings);
+        }
+        ExprKind::Assign { to, val, .. } => {
+            check_expr(to, warnings);
+            check_expr(val, warnings);
+        }
+        ExprKind::SizeofExpr(e) | ExprKind::UnaryOp(_, e) => check_expr(e, warnings),
+        ExprKind::Cast { from, .. } => check_expr(from, warnings),
+        ExprKind::Member { base, .. } | ExprKind::PtrMember { base, .. } => {
+            check_expr(base, warnings)
+        }
+        ExprKind::Call { function, params } => {
+            check_expr(function, warnings);
+            for param in params {
+                check_expr(param, warnings);
+            }
+        }
+        ExprKind::Ternary {
+            condition,
+            if_true,
+            if_false,
+        } => {
+            check_cond(condition, warnings);
+            check_expr(if_true, warnings);
+            check_expr(if_false, warnings);
+        }
+        ExprKind::StmtExpr(block) => check_block(&block, warnings),
+        ExprKind::CompoundLit { items, .. } => {
+            for item in items {
+                check_init(item, warnings);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `a & b == c` means `a & (b == c)`, and `a << b + 1` means `a << (b + 1)`
+fn precedence_trap(op: BinOp, operand: &Expr) -> Option<Error> {
+    let (inner, first) = match operand.kind {
+        ExprKind::BinOp(inner, first, _) if !parenthesized(operand, first) => (inner, first),
+        _ => return None,
+    };
+
+    let is_bitwise = matches!(op, BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor);
+    let is_shift = matches!(op, BinOp::LShift | BinOp::RShift);
+    let is_compare = matches!(
+        inner,
+        BinOp::Eq | BinOp::Neq | BinOp::Lt | BinOp::Gt | BinOp::Leq | BinOp::Geq
+    );
+    let is_sum = matches!(inner, BinOp::Add | BinOp::Sub);
+
+    if !(is_bitwise && is_compare) && !(is_shift && is_sum) {
+        return None;
+    }
+
+    let message = format!(
+        "`{}` is evaluated before `{}`",
+        bin_op_str(inner),
+        bin_op_str(op)
+    );
+    let label = "this happens first; add parentheses to make the order clear";
+    return Some(error!(message, operand.loc, label));
+}
+
+/// Whether `expr` is written in parentheses, given the subexpression it starts
+/// with. Parentheses only widen an expression's location, but an expression
+/// that comes from a macro has the location of the macro's use, so for those
+/// there's no telling, and they count as parenthesized.
+fn parenthesized(expr: &Expr, first: &Expr) -> bool {
+    return expr.loc.start != first.loc.start || expr.loc == first.loc;
+}
+
+fn check_format(symbols: &Symbols, call: &TCExpr, warnings: &mut Vec<Error>) {
+    let (func, params) = match call.kind {
+        TCExprKind::Call { func, params } => (func, params),
+        _ => return,
+    };
+
+    let name = match func.kind {
+        TCExprKind::FunctionIdent { ident } => symbols.to_str(ident).unwrap(),
+        _ => return,
+    };
+
+    let (idx, style) = match FORMAT_FUNCS.iter().find(|(f, _, _)| *f == name) {
+        Some(&(_, idx, style)) => (idx, style),
+        None => return,
+    };
+
+    let format = match params.get(idx).and_then(|p| string_lit(p)) {
+        Some(format) => format,
+        None => return,
+    };
+
+    let format_loc = params[idx].loc;
+    let conversions = match parse_format(format, style) {
+        Ok(conversions) => conversions,
+        Err(text) => {
+            let message = format!("unknown conversion `{}` in format string", text);
+            warnings.push(error!(message, format_loc, "format string here"));
+            return;
+        }
+    };
+
+    let mut args = params[(idx + 1)..].iter();
+    for conversion in &conversions {
+        for &(expected, written) in &conversion.args {
+            let arg = match args.next() {
+                Some(arg) => arg,
+                None => {
+                    let message = format!(
+                        "`{}` in the format string has no argument to go with it",
+                        conversion.text
+                    );
+                    let label = format!("`{}` needs another argument", name);
+                    warnings.push(error!(
+                        message,
+                        call.loc, label, format_loc, "format string here"
+                    ));
+                    return;
+                }
+            };
+
+            if !arg_matches(expected, arg.ty) {
+                let message = format!(
+                    "`{}` expects an argument of type `{}`, but this one has type `{}`",
+                    conversion.text,
+                    written,
+                    arg.ty.display_aka(symbols)
+                );
+                warnings.push(error!(
+                    message,
+                    arg.loc, "argument here", format_loc, "format string here"
+                ));
+            }
+        }
+    }
+
+    if let Some(extra) = args.next() {
+        let message = format!(
+            "`{}` was passed more arguments than its format string uses",
+            name
+        );
+        warnings.push(error!(
+            message,
+            extra.loc, "this argument isn't used", format_loc, "format string here"
+        ));
+    }
+}
+
+/// The string literal `expr` is, if it's one
+fn string_lit(expr: &TCExpr) -> Option<&'static str> {
+    return match expr.kind {
+        TCExprKind::StringLit(string) => Some(string),
+        TCExprKind::TypePun(e) | TCExprKind::Conv { expr: e, .. } => string_lit(e),
+        _ => None,
+    };
+}
+
+fn arg_matches(expected: Arg, ty: TCType) -> bool {
+    let pointee = TCTy::deref(&ty).filter(|_| ty.is_pointer());
+    let pointee = pointee.map(|p| (p.to_prim_type(), p.repr_size()));
+
+    return match (expected, ty.to_prim_type()) {
+        (Arg::Int(size), Some(prim)) => is_int(prim) && ty.repr_size() == size,
+        (Arg::Double, Some(prim)) => prim == TCPrimType::F64 || prim == TCPrimType::F32,
+        (Arg::Pointer, Some(TCPrimType::Pointer { .. })) => true,
+        (Arg::String, _) => match pointee {
+            Some((Some(TCPrimType::I8), _)) | Some((Some(TCPrimType::U8), _)) => true,
+            _ => false,
+        },
+        (Arg::IntPtr(size), _) => match pointee {
+            Some((Some(prim), repr_size)) => is_int(prim) && repr_size == size,
+            _ => false,
+        },
+        (Arg::FloatPtr(4), _) => matches!(pointee, Some((Some(TCPrimType::F32), _))),
+        (Arg::FloatPtr(_), _) => matches!(pointee, Some((Some(TCPrimType::F64), _))),
+        (Arg::PointerPtr, _) => matches!(pointee, Some((Some(TCPrimType::Pointer { .. }), _))),
+        _ => false,
+    };
+}
+
+fn is_int(prim: TCPrimType) -> bool {
+    return !matches!(
+        prim,
+        TCPrimType::F32 | TCPrimType::F64 | TCPrimType::Pointer { .. }
+    );
+}
+
+/// The conversions in `format`, or the text of the first one that isn't valid
+fn parse_format(format: &str, style: Style) -> Result<Vec<Conversion>, String> {
+    let bytes = format.as_bytes();
+    let mut conversions = Vec::new();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] != b'%' {
+            idx += 1;
+            continue;
+        }
+
+        let begin = idx;
+        idx += 1;
+        let mut args = Vec::new();
+        let next = |idx: usize| bytes.get(idx).copied().unwrap_or(0);
+
+        let mut suppressed = false;
+        if style == Style::Printf {
+            while b"-+ #0'".contains(&next(idx)) {
+                idx += 1;
+            }
+
+            if next(idx) == b'*' {
+                args.push((Arg::Int(4), "int"));
+                idx += 1;
+            }
+
+            while next(idx).is_ascii_digit() {
+                idx += 1;
+            }
+
+            if next(idx) == b'.' {
+                idx += 1;
+                if next(idx) == b'*' {
+                    args.push((Arg::Int(4), "int"));
+                    idx += 1;
+                }
+
+                while next(idx).is_ascii_digit() {
+                    idx += 1;
+                }
+            }
+        } else {
+            if next(idx) == b'*' {
+                suppressed = true;
+                idx += 1;
+            }
+
+            while next(idx).is_ascii_digit() {
+                idx += 1;
+            }
+        }
+
+        let length = match (next(idx), next(idx + 1)) {
+            (b'h', b'h') | (b'l', b'l') => 2,
+            (b'h', _) | (b'l', _) | (b'L', _) | (b'q', _) => 1,
+            (b'j', _) | (b'z', _) | (b't', _) => 1,
+            _ => 0,
+        };
+        let length = &format[idx..(idx + length)];
+        idx += length.len();
+
+        let conv = next(idx);
+        idx += format[idx..]
+            .chars()
+            .next()
+            .map(|c| c.len_utf8())
+            .unwrap_or(0);
+
+        if style == Style::Scanf && conv == b'[' {
+            if next(idx) == b'^' {
+                idx += 1;
+            }
+
+            if next(idx) == b']' {
+                idx += 1;
+            }
+
+            while next(idx) != b']' && next(idx) != 0 {
+                idx += 1;
+            }
+
+            idx = (idx + 1).min(bytes.len());
+        }
+
+        let text = format[begin..idx].to_string();
+        let arg = match style {
+            Style::Printf => printf_arg(conv, length),
+            Style::Scanf => scanf_arg(conv, length),
+        };
+
+        match arg {
+            Some(Some(arg)) if !suppressed => args.push(arg),
+            Some(_) => {}
+            None => return Err(text),
+        }
+
+        conversions.push(Conversion { text, args });
+    }
+
+    return Ok(conversions);
+}
+
+/// `None` for an unknown conversion, and `Some(None)` for `%%`
+fn printf_arg(conv: u8, length: &str) -> Option<Option<(Arg, &'static str)>> {
+    let wide = match length {
+        "" | "h" | "hh" => false,
+        _ => true,
+    };
+
+    let arg = match (conv, wide) {
+        (b'%', _) => return Some(None),
+        (b'd', false) | (b'i', false) => (Arg::Int(4), "int"),
+        (b'd', true) | (b'i', true) => (Arg::Int(8), "long"),
+        (b'u', false) | (b'x', false) | (b'X', false) | (b'o', false) | (b'b', false) => {
+            (Arg::Int(4), "unsigned int")
+        }
+        (b'u', true) | (b'x', true) | (b'X', true) | (b'o', true) | (b'b', true) => {
+            (Arg::Int(8), "unsigned long")
+        }
+        (b'c', _) => (Arg::Int(4), "int"),
+        (b'f', _) | (b'F', _) | (b'e', _) | (b'E', _) | (b'g', _) | (b'G', _) => {
+            (Arg::Double, "double")
+        }
+        (b's', _) => (Arg::String, "char*"),
+        (b'p', _) => (Arg::Pointer, "void*"),
+        (b'n', _) => (Arg::IntPtr(4), "int*"),
+        _ => return None,
+    };
+
+    return Some(Some(arg));
+}
+
+fn scanf_arg(conv: u8, length: &str) -> Option<Option<(Arg, &'static str)>> {
+    let size = match length {
+        "hh" => 1,
+        "h" => 2,
+        "" => 4,
+        _ => 8,
+    };
+
+    let arg = match conv {
+        b'%' => return Some(None),
+        b'd' | b'i' | b'D' => match size {
+            1 => (Arg::IntPtr(1), "char*"),
+            2 => (Arg::IntPtr(2), "short*"),
+            4 => (Arg::IntPtr(4), "int*"),
+            _ => (Arg::IntPtr(8), "long*"),
+        },
+        b'u' | b'x' | b'X' | b'o' | b'O' => match size {
+            1 => (Arg::IntPtr(1), "unsigned char*"),
+            2 => (Arg::IntPtr(2), "unsigned short*"),
+            4 => (Arg::IntPtr(4), "unsigned int*"),
+            _ => (Arg::IntPtr(8), "unsigned long*"),
+        },
+        b'f' | b'e' | b'g' | b'E' | b'G' => match size {
+            4 => (Arg::FloatPtr(4), "float*"),
+            _ => (Arg::FloatPtr(8), "double*"),
+        },
+        b's' | b'c' | b'[' => (Arg::String, "char*"),
+        b'p' => (Arg::PointerPtr, "void**"),
+        b'n' => (Arg::IntPtr(4), "int*"),
+        _ => return None,
+    };
+
+    return Some(Some(arg));
+}
@@ -125,6 +125,8 @@ pub async fn run(env: RunEnv) -> Result<(), JsValue> {
         init
     };
     let mut kernel = Kernel::new(initial);
+    kernel.stdin_open = true; // programs wait for the user to type instead of seeing EOF
+    kernel.use_host_clock(|| (js_sys::Date::now() * 1000.0) as u64);
     let mut term_out_buf = StringWriter::new();
 
     send(Out::Startup);
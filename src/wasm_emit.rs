@@ -0,0 +1,1173 @@
+//! Compiles assembled programs to standalone WebAssembly modules. Memory is laid out
+//! the same way the native backend lays it out, just in linear memory: values go on
+//! a byte-addressed expression stack, locals live on a stack of their own, and
+//! pointers stay `VarPointer`s. Jumps inside a function go through a `br_table`
+//! over its basic blocks.
+//!
+//! The module imports three functions from `env`:
+//! - `ecall(ecall: i32, sp: i32) -> i32` makes a system call. Its arguments are on
+//!   the expression stack below `sp`, laid out like the interpreter's, and it returns
+//!   the stack top after popping them and pushing its results. Pointers can be turned
+//!   into addresses with the exported `decode`. `exit` shouldn't return.
+//! - `fault(status: i32)` reports a runtime error, with the codes from `native`.
+//!   It shouldn't return either.
+//! - `throw(name: i64, message: i64)` reports an error the program raised itself,
+//!   like a failed `assert`. Both are pointers to strings.
+//!
+//! It exports `memory`, `decode(ptr: i64) -> i32`, and `_start`, which runs `main`.
+//! String literals stay in the binary, so stores check that they aren't writing to
+//! one first.
+
+use crate::native::{self, IntOp, Op};
+use crate::runtime::*;
+use crate::util::*;
+
+const I32: u8 = 0x7F;
+const I64: u8 = 0x7E;
+const VOID: u8 = 0x40;
+
+// Imported and helper functions; lowered functions come after them
+const ECALL: u32 = 0;
+const FAULT: u32 = 1;
+const THROW: u32 = 2;
+const DECODE: u32 = 3;
+const BOUNDS: u32 = 4;
+const INVALID_CALL: u32 = 5;
+const DECODE_STORE: u32 = 6;
+const FIRST_FUNC: u32 = 7;
+
+// Function types
+const VOID_TYPE: u32 = 0;
+const TYPES: [&[u8]; 6] = [
+    &[0x60, 0, 0],
+    &[0x60, 2, I32, I32, 1, I32], // ecall
+    &[0x60, 1, I32, 0],           // fault
+    &[0x60, 2, I64, I64, 0],      // throw
+    &[0x60, 1, I64, 1, I32],      // decode
+    &[0x60, 1, I64, 2, I32, I32], // bounds
+];
+
+// Globals
+const SP: u32 = 0; // top of the expression stack
+const VAR_COUNT: u32 = 1; // number of stack variables
+const VAR_TOP: u32 = 2; // top of the stack variables' data
+const FP: u32 = 3; // frame pointer, as a stack variable index
+const DEPTH: u32 = 4; // call depth
+const HEAP_COUNT: u32 = 5; // number of heap variables
+const HEAP_TOP: u32 = 6; // top of the heap variables' data
+
+// Heap variables are never actually freed, just marked with this bit
+const FREED_BIT: i32 = 1 << 31;
+const MAX_HEAP_VARS: u32 = 10_000;
+
+// Locals of lowered functions
+const PC: u32 = 0; // basic block to run next
+const X: u32 = 1;
+const Y: u32 = 2;
+const ADDR: u32 = 3;
+const SAVED_FP: u32 = 4;
+const X64: u32 = 5;
+const Y64: u32 = 6;
+
+/// Where things go in linear memory
+struct Layout {
+    read_only: u32, // whether each binary variable is read-only, a byte each
+    data: u32,      // the binary; before it is the address of each binary variable
+    exprs: u32,
+    exprs_limit: u32,
+    slots: u32,
+    vars: u32,
+    vars_end: u32,
+    heap_table: u32,
+    heap: u32,
+    heap_end: u32,
+}
+
+impl Layout {
+    fn new(binary: &BinaryData, limits: &Limits) -> Self {
+        let align = |x: usize| ((x + 15) & !15) as u32;
+        let read_only = (binary.vars.len() + 2) as u32 * 4;
+        let data = align(read_only as usize + binary.vars.len() + 1);
+        let exprs = align(data as usize + binary.data.len());
+        let slots = exprs + native::EXPRS_SIZE as u32;
+        let vars = slots + native::MAX_STACK_VARS as u32 * 4;
+        let heap_table = vars + limits.max_stack_bytes as u32;
+        let heap = heap_table + MAX_HEAP_VARS * 4;
+
+        return Self {
+            read_only,
+            data,
+            exprs,
+            exprs_limit: slots - native::EXPRS_MARGIN as u32,
+            slots,
+            vars,
+            vars_end: heap_table,
+            heap_table,
+            heap,
+            heap_end: heap + limits.max_heap_bytes as u32,
+        };
+    }
+}
+
+/// Compiles the program to a WebAssembly module
+pub fn emit(binary: &BinaryData) -> Result<Vec<u8>, Error> {
+    let limits = Limits::DEFAULT;
+    let layout = Layout::new(binary, &limits);
+    let decoded = native::decode_program(binary, native::Backend::Wasm)?;
+    let var_count = binary.vars.len() as u32;
+
+    let mut vars: Vec<u32> = decoded.keys().map(|&v| v).collect();
+    vars.sort();
+
+    let mut table = vec![INVALID_CALL; var_count as usize + 1];
+    for (idx, &var) in vars.iter().enumerate() {
+        table[var as usize] = FIRST_FUNC + idx as u32;
+    }
+
+    let mut module = Vec::new();
+    module.extend_from_slice(b"\0asm");
+    module.extend_from_slice(&1u32.to_le_bytes());
+
+    let mut types = Vec::new();
+    uleb(&mut types, TYPES.len() as u32);
+    for ty in &TYPES {
+        types.extend_from_slice(ty);
+    }
+    section(&mut module, 1, &types);
+
+    let mut imports = Vec::new();
+    uleb(&mut imports, 3);
+    for &(name, ty) in &[("ecall", 1), ("fault", 2), ("throw", 3)] {
+        name_bytes(&mut imports, "env");
+        name_bytes(&mut imports, name);
+        imports.push(0x00);
+        uleb(&mut imports, ty);
+    }
+    section(&mut module, 2, &imports);
+
+    let mut funcs = Vec::new();
+    uleb(&mut funcs, 4 + vars.len() as u32);
+    funcs.extend_from_slice(&[4, 5, VOID_TYPE as u8, 4]);
+    for _ in &vars {
+        funcs.push(VOID_TYPE as u8);
+    }
+    section(&mut module, 3, &funcs);
+
+    let mut tables = vec![1, 0x70, 0x00];
+    uleb(&mut tables, table.len() as u32);
+    section(&mut module, 4, &tables);
+
+    let mut memory = vec![1, 0x00];
+    uleb(&mut memory, (layout.heap_end + 0xFFFF) / 0x10000);
+    section(&mut module, 5, &memory);
+
+    let mut globals = Vec::new();
+    let inits = [layout.exprs, 0, layout.vars, 1, 0, 0, layout.heap];
+    uleb(&mut globals, inits.len() as u32);
+    for &init in &inits {
+        globals.extend_from_slice(&[I32, 0x01, 0x41]);
+        sleb(&mut globals, init as i32 as i64);
+        globals.push(0x0B);
+    }
+    section(&mut module, 6, &globals);
+
+    let mut exports = Vec::new();
+    uleb(&mut exports, 3);
+    name_bytes(&mut exports, "memory");
+    exports.extend_from_slice(&[0x02, 0]);
+    name_bytes(&mut exports, "decode");
+    exports.push(0x00);
+    uleb(&mut exports, DECODE);
+    name_bytes(&mut exports, "_start");
+    exports.push(0x00);
+    uleb(&mut exports, table[1]);
+    section(&mut module, 7, &exports);
+
+    let mut elems = vec![1, 0x00, 0x41, 0, 0x0B];
+    uleb(&mut elems, table.len() as u32);
+    for &func in &table {
+        uleb(&mut elems, func);
+    }
+    section(&mut module, 9, &elems);
+
+    let mut code = Vec::new();
+    uleb(&mut code, 4 + vars.len() as u32);
+    func_body(&mut code, &[(2, I32)], &decode());
+    func_body(&mut code, &[(2, I32)], &bounds(var_count, &layout));
+    func_body(&mut code, &[], &invalid_call());
+    func_body(&mut code, &[(1, I32)], &decode_store(var_count, &layout));
+    for var in &vars {
+        let mut func = Func::new(&layout, &decoded[var]);
+        func.lower(&decoded[var]);
+        func_body(&mut code, &[(5, I32), (2, I64)], &func.asm.body);
+    }
+    section(&mut module, 10, &code);
+
+    // The end of the binary is there too, so the last variable's size is known
+    let mut init = vec![0; layout.data as usize];
+    let starts = binary
+        .vars
+        .iter()
+        .map(|v| v.idx)
+        .chain(Some(binary.data.len()));
+    for (idx, start) in starts.enumerate() {
+        let addr = layout.data + start as u32;
+        init[(idx + 1) * 4..(idx + 2) * 4].copy_from_slice(&addr.to_le_bytes());
+    }
+
+    for (idx, var) in binary.vars.iter().enumerate() {
+        init[layout.read_only as usize + idx + 1] = var.meta as u8;
+    }
+    init.extend_from_slice(&binary.data);
+
+    let mut data = vec![1, 0x00, 0x41, 0, 0x0B];
+    uleb(&mut data, init.len() as u32);
+    data.extend_from_slice(&init);
+    section(&mut module, 11, &data);
+
+    return Ok(module);
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+fn name_bytes(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    uleb(module, contents.len() as u32);
+    module.extend_from_slice(contents);
+}
+
+fn func_body(code: &mut Vec<u8>, locals: &[(u32, u8)], body: &[u8]) {
+    let mut func = Vec::new();
+    uleb(&mut func, locals.len() as u32);
+    for &(count, ty) in locals {
+        uleb(&mut func, count);
+        func.push(ty);
+    }
+
+    func.extend_from_slice(body);
+    func.push(0x0B);
+
+    uleb(code, func.len() as u32);
+    code.extend_from_slice(&func);
+}
+
+/// Instructions, appended to a function body
+struct Asm {
+    body: Vec<u8>,
+}
+
+impl Asm {
+    fn op(&mut self, op: u8) -> &mut Self {
+        self.body.push(op);
+        return self;
+    }
+
+    fn idx(&mut self, op: u8, idx: u32) -> &mut Self {
+        self.body.push(op);
+        uleb(&mut self.body, idx);
+        return self;
+    }
+
+    fn i32(&mut self, value: i32) -> &mut Self {
+        self.body.push(0x41);
+        sleb(&mut self.body, value as i64);
+        return self;
+    }
+
+    fn i64(&mut self, value: i64) -> &mut Self {
+        self.body.push(0x42);
+        sleb(&mut self.body, value);
+        return self;
+    }
+
+    fn get(&mut self, local: u32) -> &mut Self {
+        return self.idx(0x20, local);
+    }
+
+    fn set(&mut self, local: u32) -> &mut Self {
+        return self.idx(0x21, local);
+    }
+
+    fn tee(&mut self, local: u32) -> &mut Self {
+        return self.idx(0x22, local);
+    }
+
+    fn global(&mut self, global: u32) -> &mut Self {
+        return self.idx(0x23, global);
+    }
+
+    fn set_global(&mut self, global: u32) -> &mut Self {
+        return self.idx(0x24, global);
+    }
+
+    /// A load or store with no alignment and the given offset
+    fn mem(&mut self, op: u8, offset: u32) -> &mut Self {
+        self.body.push(op);
+        self.body.push(0);
+        uleb(&mut self.body, offset);
+        return self;
+    }
+
+    fn block(&mut self, op: u8, ty: u8) -> &mut Self {
+        self.body.extend_from_slice(&[op, ty]);
+        return self;
+    }
+
+    fn end(&mut self) -> &mut Self {
+        return self.op(0x0B);
+    }
+
+    fn drop(&mut self) -> &mut Self {
+        return self.op(0x1A);
+    }
+
+    fn fault(&mut self, status: u32) -> &mut Self {
+        self.i32(status as i32).idx(0x10, FAULT).op(0x00);
+        return self;
+    }
+
+    /// Faults with `status` if the i32 on the stack isn't zero
+    fn fault_if(&mut self, status: u32) -> &mut Self {
+        self.block(0x04, VOID).fault(status).end();
+        return self;
+    }
+
+    fn memory_copy(&mut self) -> &mut Self {
+        self.body.extend_from_slice(&[0xFC, 0x0A, 0, 0]);
+        return self;
+    }
+
+    fn memory_fill(&mut self) -> &mut Self {
+        self.body.extend_from_slice(&[0xFC, 0x0B, 0]);
+        return self;
+    }
+
+    fn add_global(&mut self, global: u32, value: i32) -> &mut Self {
+        return self.global(global).i32(value).op(0x6A).set_global(global);
+    }
+}
+
+/// `decode(ptr: i64) -> i32`, which turns a pointer into an address
+fn decode() -> Vec<u8> {
+    let (ptr, start) = (0, 1);
+    let mut asm = Asm { body: Vec::new() };
+    asm.get(ptr).idx(0x10, BOUNDS).drop().tee(start).op(0x45);
+    asm.fault_if(native::INVALID_POINTER);
+    asm.get(start).get(ptr).op(0xA7).op(0x6A);
+    return asm.body;
+}
+
+/// `bounds(ptr: i64) -> (i32, i32)`, the addresses where the variable `ptr` points
+/// into starts and ends, or zeros if it's not a valid pointer
+fn bounds(var_count: u32, layout: &Layout) -> Vec<u8> {
+    let (ptr, idx, start) = (0, 1, 2);
+    let mut asm = Asm { body: Vec::new() };
+    let invalid = |asm: &mut Asm| {
+        asm.block(0x04, VOID).i32(0).i32(0).op(0x0F).end();
+    };
+
+    asm.get(ptr).i64(32).op(0x88).op(0xA7).set(idx);
+
+    // Binary variables, which are in a table at the start of memory
+    asm.get(ptr).i64(0).op(0x53).block(0x04, VOID);
+    asm.get(idx).i32(0x3FFF_FFFF).op(0x71).tee(idx).op(0x45);
+    asm.get(idx).i32(var_count as i32).op(0x4B).op(0x72);
+    invalid(&mut asm);
+    asm.get(idx).i32(4).op(0x6C).mem(0x28, 0);
+    asm.get(idx).i32(4).op(0x6C).mem(0x28, 4);
+    asm.op(0x0F).end();
+
+    asm.get(ptr)
+        .i64(62)
+        .op(0x88)
+        .op(0x50)
+        .op(0x45)
+        .block(0x04, VOID);
+    asm.get(idx).i32(0xFFFF).op(0x71).tee(idx).op(0x45);
+    asm.get(idx).global(VAR_COUNT).op(0x4B).op(0x72);
+    invalid(&mut asm);
+    asm.get(idx).i32(4).op(0x6C).mem(0x28, layout.slots - 4);
+    asm.global(VAR_TOP);
+    asm.get(idx).i32(4).op(0x6C).mem(0x28, layout.slots);
+    asm.get(idx).global(VAR_COUNT).op(0x46).op(0x1B);
+    asm.op(0x0F).end();
+
+    asm.get(idx).op(0x45);
+    asm.get(idx).global(HEAP_COUNT).op(0x4B).op(0x72);
+    invalid(&mut asm);
+    asm.get(idx)
+        .i32(4)
+        .op(0x6C)
+        .mem(0x28, layout.heap_table - 4)
+        .tee(start);
+    asm.i32(FREED_BIT).op(0x71);
+    invalid(&mut asm);
+    asm.get(start).global(HEAP_TOP);
+    asm.get(idx).i32(4).op(0x6C).mem(0x28, layout.heap_table);
+    asm.i32(!FREED_BIT).op(0x71);
+    asm.get(idx).global(HEAP_COUNT).op(0x46).op(0x1B);
+
+    return asm.body;
+}
+
+fn invalid_call() -> Vec<u8> {
+    let mut asm = Asm { body: Vec::new() };
+    asm.fault(native::INVALID_CALL);
+    return asm.body;
+}
+
+/// `decode_store(ptr: i64) -> i32`, like `decode`, but for pointers that are about
+/// to be written to, so string literals are invalid too
+fn decode_store(var_count: u32, layout: &Layout) -> Vec<u8> {
+    let (ptr, idx) = (0, 1);
+    let mut asm = Asm { body: Vec::new() };
+    asm.get(ptr).i64(0).op(0x53).block(0x04, VOID);
+    asm.get(ptr).i64(32).op(0x88).op(0xA7);
+    asm.i32(0x3FFF_FFFF).op(0x71).tee(idx);
+    asm.i32(var_count as i32).op(0x4D).block(0x04, VOID);
+    asm.get(idx).mem(0x2D, layout.read_only);
+    asm.fault_if(native::READ_ONLY_WRITE);
+    asm.end().end();
+
+    asm.get(ptr).idx(0x10, DECODE);
+    return asm.body;
+}
+
+struct Func<'a> {
+    asm: Asm,
+    layout: &'a Layout,
+    blocks: HashMap<u32, u32>, // bytecode offset to basic block
+    block: u32,                // the one being lowered
+    block_count: u32,
+}
+
+impl<'a> Func<'a> {
+    fn new(layout: &'a Layout, ops: &[Op]) -> Self {
+        let mut starts: Vec<u32> = ops
+            .iter()
+            .filter_map(native::jump_target)
+            .map(|t| t.offset())
+            .collect();
+        starts.push(0);
+        starts.sort();
+        starts.dedup();
+
+        let blocks = starts.iter().enumerate().map(|(idx, &o)| (o, idx as u32));
+        return Self {
+            asm: Asm { body: Vec::new() },
+            layout,
+            blocks: blocks.collect(),
+            block: 0,
+            block_count: starts.len() as u32,
+        };
+    }
+
+    fn lower(&mut self, ops: &[Op]) {
+        self.asm.block(0x03, VOID);
+        for _ in 0..self.block_count {
+            self.asm.block(0x02, VOID);
+        }
+
+        self.asm.get(PC).op(0x0E);
+        uleb(&mut self.asm.body, self.block_count);
+        for idx in 0..self.block_count {
+            uleb(&mut self.asm.body, idx);
+        }
+        uleb(&mut self.asm.body, 0);
+        self.asm.end();
+
+        for op in ops {
+            match self.blocks.get(&op.offset) {
+                Some(&block) if block != 0 => {
+                    self.asm.end();
+                    self.block = block;
+                }
+                _ => {}
+            }
+
+            self.op(op);
+        }
+
+        self.asm.end();
+    }
+
+    /// Sets the next basic block to run, for a `br` back to the loop around them all
+    fn jump_to(&mut self, op: &Op) -> u32 {
+        let target = native::jump_target(op).unwrap().offset();
+        self.asm.i32(self.blocks[&target] as i32).set(PC);
+        return self.block_count - 1 - self.block;
+    }
+
+    /// Pops a value of `width` bytes, zero-extended to an i32 or, for 8 bytes, an i64
+    fn pop(&mut self, width: u32) {
+        self.asm.add_global(SP, -(width as i32)).global(SP);
+        let load = match width {
+            1 => 0x2D,
+            2 => 0x2F,
+            4 => 0x28,
+            _ => 0x29,
+        };
+        self.asm.mem(load, 0);
+    }
+
+    /// Pushes the value of `local`
+    fn push(&mut self, width: u32, local: u32) {
+        let store = match width {
+            1 => 0x3A,
+            2 => 0x3B,
+            4 => 0x36,
+            _ => 0x37,
+        };
+        self.asm.global(SP).get(local).mem(store, 0);
+        self.asm.add_global(SP, width as i32);
+    }
+
+    fn op(&mut self, op: &Op) {
+        if let Some((from, to, signed)) = native::extension(op.op) {
+            self.asm.add_global(SP, -(from as i32)).global(SP);
+            let load = match (from, to == 8, signed) {
+                (1, true, true) => 0x30,
+                (1, true, false) => 0x31,
+                (2, true, true) => 0x32,
+                (2, true, false) => 0x33,
+                (_, true, true) => 0x34,
+                (_, true, false) => 0x35,
+                (1, false, true) => 0x2C,
+                (1, false, false) => 0x2D,
+                (_, false, true) => 0x2E,
+                (_, false, false) => 0x2F,
+            };
+
+            let local = if to == 8 { X64 } else { X };
+            self.asm.mem(load, 0).set(local);
+            self.push(to, local);
+            return;
+        }
+
+        if let Some((from, to)) = conversion(op.op) {
+            self.convert(from, to);
+            return;
+        }
+
+        if let Some((float_op, width)) = float_op(op.op) {
+            self.float_op(float_op, width);
+            return;
+        }
+
+        if let Some((int_op, width, signed)) = native::int_op(op.op) {
+            self.int_op(op, int_op, width, signed);
+            return;
+        }
+
+        let layout = self.layout;
+        match op.op {
+            Opcode::Func | Opcode::Loc => {}
+
+            Opcode::StackAlloc => {
+                let len = op.operand::<u32>() as i32;
+                let asm = &mut self.asm;
+                asm.global(VAR_COUNT)
+                    .i32(native::MAX_STACK_VARS as i32)
+                    .op(0x4F);
+                asm.global(VAR_TOP).i32(len).op(0x6A);
+                asm.i32(layout.vars_end as i32).op(0x4B).op(0x72);
+                asm.fault_if(native::STACK_OVERFLOW);
+
+                asm.global(VAR_COUNT).i32(4).op(0x6C);
+                asm.global(VAR_TOP).mem(0x36, layout.slots);
+                asm.add_global(VAR_COUNT, 1);
+                asm.global(VAR_TOP).i32(0).i32(len).memory_fill();
+                asm.add_global(VAR_TOP, len);
+            }
+            Opcode::StackDealloc => {
+                let asm = &mut self.asm;
+                asm.add_global(VAR_COUNT, -1);
+                asm.global(VAR_COUNT)
+                    .i32(4)
+                    .op(0x6C)
+                    .mem(0x28, layout.slots);
+                asm.set_global(VAR_TOP);
+            }
+
+            Opcode::Make8 | Opcode::Make16 | Opcode::Make32 => {
+                let (value, width) = match op.op {
+                    Opcode::Make8 => (op.operand::<u8>() as u32, 1),
+                    Opcode::Make16 => (op.operand::<u16>() as u32, 2),
+                    _ => (op.operand::<u32>(), 4),
+                };
+
+                self.asm.i32(value as i32).set(X);
+                self.push(width, X);
+            }
+            Opcode::Make64 => {
+                self.asm.i64(op.operand::<u64>() as i64).set(X64);
+                self.push(8, X64);
+            }
+            Opcode::MakeFp | Opcode::MakeSp => {
+                let base = if op.op == Opcode::MakeFp {
+                    FP
+                } else {
+                    VAR_COUNT
+                };
+                let asm = &mut self.asm;
+                asm.global(base)
+                    .i32(op.operand::<i16>() as i32)
+                    .op(0x6A)
+                    .op(0xAD);
+                asm.i64(32)
+                    .op(0x86)
+                    .i64(VarPointer::STACK_BIT as i64)
+                    .op(0x84);
+                asm.set(X64);
+                self.push(8, X64);
+            }
+
+            Opcode::PushUndef => {
+                let len = op.operand::<u32>() as i32;
+                self.asm.global(SP).i32(0).i32(len).memory_fill();
+                self.asm.add_global(SP, len);
+            }
+            Opcode::Pop => {
+                self.asm.add_global(SP, -(op.operand::<u32>() as i32));
+            }
+            Opcode::Dup => {
+                let len = op.operand::<u32>() as i32;
+                let asm = &mut self.asm;
+                asm.global(SP)
+                    .global(SP)
+                    .i32(len)
+                    .op(0x6B)
+                    .i32(len)
+                    .memory_copy();
+                asm.add_global(SP, len);
+            }
+            Opcode::Swap => {
+                // The bottom goes above the top, and then both move down
+                let (top, bottom): (u32, u32) = (op.operand(), native::read(&op.operand[4..]));
+                let (top, both) = (top as i32, (top + bottom) as i32);
+                let asm = &mut self.asm;
+                asm.global(SP).global(SP).i32(both).op(0x6B);
+                asm.i32(bottom as i32).memory_copy();
+                asm.global(SP).i32(both).op(0x6B);
+                asm.global(SP).i32(top).op(0x6B);
+                asm.i32(both).memory_copy();
+            }
+
+            Opcode::PushDyn => {
+                self.pop(8);
+                self.asm.set(X64);
+                self.pop(4);
+                let asm = &mut self.asm;
+                asm.set(X).get(X64).idx(0x10, DECODE).set(ADDR);
+                asm.global(SP).get(ADDR).get(X).memory_copy();
+                asm.global(SP).get(X).op(0x6A).set_global(SP);
+            }
+
+            Opcode::Get => {
+                let len = op.operand::<u32>() as i32;
+                self.pop(8);
+                let asm = &mut self.asm;
+                asm.idx(0x10, DECODE).set(ADDR);
+                asm.global(SP).get(ADDR).i32(len).memory_copy();
+                asm.add_global(SP, len);
+            }
+            Opcode::Set => {
+                let len = op.operand::<u32>() as i32;
+                self.pop(8);
+                let asm = &mut self.asm;
+                asm.idx(0x10, DECODE_STORE).set(ADDR);
+                asm.add_global(SP, -len);
+                asm.get(ADDR).global(SP).i32(len).memory_copy();
+            }
+
+            Opcode::Jump => {
+                let depth = self.jump_to(op);
+                self.asm.idx(0x0C, depth);
+            }
+            Opcode::Ret => {
+                self.asm.op(0x0F);
+            }
+            Opcode::Call => {
+                self.pop(8);
+                let asm = &mut self.asm;
+                asm.tee(X64).i64(32).op(0x88).op(0xA7);
+                asm.i32(0x3FFF_FFFF).op(0x71).set(X);
+
+                // Not a binary pointer, or not to the start of a variable
+                asm.get(X64).i64(0).op(0x59);
+                asm.get(X64).op(0xA7).i32(0).op(0x47).op(0x72);
+                asm.get(X).op(0x45).op(0x72);
+                asm.fault_if(native::INVALID_CALL);
+
+                asm.global(DEPTH)
+                    .i32(Limits::DEFAULT.max_call_depth as i32)
+                    .op(0x4B);
+                asm.global(SP)
+                    .i32(layout.exprs_limit as i32)
+                    .op(0x4B)
+                    .op(0x72);
+                asm.fault_if(native::STACK_OVERFLOW);
+
+                asm.add_global(DEPTH, 1);
+                asm.global(FP).set(SAVED_FP);
+                asm.global(VAR_COUNT).i32(1).op(0x6A).set_global(FP);
+                asm.get(X).idx(0x11, VOID_TYPE).op(0);
+                asm.get(SAVED_FP).set_global(FP);
+                asm.add_global(DEPTH, -1);
+            }
+            Opcode::Throw => {
+                self.asm.add_global(SP, -4);
+                self.pop(8);
+                self.asm.set(Y64);
+                self.pop(8);
+                self.asm.get(Y64).idx(0x10, THROW).op(0x00);
+            }
+
+            Opcode::AllocBegin | Opcode::AllocEnd => {
+                self.pop(8);
+                let asm = &mut self.asm;
+                asm.tee(X64).idx(0x10, BOUNDS).set(Y).set(X);
+                asm.i64(0);
+                asm.get(X64).i64(VarPointer::TOP_BITS as i64).op(0x83);
+                if op.op == Opcode::AllocBegin {
+                    // The pointer also has to point inside the variable
+                    asm.get(X).op(0x45);
+                    asm.get(X)
+                        .get(X64)
+                        .op(0xA7)
+                        .op(0x6A)
+                        .get(Y)
+                        .op(0x4F)
+                        .op(0x72);
+                } else {
+                    asm.get(Y).get(X).op(0x6B).op(0xAD).op(0x84);
+                    asm.get(X).op(0x45);
+                }
+
+                asm.op(0x1B).set(X64);
+                self.push(8, X64);
+            }
+            Opcode::HeapAlloc => {
+                self.asm.add_global(SP, -4);
+                self.pop(8);
+                let asm = &mut self.asm;
+                asm.set(X64);
+                asm.global(HEAP_COUNT).i32(MAX_HEAP_VARS as i32).op(0x4F);
+                asm.i32(layout.heap_end as i32)
+                    .global(HEAP_TOP)
+                    .op(0x6B)
+                    .op(0xAD);
+                asm.get(X64).op(0x54).op(0x72);
+                asm.fault_if(native::HEAP_TOO_LARGE);
+
+                // Like the interpreter, new memory is filled with garbage
+                asm.get(X64).op(0xA7).set(X);
+                asm.global(HEAP_COUNT).i32(4).op(0x6C);
+                asm.global(HEAP_TOP).mem(0x36, layout.heap_table);
+                asm.global(HEAP_TOP).i32(0xFF).get(X).memory_fill();
+                asm.global(HEAP_TOP).get(X).op(0x6A).set_global(HEAP_TOP);
+                asm.add_global(HEAP_COUNT, 1);
+
+                asm.global(HEAP_COUNT).op(0xAD).i64(32).op(0x86).set(X64);
+                self.push(8, X64);
+            }
+            Opcode::HeapDealloc | Opcode::HeapMove => {
+                self.asm.add_global(SP, -4);
+                self.pop(8);
+                let asm = &mut self.asm;
+                asm.tee(X64).i64(32).op(0x88).op(0xA7).set(X);
+                asm.get(X64).i64(62).op(0x88).op(0x50).op(0x45);
+                asm.fault_if(native::INVALID_FREE);
+                asm.get(X).op(0x45);
+                asm.get(X).global(HEAP_COUNT).op(0x4B).op(0x72);
+                asm.fault_if(native::INVALID_POINTER);
+
+                asm.get(X).i32(4).op(0x6C).set(ADDR);
+                asm.get(ADDR).mem(0x28, layout.heap_table - 4).tee(Y);
+                asm.i32(FREED_BIT).op(0x71);
+                asm.fault_if(native::DOUBLE_FREE);
+                asm.get(ADDR).get(Y).i32(FREED_BIT).op(0x72);
+                asm.mem(0x36, layout.heap_table - 4);
+
+                asm.i64(0).set(X64);
+                self.push(8, X64);
+            }
+            Opcode::AssertStr => {
+                self.pop(8);
+                self.asm.idx(0x10, DECODE).drop();
+            }
+
+            Opcode::Ecall => {
+                self.pop(4);
+                let asm = &mut self.asm;
+                asm.set(X).get(X).global(SP).idx(0x10, ECALL).set_global(SP);
+            }
+
+            op => unreachable!("{:?} should've been rejected by `decode`", op),
+        }
+    }
+
+    fn convert(&mut self, from: Num, to: Num) {
+        let from_width = match from {
+            Num::Int(width, _) | Num::Float(width) => width,
+        };
+
+        self.pop(from_width);
+        let asm = &mut self.asm;
+        match (from, to) {
+            (Num::Int(width, signed), Num::Float(to)) => {
+                match (signed, width) {
+                    (true, 1) => asm.op(0xC0),
+                    (true, 2) => asm.op(0xC1),
+                    _ => asm,
+                };
+
+                let op = match (to, width == 8, signed) {
+                    (4, false, true) => 0xB2,
+                    (4, false, false) => 0xB3,
+                    (4, true, true) => 0xB4,
+                    (4, true, false) => 0xB5,
+                    (_, false, true) => 0xB7,
+                    (_, false, false) => 0xB8,
+                    (_, true, true) => 0xB9,
+                    (_, true, false) => 0xBA,
+                };
+                asm.op(op);
+            }
+            (Num::Float(width), Num::Float(_)) => {
+                match width {
+                    4 => asm.op(0xBE).op(0xBB),
+                    _ => asm.op(0xBF).op(0xB6),
+                };
+            }
+            (Num::Float(width), Num::Int(to, signed)) => {
+                asm.op(if width == 4 { 0xBE } else { 0xBF });
+
+                // Saturates, like Rust's `as`
+                let op = match (to == 8, width == 8, signed) {
+                    (false, false, true) => 0,
+                    (false, false, false) => 1,
+                    (false, true, true) => 2,
+                    (false, true, false) => 3,
+                    (true, false, true) => 4,
+                    (true, false, false) => 5,
+                    (true, true, true) => 6,
+                    (true, true, false) => 7,
+                };
+                asm.body.extend_from_slice(&[0xFC, op]);
+
+                if to < 4 {
+                    let bits = to * 8;
+                    asm.set(X);
+                    if signed {
+                        let (min, max) = (-(1 << (bits - 1)), (1 << (bits - 1)) - 1);
+                        asm.i32(max).get(X).get(X).i32(max).op(0x4A).op(0x1B).set(X);
+                        asm.i32(min).get(X).get(X).i32(min).op(0x48).op(0x1B);
+                    } else {
+                        let max = (1 << bits) - 1;
+                        asm.i32(max).get(X).get(X).i32(max).op(0x4B).op(0x1B);
+                    }
+                }
+            }
+            (Num::Int(..), Num::Int(..)) => unreachable!("integer conversions are extensions"),
+        }
+
+        let (to_width, local) = match to {
+            Num::Float(4) => (4, X),
+            Num::Float(_) => (8, X64),
+            Num::Int(width, _) => (width, if width == 8 { X64 } else { X }),
+        };
+
+        if let Num::Float(width) = to {
+            asm.op(if width == 4 { 0xBC } else { 0xBD });
+        }
+
+        asm.set(local);
+        self.push(to_width, local);
+    }
+
+    fn float_op(&mut self, float_op: IntOp, width: u32) {
+        let wide = width == 8;
+        let (x, y) = if wide { (X64, Y64) } else { (X, Y) };
+        let reinterpret = if wide { 0xBF } else { 0xBE };
+
+        self.pop(width);
+        self.asm.set(y);
+        self.pop(width);
+        self.asm.op(reinterpret).get(y).op(reinterpret);
+
+        let (f32_op, f64_op) = match float_op {
+            IntOp::Add => (0x92, 0xA0),
+            IntOp::Sub => (0x93, 0xA1),
+            IntOp::Mul => (0x94, 0xA2),
+            IntOp::Div => (0x95, 0xA3),
+            IntOp::Lt => (0x5D, 0x63),
+            IntOp::Leq => (0x5F, 0x65),
+            IntOp::Eq => (0x5B, 0x61),
+            _ => (0x5C, 0x62),
+        };
+        self.asm.op(if wide { f64_op } else { f32_op });
+
+        match float_op {
+            IntOp::Lt | IntOp::Leq | IntOp::Eq | IntOp::Neq => {
+                self.asm.set(X);
+                self.push(1, X);
+            }
+            _ => {
+                self.asm.op(if wide { 0xBD } else { 0xBC }).set(x);
+                self.push(width, x);
+            }
+        }
+    }
+
+    fn int_op(&mut self, op: &Op, int_op: IntOp, width: u32, signed: bool) {
+        let wide = width == 8;
+        let (x, y) = if wide { (X64, Y64) } else { (X, Y) };
+
+        // Values narrower than 4 bytes are worked on as i32s
+        let extend = |asm: &mut Asm, local: u32| {
+            asm.get(local);
+            match (signed, width) {
+                (true, 1) => asm.op(0xC0),
+                (true, 2) => asm.op(0xC1),
+                _ => asm,
+            };
+        };
+
+        match int_op {
+            IntOp::BoolNorm | IntOp::BoolNot => {
+                self.pop(width);
+                self.asm.op(if wide { 0x50 } else { 0x45 });
+                if int_op == IntOp::BoolNorm {
+                    self.asm.op(0x45);
+                }
+
+                self.asm.set(X);
+                self.push(1, X);
+                return;
+            }
+            IntOp::JumpIfZero | IntOp::JumpIfNotZero => {
+                let depth = self.jump_to(op);
+                self.pop(width);
+                if wide {
+                    self.asm.op(0x50);
+                    if int_op == IntOp::JumpIfNotZero {
+                        self.asm.op(0x45);
+                    }
+                } else if int_op == IntOp::JumpIfZero {
+                    self.asm.op(0x45);
+                }
+
+                self.asm.idx(0x0D, depth);
+                return;
+            }
+            IntOp::BitNot => {
+                self.pop(width);
+                match wide {
+                    true => self.asm.i64(-1).op(0x85),
+                    false => self.asm.i32(-1).op(0x73),
+                };
+
+                self.asm.set(x);
+                self.push(width, x);
+                return;
+            }
+            _ => {}
+        }
+
+        // Shift amounts are always a byte
+        match int_op {
+            IntOp::LShift | IntOp::RShift => {
+                self.pop(1);
+                self.asm.i32(width as i32 * 8 - 1).op(0x71);
+                if wide {
+                    self.asm.op(0xAD);
+                }
+            }
+            _ => self.pop(width),
+        }
+        self.asm.set(y);
+        self.pop(width);
+        self.asm.set(x);
+
+        let asm = &mut self.asm;
+        let (i32_op, i64_op) = match (int_op, signed) {
+            (IntOp::Add, _) => (0x6A, 0x7C),
+            (IntOp::Sub, _) => (0x6B, 0x7D),
+            (IntOp::Mul, _) => (0x6C, 0x7E),
+            (IntOp::And, _) => (0x71, 0x83),
+            (IntOp::Or, _) => (0x72, 0x84),
+            (IntOp::Xor, _) => (0x73, 0x85),
+            (IntOp::LShift, _) => (0x74, 0x86),
+            (IntOp::RShift, true) => (0x75, 0x87),
+            (IntOp::RShift, false) => (0x76, 0x88),
+            (IntOp::Div, true) => (0x6D, 0x7F),
+            (IntOp::Div, false) => (0x6E, 0x80),
+            (IntOp::Mod, true) => (0x6F, 0x81),
+            (IntOp::Mod, false) => (0x70, 0x82),
+            (IntOp::Lt, true) => (0x48, 0x53),
+            (IntOp::Lt, false) => (0x49, 0x54),
+            (IntOp::Leq, true) => (0x4C, 0x57),
+            (IntOp::Leq, false) => (0x4D, 0x58),
+            (IntOp::Eq, _) => (0x46, 0x51),
+            _ => (0x47, 0x52),
+        };
+        let bin_op = if wide { i64_op } else { i32_op };
+
+        match int_op {
+            IntOp::Div | IntOp::Mod => {
+                asm.get(y).op(if wide { 0x50 } else { 0x45 });
+                asm.fault_if(native::DIVIDE_BY_ZERO);
+
+                // Dividing the smallest value by -1 wraps, like the interpreter, instead
+                // of trapping
+                let ty = if wide { I64 } else { I32 };
+                if signed && int_op == IntOp::Div {
+                    match wide {
+                        true => asm.get(y).i64(-1).op(0x51),
+                        false => asm.get(y).i32(-1).op(0x46),
+                    };
+                    asm.block(0x04, ty);
+                    match wide {
+                        true => asm.i64(0).get(x).op(0x7D),
+                        false => asm.i32(0).get(x).op(0x6B),
+                    };
+                    asm.op(0x05);
+                    extend(asm, x);
+                    extend(asm, y);
+                    asm.op(bin_op).end();
+                } else {
+                    extend(asm, x);
+                    extend(asm, y);
+                    asm.op(bin_op);
+                }
+            }
+            IntOp::LShift | IntOp::RShift => {
+                extend(asm, x);
+                asm.get(y).op(bin_op);
+            }
+            _ => {
+                extend(asm, x);
+                extend(asm, y);
+                asm.op(bin_op);
+            }
+        }
+
+        match int_op {
+            IntOp::Lt | IntOp::Leq | IntOp::Eq | IntOp::Neq => {
+                asm.set(X);
+                self.push(1, X);
+            }
+            _ => {
+                asm.set(x);
+                self.push(width, x);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Num {
+    Int(u32, bool), // width, and whether it's signed
+    Float(u32),
+}
+
+pub fn conversion(op: Opcode) -> Option<(Num, Num)> {
+    use Num::*;
+    use Opcode::*;
+
+    return Some(match op {
+        I8ToF32 => (Int(1, true), Float(4)),
+        U8ToF32 => (Int(1, false), Float(4)),
+        I8ToF64 => (Int(1, true), Float(8)),
+        U8ToF64 => (Int(1, false), Float(8)),
+        I16ToF32 => (Int(2, true), Float(4)),
+        U16ToF32 => (Int(2, false), Float(4)),
+        I16ToF64 => (Int(2, true), Float(8)),
+        U16ToF64 => (Int(2, false), Float(8)),
+        I32ToF32 => (Int(4, true), Float(4)),
+        U32ToF32 => (Int(4, false), Float(4)),
+        I32ToF64 => (Int(4, true), Float(8)),
+        U32ToF64 => (Int(4, false), Float(8)),
+        I64ToF32 => (Int(8, true), Float(4)),
+        U64ToF32 => (Int(8, false), Float(4)),
+        I64ToF64 => (Int(8, true), Float(8)),
+        U64ToF64 => (Int(8, false), Float(8)),
+
+        F32ToI8 => (Float(4), Int(1, true)),
+        F32ToU8 => (Float(4), Int(1, false)),
+        F64ToI8 => (Float(8), Int(1, true)),
+        F64ToU8 => (Float(8), Int(1, false)),
+        F32ToI16 => (Float(4), Int(2, true)),
+        F32ToU16 => (Float(4), Int(2, false)),
+        F64ToI16 => (Float(8), Int(2, true)),
+        F64ToU16 => (Float(8), Int(2, false)),
+        F32ToI32 => (Float(4), Int(4, true)),
+        F32ToU32 => (Float(4), Int(4, false)),
+        F64ToI32 => (Float(8), Int(4, true)),
+        F64ToU32 => (Float(8), Int(4, false)),
+        F32ToI64 => (Float(4), Int(8, true)),
+        F32ToU64 => (Float(4), Int(8, false)),
+        F64ToI64 => (Float(8), Int(8, true)),
+        F64ToU64 => (Float(8), Int(8, false)),
+
+        F32ToF64 => (Float(4), Float(8)),
+        F64ToF32 => (Float(8), Float(4)),
+        _ => return None,
+    });
+}
+
+// Float remainders aren't supported, since WebAssembly doesn't have them
+pub fn float_op(op: Opcode) -> Option<(IntOp, u32)> {
+    use Opcode::*;
+
+    return Some(match op {
+        AddF32 => (IntOp::Add, 4),
+        AddF64 => (IntOp::Add, 8),
+        SubF32 => (IntOp::Sub, 4),
+        SubF64 => (IntOp::Sub, 8),
+        MulF32 => (IntOp::Mul, 4),
+        MulF64 => (IntOp::Mul, 8),
+        DivF32 => (IntOp::Div, 4),
+        DivF64 => (IntOp::Div, 8),
+        CompLtF32 => (IntOp::Lt, 4),
+        CompLtF64 => (IntOp::Lt, 8),
+        CompLeqF32 => (IntOp::Leq, 4),
+        CompLeqF64 => (IntOp::Leq, 8),
+        CompEqF32 => (IntOp::Eq, 4),
+        CompEqF64 => (IntOp::Eq, 8),
+        CompNeqF32 => (IntOp::Neq, 4),
+        CompNeqF64 => (IntOp::Neq, 8),
+        _ => return None,
+    });
+}